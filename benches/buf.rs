@@ -0,0 +1,36 @@
+// cargo bench --bench buf
+//
+// a segment from the pool against a fresh one per connection, each filled
+// once like the first read of a relay would
+
+use bytes::{BufMut, BytesMut};
+use siren::common::buf::{PooledBuf, SEGMENT_SIZE};
+use std::hint::black_box;
+use std::time::Instant;
+
+fn bench(name: &str, iters: u32, mut f: impl FnMut()) {
+    for _ in 0..iters / 10 {
+        f();
+    }
+    let start = Instant::now();
+    for _ in 0..iters {
+        f();
+    }
+    let each = start.elapsed() / iters;
+    println!("{name:<24} {each:>12.2?}/iter");
+}
+
+fn main() {
+    let data = [0x42u8; SEGMENT_SIZE];
+
+    bench("pooled segment", 100_000, || {
+        let mut buf = PooledBuf::take();
+        buf.put_slice(&data);
+        black_box(&buf);
+    });
+    bench("fresh segment", 100_000, || {
+        let mut buf = BytesMut::with_capacity(SEGMENT_SIZE);
+        buf.put_slice(&data);
+        black_box(&buf);
+    });
+}
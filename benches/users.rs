@@ -0,0 +1,51 @@
+// cargo bench --bench users
+//
+// criterion is not among the dependencies, so this times plain loops and
+// prints the mean of each, like crates/hash/benches/kdf.rs
+
+use siren::common::time::SystemClock;
+use siren::proxy::vmess::auth::{create_auth_id, ReplayFilter, AUTH_ID_WINDOW};
+use siren::proxy::vmess::users::{cmd_key, UserTable};
+use std::hint::black_box;
+use std::time::Instant;
+use uuid::Uuid;
+
+const USERS: u128 = 50_000;
+
+fn bench(name: &str, iters: u32, mut f: impl FnMut()) {
+    for _ in 0..iters / 10 {
+        f();
+    }
+    let start = Instant::now();
+    for _ in 0..iters {
+        f();
+    }
+    let each = start.elapsed() / iters;
+    println!("{name:<24} {each:>12.2?}/iter");
+}
+
+fn main() {
+    let users = UserTable::new(Uuid::from_u128(1));
+    for i in 0..USERS {
+        users
+            .add_user(Uuid::from_u128(i + 2), &format!("{i}@example.com"), 0)
+            .unwrap();
+    }
+    // every key is tried whichever user it is, the last one is no slower
+    let last = cmd_key(&Uuid::from_u128(USERS + 1));
+
+    bench("auth keys, 50k users", 20, || {
+        let filter = ReplayFilter::new(AUTH_ID_WINDOW);
+        let id = create_auth_id(&last, &SystemClock);
+        let keys = users.auth_keys();
+        let (i, _) = filter.open_keys(&keys, &id).unwrap();
+        black_box(users.accepts(&keys, i));
+    });
+    bench("cmd keys, 50k users", 5, || {
+        let filter = ReplayFilter::new(AUTH_ID_WINDOW);
+        let id = create_auth_id(&last, &SystemClock);
+        let keys = users.cmd_keys();
+        let keys: Vec<&[u8]> = keys.iter().map(|x| &x.1[..]).collect();
+        black_box(filter.open_any(&keys, &id).unwrap());
+    });
+}
@@ -0,0 +1,20 @@
+// the `soft-aes` feature asks for the `aes` crate's constant time software
+// backend. a cfg of that crate picks it, and only rustflags reach it:
+//
+//     RUSTFLAGS="--cfg aes_force_soft" cargo build --features soft-aes
+//
+// a build with the feature but without the cfg fails here rather than
+// quietly keeping the hardware backends.
+fn main() {
+    println!("cargo:rerun-if-env-changed=CARGO_ENCODED_RUSTFLAGS");
+    if std::env::var_os("CARGO_FEATURE_SOFT_AES").is_none() {
+        return;
+    }
+    let flags = std::env::var("CARGO_ENCODED_RUSTFLAGS").unwrap_or_default();
+    let flags: Vec<_> = flags.split('\x1f').collect();
+    let forced = flags.contains(&"--cfg=aes_force_soft")
+        || flags.windows(2).any(|x| x == ["--cfg", "aes_force_soft"]);
+    if !forced {
+        panic!("the soft-aes feature needs RUSTFLAGS=\"--cfg aes_force_soft\"");
+    }
+}
@@ -0,0 +1,69 @@
+// cargo bench -p siren-hash
+//
+// criterion is not among the dependencies, so this times plain loops and
+// prints the mean of each
+
+use siren_hash::kdf;
+use std::hint::black_box;
+use std::time::Instant;
+
+const KEY: [u8; 16] = [0x5a; 16];
+const AUTH_ID: [u8; 16] = [0x01; 16];
+const NONCE: [u8; 8] = [0x02; 8];
+
+fn kdf16(key: &[u8], path: &[&[u8]]) -> [u8; 16] {
+    let mut out = [0u8; 16];
+    out.copy_from_slice(&kdf(key, path)[..16]);
+    out
+}
+
+// every derivation the server makes for one connection: the auth id key,
+// the four request header keys and ivs, and the four response header ones
+fn handshake(key: &[u8], auth_id: &[u8], nonce: &[u8]) -> u8 {
+    let mut acc = kdf16(key, &[b"AES Auth ID Encryption"])[0];
+    for label in [
+        &b"VMess Header AEAD Key_Length"[..],
+        b"VMess Header AEAD Nonce_Length",
+        b"VMess Header AEAD Key",
+        b"VMess Header AEAD Nonce",
+    ] {
+        acc ^= kdf16(key, &[label, auth_id, nonce])[0];
+    }
+    for label in [
+        &b"AEAD Resp Header Len Key"[..],
+        b"AEAD Resp Header Len IV",
+        b"AEAD Resp Header Key",
+        b"AEAD Resp Header IV",
+    ] {
+        acc ^= kdf16(key, &[label])[0];
+    }
+    acc
+}
+
+fn bench(name: &str, iters: u32, mut f: impl FnMut()) {
+    for _ in 0..iters / 10 {
+        f();
+    }
+    let start = Instant::now();
+    for _ in 0..iters {
+        f();
+    }
+    let each = start.elapsed() / iters;
+    println!("{name:<24} {each:>12.2?}/iter");
+}
+
+fn main() {
+    bench("kdf16", 100_000, || {
+        black_box(kdf16(black_box(&KEY), &[b"AES Auth ID Encryption"]));
+    });
+    bench("handshake", 20_000, || {
+        black_box(handshake(black_box(&KEY), &AUTH_ID, &NONCE));
+    });
+    bench("handshake x 10k", 5, || {
+        for i in 0..10_000u32 {
+            let mut auth_id = AUTH_ID;
+            auth_id[..4].copy_from_slice(&i.to_be_bytes());
+            black_box(handshake(&KEY, &auth_id, &NONCE));
+        }
+    });
+}
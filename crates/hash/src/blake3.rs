@@ -0,0 +1,95 @@
+//! BLAKE3 with a 32 byte output, the hash and key derivation modes. This
+//! is what Shadowsocks 2022 derives its session and identity keys with,
+//! through the portable build of the reference `blake3` crate.
+
+use crate::Hasher32;
+
+/// An incremental BLAKE3 hasher.
+#[derive(Clone, Default)]
+pub struct Blake3(::blake3::Hasher);
+
+impl Blake3 {
+    pub fn new() -> Self {
+        Self(::blake3::Hasher::new())
+    }
+
+    /// The key derivation mode: `context` is a hardcoded, globally unique
+    /// string and the key material goes in with `update`.
+    pub fn new_derive_key(context: &str) -> Self {
+        Self(::blake3::Hasher::new_derive_key(context))
+    }
+
+    pub fn update(&mut self, input: &[u8]) {
+        self.0.update(input);
+    }
+
+    pub fn finalize(&self) -> [u8; 32] {
+        self.0.finalize().into()
+    }
+}
+
+impl Hasher32 for Blake3 {
+    fn update(&mut self, data: &[u8]) {
+        Blake3::update(self, data);
+    }
+
+    fn finalize(self) -> [u8; 32] {
+        Blake3::finalize(&self)
+    }
+}
+
+pub fn hash(data: &[u8]) -> [u8; 32] {
+    ::blake3::hash(data).into()
+}
+
+pub fn derive_key(context: &str, material: &[u8]) -> [u8; 32] {
+    ::blake3::derive_key(context, material)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|x| format!("{x:02x}")).collect()
+    }
+
+    // the input of the official test vectors, bytes counting up mod 251
+    fn input(len: usize) -> impl Iterator<Item = u8> {
+        (0..len).map(|i| (i % 251) as u8)
+    }
+
+    #[test]
+    fn test_hash() {
+        assert_eq!(
+            hex(&hash(b"")),
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+        assert_eq!(
+            hex(&hash(&[0])),
+            "2d3adedff11b61f14c886e35afa036736dcd87a74d27b5c1510225d0f592e213"
+        );
+    }
+
+    #[test]
+    fn test_derive_key() {
+        let context = "BLAKE3 2019-12-27 16:29:52 test vectors context";
+        assert_eq!(
+            hex(&derive_key(context, b"")),
+            "2cc39783c223154fea8dfb7c1b1660f2ac2dcbd1c1de8277b0b0dd39b7e50d7d"
+        );
+    }
+
+    #[test]
+    fn test_incremental() {
+        // across block, chunk and subtree boundaries, fed in odd pieces
+        for len in [63, 64, 65, 1023, 1024, 1025, 2048, 3073, 8193] {
+            let data: std::vec::Vec<u8> = input(len).collect();
+            let mut hasher = Blake3::new();
+            for piece in data.chunks(37) {
+                hasher.update(piece);
+            }
+            assert_eq!(hasher.finalize(), hash(&data), "{len}");
+        }
+    }
+}
@@ -0,0 +1,222 @@
+//! The VMess AEAD KDF, split out of the worker so it can be built with
+//! `--no-default-features` for `no_std` targets. It needs no allocator.
+//! BLAKE3 lives here too, for the Shadowsocks 2022 key derivation.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+pub mod blake3;
+
+pub use sha2::Sha256;
+use sha2::Digest;
+
+/// A hash with a 32 byte digest. The KDF runs on SHA-256; hash chains in
+/// the worker take any implementation, so other hashes plug in from
+/// outside the crate.
+pub trait Hasher32 {
+    fn update(&mut self, data: &[u8]);
+    fn finalize(self) -> [u8; 32];
+}
+
+impl Hasher32 for Sha256 {
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(self, data);
+    }
+
+    fn finalize(self) -> [u8; 32] {
+        Digest::finalize(self).into()
+    }
+}
+
+// one hmac of the chain with its pads run through sha-256 already. every
+// input the innermost sha-256 sees starts with whole pad blocks, so a
+// hash carries on from a copy of these instead of absorbing them again
+#[derive(Clone)]
+struct Level<'a> {
+    // the inner pads of this level and of every level below it
+    inner: Sha256,
+    // the inner pads below this level, then its outer pad
+    outer: Sha256,
+    below: Option<&'a Level<'a>>,
+}
+
+fn pad(key: &[u8], byte: u8) -> [u8; 64] {
+    let mut pad = [0u8; 64];
+    pad[..key.len()].copy_from_slice(key);
+    for b in pad.iter_mut() {
+        *b ^= byte;
+    }
+    pad
+}
+
+fn absorb(mut state: Sha256, data: &[u8]) -> Sha256 {
+    Digest::update(&mut state, data);
+    state
+}
+
+impl<'a> Level<'a> {
+    fn base() -> Level<'static> {
+        let key = b"VMess AEAD KDF";
+        Level {
+            inner: absorb(Sha256::new(), &pad(key, 0x36)),
+            outer: absorb(Sha256::new(), &pad(key, 0x5c)),
+            below: None,
+        }
+    }
+
+    // the hmac keyed with `key` whose hash is this level. a key longer
+    // than a block is hashed first, as go's crypto/hmac does. an empty key
+    // is zero padded like any short one, its pads are plain ipad and opad
+    fn push(&'a self, key: &[u8]) -> Level<'a> {
+        let hashed;
+        let key = if key.len() > 64 {
+            hashed = self.hash(key);
+            &hashed[..]
+        } else {
+            key
+        };
+        Level {
+            inner: absorb(self.inner.clone(), &pad(key, 0x36)),
+            outer: absorb(self.inner.clone(), &pad(key, 0x5c)),
+            below: Some(self),
+        }
+    }
+
+    fn hash(&self, msg: &[u8]) -> [u8; 32] {
+        self.finish(absorb(self.inner.clone(), msg))
+    }
+
+    // `state` has taken this level's inner pads and the message
+    fn finish(&self, state: Sha256) -> [u8; 32] {
+        let finish_below = |state: Sha256| match self.below {
+            Some(below) => below.finish(state),
+            None => Digest::finalize(state).into(),
+        };
+        let inner = finish_below(state);
+        finish_below(absorb(self.outer.clone(), &inner))
+    }
+}
+
+// each path element keys an hmac whose hash is the hmac of the elements
+// before it, the innermost one is hmac-sha256 keyed with the kdf's salt
+fn derive(level: &Level, path: &[&[u8]], key: &[u8]) -> [u8; 32] {
+    match path.split_first() {
+        Some((first, rest)) => derive(&level.push(first), rest, key),
+        None => level.hash(key),
+    }
+}
+
+// the first path element is one of the few fixed labels, the levels for
+// them are kept once they have been made. an empty path is hmac-sha256
+// keyed with the salt alone.
+#[cfg(feature = "std")]
+pub fn kdf(key: &[u8], path: &[&[u8]]) -> [u8; 32] {
+    use std::sync::{Mutex, OnceLock};
+    const MAX_LABELS: usize = 16;
+    static BASE: OnceLock<Level<'static>> = OnceLock::new();
+    static LABELS: Mutex<Vec<(Vec<u8>, Level<'static>)>> = Mutex::new(Vec::new());
+
+    let base = BASE.get_or_init(Level::base);
+    let Some((label, rest)) = path.split_first() else {
+        return base.hash(key);
+    };
+    // a cached level is derived from where it lies, under the lock, and
+    // not copied out first
+    let mut labels = LABELS.lock().unwrap();
+    if let Some((_, level)) = labels.iter().find(|x| x.0 == *label) {
+        return derive(level, rest, key);
+    }
+    let level = base.push(label);
+    let derived = derive(&level, rest, key);
+    if labels.len() < MAX_LABELS {
+        labels.push((label.to_vec(), level));
+    }
+    derived
+}
+
+#[cfg(not(feature = "std"))]
+pub fn kdf(key: &[u8], path: &[&[u8]]) -> [u8; 32] {
+    derive(&Level::base(), path, key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use md5::Md5;
+
+    #[test]
+    fn test_kdf() {
+        let uuid = uuid::uuid!("96850032-1b92-46e9-a4f2-b99631456894").as_bytes();
+        let mut hash = Md5::new();
+        hash.update(uuid);
+        hash.update(b"c48619fe-8f02-49e0-b9e9-edf763e17e21");
+        let key = hash.finalize();
+
+        let res = kdf(&key, &[b"AES Auth ID Encryption"]);
+
+        assert_eq!(
+            res[..16],
+            [117, 82, 144, 159, 147, 65, 74, 253, 91, 74, 70, 84, 114, 118, 203, 30]
+        );
+    }
+
+    #[test]
+    fn test_kdf_nested() {
+        // the header payload key of a request, as computed by the boxed
+        // hashers this replaced
+        let res = kdf(
+            &[1u8; 16],
+            &[b"VMess Header AEAD Key", &[2u8; 16], &[3u8; 8]],
+        );
+        assert_eq!(
+            res,
+            [
+                172, 149, 19, 119, 242, 38, 212, 199, 204, 76, 11, 157, 98, 35, 235, 80, 254, 212,
+                104, 242, 80, 102, 72, 134, 116, 192, 207, 48, 24, 249, 182, 53
+            ]
+        );
+    }
+
+    #[test]
+    fn test_kdf_empty() {
+        assert_eq!(pad(b"", 0x36), [0x36; 64]);
+        assert_eq!(pad(b"", 0x5c), [0x5c; 64]);
+
+        // hmac-sha256("VMess AEAD KDF", key), from python's hmac module
+        let base = [
+            57, 19, 231, 22, 239, 241, 65, 250, 33, 247, 234, 194, 97, 233, 52, 125, 162, 74, 215,
+            240, 101, 63, 13, 58, 59, 99, 87, 236, 51, 212, 71, 72,
+        ];
+        assert_eq!(kdf(&[1u8; 16], &[]), base);
+
+        // and an hmac with the empty key on top of that one
+        let empty_label = [
+            56, 204, 181, 155, 112, 35, 213, 222, 61, 163, 26, 67, 132, 47, 232, 52, 253, 131, 128,
+            221, 76, 143, 191, 7, 219, 129, 144, 73, 36, 15, 202, 224,
+        ];
+        assert_eq!(kdf(&[1u8; 16], &[b""]), empty_label);
+        assert_eq!(kdf(&[1u8; 16], &[b"", b""]), derive(&Level::base(), &[b"", b""], &[1u8; 16]));
+    }
+
+    #[test]
+    fn test_kdf_cached() {
+        // the second call finds the label's level cached
+        let path: [&[u8]; 3] = [b"VMess Header AEAD Nonce", &[2u8; 16], &[3u8; 8]];
+        let uncached = derive(&Level::base(), &path, &[1u8; 16]);
+        assert_eq!(kdf(&[1u8; 16], &path), uncached);
+        assert_eq!(kdf(&[1u8; 16], &path), uncached);
+        assert_eq!(kdf(&[1u8; 16], &path[..1]), derive(&Level::base(), &path[..1], &[1u8; 16]));
+    }
+
+    #[test]
+    fn test_kdf_long_label() {
+        // longer than a block, hashed into the hmac key like go does
+        let res = kdf(&[1u8; 16], &[&[b'L'; 100], &[2u8; 16]]);
+        assert_eq!(
+            res,
+            [
+                223, 42, 79, 200, 183, 161, 112, 119, 138, 196, 54, 142, 82, 0, 58, 208, 151, 199,
+                245, 2, 20, 82, 191, 69, 35, 106, 251, 208, 196, 204, 172, 72
+            ]
+        );
+    }
+}
@@ -0,0 +1,32 @@
+// Arbitrary bytes as the body of a session, read through the stream the
+// proxy uses, in reads of every size the input picks.
+
+#![no_main]
+
+use futures_util::FutureExt;
+use libfuzzer_sys::fuzz_target;
+use siren::proxy::vmess::chunk::{Security, VmessStream};
+use tokio::io::AsyncReadExt;
+
+const KEY: [u8; 16] = [7u8; 16];
+const IV: [u8; 16] = [9u8; 16];
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&[security, options, read_size], body)) = data.split_first_chunk() else {
+        return;
+    };
+    let Ok(security) = Security::from_byte(security) else {
+        return;
+    };
+    let Ok(mut stream) = VmessStream::new(body, security, options, &KEY, &IV, &KEY, &IV) else {
+        return;
+    };
+    let mut buf = vec![0u8; read_size as usize + 1];
+    loop {
+        match stream.read(&mut buf).now_or_never() {
+            Some(Ok(0)) | Some(Err(_)) => break,
+            Some(Ok(_)) => {}
+            None => unreachable!("reading a slice never waits"),
+        }
+    }
+});
@@ -0,0 +1,14 @@
+// Arbitrary bytes in front of a websocket, as the relay's proxy protocol
+// header would be.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use siren::common::proxy_protocol::{header_len, parse_v2};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((_, _, len)) = parse_v2(data) {
+        assert_eq!(header_len(data), Ok(len));
+        assert!(len <= data.len());
+    }
+});
@@ -0,0 +1,24 @@
+// Arbitrary bytes into the header open path. The clock is pinned to the
+// interop capture in the seed corpus, so that seed gets past the auth id:
+// `cargo fuzz run vmess_header fuzz/corpus/vmess_header`
+
+#![no_main]
+
+use futures_util::FutureExt;
+use libfuzzer_sys::fuzz_target;
+use siren::common::time::MockClock;
+use siren::proxy::vmess::auth::{ReplayFilter, AUTH_ID_WINDOW};
+use siren::proxy::vmess::open_vmess_header_with;
+use siren::proxy::vmess::users::UserTable;
+use std::rc::Rc;
+use uuid::uuid;
+
+fuzz_target!(|data: &[u8]| {
+    let users = UserTable::new(uuid!("f282b878-8711-45a1-8c69-5564172123c1"));
+    // a fresh filter each run, or a seed would only open once
+    let filter =
+        ReplayFilter::new(AUTH_ID_WINDOW).with_clock(Rc::new(MockClock::new(1_700_000_030)));
+    let _ = open_vmess_header_with(&mut &data[..], &users, &filter)
+        .now_or_never()
+        .expect("reading a slice never waits");
+});
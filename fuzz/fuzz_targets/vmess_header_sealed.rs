@@ -0,0 +1,54 @@
+// Headers sealed for the right user, then mutated, so that the command
+// section and the lengths around it are reached more often than by
+// arbitrary bytes: `cargo fuzz run vmess_header_sealed`
+
+#![no_main]
+
+use futures_util::FutureExt;
+use libfuzzer_sys::arbitrary::{self, Arbitrary};
+use libfuzzer_sys::fuzz_target;
+use siren::common::time::MockClock;
+use siren::proxy::vmess::auth::{ReplayFilter, AUTH_ID_WINDOW};
+use siren::proxy::vmess::users::{cmd_key, UserTable};
+use siren::proxy::vmess::{open_vmess_header_with, seal_vmess_header};
+use std::rc::Rc;
+use uuid::uuid;
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    cmd: Vec<u8>,
+    // how far the sealing clock is off from the server's
+    skew: i8,
+    // offsets into the sealed header and what they are xored with
+    flips: Vec<(u16, u8)>,
+    truncate: Option<u16>,
+    trailing: Vec<u8>,
+}
+
+fuzz_target!(|input: Input| {
+    let uuid = uuid!("f282b878-8711-45a1-8c69-5564172123c1");
+    let now: u64 = 1_700_000_000;
+    let clock = MockClock::new(now.saturating_add_signed(input.skew as i64));
+    let Ok(mut sealed) = seal_vmess_header(&cmd_key(&uuid), &input.cmd, &clock) else {
+        return;
+    };
+    let untouched = input.flips.iter().all(|&(_, x)| x == 0) && input.truncate.is_none();
+    for (at, x) in input.flips {
+        let len = sealed.len();
+        sealed[at as usize % len] ^= x;
+    }
+    if let Some(len) = input.truncate {
+        sealed.truncate(len as usize);
+    }
+    sealed.extend(input.trailing);
+
+    let users = UserTable::new(uuid);
+    let filter = ReplayFilter::new(AUTH_ID_WINDOW).with_clock(Rc::new(MockClock::new(now)));
+    let res = open_vmess_header_with(&mut &sealed[..], &users, &filter)
+        .now_or_never()
+        .expect("reading a slice never waits");
+    // an unmutated header sealed within the window opens
+    if untouched && u64::from(input.skew.unsigned_abs()) <= AUTH_ID_WINDOW {
+        assert_eq!(res.ok(), Some(input.cmd));
+    }
+});
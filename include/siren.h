@@ -0,0 +1,36 @@
+/* C bindings for the vmess kdf and request header, built with
+ * `cargo build --release --features capi` as libsiren. Every function
+ * but sw2_free returns one of the SW2_* codes. */
+
+#ifndef SIREN_H
+#define SIREN_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define SW2_OK 0
+#define SW2_NULL_POINTER -1
+#define SW2_INVALID_INPUT -2
+#define SW2_PANIC -3
+
+/* bytes allocated by the library, released with sw2_free */
+typedef struct {
+    uint8_t *ptr;
+    size_t len;
+} Sw2Buffer;
+
+/* the cmd key of a 16 byte uuid into out[16] */
+int32_t sw2_cmd_key(const uint8_t *uuid, uint8_t *out);
+
+/* the vmess kdf of key over n labels into out[32] */
+int32_t sw2_kdf(const uint8_t *key, size_t key_len, const uint8_t *const *labels,
+                const size_t *labels_lens, size_t n, uint8_t *out);
+
+/* the aead request header sealing cmd for cmd_key[16] at now, in unix
+ * seconds */
+int32_t sw2_encode_request_header(const uint8_t *cmd_key, const uint8_t *cmd, size_t cmd_len,
+                                  uint64_t now, Sw2Buffer *out);
+
+void sw2_free(Sw2Buffer buffer);
+
+#endif
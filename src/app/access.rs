@@ -0,0 +1,313 @@
+use crate::common::time;
+
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Metadata, Subscriber};
+
+// target of the event closing a connection, one access log line each
+pub const ACCESS_TARGET: &str = "siren::access";
+
+// how access log lines are written, the optional `ACCESS_LOG` binding
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    Json,
+    Logfmt,
+}
+
+impl AccessLogFormat {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "json" => Some(Self::Json),
+            "logfmt" => Some(Self::Logfmt),
+            _ => None,
+        }
+    }
+}
+
+// users are named by their uuid, which is their password. logs only ever
+// get this, enough to tell users apart.
+pub fn fingerprint(user: &str) -> String {
+    let hash = Sha256::digest(user.as_bytes());
+    hash[..4].iter().map(|x| format!("{x:02x}")).collect()
+}
+
+// random rather than counted, isolates come and go and lines from all of
+// them end up in the same place
+pub fn connection_id() -> String {
+    let mut id = [0u8; 8];
+    crate::common::random(&mut id);
+    id.iter().map(|x| format!("{x:02x}")).collect()
+}
+
+struct Fields<'a>(&'a mut Vec<(&'static str, Value)>);
+
+impl Fields<'_> {
+    fn set(&mut self, name: &'static str, value: Value) {
+        match self.0.iter_mut().find(|(x, _)| *x == name) {
+            Some(x) => x.1 = value,
+            None => self.0.push((name, value)),
+        }
+    }
+
+    fn extend(&mut self, fields: Vec<(&'static str, Value)>) {
+        for (name, value) in fields {
+            self.set(name, value);
+        }
+    }
+}
+
+impl Visit for Fields<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.set(field.name(), Value::String(format!("{value:?}")));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field.name(), Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.set(field.name(), Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.set(field.name(), Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.set(field.name(), Value::from(value));
+    }
+}
+
+struct SpanData {
+    fields: Vec<(&'static str, Value)>,
+    refs: usize,
+}
+
+thread_local! {
+    // spans entered on this thread, innermost last
+    static ENTERED: RefCell<Vec<Id>> = const { RefCell::new(Vec::new()) };
+}
+
+type Sink = Box<dyn Fn(Level, &str) + Send + Sync>;
+
+// one line per event with the fields of every span it happened in. the
+// workers runtime has no subscriber of its own, this writes to the
+// console.
+pub struct ConsoleSubscriber {
+    max_level: Level,
+    access_log: Option<AccessLogFormat>,
+    next_id: AtomicU64,
+    spans: Mutex<HashMap<u64, SpanData>>,
+    sink: Sink,
+}
+
+impl ConsoleSubscriber {
+    pub fn new(access_log: Option<AccessLogFormat>) -> Self {
+        Self {
+            max_level: Level::INFO,
+            access_log,
+            next_id: AtomicU64::new(1),
+            spans: Mutex::default(),
+            sink: Box::new(|level, line| match level {
+                Level::ERROR | Level::WARN => crate::log_error!("{}", line),
+                _ => crate::log!("{}", line),
+            }),
+        }
+    }
+
+    pub fn with_max_level(mut self, level: Level) -> Self {
+        self.max_level = level;
+        self
+    }
+
+    // where lines go instead of the console
+    pub fn with_sink(mut self, sink: impl Fn(Level, &str) + Send + Sync + 'static) -> Self {
+        self.sink = Box::new(sink);
+        self
+    }
+
+    fn format(&self, event: &Event<'_>) -> String {
+        let metadata = event.metadata();
+        let mut fields = vec![(
+            "level",
+            Value::from(metadata.level().as_str().to_lowercase()),
+        )];
+        if metadata.target() == ACCESS_TARGET {
+            fields.push(("ts", Value::from(time::now().as_millis() as u64)));
+        }
+        let spans = self.spans.lock().unwrap();
+        ENTERED.with(|entered| {
+            for id in entered.borrow().iter() {
+                if let Some(span) = spans.get(&id.into_u64()) {
+                    Fields(&mut fields).extend(span.fields.clone());
+                }
+            }
+        });
+        let mut own = Vec::new();
+        event.record(&mut Fields(&mut own));
+        // the message goes first, the way logfmt readers expect it
+        if let Some(i) = own.iter().position(|(x, _)| *x == "message") {
+            let (_, message) = own.remove(i);
+            fields.insert(1, ("msg", message));
+        }
+        Fields(&mut fields).extend(own);
+
+        match self
+            .access_log
+            .filter(|_| metadata.target() == ACCESS_TARGET)
+        {
+            Some(AccessLogFormat::Json) => {
+                let object: Map<_, _> = fields
+                    .into_iter()
+                    .map(|(k, v)| (k.to_string(), v))
+                    .collect();
+                Value::Object(object).to_string()
+            }
+            _ => logfmt(&fields),
+        }
+    }
+}
+
+fn logfmt(fields: &[(&str, Value)]) -> String {
+    let mut line = String::new();
+    for (name, value) in fields {
+        let value = match value {
+            Value::String(x) => x.clone(),
+            x => x.to_string(),
+        };
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(name);
+        line.push('=');
+        if value.is_empty() || value.contains([' ', '"', '=', '\\']) {
+            line.push_str(&format!("{value:?}"));
+        } else {
+            line.push_str(&value);
+        }
+    }
+    line
+}
+
+impl Subscriber for ConsoleSubscriber {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        *metadata.level() <= self.max_level
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut fields = Vec::new();
+        span.record(&mut Fields(&mut fields));
+        let span = SpanData { fields, refs: 1 };
+        self.spans.lock().unwrap().insert(id, span);
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        if let Some(span) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            values.record(&mut Fields(&mut span.fields));
+        }
+    }
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        (self.sink)(*event.metadata().level(), &self.format(event));
+    }
+
+    fn enter(&self, span: &Id) {
+        ENTERED.with(|x| x.borrow_mut().push(span.clone()));
+    }
+
+    fn exit(&self, span: &Id) {
+        ENTERED.with(|x| {
+            let mut entered = x.borrow_mut();
+            if let Some(i) = entered.iter().rposition(|x| x == span) {
+                entered.remove(i);
+            }
+        });
+    }
+
+    fn clone_span(&self, span: &Id) -> Id {
+        if let Some(x) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            x.refs += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: Id) -> bool {
+        let mut spans = self.spans.lock().unwrap();
+        let Some(x) = spans.get_mut(&span.into_u64()) else {
+            return false;
+        };
+        x.refs -= 1;
+        if x.refs > 0 {
+            return false;
+        }
+        spans.remove(&span.into_u64());
+        true
+    }
+}
+
+// the first request of an isolate sets it up, later ones find it in place
+pub fn install(access_log: Option<AccessLogFormat>) {
+    let _ = tracing::subscriber::set_global_default(ConsoleSubscriber::new(access_log));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn capture(format: Option<AccessLogFormat>) -> (ConsoleSubscriber, Arc<Mutex<Vec<String>>>) {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let sink = lines.clone();
+        let subscriber = ConsoleSubscriber::new(format)
+            .with_sink(move |_, line| sink.lock().unwrap().push(line.to_string()));
+        (subscriber, lines)
+    }
+
+    #[test]
+    fn test_span_fields() {
+        let (subscriber, lines) = capture(Some(AccessLogFormat::Json));
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("conn", id = "ab", rule = tracing::field::Empty);
+            let _entered = span.enter();
+            span.record("rule", "rules[0]");
+            tracing::info!(ms = 5, "handshake done");
+            tracing::debug!("not shown");
+            tracing::info!(target: ACCESS_TARGET, up = 1, down = 2, "closed");
+        });
+
+        let lines = lines.lock().unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            r#"level=info msg="handshake done" id=ab rule=rules[0] ms=5"#
+        );
+        let access: Value = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(access["id"], "ab");
+        assert_eq!(access["up"], 1);
+        assert_eq!(access["msg"], "closed");
+        assert!(access["ts"].is_u64());
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let uuid = "f282b878-8711-45a1-8c69-5564172123c1";
+        assert_eq!(fingerprint(uuid).len(), 8);
+        assert_eq!(fingerprint(uuid), fingerprint(uuid));
+        assert_ne!(
+            fingerprint(uuid),
+            fingerprint("00000000-0000-0000-0000-000000000001")
+        );
+        assert!(!uuid.contains(&fingerprint(uuid)));
+    }
+}
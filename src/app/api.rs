@@ -0,0 +1,596 @@
+use crate::app::stats::Stats;
+use crate::common::protobuf::{Fields, Value as Field, Writer};
+use crate::config::ConfigError;
+use crate::proxy::vmess::users::UserTable;
+
+use serde_json::Value;
+use std::fmt;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use uuid::Uuid;
+
+pub const DEFAULT_TAG: &str = "api";
+
+// the vmess inbound, the only one whose users are managed
+const VMESS_TAG: &str = "vmess";
+
+// grpc status codes, https://grpc.github.io/grpc/core/md_doc_statuscodes.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Code {
+    Unknown = 2,
+    InvalidArgument = 3,
+    NotFound = 5,
+    Unimplemented = 12,
+}
+
+// a failed call, what goes into the grpc-status and grpc-message trailers
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Status {
+    pub code: Code,
+    pub message: String,
+}
+
+impl Status {
+    pub fn new(code: Code, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Service {
+    Handler,
+    Stats,
+}
+
+impl Service {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "HandlerService" => Some(Self::Handler),
+            "StatsService" => Some(Self::Stats),
+            _ => None,
+        }
+    }
+}
+
+// v2ray's `api` object with the address of the inbound serving it. the api
+// has no authentication, so it only listens on loopback addresses unless
+// `allowRemote` says otherwise.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ApiConfig {
+    pub tag: String,
+    pub listen: SocketAddr,
+    pub services: Vec<Service>,
+}
+
+impl ApiConfig {
+    // `{"tag": "api", "listen": "127.0.0.1:10085", "services":
+    // ["HandlerService", "StatsService"], "allowRemote": false}`
+    pub fn from_json(value: &Value, path: &str) -> Result<Self, Vec<ConfigError>> {
+        let Some(object) = value.as_object() else {
+            return Err(vec![ConfigError::new(path, "expected an object")]);
+        };
+
+        let mut errors = Vec::new();
+        let mut tag = DEFAULT_TAG.to_string();
+        let mut listen = None;
+        let mut services = Vec::new();
+        let mut allow_remote = false;
+        for (key, value) in object {
+            let path = format!("{path}.{key}");
+            match key.as_str() {
+                "tag" => match value.as_str() {
+                    Some(x) if !x.is_empty() => tag = x.to_string(),
+                    _ => errors.push(ConfigError::new(&path, "expected a tag")),
+                },
+                "listen" => match value.as_str().map(str::parse::<SocketAddr>) {
+                    Some(Ok(x)) => listen = Some(x),
+                    _ => errors.push(ConfigError::new(&path, "expected an ip:port address")),
+                },
+                "services" => match value.as_array() {
+                    Some(names) => {
+                        for (i, name) in names.iter().enumerate() {
+                            match name.as_str().and_then(Service::from_name) {
+                                Some(x) => services.push(x),
+                                None => errors.push(ConfigError::new(
+                                    &format!("{path}[{i}]"),
+                                    "expected HandlerService or StatsService",
+                                )),
+                            }
+                        }
+                    }
+                    None => errors.push(ConfigError::new(&path, "expected an array")),
+                },
+                "allowRemote" => match value.as_bool() {
+                    Some(x) => allow_remote = x,
+                    None => errors.push(ConfigError::new(&path, "expected a boolean")),
+                },
+                _ => errors.push(ConfigError::new(&path, "unknown field")),
+            }
+        }
+
+        let listen_path = format!("{path}.listen");
+        match listen {
+            Some(x) if !allow_remote && !x.ip().is_loopback() => errors.push(ConfigError::new(
+                &listen_path,
+                format!(
+                    "{} is not a loopback address, the api has no authentication",
+                    x.ip()
+                ),
+            )),
+            Some(_) => {}
+            None if !object.contains_key("listen") => {
+                errors.push(ConfigError::new(&listen_path, "missing"))
+            }
+            None => {}
+        }
+
+        match (listen, errors.is_empty()) {
+            (Some(listen), true) => Ok(Self {
+                tag,
+                listen,
+                services,
+            }),
+            _ => Err(errors),
+        }
+    }
+}
+
+fn string(value: Field) -> Result<String, Status> {
+    let bytes = value.bytes().ok_or_else(malformed)?;
+    String::from_utf8(bytes.to_vec()).map_err(|_| malformed())
+}
+
+fn malformed() -> Status {
+    Status::new(Code::InvalidArgument, "malformed request")
+}
+
+// `xray.common.serial.TypedMessage`, a message and its full name
+fn typed_message(buf: &[u8]) -> Result<(String, &[u8]), Status> {
+    let (mut name, mut value) = (String::new(), &[][..]);
+    for field in Fields::new(buf) {
+        match field.map_err(|_| malformed())? {
+            (1, x) => name = string(x)?,
+            (2, x) => value = x.bytes().ok_or_else(malformed)?,
+            _ => {}
+        }
+    }
+    Ok((name, value))
+}
+
+// v2ray and xray name their messages differently, `v2ray.core.app.stats`
+// is `xray.app.stats`
+fn short_name(name: &str) -> &str {
+    name.strip_prefix("v2ray.core.")
+        .or_else(|| name.strip_prefix("xray."))
+        .unwrap_or(name)
+}
+
+fn stat(w: &mut Writer, name: &str, value: u64) {
+    let mut stat = Writer::default();
+    stat.string(1, name).uint(2, value);
+    w.bytes(1, &stat.0);
+}
+
+// v2ray's StatsService and the user operations of its HandlerService, on
+// the stats counters and the vmess user table. calls take and return the
+// protobuf messages, the transport is up to whoever serves them.
+pub struct Api {
+    stats: Rc<Stats>,
+    users: Rc<UserTable>,
+    services: Vec<Service>,
+}
+
+impl Api {
+    pub fn new(stats: Rc<Stats>, users: Rc<UserTable>) -> Self {
+        Self {
+            stats,
+            users,
+            services: vec![Service::Handler, Service::Stats],
+        }
+    }
+
+    pub fn with_services(mut self, services: Vec<Service>) -> Self {
+        self.services = services;
+        self
+    }
+
+    // `path` is the grpc method, `/xray.app.stats.command.StatsService/
+    // QueryStats` or its v2ray name
+    pub fn call(&self, path: &str, request: &[u8]) -> Result<Vec<u8>, Status> {
+        let unimplemented = || Status::new(Code::Unimplemented, format!("unknown method {path}"));
+        let (service, method) = path
+            .strip_prefix('/')
+            .and_then(|x| x.split_once('/'))
+            .ok_or_else(unimplemented)?;
+        let service = match short_name(service) {
+            "app.stats.command.StatsService" => Service::Stats,
+            "app.proxyman.command.HandlerService" => Service::Handler,
+            _ => return Err(unimplemented()),
+        };
+        if !self.services.contains(&service) {
+            return Err(unimplemented());
+        }
+        match (service, method) {
+            (Service::Stats, "GetStats") => self.get_stats(request),
+            (Service::Stats, "QueryStats") => self.query_stats(request),
+            (Service::Handler, "AlterInbound") => self.alter_inbound(request),
+            _ => Err(unimplemented()),
+        }
+    }
+
+    // GetStatsRequest { name = 1, reset = 2 }
+    fn get_stats(&self, request: &[u8]) -> Result<Vec<u8>, Status> {
+        let (mut name, mut reset) = (String::new(), false);
+        for field in Fields::new(request) {
+            match field.map_err(|_| malformed())? {
+                (1, x) => name = string(x)?,
+                (2, x) => reset = x.varint().ok_or_else(malformed)? != 0,
+                _ => {}
+            }
+        }
+        if self.stats.get(&name).is_none() {
+            return Err(Status::new(Code::NotFound, format!("{name} not found")));
+        }
+        let counter = self.stats.counter(&name);
+        let value = if reset {
+            counter.reset()
+        } else {
+            counter.get()
+        };
+        let mut w = Writer::default();
+        stat(&mut w, &name, value);
+        Ok(w.0)
+    }
+
+    // QueryStatsRequest { pattern = 1, reset = 2, patterns = 3 }, xray's
+    // `regexp` is not supported
+    fn query_stats(&self, request: &[u8]) -> Result<Vec<u8>, Status> {
+        let (mut patterns, mut reset) = (Vec::new(), false);
+        for field in Fields::new(request) {
+            match field.map_err(|_| malformed())? {
+                (1 | 3, x) => patterns.push(string(x)?),
+                (2, x) => reset = x.varint().ok_or_else(malformed)? != 0,
+                (4, x) if x.varint() != Some(0) => {
+                    return Err(Status::new(Code::Unimplemented, "regexp patterns"));
+                }
+                _ => {}
+            }
+        }
+        patterns.retain(|x| !x.is_empty());
+        if patterns.is_empty() {
+            patterns.push(String::new());
+        }
+        let mut w = Writer::default();
+        let mut names = Vec::new();
+        for pattern in &patterns {
+            for (name, value) in self.stats.query(pattern, reset) {
+                // a counter several patterns match is reported, and taken, once
+                if !names.contains(&name) {
+                    stat(&mut w, &name, value);
+                    names.push(name);
+                }
+            }
+        }
+        Ok(w.0)
+    }
+
+    // AlterInboundRequest { tag = 1, operation = 2 }, the operation adding
+    // or removing a user
+    fn alter_inbound(&self, request: &[u8]) -> Result<Vec<u8>, Status> {
+        let (mut tag, mut operation) = (String::new(), None);
+        for field in Fields::new(request) {
+            match field.map_err(|_| malformed())? {
+                (1, x) => tag = string(x)?,
+                (2, x) => operation = Some(typed_message(x.bytes().ok_or_else(malformed)?)?),
+                _ => {}
+            }
+        }
+        if tag != VMESS_TAG {
+            return Err(Status::new(
+                Code::NotFound,
+                format!("inbound {tag:?} not found, only users of {VMESS_TAG:?} are managed"),
+            ));
+        }
+        let Some((name, operation)) = operation else {
+            return Err(Status::new(Code::InvalidArgument, "no operation"));
+        };
+        match short_name(&name) {
+            "app.proxyman.command.AddUserOperation" => self.add_user(operation)?,
+            "app.proxyman.command.RemoveUserOperation" => self.remove_user(operation)?,
+            _ => {
+                return Err(Status::new(
+                    Code::Unimplemented,
+                    format!("operation {name}"),
+                ))
+            }
+        }
+        // AlterInboundResponse is empty
+        Ok(Vec::new())
+    }
+
+    // AddUserOperation { user = 1 }, a User { level = 1, email = 2,
+    // account = 3 } whose account is a vmess Account { id = 1 }
+    fn add_user(&self, operation: &[u8]) -> Result<(), Status> {
+        let mut user = &[][..];
+        for field in Fields::new(operation) {
+            if let (1, x) = field.map_err(|_| malformed())? {
+                user = x.bytes().ok_or_else(malformed)?;
+            }
+        }
+        let (mut level, mut email, mut account) = (0, String::new(), None);
+        for field in Fields::new(user) {
+            match field.map_err(|_| malformed())? {
+                (1, x) => level = x.varint().ok_or_else(malformed)?,
+                (2, x) => email = string(x)?,
+                (3, x) => account = Some(typed_message(x.bytes().ok_or_else(malformed)?)?),
+                _ => {}
+            }
+        }
+        let Some((name, account)) = account else {
+            return Err(Status::new(Code::InvalidArgument, "no account"));
+        };
+        if short_name(&name) != "proxy.vmess.Account" {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!("not a vmess account: {name}"),
+            ));
+        }
+        let mut id = String::new();
+        for field in Fields::new(account) {
+            if let (1, x) = field.map_err(|_| malformed())? {
+                id = string(x)?;
+            }
+        }
+        let uuid = Uuid::parse_str(&id)
+            .map_err(|e| Status::new(Code::InvalidArgument, format!("invalid id {id:?}: {e}")))?;
+        if email.is_empty() {
+            return Err(Status::new(Code::InvalidArgument, "no email"));
+        }
+        let level = u32::try_from(level).map_err(|_| malformed())?;
+        self.users
+            .add_user(uuid, &email, level)
+            .map_err(|e| Status::new(Code::Unknown, e.to_string()))
+    }
+
+    // RemoveUserOperation { email = 1 }. like v2ray, sessions already open
+    // keep running
+    fn remove_user(&self, operation: &[u8]) -> Result<(), Status> {
+        let mut email = String::new();
+        for field in Fields::new(operation) {
+            if let (1, x) = field.map_err(|_| malformed())? {
+                email = string(x)?;
+            }
+        }
+        self.users
+            .remove_user(&email, false)
+            .map(|_| ())
+            .map_err(|e| Status::new(Code::NotFound, e.to_string()))
+    }
+
+    // a grpc request body, length-prefixed messages of which unary calls
+    // have one, to the response body or the status for the trailers
+    pub fn handle_grpc(&self, path: &str, body: &[u8]) -> Result<Vec<u8>, Status> {
+        let request = match body {
+            [0, len @ ..] if len.len() >= 4 => {
+                let n = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+                len[4..].get(..n).ok_or_else(malformed)?
+            }
+            [1, ..] => return Err(Status::new(Code::Unimplemented, "compressed messages")),
+            _ => return Err(malformed()),
+        };
+        let response = self.call(path, request)?;
+        let mut body = vec![0];
+        body.extend_from_slice(&(response.len() as u32).to_be_bytes());
+        body.extend_from_slice(&response);
+        Ok(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const GET_STATS: &str = "/v2ray.core.app.stats.command.StatsService/GetStats";
+    const QUERY_STATS: &str = "/xray.app.stats.command.StatsService/QueryStats";
+    const ALTER_INBOUND: &str = "/v2ray.core.app.proxyman.command.HandlerService/AlterInbound";
+
+    fn api() -> Api {
+        let users = Rc::new(UserTable::new(Uuid::from_u128(1)).with_stats(Rc::default()));
+        Api::new(Rc::default(), users)
+    }
+
+    fn typed(name: &str, value: &[u8]) -> Vec<u8> {
+        let mut w = Writer::default();
+        w.string(1, name).bytes(2, value);
+        w.0
+    }
+
+    // an AlterInboundRequest adding a vmess user, as panels send it
+    fn add_user(tag: &str, email: &str, id: &str) -> Vec<u8> {
+        let mut account = Writer::default();
+        account.string(1, id).uint(2, 0);
+        let mut user = Writer::default();
+        user.uint(1, 0)
+            .string(2, email)
+            .bytes(3, &typed("xray.proxy.vmess.Account", &account.0));
+        let mut operation = Writer::default();
+        operation.bytes(1, &user.0);
+        let mut request = Writer::default();
+        request.string(1, tag).bytes(
+            2,
+            &typed("xray.app.proxyman.command.AddUserOperation", &operation.0),
+        );
+        request.0
+    }
+
+    fn stats(response: &[u8]) -> Vec<(String, u64)> {
+        Fields::new(response)
+            .map(|x| {
+                let stat = x.unwrap().1.bytes().unwrap();
+                let mut fields = Fields::new(stat).map(|x| x.unwrap().1);
+                let name = string(fields.next().unwrap()).unwrap();
+                (name, fields.next().and_then(Field::varint).unwrap_or(0))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_add_and_remove_users() {
+        let api = api();
+        let alice = "96850032-1b92-46e9-a4f2-b99631456894";
+        let response = api.call(
+            ALTER_INBOUND,
+            &add_user("vmess", "alice@example.com", alice),
+        );
+        assert_eq!(response.unwrap(), b"");
+        let users = api.users.list_users();
+        assert_eq!(users[0].email, "alice@example.com");
+        assert_eq!(users[0].uuid.to_string(), alice);
+
+        let e = api.call(
+            ALTER_INBOUND,
+            &add_user("vmess", "alice@example.com", alice),
+        );
+        assert_eq!(e.unwrap_err().code, Code::Unknown);
+        let e = api.call(ALTER_INBOUND, &add_user("trojan", "bob@example.com", alice));
+        assert_eq!(e.unwrap_err().code, Code::NotFound);
+        let e = api.call(ALTER_INBOUND, &add_user("vmess", "bob@example.com", "nope"));
+        assert_eq!(e.unwrap_err().code, Code::InvalidArgument);
+
+        let mut remove = Writer::default();
+        remove.string(1, "alice@example.com");
+        let operation = typed(
+            "v2ray.core.app.proxyman.command.RemoveUserOperation",
+            &remove.0,
+        );
+        let mut request = Writer::default();
+        request.string(1, "vmess").bytes(2, &operation);
+        api.call(ALTER_INBOUND, &request.0).unwrap();
+        assert!(api.users.list_users().is_empty());
+        let e = api.call(ALTER_INBOUND, &request.0).unwrap_err();
+        assert_eq!(e.code, Code::NotFound);
+    }
+
+    #[test]
+    fn test_stats() {
+        let api = api();
+        api.stats
+            .counter("user>>>alice@example.com>>>traffic>>>uplink")
+            .add(100);
+        api.stats
+            .counter("user>>>alice@example.com>>>traffic>>>downlink")
+            .add(200);
+        api.stats
+            .counter("inbound>>>vmess>>>traffic>>>uplink")
+            .add(300);
+
+        let mut request = Writer::default();
+        request.string(1, "user>>>alice@example.com>>>traffic>>>downlink");
+        let response = api.call(GET_STATS, &request.0).unwrap();
+        assert_eq!(
+            stats(&response),
+            [(
+                "user>>>alice@example.com>>>traffic>>>downlink".to_string(),
+                200
+            )]
+        );
+        let mut request = Writer::default();
+        request.string(1, "user>>>bob@example.com>>>traffic>>>downlink");
+        assert_eq!(
+            api.call(GET_STATS, &request.0).unwrap_err().code,
+            Code::NotFound
+        );
+
+        // with reset the values are taken
+        let mut request = Writer::default();
+        request.string(1, "user>>>").uint(2, 1);
+        let response = api.call(QUERY_STATS, &request.0).unwrap();
+        assert_eq!(
+            stats(&response),
+            [
+                (
+                    "user>>>alice@example.com>>>traffic>>>downlink".to_string(),
+                    200
+                ),
+                (
+                    "user>>>alice@example.com>>>traffic>>>uplink".to_string(),
+                    100
+                ),
+            ]
+        );
+        let response = api.call(QUERY_STATS, &request.0).unwrap();
+        assert!(stats(&response).iter().all(|x| x.1 == 0));
+        let response = api.call(QUERY_STATS, b"").unwrap();
+        assert_eq!(stats(&response).len(), 3);
+
+        let mut request = Writer::default();
+        request.string(1, "user").uint(4, 1);
+        assert_eq!(
+            api.call(QUERY_STATS, &request.0).unwrap_err().code,
+            Code::Unimplemented
+        );
+    }
+
+    #[test]
+    fn test_grpc() {
+        let api = api().with_services(vec![Service::Stats]);
+        api.stats
+            .counter("inbound>>>vmess>>>traffic>>>uplink")
+            .add(5);
+        let response = api.handle_grpc(QUERY_STATS, &[0, 0, 0, 0, 0]).unwrap();
+        let len = u32::from_be_bytes(response[1..5].try_into().unwrap()) as usize;
+        assert_eq!(response.len(), 5 + len);
+        assert_eq!(stats(&response[5..]).len(), 1);
+
+        assert_eq!(
+            api.handle_grpc(QUERY_STATS, &[0, 0, 0, 0, 9])
+                .unwrap_err()
+                .code,
+            Code::InvalidArgument
+        );
+        let e = api
+            .handle_grpc(ALTER_INBOUND, &[0, 0, 0, 0, 0])
+            .unwrap_err();
+        assert_eq!(e.code, Code::Unimplemented);
+        let e = api.call("/xray.app.stats.command.StatsService/GetSysStats", b"");
+        assert_eq!(e.unwrap_err().code, Code::Unimplemented);
+    }
+
+    #[test]
+    fn test_api_config() {
+        let config = ApiConfig::from_json(
+            &json!({"listen": "127.0.0.1:10085", "services": ["StatsService"]}),
+            "API",
+        )
+        .unwrap();
+        assert_eq!(config.tag, "api");
+        assert_eq!(config.services, [Service::Stats]);
+
+        let errors = ApiConfig::from_json(&json!({"listen": "0.0.0.0:10085"}), "API").unwrap_err();
+        assert_eq!(errors[0].path, "API.listen");
+        assert!(
+            errors[0].message.contains("not a loopback"),
+            "{}",
+            errors[0].message
+        );
+        let config =
+            ApiConfig::from_json(&json!({"listen": "[::]:10085", "allowRemote": true}), "API");
+        assert!(config.is_ok());
+
+        let errors =
+            ApiConfig::from_json(&json!({"services": ["LoggerService"], "port": 1}), "API")
+                .unwrap_err();
+        let paths: Vec<_> = errors.iter().map(|x| x.path.as_str()).collect();
+        assert_eq!(paths, ["API.port", "API.services[0]", "API.listen"]);
+    }
+}
@@ -0,0 +1,899 @@
+use super::access::{self, ACCESS_TARGET};
+use super::fakedns::FakeDns;
+use super::metrics::{self, Metrics};
+use super::router::{Matched, Resolve, Route, Router};
+use super::sniff::{self, PeekStream, Sniffing};
+use super::stats::{self, Counter, CountingStream, Direction, Stats};
+use crate::common::ratelimit::{RateLimit, ThrottledStream};
+use crate::common::time;
+use crate::config::Config;
+use crate::outbound::{balancer, health};
+use crate::outbound::{AsyncStream, Balancer, BlockOutbound, DirectOutbound, DnsOutbound, Network};
+use crate::outbound::uot::{UotStream, UotVersion};
+use crate::outbound::{OutboundManager, ProxyDialer, Target};
+
+use futures_util::future::{self, Either};
+use std::io;
+use std::net::IpAddr;
+use std::pin::pin;
+use std::rc::Rc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tracing::field::Empty;
+use tracing::Instrument;
+use worker::*;
+
+pub const DEFAULT_OUTBOUND_TAG: &str = "direct";
+pub const BLOCK_OUTBOUND_TAG: &str = "block";
+// only registered along with the `DNS` binding
+pub const DNS_OUTBOUND_TAG: &str = "dns";
+
+// tags that `from_config` registers, routing rules may only point at these
+pub const OUTBOUND_TAGS: &[&str] = &[DEFAULT_OUTBOUND_TAG, BLOCK_OUTBOUND_TAG, DNS_OUTBOUND_TAG];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    // protocol that accepted the connection: vless, vmess, trojan or ss
+    pub inbound_tag: String,
+    pub source: Option<String>,
+    pub sniffed_host: Option<String>,
+    pub target: Target,
+    // from accepting the connection to knowing its target
+    pub handshake: Duration,
+    // the user the inbound authenticated, when it tells users apart.
+    // traffic is counted for it rather than for the dispatcher's user.
+    pub user: Option<String>,
+}
+
+pub struct Dispatcher {
+    outbounds: Rc<OutboundManager>,
+    balancers: Vec<(String, Rc<Balancer>)>,
+    router: Rc<Router>,
+    resolver: Option<Box<dyn Resolve>>,
+    sniffing: Option<Sniffing>,
+    fakedns: Option<Rc<FakeDns>>,
+    ratelimit: Option<Rc<RateLimit>>,
+    // and the user traffic is counted for
+    stats: Option<(Rc<Stats>, String)>,
+    metrics: Option<Rc<Metrics>>,
+    default_tag: String,
+}
+
+impl Dispatcher {
+    pub fn new(outbounds: OutboundManager, default_tag: &str) -> Self {
+        Self {
+            outbounds: Rc::new(outbounds),
+            balancers: Vec::new(),
+            router: Rc::default(),
+            resolver: None,
+            sniffing: None,
+            fakedns: None,
+            ratelimit: None,
+            stats: None,
+            metrics: None,
+            default_tag: default_tag.to_string(),
+        }
+    }
+
+    pub fn with_router(mut self, router: Rc<Router>) -> Self {
+        self.router = router;
+        self
+    }
+
+    // used by the IPIfNonMatch and IPOnDemand domain strategies
+    pub fn with_resolver(mut self, resolver: Box<dyn Resolve>) -> Self {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    pub fn with_sniffing(mut self, sniffing: Option<Sniffing>) -> Self {
+        self.sniffing = sniffing;
+        self
+    }
+
+    // answers the client's own dns queries and maps connections to the
+    // handed out addresses back to their domains
+    pub fn with_fakedns(mut self, fakedns: Rc<FakeDns>) -> Self {
+        self.fakedns = Some(fakedns);
+        self
+    }
+
+    // throttles the client side of every dispatched connection
+    pub fn with_ratelimit(mut self, ratelimit: Rc<RateLimit>) -> Self {
+        self.ratelimit = Some(ratelimit);
+        self
+    }
+
+    // counts traffic per user, inbound and outbound tag
+    pub fn with_stats(mut self, stats: Rc<Stats>, user: &str) -> Self {
+        self.stats = Some((stats, user.to_string()));
+        self
+    }
+
+    // active connections and failures per inbound and outbound tag
+    pub fn with_metrics(mut self, metrics: Rc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    // routing rules naming `tag` through `balancerTag` get one of the
+    // balancer's outbounds
+    pub fn with_balancer(mut self, tag: &str, balancer: Rc<Balancer>) -> Self {
+        self.balancers.push((tag.to_string(), balancer));
+        self
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        let mut outbounds = OutboundManager::default();
+        let block = BlockOutbound::default().with_response(config.blackhole);
+        outbounds.add(BLOCK_OUTBOUND_TAG, Box::new(block));
+        if let Some(dns) = config.dns.clone() {
+            outbounds.add(DNS_OUTBOUND_TAG, Box::new(DnsOutbound::new(dns)));
+        }
+        let fallback = (config.proxy_addr.clone(), config.proxy_port);
+        let mut direct = DirectOutbound::new(Some(fallback)).with_policy(config.policy);
+        if let Some(dns) = config.dns.clone() {
+            direct = direct.with_domain_strategy(config.freedom.domain_strategy, dns);
+        }
+        if let Some(x) = config.freedom.redirect.clone() {
+            direct = direct.with_redirect(x);
+        }
+        if let Some(x) = config.freedom.fragment {
+            direct = direct.with_fragment(x);
+        }
+        // outbounds are registered after the ones they dial through
+        if let Some(tag) = config.freedom.dialer_proxy.as_deref() {
+            if let Some(x) = outbounds.get_shared(tag) {
+                direct = direct.with_dialer(Rc::new(ProxyDialer::new(tag, x)));
+            }
+        }
+        outbounds.add(DEFAULT_OUTBOUND_TAG, Box::new(direct));
+
+        // one uuid is served, it stands in for v2ray's user email
+        let mut dispatcher = Self::new(outbounds, DEFAULT_OUTBOUND_TAG)
+            .with_router(config.router.clone())
+            .with_sniffing(config.sniffing.clone())
+            .with_stats(stats::shared(), &config.uuid.to_string())
+            .with_metrics(metrics::shared());
+        if let Some(x) = config.fakedns.clone() {
+            dispatcher = dispatcher.with_fakedns(x);
+        }
+        if let Some(x) = config.ratelimit.clone() {
+            dispatcher = dispatcher.with_ratelimit(x);
+        }
+        for x in config.router.balancers() {
+            let members = x.members(dispatcher.outbounds.iter().map(|(tag, _)| tag));
+            let (balancer, created) = balancer::shared(&x.tag, members, x.strategy, x.sticky);
+            if let Some(probe) = x.probe.clone().filter(|_| created) {
+                let outbounds = dispatcher.outbounds.clone();
+                health::spawn_checker(Rc::downgrade(&balancer), outbounds, probe);
+            }
+            dispatcher = dispatcher.with_balancer(&x.tag, balancer);
+        }
+        match config.dns.clone() {
+            Some(x) => dispatcher.with_resolver(Box::new(x)),
+            None => dispatcher,
+        }
+    }
+
+    // who the traffic of `metadata` is counted for
+    fn user<'a>(&'a self, metadata: &'a Metadata) -> Option<&'a str> {
+        let (_, user) = self.stats.as_ref()?;
+        Some(metadata.user.as_deref().unwrap_or(user))
+    }
+
+    pub fn outbounds(&self) -> &OutboundManager {
+        &self.outbounds
+    }
+
+    // the first matching routing rule, or the default outbound
+    pub async fn select(&self, metadata: &Metadata) -> &str {
+        self.route(metadata).await.tag
+    }
+
+    // the route picked for `metadata`. private destinations only go direct
+    // when there is a direct outbound to take them.
+    pub async fn route(&self, metadata: &Metadata) -> Route<'_> {
+        match self.router.route(metadata, self.resolver.as_deref()).await {
+            Some(x) if x.matched != Matched::Private || self.outbounds.get(x.tag).is_some() => x,
+            _ => Route::new(Matched::Default, &self.default_tag),
+        }
+    }
+
+    pub async fn dispatch(&self, metadata: &Metadata, stream: &mut dyn AsyncStream) -> Result<()> {
+        // sing-box's udp over tcp, from a client whose upstream only carries
+        // tcp. what it frames is routed and relayed as the udp it is.
+        if let Some(version) = UotVersion::of(&metadata.target) {
+            let (target, mut stream) = UotStream::accept(&mut *stream, version).await?;
+            let metadata = Metadata {
+                target,
+                ..metadata.clone()
+            };
+            return self.dispatch_logged(&metadata, &mut stream).await;
+        }
+        self.dispatch_logged(metadata, stream).await
+    }
+
+    // everything about the connection is logged within its span, which
+    // ends with one access log event
+    async fn dispatch_logged(
+        &self,
+        metadata: &Metadata,
+        stream: &mut dyn AsyncStream,
+    ) -> Result<()> {
+        let span = tracing::info_span!(
+            "conn",
+            id = access::connection_id(),
+            inbound = metadata.inbound_tag.as_str(),
+            source = metadata.source.as_deref().unwrap_or(""),
+            target = %metadata.target,
+            sniffed = Empty,
+            rule = Empty,
+            outbound = Empty,
+            user = self.user(metadata).map(access::fingerprint),
+        );
+        let started = time::now();
+        let (up, down) = (Rc::new(Counter::default()), Rc::new(Counter::default()));
+        let relay = async {
+            tracing::info!(
+                handshake_ms = metadata.handshake.as_millis() as u64,
+                "handshake done"
+            );
+            let counted = (up.clone(), down.clone());
+            let result = self.relay(metadata, stream, counted, &span).await;
+            let error = result.as_ref().err();
+            if let Some(e) = error {
+                tracing::warn!(kind = metrics::error_kind(e), error = %e, "connection failed");
+            }
+            tracing::info!(
+                target: ACCESS_TARGET,
+                up = up.get(),
+                down = down.get(),
+                duration_ms = time::now().saturating_sub(started).as_millis() as u64,
+                status = if error.is_some() { "error" } else { "ok" },
+                kind = error.map(metrics::error_kind),
+                "connection closed"
+            );
+            result
+        };
+        relay.instrument(span.clone()).await
+    }
+
+    // `counted` collects the bytes relayed for this connection alone, the
+    // route taken is recorded on `span`
+    async fn relay(
+        &self,
+        metadata: &Metadata,
+        stream: &mut dyn AsyncStream,
+        counted: (Rc<Counter>, Rc<Counter>),
+        span: &tracing::Span,
+    ) -> Result<()> {
+        let (up, down) = match &self.ratelimit {
+            Some(x) => x.buckets(&metadata.inbound_tag),
+            None => Default::default(),
+        };
+        let mut stream = PeekStream::new(ThrottledStream::new(stream, up, down));
+        let mut metadata = metadata.clone();
+        if let Some(fakedns) = self.fakedns.as_deref() {
+            let target = &mut metadata.target;
+            // one read is one query, same as the udp relay. whatever is not
+            // answered here goes out untouched.
+            if target.network == Network::Udp && target.port == 53 && stream.fill().await? > 0 {
+                if let Some(response) = fakedns.answer(stream.peeked()) {
+                    stream.write_all(&response).await?;
+                    return Ok(());
+                }
+            }
+            if let Some(domain) = target.addr.parse::<IpAddr>().ok().and_then(|x| fakedns.domain(x)) {
+                tracing::debug!(fake = target.addr.as_str(), domain = domain.as_str(), "fake address");
+                target.addr = domain;
+            }
+        }
+        if let Some(sniffing) = self.sniffing.as_ref().filter(|x| x.applies(&metadata)) {
+            let sniffed = sniff::sniff(&mut stream, &sniffing.protocols, sniff::SNIFF_TIMEOUT).await;
+            if let Some((protocol, host)) = sniffed {
+                tracing::debug!(protocol = ?protocol, host = host.as_str(), "sniffed");
+                span.record("sniffed", host.as_str());
+                if !sniffing.route_only {
+                    metadata.target.addr = host.clone();
+                }
+                metadata.sniffed_host = Some(host);
+            }
+        }
+
+        let route = self.route(&metadata).await;
+        let mut tag = route.tag;
+        match route.matched {
+            Matched::Rule(i) => span.record("rule", format!("rules[{i}]")),
+            Matched::Private => span.record("rule", "private"),
+            Matched::Default => span.record("rule", "default"),
+        };
+        if let Some((_, balancer)) = self.balancers.iter().find(|(t, _)| t == tag) {
+            let picked = balancer.select_for(metadata.source.as_deref(), &metadata.target);
+            tracing::debug!(balancer = tag, strategy = ?balancer.strategy(), picked, "balanced");
+            tag = picked;
+        }
+        span.record("outbound", tag);
+        let outbound = self
+            .outbounds
+            .get(tag)
+            .ok_or_else(|| Error::RustError(format!("outbound not found: {tag}")))?;
+
+        let (mut uplink, mut downlink) = match (&self.stats, self.user(&metadata)) {
+            (Some((stats, _)), Some(user)) => {
+                let counters = |direction| {
+                    vec![
+                        stats.counter(&stats::user_traffic(user, direction)),
+                        stats.counter(&stats::inbound_traffic(&metadata.inbound_tag, direction)),
+                        stats.counter(&stats::outbound_traffic(tag, direction)),
+                    ]
+                };
+                (counters(Direction::Uplink), counters(Direction::Downlink))
+            }
+            _ => Default::default(),
+        };
+        let answered = counted.1.clone();
+        uplink.push(counted.0);
+        downlink.push(counted.1);
+        let mut stream = CountingStream::new(stream, uplink, downlink);
+
+        tracing::info!("routed");
+        let _active = self.metrics.as_ref().map(|x| x.connection(&metadata.inbound_tag, tag));
+        let dispatch = outbound.dispatch(&metadata.target, &mut stream);
+        let result = match route.timeout {
+            Some(x) => answered_within(x, &answered, dispatch).await,
+            None => dispatch.await,
+        };
+        if let (Some(metrics), Err(e)) = (&self.metrics, &result) {
+            metrics.outbound_error(tag, e);
+        }
+        result
+    }
+}
+
+// the route's deadline for the outbound to dial and send back its first
+// byte, counted by `answered`. after that the connection has all the time
+// the policy gives it.
+async fn answered_within<F>(timeout: Duration, answered: &Counter, dispatch: F) -> Result<()>
+where
+    F: std::future::Future<Output = Result<()>>,
+{
+    let deadline = async {
+        time::sleep(timeout).await;
+        if answered.get() > 0 {
+            future::pending::<()>().await;
+        }
+    };
+    match future::select(pin!(dispatch), pin!(deadline)).await {
+        Either::Left((x, _)) => x,
+        Either::Right(_) => Err(Error::Io(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("no response in {timeout:?}"),
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbound::Outbound;
+    use async_trait::async_trait;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    type Received = Rc<RefCell<Vec<(Target, Vec<u8>)>>>;
+
+    struct MockOutbound(Received);
+
+    #[async_trait(?Send)]
+    impl Outbound for MockOutbound {
+        async fn dispatch(&self, target: &Target, stream: &mut dyn AsyncStream) -> Result<()> {
+            let mut data = Vec::new();
+            stream.read_to_end(&mut data).await?;
+            self.0.borrow_mut().push((target.clone(), data));
+            Ok(())
+        }
+    }
+
+    fn metadata(port: u16) -> Metadata {
+        Metadata {
+            inbound_tag: "vless".to_string(),
+            source: None,
+            sniffed_host: None,
+            target: Target::new("example.com".to_string(), port, Network::Tcp),
+            handshake: Duration::ZERO,
+            user: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_by_tag() {
+        let default = Received::default();
+        let other = Received::default();
+        let mut outbounds = OutboundManager::default();
+        outbounds.add("other", Box::new(MockOutbound(other.clone())));
+        outbounds.add("mock", Box::new(MockOutbound(default.clone())));
+        let dispatcher = Dispatcher::new(outbounds, "mock");
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        drop(client);
+        dispatcher.dispatch(&metadata(80), &mut server).await.unwrap();
+
+        assert!(other.borrow().is_empty());
+        assert_eq!(
+            *default.borrow(),
+            [(metadata(80).target, b"GET / HTTP/1.1\r\n\r\n".to_vec())]
+        );
+
+        let tags: Vec<_> = dispatcher.outbounds().iter().map(|(tag, _)| tag).collect();
+        assert_eq!(tags, ["other", "mock"]);
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_by_rule() {
+        let default = Received::default();
+        let other = Received::default();
+        let mut outbounds = OutboundManager::default();
+        outbounds.add("other", Box::new(MockOutbound(other.clone())));
+        outbounds.add("mock", Box::new(MockOutbound(default.clone())));
+        let router = Router::from_json(
+            &serde_json::json!({"rules": [{"port": "443", "outboundTag": "other"}]}),
+            "ROUTING",
+        )
+        .unwrap();
+        let dispatcher = Dispatcher::new(outbounds, "mock").with_router(Rc::new(router));
+
+        for port in [80, 443] {
+            let (client, mut server) = tokio::io::duplex(1024);
+            drop(client);
+            dispatcher.dispatch(&metadata(port), &mut server).await.unwrap();
+        }
+
+        assert_eq!(default.borrow()[..], [(metadata(80).target, Vec::new())]);
+        assert_eq!(other.borrow()[..], [(metadata(443).target, Vec::new())]);
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_balanced() {
+        let received: Vec<_> = (0..3).map(|_| Received::default()).collect();
+        let mut outbounds = OutboundManager::default();
+        outbounds.add("mock", Box::new(MockOutbound(Received::default())));
+        for (tag, x) in ["proxy-a", "proxy-b", "proxy-c"].iter().zip(&received) {
+            outbounds.add(tag, Box::new(MockOutbound(x.clone())));
+        }
+        let router = Router::from_json(
+            &serde_json::json!({
+                "balancers": [{"tag": "lb", "selector": ["proxy-"], "strategy": {"type": "roundRobin"}}],
+                "rules": [{"port": "443", "balancerTag": "lb"}],
+            }),
+            "ROUTING",
+        )
+        .unwrap();
+        let config = &router.balancers()[0];
+        let members = config.members(outbounds.iter().map(|(tag, _)| tag));
+        let balancer = Rc::new(Balancer::new(members, config.strategy));
+        let dispatcher = Dispatcher::new(outbounds, "mock")
+            .with_router(Rc::new(router))
+            .with_balancer("lb", balancer.clone());
+
+        let dispatch = || async {
+            let (client, mut server) = tokio::io::duplex(1024);
+            drop(client);
+            dispatcher.dispatch(&metadata(443), &mut server).await.unwrap();
+        };
+        for _ in 0..6 {
+            dispatch().await;
+        }
+        assert!(received.iter().all(|x| x.borrow().len() == 2));
+
+        // a failing outbound is left out until it recovers
+        for _ in 0..3 {
+            balancer.report_failure("proxy-b");
+        }
+        for _ in 0..4 {
+            dispatch().await;
+        }
+        let counts: Vec<_> = received.iter().map(|x| x.borrow().len()).collect();
+        assert_eq!(counts, [4, 2, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_sticky() {
+        let received: Vec<_> = (0..3).map(|_| Received::default()).collect();
+        let mut outbounds = OutboundManager::default();
+        outbounds.add("mock", Box::new(MockOutbound(Received::default())));
+        for (tag, x) in ["proxy-a", "proxy-b", "proxy-c"].iter().zip(&received) {
+            outbounds.add(tag, Box::new(MockOutbound(x.clone())));
+        }
+        let router = Router::from_json(
+            &serde_json::json!({
+                "balancers": [{"tag": "lb", "selector": ["proxy-"], "strategy": {"type": "random", "settings": {"sticky": true}}}],
+                "rules": [{"port": "443", "balancerTag": "lb"}],
+            }),
+            "ROUTING",
+        )
+        .unwrap();
+        let config = &router.balancers()[0];
+        let members = config.members(outbounds.iter().map(|(tag, _)| tag));
+        let balancer = Balancer::new(members, config.strategy).with_sticky(config.sticky.unwrap());
+        let dispatcher = Dispatcher::new(outbounds, "mock")
+            .with_router(Rc::new(router))
+            .with_balancer("lb", Rc::new(balancer));
+
+        let mut metadata = metadata(443);
+        metadata.source = Some("198.51.100.7".to_string());
+        for _ in 0..10 {
+            let (client, mut server) = tokio::io::duplex(1024);
+            drop(client);
+            dispatcher.dispatch(&metadata, &mut server).await.unwrap();
+        }
+        let counts: Vec<_> = received.iter().map(|x| x.borrow().len()).collect();
+        assert!(counts.contains(&10), "{counts:?}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_dispatch_sniffed() {
+        let received = Received::default();
+        let request = b"GET / HTTP/1.1\r\nHost: www.example.org\r\n\r\n";
+
+        for route_only in [false, true] {
+            let mut outbounds = OutboundManager::default();
+            outbounds.add("mock", Box::new(MockOutbound(received.clone())));
+            let dispatcher = Dispatcher::new(outbounds, "mock").with_sniffing(Some(Sniffing {
+                protocols: vec![sniff::Protocol::Http],
+                route_only,
+                inbound_tags: None,
+            }));
+
+            let (mut client, mut server) = tokio::io::duplex(1024);
+            client.write_all(request).await.unwrap();
+            drop(client);
+            dispatcher.dispatch(&metadata(80), &mut server).await.unwrap();
+        }
+
+        // destination override, then routing only. the outbound still gets
+        // the request bytes that were peeked at.
+        let received = received.borrow();
+        assert_eq!(received[0].0.addr, "www.example.org");
+        assert_eq!(received[1].0.addr, "example.com");
+        assert!(received.iter().all(|(_, data)| data == request));
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_fakedns() {
+        use hickory_proto::op::{Message, Query};
+        use hickory_proto::rr::{Name, RData, RecordType};
+
+        let received = Received::default();
+        let mut outbounds = OutboundManager::default();
+        outbounds.add("mock", Box::new(MockOutbound(received.clone())));
+        let fakedns = FakeDns::from_json(
+            &serde_json::json!({"ipPool": "198.18.0.0/15", "exclude": ["full:real.example.com"]}),
+            "FAKEDNS",
+        )
+        .unwrap();
+        let dispatcher = Dispatcher::new(outbounds, "mock").with_fakedns(Rc::new(fakedns));
+
+        let query = |domain: &str| {
+            let name = Name::from_ascii(domain).unwrap();
+            let mut message = Message::new();
+            message.add_query(Query::query(name, RecordType::A));
+            message.to_vec().unwrap()
+        };
+        let dns = Target::new("8.8.8.8".to_string(), 53, Network::Udp);
+        let udp = |target: &Target| Metadata { target: target.clone(), ..metadata(53) };
+
+        // answered without reaching an outbound
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&query("www.example.com.")).await.unwrap();
+        dispatcher.dispatch(&udp(&dns), &mut server).await.unwrap();
+        let mut response = vec![0u8; 512];
+        let n = client.read(&mut response).await.unwrap();
+        let response = Message::from_vec(&response[..n]).unwrap();
+        let Some(RData::A(ip)) = response.answers()[0].data() else {
+            panic!("unexpected answer {response:?}");
+        };
+        assert!(received.borrow().is_empty());
+
+        // excluded domains reach the outbound as they were sent
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&query("real.example.com.")).await.unwrap();
+        drop(client);
+        dispatcher.dispatch(&udp(&dns), &mut server).await.unwrap();
+        assert_eq!(received.borrow()[0], (dns.clone(), query("real.example.com.")));
+
+        // a connection to the fake address goes to the domain instead
+        let (client, mut server) = tokio::io::duplex(1024);
+        drop(client);
+        let mut fake = metadata(443);
+        fake.target.addr = ip.to_string();
+        dispatcher.dispatch(&fake, &mut server).await.unwrap();
+        assert_eq!(received.borrow()[1].0.addr, "www.example.com");
+        assert_eq!(received.borrow()[1].0.port, 443);
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_counted() {
+        // sends a fixed size response to whatever it was sent
+        struct Download;
+
+        #[async_trait(?Send)]
+        impl Outbound for Download {
+            async fn dispatch(&self, _: &Target, stream: &mut dyn AsyncStream) -> Result<()> {
+                let mut data = Vec::new();
+                stream.read_to_end(&mut data).await?;
+                stream.write_all(&vec![7u8; 100_000]).await?;
+                Ok(())
+            }
+        }
+
+        let mut outbounds = OutboundManager::default();
+        outbounds.add("mock", Box::new(Download));
+        let stats = Rc::new(Stats::default());
+        let dispatcher = Dispatcher::new(outbounds, "mock").with_stats(stats.clone(), "user-1");
+
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        let download = async {
+            client.write_all(&[1u8; 12_345]).await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut data = Vec::new();
+            client.read_to_end(&mut data).await.unwrap();
+            data.len()
+        };
+        let upload = async {
+            dispatcher.dispatch(&metadata(443), &mut server).await.unwrap();
+            drop(server);
+        };
+        let (n, _) = tokio::join!(download, upload);
+        assert_eq!(n, 100_000);
+
+        let traffic: Vec<_> = stats.query(">>>traffic>>>", false);
+        assert_eq!(
+            traffic,
+            [
+                ("inbound>>>vless>>>traffic>>>downlink".to_string(), 100_000),
+                ("inbound>>>vless>>>traffic>>>uplink".to_string(), 12_345),
+                ("outbound>>>mock>>>traffic>>>downlink".to_string(), 100_000),
+                ("outbound>>>mock>>>traffic>>>uplink".to_string(), 12_345),
+                ("user>>>user-1>>>traffic>>>downlink".to_string(), 100_000),
+                ("user>>>user-1>>>traffic>>>uplink".to_string(), 12_345),
+            ]
+        );
+
+        // billing takes the counters once
+        let taken = stats.query("user>>>user-1>>>", true);
+        assert_eq!(taken.iter().map(|x| x.1).sum::<u64>(), 112_345);
+        assert!(stats.query("user>>>user-1>>>", true).iter().all(|x| x.1 == 0));
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_counts_authenticated_user() {
+        let mut outbounds = OutboundManager::default();
+        outbounds.add("mock", Box::new(MockOutbound(Received::default())));
+        let stats = Rc::new(Stats::default());
+        let dispatcher = Dispatcher::new(outbounds, "mock").with_stats(stats.clone(), "binding");
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&[1u8; 300]).await.unwrap();
+        drop(client);
+        let metadata = Metadata {
+            user: Some("alice@example.com".to_string()),
+            ..metadata(443)
+        };
+        dispatcher.dispatch(&metadata, &mut server).await.unwrap();
+
+        let alice = stats::user_traffic("alice@example.com", Direction::Uplink);
+        assert_eq!(stats.get(&alice), Some(300));
+        assert!(stats.query("user>>>binding>>>", false).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_metrics() {
+        // reports how many connections it sees while it runs
+        struct Active(Rc<Metrics>, Rc<RefCell<Option<String>>>);
+
+        #[async_trait(?Send)]
+        impl Outbound for Active {
+            async fn dispatch(&self, _: &Target, _: &mut dyn AsyncStream) -> Result<()> {
+                let text = self.0.render(&Stats::default(), None, false);
+                *self.1.borrow_mut() = Some(text);
+                Ok(())
+            }
+        }
+
+        let metrics = Rc::new(Metrics::default());
+        let seen = Rc::new(RefCell::new(None));
+        let mut outbounds = OutboundManager::default();
+        outbounds.add("mock", Box::new(Active(metrics.clone(), seen.clone())));
+        outbounds.add("block", Box::new(BlockOutbound::default()));
+        let dispatcher = Dispatcher::new(outbounds, "mock").with_metrics(metrics.clone());
+
+        let (_client, mut server) = tokio::io::duplex(1024);
+        dispatcher.dispatch(&metadata(443), &mut server).await.unwrap();
+        let during = seen.borrow_mut().take().unwrap();
+        assert!(during.contains(r#"siren_outbound_active_connections{tag="mock"} 1"#));
+        let after = metrics.render(&Stats::default(), None, false);
+        assert!(after.contains(r#"siren_outbound_active_connections{tag="mock"} 0"#));
+
+        let dispatcher = Dispatcher::new(Rc::into_inner(dispatcher.outbounds).unwrap(), "block")
+            .with_metrics(metrics.clone());
+        assert!(dispatcher.dispatch(&metadata(443), &mut server).await.is_err());
+        let after = metrics.render(&Stats::default(), None, false);
+        assert!(after.contains(r#"siren_outbound_errors_total{tag="block",kind="blocked"} 1"#));
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_private_direct() {
+        let dispatch = |routing: serde_json::Value, addr: &str| {
+            let (direct, proxy) = (Received::default(), Received::default());
+            let mut outbounds = OutboundManager::default();
+            outbounds.add("proxy", Box::new(MockOutbound(proxy.clone())));
+            outbounds.add(DEFAULT_OUTBOUND_TAG, Box::new(MockOutbound(direct.clone())));
+            let router = Router::from_json(&routing, "ROUTING").unwrap();
+            let dispatcher = Dispatcher::new(outbounds, "proxy").with_router(Rc::new(router));
+            let mut metadata = metadata(443);
+            metadata.target.addr = addr.to_string();
+            async move {
+                let (client, mut server) = tokio::io::duplex(1024);
+                drop(client);
+                dispatcher.dispatch(&metadata, &mut server).await.unwrap();
+                (direct.borrow().len(), proxy.borrow().len())
+            }
+        };
+
+        let private = ["10.0.0.1", "127.0.0.1", "169.254.1.1", "239.255.255.250", "fe80::1"];
+        for addr in private.into_iter().chain(["nas.local"]) {
+            assert_eq!(dispatch(serde_json::json!({}), addr).await, (1, 0), "{addr}");
+        }
+        assert_eq!(dispatch(serde_json::json!({}), "8.8.8.8").await, (0, 1));
+        let off = serde_json::json!({"routePrivateDirect": false});
+        assert_eq!(dispatch(off, "10.0.0.1").await, (0, 1));
+        // a rule of the config's own comes first
+        let rule = serde_json::json!({"rules": [{"ip": "10.0.0.0/8", "outboundTag": "proxy"}]});
+        assert_eq!(dispatch(rule, "10.0.0.1").await, (0, 1));
+
+        // without a direct outbound the default takes them
+        let proxy = Received::default();
+        let mut outbounds = OutboundManager::default();
+        outbounds.add("proxy", Box::new(MockOutbound(proxy.clone())));
+        let dispatcher = Dispatcher::new(outbounds, "proxy");
+        let mut metadata = metadata(443);
+        metadata.target.addr = "10.0.0.1".to_string();
+        let (client, mut server) = tokio::io::duplex(1024);
+        drop(client);
+        dispatcher.dispatch(&metadata, &mut server).await.unwrap();
+        assert_eq!(proxy.borrow().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_udp_over_tcp() {
+        let direct = Received::default();
+        let mut outbounds = OutboundManager::default();
+        outbounds.add(DEFAULT_OUTBOUND_TAG, Box::new(MockOutbound(direct.clone())));
+        let dispatcher = Dispatcher::new(outbounds, DEFAULT_OUTBOUND_TAG);
+
+        let dns = Target::new("8.8.8.8".to_string(), 53, Network::Udp);
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut client = UotStream::connect(client, &dns, UotVersion::V2).unwrap();
+        client.write_all(b"query").await.unwrap();
+        client.write_all(b"again").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut metadata = metadata(0);
+        metadata.target.addr = UotVersion::V2.magic_address().to_string();
+        dispatcher.dispatch(&metadata, &mut server).await.unwrap();
+        assert_eq!(*direct.borrow(), [(dns, b"queryagain".to_vec())]);
+    }
+
+    // answers `reply` after `delay`, then holds the connection for a minute
+    struct SlowOutbound {
+        delay: Duration,
+        reply: &'static [u8],
+    }
+
+    #[async_trait(?Send)]
+    impl Outbound for SlowOutbound {
+        async fn dispatch(&self, _target: &Target, stream: &mut dyn AsyncStream) -> Result<()> {
+            tokio::time::sleep(self.delay).await;
+            stream.write_all(self.reply).await?;
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_dispatch_route_timeout() {
+        let mut outbounds = OutboundManager::default();
+        let slow = |delay, reply| Box::new(SlowOutbound { delay, reply });
+        outbounds.add("stalled", slow(Duration::from_secs(60), b""));
+        outbounds.add("quick", slow(Duration::from_millis(100), b"hi"));
+        let router = Router::from_json(
+            &serde_json::json!({"rules": [
+                {"port": 80, "outboundTag": "stalled", "timeout": "2s"},
+                {"port": 443, "outboundTag": "quick", "timeout": "2s"},
+                {"port": 8080, "outboundTag": "stalled"},
+            ]}),
+            "ROUTING",
+        )
+        .unwrap();
+        let dispatcher = Dispatcher::new(outbounds, "quick").with_router(Rc::new(router));
+
+        let (_client, mut server) = tokio::io::duplex(1024);
+        let started = tokio::time::Instant::now();
+        let e = dispatcher.dispatch(&metadata(80), &mut server).await.unwrap_err();
+        assert_eq!(e.to_string(), "IO Error: no response in 2s");
+        assert_eq!(started.elapsed(), Duration::from_secs(2));
+
+        // once answered the deadline is off
+        let started = tokio::time::Instant::now();
+        dispatcher.dispatch(&metadata(443), &mut server).await.unwrap();
+        assert!(started.elapsed() > Duration::from_secs(60));
+
+        // and routes without one wait for as long as the outbound takes
+        dispatcher.dispatch(&metadata(8080), &mut server).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_missing_tag() {
+        let dispatcher = Dispatcher::new(OutboundManager::default(), "missing");
+        let (_client, mut server) = tokio::io::duplex(1024);
+        assert!(dispatcher.dispatch(&metadata(443), &mut server).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_access_log() {
+        use crate::app::access::{AccessLogFormat, ConsoleSubscriber};
+        use std::sync::{Arc, Mutex};
+
+        let lines = Arc::new(Mutex::new(Vec::<String>::new()));
+        let sink = lines.clone();
+        let subscriber = ConsoleSubscriber::new(Some(AccessLogFormat::Json))
+            .with_max_level(tracing::Level::TRACE)
+            .with_sink(move |_, line| sink.lock().unwrap().push(line.to_string()));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let received = Received::default();
+        let mut outbounds = OutboundManager::default();
+        outbounds.add("mock", Box::new(MockOutbound(received.clone())));
+        outbounds.add("other", Box::new(MockOutbound(received.clone())));
+        let router = Router::from_json(
+            &serde_json::json!({"rules": [{"port": "443", "outboundTag": "other"}]}),
+            "ROUTING",
+        )
+        .unwrap();
+        let uuid = "f282b878-8711-45a1-8c69-5564172123c1";
+        let dispatcher = Dispatcher::new(outbounds, "mock")
+            .with_router(Rc::new(router))
+            .with_stats(Rc::new(Stats::default()), uuid);
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(b"hello").await.unwrap();
+        drop(client);
+        let metadata = Metadata {
+            source: Some("192.0.2.1".to_string()),
+            ..metadata(443)
+        };
+        dispatcher.dispatch(&metadata, &mut server).await.unwrap();
+
+        let lines = lines.lock().unwrap();
+        let closed: serde_json::Value = serde_json::from_str(lines.last().unwrap()).unwrap();
+        assert_eq!(closed["msg"], "connection closed");
+        for (field, value) in [
+            ("inbound", "vless"),
+            ("source", "192.0.2.1"),
+            ("target", "tcp:example.com:443"),
+            ("rule", "rules[0]"),
+            ("outbound", "other"),
+            ("status", "ok"),
+        ] {
+            assert_eq!(closed[field], value, "{field}");
+        }
+        assert_eq!(closed["up"], 5);
+        assert_eq!(closed["down"], 0);
+        assert_eq!(closed["user"], access::fingerprint(uuid));
+        assert!(closed["id"].is_string() && closed["duration_ms"].is_u64());
+        // the uuid is the user's password
+        assert!(lines.iter().all(|x| !x.contains(uuid)), "{lines:?}");
+    }
+}
@@ -0,0 +1,424 @@
+use super::Answer;
+use crate::app::router::normalize_domain;
+use crate::common::{task, time};
+
+use futures_util::future::{FutureExt, LocalBoxFuture, Shared};
+use hickory_proto::rr::RecordType;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::time::Duration;
+use worker::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CacheOptions {
+    pub max_entries: usize,
+    // record ttls are clamped into this range
+    pub min_ttl: Duration,
+    pub max_ttl: Duration,
+    // how long nxdomain and nodata answers are kept
+    pub negative_ttl: Duration,
+    // serve expired entries right away and refresh them in the background
+    pub optimistic: bool,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        Self {
+            max_entries: 4096,
+            min_ttl: Duration::ZERO,
+            max_ttl: Duration::from_secs(24 * 60 * 60),
+            negative_ttl: Duration::from_secs(30),
+            optimistic: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    // hits that were answered from an expired entry
+    pub stale: u64,
+    pub entries: usize,
+}
+
+// time since the unix epoch, swapped out by tests
+pub type Clock = Rc<dyn Fn() -> Duration>;
+
+type Key = (String, RecordType);
+// errors are strings so every waiter can get its own copy
+type Fetch = Shared<LocalBoxFuture<'static, std::result::Result<Vec<IpAddr>, String>>>;
+
+struct Entry {
+    ips: Vec<IpAddr>,
+    expires: Duration,
+    // position in `Cache::order`
+    used: u64,
+}
+
+// answers keyed on (domain, record type), evicting the least recently used
+// entry once full. concurrent lookups of the same key share one fetch.
+pub struct Cache {
+    options: CacheOptions,
+    clock: Clock,
+    entries: RefCell<HashMap<Key, Entry>>,
+    order: RefCell<BTreeMap<u64, Key>>,
+    tick: Cell<u64>,
+    inflight: RefCell<HashMap<Key, Fetch>>,
+    hits: Cell<u64>,
+    misses: Cell<u64>,
+    stale: Cell<u64>,
+}
+
+impl Cache {
+    pub fn new(options: CacheOptions) -> Self {
+        Self {
+            options,
+            clock: Rc::new(time::now),
+            entries: RefCell::default(),
+            order: RefCell::default(),
+            tick: Cell::new(0),
+            inflight: RefCell::default(),
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+            stale: Cell::new(0),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn options(&self) -> CacheOptions {
+        self.options
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.get(),
+            misses: self.misses.get(),
+            stale: self.stale.get(),
+            entries: self.entries.borrow().len(),
+        }
+    }
+
+    // lookups already in flight still land, everything else is gone
+    pub fn flush(&self) {
+        self.entries.borrow_mut().clear();
+        self.order.borrow_mut().clear();
+    }
+
+    // `fetch` only runs on a miss, or in the background for a stale entry
+    // in optimistic mode, and only if no other lookup of the key is
+    // already running. failures are not cached.
+    pub async fn get<F, Fut>(
+        self: &Rc<Self>,
+        domain: &str,
+        record_type: RecordType,
+        fetch: F,
+    ) -> Result<Vec<IpAddr>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Answer>> + 'static,
+    {
+        let key = (normalize_domain(domain), record_type);
+        match self.touch(&key) {
+            Some((ips, true)) => {
+                self.hits.set(self.hits.get() + 1);
+                return Ok(ips);
+            }
+            Some((ips, false)) if self.options.optimistic => {
+                self.hits.set(self.hits.get() + 1);
+                self.stale.set(self.stale.get() + 1);
+                let refresh = self.start(key, fetch);
+                task::spawn(refresh.map(drop));
+                return Ok(ips);
+            }
+            _ => {}
+        }
+
+        self.misses.set(self.misses.get() + 1);
+        self.start(key, fetch).await.map_err(Error::RustError)
+    }
+
+    // the entry's addresses and whether it is still fresh
+    fn touch(&self, key: &Key) -> Option<(Vec<IpAddr>, bool)> {
+        let mut entries = self.entries.borrow_mut();
+        let entry = entries.get_mut(key)?;
+        let mut order = self.order.borrow_mut();
+        order.remove(&entry.used);
+        entry.used = self.next_tick();
+        order.insert(entry.used, key.clone());
+        Some((entry.ips.clone(), (self.clock)() < entry.expires))
+    }
+
+    fn start<F, Fut>(self: &Rc<Self>, key: Key, fetch: F) -> Fetch
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Answer>> + 'static,
+    {
+        if let Some(x) = self.inflight.borrow().get(&key) {
+            return x.clone();
+        }
+
+        let cache = self.clone();
+        let future = fetch();
+        let owned = key.clone();
+        let shared = async move {
+            let answer = future.await;
+            cache.inflight.borrow_mut().remove(&owned);
+            let answer = answer.map_err(|e| e.to_string())?;
+            let ips = answer.ips.clone();
+            cache.insert(owned, answer);
+            Ok(ips)
+        }
+        .boxed_local()
+        .shared();
+        self.inflight.borrow_mut().insert(key, shared.clone());
+        shared
+    }
+
+    fn insert(&self, key: Key, answer: Answer) {
+        let ttl = if answer.ips.is_empty() {
+            self.options.negative_ttl
+        } else {
+            answer.ttl.clamp(self.options.min_ttl, self.options.max_ttl)
+        };
+        let used = self.next_tick();
+
+        let mut entries = self.entries.borrow_mut();
+        let mut order = self.order.borrow_mut();
+        let entry = Entry {
+            ips: answer.ips,
+            expires: (self.clock)() + ttl,
+            used,
+        };
+        if let Some(old) = entries.insert(key.clone(), entry) {
+            order.remove(&old.used);
+        }
+        order.insert(used, key);
+
+        while entries.len() > self.options.max_entries {
+            let Some((_, key)) = order.pop_first() else {
+                break;
+            };
+            entries.remove(&key);
+        }
+    }
+
+    fn next_tick(&self) -> u64 {
+        let tick = self.tick.get();
+        self.tick.set(tick + 1);
+        tick
+    }
+}
+
+thread_local! {
+    static SHARED: RefCell<Option<Rc<Cache>>> = const { RefCell::new(None) };
+}
+
+// one cache per isolate, started over when the options change
+pub fn shared(options: CacheOptions) -> Rc<Cache> {
+    SHARED.with_borrow_mut(|x| match x {
+        Some(cache) if cache.options == options => cache.clone(),
+        _ => x.insert(Rc::new(Cache::new(options))).clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::future;
+    use tokio::task::LocalSet;
+
+    struct Harness {
+        cache: Rc<Cache>,
+        now: Rc<Cell<Duration>>,
+        fetches: Rc<Cell<usize>>,
+    }
+
+    impl Harness {
+        fn new(options: CacheOptions) -> Self {
+            let now = Rc::new(Cell::new(Duration::from_secs(1_000_000)));
+            let clock = now.clone();
+            let cache = Cache::new(options).with_clock(Rc::new(move || clock.get()));
+            Self {
+                cache: Rc::new(cache),
+                now,
+                fetches: Rc::default(),
+            }
+        }
+
+        fn advance(&self, secs: u64) {
+            self.now.set(self.now.get() + Duration::from_secs(secs));
+        }
+
+        // answers with `last` as the last octet and counts the fetches.
+        // the fetch yields once so concurrent lookups overlap.
+        async fn get(&self, domain: &str, last: u8, ttl: u64) -> Result<Vec<IpAddr>> {
+            let fetches = self.fetches.clone();
+            self.cache
+                .get(domain, RecordType::A, move || async move {
+                    fetches.set(fetches.get() + 1);
+                    tokio::task::yield_now().await;
+                    if last == 0 {
+                        return Err(Error::RustError("servfail".to_string()));
+                    }
+                    let ips = if last == 255 {
+                        Vec::new()
+                    } else {
+                        vec![IpAddr::from([192, 0, 2, last])]
+                    };
+                    Ok(Answer {
+                        ips,
+                        ttl: Duration::from_secs(ttl),
+                    })
+                })
+                .await
+        }
+    }
+
+    fn ip(last: u8) -> Vec<IpAddr> {
+        vec![IpAddr::from([192, 0, 2, last])]
+    }
+
+    #[tokio::test]
+    async fn test_cache_ttl() {
+        let options = CacheOptions {
+            min_ttl: Duration::from_secs(10),
+            max_ttl: Duration::from_secs(300),
+            ..CacheOptions::default()
+        };
+        let h = Harness::new(options);
+
+        assert_eq!(h.get("Example.com.", 1, 60).await.unwrap(), ip(1));
+        assert_eq!(h.get("example.com", 2, 60).await.unwrap(), ip(1));
+        h.advance(59);
+        assert_eq!(h.get("example.com", 2, 60).await.unwrap(), ip(1));
+        h.advance(1);
+        assert_eq!(h.get("example.com", 2, 60).await.unwrap(), ip(2));
+        assert_eq!(h.fetches.get(), 2);
+
+        // clamped up to min_ttl and down to max_ttl
+        h.get("short.example.com", 1, 1).await.unwrap();
+        h.get("long.example.com", 1, 86400).await.unwrap();
+        h.advance(9);
+        assert_eq!(h.get("short.example.com", 2, 1).await.unwrap(), ip(1));
+        h.advance(1);
+        assert_eq!(h.get("short.example.com", 2, 1).await.unwrap(), ip(2));
+        h.advance(290);
+        assert_eq!(h.get("long.example.com", 2, 86400).await.unwrap(), ip(2));
+
+        assert_eq!(
+            h.cache.stats(),
+            CacheStats {
+                hits: 3,
+                misses: 6,
+                stale: 0,
+                entries: 3,
+            }
+        );
+        h.cache.flush();
+        assert_eq!(h.cache.stats().entries, 0);
+        assert_eq!(h.get("example.com", 3, 60).await.unwrap(), ip(3));
+    }
+
+    #[tokio::test]
+    async fn test_cache_negative_and_errors() {
+        let h = Harness::new(CacheOptions::default());
+
+        // nxdomain and nodata stay for negative_ttl, whatever the soa says
+        assert!(h
+            .get("missing.example.com", 255, 3600)
+            .await
+            .unwrap()
+            .is_empty());
+        h.advance(29);
+        assert!(h
+            .get("missing.example.com", 1, 3600)
+            .await
+            .unwrap()
+            .is_empty());
+        h.advance(1);
+        assert_eq!(h.get("missing.example.com", 1, 3600).await.unwrap(), ip(1));
+
+        // failures are retried on the next lookup
+        assert!(h.get("broken.example.com", 0, 60).await.is_err());
+        assert_eq!(h.get("broken.example.com", 1, 60).await.unwrap(), ip(1));
+        assert_eq!(h.fetches.get(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_cache_lru() {
+        let h = Harness::new(CacheOptions {
+            max_entries: 2,
+            ..CacheOptions::default()
+        });
+
+        h.get("a.example.com", 1, 60).await.unwrap();
+        h.get("b.example.com", 1, 60).await.unwrap();
+        h.get("a.example.com", 2, 60).await.unwrap();
+        h.get("c.example.com", 1, 60).await.unwrap();
+        assert_eq!(h.cache.stats().entries, 2);
+
+        // b was the least recently used
+        assert_eq!(h.get("a.example.com", 2, 60).await.unwrap(), ip(1));
+        assert_eq!(h.get("b.example.com", 2, 60).await.unwrap(), ip(2));
+        assert_eq!(h.fetches.get(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_cache_single_flight() {
+        let h = Harness::new(CacheOptions::default());
+
+        let (a, b, c) = future::join3(
+            h.get("example.com", 1, 60),
+            h.get("example.com", 2, 60),
+            h.get("example.com", 3, 60),
+        )
+        .await;
+        assert_eq!((a.unwrap(), b.unwrap(), c.unwrap()), (ip(1), ip(1), ip(1)));
+        assert_eq!(h.fetches.get(), 1);
+
+        // a shared failure reaches every waiter
+        let (a, b) = future::join(
+            h.get("broken.example.com", 0, 60),
+            h.get("broken.example.com", 1, 60),
+        )
+        .await;
+        assert!(a.is_err() && b.is_err());
+        assert_eq!(h.fetches.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_cache_stale_while_revalidate() {
+        let h = Harness::new(CacheOptions {
+            optimistic: true,
+            ..CacheOptions::default()
+        });
+
+        LocalSet::new()
+            .run_until(async {
+                h.get("example.com", 1, 60).await.unwrap();
+                h.advance(61);
+
+                // the expired entry comes back straight away
+                assert_eq!(h.get("example.com", 2, 60).await.unwrap(), ip(1));
+                assert_eq!(h.get("example.com", 3, 60).await.unwrap(), ip(1));
+                assert_eq!(h.cache.stats().stale, 2);
+
+                // one refresh in the background, then the new answer
+                for _ in 0..4 {
+                    tokio::task::yield_now().await;
+                }
+                assert_eq!(h.fetches.get(), 2);
+                assert_eq!(h.get("example.com", 4, 60).await.unwrap(), ip(2));
+                assert_eq!(h.cache.stats().stale, 2);
+            })
+            .await;
+    }
+}
@@ -0,0 +1,1017 @@
+pub mod cache;
+
+use super::geosite::GeoSite;
+use super::router::{normalize_domain, DomainMatcher, Resolve};
+use crate::common::{time, write_u16_be};
+use crate::config::ConfigError;
+
+use async_trait::async_trait;
+use cache::{Cache, CacheOptions};
+use futures_util::future::{self, Either};
+use hickory_proto::op::{Edns, Message, Query, ResponseCode};
+use hickory_proto::rr::rdata::opt::{ClientSubnet, EdnsOption};
+use hickory_proto::rr::{Name, RData, RecordType};
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use worker::*;
+
+// per attempt, the next upstream is tried once it runs out
+const QUERY_TIMEOUT: Duration = Duration::from_secs(4);
+// what we advertise over edns, answers larger than this come back truncated
+const MAX_UDP_PAYLOAD: u16 = 1232;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Udp,
+    Tcp,
+    Tls,
+    Https,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Server {
+    pub protocol: Protocol,
+    pub host: String,
+    pub port: u16,
+    // request path, only used over https
+    pub path: String,
+}
+
+impl Server {
+    // `8.8.8.8`, `udp://8.8.8.8:53`, `tcp://1.1.1.1`, `tls://dns.google`
+    // or `https://dns.google/dns-query`
+    pub fn parse(s: &str) -> std::result::Result<Self, String> {
+        let (protocol, rest) = match s.split_once("://") {
+            None => (Protocol::Udp, s),
+            Some(("udp", x)) => (Protocol::Udp, x),
+            Some(("tcp", x)) => (Protocol::Tcp, x),
+            Some(("tls", x)) => (Protocol::Tls, x),
+            Some(("https", x)) => (Protocol::Https, x),
+            Some((x, _)) => return Err(format!("unsupported dns protocol {x:?}")),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) if protocol == Protocol::Https => (&rest[..i], &rest[i..]),
+            Some(_) => return Err(format!("unexpected path in {s:?}")),
+            None => (rest, "/dns-query"),
+        };
+
+        let default_port = match protocol {
+            Protocol::Udp | Protocol::Tcp => 53,
+            Protocol::Tls => 853,
+            Protocol::Https => 443,
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            // a bare ipv6 address has colons of its own
+            Some((host, port)) if !host.contains(':') || host.ends_with(']') => {
+                let port = port
+                    .parse()
+                    .ok()
+                    .filter(|&x| x != 0)
+                    .ok_or_else(|| format!("invalid port in {s:?}"))?;
+                (host, port)
+            }
+            _ => (authority, default_port),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(format!("missing host in {s:?}"));
+        }
+
+        Ok(Self {
+            protocol,
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+// which records `resolve` asks for, v2ray's `queryStrategy`. the prefer
+// variants ask for both and put the preferred family first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QueryStrategy {
+    #[default]
+    UseIP,
+    UseIPv4,
+    UseIPv6,
+    PreferIPv4,
+    PreferIPv6,
+}
+
+impl QueryStrategy {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "UseIP" => Some(Self::UseIP),
+            "UseIPv4" => Some(Self::UseIPv4),
+            "UseIPv6" => Some(Self::UseIPv6),
+            "PreferIPv4" => Some(Self::PreferIPv4),
+            "PreferIPv6" => Some(Self::PreferIPv6),
+            _ => None,
+        }
+    }
+
+    // drops the families this strategy does not use. with a preference the
+    // families alternate starting with the preferred one (rfc 8305 section
+    // 4), so a dead family costs one attempt rather than all of its
+    // addresses.
+    pub fn sort(&self, ips: Vec<IpAddr>) -> Vec<IpAddr> {
+        let first_v4 = match self {
+            Self::UseIP => return ips,
+            Self::UseIPv4 => return ips.into_iter().filter(|x| x.is_ipv4()).collect(),
+            Self::UseIPv6 => return ips.into_iter().filter(|x| x.is_ipv6()).collect(),
+            Self::PreferIPv4 => true,
+            Self::PreferIPv6 => false,
+        };
+
+        let (preferred, other): (Vec<_>, Vec<_>) =
+            ips.into_iter().partition(|x| x.is_ipv4() == first_v4);
+        let mut ips = Vec::with_capacity(preferred.len() + other.len());
+        let (mut preferred, mut other) = (preferred.into_iter(), other.into_iter());
+        loop {
+            match (preferred.next(), other.next()) {
+                (None, None) => return ips,
+                (a, b) => ips.extend(a.into_iter().chain(b)),
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Upstream {
+    pub server: Server,
+    // queries this server is preferred for, `None` for a general server
+    domains: Option<DomainMatcher>,
+    // edns client subnet sent along with every query
+    client_subnet: Option<ClientSubnet>,
+}
+
+// how a message reaches a server. the worker has tcp sockets and fetch,
+// tests plug in a mock server.
+#[async_trait(?Send)]
+pub trait Transport {
+    async fn exchange(&self, server: &Server, message: &[u8]) -> Result<Vec<u8>>;
+}
+
+pub struct WorkerTransport;
+
+#[async_trait(?Send)]
+impl Transport for WorkerTransport {
+    async fn exchange(&self, server: &Server, message: &[u8]) -> Result<Vec<u8>> {
+        let secure_transport = match server.protocol {
+            Protocol::Https => return exchange_https(server, message).await,
+            Protocol::Tls => SecureTransport::On,
+            // workers can't open udp sockets, so plain servers are asked
+            // over tcp straight away
+            Protocol::Udp | Protocol::Tcp => SecureTransport::Off,
+        };
+        let mut socket = Socket::builder()
+            .secure_transport(secure_transport)
+            .connect(&server.host, server.port)?;
+        socket.opened().await?;
+        let answer = exchange_stream(&mut socket, message).await;
+        let _ = socket.close().await;
+        answer
+    }
+}
+
+async fn exchange_https(server: &Server, message: &[u8]) -> Result<Vec<u8>> {
+    let url = format!("https://{}:{}{}", server.host, server.port, server.path);
+    let response = reqwest::Client::new()
+        .post(url)
+        .header("content-type", "application/dns-message")
+        .header("accept", "application/dns-message")
+        .body(message.to_vec())
+        .send()
+        .await
+        .and_then(|x| x.error_for_status())
+        .map_err(|e| Error::RustError(e.to_string()))?;
+    let body = response
+        .bytes()
+        .await
+        .map_err(|e| Error::RustError(e.to_string()))?;
+    Ok(body.to_vec())
+}
+
+// rfc 7766 framing, every message behind a two byte length
+pub async fn exchange_stream<S>(stream: &mut S, message: &[u8]) -> Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let len = u16::try_from(message.len())
+        .map_err(|_| Error::RustError("dns message too long".to_string()))?;
+    let mut framed = Vec::with_capacity(message.len() + 2);
+    write_u16_be(&mut framed, len);
+    framed.extend_from_slice(message);
+    stream.write_all(&framed).await?;
+    stream.flush().await?;
+
+    let len = stream.read_u16().await?;
+    let mut answer = vec![0u8; len.into()];
+    stream.read_exact(&mut answer).await?;
+    Ok(answer)
+}
+
+pub struct Resolver {
+    client: Rc<Client>,
+    query_strategy: QueryStrategy,
+    cache: Option<Rc<Cache>>,
+}
+
+// the part of a resolver that background refreshes hold on to
+struct Client {
+    upstreams: Vec<Upstream>,
+    transport: Box<dyn Transport>,
+}
+
+impl Resolver {
+    // v2ray's `dns` object: `{"servers": [..], "clientIp": .., "queryStrategy": ..}`.
+    // a server is an address string or
+    // `{"address": .., "port": .., "domains": [..], "clientIp": ..}`.
+    pub fn from_json(value: &Value, path: &str) -> std::result::Result<Self, Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        let Some(object) = value.as_object() else {
+            return Err(vec![ConfigError::new(path, "expected an object")]);
+        };
+
+        let client_subnet = match object.get("clientIp").map(|x| (x, x.as_str())) {
+            None => None,
+            Some((_, Some(x))) => parse_client_subnet(x).unwrap_or_else(|e| {
+                errors.push(ConfigError::new(&format!("{path}.clientIp"), e));
+                None
+            }),
+            Some(_) => {
+                errors.push(ConfigError::new(
+                    &format!("{path}.clientIp"),
+                    "expected a string",
+                ));
+                None
+            }
+        };
+
+        let mut client = Client {
+            upstreams: Vec::new(),
+            transport: Box::new(WorkerTransport),
+        };
+        let mut query_strategy = QueryStrategy::default();
+        let mut cache = Some(CacheOptions::default());
+        for (key, value) in object {
+            let path = format!("{path}.{key}");
+            match key.as_str() {
+                "clientIp" => {}
+                "disableCache" | "serveStale" => match (value.as_bool(), cache.as_mut()) {
+                    (Some(true), _) if key == "disableCache" => cache = None,
+                    (Some(x), Some(cache)) if key == "serveStale" => cache.optimistic = x,
+                    (Some(_), _) => {}
+                    (None, _) => errors.push(ConfigError::new(&path, "expected a boolean")),
+                },
+                "cacheSize" => match value.as_u64().and_then(|x| usize::try_from(x).ok()) {
+                    Some(x) if x > 0 => cache.iter_mut().for_each(|c| c.max_entries = x),
+                    _ => errors.push(ConfigError::new(&path, "expected a positive integer")),
+                },
+                "minTtl" | "maxTtl" | "negativeTtl" => {
+                    let Some(ttl) = value.as_u64().map(Duration::from_secs) else {
+                        errors.push(ConfigError::new(&path, "expected seconds"));
+                        continue;
+                    };
+                    for cache in cache.iter_mut() {
+                        match key.as_str() {
+                            "minTtl" => cache.min_ttl = ttl,
+                            "maxTtl" => cache.max_ttl = ttl,
+                            _ => cache.negative_ttl = ttl,
+                        }
+                    }
+                }
+                "queryStrategy" => match value.as_str().and_then(QueryStrategy::parse) {
+                    Some(x) => query_strategy = x,
+                    None => errors.push(ConfigError::new(
+                        &path,
+                        "expected one of UseIP, UseIPv4 or UseIPv6",
+                    )),
+                },
+                "servers" => {
+                    let Some(servers) = value.as_array() else {
+                        errors.push(ConfigError::new(&path, "expected an array"));
+                        continue;
+                    };
+                    for (i, server) in servers.iter().enumerate() {
+                        let path = format!("{path}[{i}]");
+                        match Upstream::from_json(server, &path, client_subnet) {
+                            Ok(x) => client.upstreams.push(x),
+                            Err(e) => errors.extend(e),
+                        }
+                    }
+                }
+                _ => errors.push(ConfigError::new(&path, "unknown field")),
+            }
+        }
+
+        if client.upstreams.is_empty() && errors.is_empty() {
+            errors.push(ConfigError::new(
+                &format!("{path}.servers"),
+                "needs at least one server",
+            ));
+        }
+        if let Some(x) = cache.filter(|x| x.min_ttl > x.max_ttl) {
+            errors.push(ConfigError::new(
+                &format!("{path}.minTtl"),
+                format!("must not be above maxTtl ({}s)", x.max_ttl.as_secs()),
+            ));
+        }
+
+        if errors.is_empty() {
+            Ok(Resolver {
+                client: Rc::new(client),
+                query_strategy,
+                cache: cache.map(|x| Rc::new(Cache::new(x))),
+            })
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn with_transport(mut self, transport: Box<dyn Transport>) -> Self {
+        self.client_mut().transport = transport;
+        self
+    }
+
+    pub fn with_cache(mut self, cache: Option<Rc<Cache>>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    // workers build a new config for every request, the isolate's cache
+    // outlives them
+    pub fn share_cache(&mut self) {
+        self.cache = self.cache.as_ref().map(|x| cache::shared(x.options()));
+    }
+
+    fn client_mut(&mut self) -> &mut Client {
+        Rc::get_mut(&mut self.client).expect("client is not shared yet")
+    }
+
+    pub fn upstreams(&self) -> &[Upstream] {
+        &self.client.upstreams
+    }
+
+    pub fn query_strategy(&self) -> QueryStrategy {
+        self.query_strategy
+    }
+
+    pub fn cache(&self) -> Option<&Rc<Cache>> {
+        self.cache.as_ref()
+    }
+
+    pub fn geosite_lists(&self) -> BTreeSet<String> {
+        self.upstreams()
+            .iter()
+            .flat_map(|x| &x.domains)
+            .flat_map(|x| x.sites().iter().map(|x| x.list.clone()))
+            .collect()
+    }
+
+    pub fn load_geosite(&mut self, geosite: &GeoSite) -> std::result::Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        for (i, upstream) in self.client_mut().upstreams.iter_mut().enumerate() {
+            if let Some(Err(e)) = upstream.domains.as_mut().map(|x| x.load_geosite(geosite)) {
+                errors.push(ConfigError::new(&format!("DNS.servers[{i}].domains"), e));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn candidates(&self, domain: &str) -> Vec<&Upstream> {
+        self.client.candidates(domain)
+    }
+
+    pub async fn lookup(&self, domain: &str, record_type: RecordType) -> Result<Vec<IpAddr>> {
+        let client = self.client.clone();
+        let owned = domain.to_string();
+        let fetch = move || async move { client.fetch(&owned, record_type).await };
+        match &self.cache {
+            Some(cache) => cache.get(domain, record_type, fetch).await,
+            None => fetch().await.map(|x| x.ips),
+        }
+    }
+}
+
+impl Client {
+    // servers whose domains match come first, in config order, then the
+    // general ones
+    fn candidates(&self, domain: &str) -> Vec<&Upstream> {
+        let domain = normalize_domain(domain);
+        let matched = self
+            .upstreams
+            .iter()
+            .filter(|x| x.domains.as_ref().is_some_and(|x| x.matches(&domain)));
+        let general = self.upstreams.iter().filter(|x| x.domains.is_none());
+        matched.chain(general).collect()
+    }
+
+    // every candidate gets one attempt. a definite answer, even an empty
+    // one, ends the search; errors and server failures move on.
+    async fn fetch(&self, domain: &str, record_type: RecordType) -> Result<Answer> {
+        let name = Name::from_utf8(format!("{}.", normalize_domain(domain)))
+            .map_err(|e| Error::RustError(format!("invalid domain {domain:?}: {e}")))?;
+
+        let mut last_error = None;
+        for upstream in self.candidates(domain) {
+            match self.query(upstream, &name, record_type).await {
+                Ok(answer) => match answer.response_code() {
+                    ResponseCode::NoError | ResponseCode::NXDomain => {
+                        return Ok(Answer::from_message(&answer))
+                    }
+                    code => last_error = Some(format!("{}: {code}", upstream.server.host)),
+                },
+                Err(e) => last_error = Some(format!("{}: {e}", upstream.server.host)),
+            }
+        }
+
+        let reason = last_error.unwrap_or_else(|| "no dns server".to_string());
+        Err(Error::RustError(format!("resolving {domain}: {reason}")))
+    }
+
+    async fn query(
+        &self,
+        upstream: &Upstream,
+        name: &Name,
+        record_type: RecordType,
+    ) -> Result<Message> {
+        let id = random_id();
+        let message = build_query(id, name, record_type, upstream.client_subnet)?;
+
+        let answer = self.exchange(&upstream.server, &message, id).await?;
+        if !answer.truncated() || upstream.server.protocol != Protocol::Udp {
+            return Ok(answer);
+        }
+
+        // same server over tcp, rfc 7766
+        let server = Server {
+            protocol: Protocol::Tcp,
+            ..upstream.server.clone()
+        };
+        self.exchange(&server, &message, id).await
+    }
+
+    async fn exchange(&self, server: &Server, message: &[u8], id: u16) -> Result<Message> {
+        let exchange = self.transport.exchange(server, message);
+        let answer = match future::select(exchange, Box::pin(time::sleep(QUERY_TIMEOUT))).await {
+            Either::Left((x, _)) => x?,
+            Either::Right(_) => return Err(Error::RustError("timed out".to_string())),
+        };
+
+        let answer =
+            Message::from_vec(&answer).map_err(|e| Error::RustError(format!("bad answer: {e}")))?;
+        if answer.id() != id {
+            return Err(Error::RustError("answer id mismatch".to_string()));
+        }
+        Ok(answer)
+    }
+}
+
+#[async_trait(?Send)]
+impl Resolve for Resolver {
+    async fn resolve(&self, domain: &str) -> Result<Vec<IpAddr>> {
+        self.resolve_with(domain, self.query_strategy).await
+    }
+
+    // single family strategies only send that one query
+    async fn resolve_with(&self, domain: &str, strategy: QueryStrategy) -> Result<Vec<IpAddr>> {
+        match strategy {
+            QueryStrategy::UseIPv4 => self.lookup(domain, RecordType::A).await,
+            QueryStrategy::UseIPv6 => self.lookup(domain, RecordType::AAAA).await,
+            _ => {
+                let (v4, v6) = future::join(
+                    self.lookup(domain, RecordType::A),
+                    self.lookup(domain, RecordType::AAAA),
+                )
+                .await;
+                match (v4, v6) {
+                    (Err(e), Err(_)) => Err(e),
+                    (v4, v6) => Ok(strategy.sort(v4.into_iter().chain(v6).flatten().collect())),
+                }
+            }
+        }
+    }
+}
+
+impl Upstream {
+    fn from_json(
+        value: &Value,
+        path: &str,
+        client_subnet: Option<ClientSubnet>,
+    ) -> std::result::Result<Self, Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        let mut upstream = Upstream {
+            server: Server::parse("0.0.0.0").expect("placeholder server"),
+            domains: None,
+            client_subnet,
+        };
+
+        let object = match value {
+            Value::String(x) => {
+                upstream.server = Server::parse(x).map_err(|e| vec![ConfigError::new(path, e)])?;
+                return Ok(upstream);
+            }
+            Value::Object(x) => x,
+            _ => {
+                return Err(vec![ConfigError::new(
+                    path,
+                    "expected a string or an object",
+                )])
+            }
+        };
+
+        let mut port = None;
+        let mut address = None;
+        for (key, value) in object {
+            let path = format!("{path}.{key}");
+            match key.as_str() {
+                "address" => match value.as_str().map(Server::parse) {
+                    Some(Ok(x)) => address = Some(x),
+                    Some(Err(e)) => errors.push(ConfigError::new(&path, e)),
+                    None => errors.push(ConfigError::new(&path, "expected a string")),
+                },
+                "port" => match value.as_u64().and_then(|x| u16::try_from(x).ok()) {
+                    Some(x) if x != 0 => port = Some(x),
+                    _ => errors.push(ConfigError::new(&path, "must be between 1 and 65535")),
+                },
+                "domains" => {
+                    let mut matcher = DomainMatcher::default();
+                    let Some(domains) = value.as_array() else {
+                        errors.push(ConfigError::new(&path, "expected an array"));
+                        continue;
+                    };
+                    for (i, domain) in domains.iter().enumerate() {
+                        let path = format!("{path}[{i}]");
+                        match domain.as_str().map(|x| matcher.add(x)) {
+                            Some(Ok(())) => {}
+                            Some(Err(e)) => errors.push(ConfigError::new(&path, e)),
+                            None => errors.push(ConfigError::new(&path, "expected a string")),
+                        }
+                    }
+                    if let Err(e) = matcher.build() {
+                        errors.push(ConfigError::new(&path, e));
+                    }
+                    upstream.domains = Some(matcher);
+                }
+                "clientIp" => match value.as_str().map(parse_client_subnet) {
+                    Some(Ok(x)) => upstream.client_subnet = x,
+                    Some(Err(e)) => errors.push(ConfigError::new(&path, e)),
+                    None => errors.push(ConfigError::new(&path, "expected a string")),
+                },
+                _ => errors.push(ConfigError::new(&path, "unknown field")),
+            }
+        }
+
+        match address {
+            Some(x) => upstream.server = x,
+            None => errors.push(ConfigError::new(&format!("{path}.address"), "is not set")),
+        }
+        if let Some(x) = port {
+            upstream.server.port = x;
+        }
+
+        if errors.is_empty() {
+            Ok(upstream)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+// `1.2.3.4`, `1.2.3.0/24` or `2001:db8::/56`. without a prefix only the
+// network part is sent: /24 for ipv4 and /56 for ipv6. an empty string
+// turns the option off.
+fn parse_client_subnet(s: &str) -> std::result::Result<Option<ClientSubnet>, String> {
+    if s.is_empty() {
+        return Ok(None);
+    }
+    let (addr, prefix) = match s.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None => (s, None),
+    };
+    let addr: IpAddr = addr
+        .parse()
+        .map_err(|_| format!("invalid client ip {s:?}"))?;
+    let (max, default) = if addr.is_ipv4() { (32, 24) } else { (128, 56) };
+    let prefix = match prefix.map(|x| x.parse::<u8>()) {
+        None => default,
+        Some(Ok(x)) if x <= max => x,
+        Some(_) => return Err(format!("invalid prefix in {s:?}")),
+    };
+
+    // bits past the prefix must be zero on the wire
+    let addr = match addr {
+        IpAddr::V4(x) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::from((u32::from(x) & mask).to_be_bytes())
+        }
+        IpAddr::V6(x) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::from((u128::from(x) & mask).to_be_bytes())
+        }
+    };
+    Ok(Some(ClientSubnet::new(addr, prefix, 0)))
+}
+
+fn build_query(
+    id: u16,
+    name: &Name,
+    record_type: RecordType,
+    client_subnet: Option<ClientSubnet>,
+) -> Result<Vec<u8>> {
+    let mut edns = Edns::new();
+    edns.set_max_payload(MAX_UDP_PAYLOAD);
+    if let Some(x) = client_subnet {
+        edns.options_mut().insert(EdnsOption::Subnet(x));
+    }
+
+    let mut message = Message::new();
+    message
+        .set_id(id)
+        .set_recursion_desired(true)
+        .add_query(Query::query(name.clone(), record_type))
+        .set_edns(edns);
+    message
+        .to_vec()
+        .map_err(|e| Error::RustError(format!("encoding dns query: {e}")))
+}
+
+// what a lookup yields and the cache keeps. no addresses means nxdomain
+// or nodata.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Answer {
+    pub ips: Vec<IpAddr>,
+    // shortest ttl of the records, cnames included
+    pub ttl: Duration,
+}
+
+impl Answer {
+    // cnames are followed by the server, only the addresses are kept
+    fn from_message(answer: &Message) -> Self {
+        let ips = answer
+            .answers()
+            .iter()
+            .filter_map(|x| match x.data() {
+                Some(RData::A(x)) => Some(IpAddr::V4(x.0)),
+                Some(RData::AAAA(x)) => Some(IpAddr::V6(x.0)),
+                _ => None,
+            })
+            .collect();
+        let ttl = answer.answers().iter().map(|x| x.ttl()).min().unwrap_or(0);
+        Self {
+            ips,
+            ttl: Duration::from_secs(ttl.into()),
+        }
+    }
+}
+
+fn random_id() -> u16 {
+    let mut buf = [0u8; 2];
+    crate::common::random(&mut buf);
+    u16::from_le_bytes(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::op::MessageType;
+    use hickory_proto::rr::rdata::{A, AAAA};
+    use hickory_proto::rr::Record;
+    use serde_json::json;
+    use std::cell::RefCell;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::rc::Rc;
+
+    // what the mock server saw: (server, protocol, name, type, subnet)
+    type Queries = Rc<RefCell<Vec<(String, Protocol, String, RecordType, Option<ClientSubnet>)>>>;
+
+    // answers `big.example.com` with more records than fit in a udp answer
+    // and everything else with one record per type. `servfail` servers
+    // answer every query with SERVFAIL, `down` ones not at all.
+    struct MockServer(Queries);
+
+    fn answer(query: &[u8], protocol: Protocol, host: &str) -> Vec<u8> {
+        let query = Message::from_vec(query).unwrap();
+        let question = &query.queries()[0];
+        let mut answer = Message::new();
+        answer
+            .set_id(query.id())
+            .set_message_type(MessageType::Response)
+            .add_query(question.clone());
+
+        let n = match question.name().to_ascii().as_str() {
+            "big.example.com." => 50,
+            "missing.example.com." => {
+                answer.set_response_code(ResponseCode::NXDomain);
+                0
+            }
+            _ => 1,
+        };
+        if host == "servfail" {
+            answer.set_response_code(ResponseCode::ServFail);
+        } else if protocol == Protocol::Udp && n > 10 {
+            answer.set_truncated(true);
+        } else {
+            for i in 0..n {
+                let data = match question.query_type() {
+                    RecordType::A => RData::A(A(Ipv4Addr::new(192, 0, 2, i))),
+                    _ => RData::AAAA(AAAA(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, i.into()))),
+                };
+                answer.add_answer(Record::from_rdata(question.name().clone(), 60, data));
+            }
+        }
+        answer.to_vec().unwrap()
+    }
+
+    #[async_trait(?Send)]
+    impl Transport for MockServer {
+        async fn exchange(&self, server: &Server, message: &[u8]) -> Result<Vec<u8>> {
+            let query = Message::from_vec(message).unwrap();
+            let subnet = query
+                .extensions()
+                .as_ref()
+                .and_then(|x| x.option(hickory_proto::rr::rdata::opt::EdnsCode::Subnet))
+                .map(|x| match x {
+                    EdnsOption::Subnet(x) => *x,
+                    _ => unreachable!(),
+                });
+            let question = &query.queries()[0];
+            self.0.borrow_mut().push((
+                server.host.clone(),
+                server.protocol,
+                question.name().to_ascii(),
+                question.query_type(),
+                subnet,
+            ));
+            if server.host == "down" {
+                return Err(Error::RustError("connection refused".to_string()));
+            }
+            Ok(answer(message, server.protocol, &server.host))
+        }
+    }
+
+    fn mock_resolver(config: Value) -> (Resolver, Queries) {
+        let queries = Queries::default();
+        let resolver = Resolver::from_json(&config, "DNS")
+            .unwrap()
+            .with_transport(Box::new(MockServer(queries.clone())));
+        (resolver, queries)
+    }
+
+    #[test]
+    fn test_server_parse() {
+        let server = |protocol, host: &str, port, path: &str| Server {
+            protocol,
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        };
+        for (s, expected) in [
+            (
+                "8.8.8.8",
+                server(Protocol::Udp, "8.8.8.8", 53, "/dns-query"),
+            ),
+            (
+                "udp://8.8.8.8:5353",
+                server(Protocol::Udp, "8.8.8.8", 5353, "/dns-query"),
+            ),
+            (
+                "tcp://[2001:db8::1]:53",
+                server(Protocol::Tcp, "2001:db8::1", 53, "/dns-query"),
+            ),
+            (
+                "tcp://2001:db8::1",
+                server(Protocol::Tcp, "2001:db8::1", 53, "/dns-query"),
+            ),
+            (
+                "tls://dns.google",
+                server(Protocol::Tls, "dns.google", 853, "/dns-query"),
+            ),
+            (
+                "https://1.1.1.1/dns-query",
+                server(Protocol::Https, "1.1.1.1", 443, "/dns-query"),
+            ),
+            (
+                "https://dns.example:8443/q?x=1",
+                server(Protocol::Https, "dns.example", 8443, "/q?x=1"),
+            ),
+        ] {
+            assert_eq!(Server::parse(s).unwrap(), expected, "{s}");
+        }
+        for s in [
+            "quic://dns.adguard.com",
+            "tcp://1.1.1.1/x",
+            "tcp://:53",
+            "1.1.1.1:0",
+        ] {
+            assert!(Server::parse(s).is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn test_resolver_from_json_errors() {
+        for (config, path) in [
+            (json!({}), "DNS.servers"),
+            (json!({"servers": [1]}), "DNS.servers[0]"),
+            (json!({"servers": [{"port": 53}]}), "DNS.servers[0].address"),
+            (
+                json!({"servers": [{"address": "1.1.1.1", "port": 0}]}),
+                "DNS.servers[0].port",
+            ),
+            (
+                json!({"servers": [{"address": "1.1.1.1", "domains": ["regexp:("]}]}),
+                "DNS.servers[0].domains[0]",
+            ),
+            (
+                json!({"servers": ["1.1.1.1"], "clientIp": "1.2.3.4/33"}),
+                "DNS.clientIp",
+            ),
+            (
+                json!({"servers": ["1.1.1.1"], "queryStrategy": "UseIPv5"}),
+                "DNS.queryStrategy",
+            ),
+            (json!({"servers": ["1.1.1.1"], "hosts": {}}), "DNS.hosts"),
+            (
+                json!({"servers": ["1.1.1.1"], "minTtl": 600, "maxTtl": 60}),
+                "DNS.minTtl",
+            ),
+            (
+                json!({"servers": ["1.1.1.1"], "cacheSize": 0}),
+                "DNS.cacheSize",
+            ),
+        ] {
+            let errors = Resolver::from_json(&config, "DNS").err().unwrap();
+            assert_eq!(errors[0].path, path, "{config}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_truncation_falls_back_to_tcp() {
+        let (resolver, queries) = mock_resolver(json!({
+            "servers": ["192.0.2.53"],
+            "queryStrategy": "UseIPv4",
+        }));
+
+        let ips = resolver.resolve("big.example.com").await.unwrap();
+        assert_eq!(ips.len(), 50);
+        let protocols: Vec<_> = queries.borrow().iter().map(|x| x.1).collect();
+        assert_eq!(protocols, [Protocol::Udp, Protocol::Tcp]);
+
+        // small answers never leave udp
+        queries.borrow_mut().clear();
+        resolver.resolve("www.example.com").await.unwrap();
+        let protocols: Vec<_> = queries.borrow().iter().map(|x| x.1).collect();
+        assert_eq!(protocols, [Protocol::Udp]);
+
+        // answered from the cache, unless it is switched off
+        resolver.resolve("www.example.com").await.unwrap();
+        assert_eq!(queries.borrow().len(), 1);
+        assert_eq!(resolver.cache().unwrap().stats().hits, 1);
+
+        let (resolver, queries) = mock_resolver(json!({
+            "servers": ["192.0.2.53"],
+            "disableCache": true,
+        }));
+        resolver.resolve("www.example.com").await.unwrap();
+        resolver.resolve("www.example.com").await.unwrap();
+        assert_eq!(queries.borrow().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_per_domain_upstreams() {
+        let (resolver, queries) = mock_resolver(json!({
+            "servers": [
+                "https://doh.example/dns-query",
+                {"address": "223.5.5.5", "domains": ["domain:cn", "full:www.example.org"]},
+                {"address": "tls://dns.example", "domains": ["keyword:example"], "clientIp": "198.51.100.77"},
+            ],
+            "clientIp": "203.0.113.9/24",
+            "queryStrategy": "UseIPv4",
+        }));
+        let hosts = |domain: &str| -> Vec<String> {
+            resolver
+                .candidates(domain)
+                .iter()
+                .map(|x| x.server.host.clone())
+                .collect()
+        };
+
+        assert_eq!(hosts("www.baidu.cn"), ["223.5.5.5", "doh.example"]);
+        assert_eq!(
+            hosts("www.example.org"),
+            ["223.5.5.5", "dns.example", "doh.example"]
+        );
+        assert_eq!(hosts("example.com"), ["dns.example", "doh.example"]);
+        assert_eq!(hosts("rust-lang.org"), ["doh.example"]);
+
+        assert_eq!(
+            resolver.resolve("www.baidu.cn").await.unwrap(),
+            [IpAddr::from([192, 0, 2, 0])]
+        );
+        resolver.resolve("example.com").await.unwrap();
+
+        let subnet = |x: [u8; 4], prefix| Some(ClientSubnet::new(IpAddr::from(x), prefix, 0));
+        assert_eq!(
+            *queries.borrow(),
+            [
+                (
+                    "223.5.5.5".to_string(),
+                    Protocol::Udp,
+                    "www.baidu.cn.".to_string(),
+                    RecordType::A,
+                    subnet([203, 0, 113, 0], 24)
+                ),
+                (
+                    "dns.example".to_string(),
+                    Protocol::Tls,
+                    "example.com.".to_string(),
+                    RecordType::A,
+                    subnet([198, 51, 100, 0], 24)
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retry_and_strategy() {
+        let (resolver, queries) = mock_resolver(json!({
+            "servers": ["down", "servfail", "192.0.2.53"],
+        }));
+
+        let ips = resolver.resolve("www.example.com").await.unwrap();
+        assert_eq!(
+            ips,
+            [
+                IpAddr::from([192, 0, 2, 0]),
+                IpAddr::from(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0)),
+            ]
+        );
+        // both record types walk past the two broken servers
+        let hosts: Vec<_> = queries.borrow().iter().map(|x| x.0.clone()).collect();
+        assert_eq!(hosts.len(), 6);
+        assert_eq!(hosts.iter().filter(|x| *x == "192.0.2.53").count(), 2);
+
+        let (resolver, _) = mock_resolver(json!({
+            "servers": ["192.0.2.53"],
+            "queryStrategy": "PreferIPv6",
+        }));
+        let ips = resolver.resolve("www.example.com").await.unwrap();
+        assert!(ips[0].is_ipv6() && ips[1].is_ipv4());
+
+        // an outbound asking for one family only sends that query
+        let (resolver, queries) = mock_resolver(json!({"servers": ["192.0.2.53"]}));
+        let ips = resolver
+            .resolve_with("www.example.com", QueryStrategy::UseIPv4)
+            .await
+            .unwrap();
+        assert_eq!(ips, [IpAddr::from([192, 0, 2, 0])]);
+        assert_eq!(queries.borrow().len(), 1);
+
+        // nxdomain is a final answer, not a reason to ask the next server
+        let (resolver, queries) = mock_resolver(json!({
+            "servers": ["192.0.2.53", "192.0.2.54"],
+            "queryStrategy": "UseIPv6",
+        }));
+        assert!(resolver
+            .resolve("missing.example.com")
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(queries.borrow().len(), 1);
+
+        let (resolver, _) = mock_resolver(json!({"servers": ["down", "servfail"]}));
+        assert!(resolver.resolve("www.example.com").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_exchange_stream() {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        let name = Name::from_ascii("big.example.com.").unwrap();
+        let query = build_query(7, &name, RecordType::A, None).unwrap();
+
+        let serve = async {
+            let len = server.read_u16().await.unwrap();
+            let mut message = vec![0u8; len.into()];
+            server.read_exact(&mut message).await.unwrap();
+            let answer = answer(&message, Protocol::Tcp, "192.0.2.53");
+            server.write_u16(answer.len() as u16).await.unwrap();
+            // the length and the body in separate writes
+            server.write_all(&answer).await.unwrap();
+        };
+        let (answer, ()) = future::join(exchange_stream(&mut client, &query), serve).await;
+
+        let answer = Message::from_vec(&answer.unwrap()).unwrap();
+        assert_eq!(answer.id(), 7);
+        assert_eq!(Answer::from_message(&answer).ips.len(), 50);
+    }
+}
@@ -0,0 +1,577 @@
+use super::geosite::GeoSite;
+use super::router::{normalize_domain, DomainMatcher};
+use crate::config::ConfigError;
+
+use hickory_proto::op::{Message, MessageType, ResponseCode};
+use hickory_proto::rr::rdata::{A, AAAA};
+use hickory_proto::rr::{DNSClass, RData, Record, RecordType};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::rc::Rc;
+
+// v2ray's defaults for an empty fakedns object
+pub const DEFAULT_IP_POOL: &str = "198.18.0.0/15";
+pub const DEFAULT_POOL_SIZE: usize = 65535;
+
+// fake answers are only good for as long as the mapping survives, so
+// clients are asked not to hold on to them
+pub const FAKE_TTL: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PoolOptions {
+    // the network address, host bits cleared
+    pub net: IpAddr,
+    pub prefix: u8,
+    // upper bound on the mapped domains, at most the addresses in `net`
+    pub size: usize,
+}
+
+impl PoolOptions {
+    // `198.18.0.0/15`. the network address itself is never handed out
+    pub fn parse(cidr: &str, size: usize) -> Result<Self, String> {
+        let (addr, prefix) = cidr
+            .split_once('/')
+            .ok_or_else(|| format!("expected a cidr, got {cidr:?}"))?;
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid ip address {addr:?}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix
+            .parse::<u8>()
+            .ok()
+            .filter(|x| *x <= max)
+            .ok_or_else(|| format!("invalid prefix length {prefix:?}"))?;
+
+        let host = u32::from(max - prefix);
+        let net = match addr {
+            IpAddr::V4(x) => {
+                IpAddr::V4((u32::from(x) & u32::MAX.checked_shl(host).unwrap_or(0)).into())
+            }
+            IpAddr::V6(x) => {
+                IpAddr::V6((u128::from(x) & u128::MAX.checked_shl(host).unwrap_or(0)).into())
+            }
+        };
+        let available = 1u128
+            .checked_shl(host)
+            .map_or(u128::MAX, |x| x - 1)
+            .min(usize::MAX as u128) as usize;
+        if available == 0 {
+            return Err(format!("{cidr} has no addresses to hand out"));
+        }
+        if size == 0 {
+            return Err("poolSize must be positive".to_string());
+        }
+
+        Ok(Self {
+            net,
+            prefix,
+            size: size.min(available),
+        })
+    }
+
+    fn nth(&self, offset: usize) -> IpAddr {
+        match self.net {
+            IpAddr::V4(x) => IpAddr::V4(Ipv4Addr::from(u32::from(x) + offset as u32)),
+            IpAddr::V6(x) => IpAddr::V6(Ipv6Addr::from(u128::from(x) + offset as u128)),
+        }
+    }
+
+    // the inverse of `nth` for addresses this pool could have handed out
+    fn offset(&self, ip: IpAddr) -> Option<usize> {
+        let offset = match (self.net, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                u128::from(u32::from(ip).wrapping_sub(u32::from(net)))
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => u128::from(ip).wrapping_sub(u128::from(net)),
+            _ => return None,
+        };
+        (1..=self.size as u128)
+            .contains(&offset)
+            .then_some(offset as usize)
+    }
+}
+
+// domain <-> address mappings of one pool. once every address is taken the
+// least recently used domain gives its address up.
+pub struct Pool {
+    options: PoolOptions,
+    domains: HashMap<String, (usize, u64)>,
+    offsets: HashMap<usize, String>,
+    order: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl Pool {
+    pub fn new(options: PoolOptions) -> Self {
+        Self {
+            options,
+            domains: HashMap::new(),
+            offsets: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn options(&self) -> &PoolOptions {
+        &self.options
+    }
+
+    pub fn len(&self) -> usize {
+        self.domains.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.domains.is_empty()
+    }
+
+    fn touch(&mut self, domain: &str) {
+        self.tick += 1;
+        if let Some((_, used)) = self.domains.get_mut(domain) {
+            self.order.remove(used);
+            *used = self.tick;
+            self.order.insert(self.tick, domain.to_string());
+        }
+    }
+
+    // the address of `domain`, allocating one on first sight
+    pub fn lookup(&mut self, domain: &str) -> IpAddr {
+        if let Some(&(offset, _)) = self.domains.get(domain) {
+            self.touch(domain);
+            return self.options.nth(offset);
+        }
+
+        let offset = if self.domains.len() < self.options.size {
+            self.domains.len() + 1
+        } else {
+            let (_, evicted) = self.order.pop_first().expect("a full pool has entries");
+            let (offset, _) = self
+                .domains
+                .remove(&evicted)
+                .expect("ordered domains are mapped");
+            crate::log!(
+                "[fakedns]: pool full, {} gives up {}",
+                evicted,
+                self.options.nth(offset)
+            );
+            offset
+        };
+
+        self.tick += 1;
+        self.domains.insert(domain.to_string(), (offset, self.tick));
+        self.offsets.insert(offset, domain.to_string());
+        self.order.insert(self.tick, domain.to_string());
+        self.options.nth(offset)
+    }
+
+    // the domain a fake address was handed out for. a connection counts as
+    // a use, so busy domains outlive idle ones.
+    pub fn domain(&mut self, ip: IpAddr) -> Option<String> {
+        let offset = self.options.offset(ip)?;
+        let domain = self.offsets.get(&offset)?.clone();
+        self.touch(&domain);
+        Some(domain)
+    }
+}
+
+thread_local! {
+    static SHARED: RefCell<Vec<Rc<RefCell<Pool>>>> = const { RefCell::new(Vec::new()) };
+}
+
+// one pool per family and isolate, so connections to addresses handed out
+// before the last config rebuild still map back. changing the options of a
+// family starts it over.
+pub fn shared(options: PoolOptions) -> Rc<RefCell<Pool>> {
+    SHARED.with_borrow_mut(|pools| {
+        if let Some(pool) = pools.iter().find(|x| x.borrow().options == options) {
+            return pool.clone();
+        }
+        pools.retain(|x| x.borrow().options.net.is_ipv4() != options.net.is_ipv4());
+        let pool = Rc::new(RefCell::new(Pool::new(options)));
+        pools.push(pool.clone());
+        pool
+    })
+}
+
+pub struct FakeDns {
+    pools: Vec<Rc<RefCell<Pool>>>,
+    // domains that keep getting real answers
+    exclude: Option<DomainMatcher>,
+}
+
+impl FakeDns {
+    pub fn new(pools: Vec<PoolOptions>) -> Self {
+        Self {
+            pools: pools
+                .into_iter()
+                .map(|x| Rc::new(RefCell::new(Pool::new(x))))
+                .collect(),
+            exclude: None,
+        }
+    }
+
+    pub fn with_exclude(mut self, exclude: DomainMatcher) -> Self {
+        self.exclude = Some(exclude);
+        self
+    }
+
+    // v2ray's fakedns object, or an array of them for separate ipv4 and
+    // ipv6 pools. `exclude` takes domain rules and applies to every pool.
+    pub fn from_json(value: &Value, path: &str) -> Result<Self, Vec<ConfigError>> {
+        let entries = match value {
+            Value::Array(x) => x
+                .iter()
+                .enumerate()
+                .map(|(i, x)| (format!("{path}[{i}]"), x))
+                .collect(),
+            x => vec![(path.to_string(), x)],
+        };
+        if entries.is_empty() {
+            return Err(vec![ConfigError::new(path, "expected at least one pool")]);
+        }
+
+        let mut errors = Vec::new();
+        let mut pools = Vec::<PoolOptions>::new();
+        let mut exclude = DomainMatcher::default();
+        for (path, value) in entries {
+            let Some(object) = value.as_object() else {
+                errors.push(ConfigError::new(&path, "expected an object"));
+                continue;
+            };
+
+            let mut ip_pool = DEFAULT_IP_POOL;
+            let mut size = DEFAULT_POOL_SIZE;
+            for (key, value) in object {
+                let path = format!("{path}.{key}");
+                match key.as_str() {
+                    "ipPool" => match value.as_str() {
+                        Some(x) => ip_pool = x,
+                        None => errors.push(ConfigError::new(&path, "expected a string")),
+                    },
+                    "poolSize" => match value.as_u64().and_then(|x| usize::try_from(x).ok()) {
+                        Some(x) => size = x,
+                        None => errors.push(ConfigError::new(&path, "expected a positive integer")),
+                    },
+                    "exclude" => {
+                        let Some(domains) = value.as_array() else {
+                            errors.push(ConfigError::new(&path, "expected an array"));
+                            continue;
+                        };
+                        for (i, domain) in domains.iter().enumerate() {
+                            let path = format!("{path}[{i}]");
+                            match domain.as_str().map(|x| exclude.add(x)) {
+                                Some(Ok(())) => {}
+                                Some(Err(e)) => errors.push(ConfigError::new(&path, e)),
+                                None => errors.push(ConfigError::new(&path, "expected a string")),
+                            }
+                        }
+                    }
+                    _ => errors.push(ConfigError::new(&path, "unknown field")),
+                }
+            }
+
+            match PoolOptions::parse(ip_pool, size) {
+                Ok(x) if pools.iter().any(|p| p.net.is_ipv4() == x.net.is_ipv4()) => {
+                    errors.push(ConfigError::new(
+                        &format!("{path}.ipPool"),
+                        "only one pool per address family",
+                    ))
+                }
+                Ok(x) => pools.push(x),
+                Err(e) => errors.push(ConfigError::new(&format!("{path}.ipPool"), e)),
+            }
+        }
+        if let Err(e) = exclude.build() {
+            errors.push(ConfigError::new(path, e));
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        let fakedns = Self::new(pools);
+        Ok(match exclude.is_empty() && exclude.sites().is_empty() {
+            true => fakedns,
+            false => fakedns.with_exclude(exclude),
+        })
+    }
+
+    // swaps the pools for the per-isolate ones, see `shared`
+    pub fn share_pools(&mut self) {
+        for pool in self.pools.iter_mut() {
+            let options = pool.borrow().options;
+            *pool = shared(options);
+        }
+    }
+
+    pub fn pools(&self) -> &[Rc<RefCell<Pool>>] {
+        &self.pools
+    }
+
+    pub fn geosite_lists(&self) -> BTreeSet<String> {
+        self.exclude
+            .iter()
+            .flat_map(|x| x.sites().iter().map(|x| x.list.clone()))
+            .collect()
+    }
+
+    pub fn load_geosite(&mut self, geosite: &GeoSite) -> Result<(), Vec<ConfigError>> {
+        match self.exclude.as_mut().map(|x| x.load_geosite(geosite)) {
+            Some(Err(e)) => Err(vec![ConfigError::new("FAKEDNS", e)]),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn is_excluded(&self, domain: &str) -> bool {
+        self.exclude.as_ref().is_some_and(|x| x.matches(domain))
+    }
+
+    fn pool(&self, ipv4: bool) -> Option<&Rc<RefCell<Pool>>> {
+        self.pools
+            .iter()
+            .find(|x| x.borrow().options.net.is_ipv4() == ipv4)
+    }
+
+    // a fake address for `domain`, or None when it is excluded or there is
+    // no pool of the address family asked for
+    pub fn lookup(&self, domain: &str, record_type: RecordType) -> Option<IpAddr> {
+        let domain = normalize_domain(domain);
+        if self.is_excluded(&domain) {
+            return None;
+        }
+        let pool = match record_type {
+            RecordType::A => self.pool(true)?,
+            RecordType::AAAA => self.pool(false)?,
+            _ => return None,
+        };
+        let ip = pool.borrow_mut().lookup(&domain);
+        Some(ip)
+    }
+
+    pub fn domain(&self, ip: IpAddr) -> Option<String> {
+        self.pool(ip.is_ipv4())?.borrow_mut().domain(ip)
+    }
+
+    // the response to a client's A or AAAA query. anything else, including
+    // excluded domains, is left to the real resolver by returning None.
+    pub fn answer(&self, query: &[u8]) -> Option<Vec<u8>> {
+        let query = Message::from_vec(query).ok()?;
+        let [question] = query.queries() else {
+            return None;
+        };
+        if query.message_type() != MessageType::Query
+            || question.query_class() != DNSClass::IN
+            || !matches!(question.query_type(), RecordType::A | RecordType::AAAA)
+        {
+            return None;
+        }
+        let domain = normalize_domain(&question.name().to_ascii());
+        if self.is_excluded(&domain) {
+            return None;
+        }
+
+        let mut response = Message::new();
+        response
+            .set_id(query.id())
+            .set_message_type(MessageType::Response)
+            .set_op_code(query.op_code())
+            .set_recursion_desired(query.recursion_desired())
+            .set_recursion_available(true)
+            .set_response_code(ResponseCode::NoError)
+            .add_query(question.clone());
+
+        // without a pool for the family the answer is empty, so the client
+        // falls back to the other one instead of a real address
+        let rdata = match self.lookup(&domain, question.query_type()) {
+            Some(IpAddr::V4(x)) => Some(RData::A(A(x))),
+            Some(IpAddr::V6(x)) => Some(RData::AAAA(AAAA(x))),
+            None => None,
+        };
+        if let Some(rdata) = rdata {
+            crate::log!("[fakedns]: {} is {}", domain, rdata);
+            response.add_answer(Record::from_rdata(question.name().clone(), FAKE_TTL, rdata));
+        }
+        response.to_vec().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::op::Query;
+    use hickory_proto::rr::Name;
+    use serde_json::json;
+
+    fn query(domain: &str, record_type: RecordType) -> Vec<u8> {
+        let mut message = Message::new();
+        message
+            .set_id(0x1234)
+            .set_recursion_desired(true)
+            .add_query(Query::query(Name::from_ascii(domain).unwrap(), record_type));
+        message.to_vec().unwrap()
+    }
+
+    fn answers(response: &[u8]) -> Vec<RData> {
+        let response = Message::from_vec(response).unwrap();
+        assert_eq!(response.id(), 0x1234);
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        response
+            .answers()
+            .iter()
+            .filter_map(|x| x.data().cloned())
+            .collect()
+    }
+
+    #[test]
+    fn test_pool_options() {
+        let options = PoolOptions::parse("198.18.1.2/15", DEFAULT_POOL_SIZE).unwrap();
+        assert_eq!(options.net, "198.18.0.0".parse::<IpAddr>().unwrap());
+        assert_eq!(options.size, 65535);
+        assert_eq!(options.nth(1), "198.18.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(
+            options.offset("198.18.255.255".parse().unwrap()),
+            Some(65535)
+        );
+        assert_eq!(options.offset("198.19.0.0".parse().unwrap()), None);
+        assert_eq!(options.offset("198.18.0.0".parse().unwrap()), None);
+        assert_eq!(options.offset("fc00::1".parse().unwrap()), None);
+
+        // the size is capped by the addresses in the network
+        assert_eq!(PoolOptions::parse("10.0.0.0/30", 100).unwrap().size, 3);
+        assert_eq!(PoolOptions::parse("fc00::/18", 10).unwrap().size, 10);
+        assert!(PoolOptions::parse("10.0.0.1/32", 10).is_err());
+        assert!(PoolOptions::parse("10.0.0.0/33", 10).is_err());
+        assert!(PoolOptions::parse("10.0.0.0", 10).is_err());
+        assert!(PoolOptions::parse("10.0.0.0/8", 0).is_err());
+    }
+
+    #[test]
+    fn test_pool_exhaustion() {
+        let mut pool = Pool::new(PoolOptions::parse("10.0.0.0/30", 3).unwrap());
+        let a = pool.lookup("a.com");
+        let b = pool.lookup("b.com");
+        let c = pool.lookup("c.com");
+        assert_eq!(pool.lookup("a.com"), a);
+        assert_eq!(pool.len(), 3);
+
+        // b.com is the least recently used once a.com was asked for again
+        // and c.com was connected to
+        assert_eq!(pool.domain(c).as_deref(), Some("c.com"));
+        assert_eq!(pool.lookup("d.com"), b);
+        assert_eq!(pool.domain(b).as_deref(), Some("d.com"));
+        assert_eq!(pool.lookup("a.com"), a);
+        assert_eq!(pool.len(), 3);
+
+        // c.com is next, b.com comes back on its address
+        assert_eq!(pool.lookup("b.com"), c);
+        assert_eq!(pool.domain(a).as_deref(), Some("a.com"));
+        assert_eq!(pool.domain("10.0.0.0".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn test_answer() {
+        let fakedns = FakeDns::from_json(
+            &json!([
+                {"ipPool": "198.18.0.0/15", "exclude": ["domain:lan", "full:real.example.com"]},
+                {"ipPool": "fc00::/18", "poolSize": 16},
+            ]),
+            "FAKEDNS",
+        )
+        .unwrap();
+
+        let v4 = answers(
+            &fakedns
+                .answer(&query("www.Example.com.", RecordType::A))
+                .unwrap(),
+        );
+        let v6 = answers(
+            &fakedns
+                .answer(&query("www.example.com.", RecordType::AAAA))
+                .unwrap(),
+        );
+        let (RData::A(v4), RData::AAAA(v6)) = (&v4[0], &v6[0]) else {
+            panic!("unexpected answers {v4:?} {v6:?}");
+        };
+        assert_eq!(
+            fakedns.domain(IpAddr::V4(v4.0)).as_deref(),
+            Some("www.example.com")
+        );
+        assert_eq!(
+            fakedns.domain(IpAddr::V6(v6.0)).as_deref(),
+            Some("www.example.com")
+        );
+        assert_eq!(
+            fakedns.lookup("www.example.com", RecordType::A),
+            Some(IpAddr::V4(v4.0))
+        );
+
+        for (domain, record_type) in [
+            ("printer.lan.", RecordType::A),
+            ("real.example.com.", RecordType::AAAA),
+            ("www.example.com.", RecordType::MX),
+        ] {
+            assert!(
+                fakedns.answer(&query(domain, record_type)).is_none(),
+                "{domain}"
+            );
+        }
+        assert!(fakedns.answer(b"not a dns message").is_none());
+
+        // no ipv6 pool, the aaaa answer is empty rather than real
+        let fakedns = FakeDns::from_json(&json!({}), "FAKEDNS").unwrap();
+        assert!(answers(
+            &fakedns
+                .answer(&query("example.com.", RecordType::AAAA))
+                .unwrap()
+        )
+        .is_empty());
+        assert_eq!(
+            fakedns.pools()[0].borrow().options().size,
+            DEFAULT_POOL_SIZE
+        );
+    }
+
+    #[test]
+    fn test_from_json_errors() {
+        let errors = FakeDns::from_json(
+            &json!([
+                {"ipPool": "198.18.0.0/15", "poolSize": -1},
+                {"ipPool": "10.0.0.0/8", "exclude": ["regexp:("]},
+                {"ipPool": "fc00::"},
+                "198.18.0.0/15",
+            ]),
+            "FAKEDNS",
+        )
+        .err()
+        .unwrap();
+        let paths: Vec<_> = errors.iter().map(|x| x.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "FAKEDNS[0].poolSize",
+                "FAKEDNS[1].exclude[0]",
+                "FAKEDNS[1].ipPool",
+                "FAKEDNS[2].ipPool",
+                "FAKEDNS[3]",
+            ]
+        );
+        assert!(FakeDns::from_json(&json!([]), "FAKEDNS").is_err());
+    }
+
+    #[test]
+    fn test_shared_pools() {
+        let options = PoolOptions::parse("198.18.0.0/15", 100).unwrap();
+        let mut first = FakeDns::new(vec![options]);
+        first.share_pools();
+        let ip = first.lookup("example.com", RecordType::A).unwrap();
+
+        // a rebuilt config finds the mapping, a changed one starts over
+        let mut second = FakeDns::new(vec![options]);
+        second.share_pools();
+        assert_eq!(second.domain(ip).as_deref(), Some("example.com"));
+
+        let mut third = FakeDns::new(vec![PoolOptions::parse("198.18.0.0/15", 200).unwrap()]);
+        third.share_pools();
+        assert_eq!(third.domain(ip), None);
+    }
+}
@@ -0,0 +1,511 @@
+use super::router::IpMatcher;
+use crate::common::protobuf::{Fields, Value};
+
+use maxminddb::{PathElement, WithinOptions};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::IpAddr;
+use std::rc::Rc;
+use worker::*;
+
+// built in, so `geoip:private` works without a data file
+pub const PRIVATE: &str = "private";
+
+const PRIVATE_CIDRS: &[&str] = &[
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "::1/128",
+    "fe80::/10",
+    "fc00::/7",
+];
+
+// kv entry holding either a geoip.dat or an mmdb, and the var with a url
+// to fill it from
+const GEOIP_KV_KEY: &str = "geoip";
+const GEOIP_URL_VAR: &str = "GEOIP_URL";
+
+const MMDB_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+// the spec caps the metadata section at 128KiB
+const MMDB_METADATA_MAX: usize = 128 * 1024;
+
+pub fn private() -> IpMatcher {
+    let mut matcher = IpMatcher::default();
+    for cidr in PRIVATE_CIDRS {
+        matcher.add(cidr).expect("valid builtin cidr");
+    }
+    matcher.build();
+    matcher
+}
+
+// country code -> cidr set, holding only the countries that were asked for.
+// codes are lowercase, like they are written in rules.
+#[derive(Debug)]
+pub struct GeoIp {
+    countries: BTreeMap<String, IpMatcher>,
+}
+
+impl Default for GeoIp {
+    fn default() -> Self {
+        let countries = BTreeMap::from([(PRIVATE.to_string(), private())]);
+        Self { countries }
+    }
+}
+
+impl GeoIp {
+    pub fn from_bytes(data: &[u8], wanted: &BTreeSet<String>) -> Result<Self> {
+        if is_mmdb(data) {
+            Self::from_mmdb(data, wanted)
+        } else {
+            Self::from_dat(data, wanted)
+        }
+    }
+
+    // v2ray's GeoIPList. entries that weren't asked for are skipped
+    // without decoding their cidrs.
+    pub fn from_dat(data: &[u8], wanted: &BTreeSet<String>) -> Result<Self> {
+        let mut geoip = Self::default();
+
+        for field in Fields::new(data) {
+            let (1, Value::Bytes(entry)) = field? else {
+                continue;
+            };
+
+            let mut code = None;
+            for field in Fields::new(entry) {
+                if let (1, x) = field? {
+                    code = x
+                        .bytes()
+                        .map(|x| String::from_utf8_lossy(x).to_ascii_lowercase());
+                    break;
+                }
+            }
+            let Some(code) = code.filter(|x| wanted.contains(x)) else {
+                continue;
+            };
+
+            let matcher = geoip.countries.entry(code.clone()).or_default();
+            for field in Fields::new(entry) {
+                match field? {
+                    (2, Value::Bytes(cidr)) => add_dat_cidr(matcher, cidr)?,
+                    (3, Value::Varint(x)) if x != 0 => {
+                        return Err(Error::RustError(format!(
+                            "geoip {code}: reverse_match is not supported"
+                        )))
+                    }
+                    _ => {}
+                }
+            }
+            matcher.build();
+        }
+
+        Ok(geoip)
+    }
+
+    // walks every network in the tree once, keeping the ones whose
+    // country (or registered country) was asked for
+    pub fn from_mmdb(data: &[u8], wanted: &BTreeSet<String>) -> Result<Self> {
+        let mmdb_error =
+            |e: maxminddb::MaxMindDbError| Error::RustError(format!("invalid mmdb: {e}"));
+        let reader = maxminddb::Reader::from_source(data).map_err(mmdb_error)?;
+
+        let mut geoip = Self::default();
+        // networks share data records, so each one is decoded once
+        let mut codes: HashMap<usize, Option<String>> = HashMap::new();
+
+        for item in reader
+            .networks(WithinOptions::default())
+            .map_err(mmdb_error)?
+        {
+            let item = item.map_err(mmdb_error)?;
+            let Some(offset) = item.offset() else {
+                continue;
+            };
+
+            let code = match codes.get(&offset) {
+                Some(x) => x.clone(),
+                None => {
+                    let mut code = None;
+                    for key in ["country", "registered_country"] {
+                        let path = [PathElement::Key(key), PathElement::Key("iso_code")];
+                        code = item.decode_path::<String>(&path).map_err(mmdb_error)?;
+                        if code.is_some() {
+                            break;
+                        }
+                    }
+                    let code = code
+                        .map(|x| x.to_ascii_lowercase())
+                        .filter(|x| wanted.contains(x));
+                    codes.insert(offset, code.clone());
+                    code
+                }
+            };
+
+            if let Some(code) = code {
+                let network = item.network().map_err(mmdb_error)?;
+                geoip
+                    .countries
+                    .entry(code)
+                    .or_default()
+                    .add_net(network.ip(), network.prefix());
+            }
+        }
+
+        for matcher in geoip.countries.values_mut() {
+            matcher.build();
+        }
+        Ok(geoip)
+    }
+
+    pub fn insert(&mut self, country: &str, mut matcher: IpMatcher) {
+        matcher.build();
+        self.countries.insert(country.to_ascii_lowercase(), matcher);
+    }
+
+    pub fn get(&self, country: &str) -> Option<&IpMatcher> {
+        self.countries.get(country)
+    }
+
+    // checks the loaded countries in code order, private last so a real
+    // country wins if a file claims part of a private range
+    pub fn lookup(&self, ip: IpAddr) -> Option<&str> {
+        self.countries
+            .iter()
+            .filter(|(code, _)| *code != PRIVATE)
+            .chain(self.countries.get_key_value(PRIVATE))
+            .find(|(_, matcher)| matcher.contains(ip))
+            .map(|(code, _)| code.as_str())
+    }
+
+    pub fn contains(&self, country: &str, ip: IpAddr) -> bool {
+        self.get(country).is_some_and(|x| x.contains(ip))
+    }
+}
+
+fn is_mmdb(data: &[u8]) -> bool {
+    let tail = &data[data.len().saturating_sub(MMDB_METADATA_MAX)..];
+    tail.windows(MMDB_MARKER.len()).any(|x| x == MMDB_MARKER)
+}
+
+fn add_dat_cidr(matcher: &mut IpMatcher, cidr: &[u8]) -> Result<()> {
+    let mut ip = None;
+    let mut prefix = None;
+    for field in Fields::new(cidr) {
+        match field? {
+            (1, Value::Bytes(x)) => ip = Some(x),
+            (2, x) => prefix = x.varint(),
+            _ => {}
+        }
+    }
+
+    let ip = match ip {
+        Some(x) => match <[u8; 4]>::try_from(x) {
+            Ok(x) => IpAddr::from(x),
+            Err(_) => <[u8; 16]>::try_from(x).map(IpAddr::from).map_err(|_| {
+                Error::RustError(format!("invalid geoip address length {}", x.len()))
+            })?,
+        },
+        None => {
+            return Err(Error::RustError(
+                "geoip cidr without an address".to_string(),
+            ))
+        }
+    };
+    // a missing prefix is the protobuf default of 0
+    let prefix = u8::try_from(prefix.unwrap_or(0))
+        .map_err(|_| Error::RustError("invalid geoip prefix".to_string()))?;
+    matcher.add_net(ip, prefix);
+    Ok(())
+}
+
+thread_local! {
+    // the worker isolate outlives a request, so the parsed file is kept
+    // around for as long as the rules ask for the same countries
+    static CACHE: RefCell<Option<(BTreeSet<String>, Rc<GeoIp>)>> = const { RefCell::new(None) };
+}
+
+// loads the `geoip` kv entry, see `load_file`. `private` never needs the
+// file.
+pub async fn load(env: &Env, wanted: &BTreeSet<String>) -> Result<Rc<GeoIp>> {
+    let wanted: BTreeSet<String> = wanted.iter().filter(|x| *x != PRIVATE).cloned().collect();
+    if wanted.is_empty() {
+        return Ok(Rc::default());
+    }
+
+    let cached = CACHE.with_borrow(|x| match x {
+        Some((countries, geoip)) if *countries == wanted => Some(geoip.clone()),
+        _ => None,
+    });
+    if let Some(geoip) = cached {
+        return Ok(geoip);
+    }
+
+    let data = load_file(env, GEOIP_KV_KEY, GEOIP_URL_VAR).await?;
+    let geoip = Rc::new(GeoIp::from_bytes(&data, &wanted)?);
+    CACHE.set(Some((wanted, geoip.clone())));
+    Ok(geoip)
+}
+
+// reads a data file from the SIREN kv, filling the entry from the url in
+// `url_var` first if it is missing. shared with geosite.
+pub(crate) async fn load_file(env: &Env, kv_key: &str, url_var: &str) -> Result<Vec<u8>> {
+    let kv = env.kv("SIREN")?;
+    if let Some(data) = kv.get(kv_key).bytes().await? {
+        return Ok(data);
+    }
+
+    let Ok(url) = env.var(url_var).map(|x| x.to_string()) else {
+        return Err(Error::RustError(format!(
+            "{kv_key} rules need a `{kv_key}` kv entry or {url_var}"
+        )));
+    };
+    crate::log!("getting {} from {}...", kv_key, url);
+    let mut res = Fetch::Url(Url::parse(&url)?).send().await?;
+    if res.status_code() != 200 {
+        return Err(Error::from(format!(
+            "error getting {kv_key}: {}",
+            res.status_code()
+        )));
+    }
+    let data = res.bytes().await?;
+    kv.put_bytes(kv_key, &data)?
+        .expiration_ttl(60 * 60 * 24 * 7)
+        .execute()
+        .await?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::protobuf::Writer;
+
+    fn wanted(codes: &[&str]) -> BTreeSet<String> {
+        codes.iter().map(|x| x.to_string()).collect()
+    }
+
+    fn ip(x: &str) -> IpAddr {
+        x.parse().unwrap()
+    }
+
+    // first and last address of every range, plus the neighbours outside it
+    fn assert_boundaries(geoip: &GeoIp) {
+        for (addr, expected) in [
+            ("1.0.0.255", None),
+            ("1.0.1.0", Some("cn")),
+            ("1.0.1.255", Some("cn")),
+            ("1.0.2.0", None),
+            ("1.0.7.255", None),
+            ("1.0.8.0", Some("cn")),
+            ("1.0.15.255", Some("cn")),
+            ("1.0.16.0", None),
+            ("8.8.7.255", None),
+            ("8.8.8.0", Some("us")),
+            ("8.8.8.255", Some("us")),
+            ("8.8.9.0", None),
+            ("2001:24f:ffff:ffff:ffff:ffff:ffff:ffff", None),
+            ("2001:250::", Some("cn")),
+            ("2001:250:1fff:ffff:ffff:ffff:ffff:ffff", Some("cn")),
+            ("2001:250:2000::", None),
+            ("192.168.1.1", Some("private")),
+            ("fd00::1", Some("private")),
+        ] {
+            assert_eq!(geoip.lookup(ip(addr)), expected, "{addr}");
+        }
+        assert!(geoip.contains("cn", ip("1.0.8.1")));
+        assert!(!geoip.contains("us", ip("1.0.8.1")));
+    }
+
+    const FIXTURE: &[(&str, &str)] = &[
+        ("CN", "1.0.1.0/24"),
+        ("CN", "1.0.8.0/21"),
+        ("US", "8.8.8.0/24"),
+        ("CN", "2001:250::/35"),
+        ("JP", "1.0.16.0/20"),
+    ];
+
+    // a trimmed GeoIPList: entry { country_code, cidr { ip, prefix } }
+    fn dat_fixture() -> Vec<u8> {
+        let mut list = Writer::default();
+        for code in ["CN", "US", "JP"] {
+            let mut entry = Writer::default();
+            entry.bytes(1, code.as_bytes());
+            for (_, cidr) in FIXTURE.iter().filter(|(c, _)| *c == code) {
+                let (addr, prefix) = cidr.split_once('/').unwrap();
+                let addr = match ip(addr) {
+                    IpAddr::V4(x) => x.octets().to_vec(),
+                    IpAddr::V6(x) => x.octets().to_vec(),
+                };
+                let mut cidr = Writer::default();
+                cidr.bytes(1, &addr).uint(2, prefix.parse().unwrap());
+                entry.bytes(2, &cidr.0);
+            }
+            list.bytes(1, &entry.0);
+        }
+        list.0
+    }
+
+    // a hand-built ipv6 mmdb with 32 bit records: search tree, 16 byte
+    // separator, data section, then the metadata map after the marker
+    mod mmdb {
+        pub fn string(out: &mut Vec<u8>, x: &str) {
+            out.push(2 << 5 | x.len() as u8);
+            out.extend_from_slice(x.as_bytes());
+        }
+
+        pub fn map(out: &mut Vec<u8>, pairs: usize) {
+            out.push(7 << 5 | pairs as u8);
+        }
+
+        pub fn uint16(out: &mut Vec<u8>, x: u16) {
+            out.push(5 << 5 | 2);
+            out.extend_from_slice(&x.to_be_bytes());
+        }
+
+        pub fn uint32(out: &mut Vec<u8>, x: u32) {
+            out.push(6 << 5 | 4);
+            out.extend_from_slice(&x.to_be_bytes());
+        }
+
+        // extended types carry `type - 7` in a second byte
+        pub fn uint64(out: &mut Vec<u8>, x: u64) {
+            out.extend_from_slice(&[8, 9 - 7]);
+            out.extend_from_slice(&x.to_be_bytes());
+        }
+
+        pub fn array(out: &mut Vec<u8>, len: usize) {
+            out.extend_from_slice(&[len as u8, 11 - 7]);
+        }
+    }
+
+    #[derive(Clone, Copy)]
+    enum Record {
+        Empty,
+        Node(usize),
+        Data(usize),
+    }
+
+    fn mmdb_fixture() -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut offsets = HashMap::new();
+        for code in ["CN", "US", "JP"] {
+            offsets.insert(code, data.len());
+            mmdb::map(&mut data, 1);
+            mmdb::string(&mut data, "country");
+            mmdb::map(&mut data, 1);
+            mmdb::string(&mut data, "iso_code");
+            mmdb::string(&mut data, code);
+        }
+
+        let mut nodes = vec![[Record::Empty; 2]];
+        for (code, cidr) in FIXTURE {
+            let (addr, prefix) = cidr.split_once('/').unwrap();
+            let prefix: usize = prefix.parse().unwrap();
+            // ipv4 lives under ::/96 in an ipv6 tree
+            let (bits, depth) = match ip(addr) {
+                IpAddr::V4(x) => (u128::from(u32::from(x)), 96 + prefix),
+                IpAddr::V6(x) => (u128::from(x), prefix),
+            };
+
+            let mut node = 0;
+            for d in 0..depth {
+                let bit = (bits >> (127 - d) & 1) as usize;
+                if d == depth - 1 {
+                    nodes[node][bit] = Record::Data(offsets[code]);
+                    break;
+                }
+                node = match nodes[node][bit] {
+                    Record::Node(x) => x,
+                    Record::Empty => {
+                        nodes.push([Record::Empty; 2]);
+                        nodes[node][bit] = Record::Node(nodes.len() - 1);
+                        nodes.len() - 1
+                    }
+                    Record::Data(_) => unreachable!("fixture networks overlap"),
+                };
+            }
+        }
+
+        let count = nodes.len();
+        let mut out = Vec::new();
+        for records in &nodes {
+            for record in records {
+                let x = match *record {
+                    Record::Empty => count,
+                    Record::Node(x) => x,
+                    Record::Data(x) => count + 16 + x,
+                };
+                out.extend_from_slice(&(x as u32).to_be_bytes());
+            }
+        }
+        out.extend_from_slice(&[0; 16]);
+        out.extend_from_slice(&data);
+
+        out.extend_from_slice(MMDB_MARKER);
+        mmdb::map(&mut out, 9);
+        mmdb::string(&mut out, "node_count");
+        mmdb::uint32(&mut out, count as u32);
+        mmdb::string(&mut out, "record_size");
+        mmdb::uint16(&mut out, 32);
+        mmdb::string(&mut out, "ip_version");
+        mmdb::uint16(&mut out, 6);
+        mmdb::string(&mut out, "database_type");
+        mmdb::string(&mut out, "GeoLite2-Country");
+        mmdb::string(&mut out, "languages");
+        mmdb::array(&mut out, 0);
+        mmdb::string(&mut out, "binary_format_major_version");
+        mmdb::uint16(&mut out, 2);
+        mmdb::string(&mut out, "binary_format_minor_version");
+        mmdb::uint16(&mut out, 0);
+        mmdb::string(&mut out, "build_epoch");
+        mmdb::uint64(&mut out, 0);
+        mmdb::string(&mut out, "description");
+        mmdb::map(&mut out, 0);
+        out
+    }
+
+    #[test]
+    fn test_geoip_dat() {
+        let data = dat_fixture();
+        assert!(!is_mmdb(&data));
+        assert_boundaries(&GeoIp::from_bytes(&data, &wanted(&["cn", "us"])).unwrap());
+    }
+
+    #[test]
+    fn test_geoip_mmdb() {
+        let data = mmdb_fixture();
+        assert!(is_mmdb(&data));
+        assert_boundaries(&GeoIp::from_bytes(&data, &wanted(&["cn", "us"])).unwrap());
+    }
+
+    #[test]
+    fn test_geoip_only_wanted_countries() {
+        for data in [dat_fixture(), mmdb_fixture()] {
+            let geoip = GeoIp::from_bytes(&data, &wanted(&["jp"])).unwrap();
+            let codes: Vec<_> = geoip.countries.keys().map(|x| x.as_str()).collect();
+            assert_eq!(codes, ["jp", "private"]);
+            assert_eq!(geoip.lookup(ip("1.0.16.0")), Some("jp"));
+            assert_eq!(geoip.lookup(ip("1.0.1.0")), None);
+        }
+    }
+
+    #[test]
+    fn test_geoip_private() {
+        let geoip = GeoIp::default();
+        for addr in [
+            "10.0.0.0",
+            "172.31.255.255",
+            "127.0.0.1",
+            "169.254.0.1",
+            "::1",
+            "fe80::1",
+        ] {
+            assert!(geoip.contains(PRIVATE, ip(addr)), "{addr}");
+        }
+        for addr in ["172.32.0.0", "11.0.0.0", "2001:db8::1", "::2"] {
+            assert!(!geoip.contains(PRIVATE, ip(addr)), "{addr}");
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use super::geoip::load_file;
+use super::router::DomainMatcher;
+use crate::common::protobuf::{Fields, Value};
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::rc::Rc;
+use worker::*;
+
+const GEOSITE_KV_KEY: &str = "geosite";
+const GEOSITE_URL_VAR: &str = "GEOSITE_URL";
+
+// Domain.Type in v2ray's routercommon.proto
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DomainKind {
+    Keyword,
+    Regex,
+    Suffix,
+    Full,
+}
+
+impl DomainKind {
+    fn from_wire(x: u64) -> Option<Self> {
+        match x {
+            0 => Some(Self::Keyword),
+            1 => Some(Self::Regex),
+            2 => Some(Self::Suffix),
+            3 => Some(Self::Full),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SiteDomain {
+    pub kind: DomainKind,
+    pub value: String,
+    // attribute keys, lowercase
+    pub attrs: Vec<String>,
+}
+
+// `geosite:cn@ads@tracking` without the prefix: a list name and the attributes
+// every entry taken from it has to carry
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SiteRef {
+    pub list: String,
+    pub attrs: Vec<String>,
+}
+
+impl SiteRef {
+    pub fn parse(s: &str) -> std::result::Result<Self, String> {
+        let mut parts = s.split('@').map(|x| x.trim().to_ascii_lowercase());
+        let list = parts.next().unwrap_or_default();
+        let attrs: Vec<String> = parts.collect();
+        if list.is_empty() || attrs.iter().any(|x| x.is_empty()) {
+            return Err(format!("invalid geosite list {s:?}"));
+        }
+        if let Some(x) = attrs.iter().find(|x| x.starts_with('!')) {
+            return Err(format!("negated geosite attribute {x:?} is not supported"));
+        }
+        Ok(Self { list, attrs })
+    }
+}
+
+// list name -> entries, holding only the lists that were asked for
+#[derive(Debug, Default)]
+pub struct GeoSite {
+    lists: BTreeMap<String, Vec<SiteDomain>>,
+}
+
+impl GeoSite {
+    // v2ray's GeoSiteList. entries are copied out only for the wanted
+    // lists, everything else is skipped over.
+    pub fn from_dat(data: &[u8], wanted: &BTreeSet<String>) -> Result<Self> {
+        let mut geosite = Self::default();
+
+        for field in Fields::new(data) {
+            let (1, Value::Bytes(entry)) = field? else {
+                continue;
+            };
+
+            let mut code = None;
+            for field in Fields::new(entry) {
+                if let (1, x) = field? {
+                    code = x
+                        .bytes()
+                        .map(|x| String::from_utf8_lossy(x).to_ascii_lowercase());
+                    break;
+                }
+            }
+            let Some(code) = code.filter(|x| wanted.contains(x)) else {
+                continue;
+            };
+
+            let mut domains = Vec::new();
+            for field in Fields::new(entry) {
+                if let (2, Value::Bytes(domain)) = field? {
+                    domains.push(parse_domain(domain)?);
+                }
+            }
+            geosite.lists.entry(code).or_default().extend(domains);
+        }
+
+        Ok(geosite)
+    }
+
+    pub fn insert(&mut self, list: &str, domains: Vec<SiteDomain>) {
+        self.lists.insert(list.to_ascii_lowercase(), domains);
+    }
+
+    pub fn get(&self, list: &str) -> Option<&[SiteDomain]> {
+        self.lists.get(list).map(|x| x.as_slice())
+    }
+
+    // copies the entries of `site` into `matcher`. the caller still has to
+    // `build` it.
+    pub fn add_to(
+        &self,
+        site: &SiteRef,
+        matcher: &mut DomainMatcher,
+    ) -> std::result::Result<(), String> {
+        let Some(domains) = self.get(&site.list) else {
+            return Err(format!("unknown geosite list {:?}", site.list));
+        };
+
+        let selected = domains
+            .iter()
+            .filter(|x| site.attrs.iter().all(|attr| x.attrs.contains(attr)));
+        for domain in selected {
+            match domain.kind {
+                DomainKind::Keyword => matcher.add_keyword(&domain.value),
+                DomainKind::Regex => matcher.add_regex(&domain.value),
+                DomainKind::Suffix => matcher.add_suffix(&domain.value),
+                DomainKind::Full => matcher.add_full(&domain.value),
+            }
+        }
+        Ok(())
+    }
+}
+
+// Domain { type, value, repeated Attribute { key, bool_value | int_value } }.
+// an attribute counts as present whatever its value.
+fn parse_domain(data: &[u8]) -> Result<SiteDomain> {
+    let mut kind = Some(DomainKind::Keyword);
+    let mut value = String::new();
+    let mut attrs = Vec::new();
+
+    for field in Fields::new(data) {
+        match field? {
+            (1, Value::Varint(x)) => kind = DomainKind::from_wire(x),
+            (2, Value::Bytes(x)) => value = String::from_utf8_lossy(x).into_owned(),
+            (3, Value::Bytes(attr)) => {
+                for field in Fields::new(attr) {
+                    if let (1, Value::Bytes(key)) = field? {
+                        attrs.push(String::from_utf8_lossy(key).to_ascii_lowercase());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let kind =
+        kind.ok_or_else(|| Error::RustError(format!("geosite {value:?}: unknown domain type")))?;
+    Ok(SiteDomain { kind, value, attrs })
+}
+
+thread_local! {
+    // kept per isolate while the rules ask for the same lists, same as geoip
+    static CACHE: RefCell<Option<(BTreeSet<String>, Rc<GeoSite>)>> = const { RefCell::new(None) };
+}
+
+// loads the `geosite` kv entry, see `geoip::load_file`
+pub async fn load(env: &Env, wanted: &BTreeSet<String>) -> Result<Rc<GeoSite>> {
+    let cached = CACHE.with_borrow(|x| match x {
+        Some((lists, geosite)) if lists == wanted => Some(geosite.clone()),
+        _ => None,
+    });
+    if let Some(geosite) = cached {
+        return Ok(geosite);
+    }
+
+    let data = load_file(env, GEOSITE_KV_KEY, GEOSITE_URL_VAR).await?;
+    let geosite = Rc::new(GeoSite::from_dat(&data, wanted)?);
+    CACHE.set(Some((wanted.clone(), geosite.clone())));
+    Ok(geosite)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::router::normalize_domain;
+    use crate::common::protobuf::Writer;
+
+    // (list, type, value, attributes)
+    const FIXTURE: &[(&str, u64, &str, &[&str])] = &[
+        ("GOOGLE", 2, "google.com", &[]),
+        ("GOOGLE", 3, "www.youtube.com", &[]),
+        ("GOOGLE", 0, "googleapis", &[]),
+        ("GOOGLE", 1, "^gstatic[0-9]*\\.", &[]),
+        ("GOOGLE", 2, "google.cn", &["cn"]),
+        ("CATEGORY-ADS-ALL", 2, "doubleclick.net", &["ads"]),
+        ("CATEGORY-ADS-ALL", 3, "ads.example.com", &["ads"]),
+        ("CN", 2, "baidu.com", &[]),
+        ("CN", 2, "pos.baidu.com", &["ads"]),
+        ("CN", 3, "cpro.baidustatic.com", &["ads", "tracking"]),
+        ("CN", 2, "qq.com", &["tracking"]),
+        ("HOSTILE", 1, "(?:\\w{100}){100}", &[]),
+    ];
+
+    fn dat_fixture() -> Vec<u8> {
+        let mut list = Writer::default();
+        for code in ["GOOGLE", "CATEGORY-ADS-ALL", "CN", "HOSTILE"] {
+            let mut entry = Writer::default();
+            entry.bytes(1, code.as_bytes());
+            for (_, kind, value, attrs) in FIXTURE.iter().filter(|x| x.0 == code) {
+                let mut domain = Writer::default();
+                domain.uint(1, *kind).bytes(2, value.as_bytes());
+                for attr in attrs.iter() {
+                    let mut a = Writer::default();
+                    a.bytes(1, attr.as_bytes()).uint(2, 1);
+                    domain.bytes(3, &a.0);
+                }
+                entry.bytes(2, &domain.0);
+            }
+            list.bytes(1, &entry.0);
+        }
+        list.0
+    }
+
+    fn matcher(geosite: &GeoSite, specs: &[&str]) -> std::result::Result<DomainMatcher, String> {
+        let mut m = DomainMatcher::default();
+        for spec in specs {
+            m.add(spec)?;
+        }
+        m.load_geosite(geosite)?;
+        Ok(m)
+    }
+
+    #[test]
+    fn test_geosite_lazy() {
+        let wanted = BTreeSet::from(["cn".to_string()]);
+        let geosite = GeoSite::from_dat(&dat_fixture(), &wanted).unwrap();
+        assert_eq!(geosite.lists.keys().collect::<Vec<_>>(), ["cn"]);
+        assert_eq!(
+            geosite.get("cn").unwrap()[2],
+            SiteDomain {
+                kind: DomainKind::Full,
+                value: "cpro.baidustatic.com".to_string(),
+                attrs: vec!["ads".to_string(), "tracking".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_geosite_matrix() {
+        let wanted = ["google", "category-ads-all", "cn", "hostile"];
+        let wanted = wanted.iter().map(|x| x.to_string()).collect();
+        let geosite = GeoSite::from_dat(&dat_fixture(), &wanted).unwrap();
+
+        for (spec, domain, expected) in [
+            ("geosite:google", "google.com", true),
+            ("geosite:google", "mail.google.com", true),
+            ("geosite:google", "notgoogle.com", false),
+            ("geosite:google", "www.youtube.com", true),
+            ("geosite:google", "m.youtube.com", false),
+            ("geosite:google", "storage.googleapis.net", true),
+            ("geosite:google", "gstatic1.com", true),
+            ("geosite:google", "fonts.gstatic.com", false),
+            ("geosite:google", "maps.google.cn", true),
+            ("geosite:google@cn", "maps.google.cn", true),
+            ("geosite:google@cn", "google.com", false),
+            ("geosite:category-ads-all", "ad.doubleclick.net", true),
+            ("geosite:category-ads-all", "ads.example.com", true),
+            ("geosite:category-ads-all", "x.ads.example.com", false),
+            ("geosite:cn", "baidu.com", true),
+            ("geosite:cn", "qq.com", true),
+            ("geosite:cn@ads", "pos.baidu.com", true),
+            ("geosite:cn@ads", "baidu.com", false),
+            ("geosite:cn@ads", "cpro.baidustatic.com", true),
+            ("geosite:cn@ads", "qq.com", false),
+            ("geosite:CN@Ads@tracking", "cpro.baidustatic.com", true),
+            ("geosite:cn@ads@tracking", "pos.baidu.com", false),
+            ("geosite:cn@tracking", "www.qq.com", true),
+        ] {
+            let m = matcher(&geosite, &[spec]).unwrap();
+            assert_eq!(
+                m.matches(&normalize_domain(domain)),
+                expected,
+                "{spec} {domain}"
+            );
+        }
+
+        assert!(matcher(&geosite, &["geosite:missing"]).is_err());
+        assert!(matcher(&geosite, &["geosite:cn@"]).is_err());
+        // blows through the regex size limit instead of eating memory
+        assert!(matcher(&geosite, &["geosite:hostile"]).is_err());
+    }
+}
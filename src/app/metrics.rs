@@ -0,0 +1,500 @@
+use super::dns::cache::CacheStats;
+use super::policy::Policy;
+use super::stats::{Counter, Stats};
+use crate::config::ConfigError;
+
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
+use worker::Error;
+
+pub const DEFAULT_METRICS_PATH: &str = "/metrics";
+
+// pages the worker already serves
+const RESERVED_PATHS: &[&str] = &["/", "/link", "/sub"];
+
+// upper bounds in seconds, the last bucket is +Inf
+const HANDSHAKE_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// the optional `METRICS` binding. workers can not listen on a port, the
+// text format is served on a path of the worker's own host instead.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MetricsConfig {
+    pub path: String,
+    // required as `Authorization: Bearer <token>` when set
+    pub token: Option<String>,
+    // a series per user, off by default to keep label cardinality down
+    pub users: bool,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            path: DEFAULT_METRICS_PATH.to_string(),
+            token: None,
+            users: false,
+        }
+    }
+}
+
+impl MetricsConfig {
+    pub fn from_json(value: &Value, path: &str) -> Result<Self, Vec<ConfigError>> {
+        let Some(object) = value.as_object() else {
+            return Err(vec![ConfigError::new(path, "expected an object")]);
+        };
+
+        let mut errors = Vec::new();
+        let mut config = Self::default();
+        for (key, value) in object {
+            let path = format!("{path}.{key}");
+            match key.as_str() {
+                "path" => match value.as_str() {
+                    Some(x) if RESERVED_PATHS.contains(&x) => {
+                        errors.push(ConfigError::new(&path, format!("{x} is already served")))
+                    }
+                    Some(x) if x.starts_with('/') => config.path = x.to_string(),
+                    _ => errors.push(ConfigError::new(&path, "expected a path starting with /")),
+                },
+                "token" => match value.as_str() {
+                    Some(x) if !x.is_empty() => config.token = Some(x.to_string()),
+                    _ => errors.push(ConfigError::new(&path, "expected a non-empty string")),
+                },
+                "users" => match value.as_bool() {
+                    Some(x) => config.users = x,
+                    None => errors.push(ConfigError::new(&path, "expected a boolean")),
+                },
+                "listen" => errors.push(ConfigError::new(
+                    &path,
+                    "workers can not listen on a port, metrics are served on path",
+                )),
+                _ => errors.push(ConfigError::new(&path, "unknown field")),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn authorized(&self, authorization: Option<&str>) -> bool {
+        match &self.token {
+            Some(token) => authorization.and_then(|x| x.strip_prefix("Bearer ")) == Some(token),
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    // not cumulative, summed up when rendered
+    buckets: Vec<Counter>,
+    sum_micros: Counter,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| Counter::default()).collect(),
+            sum_micros: Counter::default(),
+        }
+    }
+
+    pub fn observe(&self, value: Duration) {
+        let secs = value.as_secs_f64();
+        let i = self.bounds.iter().position(|x| secs <= *x);
+        self.buckets[i.unwrap_or(self.bounds.len())].add(1);
+        self.sum_micros.add(value.as_micros() as u64);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|x| x.get()).sum()
+    }
+}
+
+// a relayed connection, counted as active until dropped
+pub struct Active(Vec<Rc<Gauge>>);
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.0.iter().for_each(|x| x.dec());
+    }
+}
+
+// what the counters in `Stats` do not cover. instrumented code takes its
+// handles once per connection, after that only atomics are touched, so
+// rendering never stands in the way of the data path.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    inbound_active: RefCell<BTreeMap<String, Rc<Gauge>>>,
+    outbound_active: RefCell<BTreeMap<String, Rc<Gauge>>>,
+    handshakes: RefCell<BTreeMap<String, Rc<Histogram>>>,
+    // by (dialer, kind) and (outbound, kind)
+    dial_errors: RefCell<BTreeMap<(String, &'static str), Rc<Counter>>>,
+    outbound_errors: RefCell<BTreeMap<(String, &'static str), Rc<Counter>>>,
+}
+
+impl Metrics {
+    pub fn connection(&self, inbound: &str, outbound: &str) -> Active {
+        let gauges = [
+            (&self.inbound_active, inbound),
+            (&self.outbound_active, outbound),
+        ];
+        Active(
+            gauges
+                .into_iter()
+                .map(|(map, tag)| {
+                    let gauge = map.borrow_mut().entry(tag.to_string()).or_default().clone();
+                    gauge.inc();
+                    gauge
+                })
+                .collect(),
+        )
+    }
+
+    pub fn handshake(&self, inbound: &str) -> Rc<Histogram> {
+        self.handshakes
+            .borrow_mut()
+            .entry(inbound.to_string())
+            .or_insert_with(|| Rc::new(Histogram::new(HANDSHAKE_BUCKETS)))
+            .clone()
+    }
+
+    pub fn dial_error(&self, dialer: &str, e: &Error) {
+        let key = (dialer.to_string(), error_kind(e));
+        self.dial_errors.borrow_mut().entry(key).or_default().add(1);
+    }
+
+    pub fn outbound_error(&self, tag: &str, e: &Error) {
+        let key = (tag.to_string(), error_kind(e));
+        self.outbound_errors
+            .borrow_mut()
+            .entry(key)
+            .or_default()
+            .add(1);
+    }
+
+    // prometheus' text format, traffic comes from the stats counters
+    pub fn render(&self, stats: &Stats, dns: Option<CacheStats>, users: bool) -> String {
+        let mut out = String::new();
+        let header = |out: &mut String, name: &str, kind: &str, help: &str| {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+        };
+
+        for (name, map, help) in [
+            (
+                "siren_inbound_active_connections",
+                &self.inbound_active,
+                "Connections being relayed, by inbound.",
+            ),
+            (
+                "siren_outbound_active_connections",
+                &self.outbound_active,
+                "Connections being relayed, by outbound.",
+            ),
+        ] {
+            header(&mut out, name, "gauge", help);
+            for (tag, gauge) in map.borrow().iter() {
+                let _ = writeln!(out, "{name}{{tag=\"{}\"}} {}", escape(tag), gauge.get());
+            }
+        }
+
+        let name = "siren_handshake_duration_seconds";
+        header(
+            &mut out,
+            name,
+            "histogram",
+            "Time from accepting to dispatching.",
+        );
+        for (inbound, histogram) in self.handshakes.borrow().iter() {
+            let inbound = escape(inbound);
+            let mut cumulative = 0;
+            for (i, bucket) in histogram.buckets.iter().enumerate() {
+                cumulative += bucket.get();
+                let le = match histogram.bounds.get(i) {
+                    Some(x) => x.to_string(),
+                    None => "+Inf".to_string(),
+                };
+                let _ = writeln!(
+                    out,
+                    "{name}_bucket{{inbound=\"{inbound}\",le=\"{le}\"}} {cumulative}"
+                );
+            }
+            let sum = histogram.sum_micros.get() as f64 / 1e6;
+            let _ = writeln!(out, "{name}_sum{{inbound=\"{inbound}\"}} {sum}");
+            let _ = writeln!(out, "{name}_count{{inbound=\"{inbound}\"}} {cumulative}");
+        }
+
+        for (name, map, label, help) in [
+            (
+                "siren_dial_errors_total",
+                &self.dial_errors,
+                "dialer",
+                "Failed dials, by dialer and error kind.",
+            ),
+            (
+                "siren_outbound_errors_total",
+                &self.outbound_errors,
+                "tag",
+                "Connections an outbound failed, by error kind.",
+            ),
+        ] {
+            header(&mut out, name, "counter", help);
+            for ((tag, kind), counter) in map.borrow().iter() {
+                let tag = escape(tag);
+                let _ = writeln!(
+                    out,
+                    "{name}{{{label}=\"{tag}\",kind=\"{kind}\"}} {}",
+                    counter.get()
+                );
+            }
+        }
+
+        let mut traffic = vec![
+            ("inbound", "Bytes relayed, by inbound."),
+            ("outbound", "Bytes relayed, by outbound."),
+        ];
+        if users {
+            traffic.push(("user", "Bytes relayed, by user."));
+        }
+        for (kind, help) in traffic {
+            let name = format!("siren_{kind}_traffic_bytes_total");
+            header(&mut out, &name, "counter", help);
+            let label = if kind == "user" { "user" } else { "tag" };
+            for (counter, value) in stats.query(&format!("{kind}>>>"), false) {
+                // `kind>>>name>>>traffic>>>direction`, names may hold `>>>`
+                let Some(rest) = counter.strip_prefix(&format!("{kind}>>>")) else {
+                    continue;
+                };
+                let Some((tag, direction)) = rest.rsplit_once(">>>traffic>>>") else {
+                    continue;
+                };
+                let _ = writeln!(
+                    out,
+                    "{name}{{{label}=\"{}\",direction=\"{direction}\"}} {value}",
+                    escape(tag)
+                );
+            }
+        }
+
+        if let Some(dns) = dns {
+            header(
+                &mut out,
+                "siren_dns_cache_hits_total",
+                "counter",
+                "Dns cache hits.",
+            );
+            let _ = writeln!(out, "siren_dns_cache_hits_total {}", dns.hits);
+            header(
+                &mut out,
+                "siren_dns_cache_misses_total",
+                "counter",
+                "Dns cache misses.",
+            );
+            let _ = writeln!(out, "siren_dns_cache_misses_total {}", dns.misses);
+            let name = "siren_dns_cache_hit_ratio";
+            header(
+                &mut out,
+                name,
+                "gauge",
+                "Share of dns lookups answered by the cache.",
+            );
+            let total = dns.hits + dns.misses;
+            let ratio = if total == 0 {
+                0.0
+            } else {
+                dns.hits as f64 / total as f64
+            };
+            let _ = writeln!(out, "{name} {ratio}");
+        }
+
+        out
+    }
+}
+
+// errors are strings by the time they get here, sorted by what they say
+pub fn error_kind(e: &Error) -> &'static str {
+    let message = e.to_string().to_lowercase();
+    let kinds: &[(&'static str, &[&str])] = &[
+        ("timeout", &["timed out", "timeout"]),
+        ("refused", &["refused"]),
+        ("reset", &["reset", "broken pipe"]),
+        ("dns", &["resolv", "dns"]),
+        ("blocked", &["blocked"]),
+        ("unsupported", &["does not carry", "tcp only"]),
+    ];
+    kinds
+        .iter()
+        .find(|(_, needles)| needles.iter().any(|x| message.contains(x)))
+        .map(|(kind, _)| *kind)
+        .unwrap_or("other")
+}
+
+// the timeouts in force, 0 for a lifetime that is not capped
+pub fn render_policy(policy: &Policy) -> String {
+    let name = "siren_policy_timeout_seconds";
+    let mut out = format!(
+        "# HELP {name} Connection timeouts of the POLICY binding.\n# TYPE {name} gauge\n"
+    );
+    for (setting, value) in policy.settings() {
+        let _ = writeln!(out, "{name}{{setting=\"{setting}\"}} {}", value.as_secs());
+    }
+    out
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+thread_local! {
+    static SHARED: Rc<Metrics> = Rc::default();
+}
+
+// like the stats counters these live per isolate, every scrape sees the
+// isolate it lands on
+pub fn shared() -> Rc<Metrics> {
+    SHARED.with(Rc::clone)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::stats::{inbound_traffic, user_traffic, Direction};
+    use serde_json::json;
+
+    #[test]
+    fn test_metrics_config_from_json() {
+        let config = MetricsConfig::from_json(&json!({}), "METRICS").unwrap();
+        assert_eq!(config, MetricsConfig::default());
+        assert!(config.authorized(None));
+
+        let config = MetricsConfig::from_json(
+            &json!({"path": "/stats", "token": "secret", "users": true}),
+            "METRICS",
+        )
+        .unwrap();
+        assert_eq!(config.path, "/stats");
+        assert!(config.users);
+        assert!(config.authorized(Some("Bearer secret")));
+        assert!(!config.authorized(Some("Bearer other")));
+        assert!(!config.authorized(None));
+
+        let errors = MetricsConfig::from_json(
+            &json!({"listen": "127.0.0.1:9100", "path": "/sub", "users": 1}),
+            "METRICS",
+        )
+        .err()
+        .unwrap();
+        let paths: Vec<_> = errors.iter().map(|x| x.path.as_str()).collect();
+        assert_eq!(paths, ["METRICS.listen", "METRICS.path", "METRICS.users"]);
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        let first = metrics.connection("vless", "direct");
+        let second = metrics.connection("vmess", "direct");
+        drop(first);
+        let _ = second;
+
+        let handshake = metrics.handshake("vless");
+        handshake.observe(Duration::from_millis(3));
+        handshake.observe(Duration::from_millis(40));
+        handshake.observe(Duration::from_secs(30));
+        assert_eq!(handshake.count(), 3);
+
+        metrics.dial_error("socket", &Error::RustError("connection refused".into()));
+        metrics.outbound_error("block", &Error::RustError("blocked tcp:a:1".into()));
+
+        let stats = Stats::default();
+        stats
+            .counter(&inbound_traffic("vless", Direction::Uplink))
+            .add(10);
+        stats
+            .counter(&user_traffic("a\"b", Direction::Downlink))
+            .add(20);
+
+        let dns = CacheStats {
+            hits: 3,
+            misses: 1,
+            ..Default::default()
+        };
+        let text = metrics.render(&stats, Some(dns), false);
+        let lines: Vec<_> = text.lines().filter(|x| !x.starts_with('#')).collect();
+        for line in [
+            r#"siren_inbound_active_connections{tag="vless"} 0"#,
+            r#"siren_inbound_active_connections{tag="vmess"} 1"#,
+            r#"siren_outbound_active_connections{tag="direct"} 1"#,
+            r#"siren_handshake_duration_seconds_bucket{inbound="vless",le="0.005"} 1"#,
+            r#"siren_handshake_duration_seconds_bucket{inbound="vless",le="0.05"} 2"#,
+            r#"siren_handshake_duration_seconds_bucket{inbound="vless",le="10"} 2"#,
+            r#"siren_handshake_duration_seconds_bucket{inbound="vless",le="+Inf"} 3"#,
+            r#"siren_handshake_duration_seconds_sum{inbound="vless"} 30.043"#,
+            r#"siren_handshake_duration_seconds_count{inbound="vless"} 3"#,
+            r#"siren_dial_errors_total{dialer="socket",kind="refused"} 1"#,
+            r#"siren_outbound_errors_total{tag="block",kind="blocked"} 1"#,
+            r#"siren_inbound_traffic_bytes_total{tag="vless",direction="uplink"} 10"#,
+            "siren_dns_cache_hits_total 3",
+            "siren_dns_cache_hit_ratio 0.75",
+        ] {
+            assert!(lines.contains(&line), "{line} not in\n{text}");
+        }
+        assert!(!text.contains("siren_user_traffic_bytes_total"));
+
+        // users are opt-in, and their names are escaped
+        let text = metrics.render(&stats, None, true);
+        assert!(
+            text.contains(r#"siren_user_traffic_bytes_total{user="a\"b",direction="downlink"} 20"#)
+        );
+        assert!(!text.contains("siren_dns_cache"));
+    }
+
+    #[test]
+    fn test_render_policy() {
+        let text = render_policy(&Policy::default());
+        assert!(text.contains(r#"siren_policy_timeout_seconds{setting="connIdle"} 300"#));
+        assert!(text.contains(r#"siren_policy_timeout_seconds{setting="udpIdle"} 60"#));
+        assert!(text.contains(r#"siren_policy_timeout_seconds{setting="maxLifetime"} 0"#));
+    }
+
+    #[test]
+    fn test_error_kind() {
+        let kind = |x: &str| error_kind(&Error::RustError(x.to_string()));
+        assert_eq!(kind("dial timed out"), "timeout");
+        assert_eq!(kind("Connection refused (os error 111)"), "refused");
+        assert_eq!(
+            kind("can not dial udp:1.1.1.1:53, worker sockets are tcp only"),
+            "unsupported"
+        );
+        assert_eq!(kind("something else"), "other");
+    }
+}
@@ -0,0 +1,14 @@
+pub mod access;
+pub mod api;
+pub mod dispatcher;
+pub mod dns;
+pub mod fakedns;
+pub mod geoip;
+pub mod geosite;
+pub mod metrics;
+pub mod policy;
+pub mod router;
+pub mod sniff;
+pub mod stats;
+
+pub use dispatcher::*;
@@ -0,0 +1,153 @@
+use crate::common::relay::Timeouts;
+use crate::config::ConfigError;
+use crate::outbound::Network;
+
+use serde_json::Value;
+use std::time::Duration;
+
+// the optional `POLICY` binding, the timeouts of v2ray's level policy in
+// seconds. udp has a shorter idle timeout of its own, and connections can
+// be capped regardless of activity. `handshake` bounds the vmess header
+// exchange, before any of the others apply.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Policy {
+    pub handshake: Duration,
+    pub conn_idle: Duration,
+    pub udp_idle: Duration,
+    pub uplink_only: Duration,
+    pub downlink_only: Duration,
+    pub max_lifetime: Option<Duration>,
+}
+
+// v2ray's defaults, except for udp which it does not tell apart and the
+// handshake, which gets more than v2ray's 4s over a worker's sockets
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            handshake: Duration::from_secs(10),
+            conn_idle: Duration::from_secs(300),
+            udp_idle: Duration::from_secs(60),
+            uplink_only: Duration::from_secs(2),
+            downlink_only: Duration::from_secs(5),
+            max_lifetime: None,
+        }
+    }
+}
+
+impl Policy {
+    pub fn from_json(value: &Value, path: &str) -> Result<Self, Vec<ConfigError>> {
+        let Some(object) = value.as_object() else {
+            return Err(vec![ConfigError::new(path, "expected an object")]);
+        };
+
+        let mut errors = Vec::new();
+        let mut policy = Self::default();
+        for (key, value) in object {
+            let path = format!("{path}.{key}");
+            let seconds = value.as_u64().map(Duration::from_secs);
+            let setting = match key.as_str() {
+                "handshake" => &mut policy.handshake,
+                "connIdle" => &mut policy.conn_idle,
+                "udpIdle" => &mut policy.udp_idle,
+                "uplinkOnly" => &mut policy.uplink_only,
+                "downlinkOnly" => &mut policy.downlink_only,
+                // 0 leaves connections uncapped
+                "maxLifetime" => {
+                    match seconds {
+                        Some(x) => policy.max_lifetime = Some(x).filter(|x| !x.is_zero()),
+                        None => errors.push(ConfigError::new(&path, "expected seconds")),
+                    }
+                    continue;
+                }
+                _ => {
+                    errors.push(ConfigError::new(&path, "unknown field"));
+                    continue;
+                }
+            };
+            match seconds {
+                // a relay that is never idle long enough would be torn down
+                // before it starts, as would a handshake
+                Some(x) if x.is_zero() && (key.ends_with("Idle") || key == "handshake") => {
+                    errors.push(ConfigError::new(&path, "expected at least one second"))
+                }
+                Some(x) => *setting = x,
+                None => errors.push(ConfigError::new(&path, "expected seconds")),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(policy)
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn timeouts(&self, network: Network) -> Timeouts {
+        let idle = match network {
+            Network::Tcp => self.conn_idle,
+            Network::Udp => self.udp_idle,
+        };
+        Timeouts {
+            idle,
+            uplink_only: self.uplink_only,
+            downlink_only: self.downlink_only,
+            max_lifetime: self.max_lifetime,
+        }
+    }
+
+    // by their json names, for the metrics page
+    pub fn settings(&self) -> [(&'static str, Duration); 6] {
+        [
+            ("handshake", self.handshake),
+            ("connIdle", self.conn_idle),
+            ("udpIdle", self.udp_idle),
+            ("uplinkOnly", self.uplink_only),
+            ("downlinkOnly", self.downlink_only),
+            ("maxLifetime", self.max_lifetime.unwrap_or_default()),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_from_json() {
+        let policy = json!({
+            "connIdle": 600, "udpIdle": 30, "uplinkOnly": 0, "maxLifetime": 3600, "handshake": 4
+        });
+        let policy = Policy::from_json(&policy, "POLICY").unwrap();
+        assert_eq!(
+            policy.timeouts(Network::Tcp),
+            Timeouts {
+                idle: Duration::from_secs(600),
+                uplink_only: Duration::ZERO,
+                downlink_only: Duration::from_secs(5),
+                max_lifetime: Some(Duration::from_secs(3600)),
+            }
+        );
+        assert_eq!(policy.timeouts(Network::Udp).idle, Duration::from_secs(30));
+        assert_eq!(policy.handshake, Duration::from_secs(4));
+
+        let policy = Policy::from_json(&json!({"maxLifetime": 0}), "POLICY").unwrap();
+        assert_eq!(policy, Policy::default());
+
+        let errors = Policy::from_json(
+            &json!({"connIdle": 0, "downlinkOnly": "5s", "handshake": 0, "bufferSize": 4}),
+            "POLICY",
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            errors,
+            [
+                ConfigError::new("POLICY.bufferSize", "unknown field"),
+                ConfigError::new("POLICY.connIdle", "expected at least one second"),
+                ConfigError::new("POLICY.downlinkOnly", "expected seconds"),
+                ConfigError::new("POLICY.handshake", "expected at least one second"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,1448 @@
+use super::dns::QueryStrategy;
+use super::geoip::{self, GeoIp};
+use super::geosite::{GeoSite, SiteRef};
+use super::{Metadata, DEFAULT_OUTBOUND_TAG};
+use crate::common::time;
+use crate::config::ConfigError;
+use crate::outbound::{BalancerConfig, Network};
+
+use aho_corasick::AhoCorasick;
+use async_trait::async_trait;
+use regex::{RegexBuilder, RegexSetBuilder};
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::net::IpAddr;
+use std::time::Duration;
+use worker::*;
+
+// destinations that are never out on the internet, sent to the direct
+// outbound unless a rule says otherwise. `geoip:private` plus multicast.
+const PRIVATE_DIRECT_CIDRS: &[&str] = &["geoip:private", "224.0.0.0/4", "ff00::/8"];
+const PRIVATE_DIRECT_DOMAINS: &[&str] = &["domain:local"];
+
+// what to do when a rule wants an ip but the target is a domain
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DomainStrategy {
+    // ip conditions only ever see ip targets
+    #[default]
+    AsIs,
+    // try every rule on the domain first, then resolve and try again
+    IPIfNonMatch,
+    // resolve as soon as the first rule with an ip condition is reached
+    IPOnDemand,
+}
+
+impl DomainStrategy {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "AsIs" => Some(Self::AsIs),
+            "IPIfNonMatch" => Some(Self::IPIfNonMatch),
+            "IPOnDemand" => Some(Self::IPOnDemand),
+            _ => None,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+pub trait Resolve {
+    async fn resolve(&self, domain: &str) -> Result<Vec<IpAddr>>;
+
+    // a strategy other than the resolver's own, e.g. an outbound's
+    async fn resolve_with(&self, domain: &str, strategy: QueryStrategy) -> Result<Vec<IpAddr>> {
+        Ok(strategy.sort(self.resolve(domain).await?))
+    }
+}
+
+#[async_trait(?Send)]
+impl<T: Resolve + ?Sized> Resolve for std::rc::Rc<T> {
+    async fn resolve(&self, domain: &str) -> Result<Vec<IpAddr>> {
+        (**self).resolve(domain).await
+    }
+
+    async fn resolve_with(&self, domain: &str, strategy: QueryStrategy) -> Result<Vec<IpAddr>> {
+        (**self).resolve_with(domain, strategy).await
+    }
+}
+
+// lowercase without the trailing dot, which is how every matcher below
+// expects to be fed.
+pub fn normalize_domain(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+// suffixes keyed on reversed labels: `example.com` is stored as
+// com -> example, so a lookup walks at most one node per label.
+#[derive(Debug, Default)]
+struct DomainTrie {
+    children: HashMap<String, DomainTrie>,
+    terminal: bool,
+}
+
+impl DomainTrie {
+    fn insert(&mut self, suffix: &str) {
+        let node = suffix.rsplit('.').fold(self, |node, label| {
+            node.children.entry(label.to_string()).or_default()
+        });
+        node.terminal = true;
+    }
+
+    fn matches(&self, domain: &str) -> bool {
+        let mut node = self;
+        for label in domain.rsplit('.') {
+            match node.children.get(label) {
+                Some(x) if x.terminal => return true,
+                Some(x) => node = x,
+                None => return false,
+            }
+        }
+        false
+    }
+}
+
+// compiled program limits, so a hostile geosite file can't make the
+// regex set eat the isolate's memory
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+const REGEX_SET_SIZE_LIMIT: usize = 8 << 20;
+const REGEX_DFA_SIZE_LIMIT: usize = 2 << 20;
+
+// one matcher per rule. entries are added first and compiled by `build`,
+// which has to run before `matches`.
+#[derive(Debug, Default)]
+pub struct DomainMatcher {
+    full: HashSet<String>,
+    suffix: DomainTrie,
+    keywords: Vec<String>,
+    regexes: Vec<String>,
+    keyword_set: Option<AhoCorasick>,
+    regex_set: Option<regex::RegexSet>,
+    len: usize,
+    // `geosite:` lists waiting for `load_geosite`
+    sites: Vec<SiteRef>,
+}
+
+impl DomainMatcher {
+    // v2ray rule syntax: `full:`, `domain:`, `keyword:`, `regexp:`,
+    // `geosite:` and a bare string for a keyword
+    pub fn add(&mut self, spec: &str) -> std::result::Result<(), String> {
+        match spec.split_once(':') {
+            Some(("full", x)) => self.add_full(x),
+            Some(("domain", x)) => self.add_suffix(x),
+            Some(("keyword", x)) => self.add_keyword(x),
+            Some(("regexp", x)) => {
+                // checked on its own so the error points at this entry
+                RegexBuilder::new(x)
+                    .size_limit(REGEX_SIZE_LIMIT)
+                    .build()
+                    .map_err(|e| format!("invalid regex {x:?}: {e}"))?;
+                self.add_regex(x)
+            }
+            Some(("geosite", x)) => self.sites.push(SiteRef::parse(x)?),
+            Some(("ext", _)) => return Err("ext lists are not supported".to_string()),
+            _ => self.add_keyword(spec),
+        }
+        Ok(())
+    }
+
+    pub fn add_full(&mut self, domain: &str) {
+        self.full.insert(normalize_domain(domain));
+        self.len += 1;
+    }
+
+    pub fn add_suffix(&mut self, domain: &str) {
+        self.suffix.insert(&normalize_domain(domain));
+        self.len += 1;
+    }
+
+    pub fn add_keyword(&mut self, keyword: &str) {
+        self.keywords.push(keyword.to_ascii_lowercase());
+        self.len += 1;
+    }
+
+    // not compiled until `build`
+    pub fn add_regex(&mut self, pattern: &str) {
+        self.regexes.push(pattern.to_string());
+        self.len += 1;
+    }
+
+    pub fn build(&mut self) -> std::result::Result<(), String> {
+        if !self.keywords.is_empty() {
+            let set = AhoCorasick::new(&self.keywords).map_err(|e| e.to_string())?;
+            self.keyword_set = Some(set);
+        }
+        if !self.regexes.is_empty() {
+            let set = RegexSetBuilder::new(&self.regexes)
+                .size_limit(REGEX_SET_SIZE_LIMIT)
+                .dfa_size_limit(REGEX_DFA_SIZE_LIMIT)
+                .build()
+                .map_err(|e| format!("compiling {} regexes: {e}", self.regexes.len()))?;
+            self.regex_set = Some(set);
+        }
+        Ok(())
+    }
+
+    pub fn sites(&self) -> &[SiteRef] {
+        &self.sites
+    }
+
+    // copies the referenced lists in and compiles everything again
+    pub fn load_geosite(&mut self, geosite: &GeoSite) -> std::result::Result<(), String> {
+        for site in std::mem::take(&mut self.sites) {
+            geosite.add_to(&site, self)?;
+        }
+        self.build()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // `domain` must already be normalized
+    pub fn matches(&self, domain: &str) -> bool {
+        self.full.contains(domain)
+            || self.suffix.matches(domain)
+            || self
+                .keyword_set
+                .as_ref()
+                .is_some_and(|x| x.is_match(domain))
+            || self.regex_set.as_ref().is_some_and(|x| x.is_match(domain))
+    }
+}
+
+// cidrs flattened into sorted, non-overlapping address ranges so a lookup
+// is a binary search. like DomainMatcher, `build` has to run before
+// `contains`.
+#[derive(Clone, Debug, Default)]
+pub struct IpMatcher {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+    // `geoip:` countries waiting for `load_geoip`
+    countries: Vec<String>,
+}
+
+impl IpMatcher {
+    // `10.0.0.0/8`, `2001:db8::/32`, a bare address or `geoip:cn`
+    pub fn add(&mut self, cidr: &str) -> std::result::Result<(), String> {
+        if let Some(country) = cidr.strip_prefix("geoip:") {
+            match country.to_ascii_lowercase() {
+                x if x == geoip::PRIVATE => self.extend(&geoip::private()),
+                x if x.is_empty() || x.starts_with('!') => {
+                    return Err(format!("unsupported geoip code {country:?}"))
+                }
+                x => self.countries.push(x),
+            }
+            return Ok(());
+        }
+
+        let (addr, prefix) = match cidr.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (cidr, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid ip address {addr:?}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(x) => x
+                .parse::<u8>()
+                .ok()
+                .filter(|x| *x <= max)
+                .ok_or_else(|| format!("invalid prefix length {x:?}"))?,
+            None => max,
+        };
+        self.add_net(addr, prefix);
+        Ok(())
+    }
+
+    // `prefix` is clamped to the address width
+    pub fn add_net(&mut self, addr: IpAddr, prefix: u8) {
+        match addr {
+            IpAddr::V4(x) => {
+                let host = 32 - u32::from(prefix.min(32));
+                let mask = u32::MAX
+                    .checked_shr(host)
+                    .unwrap_or(0)
+                    .checked_shl(host)
+                    .unwrap_or(0);
+                let start = u32::from(x) & mask;
+                self.v4.push((start, start | !mask));
+            }
+            IpAddr::V6(x) => {
+                let host = 128 - u32::from(prefix.min(128));
+                let mask = u128::MAX
+                    .checked_shr(host)
+                    .unwrap_or(0)
+                    .checked_shl(host)
+                    .unwrap_or(0);
+                let start = u128::from(x) & mask;
+                self.v6.push((start, start | !mask));
+            }
+        }
+    }
+
+    pub fn extend(&mut self, other: &IpMatcher) {
+        self.v4.extend_from_slice(&other.v4);
+        self.v6.extend_from_slice(&other.v6);
+    }
+
+    pub fn build(&mut self) {
+        merge_ranges(&mut self.v4);
+        merge_ranges(&mut self.v6);
+    }
+
+    pub fn countries(&self) -> &[String] {
+        &self.countries
+    }
+
+    // merges the referenced countries into the ranges, so a lookup stays a
+    // single binary search
+    pub fn load_geoip(&mut self, geoip: &GeoIp) -> std::result::Result<(), String> {
+        for country in std::mem::take(&mut self.countries) {
+            match geoip.get(&country) {
+                Some(x) => self.extend(x),
+                None => return Err(format!("unknown geoip country {country:?}")),
+            }
+        }
+        self.build();
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.v4.is_empty() && self.v6.is_empty()
+    }
+
+    // v4-mapped v6 addresses are matched against the v4 ranges
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match addr.to_canonical() {
+            IpAddr::V4(x) => in_ranges(&self.v4, u32::from(x)),
+            IpAddr::V6(x) => in_ranges(&self.v6, u128::from(x)),
+        }
+    }
+}
+
+fn merge_ranges<T: Ord + Copy>(ranges: &mut Vec<(T, T)>) {
+    ranges.sort_unstable();
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for &(start, end) in ranges.iter() {
+        match merged.last_mut() {
+            // overlapping or nested; adjacent ranges are left apart, which
+            // costs one extra entry and saves the overflow checks
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    *ranges = merged;
+}
+
+fn in_ranges<T: Ord + Copy>(ranges: &[(T, T)], x: T) -> bool {
+    let i = ranges.partition_point(|(start, _)| *start <= x);
+    i > 0 && x <= ranges[i - 1].1
+}
+
+// everything a rule can look at, extracted from the metadata once per
+// connection
+#[derive(Clone, Debug)]
+pub struct Query {
+    pub domain: Option<String>,
+    pub ips: Vec<IpAddr>,
+    pub port: u16,
+    pub source: Option<IpAddr>,
+    pub inbound_tag: String,
+    pub network: Network,
+}
+
+impl Query {
+    pub fn new(metadata: &Metadata) -> Self {
+        let target_ip = metadata.target.addr.parse::<IpAddr>().ok();
+        let domain = match (&metadata.sniffed_host, target_ip) {
+            (Some(x), _) => Some(normalize_domain(x)),
+            (None, None) => Some(normalize_domain(&metadata.target.addr)),
+            (None, Some(_)) => None,
+        };
+
+        Self {
+            domain,
+            ips: target_ip.into_iter().collect(),
+            port: metadata.target.port,
+            source: metadata.source.as_deref().and_then(|x| x.parse().ok()),
+            inbound_tag: metadata.inbound_tag.clone(),
+            network: metadata.target.network,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Rule {
+    // with `balancer` set this names a balancer rather than an outbound
+    pub outbound_tag: String,
+    pub balancer: bool,
+    // how long the outbound gets to answer on this route
+    pub timeout: Option<Duration>,
+    domain: Option<DomainMatcher>,
+    ip: Option<IpMatcher>,
+    port: Option<Vec<(u16, u16)>>,
+    source: Option<IpMatcher>,
+    inbound_tag: Option<HashSet<String>>,
+    network: Option<Vec<Network>>,
+}
+
+impl Rule {
+    // every condition present has to match; within a condition any entry
+    // will do
+    pub fn matches(&self, query: &Query) -> bool {
+        if let Some(x) = &self.domain {
+            if !query.domain.as_deref().is_some_and(|d| x.matches(d)) {
+                return false;
+            }
+        }
+        if let Some(x) = &self.ip {
+            if !query.ips.iter().any(|ip| x.contains(*ip)) {
+                return false;
+            }
+        }
+        if let Some(x) = &self.port {
+            if !x.iter().any(|(lo, hi)| (*lo..=*hi).contains(&query.port)) {
+                return false;
+            }
+        }
+        if let Some(x) = &self.source {
+            if !query.source.is_some_and(|ip| x.contains(ip)) {
+                return false;
+            }
+        }
+        if let Some(x) = &self.inbound_tag {
+            if !x.contains(&query.inbound_tag) {
+                return false;
+            }
+        }
+        if let Some(x) = &self.network {
+            if !x.contains(&query.network) {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub fn has_ip_condition(&self) -> bool {
+        self.ip.is_some()
+    }
+
+    // one entry of v2ray's `routing.rules`
+    pub fn from_json(value: &Value, path: &str) -> std::result::Result<Self, Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        let Some(object) = value.as_object() else {
+            return Err(vec![ConfigError::new(path, "expected an object")]);
+        };
+
+        let mut rule = Rule {
+            outbound_tag: String::new(),
+            balancer: false,
+            timeout: None,
+            domain: None,
+            ip: None,
+            port: None,
+            source: None,
+            inbound_tag: None,
+            network: None,
+        };
+        let mut outbound_tag = None;
+
+        for (key, value) in object {
+            let path = format!("{path}.{key}");
+            match key.as_str() {
+                "type" => {}
+                "outboundTag" | "balancerTag" => match value.as_str() {
+                    Some(_) if outbound_tag.is_some() => errors.push(ConfigError::new(
+                        &path,
+                        "only one of outboundTag and balancerTag may be set",
+                    )),
+                    Some(x) if !x.is_empty() => {
+                        outbound_tag = Some(x.to_string());
+                        rule.balancer = key == "balancerTag";
+                    }
+                    _ => errors.push(ConfigError::new(&path, "expected a non-empty string")),
+                },
+                "domain" | "domains" => {
+                    let mut matcher = DomainMatcher::default();
+                    for_each_str(value, &path, &mut errors, |x| matcher.add(x));
+                    if let Err(e) = matcher.build() {
+                        errors.push(ConfigError::new(&path, e));
+                    }
+                    rule.domain = Some(matcher);
+                }
+                "ip" | "source" => {
+                    let mut matcher = IpMatcher::default();
+                    for_each_str(value, &path, &mut errors, |x| matcher.add(x));
+                    matcher.build();
+                    match key.as_str() {
+                        "ip" => rule.ip = Some(matcher),
+                        _ => rule.source = Some(matcher),
+                    }
+                }
+                "port" => match parse_ports(value) {
+                    Ok(x) => rule.port = Some(x),
+                    Err(e) => errors.push(ConfigError::new(&path, e)),
+                },
+                "inboundTag" => {
+                    let mut tags = HashSet::new();
+                    for_each_str(value, &path, &mut errors, |x| {
+                        tags.insert(x.to_string());
+                        Ok(())
+                    });
+                    rule.inbound_tag = Some(tags);
+                }
+                "network" => match parse_networks(value) {
+                    Ok(x) => rule.network = Some(x),
+                    Err(e) => errors.push(ConfigError::new(&path, e)),
+                },
+                "timeout" => match time::parse_duration(value) {
+                    Ok(x) if !x.is_zero() => rule.timeout = Some(x),
+                    Ok(_) => errors.push(ConfigError::new(&path, "expected a non-zero duration")),
+                    Err(e) => errors.push(ConfigError::new(&path, e)),
+                },
+                _ => errors.push(ConfigError::new(&path, "unknown field")),
+            }
+        }
+
+        match outbound_tag {
+            Some(x) => rule.outbound_tag = x,
+            None if errors.is_empty() => errors.push(ConfigError::new(
+                &format!("{path}.outboundTag"),
+                "is not set",
+            )),
+            None => {}
+        }
+
+        let conditions = [
+            rule.domain.is_some(),
+            rule.ip.is_some(),
+            rule.port.is_some(),
+            rule.source.is_some(),
+            rule.inbound_tag.is_some(),
+            rule.network.is_some(),
+        ];
+        if errors.is_empty() && !conditions.contains(&true) {
+            errors.push(ConfigError::new(path, "rule has no conditions"));
+        }
+
+        if errors.is_empty() {
+            Ok(rule)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+// accepts a string or an array of them, `f` is called for each one and its
+// error is reported against that entry's path
+fn for_each_str<F>(value: &Value, path: &str, errors: &mut Vec<ConfigError>, mut f: F)
+where
+    F: FnMut(&str) -> std::result::Result<(), String>,
+{
+    let items = match value {
+        Value::String(_) => std::slice::from_ref(value),
+        Value::Array(x) => x.as_slice(),
+        _ => {
+            errors.push(ConfigError::new(
+                path,
+                "expected a string or an array of strings",
+            ));
+            return;
+        }
+    };
+
+    for (i, item) in items.iter().enumerate() {
+        let path = match value {
+            Value::Array(_) => format!("{path}[{i}]"),
+            _ => path.to_string(),
+        };
+        match item.as_str() {
+            Some(x) => {
+                if let Err(e) = f(x) {
+                    errors.push(ConfigError::new(&path, e));
+                }
+            }
+            None => errors.push(ConfigError::new(&path, "expected a string")),
+        }
+    }
+}
+
+// `443`, `"53,443"` or `"1000-2000,8443"`
+fn parse_ports(value: &Value) -> std::result::Result<Vec<(u16, u16)>, String> {
+    if let Some(x) = value.as_u64() {
+        let port = u16::try_from(x).map_err(|_| format!("invalid port {x}"))?;
+        return Ok(vec![(port, port)]);
+    }
+    let Some(s) = value.as_str() else {
+        return Err("expected a number or a string".to_string());
+    };
+
+    let parse = |x: &str| {
+        x.trim()
+            .parse::<u16>()
+            .map_err(|_| format!("invalid port {x:?}"))
+    };
+    s.split(',')
+        .map(|part| match part.split_once('-') {
+            Some((lo, hi)) => {
+                let (lo, hi) = (parse(lo)?, parse(hi)?);
+                if lo > hi {
+                    return Err(format!("invalid port range {part:?}"));
+                }
+                Ok((lo, hi))
+            }
+            None => parse(part).map(|x| (x, x)),
+        })
+        .collect()
+}
+
+// `"tcp"`, `"tcp,udp"` or `["tcp", "udp"]`
+pub(crate) fn parse_networks(value: &Value) -> std::result::Result<Vec<Network>, String> {
+    let names: Vec<&str> = match value {
+        Value::String(x) => x.split(',').collect(),
+        Value::Array(x) => x.iter().filter_map(|x| x.as_str()).collect(),
+        _ => return Err("expected a string or an array of strings".to_string()),
+    };
+
+    names
+        .into_iter()
+        .map(|x| match x.trim() {
+            "tcp" => Ok(Network::Tcp),
+            "udp" => Ok(Network::Udp),
+            x => Err(format!("unknown network {x:?}")),
+        })
+        .collect()
+}
+
+// what routing made of a connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Matched {
+    // the index among `rules()`
+    Rule(usize),
+    // the built in shortcut for private destinations
+    Private,
+    Default,
+}
+
+// the route of one connection, with the settings of the rule that picked it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Route<'a> {
+    pub matched: Matched,
+    // with `balancer` set this names a balancer rather than an outbound
+    pub tag: &'a str,
+    pub balancer: bool,
+    pub timeout: Option<Duration>,
+}
+
+impl<'a> Route<'a> {
+    pub fn new(matched: Matched, tag: &'a str) -> Self {
+        Self {
+            matched,
+            tag,
+            balancer: false,
+            timeout: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Router {
+    rules: Vec<Rule>,
+    domain_strategy: DomainStrategy,
+    balancers: Vec<BalancerConfig>,
+    // consulted after `rules`, empty with `routePrivateDirect` off
+    private_direct: Vec<Rule>,
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new(Vec::new(), DomainStrategy::default())
+    }
+}
+
+impl Router {
+    pub fn new(rules: Vec<Rule>, domain_strategy: DomainStrategy) -> Self {
+        Self {
+            rules,
+            domain_strategy,
+            balancers: Vec::new(),
+            private_direct: private_direct_rules(),
+        }
+    }
+
+    // v2ray's `routing` object:
+    // `{"domainStrategy": .., "rules": [..], "balancers": [..]}`
+    pub fn from_json(value: &Value, path: &str) -> std::result::Result<Self, Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        let Some(object) = value.as_object() else {
+            return Err(vec![ConfigError::new(path, "expected an object")]);
+        };
+
+        let mut router = Router::default();
+        for (key, value) in object {
+            let path = format!("{path}.{key}");
+            match key.as_str() {
+                "domainStrategy" => match value.as_str().and_then(DomainStrategy::parse) {
+                    Some(x) => router.domain_strategy = x,
+                    None => errors.push(ConfigError::new(
+                        &path,
+                        "expected one of AsIs, IPIfNonMatch or IPOnDemand",
+                    )),
+                },
+                "rules" => {
+                    let Some(rules) = value.as_array() else {
+                        errors.push(ConfigError::new(&path, "expected an array"));
+                        continue;
+                    };
+                    for (i, rule) in rules.iter().enumerate() {
+                        match Rule::from_json(rule, &format!("{path}[{i}]")) {
+                            Ok(x) => router.rules.push(x),
+                            Err(e) => errors.extend(e),
+                        }
+                    }
+                }
+                // on by default, off for reaching the lan on the far side
+                "routePrivateDirect" => match value.as_bool() {
+                    Some(true) => router.private_direct = private_direct_rules(),
+                    Some(false) => router.private_direct = Vec::new(),
+                    None => errors.push(ConfigError::new(&path, "expected a boolean")),
+                },
+                "balancers" => {
+                    let Some(balancers) = value.as_array() else {
+                        errors.push(ConfigError::new(&path, "expected an array"));
+                        continue;
+                    };
+                    for (i, balancer) in balancers.iter().enumerate() {
+                        let path = format!("{path}[{i}]");
+                        match BalancerConfig::from_json(balancer, &path) {
+                            Ok(x) if router.balancer(&x.tag).is_some() => errors.push(
+                                ConfigError::new(&format!("{path}.tag"), "duplicate balancer tag"),
+                            ),
+                            Ok(x) => router.balancers.push(x),
+                            Err(e) => errors.extend(e),
+                        }
+                    }
+                }
+                _ => errors.push(ConfigError::new(&path, "unknown field")),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(router)
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    pub fn domain_strategy(&self) -> DomainStrategy {
+        self.domain_strategy
+    }
+
+    pub fn balancers(&self) -> &[BalancerConfig] {
+        &self.balancers
+    }
+
+    pub fn balancer(&self, tag: &str) -> Option<&BalancerConfig> {
+        self.balancers.iter().find(|x| x.tag == tag)
+    }
+
+    // countries named by `geoip:` entries, which have to be loaded before
+    // the rules see any traffic
+    pub fn geoip_countries(&self) -> BTreeSet<String> {
+        self.rules
+            .iter()
+            .flat_map(|x| x.ip.iter().chain(&x.source))
+            .flat_map(|x| x.countries().iter().cloned())
+            .collect()
+    }
+
+    pub fn geosite_lists(&self) -> BTreeSet<String> {
+        self.rules
+            .iter()
+            .flat_map(|x| &x.domain)
+            .flat_map(|x| x.sites().iter().map(|x| x.list.clone()))
+            .collect()
+    }
+
+    pub fn load_geosite(&mut self, geosite: &GeoSite) -> std::result::Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        for (i, rule) in self.rules.iter_mut().enumerate() {
+            if let Some(Err(e)) = rule.domain.as_mut().map(|x| x.load_geosite(geosite)) {
+                errors.push(ConfigError::new(&format!("ROUTING.rules[{i}].domain"), e));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn load_geoip(&mut self, geoip: &GeoIp) -> std::result::Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        for (i, rule) in self.rules.iter_mut().enumerate() {
+            for (key, matcher) in [("ip", &mut rule.ip), ("source", &mut rule.source)] {
+                if let Some(Err(e)) = matcher.as_mut().map(|x| x.load_geoip(geoip)) {
+                    errors.push(ConfigError::new(&format!("ROUTING.rules[{i}].{key}"), e));
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    // first rule that matches wins, no resolution involved
+    pub fn first_match(&self, query: &Query) -> Option<&str> {
+        self.first_rule(query).map(|i| self.rules[i].outbound_tag.as_str())
+    }
+
+    fn first_rule(&self, query: &Query) -> Option<usize> {
+        self.rules.iter().position(|x| x.matches(query))
+    }
+
+    // `None` means no rule matched and the caller should use its default.
+    // without a resolver every strategy behaves like AsIs.
+    pub async fn pick(&self, metadata: &Metadata, resolver: Option<&dyn Resolve>) -> Option<&str> {
+        let i = self.pick_rule(metadata, resolver).await?;
+        Some(&self.rules[i].outbound_tag)
+    }
+
+    // `pick`, as the index of the rule among `rules()`
+    pub async fn pick_rule(
+        &self,
+        metadata: &Metadata,
+        resolver: Option<&dyn Resolve>,
+    ) -> Option<usize> {
+        self.match_rules(metadata, resolver).await.0
+    }
+
+    // the matching rule, or the direct outbound for a private destination.
+    // that one also goes by the addresses a domain strategy resolved.
+    pub async fn route(
+        &self,
+        metadata: &Metadata,
+        resolver: Option<&dyn Resolve>,
+    ) -> Option<Route<'_>> {
+        let (i, query) = self.match_rules(metadata, resolver).await;
+        if let Some(i) = i {
+            let rule = &self.rules[i];
+            return Some(Route {
+                balancer: rule.balancer,
+                timeout: rule.timeout,
+                ..Route::new(Matched::Rule(i), &rule.outbound_tag)
+            });
+        }
+        self.private_direct
+            .iter()
+            .any(|x| x.matches(&query))
+            .then(|| Route::new(Matched::Private, DEFAULT_OUTBOUND_TAG))
+    }
+
+    async fn match_rules(
+        &self,
+        metadata: &Metadata,
+        resolver: Option<&dyn Resolve>,
+    ) -> (Option<usize>, Query) {
+        let mut query = Query::new(metadata);
+        let resolver = match (resolver, &query.domain) {
+            (Some(x), Some(_)) if query.ips.is_empty() => x,
+            _ => return (self.first_rule(&query), query),
+        };
+
+        let i = match self.domain_strategy {
+            DomainStrategy::AsIs => self.first_rule(&query),
+            DomainStrategy::IPIfNonMatch => match self.first_rule(&query) {
+                Some(i) => Some(i),
+                None => {
+                    query.ips = resolve(resolver, &query).await;
+                    self.first_rule(&query)
+                }
+            },
+            DomainStrategy::IPOnDemand => {
+                let mut resolved = false;
+                let mut matched = None;
+                for (i, rule) in self.rules.iter().enumerate() {
+                    if rule.has_ip_condition() && !resolved {
+                        query.ips = resolve(resolver, &query).await;
+                        resolved = true;
+                    }
+                    if rule.matches(&query) {
+                        matched = Some(i);
+                        break;
+                    }
+                }
+                matched
+            }
+        };
+        (i, query)
+    }
+}
+
+// one rule per condition, either one is enough
+fn private_direct_rules() -> Vec<Rule> {
+    let mut ip = IpMatcher::default();
+    for cidr in PRIVATE_DIRECT_CIDRS {
+        ip.add(cidr).expect("valid builtin cidr");
+    }
+    ip.build();
+    let mut domain = DomainMatcher::default();
+    for spec in PRIVATE_DIRECT_DOMAINS {
+        domain.add(spec).expect("valid builtin domain");
+    }
+    domain.build().expect("valid builtin domain");
+
+    let rule = |domain, ip| Rule {
+        outbound_tag: DEFAULT_OUTBOUND_TAG.to_string(),
+        balancer: false,
+        timeout: None,
+        domain,
+        ip,
+        port: None,
+        source: None,
+        inbound_tag: None,
+        network: None,
+    };
+    vec![rule(None, Some(ip)), rule(Some(domain), None)]
+}
+
+// a failed lookup is not fatal, the ip rules just won't match
+async fn resolve(resolver: &dyn Resolve, query: &Query) -> Vec<IpAddr> {
+    let Some(domain) = &query.domain else {
+        return Vec::new();
+    };
+    match resolver.resolve(domain).await {
+        Ok(x) => x,
+        Err(e) => {
+            crate::log_error!("[router]: failed resolving {}: {}", domain, e);
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbound::Target;
+    use serde_json::json;
+    use std::cell::Cell;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn router(value: Value) -> Router {
+        Router::from_json(&value, "ROUTING").unwrap()
+    }
+
+    fn metadata(addr: &str, port: u16) -> Metadata {
+        Metadata {
+            inbound_tag: "vless".to_string(),
+            source: Some("198.51.100.7".to_string()),
+            sniffed_host: None,
+            target: Target::new(addr.to_string(), port, Network::Tcp),
+            handshake: std::time::Duration::ZERO,
+            user: None,
+        }
+    }
+
+    #[test]
+    fn test_domain_matcher() {
+        let mut m = DomainMatcher::default();
+        for spec in [
+            "domain:example.com",
+            "full:a.test",
+            "keyword:ads",
+            "regexp:^cdn[0-9]+\\.",
+        ] {
+            m.add(spec).unwrap();
+        }
+        m.build().unwrap();
+
+        for d in [
+            "example.com",
+            "www.example.com",
+            "a.test",
+            "myads.net",
+            "cdn42.net",
+        ] {
+            assert!(m.matches(d), "{d}");
+        }
+        for d in [
+            "badexample.com",
+            "example.org",
+            "b.a.test",
+            "cdn.net",
+            "com",
+        ] {
+            assert!(!m.matches(d), "{d}");
+        }
+
+        assert!(m.add("ext:geosite.dat:cn").is_err());
+        assert!(m.add("regexp:(").is_err());
+    }
+
+    #[test]
+    fn test_ip_matcher() {
+        let mut m = IpMatcher::default();
+        for cidr in [
+            "10.0.0.0/8",
+            "10.1.0.0/16",
+            "192.168.1.1",
+            "2001:db8::/32",
+            "0.0.0.0/0",
+        ] {
+            m.add(cidr).unwrap();
+        }
+        m.build();
+        // 10.1/16 collapses into 10/8 and both into 0/0
+        assert_eq!(m.v4, [(0, u32::MAX)]);
+
+        let mut m = IpMatcher::default();
+        for cidr in ["10.0.0.0/8", "192.168.1.1", "2001:db8::/32"] {
+            m.add(cidr).unwrap();
+        }
+        m.build();
+        let ip = |x: &str| x.parse::<IpAddr>().unwrap();
+        assert!(m.contains(ip("10.0.0.0")));
+        assert!(m.contains(ip("10.255.255.255")));
+        assert!(!m.contains(ip("11.0.0.0")));
+        assert!(!m.contains(ip("9.255.255.255")));
+        assert!(m.contains(ip("192.168.1.1")));
+        assert!(!m.contains(ip("192.168.1.2")));
+        assert!(m.contains(ip("::ffff:10.2.3.4")));
+        assert!(m.contains(ip("2001:db8:ffff::1")));
+        assert!(!m.contains(ip("2001:db9::")));
+
+        assert!(m.add("10.0.0.0/33").is_err());
+        assert!(m.add("example.com").is_err());
+    }
+
+    #[test]
+    fn test_router_from_json_errors() {
+        let errors = Router::from_json(
+            &json!({
+                "domainStrategy": "Sometimes",
+                "rules": [
+                    {"type": "field", "domain": ["example.com", "regexp:("], "outboundTag": "direct"},
+                    {"type": "field", "ip": ["10.0.0.0/8"], "port": "80-20", "outboundTag": "direct"},
+                    {"type": "field", "outboundTag": "direct"},
+                    {"type": "field", "network": "tcp"},
+                    {"type": "field", "port": 53, "outboundTag": "direct", "timeout": "0s"},
+                ],
+                "routePrivateDirect": "no",
+            }),
+            "ROUTING",
+        )
+        .err()
+        .unwrap();
+
+        let paths: Vec<_> = errors.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "ROUTING.domainStrategy",
+                "ROUTING.routePrivateDirect",
+                "ROUTING.rules[0].domain[1]",
+                "ROUTING.rules[1].port",
+                "ROUTING.rules[2]",
+                "ROUTING.rules[3].outboundTag",
+                "ROUTING.rules[4].timeout",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_router_geoip() {
+        let mut r = router(json!({
+            "rules": [
+                {"ip": ["geoip:private"], "outboundTag": "private"},
+                {"ip": ["geoip:CN", "198.18.0.0/15"], "outboundTag": "cn"},
+                {"source": ["geoip:jp"], "outboundTag": "jp"},
+            ]
+        }));
+        assert_eq!(
+            r.geoip_countries(),
+            BTreeSet::from(["cn".into(), "jp".into()])
+        );
+
+        let query = |addr: &str| Query::new(&metadata(addr, 443));
+        assert_eq!(r.first_match(&query("10.1.2.3")), Some("private"));
+        assert_eq!(r.first_match(&query("198.18.0.1")), Some("cn"));
+
+        let mut cn = IpMatcher::default();
+        cn.add("1.0.1.0/24").unwrap();
+        let mut geoip = GeoIp::default();
+        geoip.insert("cn", cn);
+        let errors = r.load_geoip(&geoip).err().unwrap();
+        assert_eq!(errors[0].path, "ROUTING.rules[2].source");
+
+        assert_eq!(r.first_match(&query("1.0.1.255")), Some("cn"));
+        assert_eq!(r.first_match(&query("1.0.2.0")), None);
+        assert_eq!(r.first_match(&query("198.18.0.1")), Some("cn"));
+    }
+
+    #[tokio::test]
+    async fn test_router_pick() {
+        let r = router(json!({
+            "rules": [
+                {"domain": ["domain:example.com"], "port": "443", "outboundTag": "a"},
+                {"ip": ["203.0.113.0/24"], "outboundTag": "b"},
+                {"source": ["198.51.100.0/24"], "network": "udp", "outboundTag": "c"},
+                {"inboundTag": ["vless"], "port": 80, "outboundTag": "d"},
+            ]
+        }));
+
+        assert_eq!(
+            r.pick(&metadata("www.example.com", 443), None).await,
+            Some("a")
+        );
+        assert_eq!(
+            r.pick(&metadata("www.example.com", 80), None).await,
+            Some("d")
+        );
+        assert_eq!(r.pick(&metadata("203.0.113.9", 443), None).await, Some("b"));
+        assert_eq!(r.pick(&metadata("example.org", 443), None).await, None);
+
+        let mut udp = metadata("example.org", 53);
+        udp.target.network = Network::Udp;
+        assert_eq!(r.pick(&udp, None).await, Some("c"));
+
+        let mut sniffed = metadata("203.0.114.1", 443);
+        sniffed.sniffed_host = Some("Example.COM.".to_string());
+        assert_eq!(r.pick(&sniffed, None).await, Some("a"));
+    }
+
+    struct MockResolver(Cell<usize>);
+
+    #[async_trait(?Send)]
+    impl Resolve for MockResolver {
+        async fn resolve(&self, _domain: &str) -> Result<Vec<IpAddr>> {
+            self.0.set(self.0.get() + 1);
+            Ok(vec![IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1))])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_domain_strategy() {
+        let rules = |strategy: &str| {
+            router(json!({
+                "domainStrategy": strategy,
+                "rules": [
+                    {"domain": ["full:example.com"], "outboundTag": "domain"},
+                    {"ip": ["203.0.113.0/24"], "outboundTag": "ip"},
+                    {"domain": ["keyword:example"], "outboundTag": "late"},
+                ]
+            }))
+        };
+        let mock = MockResolver(Cell::new(0));
+        let resolver = Some(&mock as &dyn Resolve);
+        let count = || mock.0.get();
+
+        let r = rules("AsIs");
+        assert_eq!(r.pick(&metadata("other.org", 443), resolver).await, None);
+        assert_eq!(count(), 0);
+
+        let r = rules("IPIfNonMatch");
+        assert_eq!(
+            r.pick(&metadata("example.com", 443), resolver).await,
+            Some("domain")
+        );
+        assert_eq!(
+            r.pick(&metadata("a.example.org", 443), resolver).await,
+            Some("late")
+        );
+        assert_eq!(count(), 0);
+        assert_eq!(
+            r.pick(&metadata("other.org", 443), resolver).await,
+            Some("ip")
+        );
+        assert_eq!(count(), 1);
+
+        let r = rules("IPOnDemand");
+        assert_eq!(
+            r.pick(&metadata("example.com", 443), resolver).await,
+            Some("domain")
+        );
+        assert_eq!(count(), 1);
+        assert_eq!(
+            r.pick(&metadata("a.example.org", 443), resolver).await,
+            Some("ip")
+        );
+        assert_eq!(count(), 2);
+        // ip targets are never resolved
+        assert_eq!(r.pick(&metadata("192.0.2.1", 443), resolver).await, None);
+        assert_eq!(count(), 2);
+    }
+
+    // a deliberately dumb model of the rules: linear scans over the raw
+    // entries, no tries, no merged ranges
+    #[derive(Debug)]
+    struct NaiveRule {
+        full: Vec<String>,
+        suffix: Vec<String>,
+        keyword: Vec<String>,
+        cidr: Vec<(IpAddr, u8)>,
+        port: Vec<(u16, u16)>,
+        source: Vec<(IpAddr, u8)>,
+        inbound: Vec<String>,
+        network: Vec<Network>,
+        tag: String,
+    }
+
+    fn naive_in_cidr(ip: IpAddr, (net, prefix): (IpAddr, u8)) -> bool {
+        match (ip.to_canonical(), net) {
+            (IpAddr::V4(ip), IpAddr::V4(net)) => {
+                let shift = 32 - u32::from(prefix);
+                u32::from(ip).checked_shr(shift).unwrap_or(0)
+                    == u32::from(net).checked_shr(shift).unwrap_or(0)
+            }
+            (IpAddr::V6(ip), IpAddr::V6(net)) => {
+                let shift = 128 - u32::from(prefix);
+                u128::from(ip).checked_shr(shift).unwrap_or(0)
+                    == u128::from(net).checked_shr(shift).unwrap_or(0)
+            }
+            _ => false,
+        }
+    }
+
+    impl NaiveRule {
+        fn has_domain(&self) -> bool {
+            !(self.full.is_empty() && self.suffix.is_empty() && self.keyword.is_empty())
+        }
+
+        fn matches(&self, q: &Query) -> bool {
+            if self.has_domain() {
+                let Some(d) = &q.domain else { return false };
+                let hit = self.full.iter().any(|x| x == d)
+                    || self
+                        .suffix
+                        .iter()
+                        .any(|x| d == x || d.ends_with(&format!(".{x}")))
+                    || self.keyword.iter().any(|x| d.contains(x.as_str()));
+                if !hit {
+                    return false;
+                }
+            }
+            if !self.cidr.is_empty()
+                && !q
+                    .ips
+                    .iter()
+                    .any(|ip| self.cidr.iter().any(|c| naive_in_cidr(*ip, *c)))
+            {
+                return false;
+            }
+            if !self.port.is_empty()
+                && !self
+                    .port
+                    .iter()
+                    .any(|(lo, hi)| *lo <= q.port && q.port <= *hi)
+            {
+                return false;
+            }
+            if !self.source.is_empty()
+                && !q
+                    .source
+                    .is_some_and(|ip| self.source.iter().any(|c| naive_in_cidr(ip, *c)))
+            {
+                return false;
+            }
+            if !self.inbound.is_empty() && !self.inbound.contains(&q.inbound_tag) {
+                return false;
+            }
+            if !self.network.is_empty() && !self.network.contains(&q.network) {
+                return false;
+            }
+            true
+        }
+
+        fn to_json(&self) -> Value {
+            let mut rule = serde_json::Map::new();
+            let domains: Vec<_> = (self.full.iter().map(|x| format!("full:{x}")))
+                .chain(self.suffix.iter().map(|x| format!("domain:{x}")))
+                .chain(self.keyword.iter().map(|x| format!("keyword:{x}")))
+                .collect();
+            let cidrs = |x: &[(IpAddr, u8)]| -> Vec<String> {
+                x.iter()
+                    .map(|(ip, prefix)| format!("{ip}/{prefix}"))
+                    .collect()
+            };
+            if !domains.is_empty() {
+                rule.insert("domain".into(), json!(domains));
+            }
+            if !self.cidr.is_empty() {
+                rule.insert("ip".into(), json!(cidrs(&self.cidr)));
+            }
+            if !self.port.is_empty() {
+                let ports: Vec<_> = self
+                    .port
+                    .iter()
+                    .map(|(lo, hi)| format!("{lo}-{hi}"))
+                    .collect();
+                rule.insert("port".into(), json!(ports.join(",")));
+            }
+            if !self.source.is_empty() {
+                rule.insert("source".into(), json!(cidrs(&self.source)));
+            }
+            if !self.inbound.is_empty() {
+                rule.insert("inboundTag".into(), json!(self.inbound));
+            }
+            if !self.network.is_empty() {
+                let names: Vec<_> = self
+                    .network
+                    .iter()
+                    .map(|x| if *x == Network::Tcp { "tcp" } else { "udp" })
+                    .collect();
+                rule.insert("network".into(), json!(names));
+            }
+            rule.insert("outboundTag".into(), json!(self.tag));
+            Value::Object(rule)
+        }
+    }
+
+    // xorshift, so failures are reproducible from the seed
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+
+        fn pick<'a>(&mut self, items: &'a [&'a str]) -> &'a str {
+            items[self.below(items.len() as u64) as usize]
+        }
+
+        // small alphabets so rules and queries actually collide
+        fn domain(&mut self) -> String {
+            const LABELS: &[&str] = &["a", "b", "ex", "com", "net", "cdn"];
+            let n = 1 + self.below(4);
+            (0..n)
+                .map(|_| self.pick(LABELS))
+                .collect::<Vec<_>>()
+                .join(".")
+        }
+
+        fn ip(&mut self) -> IpAddr {
+            if self.below(4) == 0 {
+                let x = 0x2001_0db8u128 << 96
+                    | u128::from(self.below(4)) << 64
+                    | u128::from(self.below(4));
+                IpAddr::V6(Ipv6Addr::from(x))
+            } else {
+                IpAddr::V4(Ipv4Addr::new(
+                    10,
+                    self.below(4) as u8,
+                    self.below(4) as u8,
+                    self.below(4) as u8,
+                ))
+            }
+        }
+
+        fn cidr(&mut self) -> (IpAddr, u8) {
+            let ip = self.ip();
+            let prefix = match ip {
+                IpAddr::V4(_) => [0, 8, 16, 24, 30, 32][self.below(6) as usize],
+                IpAddr::V6(_) => [0, 32, 64, 126, 128][self.below(5) as usize],
+            };
+            (ip, prefix)
+        }
+
+        fn list<T>(&mut self, max: u64, mut f: impl FnMut(&mut Self) -> T) -> Vec<T> {
+            if self.below(2) == 0 {
+                return Vec::new();
+            }
+            (0..1 + self.below(max)).map(|_| f(self)).collect()
+        }
+
+        fn rule(&mut self, i: usize) -> NaiveRule {
+            loop {
+                let rule = NaiveRule {
+                    full: self.list(2, |r| r.domain()),
+                    suffix: self.list(3, |r| r.domain()),
+                    keyword: self.list(2, |r| r.pick(&["ex", "cdn", "b.c", "t.n"]).to_string()),
+                    cidr: self.list(3, |r| r.cidr()),
+                    port: self.list(2, |r| {
+                        let lo = r.below(1000) as u16;
+                        (lo, lo + r.below(200) as u16)
+                    }),
+                    source: self.list(2, |r| r.cidr()),
+                    inbound: self.list(2, |r| r.pick(&["vless", "vmess", "trojan"]).to_string()),
+                    network: self.list(1, |r| {
+                        if r.below(2) == 0 {
+                            Network::Tcp
+                        } else {
+                            Network::Udp
+                        }
+                    }),
+                    tag: format!("rule{i}"),
+                };
+                // the parser rejects rules without conditions
+                let empty = !rule.has_domain()
+                    && rule.cidr.is_empty()
+                    && rule.port.is_empty()
+                    && rule.source.is_empty()
+                    && rule.inbound.is_empty()
+                    && rule.network.is_empty();
+                if !empty {
+                    return rule;
+                }
+            }
+        }
+
+        fn query(&mut self) -> Query {
+            let is_ip = self.below(2) == 0;
+            Query {
+                domain: (!is_ip || self.below(4) == 0).then(|| self.domain()),
+                ips: if is_ip {
+                    vec![self.ip()]
+                } else {
+                    self.list(2, |r| r.ip())
+                },
+                port: self.below(1200) as u16,
+                source: (self.below(4) != 0).then(|| self.ip()),
+                inbound_tag: self.pick(&["vless", "vmess", "trojan", "ss"]).to_string(),
+                network: if self.below(2) == 0 {
+                    Network::Tcp
+                } else {
+                    Network::Udp
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn test_router_matches_naive_reference() {
+        for seed in 1..=200u64 {
+            let mut rng = Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+            let rules: Vec<_> = (0..1 + rng.below(12) as usize)
+                .map(|i| rng.rule(i))
+                .collect();
+            let json = json!({ "rules": rules.iter().map(|x| x.to_json()).collect::<Vec<_>>() });
+            let router = router(json);
+
+            for _ in 0..200 {
+                let query = rng.query();
+                let expected = rules
+                    .iter()
+                    .find(|x| x.matches(&query))
+                    .map(|x| x.tag.as_str());
+                assert_eq!(
+                    router.first_match(&query),
+                    expected,
+                    "seed {seed}: {query:?} against {rules:#?}"
+                );
+            }
+        }
+    }
+}
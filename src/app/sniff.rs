@@ -0,0 +1,537 @@
+use super::Metadata;
+use crate::common::{read_u16_be, time};
+use crate::config::ConfigError;
+use crate::outbound::Network;
+
+use futures_util::future::{self, Either};
+use serde_json::Value;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+// how long a client gets to send its first bytes. protocols where the
+// server speaks first pay this once per connection.
+pub const SNIFF_TIMEOUT: Duration = Duration::from_millis(300);
+// larger than any sane ClientHello, post-quantum key shares included
+const MAX_SNIFF_SIZE: usize = 16 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Http,
+    Tls,
+}
+
+impl Protocol {
+    fn sniff(self, buf: &[u8]) -> Sniffed {
+        match self {
+            Self::Http => sniff_http(buf),
+            Self::Tls => sniff_tls(buf),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Sniffed {
+    Host(String),
+    NeedMore,
+    Mismatch,
+}
+
+// v2ray's inbound `sniffing` object, applied to every inbound unless
+// `inboundTags` narrows it down
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sniffing {
+    pub protocols: Vec<Protocol>,
+    // only route by the sniffed domain instead of also connecting to it
+    pub route_only: bool,
+    pub inbound_tags: Option<Vec<String>>,
+}
+
+fn strings<'a>(value: &'a Value, path: &str, errors: &mut Vec<ConfigError>) -> Vec<&'a str> {
+    match value
+        .as_array()
+        .map(|x| x.iter().map(|x| x.as_str()).collect())
+    {
+        Some(Some(x)) => x,
+        _ => {
+            errors.push(ConfigError::new(path, "expected an array of strings"));
+            Vec::new()
+        }
+    }
+}
+
+impl Sniffing {
+    // `None` when sniffing is switched off
+    pub fn from_json(value: &Value, path: &str) -> Result<Option<Self>, Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        let Some(object) = value.as_object() else {
+            return Err(vec![ConfigError::new(path, "expected an object")]);
+        };
+
+        let mut enabled = false;
+        let mut sniffing = Sniffing {
+            protocols: vec![Protocol::Http, Protocol::Tls],
+            route_only: false,
+            inbound_tags: None,
+        };
+
+        for (key, value) in object {
+            let path = format!("{path}.{key}");
+            match key.as_str() {
+                "enabled" | "routeOnly" => match value.as_bool() {
+                    Some(x) if key == "enabled" => enabled = x,
+                    Some(x) => sniffing.route_only = x,
+                    None => errors.push(ConfigError::new(&path, "expected a boolean")),
+                },
+                "destOverride" => {
+                    sniffing.protocols.clear();
+                    for (i, name) in strings(value, &path, &mut errors).into_iter().enumerate() {
+                        match name {
+                            "http" => sniffing.protocols.push(Protocol::Http),
+                            "tls" => sniffing.protocols.push(Protocol::Tls),
+                            // udp only ever reaches the dns relay
+                            "quic" => errors.push(ConfigError::new(
+                                &format!("{path}[{i}]"),
+                                "quic is not supported, udp is only relayed as dns",
+                            )),
+                            x => errors.push(ConfigError::new(
+                                &format!("{path}[{i}]"),
+                                format!("unknown protocol {x:?}"),
+                            )),
+                        }
+                    }
+                }
+                "inboundTags" => {
+                    let tags = strings(value, &path, &mut errors);
+                    sniffing.inbound_tags = Some(tags.into_iter().map(String::from).collect());
+                }
+                _ => errors.push(ConfigError::new(&path, "unknown field")),
+            }
+        }
+
+        if !errors.is_empty() {
+            Err(errors)
+        } else if enabled {
+            Ok(Some(sniffing))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn applies(&self, metadata: &Metadata) -> bool {
+        metadata.target.network == Network::Tcp
+            && self
+                .inbound_tags
+                .as_ref()
+                .is_none_or(|x| x.contains(&metadata.inbound_tag))
+    }
+}
+
+// replays whatever was read while sniffing before reading any further, so
+// the outbound sees the stream untouched
+pub struct PeekStream<S> {
+    inner: S,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<S: AsyncRead + Unpin> PeekStream<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    pub fn peeked(&self) -> &[u8] {
+        &self.buf[self.pos..]
+    }
+
+    // cancel safe, a read that never completes leaves the buffer as it was
+    pub(super) async fn fill(&mut self) -> io::Result<usize> {
+        self.buf.reserve(4096);
+        self.inner.read_buf(&mut self.buf).await
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PeekStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.pos < this.buf.len() {
+            let n = buf.remaining().min(this.buf.len() - this.pos);
+            buf.put_slice(&this.buf[this.pos..this.pos + n]);
+            this.pos += n;
+            if this.pos == this.buf.len() {
+                this.buf = Vec::new();
+                this.pos = 0;
+            }
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PeekStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// reads until one of `protocols` yields a host, all of them mismatch, the
+// client stops sending or `timeout` passes. nothing is consumed.
+pub async fn sniff<S>(
+    stream: &mut PeekStream<S>,
+    protocols: &[Protocol],
+    timeout: Duration,
+) -> Option<(Protocol, String)>
+where
+    S: AsyncRead + Unpin,
+{
+    let work = async {
+        loop {
+            let buf = stream.peeked();
+            let mut pending = false;
+            for protocol in protocols {
+                match protocol.sniff(buf) {
+                    Sniffed::Host(x) => return Some((*protocol, x)),
+                    Sniffed::NeedMore => pending = true,
+                    Sniffed::Mismatch => {}
+                }
+            }
+            if !pending || buf.len() >= MAX_SNIFF_SIZE {
+                return None;
+            }
+            match stream.fill().await {
+                Ok(0) | Err(_) => return None,
+                Ok(_) => {}
+            }
+        }
+    };
+
+    match future::select(Box::pin(work), Box::pin(time::sleep(timeout))).await {
+        Either::Left((x, _)) => x,
+        Either::Right(_) => None,
+    }
+}
+
+// a domain as it would appear in a rule, ip literals don't count
+fn host_name(host: &[u8]) -> Option<String> {
+    let host = std::str::from_utf8(host).ok()?.trim().trim_end_matches('.');
+    let valid = |b: u8| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_');
+    if host.is_empty() || !host.bytes().all(valid) || host.parse::<IpAddr>().is_ok() {
+        return None;
+    }
+    Some(host.to_ascii_lowercase())
+}
+
+const HTTP_METHODS: &[&[u8]] = &[
+    b"GET", b"POST", b"HEAD", b"PUT", b"DELETE", b"OPTIONS", b"CONNECT", b"PATCH", b"TRACE",
+];
+
+// the Host header of an HTTP/1.x request
+pub fn sniff_http(buf: &[u8]) -> Sniffed {
+    match buf.iter().position(|b| *b == b' ') {
+        Some(i) if HTTP_METHODS.contains(&&buf[..i]) => {}
+        None if HTTP_METHODS.iter().any(|x| x.starts_with(buf)) => return Sniffed::NeedMore,
+        _ => return Sniffed::Mismatch,
+    }
+
+    // only complete lines, the request line first
+    let complete = match buf.windows(2).rposition(|x| x == b"\r\n") {
+        Some(i) => &buf[..i],
+        None => return Sniffed::NeedMore,
+    };
+    for line in complete.split(|b| *b == b'\n').skip(1) {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            // end of the headers without a Host
+            return Sniffed::Mismatch;
+        }
+        let Some(colon) = line.iter().position(|b| *b == b':') else {
+            return Sniffed::Mismatch;
+        };
+        if !line[..colon].eq_ignore_ascii_case(b"host") {
+            continue;
+        }
+
+        let value = &line[colon + 1..];
+        // `[::1]:8080` is an ip literal, `example.com:8080` loses the port
+        if value.trim_ascii_start().starts_with(b"[") {
+            return Sniffed::Mismatch;
+        }
+        let host = match value.iter().rposition(|b| *b == b':') {
+            Some(i) => &value[..i],
+            None => value,
+        };
+        return host_name(host).map_or(Sniffed::Mismatch, Sniffed::Host);
+    }
+
+    Sniffed::NeedMore
+}
+
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (x, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(x)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|x| x[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(read_u16_be)
+    }
+
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let n = self.u8()?;
+        self.take(n.into())
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let n = self.u16()?;
+        self.take(n.into())
+    }
+}
+
+const TLS_HANDSHAKE: u8 = 0x16;
+const TLS_CLIENT_HELLO: u8 = 0x01;
+const TLS_EXT_SERVER_NAME: u16 = 0x0000;
+const TLS_MAX_RECORD: usize = 16 * 1024 + 2048;
+
+// the server_name of a ClientHello, which may be spread over several
+// handshake records
+pub fn sniff_tls(buf: &[u8]) -> Sniffed {
+    let mut handshake = Vec::new();
+    let mut rest = buf;
+
+    loop {
+        match rest {
+            [] => return Sniffed::NeedMore,
+            [x, ..] if *x != TLS_HANDSHAKE => return Sniffed::Mismatch,
+            [_, major, ..] if *major != 3 => return Sniffed::Mismatch,
+            _ => {}
+        }
+        if rest.len() < 5 {
+            return Sniffed::NeedMore;
+        }
+        let len = usize::from(read_u16_be(&rest[3..]));
+        if len == 0 || len > TLS_MAX_RECORD {
+            return Sniffed::Mismatch;
+        }
+        if rest.len() < 5 + len {
+            return Sniffed::NeedMore;
+        }
+        handshake.extend_from_slice(&rest[5..5 + len]);
+        rest = &rest[5 + len..];
+
+        if handshake[0] != TLS_CLIENT_HELLO {
+            return Sniffed::Mismatch;
+        }
+        if handshake.len() < 4 {
+            continue;
+        }
+        let body_len = usize::from(handshake[1]) << 16
+            | usize::from(handshake[2]) << 8
+            | usize::from(handshake[3]);
+        if handshake.len() >= 4 + body_len {
+            let body = &handshake[4..4 + body_len];
+            return client_hello_server_name(body).map_or(Sniffed::Mismatch, Sniffed::Host);
+        }
+        if handshake.len() >= MAX_SNIFF_SIZE {
+            return Sniffed::Mismatch;
+        }
+    }
+}
+
+fn client_hello_server_name(body: &[u8]) -> Option<String> {
+    let mut c = Cursor(body);
+    c.take(2)?; // legacy_version
+    c.take(32)?; // random
+    c.vec8()?; // session id
+    c.vec16()?; // cipher suites
+    c.vec8()?; // compression methods
+
+    let mut extensions = Cursor(c.vec16()?);
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let data = extensions.vec16()?;
+        if kind != TLS_EXT_SERVER_NAME {
+            continue;
+        }
+
+        let mut names = Cursor(Cursor(data).vec16()?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let name = names.vec16()?;
+            // host_name is the only type ever defined
+            if name_type == 0 {
+                return host_name(name);
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    // captured from `openssl s_client -servername www.example.com -alpn
+    // h2,http/1.1` (OpenSSL 3.0), a single 334 byte handshake record
+    const CLIENT_HELLO: &[u8] = include_bytes!("../../testdata/tls_client_hello.bin");
+
+    // captured from curl 7.88 with `-H "Host: Example.org:8080"`
+    const HTTP_REQUEST: &[u8] = b"GET /index.html HTTP/1.1\r\nHost: Example.org:8080\r\nUser-Agent: curl/7.88.1\r\nAccept: */*\r\n\r\n";
+
+    // re-frames the captured handshake into records of at most `size` bytes
+    fn fragment(hello: &[u8], size: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        for chunk in hello[5..].chunks(size) {
+            out.extend_from_slice(&[TLS_HANDSHAKE, 3, 1]);
+            out.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+            out.extend_from_slice(chunk);
+        }
+        out
+    }
+
+    #[test]
+    fn test_sniff_tls() {
+        let host = Sniffed::Host("www.example.com".to_string());
+        assert_eq!(sniff_tls(CLIENT_HELLO), host);
+        // every prefix asks for more, never a wrong answer
+        for n in 0..CLIENT_HELLO.len() {
+            assert_eq!(sniff_tls(&CLIENT_HELLO[..n]), Sniffed::NeedMore, "{n}");
+        }
+
+        // multi-record hellos, down to records shorter than the handshake
+        // header
+        for size in [2, 3, 100, 200] {
+            assert_eq!(sniff_tls(&fragment(CLIENT_HELLO, size)), host, "{size}");
+        }
+
+        assert_eq!(sniff_tls(HTTP_REQUEST), Sniffed::Mismatch);
+        assert_eq!(
+            sniff_tls(b"\x16\x03\x01\x00\x05\x02\x00\x00\x01\x00"),
+            Sniffed::Mismatch
+        );
+    }
+
+    #[test]
+    fn test_sniff_http() {
+        let host = Sniffed::Host("example.org".to_string());
+        assert_eq!(sniff_http(HTTP_REQUEST), host);
+        assert_eq!(sniff_http(&HTTP_REQUEST[..2]), Sniffed::NeedMore);
+        assert_eq!(sniff_http(&HTTP_REQUEST[..30]), Sniffed::NeedMore);
+        assert_eq!(sniff_http(CLIENT_HELLO), Sniffed::Mismatch);
+
+        for (request, expected) in [
+            (
+                &b"POST / HTTP/1.1\r\nhost: a.test\r\n\r\n"[..],
+                Sniffed::Host("a.test".into()),
+            ),
+            (b"GET / HTTP/1.1\r\nAccept: */*\r\n\r\n", Sniffed::Mismatch),
+            (
+                b"GET / HTTP/1.1\r\nHost: 192.0.2.1:80\r\n\r\n",
+                Sniffed::Mismatch,
+            ),
+            (
+                b"GET / HTTP/1.1\r\nHost: [2001:db8::1]:80\r\n\r\n",
+                Sniffed::Mismatch,
+            ),
+            (b"SSH-2.0-OpenSSH_9.6\r\n", Sniffed::Mismatch),
+        ] {
+            assert_eq!(sniff_http(request), expected);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sniff_split_reads() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let mut stream = PeekStream::new(server);
+        let protocols = [Protocol::Http, Protocol::Tls];
+
+        let writer = async {
+            client.write_all(&CLIENT_HELLO[..100]).await.unwrap();
+            time::sleep(Duration::from_millis(50)).await;
+            client.write_all(&CLIENT_HELLO[100..]).await.unwrap();
+            client.write_all(b"rest of the stream").await.unwrap();
+            client
+        };
+        let (sniffed, client) = tokio::join!(sniff(&mut stream, &protocols, SNIFF_TIMEOUT), writer);
+        assert_eq!(
+            sniffed,
+            Some((Protocol::Tls, "www.example.com".to_string()))
+        );
+        drop(client);
+
+        // the outbound still gets every byte
+        let mut replayed = Vec::new();
+        stream.read_to_end(&mut replayed).await.unwrap();
+        assert_eq!(replayed, [CLIENT_HELLO, b"rest of the stream"].concat());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sniff_timeout() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let mut stream = PeekStream::new(server);
+
+        // half a hello and then silence
+        client.write_all(&CLIENT_HELLO[..10]).await.unwrap();
+        let sniffed = sniff(&mut stream, &[Protocol::Tls], SNIFF_TIMEOUT).await;
+        assert_eq!(sniffed, None);
+        assert_eq!(stream.peeked(), &CLIENT_HELLO[..10]);
+    }
+
+    #[test]
+    fn test_sniffing_from_json() {
+        let parse = |x: Value| Sniffing::from_json(&x, "SNIFFING");
+
+        let sniffing = parse(serde_json::json!({
+            "enabled": true,
+            "destOverride": ["tls"],
+            "routeOnly": true,
+        }));
+        assert_eq!(
+            sniffing,
+            Ok(Some(Sniffing {
+                protocols: vec![Protocol::Tls],
+                route_only: true,
+                inbound_tags: None,
+            }))
+        );
+        assert_eq!(parse(serde_json::json!({"enabled": false})), Ok(None));
+
+        let errors = parse(serde_json::json!({"enabled": true, "destOverride": ["tls", "quic"]}))
+            .err()
+            .unwrap();
+        assert_eq!(errors[0].path, "SNIFFING.destOverride[1]");
+    }
+}
@@ -0,0 +1,292 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    // the value up to now, counting starts over from zero
+    pub fn reset(&self) -> u64 {
+        self.0.swap(0, Ordering::Relaxed)
+    }
+}
+
+// v2ray's counter names, so its stats tooling reads these as they are
+pub fn user_traffic(email: &str, direction: Direction) -> String {
+    format!("user>>>{email}>>>traffic>>>{direction}")
+}
+
+pub fn inbound_traffic(tag: &str, direction: Direction) -> String {
+    format!("inbound>>>{tag}>>>traffic>>>{direction}")
+}
+
+pub fn outbound_traffic(tag: &str, direction: Direction) -> String {
+    format!("outbound>>>{tag}>>>traffic>>>{direction}")
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    // client to target
+    Uplink,
+    Downlink,
+}
+
+impl std::fmt::Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Uplink => write!(f, "uplink"),
+            Self::Downlink => write!(f, "downlink"),
+        }
+    }
+}
+
+// counters by name, created on first use
+#[derive(Debug, Default)]
+pub struct Stats {
+    counters: RefCell<BTreeMap<String, Rc<Counter>>>,
+}
+
+impl Stats {
+    pub fn counter(&self, name: &str) -> Rc<Counter> {
+        let mut counters = self.counters.borrow_mut();
+        match counters.get(name) {
+            Some(x) => x.clone(),
+            None => counters.entry(name.to_string()).or_default().clone(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<u64> {
+        self.counters.borrow().get(name).map(|x| x.get())
+    }
+
+    // every counter whose name contains `pattern`, like v2ray's QueryStats.
+    // with `reset` the values are taken, each byte is reported once.
+    pub fn query(&self, pattern: &str, reset: bool) -> Vec<(String, u64)> {
+        self.counters
+            .borrow()
+            .iter()
+            .filter(|(name, _)| name.contains(pattern))
+            .map(|(name, x)| (name.clone(), if reset { x.reset() } else { x.get() }))
+            .collect()
+    }
+}
+
+thread_local! {
+    static SHARED: Rc<Stats> = Rc::default();
+}
+
+// counters live as long as the isolate, whichever request they came in on
+pub fn shared() -> Rc<Stats> {
+    SHARED.with(Rc::clone)
+}
+
+// a byte cap on counters that other streams add to as well, `remaining`
+// bytes from when it was made. checking is a load per counter. a counter
+// reset by billing counts as nothing used since.
+#[derive(Clone, Debug)]
+pub struct QuotaGuard {
+    counters: Vec<Rc<Counter>>,
+    start: u64,
+    remaining: u64,
+}
+
+impl QuotaGuard {
+    pub fn new(counters: Vec<Rc<Counter>>, remaining: u64) -> Self {
+        let start = counters.iter().map(|x| x.get()).sum();
+        Self {
+            counters,
+            start,
+            remaining,
+        }
+    }
+
+    pub fn exceeded(&self) -> bool {
+        let counted: u64 = self.counters.iter().map(|x| x.get()).sum();
+        counted.saturating_sub(self.start) >= self.remaining
+    }
+}
+
+// what a stream over its quota fails with, inside the io error
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QuotaExceeded;
+
+impl std::fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "quota exceeded")
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+impl From<QuotaExceeded> for io::Error {
+    fn from(e: QuotaExceeded) -> Self {
+        io::Error::new(io::ErrorKind::PermissionDenied, e)
+    }
+}
+
+// counts what goes through it, once per completed read or write rather than
+// per byte
+pub struct CountingStream<S> {
+    inner: S,
+    uplink: Vec<Rc<Counter>>,
+    downlink: Vec<Rc<Counter>>,
+    quota: Option<QuotaGuard>,
+}
+
+impl<S> CountingStream<S> {
+    // `inner` is the client side: reads are uplink, writes downlink
+    pub fn new(inner: S, uplink: Vec<Rc<Counter>>, downlink: Vec<Rc<Counter>>) -> Self {
+        Self {
+            inner,
+            uplink,
+            downlink,
+            quota: None,
+        }
+    }
+
+    // reads and writes fail once the quota is used up. one that starts
+    // under it completes, so the cap is passed by at most that one.
+    pub fn with_quota(mut self, quota: QuotaGuard) -> Self {
+        self.quota = Some(quota);
+        self
+    }
+
+    fn check_quota(&self) -> io::Result<()> {
+        match &self.quota {
+            Some(x) if x.exceeded() => Err(QuotaExceeded.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountingStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.check_quota()?;
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let n = (buf.filled().len() - before) as u64;
+        if n > 0 {
+            self.uplink.iter().for_each(|x| x.add(n));
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountingStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.check_quota()?;
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.downlink.iter().for_each(|x| x.add(n as u64));
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query() {
+        let stats = Stats::default();
+        stats
+            .counter(&user_traffic("a@example.com", Direction::Uplink))
+            .add(10);
+        stats
+            .counter(&user_traffic("a@example.com", Direction::Downlink))
+            .add(20);
+        stats
+            .counter(&inbound_traffic("vless", Direction::Uplink))
+            .add(30);
+
+        assert_eq!(
+            stats.get("user>>>a@example.com>>>traffic>>>downlink"),
+            Some(20)
+        );
+        assert_eq!(stats.get("user>>>b@example.com>>>traffic>>>downlink"), None);
+        assert_eq!(
+            stats.query("user>>>", false),
+            [
+                ("user>>>a@example.com>>>traffic>>>downlink".to_string(), 20),
+                ("user>>>a@example.com>>>traffic>>>uplink".to_string(), 10),
+            ]
+        );
+
+        // a reset hands the value out exactly once
+        assert_eq!(
+            stats.query("uplink", true).iter().map(|x| x.1).sum::<u64>(),
+            40
+        );
+        assert!(stats.query("uplink", true).iter().all(|x| x.1 == 0));
+        assert_eq!(
+            stats.get("user>>>a@example.com>>>traffic>>>downlink"),
+            Some(20)
+        );
+    }
+
+    // the other end keeps sending, the guarded side stops partway
+    #[tokio::test]
+    async fn test_quota_cut_off_mid_transfer() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let stats = Stats::default();
+        let up = stats.counter(&user_traffic("a@example.com", Direction::Uplink));
+        let down = stats.counter(&user_traffic("a@example.com", Direction::Downlink));
+        up.add(5_000); // before the guard, not counted against it
+        // room for everything the other end sends, which it has by the time
+        // the guarded side reads
+        let (mut client, server) = tokio::io::duplex(8192);
+        let guard = QuotaGuard::new(vec![up.clone(), down.clone()], 1_000);
+        let mut stream = CountingStream::new(server, vec![up.clone()], vec![down.clone()])
+            .with_quota(guard.clone());
+        for _ in 0..40 {
+            client.write_all(&[7u8; 100]).await.unwrap();
+        }
+
+        let mut buf = [0u8; 100];
+        let mut received = 0;
+        let e = loop {
+            match stream.read(&mut buf).await {
+                Ok(n) => received += n,
+                Err(e) => break e,
+            }
+            assert!(received <= 1_100, "{received}");
+        };
+        assert!((1_000..=1_100).contains(&received), "{received}");
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(e.get_ref().unwrap().downcast_ref(), Some(&QuotaExceeded));
+        assert!(guard.exceeded());
+        assert!(stream.write_all(b"late").await.is_err());
+    }
+}
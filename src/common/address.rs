@@ -0,0 +1,151 @@
+use super::error;
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use worker::*;
+
+// ports and lengths on the wire are big-endian everywhere, vmess, vless,
+// trojan and shadowsocks alike. they all go through these so none of them
+// drifts to the host's order.
+pub fn u16_be(x: u16) -> [u8; 2] {
+    let bytes = x.to_be_bytes();
+    debug_assert_eq!(
+        [(x >> 8) as u8, x as u8],
+        bytes,
+        "{x} not written big-endian"
+    );
+    bytes
+}
+
+pub fn write_u16_be(out: &mut Vec<u8>, x: u16) {
+    out.extend_from_slice(&u16_be(x));
+}
+
+// the first two bytes of `buf`
+pub fn read_u16_be(buf: &[u8]) -> u16 {
+    u16::from_be_bytes([buf[0], buf[1]])
+}
+
+pub async fn parse_addr<R: AsyncRead + std::marker::Unpin>(buf: &mut R) -> Result<String> {
+    // combined addr type between Vmess, VLESS, and Trojan.
+    // VLESS wouldn't connect to ipv6 address due to mismatch addr type
+    let addr = match buf.read_u8().await? {
+        1 => {
+            let mut addr = [0u8; 4];
+            buf.read_exact(&mut addr).await?;
+            Ipv4Addr::from(addr).to_string()
+        }
+        2 | 3 => {
+            let len = buf.read_u8().await?;
+            let mut domain = vec![0u8; len as _];
+            buf.read_exact(&mut domain).await?;
+            String::from_utf8_lossy(&domain).to_string()
+        }
+        4 => {
+            let mut addr = [0u8; 16];
+            buf.read_exact(&mut addr).await?;
+            Ipv6Addr::from(addr).to_string()
+        }
+        _ => {
+            return Err(error::ProtocolError::BadMagic("invalid address".to_string()).into());
+        }
+    };
+
+    Ok(addr)
+}
+
+pub async fn parse_port<R: AsyncRead + std::marker::Unpin>(buf: &mut R) -> Result<u16> {
+    let mut port = [0u8; 2];
+    buf.read_exact(&mut port).await?;
+
+    Ok(read_u16_be(&port))
+}
+
+// `parse_addr` and `parse_port` on bytes that are all there already
+pub fn read_addr<R: std::io::Read>(buf: &mut R) -> Result<String> {
+    let mut kind = [0u8];
+    buf.read_exact(&mut kind)?;
+    let addr = match kind[0] {
+        1 => {
+            let mut addr = [0u8; 4];
+            buf.read_exact(&mut addr)?;
+            Ipv4Addr::from(addr).to_string()
+        }
+        2 | 3 => {
+            let mut len = [0u8];
+            buf.read_exact(&mut len)?;
+            let mut domain = vec![0u8; len[0] as _];
+            buf.read_exact(&mut domain)?;
+            String::from_utf8_lossy(&domain).to_string()
+        }
+        4 => {
+            let mut addr = [0u8; 16];
+            buf.read_exact(&mut addr)?;
+            Ipv6Addr::from(addr).to_string()
+        }
+        _ => {
+            return Err(error::ProtocolError::BadMagic("invalid address".to_string()).into());
+        }
+    };
+
+    Ok(addr)
+}
+
+pub fn read_port<R: std::io::Read>(buf: &mut R) -> Result<u16> {
+    let mut port = [0u8; 2];
+    buf.read_exact(&mut port)?;
+
+    Ok(read_u16_be(&port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_u16_be() {
+        assert_eq!(u16_be(443), [0x01, 0xbb]);
+        assert_eq!(u16_be(0), [0x00, 0x00]);
+        assert_eq!(u16_be(65535), [0xff, 0xff]);
+        // not the same read backwards
+        assert_eq!(u16_be(0x0100), [0x01, 0x00]);
+
+        let mut out = vec![0x01];
+        for x in [0, 1, 443, 0x1234, 65535] {
+            write_u16_be(&mut out, x);
+        }
+        assert_eq!(out.len(), 11);
+        let read: Vec<_> = out[1..].chunks(2).map(read_u16_be).collect();
+        assert_eq!(read, [0, 1, 443, 0x1234, 65535]);
+        assert_eq!(read_u16_be(&[0x01, 0xbb, 0xff]), 443);
+    }
+
+    #[tokio::test]
+    async fn test_parse_port() {
+        for (bytes, port) in [([0x01, 0xbb], 443), ([0, 0], 0), ([0xff, 0xff], 65535)] {
+            assert_eq!(parse_port(&mut &bytes[..]).await.unwrap(), port);
+        }
+        assert!(parse_port(&mut &[0x01][..]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_parse_addr() {
+        let mut v4 = &[1, 127, 0, 0, 1][..];
+        assert_eq!(parse_addr(&mut v4).await.unwrap(), "127.0.0.1");
+
+        // the length byte is followed by that many bytes, then the port
+        let mut domain = vec![2, 11];
+        domain.extend_from_slice(b"example.com");
+        write_u16_be(&mut domain, 443);
+        let mut reader = &domain[..];
+        assert_eq!(parse_addr(&mut reader).await.unwrap(), "example.com");
+        assert_eq!(parse_port(&mut reader).await.unwrap(), 443);
+
+        let mut v6 = vec![4, 0x20, 0x01, 0x0d, 0xb8];
+        v6.extend_from_slice(&[0; 11]);
+        v6.push(1);
+        assert_eq!(parse_addr(&mut &v6[..]).await.unwrap(), "2001:db8::1");
+
+        assert!(parse_addr(&mut &[5, 0][..]).await.is_err());
+    }
+}
@@ -0,0 +1,32 @@
+// aes-gcm for vmess and shadowsocks 2022. the `aes` crate picks aes-ni or
+// armv8 at runtime and its constant time fixsliced software backend where
+// there is neither, wasm included. builds with the `soft-aes` feature use
+// that backend everywhere, see build.rs. either way the type is aes_gcm's,
+// so sealing and opening look the same.
+
+pub use aes_gcm::{Aes128Gcm, Aes256Gcm};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes_gcm::aead::{Aead, KeyInit};
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // the gcm spec's test cases 2 and 14, whichever backend is built
+    #[test]
+    fn test_gcm() {
+        let sealed = Aes128Gcm::new(&[0u8; 16].into()).encrypt(&[0u8; 12].into(), &[0u8; 16][..]);
+        let expected = hex("0388dace60b6a392f328c2b971b2fe78ab6e47d42cec13bdf53a67b21257bddf");
+        assert_eq!(sealed.unwrap(), expected);
+
+        let sealed = Aes256Gcm::new(&[0u8; 32].into()).encrypt(&[0u8; 12].into(), &[0u8; 16][..]);
+        let expected = hex("cea7403d4d606b6e074ec5d3baf39d18d0d1c8a799996bf0265b98b5d48ab919");
+        assert_eq!(sealed.unwrap(), expected);
+    }
+}
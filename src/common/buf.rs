@@ -0,0 +1,158 @@
+use super::time::Instant;
+
+use bytes::BytesMut;
+use std::cell::{Cell, RefCell};
+use std::ops::{Deref, DerefMut};
+use std::time::Duration;
+
+// vmess' largest chunk, what a reader needs to hold at once
+pub const SEGMENT_SIZE: usize = 16 * 1024;
+
+// idle segments kept per isolate by default, 1 MiB
+pub const MAX_RETAINED: usize = 64;
+
+// half of what is kept goes once nothing was taken for this long
+const IDLE_SHRINK: Duration = Duration::from_secs(30);
+
+struct Pool {
+    free: RefCell<Vec<BytesMut>>,
+    // when a segment was last taken
+    used: Cell<Option<Instant>>,
+    max_retained: Cell<usize>,
+}
+
+thread_local! {
+    static POOL: Pool = Pool {
+        free: RefCell::default(),
+        used: Cell::default(),
+        max_retained: Cell::new(MAX_RETAINED),
+    };
+}
+
+// how many idle segments this isolate keeps from now on, those beyond it
+// are let go right away
+pub fn set_max_retained(n: usize) {
+    POOL.with(|pool| {
+        pool.max_retained.set(n);
+        pool.free.borrow_mut().truncate(n);
+    });
+}
+
+// a buffer of SEGMENT_SIZE that goes back to the pool when dropped. it can
+// grow like any BytesMut, but only comes back if all of it is free again.
+pub struct PooledBuf(BytesMut);
+
+impl PooledBuf {
+    pub fn take() -> Self {
+        let buf = POOL.with(|pool| {
+            let now = Instant::now();
+            let mut free = pool.free.borrow_mut();
+            if let Some(used) = pool.used.get() {
+                if now.saturating_duration_since(used) >= IDLE_SHRINK {
+                    let keep = free.len() / 2;
+                    free.truncate(keep);
+                }
+            }
+            pool.used.set(Some(now));
+            free.pop()
+        });
+        Self(buf.unwrap_or_else(|| BytesMut::with_capacity(SEGMENT_SIZE)))
+    }
+}
+
+impl Deref for PooledBuf {
+    type Target = BytesMut;
+
+    fn deref(&self) -> &BytesMut {
+        &self.0
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut BytesMut {
+        &mut self.0
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        let mut buf = std::mem::take(&mut self.0);
+        buf.clear();
+        // one that grew holds more than a segment, even when it was advanced
+        // down to one, and one still shared with a split off part would be
+        // reallocated by its next user
+        if buf.try_reclaim(SEGMENT_SIZE + 1) || !buf.try_reclaim(SEGMENT_SIZE) {
+            return;
+        }
+        POOL.with(|pool| {
+            let mut free = pool.free.borrow_mut();
+            if free.len() < pool.max_retained.get() {
+                free.push(buf);
+            }
+        });
+    }
+}
+
+// idle segments held by this isolate
+pub fn retained() -> usize {
+    POOL.with(|pool| pool.free.borrow().len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::{Buf, BufMut};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pool() {
+        let bufs: Vec<_> = (0..MAX_RETAINED + 2).map(|_| PooledBuf::take()).collect();
+        drop(bufs);
+        assert_eq!(retained(), MAX_RETAINED);
+
+        // handed out again as it came back, empty
+        let mut buf = PooledBuf::take();
+        assert!(buf.is_empty() && buf.capacity() == SEGMENT_SIZE);
+        assert_eq!(retained(), MAX_RETAINED - 1);
+        buf.put_slice(b"kept while shared");
+        let split = buf.split_to(4);
+        drop(buf);
+        assert_eq!(retained(), MAX_RETAINED - 1);
+        drop(split);
+
+        let mut grown = PooledBuf::take();
+        grown.reserve(4 * SEGMENT_SIZE);
+        drop(grown);
+        assert_eq!(retained(), MAX_RETAINED - 2);
+
+        // left with a segment's worth of room, but not of memory
+        let mut grown = PooledBuf::take();
+        grown.reserve(4 * SEGMENT_SIZE);
+        let extra = grown.capacity() - SEGMENT_SIZE;
+        grown.put_bytes(0, extra);
+        grown.advance(extra);
+        assert_eq!(grown.capacity(), SEGMENT_SIZE);
+        drop(grown);
+        assert_eq!(retained(), MAX_RETAINED - 3);
+
+        // an idle isolate gives half of it back
+        crate::common::time::sleep(IDLE_SHRINK).await;
+        drop(PooledBuf::take());
+        assert_eq!(retained(), (MAX_RETAINED - 3) / 2);
+    }
+
+    #[test]
+    fn test_reuse() {
+        let first = PooledBuf::take().as_ptr();
+        for i in 0..1000 {
+            let mut buf = PooledBuf::take();
+            assert_eq!(buf.as_ptr(), first);
+            assert!(buf.is_empty() && buf.capacity() == SEGMENT_SIZE);
+            buf.put_slice(&[i as u8; 100]);
+        }
+        assert_eq!(retained(), 1);
+
+        set_max_retained(0);
+        drop(PooledBuf::take());
+        assert_eq!(retained(), 0);
+    }
+}
@@ -0,0 +1,182 @@
+use crate::common::time;
+use crate::outbound::dialer::{BoxStream, Dialer};
+use crate::outbound::Target;
+
+use futures_util::future::{self, Either};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use std::time::Duration;
+use worker::*;
+
+// rfc 8305's recommended connection attempt delay
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+// rfc 8305: attempts start `delay` apart, or right away once the one before
+// failed, in the order given (interleave the families beforehand). the
+// first to connect wins and the others are dropped, which cancels them.
+pub async fn happy_eyeballs_connect(
+    dialer: &dyn Dialer,
+    targets: Vec<Target>,
+    delay: Duration,
+) -> Result<BoxStream> {
+    let mut pending = targets.iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+    loop {
+        if attempts.is_empty() {
+            match pending.next() {
+                Some(target) => attempts.push(dialer.dial(target)),
+                None => break,
+            }
+        }
+
+        let attempt = match pending.len() {
+            // nothing left to start, only the running attempts matter
+            0 => attempts.next().await,
+            _ => match future::select(attempts.next(), Box::pin(time::sleep(delay))).await {
+                Either::Left((x, _)) => x,
+                Either::Right(_) => None,
+            },
+        };
+        match attempt {
+            Some(Ok(stream)) => return Ok(stream),
+            Some(Err(e)) => last_error = Some(e),
+            // the delay is up
+            None => {}
+        }
+        if let Some(target) = pending.next() {
+            attempts.push(dialer.dial(target));
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| Error::RustError("no addresses to dial".to_string())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbound::Network;
+    use async_trait::async_trait;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use tokio::io::AsyncReadExt;
+    use tokio::time::Instant;
+
+    // ipv6 is blackholed, `refused` addresses fail after 10ms and the rest
+    // connect after 50ms, answering with their own address
+    struct MockDialer {
+        epoch: Instant,
+        started: RefCell<Vec<(String, Duration)>>,
+        cancelled: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl MockDialer {
+        fn new() -> Self {
+            Self {
+                epoch: Instant::now(),
+                started: RefCell::default(),
+                cancelled: Rc::default(),
+            }
+        }
+    }
+
+    // records attempts dropped before they finished
+    struct Attempt {
+        addr: String,
+        cancelled: Rc<RefCell<Vec<String>>>,
+        done: bool,
+    }
+
+    impl Drop for Attempt {
+        fn drop(&mut self) {
+            if !self.done {
+                self.cancelled.borrow_mut().push(self.addr.clone());
+            }
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl Dialer for MockDialer {
+        async fn dial(&self, target: &Target) -> Result<BoxStream> {
+            let elapsed = self.epoch.elapsed();
+            self.started
+                .borrow_mut()
+                .push((target.addr.clone(), elapsed));
+            let mut attempt = Attempt {
+                addr: target.addr.clone(),
+                cancelled: self.cancelled.clone(),
+                done: false,
+            };
+            if target.addr.contains(':') {
+                std::future::pending::<()>().await;
+            }
+            if target.addr.starts_with("refused") {
+                time::sleep(Duration::from_millis(10)).await;
+                attempt.done = true;
+                return Err(Error::RustError(format!("{} refused", target.addr)));
+            }
+            time::sleep(Duration::from_millis(50)).await;
+            attempt.done = true;
+            let (client, mut server) = tokio::io::duplex(64);
+            tokio::io::AsyncWriteExt::write_all(&mut server, target.addr.as_bytes()).await?;
+            Ok(Box::new(client))
+        }
+    }
+
+    fn targets(addrs: &[&str]) -> Vec<Target> {
+        addrs
+            .iter()
+            .map(|x| Target::new(x.to_string(), 443, Network::Tcp))
+            .collect()
+    }
+
+    async fn answer(mut stream: BoxStream, len: usize) -> String {
+        let mut data = vec![0u8; len];
+        stream.read_exact(&mut data).await.unwrap();
+        String::from_utf8(data).unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_falls_back_to_ipv4() {
+        let dialer = MockDialer::new();
+        let targets = targets(&["2001:db8::1", "192.0.2.1", "2001:db8::2"]);
+        let stream = happy_eyeballs_connect(&dialer, targets, CONNECTION_ATTEMPT_DELAY)
+            .await
+            .unwrap();
+        assert_eq!(answer(stream, 9).await, "192.0.2.1");
+
+        // v4 started after the delay and won 50ms later, before the second
+        // v6 attempt was due. the hanging v6 attempt was cancelled.
+        assert_eq!(
+            *dialer.started.borrow(),
+            [
+                ("2001:db8::1".to_string(), Duration::ZERO),
+                ("192.0.2.1".to_string(), Duration::from_millis(250)),
+            ]
+        );
+        assert_eq!(*dialer.cancelled.borrow(), ["2001:db8::1"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failure_starts_the_next_attempt() {
+        let dialer = MockDialer::new();
+        let targets = targets(&["refused-1", "192.0.2.1"]);
+        let stream = happy_eyeballs_connect(&dialer, targets, CONNECTION_ATTEMPT_DELAY)
+            .await
+            .unwrap();
+        assert_eq!(answer(stream, 9).await, "192.0.2.1");
+        assert_eq!(dialer.started.borrow()[1].1, Duration::from_millis(10));
+
+        let targets = self::targets(&["refused-1", "refused-2"]);
+        let e = happy_eyeballs_connect(&dialer, targets, CONNECTION_ATTEMPT_DELAY)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(e.to_string(), "refused-2 refused");
+
+        let e = happy_eyeballs_connect(&dialer, Vec::new(), CONNECTION_ATTEMPT_DELAY)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(e.to_string(), "no addresses to dial");
+    }
+}
@@ -0,0 +1,158 @@
+use std::fmt;
+use std::io;
+
+// why bytes off the wire were refused. whatever parses input returns it,
+// it turns into a worker or io error where those are needed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProtocolError {
+    // fewer bytes than the format needs
+    Truncated(&'static str),
+    // a signature, version or type byte the protocol does not have
+    BadMagic(String),
+    // a length beyond what the format or this crate allows
+    LengthOverflow(&'static str),
+    // a tag or checksum that does not verify
+    AuthFailed(&'static str),
+    // an auth id or request sealed too long ago or too far ahead, at this
+    // time
+    Timestamp(u64),
+    // an auth id or salt seen before within the window
+    Replayed,
+    // valid, but not implemented here
+    Unsupported(String),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated(x) | Self::LengthOverflow(x) | Self::AuthFailed(x) => {
+                write!(f, "{x}")
+            }
+            Self::BadMagic(x) | Self::Unsupported(x) => write!(f, "{x}"),
+            Self::Timestamp(x) => write!(f, "timestamp {x} is outside the window"),
+            Self::Replayed => write!(f, "replayed request"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<ProtocolError> for worker::Error {
+    fn from(e: ProtocolError) -> Self {
+        worker::Error::RustError(e.to_string())
+    }
+}
+
+impl From<ProtocolError> for io::Error {
+    fn from(e: ProtocolError) -> Self {
+        let kind = match e {
+            ProtocolError::Truncated(_) => io::ErrorKind::UnexpectedEof,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+// whether a failed connection is worth trying again, through another
+// outbound or after a while. refusals, resets and timeouts pass; a server
+// turning down credentials or a malformed exchange fails the same way
+// every time, and is better surfaced at once.
+pub trait Retryable {
+    fn is_retryable(&self) -> bool;
+}
+
+impl Retryable for io::ErrorKind {
+    fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::NotConnected
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::Interrupted
+                | io::ErrorKind::WouldBlock
+                | io::ErrorKind::UnexpectedEof
+                | io::ErrorKind::AddrNotAvailable
+                | io::ErrorKind::HostUnreachable
+                | io::ErrorKind::NetworkUnreachable
+                | io::ErrorKind::NetworkDown
+        )
+    }
+}
+
+impl Retryable for ProtocolError {
+    fn is_retryable(&self) -> bool {
+        false
+    }
+}
+
+impl Retryable for io::Error {
+    fn is_retryable(&self) -> bool {
+        match self.get_ref().and_then(|x| x.downcast_ref::<ProtocolError>()) {
+            Some(e) => e.is_retryable(),
+            None => self.kind().is_retryable(),
+        }
+    }
+}
+
+impl Retryable for worker::Error {
+    fn is_retryable(&self) -> bool {
+        match self {
+            worker::Error::Io(e) => e.is_retryable(),
+            // the sockets' own errors only come as messages
+            worker::Error::RustError(x) | worker::Error::JsError(x) => {
+                let x = x.to_lowercase();
+                ["refused", "reset", "timed out", "timeout", "unreachable", "broken pipe"]
+                    .iter()
+                    .any(|needle| x.contains(needle))
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retryable_kinds() {
+        for kind in [
+            io::ErrorKind::ConnectionRefused,
+            io::ErrorKind::ConnectionReset,
+            io::ErrorKind::TimedOut,
+            io::ErrorKind::UnexpectedEof,
+        ] {
+            assert!(kind.is_retryable(), "{kind:?}");
+            assert!(io::Error::from(kind).is_retryable(), "{kind:?}");
+        }
+        for kind in [
+            io::ErrorKind::PermissionDenied,
+            io::ErrorKind::InvalidData,
+            io::ErrorKind::InvalidInput,
+            io::ErrorKind::Unsupported,
+        ] {
+            assert!(!kind.is_retryable(), "{kind:?}");
+        }
+    }
+
+    #[test]
+    fn test_retryable_errors() {
+        // a cut off header is still a protocol error, not a dropped link
+        let e = io::Error::from(ProtocolError::Truncated("vmess header"));
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(!e.is_retryable());
+        assert!(!io::Error::from(ProtocolError::AuthFailed("auth id")).is_retryable());
+
+        let io = |kind| worker::Error::Io(io::Error::new(kind, "x"));
+        assert!(io(io::ErrorKind::TimedOut).is_retryable());
+        assert!(!io(io::ErrorKind::PermissionDenied).is_retryable());
+        let message = |x: &str| worker::Error::RustError(x.to_string());
+        assert!(message("Connection refused").is_retryable());
+        assert!(message("dial timed out").is_retryable());
+        assert!(!message("blocked by routing").is_retryable());
+        assert!(!worker::Error::from(ProtocolError::Replayed).is_retryable());
+    }
+}
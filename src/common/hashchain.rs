@@ -0,0 +1,85 @@
+use super::hash::Hasher32;
+
+use std::marker::PhantomData;
+
+// a rolling hash over a sequence of blocks: every link is the hash of the
+// one before and the next block, the last one is the root. a block changed,
+// dropped or moved changes every link after it.
+pub struct HashChain<H> {
+    link: [u8; 32],
+    blocks: u64,
+    hasher: PhantomData<H>,
+}
+
+impl<H: Hasher32 + Default> HashChain<H> {
+    pub fn new() -> Self {
+        Self {
+            link: [0u8; 32],
+            blocks: 0,
+            hasher: PhantomData,
+        }
+    }
+
+    pub fn update(&mut self, block: &[u8]) {
+        let mut hasher = H::default();
+        hasher.update(&self.link);
+        hasher.update(block);
+        self.link = hasher.finalize();
+        self.blocks += 1;
+    }
+
+    pub fn blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    // the link of the last block, the zero link before the first
+    pub fn root(&self) -> [u8; 32] {
+        self.link
+    }
+}
+
+impl<H: Hasher32 + Default> Default for HashChain<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// whether `blocks`, in this order, chain up to `root`
+pub fn verify<H, B>(blocks: impl IntoIterator<Item = B>, root: &[u8; 32]) -> bool
+where
+    H: Hasher32 + Default,
+    B: AsRef<[u8]>,
+{
+    let mut chain = HashChain::<H>::new();
+    for block in blocks {
+        chain.update(block.as_ref());
+    }
+    chain.root() == *root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::hash::Sha256;
+
+    #[test]
+    fn test_tampered_block_changes_root() {
+        let blocks: Vec<Vec<u8>> = (0..8u8).map(|i| vec![i; 4096]).collect();
+        let mut chain = HashChain::<Sha256>::new();
+        for block in &blocks {
+            chain.update(block);
+        }
+        let root = chain.root();
+        assert_eq!(chain.blocks(), 8);
+        assert!(verify::<Sha256, _>(&blocks, &root));
+
+        let mut tampered = blocks.clone();
+        tampered[3][100] ^= 1;
+        assert!(!verify::<Sha256, _>(&tampered, &root));
+
+        let mut swapped = blocks.clone();
+        swapped.swap(1, 2);
+        assert!(!verify::<Sha256, _>(&swapped, &root));
+        assert!(!verify::<Sha256, _>(&blocks[..7], &root));
+    }
+}
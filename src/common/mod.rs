@@ -1,9 +1,32 @@
-pub mod hash;
+pub mod address;
+pub mod aead;
+pub mod buf;
+pub mod dial;
+pub mod error;
+pub mod hashchain;
+pub mod protobuf;
+pub mod proxy_protocol;
+pub mod ratelimit;
+pub mod relay;
+pub mod selftest;
+pub mod task;
+pub mod time;
 
-use std::net::{Ipv4Addr, Ipv6Addr};
-use tokio::io::{AsyncRead, AsyncReadExt};
-use worker::*;
+pub use address::{
+    parse_addr, parse_port, read_addr, read_port, read_u16_be, u16_be, write_u16_be,
+};
+pub use siren_hash as hash;
 
+// the platform's randomness, nothing here works without it
+pub fn random(buf: &mut [u8]) {
+    getrandom::getrandom(buf).expect("no random source");
+}
+
+// fills a buffer with random bytes, `random` unless a test wants them
+// reproducible
+pub type Random = Box<dyn FnMut(&mut [u8])>;
+
+pub const KDFSALT_CONST_AUTH_ID_ENCRYPTION_KEY: &[u8] = b"AES Auth ID Encryption";
 pub const KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_KEY: &[u8] =
     b"VMess Header AEAD Key_Length";
 pub const KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_IV: &[u8] =
@@ -41,47 +64,28 @@ macro_rules! sha256 {
     }
 }
 
-pub async fn parse_addr<R: AsyncRead + std::marker::Unpin>(buf: &mut R) -> Result<String> {
-    // combined addr type between Vmess, VLESS, and Trojan.
-    // VLESS wouldn't connect to ipv6 address due to mismatch addr type
-    let addr = match buf.read_u8().await? {
-        1 => {
-            let mut addr = [0u8; 4];
-            buf.read_exact(&mut addr).await?;
-            Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]).to_string()
-        }
-        2 | 3 => {
-            let len = buf.read_u8().await?;
-            let mut domain = vec![0u8; len as _];
-            buf.read_exact(&mut domain).await?;
-            String::from_utf8_lossy(&domain).to_string()
-        }
-        4 => {
-            let mut addr = [0u8; 16];
-            buf.read_exact(&mut addr).await?;
-            Ipv6Addr::new(
-                u16::from_be_bytes([addr[0], addr[1]]),
-                u16::from_be_bytes([addr[2], addr[3]]),
-                u16::from_be_bytes([addr[4], addr[5]]),
-                u16::from_be_bytes([addr[6], addr[7]]),
-                u16::from_be_bytes([addr[8], addr[9]]),
-                u16::from_be_bytes([addr[10], addr[11]]),
-                u16::from_be_bytes([addr[12], addr[13]]),
-                u16::from_be_bytes([addr[14], addr[15]]),
-            )
-            .to_string()
-        }
-        _ => {
-            return Err(Error::RustError("invalid address".to_string()));
+// console_* calls into the workers runtime and panics anywhere else, so
+// code that also runs in native tests logs through these instead.
+#[macro_export]
+macro_rules! log {
+    ( $($t:tt)* ) => {
+        {
+            #[cfg(target_arch = "wasm32")]
+            worker::console_log!($($t)*);
+            #[cfg(not(target_arch = "wasm32"))]
+            eprintln!($($t)*);
         }
-    };
-
-    Ok(addr)
+    }
 }
 
-pub async fn parse_port<R: AsyncRead + std::marker::Unpin>(buf: &mut R) -> Result<u16> {
-    let mut port = [0u8; 2];
-    buf.read_exact(&mut port).await?;
-
-    Ok(u16::from_be_bytes([port[0], port[1]]))
+#[macro_export]
+macro_rules! log_error {
+    ( $($t:tt)* ) => {
+        {
+            #[cfg(target_arch = "wasm32")]
+            worker::console_error!($($t)*);
+            #[cfg(not(target_arch = "wasm32"))]
+            eprintln!($($t)*);
+        }
+    }
 }
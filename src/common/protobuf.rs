@@ -0,0 +1,151 @@
+use worker::*;
+
+// just enough of the protobuf wire format to walk v2ray's dat files and
+// answer its api without generated code. fields are yielded in file order and nothing is
+// copied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Value<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+impl<'a> Value<'a> {
+    pub fn bytes(self) -> Option<&'a [u8]> {
+        match self {
+            Self::Bytes(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn varint(self) -> Option<u64> {
+        match self {
+            Self::Varint(x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Fields<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn varint(&mut self) -> Option<u64> {
+        let mut x = 0u64;
+        for (i, b) in self.buf.iter().take(10).enumerate() {
+            x |= u64::from(b & 0x7f) << (7 * i);
+            if b & 0x80 == 0 {
+                self.buf = &self.buf[i + 1..];
+                return Some(x);
+            }
+        }
+        None
+    }
+
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < n {
+            return None;
+        }
+        let (x, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Some(x)
+    }
+
+    fn field(&mut self) -> Option<(u32, Value<'a>)> {
+        let key = self.varint()?;
+        let number = u32::try_from(key >> 3).ok()?;
+        let value = match key & 7 {
+            0 => Value::Varint(self.varint()?),
+            1 => Value::Fixed64(u64::from_le_bytes(self.take(8)?.try_into().ok()?)),
+            2 => {
+                let len = usize::try_from(self.varint()?).ok()?;
+                Value::Bytes(self.take(len)?)
+            }
+            5 => Value::Fixed32(u32::from_le_bytes(self.take(4)?.try_into().ok()?)),
+            _ => return None,
+        };
+        Some((number, value))
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = Result<(u32, Value<'a>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        match self.field() {
+            Some(x) => Some(Ok(x)),
+            None => {
+                // nothing after a malformed field can be trusted
+                self.buf = &[];
+                Some(Err(Error::RustError("malformed protobuf".to_string())))
+            }
+        }
+    }
+}
+
+// the encoding half, for api responses and test fixtures
+#[derive(Default)]
+pub struct Writer(pub Vec<u8>);
+
+impl Writer {
+    fn varint(&mut self, mut x: u64) {
+        while x >= 0x80 {
+            self.0.push(x as u8 | 0x80);
+            x >>= 7;
+        }
+        self.0.push(x as u8);
+    }
+
+    pub fn uint(&mut self, number: u32, x: u64) -> &mut Self {
+        self.varint(u64::from(number) << 3);
+        self.varint(x);
+        self
+    }
+
+    pub fn bytes(&mut self, number: u32, x: &[u8]) -> &mut Self {
+        self.varint(u64::from(number) << 3 | 2);
+        self.varint(x.len() as u64);
+        self.0.extend_from_slice(x);
+        self
+    }
+
+    pub fn string(&mut self, number: u32, x: &str) -> &mut Self {
+        self.bytes(number, x.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fields() {
+        let mut w = Writer::default();
+        w.uint(1, 300).bytes(2, b"hello").uint(15, u64::MAX);
+        let fields: Vec<_> = Fields::new(&w.0).map(|x| x.unwrap()).collect();
+        assert_eq!(
+            fields,
+            [
+                (1, Value::Varint(300)),
+                (2, Value::Bytes(b"hello")),
+                (15, Value::Varint(u64::MAX)),
+            ]
+        );
+
+        // a length running past the end of the buffer
+        let truncated = &w.0[..5];
+        let fields: Vec<_> = Fields::new(truncated).collect();
+        assert_eq!(fields.len(), 2);
+        assert!(fields[1].is_err());
+    }
+}
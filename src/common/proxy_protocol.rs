@@ -0,0 +1,166 @@
+use super::error::ProtocolError;
+use super::{read_u16_be, write_u16_be};
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+// https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+pub const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+// signature, version and command, family and protocol, length
+pub const HEADER_LEN: usize = 16;
+
+const VERSION_2: u8 = 0x20;
+const COMMAND_LOCAL: u8 = 0x00;
+const COMMAND_PROXY: u8 = 0x01;
+const TCP4: u8 = 0x11;
+const TCP6: u8 = 0x21;
+
+// how long the whole header is, from its first HEADER_LEN bytes
+pub fn header_len(buf: &[u8]) -> Result<usize, ProtocolError> {
+    if buf.len() < HEADER_LEN {
+        return Err(ProtocolError::Truncated("incomplete proxy protocol header"));
+    }
+    if buf[..12] != SIGNATURE {
+        return Err(ProtocolError::BadMagic(
+            "invalid proxy protocol signature".to_string(),
+        ));
+    }
+    if buf[12] & 0xf0 != VERSION_2 {
+        return Err(ProtocolError::Unsupported(format!(
+            "unsupported proxy protocol version {}",
+            buf[12] >> 4
+        )));
+    }
+    Ok(HEADER_LEN + read_u16_be(&buf[14..]) as usize)
+}
+
+// the source and destination of a v2 header of a proxied tcp connection,
+// and how many bytes of `buf` it took. tlvs are skipped.
+pub fn parse_v2(buf: &[u8]) -> Result<(SocketAddr, SocketAddr, usize), ProtocolError> {
+    let len = header_len(buf)?;
+    let Some(addrs) = buf.get(HEADER_LEN..len) else {
+        return Err(ProtocolError::Truncated("incomplete proxy protocol header"));
+    };
+    match buf[12] & 0x0f {
+        COMMAND_PROXY => {}
+        // health checks of the balancer itself
+        COMMAND_LOCAL => {
+            return Err(ProtocolError::Unsupported(
+                "proxy protocol local command carries no addresses".to_string(),
+            ))
+        }
+        x => {
+            return Err(ProtocolError::BadMagic(format!(
+                "unknown proxy protocol command {x}"
+            )))
+        }
+    }
+
+    let port = read_u16_be;
+    let (src, dst) = match buf[13] {
+        TCP4 if addrs.len() >= 12 => {
+            let ip = |x: &[u8]| IpAddr::V4(Ipv4Addr::new(x[0], x[1], x[2], x[3]));
+            (
+                SocketAddr::new(ip(&addrs[..4]), port(&addrs[8..])),
+                SocketAddr::new(ip(&addrs[4..]), port(&addrs[10..])),
+            )
+        }
+        TCP6 if addrs.len() >= 36 => {
+            let ip = |x: &[u8]| {
+                let mut ip = [0u8; 16];
+                ip.copy_from_slice(&x[..16]);
+                IpAddr::V6(Ipv6Addr::from(ip))
+            };
+            (
+                SocketAddr::new(ip(&addrs[..16]), port(&addrs[32..])),
+                SocketAddr::new(ip(&addrs[16..]), port(&addrs[34..])),
+            )
+        }
+        TCP4 | TCP6 => {
+            return Err(ProtocolError::Truncated(
+                "proxy protocol addresses are truncated",
+            ))
+        }
+        x => {
+            return Err(ProtocolError::Unsupported(format!(
+                "unsupported proxy protocol family and protocol {x:#04x}"
+            )))
+        }
+    };
+    Ok((src, dst, len))
+}
+
+// a v2 header for a tcp connection from `src` to `dst`. both need to be of
+// the same family, a v4 address is mapped into v6 otherwise.
+pub fn encode_v2(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let mut header = SIGNATURE.to_vec();
+    header.push(VERSION_2 | COMMAND_PROXY);
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            header.push(TCP4);
+            write_u16_be(&mut header, 12);
+            header.extend(s.octets());
+            header.extend(d.octets());
+        }
+        (s, d) => {
+            let v6 = |x: IpAddr| match x {
+                IpAddr::V4(x) => x.to_ipv6_mapped(),
+                IpAddr::V6(x) => x,
+            };
+            header.push(TCP6);
+            write_u16_be(&mut header, 36);
+            header.extend(v6(s).octets());
+            header.extend(v6(d).octets());
+        }
+    }
+    write_u16_be(&mut header, src.port());
+    write_u16_be(&mut header, dst.port());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tcp4() {
+        let mut buf = SIGNATURE.to_vec();
+        buf.extend([0x21, 0x11, 0x00, 0x0c]);
+        buf.extend([192, 0, 2, 1, 198, 51, 100, 7]);
+        buf.extend(51234u16.to_be_bytes());
+        buf.extend(443u16.to_be_bytes());
+        buf.extend(b"payload");
+
+        let (src, dst, len) = parse_v2(&buf).unwrap();
+        assert_eq!(src, "192.0.2.1:51234".parse().unwrap());
+        assert_eq!(dst, "198.51.100.7:443".parse().unwrap());
+        assert_eq!(&buf[len..], b"payload");
+        assert_eq!(encode_v2(src, dst), buf[..len]);
+
+        let src = "[2001:db8::1]:51234".parse().unwrap();
+        let header = encode_v2(src, dst);
+        let dst = "[::ffff:198.51.100.7]:443".parse().unwrap();
+        assert_eq!(parse_v2(&header).unwrap(), (src, dst, 52));
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        let mut header = encode_v2(
+            "192.0.2.1:51234".parse().unwrap(),
+            "198.51.100.7:443".parse().unwrap(),
+        );
+        let e = parse_v2(&header[..20]).unwrap_err();
+        assert!(matches!(e, ProtocolError::Truncated(_)));
+        assert_eq!(e.to_string(), "incomplete proxy protocol header");
+
+        header[12] = 0x20;
+        let e = parse_v2(&header).unwrap_err();
+        assert!(e.to_string().contains("local command"), "{e}");
+
+        header[3] = b'x';
+        let e = parse_v2(&header).unwrap_err();
+        assert_eq!(e.to_string(), "invalid proxy protocol signature");
+        let e = parse_v2(b"PROXY TCP4 192.0.2.1 198.51.100.7 51234 443\r\n").unwrap_err();
+        assert_eq!(e.to_string(), "invalid proxy protocol signature");
+    }
+}
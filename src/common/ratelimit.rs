@@ -0,0 +1,668 @@
+use super::time::{self, Instant};
+use crate::config::ConfigError;
+
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limit {
+    // bytes per second, sustained
+    pub rate: u64,
+    // bytes that may go through at once after a quiet period
+    pub burst: u64,
+}
+
+// bytes as a positive integer or a string with a unit, `"5MiB"`, `"100kB"`
+pub fn parse_size(value: &Value) -> Option<u64> {
+    if let Some(x) = value.as_u64() {
+        return Some(x).filter(|x| *x > 0);
+    }
+    let s = value.as_str()?.trim();
+    let unit = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(unit);
+    let multiplier = match unit.trim() {
+        "" | "B" => 1,
+        "kB" | "KB" => 1000,
+        "KiB" => 1 << 10,
+        "MB" => 1000 * 1000,
+        "MiB" => 1 << 20,
+        "GB" => 1000 * 1000 * 1000,
+        "GiB" => 1 << 30,
+        _ => return None,
+    };
+    n.parse::<u64>()
+        .ok()
+        .and_then(|x| x.checked_mul(multiplier))
+        .filter(|x| *x > 0)
+}
+
+// one limit for both directions, `{"rate": "1MiB"}`, or one for each,
+// `{"up": "5MiB", "down": "20MiB"}`. the burst defaults to one second
+// worth of rate and goes for either direction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy {
+    Shared(Limit),
+    Split {
+        up: Option<Limit>,
+        down: Option<Limit>,
+    },
+}
+
+impl Policy {
+    pub fn from_json(value: &Value, path: &str) -> Result<Self, Vec<ConfigError>> {
+        let Some(object) = value.as_object() else {
+            return Err(vec![ConfigError::new(path, "expected an object")]);
+        };
+
+        let mut errors = Vec::new();
+        let (mut rate, mut up, mut down, mut burst) = (None, None, None, None);
+        for (key, value) in object {
+            let path = format!("{path}.{key}");
+            let slot = match key.as_str() {
+                "rate" => &mut rate,
+                "up" => &mut up,
+                "down" => &mut down,
+                "burst" => &mut burst,
+                _ => {
+                    errors.push(ConfigError::new(&path, "unknown field"));
+                    continue;
+                }
+            };
+            match parse_size(value) {
+                Some(x) => *slot = Some(x),
+                None => errors.push(ConfigError::new(
+                    &path,
+                    "expected a positive number of bytes, e.g. 1048576 or \"1MiB\"",
+                )),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let limit = |rate| Limit {
+            rate,
+            burst: burst.unwrap_or(rate),
+        };
+        match (rate, up, down) {
+            (Some(rate), None, None) => Ok(Self::Shared(limit(rate))),
+            (None, None, None) => Err(vec![ConfigError::new(path, "rate, up or down is not set")]),
+            (None, up, down) => Ok(Self::Split {
+                up: up.map(limit),
+                down: down.map(limit),
+            }),
+            _ => Err(vec![ConfigError::new(
+                path,
+                "rate is for both directions, it can not be set along with up or down",
+            )]),
+        }
+    }
+
+    // new, full buckets
+    pub fn buckets(&self) -> Buckets {
+        let bucket = |x: Limit| Rc::new(RefCell::new(TokenBucket::new(x)));
+        match *self {
+            Self::Shared(x) => {
+                let bucket = bucket(x);
+                Buckets {
+                    up: Some(bucket.clone()),
+                    down: Some(bucket),
+                }
+            }
+            Self::Split { up, down } => Buckets {
+                up: up.map(bucket),
+                down: down.map(bucket),
+            },
+        }
+    }
+}
+
+// a bucket that may go into debt: whatever was just moved is taken out
+// after the fact, and the next read or write waits until it is paid off.
+// it goes by the monotonic clock, which paused tests move along.
+pub struct TokenBucket {
+    limit: Limit,
+    tokens: f64,
+    last: Option<Instant>,
+}
+
+impl TokenBucket {
+    // starts out full
+    pub fn new(limit: Limit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst as f64,
+            last: None,
+        }
+    }
+
+    pub fn limit(&self) -> Limit {
+        self.limit
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = self.last.map_or(Duration::ZERO, |x| now.saturating_duration_since(x));
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.limit.rate as f64)
+            .min(self.limit.burst as f64);
+        self.last = Some(now);
+    }
+
+    pub fn consume(&mut self, n: usize, now: Instant) {
+        self.refill(now);
+        self.tokens -= n as f64;
+    }
+
+    // how long until there is a token to spend
+    pub fn wait(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((1.0 - self.tokens) / self.limit.rate as f64)
+    }
+}
+
+pub type Bucket = Rc<RefCell<TokenBucket>>;
+
+// what a policy charges: the client's uplink, its reads, and its downlink,
+// its writes. a shared policy is one bucket in both.
+#[derive(Clone, Default)]
+pub struct Buckets {
+    pub up: Option<Bucket>,
+    pub down: Option<Bucket>,
+}
+
+thread_local! {
+    static SHARED: RefCell<BTreeMap<String, (Policy, Buckets)>> = RefCell::default();
+}
+
+// buckets every connection of the isolate draws from, by `key`. the worker
+// serves a single uuid, so its user has one set per isolate. changing the
+// policy starts them over.
+pub fn shared(key: &str, policy: Policy) -> Buckets {
+    SHARED.with_borrow_mut(|x| match x.get(key) {
+        Some((p, buckets)) if *p == policy => buckets.clone(),
+        _ => {
+            let buckets = policy.buckets();
+            x.insert(key.to_string(), (policy, buckets.clone()));
+            buckets
+        }
+    })
+}
+
+// the `RATELIMIT` binding: `{"connection": {...}, "user": {...}, "inbound":
+// {"vless": {...}}}`, every policy optional. the connection one is fresh
+// for each connection, the others are shared by all of theirs.
+pub struct RateLimit {
+    connection: Option<Policy>,
+    user: Option<(Policy, Buckets)>,
+    inbound: BTreeMap<String, (Policy, Buckets)>,
+}
+
+impl RateLimit {
+    pub fn new(connection: Option<Policy>, user: Option<Policy>) -> Self {
+        Self {
+            connection,
+            user: user.map(|x| (x, x.buckets())),
+            inbound: BTreeMap::new(),
+        }
+    }
+
+    // connections accepted by `tag` draw from these together
+    pub fn with_inbound(mut self, tag: &str, policy: Policy) -> Self {
+        self.inbound
+            .insert(tag.to_string(), (policy, policy.buckets()));
+        self
+    }
+
+    pub fn from_json(value: &Value, path: &str) -> Result<Self, Vec<ConfigError>> {
+        let Some(object) = value.as_object() else {
+            return Err(vec![ConfigError::new(path, "expected an object")]);
+        };
+
+        let mut errors = Vec::new();
+        let (mut connection, mut user, mut inbound) = (None, None, Vec::new());
+        for (key, value) in object {
+            let path = format!("{path}.{key}");
+            let slot = match key.as_str() {
+                "connection" => &mut connection,
+                "user" => &mut user,
+                "inbound" => {
+                    let Some(tags) = value.as_object() else {
+                        errors.push(ConfigError::new(&path, "expected an object"));
+                        continue;
+                    };
+                    for (tag, value) in tags {
+                        match Policy::from_json(value, &format!("{path}.{tag}")) {
+                            Ok(x) => inbound.push((tag.clone(), x)),
+                            Err(e) => errors.extend(e),
+                        }
+                    }
+                    continue;
+                }
+                _ => {
+                    errors.push(ConfigError::new(&path, "unknown field"));
+                    continue;
+                }
+            };
+            match Policy::from_json(value, &path) {
+                Ok(x) => *slot = Some(x),
+                Err(e) => errors.extend(e),
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        let limit = Self::new(connection, user);
+        Ok(inbound
+            .into_iter()
+            .fold(limit, |limit, (tag, x)| limit.with_inbound(&tag, x)))
+    }
+
+    // swaps the user and inbound buckets for the per-isolate ones, see
+    // `shared`
+    pub fn share_buckets(&mut self) {
+        if let Some((policy, buckets)) = self.user.as_mut() {
+            *buckets = shared("user", *policy);
+        }
+        for (tag, (policy, buckets)) in self.inbound.iter_mut() {
+            *buckets = shared(&format!("inbound>>>{tag}"), *policy);
+        }
+    }
+
+    pub fn connection(&self) -> Option<Policy> {
+        self.connection
+    }
+
+    pub fn user(&self) -> Option<&Buckets> {
+        self.user.as_ref().map(|(_, x)| x)
+    }
+
+    pub fn inbound(&self, tag: &str) -> Option<&Buckets> {
+        self.inbound.get(tag).map(|(_, x)| x)
+    }
+
+    // the uplink and downlink buckets of a connection accepted by
+    // `inbound`: a fresh connection set plus the shared ones
+    pub fn buckets(&self, inbound: &str) -> (Vec<Bucket>, Vec<Bucket>) {
+        let connection = self.connection.map(|x| x.buckets());
+        let all = connection
+            .iter()
+            .chain(self.user())
+            .chain(self.inbound(inbound));
+        let (mut up, mut down) = (Vec::new(), Vec::new());
+        for x in all {
+            up.extend(x.up.clone());
+            down.extend(x.down.clone());
+        }
+        (up, down)
+    }
+}
+
+type Delay = Pin<Box<dyn Future<Output = ()>>>;
+
+// waits for its buckets to have tokens before each read and write, and
+// charges them for the bytes moved: reads, the client's uplink, against
+// `up` and writes against `down`. the task sleeps until the deficit is
+// paid off rather than polling, and a stalled direction does not hold the
+// other one up.
+pub struct ThrottledStream<S> {
+    inner: S,
+    up: Vec<Bucket>,
+    down: Vec<Bucket>,
+    // no single write takes more than the smallest burst
+    max_write: usize,
+    read_delay: Option<Delay>,
+    write_delay: Option<Delay>,
+}
+
+impl<S> ThrottledStream<S> {
+    pub fn new(inner: S, up: Vec<Bucket>, down: Vec<Bucket>) -> Self {
+        let max_write = down
+            .iter()
+            .map(|x| x.borrow().limit.burst)
+            .min()
+            .map_or(usize::MAX, |x| usize::try_from(x).unwrap_or(usize::MAX));
+        Self {
+            inner,
+            up,
+            down,
+            max_write,
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+}
+
+fn charge(buckets: &[Bucket], n: usize) {
+    if n == 0 {
+        return;
+    }
+    let now = Instant::now();
+    for bucket in buckets {
+        bucket.borrow_mut().consume(n, now);
+    }
+}
+
+fn poll_tokens(buckets: &[Bucket], delay: &mut Option<Delay>, cx: &mut Context<'_>) -> Poll<()> {
+    loop {
+        if let Some(x) = delay.as_mut() {
+            ready!(x.as_mut().poll(cx));
+            *delay = None;
+        }
+        let now = Instant::now();
+        let wait = buckets
+            .iter()
+            .map(|x| x.borrow_mut().wait(now))
+            .max()
+            .unwrap_or_default();
+        if wait.is_zero() {
+            return Poll::Ready(());
+        }
+        *delay = Some(Box::pin(time::sleep(wait)));
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ThrottledStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(poll_tokens(&this.up, &mut this.read_delay, cx));
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        charge(&this.up, buf.filled().len() - before);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ThrottledStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(poll_tokens(&this.down, &mut this.write_delay, cx));
+        let buf = &buf[..buf.len().min(this.max_write)];
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        charge(&this.down, n);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::Instant;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(Limit {
+            rate: 1000,
+            burst: 500,
+        });
+        let start = time::Instant::now();
+        let at = |x: Duration| start + x;
+        assert_eq!(bucket.wait(at(SECOND)), Duration::ZERO);
+
+        // the burst goes through at once, the debt is paid at the rate
+        bucket.consume(1499, at(SECOND));
+        assert_eq!(bucket.wait(at(SECOND)), SECOND);
+        assert_eq!(bucket.wait(at(SECOND * 3 / 2)), SECOND / 2);
+        assert_eq!(bucket.wait(at(SECOND * 2)), Duration::ZERO);
+
+        // idle time refills no further than the burst
+        bucket.consume(1, at(SECOND * 2));
+        assert_eq!(bucket.wait(at(SECOND * 100)), Duration::ZERO);
+        bucket.consume(500, at(SECOND * 100));
+        assert_eq!(bucket.wait(at(SECOND * 100)), Duration::from_millis(1));
+    }
+
+    #[test]
+    fn test_from_json() {
+        let limit =
+            RateLimit::from_json(&json!({"connection": {"rate": 100}}), "RATELIMIT").unwrap();
+        assert_eq!(
+            limit.connection(),
+            Some(Policy::Shared(Limit {
+                rate: 100,
+                burst: 100
+            }))
+        );
+        assert!(limit.user().is_none());
+        let (up, down) = limit.buckets("vless");
+        assert_eq!((up.len(), down.len()), (1, 1));
+        assert!(Rc::ptr_eq(&up[0], &down[0]));
+
+        let errors = RateLimit::from_json(
+            &json!({"connection": {"burst": 10}, "user": {"rate": 0}, "total": {}}),
+            "RATELIMIT",
+        )
+        .err()
+        .unwrap();
+        let paths: Vec<_> = errors.iter().map(|x| x.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "RATELIMIT.connection",
+                "RATELIMIT.total",
+                "RATELIMIT.user.rate"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_policy() {
+        let limit = RateLimit::from_json(
+            &json!({
+                "user": {"up": "5MiB", "down": "20MiB", "burst": "1MiB"},
+                "inbound": {"vless": {"down": 1000}},
+            }),
+            "RATELIMIT",
+        )
+        .unwrap();
+        let user = limit.user().unwrap();
+        let limit_of = |x: &Option<Bucket>| x.as_ref().unwrap().borrow().limit();
+        assert_eq!(
+            limit_of(&user.up),
+            Limit {
+                rate: 5 << 20,
+                burst: 1 << 20
+            }
+        );
+        assert_eq!(limit_of(&user.down).rate, 20 << 20);
+
+        // vless connections draw from the user's and vless' downlink
+        assert_eq!(limit.buckets("vless").1.len(), 2);
+        assert_eq!(limit.buckets("vmess").1.len(), 1);
+        assert!(limit.inbound("vless").unwrap().up.is_none());
+
+        let errors = RateLimit::from_json(
+            &json!({
+                "connection": {"rate": "1MiB", "up": "1MiB"},
+                "inbound": {"trojan": {"down": "fast"}},
+            }),
+            "RATELIMIT",
+        )
+        .err()
+        .unwrap();
+        let paths: Vec<_> = errors.iter().map(|x| x.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            ["RATELIMIT.connection", "RATELIMIT.inbound.trojan.down"]
+        );
+    }
+
+    #[test]
+    fn test_parse_size() {
+        for (value, expected) in [
+            (json!(1500), Some(1500)),
+            (json!("1500"), Some(1500)),
+            (json!("100kB"), Some(100_000)),
+            (json!("64KiB"), Some(65_536)),
+            (json!("5MiB"), Some(5 << 20)),
+            (json!("2 GB"), Some(2_000_000_000)),
+            (json!(0), None),
+            (json!("0MiB"), None),
+            (json!("1.5MiB"), None),
+            (json!("5Mbps"), None),
+            (json!(-1), None),
+        ] {
+            assert_eq!(parse_size(&value), expected, "{value}");
+        }
+    }
+
+    #[test]
+    fn test_shared_user_bucket() {
+        let limit = json!({"user": {"rate": 100, "burst": 1000}, "inbound": {"ss": {"up": 10}}});
+        let mut first = RateLimit::from_json(&limit, "RATELIMIT").unwrap();
+        let mut second = RateLimit::from_json(&limit, "RATELIMIT").unwrap();
+        first.share_buckets();
+        second.share_buckets();
+        let up = |x: &RateLimit, tag| x.buckets(tag).0;
+        for (a, b) in up(&first, "ss").iter().zip(up(&second, "ss").iter()) {
+            assert!(Rc::ptr_eq(a, b));
+        }
+        assert_eq!(up(&first, "ss").len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttled_stream() {
+        let limit = Limit {
+            rate: 100_000,
+            burst: 10_000,
+        };
+        let data = vec![0x42u8; 60_000];
+
+        for direction in ["write", "read"] {
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            let buckets = vec![Rc::new(RefCell::new(TokenBucket::new(limit)))];
+            let start = Instant::now();
+            let received = if direction == "write" {
+                let mut throttled = ThrottledStream::new(client, buckets.clone(), buckets);
+                let mut server = server;
+                let (_, received) = tokio::join!(
+                    async {
+                        throttled.write_all(&data).await.unwrap();
+                        throttled.shutdown().await.unwrap();
+                    },
+                    async {
+                        let mut received = Vec::new();
+                        server.read_to_end(&mut received).await.unwrap();
+                        received
+                    }
+                );
+                received
+            } else {
+                let mut throttled = ThrottledStream::new(server, buckets.clone(), buckets);
+                let mut client = client;
+                let (_, received) = tokio::join!(
+                    async {
+                        client.write_all(&data).await.unwrap();
+                        client.shutdown().await.unwrap();
+                    },
+                    async {
+                        let mut received = Vec::new();
+                        throttled.read_to_end(&mut received).await.unwrap();
+                        received
+                    }
+                );
+                received
+            };
+
+            // 10k of burst, then 50k at 100k/s. writes finish owing for the
+            // last one, reads pay it off before they see the end.
+            let elapsed = start.elapsed();
+            assert_eq!(received, data);
+            assert!(
+                elapsed >= Duration::from_millis(400),
+                "{direction} took {elapsed:?}"
+            );
+            assert!(
+                elapsed < Duration::from_millis(550),
+                "{direction} took {elapsed:?}"
+            );
+        }
+
+        // without limits nothing waits
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let start = Instant::now();
+        let mut throttled = ThrottledStream::new(server, Vec::new(), Vec::new());
+        client.write_all(&data[..32 * 1024]).await.unwrap();
+        drop(client);
+        let mut received = Vec::new();
+        throttled.read_to_end(&mut received).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    // two connections of the same user download as fast as they can for 3s
+    // and share its 1MiB/s
+    #[tokio::test(start_paused = true)]
+    async fn test_user_downlink_over_time() {
+        let limit = RateLimit::from_json(
+            &json!({"user": {"down": "1MiB", "burst": "64KiB"}}),
+            "RATELIMIT",
+        )
+        .unwrap();
+        let duration = Duration::from_secs(3);
+        let start = Instant::now();
+
+        let download = || async {
+            let (client, mut server) = tokio::io::duplex(64 * 1024);
+            let (up, down) = limit.buckets("vless");
+            let mut throttled = ThrottledStream::new(client, up, down);
+            let chunk = vec![0u8; 16 * 1024];
+            let send = async {
+                while start.elapsed() < duration {
+                    throttled.write_all(&chunk).await.unwrap();
+                }
+                throttled.shutdown().await.unwrap();
+            };
+            let receive = async {
+                let mut total = 0;
+                let mut buf = vec![0u8; 64 * 1024];
+                loop {
+                    match server.read(&mut buf).await.unwrap() {
+                        0 => return total,
+                        n => total += n,
+                    }
+                }
+            };
+            tokio::join!(send, receive).1
+        };
+        let (a, b) = tokio::join!(download(), download());
+
+        let expected = 3.0 * (1 << 20) as f64;
+        let total = (a + b) as f64;
+        assert!(
+            (total - expected).abs() < expected * 0.05,
+            "moved {total} bytes, {a} and {b}"
+        );
+        // both got a share
+        assert!(a > 0 && b > 0, "{a} and {b}");
+    }
+}
@@ -0,0 +1,380 @@
+use super::buf::{PooledBuf, SEGMENT_SIZE};
+use super::time::{self, Instant};
+
+use futures_util::future::{self, Either};
+use std::cell::Cell;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use worker::*;
+
+// how long a relay may go on, v2ray's connIdle, uplinkOnly and
+// downlinkOnly plus an overall cap
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timeouts {
+    // nothing read in either direction
+    pub idle: Duration,
+    // idle limits once the downlink, or the uplink, is done and only the
+    // other direction is left
+    pub uplink_only: Duration,
+    pub downlink_only: Duration,
+    pub max_lifetime: Option<Duration>,
+}
+
+impl Timeouts {
+    // the same limit whether or not a direction is done
+    pub fn idle(idle: Duration) -> Self {
+        Self {
+            idle,
+            uplink_only: idle,
+            downlink_only: idle,
+            max_lifetime: None,
+        }
+    }
+}
+
+// notes when something was last read through it. reads never touch a
+// timer, the watchdog works out the deadline from this when it wakes.
+struct Activity<'a, R> {
+    inner: R,
+    last: &'a Cell<Instant>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Activity<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if buf.filled().len() > before {
+            self.last.set(Instant::now());
+        }
+        res
+    }
+}
+
+// tokio's copy with a segment from the pool in place of a buffer of its
+// own. it reads on while the writer is busy and there is room, and what
+// the writer holds back is flushed whenever the reader has nothing yet, so
+// coalescing writers still send in time.
+struct Copy<'a, R, W> {
+    reader: &'a mut R,
+    writer: &'a mut W,
+    buf: PooledBuf,
+    // what of `buf` was read and how much of that is written
+    pos: usize,
+    len: usize,
+    eof: bool,
+    flushed: bool,
+    total: u64,
+}
+
+fn copy<'a, R, W>(reader: &'a mut R, writer: &'a mut W) -> Copy<'a, R, W> {
+    let mut buf = PooledBuf::take();
+    buf.resize(SEGMENT_SIZE, 0);
+    Copy {
+        reader,
+        writer,
+        buf,
+        pos: 0,
+        len: 0,
+        eof: false,
+        flushed: true,
+        total: 0,
+    }
+}
+
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> Future for Copy<'_, R, W> {
+    type Output = io::Result<u64>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let this = &mut *self;
+        loop {
+            if this.pos == this.len {
+                (this.pos, this.len) = (0, 0);
+            }
+            if !this.eof && this.len < this.buf.len() {
+                let mut read = ReadBuf::new(&mut this.buf[this.len..]);
+                match Pin::new(&mut *this.reader).poll_read(cx, &mut read) {
+                    Poll::Ready(res) => {
+                        res?;
+                        let n = read.filled().len();
+                        this.eof = n == 0;
+                        this.len += n;
+                    }
+                    Poll::Pending if this.pos < this.len => {}
+                    Poll::Pending => {
+                        if !this.flushed {
+                            ready!(Pin::new(&mut *this.writer).poll_flush(cx))?;
+                            this.flushed = true;
+                        }
+                        return Poll::Pending;
+                    }
+                }
+            }
+            if this.pos == this.len {
+                ready!(Pin::new(&mut *this.writer).poll_flush(cx))?;
+                return Poll::Ready(Ok(this.total));
+            }
+            let buf = &this.buf[this.pos..this.len];
+            let n = ready!(Pin::new(&mut *this.writer).poll_write(cx, buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            this.pos += n;
+            this.total += n as u64;
+            this.flushed = false;
+        }
+    }
+}
+
+type Sleep = Pin<Box<dyn Future<Output = ()>>>;
+
+// resolves once nothing was read for the idle timeout in force, or when
+// the lifetime is up. one sleep is armed at a time: activity only moves
+// the deadline later, which is picked up when the sleep fires, and a
+// shorter timeout re-arms it right away.
+struct Watchdog<'a> {
+    last: &'a Cell<Instant>,
+    idle: &'a Cell<Duration>,
+    // and the lifetime it stems from
+    end: Option<(Instant, Duration)>,
+    armed: Option<(Instant, Sleep)>,
+}
+
+impl Future for Watchdog<'_> {
+    type Output = Error;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Error> {
+        loop {
+            let idle = self.idle.get();
+            let deadline = self.last.get() + idle;
+            let now = Instant::now();
+            match self.end {
+                Some((end, lifetime)) if end <= deadline.min(now) => {
+                    return Poll::Ready(Error::RustError(format!(
+                        "relay reached its lifetime of {}s",
+                        lifetime.as_secs_f32()
+                    )));
+                }
+                _ if deadline <= now => {
+                    return Poll::Ready(Error::RustError(format!(
+                        "relay idle for {}s",
+                        idle.as_secs_f32()
+                    )));
+                }
+                _ => {}
+            }
+
+            let deadline = match self.end {
+                Some((end, _)) => deadline.min(end),
+                None => deadline,
+            };
+            match self.armed.as_mut() {
+                Some((at, sleep)) if *at <= deadline => {
+                    ready!(sleep.as_mut().poll(cx));
+                    self.armed = None;
+                }
+                _ => {
+                    let sleep = time::sleep(deadline.saturating_duration_since(now));
+                    self.armed = Some((deadline, Box::pin(sleep)));
+                }
+            }
+        }
+    }
+}
+
+// copies a -> b (the uplink) and b -> a (the downlink) until both sides
+// hit eof, shutting down the write half of the other side as soon as one
+// side is done. gives up on both directions once nothing has been read
+// for `timeouts.idle`, or for the uplink or downlink only timeout once the
+// other direction is done, and when the lifetime is up. returns the bytes
+// copied as (a -> b, b -> a).
+pub async fn relay_bidirectional<A, B>(a: A, b: B, timeouts: Timeouts) -> Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let started = Instant::now();
+    let last = Cell::new(started);
+    let idle = Cell::new(timeouts.idle);
+    let (ar, mut aw) = tokio::io::split(a);
+    let (br, mut bw) = tokio::io::split(b);
+
+    let res = {
+        // what is left of the relay once a direction is done
+        let done = |grace: Duration| {
+            last.set(Instant::now());
+            idle.set(idle.get().min(grace));
+        };
+        let a_to_b = async {
+            let mut reader = Activity {
+                inner: ar,
+                last: &last,
+            };
+            let n = copy(&mut reader, &mut bw).await?;
+            bw.shutdown().await?;
+            done(timeouts.downlink_only);
+            io::Result::Ok(n)
+        };
+        let b_to_a = async {
+            let mut reader = Activity {
+                inner: br,
+                last: &last,
+            };
+            let n = copy(&mut reader, &mut aw).await?;
+            aw.shutdown().await?;
+            done(timeouts.uplink_only);
+            io::Result::Ok(n)
+        };
+        let watchdog = Watchdog {
+            last: &last,
+            idle: &idle,
+            end: timeouts.max_lifetime.map(|x| (started + x, x)),
+            armed: None,
+        };
+
+        let transfer = future::try_join(a_to_b, b_to_a);
+        let res = match future::select(Box::pin(transfer), watchdog).await {
+            Either::Left((res, _)) => res.map_err(|e| Error::RustError(e.to_string())),
+            Either::Right((e, _)) => Err(e),
+        };
+        res
+    };
+
+    if res.is_err() {
+        // best effort, the peers may already be gone
+        let _ = aw.shutdown().await;
+        let _ = bw.shutdown().await;
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_relay_half_close() {
+        let (mut client, a) = tokio::io::duplex(64);
+        let (b, mut server) = tokio::io::duplex(64);
+
+        let relay = relay_bidirectional(a, b, Timeouts::idle(Duration::from_secs(10)));
+        let peers = async {
+            client.write_all(b"request").await.unwrap();
+            client.shutdown().await.unwrap();
+
+            // the server sees eof from the client but can still answer
+            let mut request = Vec::new();
+            server.read_to_end(&mut request).await.unwrap();
+            assert_eq!(request, b"request");
+            server.write_all(&[7u8; 1000]).await.unwrap();
+            server.shutdown().await.unwrap();
+
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            assert_eq!(response, [7u8; 1000]);
+        };
+
+        let (res, ()) = tokio::join!(relay, peers);
+        assert_eq!(res.unwrap(), (7, 1000));
+        // each direction's segment went back to the pool
+        assert_eq!(crate::common::buf::retained(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_relay_idle_timeout() {
+        let (mut client, a) = tokio::io::duplex(64);
+        let (b, mut server) = tokio::io::duplex(64);
+
+        let relay = relay_bidirectional(a, b, Timeouts::idle(Duration::from_secs(10)));
+        let peers = async {
+            // keeps the relay alive well past one timeout
+            for _ in 0..5 {
+                client.write_all(b"ping").await.unwrap();
+                let mut buf = [0u8; 4];
+                server.read_exact(&mut buf).await.unwrap();
+                tokio::time::sleep(Duration::from_secs(7)).await;
+            }
+            let started = tokio::time::Instant::now();
+            // then goes quiet, both ends should be shut down
+            let mut buf = Vec::new();
+            client.read_to_end(&mut buf).await.unwrap();
+            server.read_to_end(&mut buf).await.unwrap();
+            started.elapsed()
+        };
+
+        let (res, quiet) = tokio::join!(relay, peers);
+        assert!(res.is_err());
+        // the last ping was 7s before going quiet and the timeout is 10s
+        assert_eq!(quiet, Duration::from_secs(3));
+        assert_eq!(res.err().unwrap().to_string(), "relay idle for 10s");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_relay_downlink_only() {
+        let (mut client, a) = tokio::io::duplex(64);
+        let (b, mut server) = tokio::io::duplex(64);
+
+        let timeouts = Timeouts {
+            downlink_only: Duration::from_secs(5),
+            ..Timeouts::idle(Duration::from_secs(300))
+        };
+        let relay = relay_bidirectional(a, b, timeouts);
+        let peers = async {
+            client.write_all(b"request").await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut request = Vec::new();
+            server.read_to_end(&mut request).await.unwrap();
+
+            // the server never answers nor hangs up
+            let started = tokio::time::Instant::now();
+            let mut buf = Vec::new();
+            client.read_to_end(&mut buf).await.unwrap();
+            started.elapsed()
+        };
+
+        let (res, silent) = tokio::join!(relay, peers);
+        assert_eq!(silent, Duration::from_secs(5));
+        assert_eq!(res.err().unwrap().to_string(), "relay idle for 5s");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_relay_max_lifetime() {
+        let (mut client, a) = tokio::io::duplex(64);
+        let (b, mut server) = tokio::io::duplex(64);
+
+        let timeouts = Timeouts {
+            max_lifetime: Some(Duration::from_secs(20)),
+            ..Timeouts::idle(Duration::from_secs(10))
+        };
+        let relay = relay_bidirectional(a, b, timeouts);
+        let started = tokio::time::Instant::now();
+        let peers = async {
+            // never idle, but busy for longer than the lifetime
+            loop {
+                if client.write_all(b"ping").await.is_err() {
+                    break;
+                }
+                let mut buf = [0u8; 4];
+                if server.read_exact(&mut buf).await.is_err() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        };
+
+        let (res, ()) = tokio::join!(relay, peers);
+        assert_eq!(
+            res.err().unwrap().to_string(),
+            "relay reached its lifetime of 20s"
+        );
+        assert!(started.elapsed() <= Duration::from_secs(21));
+    }
+}
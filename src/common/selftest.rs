@@ -0,0 +1,217 @@
+// known answers for the primitives every session goes through, checked
+// before the worker serves anything. a build or platform that gets one of
+// them wrong would otherwise only show as clients failing to connect.
+
+use super::aead::Aes128Gcm;
+use super::hash;
+use super::KDFSALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_KEY;
+use crate::proxy::vmess::chunk::Security;
+
+use aes::cipher::KeyInit;
+use aes_gcm::aead::{Aead, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use once_cell::unsync::OnceCell;
+use worker::*;
+
+#[derive(Clone, Copy, Debug)]
+enum Vector {
+    // `path` after one of the crate's labels
+    Kdf {
+        key: &'static str,
+        label: &'static [u8],
+        path: &'static [&'static str],
+        output: &'static str,
+    },
+    // the kdf's innermost hmac-sha256, keyed with its salt
+    Hmac {
+        message: &'static str,
+        mac: &'static str,
+    },
+    // sealed to `ciphertext`, which opens back to `plaintext`
+    Aead {
+        security: Security,
+        key: &'static str,
+        nonce: &'static str,
+        aad: &'static str,
+        plaintext: &'static [u8],
+        ciphertext: &'static str,
+    },
+}
+
+const VECTORS: &[(&str, Vector)] = &[
+    // tests/vectors/kdf.json, from v2ray-core
+    (
+        "kdf",
+        Vector::Kdf {
+            key: "c0f5aed90ca4c24a1d5acf745d7610db",
+            label: KDFSALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_KEY,
+            path: &["000102030405060708090a0b0c0d0e0f", "0001020304050607"],
+            output: "e7d1063fca6d12b2e88e28327b7561e776eba749a8a1ea75a6156dfe48f7dcf7",
+        },
+    ),
+    // from python's hmac module
+    (
+        "hmac-sha256",
+        Vector::Hmac {
+            message: "01010101010101010101010101010101",
+            mac: "3913e716eff141fa21f7eac261e9347da24ad7f0653f0d3a3b6357ec33d44748",
+        },
+    ),
+    // test case 2 of the gcm spec
+    (
+        "aes-128-gcm",
+        Vector::Aead {
+            security: Security::Aes128Gcm,
+            key: "00000000000000000000000000000000",
+            nonce: "000000000000000000000000",
+            aad: "",
+            plaintext: &[0; 16],
+            ciphertext: "0388dace60b6a392f328c2b971b2fe78ab6e47d42cec13bdf53a67b21257bddf",
+        },
+    ),
+    // rfc 8439, section 2.8.2
+    (
+        "chacha20-poly1305",
+        Vector::Aead {
+            security: Security::ChaCha20Poly1305,
+            key: "808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f",
+            nonce: "070000004041424344454647",
+            aad: "50515253c0c1c2c3c4c5c6c7",
+            plaintext: b"Ladies and Gentlemen of the class of '99: If I could offer you only one \
+                         tip for the future, sunscreen would be it.",
+            ciphertext: concat!(
+                "d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d6",
+                "3dbea45e8ca9671282fafb69da92728b1a71de0a9e060b2905d6a5b67ecd3b36",
+                "92ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc",
+                "3ff4def08e4b7a9de576d26586cec64b6116",
+                "1ae10b594f09e26a7e902ecbd0600691",
+            ),
+        },
+    ),
+];
+
+// the error names the first vector that did not come out as expected
+pub fn self_test() -> Result<()> {
+    check(VECTORS)
+}
+
+// `self_test`, run once per isolate and remembered
+pub fn self_test_once() -> Result<()> {
+    thread_local! {
+        static RESULT: OnceCell<std::result::Result<(), String>> = const { OnceCell::new() };
+    }
+    RESULT
+        .with(|x| {
+            x.get_or_init(|| self_test().map_err(|e| e.to_string()))
+                .clone()
+        })
+        .map_err(Error::RustError)
+}
+
+fn check(vectors: &[(&str, Vector)]) -> Result<()> {
+    for (name, vector) in vectors {
+        if !passes(vector) {
+            return Err(Error::RustError(format!("crypto self-test failed: {name}")));
+        }
+    }
+    Ok(())
+}
+
+fn passes(vector: &Vector) -> bool {
+    match *vector {
+        Vector::Kdf {
+            key,
+            label,
+            path,
+            output,
+        } => {
+            let path: Vec<Vec<u8>> = path.iter().map(|x| unhex(x)).collect();
+            let path: Vec<&[u8]> = [label]
+                .into_iter()
+                .chain(path.iter().map(Vec::as_slice))
+                .collect();
+            hash::kdf(&unhex(key), &path)[..] == unhex(output)
+        }
+        Vector::Hmac { message, mac } => hash::kdf(&unhex(message), &[])[..] == unhex(mac),
+        Vector::Aead {
+            security,
+            key,
+            nonce,
+            aad,
+            plaintext,
+            ciphertext,
+        } => {
+            let (key, nonce, aad) = (unhex(key), unhex(nonce), unhex(aad));
+            let (seal, open): (AeadFn, AeadFn) = match security {
+                Security::Aes128Gcm => (
+                    |k, n, p| Aes128Gcm::new_from_slice(k).ok()?.encrypt(n.into(), p).ok(),
+                    |k, n, p| Aes128Gcm::new_from_slice(k).ok()?.decrypt(n.into(), p).ok(),
+                ),
+                Security::ChaCha20Poly1305 => (
+                    |k, n, p| {
+                        ChaCha20Poly1305::new_from_slice(k)
+                            .ok()?
+                            .encrypt(n.into(), p)
+                            .ok()
+                    },
+                    |k, n, p| {
+                        ChaCha20Poly1305::new_from_slice(k)
+                            .ok()?
+                            .decrypt(n.into(), p)
+                            .ok()
+                    },
+                ),
+                _ => return false,
+            };
+            if nonce.len() != 12 {
+                return false;
+            }
+            let payload = |msg| Payload { msg, aad: &aad };
+            let expected = unhex(ciphertext);
+            // and a flipped bit of the tag is turned down
+            let mut forged = expected.clone();
+            *forged.last_mut().unwrap() ^= 1;
+            seal(&key, &nonce, payload(plaintext)).as_ref() == Some(&expected)
+                && open(&key, &nonce, payload(&expected)).as_deref() == Some(plaintext)
+                && open(&key, &nonce, payload(&forged)).is_none()
+        }
+    }
+}
+
+type AeadFn = fn(&[u8], &[u8], Payload) -> Option<Vec<u8>>;
+
+// the vectors are ours, anything but pairs of hex digits is a typo
+fn unhex(s: &str) -> Vec<u8> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).expect("hex vector"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_self_test() {
+        self_test().unwrap();
+        self_test_once().unwrap();
+    }
+
+    #[test]
+    fn test_corrupted_vector() {
+        for i in 0..VECTORS.len() {
+            let mut vectors = VECTORS.to_vec();
+            match &mut vectors[i].1 {
+                Vector::Kdf { output, .. } => *output = &output[2..],
+                Vector::Hmac { mac, .. } => *mac = "00",
+                Vector::Aead { plaintext, .. } => *plaintext = &plaintext[1..],
+            }
+            let e = check(&vectors).unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                format!("crypto self-test failed: {}", VECTORS[i].0)
+            );
+        }
+    }
+}
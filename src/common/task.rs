@@ -0,0 +1,13 @@
+use std::future::Future;
+
+// fire and forget on the current thread: the isolate's event loop in a
+// worker, the surrounding LocalSet everywhere else (tests)
+#[cfg(target_arch = "wasm32")]
+pub fn spawn<F: Future<Output = ()> + 'static>(future: F) {
+    worker::wasm_bindgen_futures::spawn_local(future)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn spawn<F: Future<Output = ()> + 'static>(future: F) {
+    tokio::task::spawn_local(future);
+}
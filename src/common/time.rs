@@ -0,0 +1,130 @@
+use serde_json::Value;
+use std::time::Duration;
+
+// workers have no tokio timer, so sleeps go through setTimeout there and
+// through tokio everywhere else (tests)
+#[cfg(target_arch = "wasm32")]
+pub async fn sleep(duration: Duration) {
+    worker::Delay::from(duration).await
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await
+}
+
+// `future`'s output, or none once `duration` passed without it. the future
+// is dropped then, and whatever it owned with it.
+pub async fn timeout<F: std::future::Future>(duration: Duration, future: F) -> Option<F::Output> {
+    use futures_util::future::{select, Either};
+    match select(std::pin::pin!(future), std::pin::pin!(sleep(duration))).await {
+        Either::Left((x, _)) => Some(x),
+        Either::Right(_) => None,
+    }
+}
+
+// wall clock time since the unix epoch
+#[cfg(target_arch = "wasm32")]
+pub fn now() -> Duration {
+    Duration::from_millis(worker::Date::now().as_millis())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn now() -> Duration {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+// for deadlines: tokio's clock natively, which paused tests move along,
+// and the wall clock in workers, which have nothing monotonic
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(
+    #[cfg(target_arch = "wasm32")] Duration,
+    #[cfg(not(target_arch = "wasm32"))] tokio::time::Instant,
+);
+
+impl Instant {
+    #[cfg(target_arch = "wasm32")]
+    pub fn now() -> Self {
+        Self(now())
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn now() -> Self {
+        Self(tokio::time::Instant::now())
+    }
+
+    pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+        #[cfg(target_arch = "wasm32")]
+        return self.0.saturating_sub(earlier.0);
+        #[cfg(not(target_arch = "wasm32"))]
+        return self.0.saturating_duration_since(earlier.0);
+    }
+}
+
+impl std::ops::Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant(self.0 + rhs)
+    }
+}
+
+// a number of seconds, or v2ray's "500ms", "10s", "1m"
+pub fn parse_duration(value: &Value) -> Result<Duration, String> {
+    if let Some(x) = value.as_u64() {
+        return Ok(Duration::from_secs(x));
+    }
+    let s = value
+        .as_str()
+        .ok_or("expected a number of seconds or a duration")?;
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let n: u64 = s[..split]
+        .parse()
+        .map_err(|_| format!("invalid duration {s:?}"))?;
+    match &s[split..] {
+        "ms" => Ok(Duration::from_millis(n)),
+        "" | "s" => Ok(Duration::from_secs(n)),
+        "m" => Ok(Duration::from_secs(n * 60)),
+        _ => Err(format!("invalid duration {s:?}")),
+    }
+}
+
+// unix seconds, for checks that have to be tested at a fixed time
+pub trait Clock {
+    fn now(&self) -> u64;
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        now().as_secs()
+    }
+}
+
+// stands still until moved
+#[derive(Debug, Default)]
+pub struct MockClock(std::cell::Cell<u64>);
+
+impl MockClock {
+    pub fn new(now: u64) -> Self {
+        Self(std::cell::Cell::new(now))
+    }
+
+    pub fn set(&self, now: u64) {
+        self.0.set(now);
+    }
+
+    pub fn advance(&self, secs: u64) {
+        self.0.set(self.0.get() + secs);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> u64 {
+        self.0.get()
+    }
+}
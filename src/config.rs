@@ -1,4 +1,23 @@
+pub mod v2ray;
+
+use crate::app::{
+    access::AccessLogFormat, dns::Resolver, fakedns::FakeDns, geoip, geosite, metrics::MetricsConfig, policy::Policy,
+    router::Router,
+    sniff::Sniffing, DEFAULT_OUTBOUND_TAG, DNS_OUTBOUND_TAG, OUTBOUND_TAGS,
+};
+use crate::common::buf;
+use crate::common::ratelimit::RateLimit;
+use crate::outbound::block::BlockResponse;
+use crate::outbound::dialer;
+use crate::outbound::direct::{DomainStrategy, Freedom};
+use crate::proxy::fallback::Fallbacks;
+use crate::proxy::shadowsocks::ss2022::Shadowsocks2022;
+
+use serde_json::Value;
+use std::fmt;
+use std::rc::Rc;
 use uuid::Uuid;
+use worker::{Env, Url};
 
 pub struct Config {
     pub uuid: Uuid,
@@ -8,5 +27,591 @@ pub struct Config {
 
     pub main_page_url: String,
     pub link_page_url: String,
-    
+
+    // optional `ROUTING` binding, v2ray's routing object as json
+    pub router: Rc<Router>,
+    // optional `SNIFFING` binding, v2ray's inbound sniffing object
+    pub sniffing: Option<Sniffing>,
+    // optional `DNS` binding, v2ray's dns object. without it domains are
+    // never resolved for routing.
+    pub dns: Option<Rc<Resolver>>,
+    // optional `FAKEDNS` binding, v2ray's fakedns object or an array of them
+    pub fakedns: Option<Rc<FakeDns>>,
+    // optional `RATELIMIT` binding, per connection and per user bandwidth
+    pub ratelimit: Option<Rc<RateLimit>>,
+    // optional `FREEDOM` binding, settings of the direct outbound
+    pub freedom: Freedom,
+    // optional `BLACKHOLE` binding, what the block outbound tells clients
+    pub blackhole: BlockResponse,
+    // optional `SHADOWSOCKS` binding, a shadowsocks 2022 inbound next to
+    // the others, multi-user with `clients`
+    pub shadowsocks: Option<Rc<Shadowsocks2022>>,
+    // optional `FALLBACKS` binding, where connections that open as none of
+    // the protocols go instead, like xray's fallbacks
+    pub fallbacks: Fallbacks,
+    // optional `METRICS` binding, where prometheus scrapes the worker
+    pub metrics: Option<MetricsConfig>,
+    // optional `POLICY` binding, how long connections may stay idle or open
+    pub policy: Policy,
+    // optional `PROXY_PROTOCOL` binding, `true` when whatever relays into
+    // the websocket starts it with a proxy protocol v2 header
+    pub proxy_protocol: bool,
+    // optional `BUFFER_POOL` binding, how many idle 16 KiB segments an
+    // isolate keeps for new connections
+    pub buffer_pool: usize,
+    // optional `ACCESS_LOG` binding, `json` or `logfmt` for the line that
+    // closes each connection. other log lines are always logfmt.
+    pub access_log: Option<AccessLogFormat>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ConfigError {
+    // offending binding, e.g. `UUID` or `ROUTING.rules[0].ip[1]`
+    pub path: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn new(path: &str, message: impl Into<String>) -> Self {
+        Self {
+            path: path.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+// the json binding `name` parsed with `parse`, None when it is not set or
+// has errors, which go to `errors`
+fn optional_json<T, F>(
+    var: &F,
+    name: &str,
+    errors: &mut Vec<ConfigError>,
+    parse: impl FnOnce(&Value, &str) -> Result<T, Vec<ConfigError>>,
+) -> Option<T>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let value = match serde_json::from_str::<Value>(&var(name)?) {
+        Ok(x) => x,
+        Err(e) => {
+            errors.push(ConfigError::new(name, format!("invalid json: {e}")));
+            return None;
+        }
+    };
+    parse(&value, name).map_err(|e| errors.extend(e)).ok()
+}
+
+impl Config {
+    pub async fn from_env(env: &Env, host: String) -> Result<Self, Vec<ConfigError>> {
+        let mut config = Self::from_vars(host, |name| env.var(name).ok().map(|x| x.to_string()))?;
+
+        let countries = config.router.geoip_countries();
+        if !countries.is_empty() {
+            let geoip = geoip::load(env, &countries)
+                .await
+                .map_err(|e| vec![ConfigError::new("ROUTING", format!("loading geoip: {e}"))])?;
+            Rc::get_mut(&mut config.router)
+                .expect("router is not shared yet")
+                .load_geoip(&geoip)?;
+        }
+
+        if let Some(dns) = config.dns.as_mut() {
+            Rc::get_mut(dns)
+                .expect("resolver is not shared yet")
+                .share_cache();
+        }
+        if let Some(fakedns) = config.fakedns.as_mut() {
+            Rc::get_mut(fakedns)
+                .expect("fakedns is not shared yet")
+                .share_pools();
+        }
+        if let Some(ratelimit) = config.ratelimit.as_mut() {
+            Rc::get_mut(ratelimit)
+                .expect("ratelimit is not shared yet")
+                .share_buckets();
+        }
+
+        let mut lists = config.router.geosite_lists();
+        lists.extend(config.dns.iter().flat_map(|x| x.geosite_lists()));
+        lists.extend(config.fakedns.iter().flat_map(|x| x.geosite_lists()));
+        if !lists.is_empty() {
+            let geosite = geosite::load(env, &lists)
+                .await
+                .map_err(|e| vec![ConfigError::new("ROUTING", format!("loading geosite: {e}"))])?;
+            Rc::get_mut(&mut config.router)
+                .expect("router is not shared yet")
+                .load_geosite(&geosite)?;
+            if let Some(dns) = config.dns.as_mut() {
+                Rc::get_mut(dns)
+                    .expect("resolver is not shared yet")
+                    .load_geosite(&geosite)?;
+            }
+            if let Some(fakedns) = config.fakedns.as_mut() {
+                Rc::get_mut(fakedns)
+                    .expect("fakedns is not shared yet")
+                    .load_geosite(&geosite)?;
+            }
+        }
+
+        Ok(config)
+    }
+
+    // every binding is checked before giving up, so a single deploy
+    // reports all of its mistakes at once.
+    pub fn from_vars<F>(host: String, var: F) -> Result<Self, Vec<ConfigError>>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut errors = Vec::new();
+        let mut required = |name: &str| match var(name) {
+            Some(x) => Some(x),
+            None => {
+                errors.push(ConfigError::new(name, "is not set"));
+                None
+            }
+        };
+
+        let uuid = required("UUID");
+        let main_page_url = required("MAIN_PAGE_URL");
+        let link_page_url = required("LINK_PAGE_URL");
+
+        let uuid = uuid.and_then(|x| match Uuid::parse_str(x.trim()) {
+            Ok(uuid) => Some(uuid),
+            Err(e) => {
+                errors.push(ConfigError::new("UUID", format!("invalid uuid {x:?}: {e}")));
+                None
+            }
+        });
+
+        let router = optional_json(&var, "ROUTING", &mut errors, Router::from_json);
+        let sniffing = optional_json(&var, "SNIFFING", &mut errors, Sniffing::from_json);
+        let dns = optional_json(&var, "DNS", &mut errors, Resolver::from_json);
+        let fakedns = optional_json(&var, "FAKEDNS", &mut errors, FakeDns::from_json);
+        let ratelimit = optional_json(&var, "RATELIMIT", &mut errors, RateLimit::from_json);
+        let freedom = optional_json(&var, "FREEDOM", &mut errors, Freedom::from_json);
+        let blackhole = optional_json(&var, "BLACKHOLE", &mut errors, BlockResponse::from_json);
+        let shadowsocks =
+            optional_json(&var, "SHADOWSOCKS", &mut errors, Shadowsocks2022::from_json);
+        let fallbacks = optional_json(&var, "FALLBACKS", &mut errors, Fallbacks::from_json);
+        let metrics = optional_json(&var, "METRICS", &mut errors, MetricsConfig::from_json);
+        let policy = optional_json(&var, "POLICY", &mut errors, Policy::from_json);
+
+        let proxy_protocol = match var("PROXY_PROTOCOL").as_deref().map(str::trim) {
+            None | Some("false") => false,
+            Some("true") => true,
+            Some(x) => {
+                errors.push(ConfigError::new(
+                    "PROXY_PROTOCOL",
+                    format!("expected true or false, got {x:?}"),
+                ));
+                false
+            }
+        };
+
+        let buffer_pool = match var("BUFFER_POOL").as_deref().map(str::trim) {
+            None => buf::MAX_RETAINED,
+            Some(x) => x.parse().unwrap_or_else(|_| {
+                errors.push(ConfigError::new(
+                    "BUFFER_POOL",
+                    format!("expected a number of segments, got {x:?}"),
+                ));
+                buf::MAX_RETAINED
+            }),
+        };
+
+        let access_log = match var("ACCESS_LOG").as_deref().map(str::trim) {
+            None => None,
+            Some(x) => match AccessLogFormat::parse(x) {
+                Some(x) => Some(x),
+                None => {
+                    errors.push(ConfigError::new(
+                        "ACCESS_LOG",
+                        format!("expected json or logfmt, got {x:?}"),
+                    ));
+                    None
+                }
+            },
+        };
+
+        let freedom = freedom.unwrap_or_default();
+        if freedom.domain_strategy != DomainStrategy::AsIs && dns.is_none() {
+            errors.push(ConfigError::new(
+                "FREEDOM.domainStrategy",
+                "resolving needs the DNS binding",
+            ));
+        }
+
+        let (Some(uuid), Some(main_page_url), Some(link_page_url)) =
+            (uuid, main_page_url, link_page_url)
+        else {
+            return Err(errors);
+        };
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let config = Self {
+            uuid,
+            host: host.clone(),
+            proxy_addr: host,
+            proxy_port: 443,
+            main_page_url,
+            link_page_url,
+            router: Rc::new(router.unwrap_or_default()),
+            sniffing: sniffing.flatten(),
+            dns: dns.map(Rc::new),
+            fakedns: fakedns.map(Rc::new),
+            ratelimit: ratelimit.map(Rc::new),
+            freedom,
+            blackhole: blackhole.unwrap_or_default(),
+            shadowsocks: shadowsocks.map(Rc::new),
+            fallbacks: fallbacks.unwrap_or_default(),
+            metrics,
+            policy: policy.unwrap_or_default(),
+            proxy_protocol,
+            buffer_pool,
+            access_log,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+
+        if self.uuid.is_nil() {
+            errors.push(ConfigError::new("UUID", "must not be the nil uuid"));
+        }
+
+        for (path, url) in [
+            ("MAIN_PAGE_URL", &self.main_page_url),
+            ("LINK_PAGE_URL", &self.link_page_url),
+        ] {
+            match Url::parse(url) {
+                Ok(x) if matches!(x.scheme(), "http" | "https") => {}
+                Ok(x) => errors.push(ConfigError::new(
+                    path,
+                    format!("unsupported scheme {:?}, expected http or https", x.scheme()),
+                )),
+                Err(e) => errors.push(ConfigError::new(path, format!("invalid url {url:?}: {e}"))),
+            }
+        }
+
+        if self.proxy_port == 0 {
+            errors.push(ConfigError::new("proxy_port", "must be between 1 and 65535"));
+        }
+
+        // the dns outbound is only there with the `DNS` binding
+        let tags: Vec<&str> = OUTBOUND_TAGS
+            .iter()
+            .copied()
+            .filter(|x| *x != DNS_OUTBOUND_TAG || self.dns.is_some())
+            .collect();
+
+        // the `dialerProxy` of every registered outbound
+        let chains = [(DEFAULT_OUTBOUND_TAG, self.freedom.dialer_proxy.as_deref())];
+        if let Some(tag) = self.freedom.dialer_proxy.as_deref() {
+            if !tags.contains(&tag) {
+                errors.push(ConfigError::new(
+                    "FREEDOM.dialerProxy",
+                    format!("unknown outbound {tag:?}"),
+                ));
+            }
+        }
+        if let Some(cycle) = dialer::find_cycle(&chains) {
+            errors.push(ConfigError::new(
+                "FREEDOM.dialerProxy",
+                format!("dialer chain loops: {}", cycle.join(" -> ")),
+            ));
+        }
+
+        for (i, balancer) in self.router.balancers().iter().enumerate() {
+            if OUTBOUND_TAGS.contains(&balancer.tag.as_str()) {
+                errors.push(ConfigError::new(
+                    &format!("ROUTING.balancers[{i}].tag"),
+                    format!("{:?} is already an outbound", balancer.tag),
+                ));
+            }
+            if balancer.members(tags.iter().copied()).is_empty() {
+                errors.push(ConfigError::new(
+                    &format!("ROUTING.balancers[{i}].selector"),
+                    "matches no outbound",
+                ));
+            }
+        }
+
+        for (i, rule) in self.router.rules().iter().enumerate() {
+            let tag = rule.outbound_tag.as_str();
+            if rule.balancer && self.router.balancer(tag).is_none() {
+                errors.push(ConfigError::new(
+                    &format!("ROUTING.rules[{i}].balancerTag"),
+                    format!("unknown balancer {tag:?}"),
+                ));
+            } else if !rule.balancer && !tags.contains(&tag) {
+                errors.push(ConfigError::new(
+                    &format!("ROUTING.rules[{i}].outboundTag"),
+                    format!("unknown outbound {tag:?}"),
+                ));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn load(vars: &[(&str, &str)]) -> Result<Config, Vec<ConfigError>> {
+        let vars: HashMap<_, _> = vars.iter().cloned().collect();
+        Config::from_vars("example.com".to_string(), |name| {
+            vars.get(name).map(|x| x.to_string())
+        })
+    }
+
+    // a valid config with `extra` layered over it, later bindings winning
+    fn base_vars(extra: &[(&str, &str)]) -> Result<Config, Vec<ConfigError>> {
+        let mut vars = vec![
+            ("UUID", "f282b878-8711-45a1-8c69-5564172123c1"),
+            ("MAIN_PAGE_URL", "https://example.com/index.html"),
+            ("LINK_PAGE_URL", "https://example.com/link.html"),
+        ];
+        vars.extend(extra);
+        load(&vars)
+    }
+
+    #[test]
+    fn test_config_errors_are_collected() {
+        let errors = load(&[("UUID", "f282b878-8711-45a1-8c69-5564172123c")])
+            .err()
+            .unwrap();
+
+        let paths: Vec<_> = errors.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, ["MAIN_PAGE_URL", "LINK_PAGE_URL", "UUID"]);
+    }
+
+    #[test]
+    fn test_config_validate() {
+        let errors = load(&[
+            ("UUID", "00000000-0000-0000-0000-000000000000"),
+            ("MAIN_PAGE_URL", "ftp://example.com/index.html"),
+            ("LINK_PAGE_URL", "https://example.com/link.html"),
+        ])
+        .err()
+        .unwrap();
+
+        let paths: Vec<_> = errors.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, ["UUID", "MAIN_PAGE_URL"]);
+
+        assert!(base_vars(&[]).is_ok());
+    }
+
+    #[test]
+    fn test_config_routing() {
+        let vars = |routing: &str| base_vars(&[("ROUTING", routing)]);
+
+        let config = vars(r#"{"rules": [{"port": "53", "outboundTag": "direct"}]}"#).unwrap();
+        assert_eq!(config.router.rules().len(), 1);
+        assert!(vars(r#"{"rules": [{"port": "25", "outboundTag": "block"}]}"#).is_ok());
+
+        let errors = vars(r#"{"rules": [{"port": "53", "outboundTag": "nowhere"}]}"#)
+            .err()
+            .unwrap();
+        let paths: Vec<_> = errors.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, ["ROUTING.rules[0].outboundTag"]);
+
+        let errors = vars("{").err().unwrap();
+        assert_eq!(errors[0].path, "ROUTING");
+
+        let balanced = r#"{"balancers": [{"tag": "lb", "selector": ["dir"]}],
+            "rules": [{"port": "443", "balancerTag": "lb"}]}"#;
+        let config = vars(balanced).unwrap();
+        assert!(config.router.rules()[0].balancer);
+
+        let errors = vars(
+            r#"{"balancers": [{"tag": "direct", "selector": ["proxy-"]}],
+            "rules": [{"port": "443", "balancerTag": "nowhere"}]}"#,
+        )
+        .err()
+        .unwrap();
+        let paths: Vec<_> = errors.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "ROUTING.balancers[0].tag",
+                "ROUTING.balancers[0].selector",
+                "ROUTING.rules[0].balancerTag",
+            ]
+        );
+
+        let both = r#"{"rules": [{"port": "80", "balancerTag": "lb", "outboundTag": "direct"}]}"#;
+        let errors = vars(both).err().unwrap();
+        assert_eq!(errors[0].path, "ROUTING.rules[0].outboundTag");
+    }
+
+    #[test]
+    fn test_config_sniffing() {
+        let vars = |sniffing: &str| base_vars(&[("SNIFFING", sniffing)]);
+
+        let config = vars(r#"{"enabled": true, "routeOnly": true}"#).unwrap();
+        assert!(config.sniffing.unwrap().route_only);
+        assert!(vars(r#"{"enabled": false}"#).unwrap().sniffing.is_none());
+
+        let errors = vars(r#"{"enabled": true, "destOverride": ["quic"]}"#)
+            .err()
+            .unwrap();
+        assert_eq!(errors[0].path, "SNIFFING.destOverride[0]");
+    }
+
+    #[test]
+    fn test_config_dns() {
+        let vars = |dns: &str| base_vars(&[("DNS", dns)]);
+
+        let config = vars(r#"{"servers": ["https://1.1.1.1/dns-query"]}"#).unwrap();
+        assert_eq!(config.dns.unwrap().upstreams().len(), 1);
+
+        let errors = vars(r#"{"servers": ["quic://1.1.1.1"]}"#).err().unwrap();
+        assert_eq!(errors[0].path, "DNS.servers[0]");
+    }
+
+    #[test]
+    fn test_config_fakedns() {
+        let vars = |fakedns: &str| base_vars(&[("FAKEDNS", fakedns)]);
+
+        let config = vars(r#"{"ipPool": "198.18.0.0/16", "poolSize": 1000}"#).unwrap();
+        assert_eq!(config.fakedns.unwrap().pools()[0].borrow().options().size, 1000);
+
+        let errors = vars(r#"{"ipPool": "198.18.0.0"}"#).err().unwrap();
+        assert_eq!(errors[0].path, "FAKEDNS.ipPool");
+    }
+
+    #[test]
+    fn test_config_freedom() {
+        let vars = |extra: &[(&str, &str)]| {
+            let mut vars = vec![("FREEDOM", r#"{"domainStrategy": "PreferIPv4"}"#)];
+            vars.extend(extra);
+            base_vars(&vars)
+        };
+
+        let config = vars(&[("DNS", r#"{"servers": ["1.1.1.1"]}"#)]).unwrap();
+        assert_eq!(config.freedom.domain_strategy, DomainStrategy::PreferIPv4);
+
+        let errors = vars(&[]).err().unwrap();
+        assert_eq!(errors[0].path, "FREEDOM.domainStrategy");
+
+        // direct dialing through itself loops
+        let errors = base_vars(&[("FREEDOM", r#"{"dialerProxy": "direct"}"#)])
+            .err()
+            .unwrap();
+        assert_eq!(
+            errors,
+            [ConfigError::new(
+                "FREEDOM.dialerProxy",
+                "dialer chain loops: direct -> direct"
+            )]
+        );
+    }
+
+    #[test]
+    fn test_config_builtin_outbounds() {
+        let vars = |extra: &[(&str, &str)]| {
+            let mut vars = vec![
+                ("BLACKHOLE", r#"{"response": {"type": "http"}}"#),
+                (
+                    "ROUTING",
+                    r#"{"rules": [{"type": "field", "port": "53", "outboundTag": "dns"}]}"#,
+                ),
+            ];
+            vars.extend(extra);
+            base_vars(&vars)
+        };
+
+        let config = vars(&[("DNS", r#"{"servers": ["1.1.1.1"]}"#)]).unwrap();
+        assert_eq!(config.blackhole, BlockResponse::Http);
+
+        let errors = vars(&[]).err().unwrap();
+        assert_eq!(
+            errors,
+            [ConfigError::new("ROUTING.rules[0].outboundTag", "unknown outbound \"dns\"")]
+        );
+
+        let errors = vars(&[("BLACKHOLE", r#"{"response": "http"}"#)]).err().unwrap();
+        assert_eq!(errors[0].path, "BLACKHOLE.response.type");
+    }
+
+    #[test]
+    fn test_config_metrics() {
+        let vars = |metrics: &str| base_vars(&[("METRICS", metrics)]);
+
+        let config = vars(r#"{"users": true}"#).unwrap();
+        assert_eq!(config.metrics.unwrap().path, "/metrics");
+
+        let errors = vars(r#"{"listen": "127.0.0.1:9100"}"#).err().unwrap();
+        assert_eq!(errors[0].path, "METRICS.listen");
+    }
+
+    #[test]
+    fn test_config_policy() {
+        let vars = |policy: &str| base_vars(&[("POLICY", policy)]);
+
+        let config = vars(r#"{"connIdle": 120, "maxLifetime": 86400}"#).unwrap();
+        assert_eq!(config.policy.conn_idle, std::time::Duration::from_secs(120));
+        assert_eq!(
+            config.policy.max_lifetime,
+            Some(std::time::Duration::from_secs(86400))
+        );
+
+        let errors = vars(r#"{"udpIdle": -1}"#).err().unwrap();
+        assert_eq!(errors[0].path, "POLICY.udpIdle");
+    }
+
+    #[test]
+    fn test_config_proxy_protocol() {
+        let vars = |value: &str| base_vars(&[("PROXY_PROTOCOL", value)]);
+
+        assert!(vars("true").unwrap().proxy_protocol);
+        let errors = vars("v2").err().unwrap();
+        assert_eq!(errors[0].path, "PROXY_PROTOCOL");
+    }
+
+    #[test]
+    fn test_config_buffer_pool() {
+        let vars = |value: &str| base_vars(&[("BUFFER_POOL", value)]);
+
+        assert_eq!(vars(" 256 ").unwrap().buffer_pool, 256);
+        assert_eq!(vars("0").unwrap().buffer_pool, 0);
+        let errors = vars("1MiB").err().unwrap();
+        assert_eq!(errors[0].path, "BUFFER_POOL");
+    }
+
+    #[test]
+    fn test_config_fallbacks() {
+        let vars = |value: &str| base_vars(&[("FALLBACKS", value)]);
+
+        assert!(!vars(r#"[{"dest": "nginx.example.com:443"}]"#).unwrap().fallbacks.is_empty());
+        let errors = vars(r#"[{"dest": 80}]"#).err().unwrap();
+        assert_eq!(errors[0].path, "FALLBACKS[0].dest");
+    }
+
+    #[test]
+    fn test_config_access_log() {
+        let vars = |value: &str| base_vars(&[("ACCESS_LOG", value)]);
+
+        let config = vars("json").unwrap();
+        assert_eq!(config.access_log, Some(AccessLogFormat::Json));
+        let errors = vars("yaml").err().unwrap();
+        assert_eq!(errors[0].path, "ACCESS_LOG");
+    }
 }
@@ -0,0 +1,607 @@
+use super::ConfigError;
+use crate::app::router::Router;
+use crate::outbound::block::{BlockOutbound, BlockResponse};
+use crate::outbound::chain::{ChainDialer, ChainOutbound};
+use crate::outbound::dialer::Dialer;
+use crate::outbound::direct::DirectOutbound;
+use crate::outbound::{Network, Outbound, Target, UotOutbound, UotVersion};
+use crate::proxy::vmess::chunk::Security;
+use crate::proxy::vmess::client::VmessConnector;
+use crate::proxy::vmess::link::parse_security;
+use crate::proxy::vmess::users::UserTable;
+use crate::server::memory::MemoryDialer;
+use crate::server::ServerBuilder;
+
+use serde_json::{Map, Value};
+use std::rc::Rc;
+use uuid::Uuid;
+
+// rule conditions v2ray has and the router does not. a rule is dropped as
+// a whole rather than matching more than it was written to.
+const UNSUPPORTED_RULE_FIELDS: &[&str] = &["protocol", "user", "attrs", "sourcePort"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VmessClient {
+    pub id: Uuid,
+    pub email: String,
+    pub level: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum OutboundKind {
+    Freedom,
+    Blackhole(BlockResponse),
+    // the first user of the first server in `vnext`
+    Vmess {
+        server: Target,
+        id: Uuid,
+        security: Security,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutboundConfig {
+    pub tag: String,
+    pub kind: OutboundKind,
+    // `udpOverTcp`, for outbounds that only carry tcp
+    pub udp_over_tcp: Option<UotVersion>,
+    // vmess `streamSettings` on the memory network, which reach the server
+    // at that listener
+    pub memory: Option<MemoryDialer>,
+}
+
+// an upstream v2ray config.json, as far as this crate has counterparts for
+// it: the clients of its vmess inbounds, its freedom, blackhole and vmess
+// outbounds and the routing. whatever is left behind on the way is one of
+// the warnings, only what can not be read at all is an error.
+pub struct V2rayConfig {
+    pub clients: Vec<VmessClient>,
+    pub outbounds: Vec<OutboundConfig>,
+    pub router: Router,
+    pub warnings: Vec<ConfigError>,
+}
+
+impl V2rayConfig {
+    pub fn from_json(value: &Value, path: &str) -> Result<Self, Vec<ConfigError>> {
+        let Some(object) = value.as_object() else {
+            return Err(vec![ConfigError::new(path, "expected an object")]);
+        };
+
+        let mut errors = Vec::new();
+        let mut config = Self {
+            clients: Vec::new(),
+            outbounds: Vec::new(),
+            router: Router::default(),
+            warnings: Vec::new(),
+        };
+        for (key, value) in object {
+            let path = format!("{path}.{key}");
+            match key.as_str() {
+                "inbounds" => {
+                    for (i, x) in array(value, &path, &mut errors).iter().enumerate() {
+                        config.inbound(x, &format!("{path}[{i}]"), &mut errors);
+                    }
+                }
+                "outbounds" => {
+                    for (i, x) in array(value, &path, &mut errors).iter().enumerate() {
+                        config.outbound(x, &format!("{path}[{i}]"), &mut errors);
+                    }
+                }
+                "routing" => {
+                    let routing = config.supported_routing(value, &path);
+                    match Router::from_json(&routing, &path) {
+                        Ok(x) => config.router = x,
+                        Err(e) => errors.extend(e),
+                    }
+                }
+                _ => config
+                    .warnings
+                    .push(ConfigError::new(&path, "not imported")),
+            }
+        }
+
+        let mut seen = Vec::new();
+        for (i, client) in config.clients.iter().enumerate() {
+            if seen
+                .iter()
+                .any(|x: &&VmessClient| x.id == client.id || x.email == client.email)
+            {
+                errors.push(ConfigError::new(
+                    &format!("{path}.inbounds"),
+                    format!("client {} is there more than once", client.email),
+                ));
+            }
+            seen.push(&config.clients[i]);
+        }
+
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn inbound(&mut self, value: &Value, path: &str, errors: &mut Vec<ConfigError>) {
+        let Some(object) = value.as_object() else {
+            return errors.push(ConfigError::new(path, "expected an object"));
+        };
+        let protocol = object
+            .get("protocol")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        if protocol != "vmess" {
+            let message = format!("{protocol:?} inbounds are not imported");
+            return self.warnings.push(ConfigError::new(path, message));
+        }
+        for (key, value) in object {
+            let path = format!("{path}.{key}");
+            match key.as_str() {
+                "protocol" | "tag" => {}
+                "settings" => self.vmess_clients(value, &path, errors),
+                // the worker takes its connections over the websocket of
+                // its route, on whatever port cloudflare has
+                _ => self.warnings.push(ConfigError::new(&path, "not imported")),
+            }
+        }
+    }
+
+    fn vmess_clients(&mut self, value: &Value, path: &str, errors: &mut Vec<ConfigError>) {
+        let clients = value.get("clients").unwrap_or(&Value::Null);
+        let path = format!("{path}.clients");
+        for (i, client) in array(clients, &path, errors).iter().enumerate() {
+            let path = format!("{path}[{i}]");
+            let Some(object) = client.as_object() else {
+                errors.push(ConfigError::new(&path, "expected an object"));
+                continue;
+            };
+            let (mut id, mut email, mut level) = (None, None, 0);
+            for (key, value) in object {
+                let path = format!("{path}.{key}");
+                match key.as_str() {
+                    "id" => match value.as_str().map(Uuid::parse_str) {
+                        Some(Ok(x)) => id = Some(x),
+                        _ => errors.push(ConfigError::new(&path, "expected a uuid")),
+                    },
+                    "email" => match value.as_str() {
+                        Some(x) => email = Some(x.to_string()),
+                        None => errors.push(ConfigError::new(&path, "expected a string")),
+                    },
+                    "level" => match value.as_u64().and_then(|x| u32::try_from(x).ok()) {
+                        Some(x) => level = x,
+                        None => errors.push(ConfigError::new(&path, "expected a number")),
+                    },
+                    "alterId" if value.as_u64() == Some(0) => {}
+                    "alterId" => self.warnings.push(ConfigError::new(
+                        &path,
+                        "legacy md5 auth is refused, the client needs to use aead",
+                    )),
+                    _ => self.warnings.push(ConfigError::new(&path, "not imported")),
+                }
+            }
+            match id {
+                // users are counted by email, the uuid stands in without one
+                Some(id) => self.clients.push(VmessClient {
+                    id,
+                    email: email.unwrap_or_else(|| id.to_string()),
+                    level,
+                }),
+                None if !object.contains_key("id") => {
+                    errors.push(ConfigError::new(&format!("{path}.id"), "missing"))
+                }
+                None => {}
+            }
+        }
+    }
+
+    fn outbound(&mut self, value: &Value, path: &str, errors: &mut Vec<ConfigError>) {
+        let Some(object) = value.as_object() else {
+            return errors.push(ConfigError::new(path, "expected an object"));
+        };
+        let Some(tag) = object.get("tag").and_then(Value::as_str) else {
+            return errors.push(ConfigError::new(&format!("{path}.tag"), "missing"));
+        };
+        let settings = object
+            .get("settings")
+            .cloned()
+            .unwrap_or(Value::Object(Map::new()));
+        let settings_path = format!("{path}.settings");
+        let kind = match object
+            .get("protocol")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+        {
+            "freedom" => {
+                if settings.as_object().is_some_and(|x| !x.is_empty()) {
+                    let message = "not imported, see the FREEDOM binding";
+                    self.warnings
+                        .push(ConfigError::new(&settings_path, message));
+                }
+                OutboundKind::Freedom
+            }
+            "blackhole" => match BlockResponse::from_json(&settings, &settings_path) {
+                Ok(x) => OutboundKind::Blackhole(x),
+                Err(e) => return errors.extend(e),
+            },
+            "vmess" => match self.vmess_server(&settings, &settings_path, errors) {
+                Some(x) => x,
+                None => return,
+            },
+            protocol => {
+                let message = format!("{protocol:?} outbounds are not imported");
+                return self.warnings.push(ConfigError::new(path, message));
+            }
+        };
+        let udp_over_tcp = match object.get("udpOverTcp") {
+            Some(x) => match UotVersion::from_json(x, &format!("{path}.udpOverTcp")) {
+                Ok(x) => x,
+                Err(e) => return errors.extend(e),
+            },
+            None => None,
+        };
+        let stream_settings = match (&kind, object.get("streamSettings")) {
+            (OutboundKind::Vmess { .. }, Some(x)) => {
+                match MemoryDialer::from_json(x, &format!("{path}.streamSettings")) {
+                    Ok(x) => x,
+                    Err(e) => return errors.extend(e),
+                }
+            }
+            _ => None,
+        };
+        for key in object.keys() {
+            let imported = match key.as_str() {
+                "protocol" | "tag" | "settings" | "udpOverTcp" => true,
+                "streamSettings" => stream_settings.is_some(),
+                _ => false,
+            };
+            if !imported {
+                let path = format!("{path}.{key}");
+                self.warnings.push(ConfigError::new(&path, "not imported"));
+            }
+        }
+        self.outbounds.push(OutboundConfig {
+            tag: tag.to_string(),
+            kind,
+            udp_over_tcp,
+            memory: stream_settings,
+        });
+    }
+
+    fn vmess_server(
+        &mut self,
+        settings: &Value,
+        path: &str,
+        errors: &mut Vec<ConfigError>,
+    ) -> Option<OutboundKind> {
+        let vnext = settings.get("vnext").unwrap_or(&Value::Null);
+        let path = format!("{path}.vnext");
+        let servers = array(vnext, &path, errors);
+        let Some(server) = servers.first() else {
+            errors.push(ConfigError::new(&path, "expected at least one server"));
+            return None;
+        };
+        if servers.len() > 1 {
+            self.warnings
+                .push(ConfigError::new(&path, "only the first server is used"));
+        }
+
+        let path = format!("{path}[0]");
+        let address = server.get("address").and_then(Value::as_str);
+        let port = server.get("port").and_then(Value::as_u64);
+        let port = port.and_then(|x| u16::try_from(x).ok()).filter(|x| *x != 0);
+        let users = server.get("users").and_then(Value::as_array);
+        let user = users.and_then(|x| x.first());
+        let id = user.and_then(|x| x.get("id")?.as_str()?.parse::<Uuid>().ok());
+        let security = user
+            .and_then(|x| x.get("security")?.as_str())
+            .map(parse_security)
+            .unwrap_or(Ok(Security::Aes128Gcm));
+        if users.is_some_and(|x| x.len() > 1) {
+            let message = "only the first user is used";
+            self.warnings
+                .push(ConfigError::new(&format!("{path}.users"), message));
+        }
+
+        let mut failed = false;
+        let mut fail = |field: &str, message: String| {
+            errors.push(ConfigError::new(&format!("{path}.{field}"), message));
+            failed = true;
+        };
+        if address.is_none_or(str::is_empty) {
+            fail("address", "expected a host".to_string());
+        }
+        if port.is_none() {
+            fail("port", "must be between 1 and 65535".to_string());
+        }
+        if id.is_none() {
+            fail("users[0].id", "expected a uuid".to_string());
+        }
+        if let Err(e) = &security {
+            fail("users[0].security", e.clone());
+        }
+        if failed {
+            return None;
+        }
+        Some(OutboundKind::Vmess {
+            server: Target::new(address?.to_string(), port?, Network::Tcp),
+            id: id?,
+            security: security.ok()?,
+        })
+    }
+
+    // the routing object without what the router would not take
+    fn supported_routing(&mut self, value: &Value, path: &str) -> Value {
+        let Some(object) = value.as_object() else {
+            return value.clone();
+        };
+        let mut routing = object.clone();
+        if routing.remove("domainMatcher").is_some() {
+            let path = format!("{path}.domainMatcher");
+            self.warnings.push(ConfigError::new(&path, "not imported"));
+        }
+        if let Some(Value::Array(rules)) = routing.get_mut("rules") {
+            let mut i = 0;
+            rules.retain(|rule| {
+                let path = format!("{path}.rules[{i}]");
+                i += 1;
+                let unsupported = UNSUPPORTED_RULE_FIELDS
+                    .iter()
+                    .find(|x| rule.get(**x).is_some());
+                match unsupported {
+                    Some(field) => {
+                        let message = format!("dropped, {field} is not supported");
+                        self.warnings.push(ConfigError::new(&path, message));
+                        false
+                    }
+                    None => true,
+                }
+            });
+        }
+        Value::Object(routing)
+    }
+
+    // the first client is the table's own uuid, the others are added users
+    pub fn users(&self) -> Option<UserTable> {
+        let (first, rest) = self.clients.split_first()?;
+        let users = UserTable::new(first.id);
+        for client in rest {
+            // from_json has turned down duplicates
+            users
+                .add_user(client.id, &client.email, client.level)
+                .ok()?;
+        }
+        Some(users)
+    }
+
+    // the outbounds in order, the first being the default, and the routing
+    pub fn into_builder(self) -> ServerBuilder {
+        let mut builder = ServerBuilder::default().router(self.router);
+        for OutboundConfig {
+            tag,
+            kind,
+            udp_over_tcp,
+            memory,
+        } in self.outbounds
+        {
+            builder = match kind {
+                OutboundKind::Freedom => {
+                    add(builder, &tag, DirectOutbound::new(None), udp_over_tcp)
+                }
+                OutboundKind::Blackhole(response) => {
+                    let block = BlockOutbound::default().with_response(response);
+                    add(builder, &tag, block, udp_over_tcp)
+                }
+                OutboundKind::Vmess {
+                    server,
+                    id,
+                    security,
+                } => {
+                    let mut connector = VmessConnector::new(server, id, security);
+                    let mut chain = ChainDialer::new(Vec::new());
+                    if let Some(memory) = memory {
+                        let dialer: Rc<dyn Dialer> = Rc::new(memory);
+                        connector = connector.with_dialer(dialer.clone());
+                        chain = chain.with_dialer(dialer);
+                    }
+                    let outbound = ChainOutbound::new(connector, chain);
+                    add(builder, &tag, outbound, udp_over_tcp)
+                }
+            };
+        }
+        builder
+    }
+}
+
+// only the udp the outbound can not carry itself goes over tcp
+fn add(
+    builder: ServerBuilder,
+    tag: &str,
+    outbound: impl Outbound + 'static,
+    udp_over_tcp: Option<UotVersion>,
+) -> ServerBuilder {
+    match udp_over_tcp {
+        Some(version) => builder.add_outbound(tag, UotOutbound::new(Box::new(outbound), version)),
+        None => builder.add_outbound(tag, outbound),
+    }
+}
+
+fn array<'a>(value: &'a Value, path: &str, errors: &mut Vec<ConfigError>) -> &'a [Value] {
+    match value.as_array() {
+        Some(x) => x,
+        None => {
+            errors.push(ConfigError::new(path, "expected an array"));
+            &[]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // what `v2ray uuid` and the docs' examples put together look like
+    fn fixture() -> Value {
+        json!({
+            "log": {"loglevel": "warning"},
+            "inbounds": [
+                {
+                    "port": 10086,
+                    "protocol": "vmess",
+                    "settings": {
+                        "clients": [
+                            {"id": "f282b878-8711-45a1-8c69-5564172123c1", "alterId": 0},
+                            {
+                                "id": "b831381d-6324-4d53-ad4f-8cda48b30811",
+                                "email": "love@v2fly.org",
+                                "level": 1,
+                                "alterId": 64
+                            }
+                        ]
+                    },
+                    "streamSettings": {"network": "ws", "wsSettings": {"path": "/ray"}}
+                },
+                {"port": 1080, "protocol": "socks", "settings": {"udp": true}}
+            ],
+            "outbounds": [
+                {"protocol": "freedom", "tag": "direct", "settings": {}},
+                {
+                    "protocol": "vmess",
+                    "tag": "proxy",
+                    "settings": {
+                        "vnext": [{
+                            "address": "vmess.example.com",
+                            "port": 443,
+                            "users": [{
+                                "id": "27848739-7e62-4138-9fd3-098a63964b6b",
+                                "security": "chacha20-poly1305"
+                            }]
+                        }]
+                    },
+                    "mux": {"enabled": false},
+                    "udpOverTcp": {"version": 1},
+                    "streamSettings": {"network": "memory", "name": "vmess"}
+                },
+                {"protocol": "blackhole", "tag": "block", "settings": {"response": {"type": "http"}}},
+                {"protocol": "socks", "tag": "tor", "settings": {}}
+            ],
+            "routing": {
+                "domainStrategy": "AsIs",
+                "rules": [
+                    {"type": "field", "ip": ["geoip:private"], "outboundTag": "block"},
+                    {"type": "field", "domain": ["domain:example.org"], "outboundTag": "proxy"},
+                    {"type": "field", "protocol": ["bittorrent"], "outboundTag": "block"}
+                ]
+            }
+        })
+    }
+
+    #[test]
+    fn test_import() {
+        let config = V2rayConfig::from_json(&fixture(), "config").unwrap();
+        let ids: Vec<_> = config
+            .clients
+            .iter()
+            .map(|x| (x.id.to_string(), &x.email, x.level))
+            .collect();
+        assert_eq!(
+            ids,
+            [
+                (
+                    "f282b878-8711-45a1-8c69-5564172123c1".to_string(),
+                    &"f282b878-8711-45a1-8c69-5564172123c1".to_string(),
+                    0
+                ),
+                (
+                    "b831381d-6324-4d53-ad4f-8cda48b30811".to_string(),
+                    &"love@v2fly.org".to_string(),
+                    1
+                ),
+            ]
+        );
+        let users = config.users().unwrap();
+        assert_eq!(users.uuid(), config.clients[0].id);
+        assert_eq!(users.list_users()[0].email, "love@v2fly.org");
+
+        let tags: Vec<_> = config.outbounds.iter().map(|x| x.tag.as_str()).collect();
+        assert_eq!(tags, ["direct", "proxy", "block"]);
+        assert_eq!(
+            config.outbounds[1].kind,
+            OutboundKind::Vmess {
+                server: Target::new("vmess.example.com".to_string(), 443, Network::Tcp),
+                id: "27848739-7e62-4138-9fd3-098a63964b6b".parse().unwrap(),
+                security: Security::ChaCha20Poly1305,
+            }
+        );
+        assert_eq!(config.outbounds[1].udp_over_tcp, Some(UotVersion::V1));
+        assert_eq!(config.outbounds[1].memory, Some(MemoryDialer::new("vmess")));
+        assert_eq!(
+            config.outbounds[2].kind,
+            OutboundKind::Blackhole(BlockResponse::Http)
+        );
+
+        // the bittorrent rule goes, the other two stay in order
+        let rules = config.router.rules();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[1].outbound_tag, "proxy");
+        assert!(!rules[1].has_ip_condition());
+
+        let warnings: Vec<_> = config.warnings.iter().map(|x| x.to_string()).collect();
+        assert_eq!(
+            warnings,
+            [
+                "config.inbounds[0].port: not imported",
+                "config.inbounds[0].settings.clients[1].alterId: legacy md5 auth is refused, \
+                 the client needs to use aead",
+                "config.inbounds[0].streamSettings: not imported",
+                "config.inbounds[1]: \"socks\" inbounds are not imported",
+                "config.log: not imported",
+                "config.outbounds[1].mux: not imported",
+                "config.outbounds[3]: \"socks\" outbounds are not imported",
+                "config.routing.rules[2]: dropped, protocol is not supported",
+            ]
+        );
+
+        // routing names an outbound that is there, the others are dropped
+        let config = V2rayConfig::from_json(&fixture(), "config").unwrap();
+        assert!(config.into_builder().build().is_ok());
+    }
+
+    #[test]
+    fn test_import_errors() {
+        let errors = V2rayConfig::from_json(
+            &json!({
+                "inbounds": [{"protocol": "vmess", "settings": {"clients": [{"id": "nope"}, {}]}}],
+                "outbounds": [
+                    {"protocol": "vmess", "settings": {}},
+                    {"protocol": "vmess", "tag": "a", "settings": {"vnext": [{"port": 0}]}}
+                ],
+            }),
+            "config",
+        )
+        .err()
+        .unwrap();
+        let errors: Vec<_> = errors.iter().map(|x| x.to_string()).collect();
+        assert_eq!(
+            errors,
+            [
+                "config.inbounds[0].settings.clients[0].id: expected a uuid",
+                "config.inbounds[0].settings.clients[1].id: missing",
+                "config.outbounds[0].tag: missing",
+                "config.outbounds[1].settings.vnext[0].address: expected a host",
+                "config.outbounds[1].settings.vnext[0].port: must be between 1 and 65535",
+                "config.outbounds[1].settings.vnext[0].users[0].id: expected a uuid",
+            ]
+        );
+
+        let client = json!({"id": "f282b878-8711-45a1-8c69-5564172123c1", "email": "a"});
+        let inbound = json!({"protocol": "vmess", "settings": {"clients": [client, client]}});
+        let errors = V2rayConfig::from_json(&json!({"inbounds": [inbound]}), "config");
+        assert_eq!(
+            errors.err().unwrap(),
+            [ConfigError::new(
+                "config.inbounds",
+                "client a is there more than once"
+            )]
+        );
+    }
+}
@@ -0,0 +1,147 @@
+// c bindings for clients that only want the vmess crypto, declared in
+// include/siren.h. every function returns one of the SW2_* codes, catches
+// panics and writes nothing beyond the lengths it is given.
+
+use crate::common::hash;
+use crate::common::time::Clock;
+use crate::proxy::vmess::{seal_vmess_header, users};
+
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+use uuid::Uuid;
+
+pub const SW2_OK: i32 = 0;
+pub const SW2_NULL_POINTER: i32 = -1;
+pub const SW2_INVALID_INPUT: i32 = -2;
+pub const SW2_PANIC: i32 = -3;
+
+// bytes allocated here, handed back to `sw2_free` once read
+#[repr(C)]
+pub struct Sw2Buffer {
+    pub ptr: *mut u8,
+    pub len: usize,
+}
+
+struct At(u64);
+
+impl Clock for At {
+    fn now(&self) -> u64 {
+        self.0
+    }
+}
+
+fn guard(f: impl FnOnce() -> i32) -> i32 {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(SW2_PANIC)
+}
+
+// an empty slice for a null pointer of no length, which c callers pass for
+// empty input
+unsafe fn input<'a>(ptr: *const u8, len: usize) -> Option<&'a [u8]> {
+    match (ptr.is_null(), len) {
+        (true, 0) => Some(&[]),
+        (true, _) => None,
+        (false, _) => Some(slice::from_raw_parts(ptr, len)),
+    }
+}
+
+/// Writes the cmd key of a 16 byte uuid to `out`.
+///
+/// # Safety
+/// `uuid` must be readable and `out` writable for 16 bytes.
+#[no_mangle]
+pub unsafe extern "C" fn sw2_cmd_key(uuid: *const u8, out: *mut u8) -> i32 {
+    if uuid.is_null() || out.is_null() {
+        return SW2_NULL_POINTER;
+    }
+    guard(|| {
+        let mut bytes = [0u8; 16];
+        ptr::copy_nonoverlapping(uuid, bytes.as_mut_ptr(), 16);
+        let key = users::cmd_key(&Uuid::from_bytes(bytes));
+        ptr::copy_nonoverlapping(key.as_ptr(), out, 16);
+        SW2_OK
+    })
+}
+
+/// Writes the vmess kdf of `key` over `n` labels to `out`.
+///
+/// # Safety
+/// `key` must be readable for `key_len` bytes, `labels` and `labels_lens`
+/// for `n` entries with each label readable for its length, and `out`
+/// writable for 32 bytes.
+#[no_mangle]
+pub unsafe extern "C" fn sw2_kdf(
+    key: *const u8,
+    key_len: usize,
+    labels: *const *const u8,
+    labels_lens: *const usize,
+    n: usize,
+    out: *mut u8,
+) -> i32 {
+    if out.is_null() || (n > 0 && (labels.is_null() || labels_lens.is_null())) {
+        return SW2_NULL_POINTER;
+    }
+    let Some(key) = input(key, key_len) else {
+        return SW2_NULL_POINTER;
+    };
+    guard(|| {
+        let mut path = Vec::with_capacity(n);
+        for i in 0..n {
+            let Some(label) = input(*labels.add(i), *labels_lens.add(i)) else {
+                return SW2_NULL_POINTER;
+            };
+            path.push(label);
+        }
+        let derived = hash::kdf(key, &path);
+        ptr::copy_nonoverlapping(derived.as_ptr(), out, 32);
+        SW2_OK
+    })
+}
+
+/// Seals the command section `cmd` for `cmd_key` at `now`, in unix seconds,
+/// into a buffer that `sw2_free` releases.
+///
+/// # Safety
+/// `cmd_key` must be readable for 16 bytes, `cmd` for `cmd_len` and `out`
+/// writable for one `Sw2Buffer`.
+#[no_mangle]
+pub unsafe extern "C" fn sw2_encode_request_header(
+    cmd_key: *const u8,
+    cmd: *const u8,
+    cmd_len: usize,
+    now: u64,
+    out: *mut Sw2Buffer,
+) -> i32 {
+    if cmd_key.is_null() || out.is_null() {
+        return SW2_NULL_POINTER;
+    }
+    let Some(cmd) = input(cmd, cmd_len) else {
+        return SW2_NULL_POINTER;
+    };
+    guard(|| {
+        let mut key = [0u8; 16];
+        ptr::copy_nonoverlapping(cmd_key, key.as_mut_ptr(), 16);
+        let Ok(header) = seal_vmess_header(&key, cmd, &At(now)) else {
+            return SW2_INVALID_INPUT;
+        };
+        let header = Box::into_raw(header.into_boxed_slice());
+        out.write(Sw2Buffer {
+            ptr: header.cast(),
+            len: header.len(),
+        });
+        SW2_OK
+    })
+}
+
+/// Releases a buffer filled in by this library. A null one is ignored.
+///
+/// # Safety
+/// `buffer` must come from this library and not have been freed before.
+#[no_mangle]
+pub unsafe extern "C" fn sw2_free(buffer: Sw2Buffer) {
+    if !buffer.ptr.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+            buffer.ptr, buffer.len,
+        )));
+    }
+}
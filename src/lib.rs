@@ -1,14 +1,24 @@
-mod common;
-mod config;
-mod proxy;
+pub mod app;
+pub mod common;
+pub mod config;
+#[cfg(feature = "capi")]
+pub mod ffi;
+pub mod outbound;
+pub mod proxy;
+pub mod server;
+#[cfg(any(test, feature = "testutil"))]
+pub mod testutil;
+#[cfg(feature = "wasm")]
+pub mod wasm;
 
+use crate::app::metrics::MetricsConfig;
 use crate::config::Config;
 use crate::proxy::*;
 
 use std::collections::HashMap;
+use std::rc::Rc;
 use base64::{engine::general_purpose::URL_SAFE, Engine as _};
 use serde_json::json;
-use uuid::Uuid;
 use worker::*;
 use once_cell::sync::Lazy;
 use regex::Regex;
@@ -18,13 +28,25 @@ static PROXYKV_PATTERN: Lazy<Regex> = Lazy::new(|| Regex::new(r"^([A-Z]{2})").un
 
 #[event(fetch)]
 async fn main(req: Request, env: Env, _: Context) -> Result<Response> {
-    let uuid = env
-        .var("UUID")
-        .map(|x| Uuid::parse_str(&x.to_string()).unwrap_or_default())?;
+    if let Err(e) = common::selftest::self_test_once() {
+        console_error!("[selftest]: {}", e);
+        return Response::error("crypto self-test failed", 500);
+    }
     let host = req.url()?.host().map(|x| x.to_string()).unwrap_or_default();
-    let main_page_url = env.var("MAIN_PAGE_URL").map(|x|x.to_string()).unwrap();
-    let link_page_url = env.var("LINK_PAGE_URL").map(|x|x.to_string()).unwrap();
-    let config = Config { uuid, host: host.clone(), proxy_addr: host, proxy_port: 443, main_page_url, link_page_url };
+    let config = match Config::from_env(&env, host).await {
+        Ok(config) => config,
+        Err(errors) => {
+            for e in &errors {
+                console_error!("[config]: {}", e);
+            }
+            return Response::error("invalid configuration", 500);
+        }
+    };
+    app::access::install(config.access_log);
+    common::buf::set_max_retained(config.buffer_pool);
+    if let Some(metrics) = config.metrics.as_ref().filter(|x| x.path == req.path()) {
+        return metrics_page(&req, &config, metrics);
+    }
 
     Router::with_data(config)
         .on_async("/", fe)
@@ -36,6 +58,20 @@ async fn main(req: Request, env: Env, _: Context) -> Result<Response> {
         .await
 }
 
+fn metrics_page(req: &Request, config: &Config, metrics: &MetricsConfig) -> Result<Response> {
+    let authorization = req.headers().get("Authorization")?;
+    if !metrics.authorized(authorization.as_deref()) {
+        return Response::error("unauthorized", 401);
+    }
+
+    let dns = config.dns.as_ref().and_then(|x| x.cache()).map(|x| x.stats());
+    let mut body = app::metrics::shared().render(&app::stats::shared(), dns, metrics.users);
+    body.push_str(&app::metrics::render_policy(&config.policy));
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "text/plain; version=0.0.4")?;
+    Ok(Response::ok(body)?.with_headers(headers))
+}
+
 async fn get_response_from_url(url: String) -> Result<Response> {
     let req = Fetch::Url(Url::parse(url.as_str())?);
     let mut res = req.send().await?;
@@ -60,7 +96,7 @@ async fn tunnel(req: Request, mut cx: RouteContext<Config>) -> Result<Response>
         let mut rand_buf = [0u8, 1];
         getrandom::getrandom(&mut rand_buf).expect("failed generating random number");
         
-        if proxy_kv_str.len() == 0 {
+        if proxy_kv_str.is_empty() {
             console_log!("getting proxy kv from github...");
             let req = Fetch::Url(Url::parse("https://raw.githubusercontent.com/FoolVPN-ID/Nautica/refs/heads/main/kvProxyList.json")?);
             let mut res = req.send().await?;
@@ -84,7 +120,7 @@ async fn tunnel(req: Request, mut cx: RouteContext<Config>) -> Result<Response>
     }
 
     let upgrade = req.headers().get("Upgrade")?.unwrap_or_default();
-    if upgrade == "websocket".to_string() && PROXYIP_PATTERN.is_match(&proxyip) {
+    if upgrade == "websocket" && PROXYIP_PATTERN.is_match(&proxyip) {
         if let Some((addr, port_str)) = proxyip.split_once('-') {
             if let Ok(port) = port_str.parse() {
                 cx.data.proxy_addr = addr.to_string();
@@ -92,12 +128,21 @@ async fn tunnel(req: Request, mut cx: RouteContext<Config>) -> Result<Response>
             }
         }
         
+        // usage of the vmess users is kept in kv across isolates
+        proxy::vmess::users::shared_with_store(&cx.data.uuid, || {
+            let kv = cx.kv("SIREN").ok()?;
+            Some(Rc::new(proxy::vmess::users::KvUsageStore::new(kv)) as _)
+        });
+        let source = req.headers().get("CF-Connecting-IP")?;
+        let alpn = req.cf().map(|x| x.http_protocol());
         let WebSocketPair { server, client } = WebSocketPair::new()?;
         server.accept()?;
     
         wasm_bindgen_futures::spawn_local(async move {
             let events = server.events().unwrap();
-            if let Err(e) = ProxyStream::new(cx.data, &server, events).process().await {
+            let mut stream = ProxyStream::new(cx.data, source, &server, events);
+            stream.alpn = alpn;
+            if let Err(e) = stream.process().await {
                 console_error!("[tunnel]: {}", e);
             }
         });
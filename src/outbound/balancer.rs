@@ -0,0 +1,742 @@
+use super::Target;
+use crate::common::time::{parse_duration, Instant};
+use crate::config::ConfigError;
+
+use serde_json::Value;
+use std::cell::{Cell, RefCell, RefMut};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+use std::time::Duration;
+use worker::Url;
+
+// consecutive failed probes before an outbound is skipped
+const MAX_FAILURES: u32 = 3;
+
+// v2ray's observatory defaults, except that probes go out in plain http:
+// the probe is written straight into the outbound stream and there is no
+// tls client to wrap it in
+pub const DEFAULT_PROBE_URL: &str = "http://www.google.com/generate_204";
+pub const DEFAULT_PROBE_INTERVAL: Duration = Duration::from_secs(60);
+pub const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub const DEFAULT_STICKY_TTL: Duration = Duration::from_secs(600);
+// sticky sessions remembered per balancer, the least recently used go
+// first past this
+const STICKY_CAPACITY: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    RoundRobin,
+    LeastPing,
+    Random,
+    // the same destination always leaves through the same outbound while
+    // it is alive
+    ConsistentHash,
+}
+
+impl Strategy {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "roundRobin" => Some(Self::RoundRobin),
+            "leastPing" => Some(Self::LeastPing),
+            "random" => Some(Self::Random),
+            "consistentHash" => Some(Self::ConsistentHash),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Health {
+    latency: Option<Duration>,
+    failures: u32,
+}
+
+impl Health {
+    fn alive(&self) -> bool {
+        self.failures < MAX_FAILURES
+    }
+}
+
+// the most specific name anyone can register, roughly: the last two labels
+// of a domain, there being no public suffix list here. ips stay as they are.
+fn root_domain(addr: &str) -> &str {
+    if addr.parse::<std::net::IpAddr>().is_ok() {
+        return addr;
+    }
+    let addr = addr.trim_end_matches('.');
+    match addr.rmatch_indices('.').nth(1) {
+        Some((i, _)) => &addr[i + 1..],
+        None => addr,
+    }
+}
+
+// the outbound picked for a source and root domain, kept until it goes
+// unused for `ttl`
+#[derive(Debug)]
+struct Sticky {
+    ttl: Duration,
+    // the index into the balancer's tags, and when the entry expires
+    entries: HashMap<(String, String), (usize, Instant)>,
+}
+
+impl Sticky {
+    fn insert(&mut self, key: (String, String), i: usize, now: Instant) {
+        if self.entries.len() >= STICKY_CAPACITY && !self.entries.contains_key(&key) {
+            self.entries.retain(|_, (_, expiry)| *expiry > now);
+        }
+        if self.entries.len() >= STICKY_CAPACITY && !self.entries.contains_key(&key) {
+            let oldest = self.entries.iter().min_by_key(|(_, (_, expiry))| *expiry);
+            if let Some(oldest) = oldest.map(|(k, _)| k.clone()) {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(key, (i, now + self.ttl));
+    }
+}
+
+// picks one of several outbound tags. health comes from tcp pings done
+// elsewhere and fed in through `report_latency` and `report_failure`.
+#[derive(Debug)]
+pub struct Balancer {
+    tags: Vec<String>,
+    strategy: Strategy,
+    health: RefCell<Vec<Health>>,
+    next: Cell<usize>,
+    sticky: Option<RefCell<Sticky>>,
+}
+
+impl Balancer {
+    pub fn new(tags: Vec<String>, strategy: Strategy) -> Self {
+        assert!(!tags.is_empty(), "balancer needs at least one outbound");
+        Self {
+            health: RefCell::new(vec![Health::default(); tags.len()]),
+            tags,
+            strategy,
+            next: Cell::new(0),
+            sticky: None,
+        }
+    }
+
+    // connections from one source to one site keep leaving through the
+    // outbound first picked for them, for sites that tie a session to the
+    // ip it started from
+    pub fn with_sticky(mut self, ttl: Duration) -> Self {
+        self.sticky = Some(RefCell::new(Sticky {
+            ttl,
+            entries: HashMap::new(),
+        }));
+        self
+    }
+
+    pub fn sticky_ttl(&self) -> Option<Duration> {
+        self.sticky.as_ref().map(|x| x.borrow().ttl)
+    }
+
+    // the sessions of `old` that are on outbounds still in this one. the
+    // ones on removed outbounds are forgotten.
+    fn keep_sticky(&self, old: &Balancer) {
+        let (Some(sticky), Some(remembered)) = (&self.sticky, &old.sticky) else {
+            return;
+        };
+        let mut sticky = sticky.borrow_mut();
+        for (key, (i, expiry)) in remembered.borrow().entries.iter() {
+            if let Some(i) = self.tags.iter().position(|x| *x == old.tags[*i]) {
+                sticky.entries.insert(key.clone(), (i, *expiry));
+            }
+        }
+    }
+
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    // `select`, remembering the pick for `source` when sessions are sticky.
+    // a remembered outbound that has died is picked afresh.
+    pub fn select_for(&self, source: Option<&str>, target: &Target) -> &str {
+        let (Some(sticky), Some(source)) = (&self.sticky, source) else {
+            return self.select(target);
+        };
+        let key = (source.to_string(), root_domain(&target.addr).to_ascii_lowercase());
+        let now = Instant::now();
+        let mut sticky = sticky.borrow_mut();
+        let remembered = sticky.entries.get(&key).copied();
+        let i = match remembered {
+            Some((i, expiry)) if expiry > now && self.health.borrow()[i].alive() => i,
+            _ => self.pick(target),
+        };
+        sticky.insert(key, i, now);
+        &self.tags[i]
+    }
+
+    pub fn select(&self, target: &Target) -> &str {
+        &self.tags[self.pick(target)]
+    }
+
+    // when every outbound is dead they are all tried again rather than
+    // failing the connection outright
+    fn pick(&self, target: &Target) -> usize {
+        let health = self.health.borrow();
+        let mut alive: Vec<usize> = (0..self.tags.len())
+            .filter(|&i| health[i].alive())
+            .collect();
+        if alive.is_empty() {
+            alive = (0..self.tags.len()).collect();
+        }
+
+        let i = match self.strategy {
+            Strategy::RoundRobin => {
+                // the first live outbound at or after the cursor
+                let next = self.next.get();
+                let i = alive
+                    .iter()
+                    .copied()
+                    .find(|&i| i >= next)
+                    .unwrap_or(alive[0]);
+                self.next.set(i + 1);
+                i
+            }
+            // outbounds that were never measured go last
+            Strategy::LeastPing => alive
+                .iter()
+                .copied()
+                .min_by_key(|&i| health[i].latency.unwrap_or(Duration::MAX))
+                .unwrap_or(alive[0]),
+            Strategy::Random => alive[random() % alive.len()],
+            // rendezvous hashing, so losing an outbound only moves the
+            // destinations that were on it
+            Strategy::ConsistentHash => alive
+                .iter()
+                .copied()
+                .max_by_key(|&i| {
+                    let mut hasher = DefaultHasher::new();
+                    (&target.addr, &self.tags[i]).hash(&mut hasher);
+                    hasher.finish()
+                })
+                .unwrap_or(alive[0]),
+        };
+        i
+    }
+
+    // a successful probe, which also brings a dead outbound back
+    pub fn report_latency(&self, tag: &str, latency: Duration) {
+        if let Some(mut x) = self.health_of(tag) {
+            x.latency = Some(latency);
+            x.failures = 0;
+        }
+    }
+
+    pub fn report_failure(&self, tag: &str) {
+        if let Some(mut x) = self.health_of(tag) {
+            x.failures = x.failures.saturating_add(1);
+        }
+    }
+
+    pub fn is_alive(&self, tag: &str) -> bool {
+        self.health_of(tag).is_some_and(|x| x.alive())
+    }
+
+    fn health_of(&self, tag: &str) -> Option<RefMut<'_, Health>> {
+        let i = self.tags.iter().position(|x| x == tag)?;
+        Some(RefMut::map(self.health.borrow_mut(), |x| &mut x[i]))
+    }
+}
+
+thread_local! {
+    static SHARED: RefCell<Vec<(String, Rc<Balancer>)>> = const { RefCell::new(Vec::new()) };
+}
+
+// config is rebuilt for every request but health has to outlive it, so
+// balancers are kept per isolate by tag. one whose members or settings
+// changed starts over, except for the sticky sessions on outbounds it
+// still has. the flag says whether the balancer is new, and so still
+// needs a health checker.
+pub fn shared(
+    tag: &str,
+    tags: Vec<String>,
+    strategy: Strategy,
+    sticky: Option<Duration>,
+) -> (Rc<Balancer>, bool) {
+    let build = |tags| {
+        let b = Balancer::new(tags, strategy);
+        match sticky {
+            Some(ttl) => b.with_sticky(ttl),
+            None => b,
+        }
+    };
+    SHARED.with_borrow_mut(|x| {
+        let existing = x.iter().position(|(t, _)| t == tag);
+        match existing.map(|i| &mut x[i].1) {
+            Some(b) if b.tags == tags && b.strategy == strategy && b.sticky_ttl() == sticky => {
+                (b.clone(), false)
+            }
+            Some(b) => {
+                let new = build(tags);
+                new.keep_sticky(b);
+                *b = Rc::new(new);
+                (b.clone(), true)
+            }
+            None => {
+                let b = Rc::new(build(tags));
+                x.push((tag.to_string(), b.clone()));
+                (b, true)
+            }
+        }
+    })
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Probe {
+    pub url: Url,
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for Probe {
+    fn default() -> Self {
+        Self {
+            url: Url::parse(DEFAULT_PROBE_URL).unwrap(),
+            interval: DEFAULT_PROBE_INTERVAL,
+            timeout: DEFAULT_PROBE_TIMEOUT,
+        }
+    }
+}
+
+// one entry of `ROUTING.balancers`:
+// `{"tag": "b", "selector": ["proxy-"], "strategy": {"type": "leastPing"}}`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BalancerConfig {
+    pub tag: String,
+    // outbound tag prefixes
+    pub selector: Vec<String>,
+    pub strategy: Strategy,
+    // health checks, always on for leastPing and otherwise only when a
+    // `probeUrl` is set
+    pub probe: Option<Probe>,
+    // how long an unused sticky session is kept, when they are on
+    pub sticky: Option<Duration>,
+}
+
+impl BalancerConfig {
+    pub fn from_json(value: &Value, path: &str) -> std::result::Result<Self, Vec<ConfigError>> {
+        let Some(object) = value.as_object() else {
+            return Err(vec![ConfigError::new(path, "expected an object")]);
+        };
+
+        let mut errors = Vec::new();
+        let mut tag = None;
+        let mut selector = Vec::new();
+        let mut strategy = Strategy::Random;
+        let mut probe = None;
+        let mut sticky = None;
+        for (key, value) in object {
+            let path = format!("{path}.{key}");
+            match key.as_str() {
+                "tag" => match value.as_str() {
+                    Some(x) if !x.is_empty() => tag = Some(x.to_string()),
+                    _ => errors.push(ConfigError::new(&path, "expected a non-empty string")),
+                },
+                "selector" => match value.as_array() {
+                    Some(x) if !x.is_empty() => {
+                        for (i, x) in x.iter().enumerate() {
+                            match x.as_str() {
+                                Some(x) if !x.is_empty() => selector.push(x.to_string()),
+                                _ => errors.push(ConfigError::new(
+                                    &format!("{path}[{i}]"),
+                                    "expected a non-empty string",
+                                )),
+                            }
+                        }
+                    }
+                    _ => errors.push(ConfigError::new(&path, "expected a non-empty array")),
+                },
+                "strategy" => match Self::strategy_from_json(value, &path) {
+                    Ok(x) => (strategy, probe, sticky) = x,
+                    Err(e) => errors.extend(e),
+                },
+                _ => errors.push(ConfigError::new(&path, "unknown field")),
+            }
+        }
+
+        if tag.is_none() && errors.is_empty() {
+            errors.push(ConfigError::new(&format!("{path}.tag"), "is not set"));
+        }
+        if selector.is_empty() && errors.is_empty() {
+            errors.push(ConfigError::new(&format!("{path}.selector"), "is not set"));
+        }
+        match tag {
+            Some(tag) if errors.is_empty() => Ok(Self {
+                tag,
+                selector,
+                strategy,
+                probe,
+                sticky,
+            }),
+            _ => Err(errors),
+        }
+    }
+
+    fn strategy_from_json(
+        value: &Value,
+        path: &str,
+    ) -> std::result::Result<(Strategy, Option<Probe>, Option<Duration>), Vec<ConfigError>> {
+        let Some(object) = value.as_object() else {
+            return Err(vec![ConfigError::new(path, "expected an object")]);
+        };
+
+        let mut errors = Vec::new();
+        let mut strategy = Strategy::Random;
+        let mut probe = Probe::default();
+        let mut probe_url = false;
+        let (mut sticky, mut sticky_ttl) = (false, DEFAULT_STICKY_TTL);
+        for (key, value) in object {
+            let path = format!("{path}.{key}");
+            match key.as_str() {
+                "type" => match value.as_str().and_then(Strategy::parse) {
+                    Some(x) => strategy = x,
+                    None => errors.push(ConfigError::new(
+                        &path,
+                        "expected random, roundRobin, leastPing or consistentHash",
+                    )),
+                },
+                "settings" => {
+                    let Some(settings) = value.as_object() else {
+                        errors.push(ConfigError::new(&path, "expected an object"));
+                        continue;
+                    };
+                    for (key, value) in settings {
+                        let path = format!("{path}.{key}");
+                        match key.as_str() {
+                            "probeUrl" => match value.as_str().map(Url::parse) {
+                                Some(Ok(x)) if x.scheme() == "http" && x.host_str().is_some() => {
+                                    probe.url = x;
+                                    probe_url = true;
+                                }
+                                Some(Ok(_)) => errors.push(ConfigError::new(
+                                    &path,
+                                    "probes are sent in plain http, expected an http:// url",
+                                )),
+                                _ => errors.push(ConfigError::new(&path, "expected a url")),
+                            },
+                            "probeInterval" | "probeTimeout" => match parse_duration(value) {
+                                Ok(x) if !x.is_zero() => match key.as_str() {
+                                    "probeInterval" => probe.interval = x,
+                                    _ => probe.timeout = x,
+                                },
+                                Ok(_) => errors.push(ConfigError::new(&path, "must not be zero")),
+                                Err(e) => errors.push(ConfigError::new(&path, e)),
+                            },
+                            "sticky" => match value.as_bool() {
+                                Some(x) => sticky = x,
+                                None => errors.push(ConfigError::new(&path, "expected a boolean")),
+                            },
+                            "stickyTtl" => match parse_duration(value) {
+                                Ok(x) if !x.is_zero() => sticky_ttl = x,
+                                Ok(_) => errors.push(ConfigError::new(&path, "must not be zero")),
+                                Err(e) => errors.push(ConfigError::new(&path, e)),
+                            },
+                            _ => errors.push(ConfigError::new(&path, "unknown field")),
+                        }
+                    }
+                }
+                _ => errors.push(ConfigError::new(&path, "unknown field")),
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        let probe = (strategy == Strategy::LeastPing || probe_url).then_some(probe);
+        Ok((strategy, probe, sticky.then_some(sticky_ttl)))
+    }
+
+    // the registered outbound tags picked by the selector, in the order
+    // they were registered
+    pub fn members<'a>(&self, tags: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+        tags.into_iter()
+            .filter(|tag| self.selector.iter().any(|x| tag.starts_with(x.as_str())))
+            .map(|x| x.to_string())
+            .collect()
+    }
+}
+
+fn random() -> usize {
+    let mut buf = [0u8; 8];
+    crate::common::random(&mut buf);
+    u64::from_le_bytes(buf) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbound::Network;
+    use serde_json::json;
+
+    fn target(addr: &str) -> Target {
+        Target::new(addr.to_string(), 443, Network::Tcp)
+    }
+
+    fn balancer(strategy: Strategy) -> Balancer {
+        let tags = ["a", "b", "c"].iter().map(|x| x.to_string()).collect();
+        Balancer::new(tags, strategy)
+    }
+
+    fn picks(b: &Balancer, n: usize) -> Vec<String> {
+        let target = target("example.com");
+        (0..n).map(|_| b.select(&target).to_string()).collect()
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let b = balancer(Strategy::RoundRobin);
+        assert_eq!(picks(&b, 7), ["a", "b", "c", "a", "b", "c", "a"]);
+    }
+
+    #[test]
+    fn test_dead_outbounds_skipped() {
+        let b = balancer(Strategy::RoundRobin);
+        b.report_failure("b");
+        b.report_failure("b");
+        assert!(b.is_alive("b"));
+        assert_eq!(picks(&b, 3), ["a", "b", "c"]);
+
+        b.report_failure("b");
+        assert!(!b.is_alive("b"));
+        assert_eq!(picks(&b, 4), ["a", "c", "a", "c"]);
+
+        // one good probe is enough to bring it back
+        b.report_latency("b", Duration::from_millis(40));
+        assert_eq!(picks(&b, 3), ["a", "b", "c"]);
+
+        // with nothing alive every outbound is a candidate again
+        for tag in ["a", "b", "c"] {
+            for _ in 0..MAX_FAILURES {
+                b.report_failure(tag);
+            }
+        }
+        assert_eq!(picks(&b, 3), ["a", "b", "c"]);
+
+        let b = balancer(Strategy::Random);
+        for tag in ["a", "c"] {
+            for _ in 0..MAX_FAILURES {
+                b.report_failure(tag);
+            }
+        }
+        assert!(picks(&b, 50).iter().all(|x| x == "b"));
+    }
+
+    #[test]
+    fn test_least_ping() {
+        let b = balancer(Strategy::LeastPing);
+        assert_eq!(b.select(&target("example.com")), "a");
+
+        b.report_latency("a", Duration::from_millis(120));
+        b.report_latency("c", Duration::from_millis(30));
+        assert_eq!(picks(&b, 2), ["c", "c"]);
+
+        for _ in 0..MAX_FAILURES {
+            b.report_failure("c");
+        }
+        assert_eq!(b.select(&target("example.com")), "a");
+
+        // unknown tags are ignored
+        b.report_latency("missing", Duration::ZERO);
+        assert_eq!(b.select(&target("example.com")), "a");
+    }
+
+    #[test]
+    fn test_random() {
+        let b = balancer(Strategy::Random);
+        let picked = picks(&b, 200);
+        for tag in ["a", "b", "c"] {
+            assert!(picked.iter().any(|x| x == tag), "{tag}");
+        }
+    }
+
+    #[test]
+    fn test_consistent_hash() {
+        let b = balancer(Strategy::ConsistentHash);
+        let sites: Vec<_> = (0..300)
+            .map(|i| target(&format!("site{i}.example")))
+            .collect();
+        let picked: Vec<_> = sites.iter().map(|x| b.select(x).to_string()).collect();
+
+        // sticky, and spread over every outbound
+        for (site, tag) in sites.iter().zip(&picked) {
+            assert_eq!(b.select(site), tag);
+        }
+        for tag in ["a", "b", "c"] {
+            let n = picked.iter().filter(|x| *x == tag).count();
+            assert!(n > 50, "{tag}: {n}");
+        }
+
+        // only the sites on a dead outbound move
+        for _ in 0..MAX_FAILURES {
+            b.report_failure("b");
+        }
+        for (site, tag) in sites.iter().zip(&picked) {
+            match tag.as_str() {
+                "b" => assert_ne!(b.select(site), "b"),
+                _ => assert_eq!(b.select(site), tag),
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sticky() {
+        let b = balancer(Strategy::RoundRobin).with_sticky(Duration::from_secs(600));
+        let (one, two) = (Some("198.51.100.1"), Some("198.51.100.2"));
+        assert_eq!(b.select_for(one, &target("www.example.com")), "a");
+        // the same site from elsewhere, and another site, are balanced
+        assert_eq!(b.select_for(two, &target("www.example.com")), "b");
+        assert_eq!(b.select_for(one, &target("example.net")), "c");
+        assert_eq!(b.select_for(one, &target("api.EXAMPLE.com")), "a");
+        assert_eq!(b.select_for(two, &target("cdn.example.com")), "b");
+        // nothing to be sticky about without a source
+        assert_eq!(b.select_for(None, &target("www.example.com")), "a");
+
+        // a session kept in use stays put
+        for _ in 0..3 {
+            tokio::time::advance(Duration::from_secs(500)).await;
+            assert_eq!(b.select_for(one, &target("example.com")), "a");
+        }
+        // and one left alone past its ttl is balanced again
+        tokio::time::advance(Duration::from_secs(601)).await;
+        assert_eq!(b.select_for(one, &target("example.com")), "b");
+
+        // so is one whose outbound died
+        for _ in 0..MAX_FAILURES {
+            b.report_failure("b");
+        }
+        assert_eq!(b.select_for(one, &target("example.com")), "c");
+        assert_eq!(b.select_for(one, &target("example.com")), "c");
+    }
+
+    #[test]
+    fn test_sticky_capacity() {
+        let b = balancer(Strategy::Random).with_sticky(Duration::from_secs(600));
+        for i in 0..STICKY_CAPACITY + 10 {
+            b.select_for(Some(&format!("10.0.{}.{}", i / 256, i % 256)), &target("example.com"));
+        }
+        let sticky = b.sticky.as_ref().unwrap().borrow();
+        assert_eq!(sticky.entries.len(), STICKY_CAPACITY);
+    }
+
+    #[test]
+    fn test_root_domain() {
+        assert_eq!(root_domain("www.example.com"), "example.com");
+        assert_eq!(root_domain("a.b.example.com."), "example.com");
+        assert_eq!(root_domain("example.com"), "example.com");
+        assert_eq!(root_domain("localhost"), "localhost");
+        assert_eq!(root_domain("192.0.2.1"), "192.0.2.1");
+        assert_eq!(root_domain("2001:db8::1"), "2001:db8::1");
+    }
+
+    #[test]
+    fn test_shared() {
+        let tags = || vec!["a".to_string(), "b".to_string()];
+        let (first, created) = shared("test-shared", tags(), Strategy::RoundRobin, None);
+        assert!(created);
+        first.report_failure("a");
+
+        let (again, created) = shared("test-shared", tags(), Strategy::RoundRobin, None);
+        assert!(!created && Rc::ptr_eq(&first, &again));
+
+        let (changed, created) = shared("test-shared", tags(), Strategy::Random, None);
+        assert!(created && !Rc::ptr_eq(&first, &changed));
+    }
+
+    #[test]
+    fn test_shared_sticky() {
+        let ttl = Some(Duration::from_secs(600));
+        let tags = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        let (b, _) = shared("test-sticky", tags(&["a", "b", "c"]), Strategy::RoundRobin, ttl);
+        let (one, two) = (Some("198.51.100.1"), Some("198.51.100.2"));
+        assert_eq!(b.select_for(one, &target("example.com")), "a");
+        assert_eq!(b.select_for(two, &target("example.com")), "b");
+
+        // b is dropped by a reload, its sessions go with it
+        let (b, created) = shared("test-sticky", tags(&["c", "a"]), Strategy::RoundRobin, ttl);
+        assert!(created);
+        assert_eq!(b.select_for(one, &target("example.com")), "a");
+        let entries = b.sticky.as_ref().unwrap().borrow().entries.len();
+        assert_eq!(entries, 1);
+        assert_eq!(b.select_for(two, &target("example.com")), "c");
+    }
+
+    #[test]
+    fn test_config_from_json() {
+        let config = BalancerConfig::from_json(
+            &json!({"tag": "b", "selector": ["proxy-"], "strategy": {"type": "leastPing", "settings": {"probeInterval": "10s"}}}),
+            "ROUTING.balancers[0]",
+        )
+        .unwrap();
+        assert_eq!(config.strategy, Strategy::LeastPing);
+        let probe = config.probe.clone().unwrap();
+        assert_eq!(probe.url.as_str(), DEFAULT_PROBE_URL);
+        assert_eq!(probe.interval, Duration::from_secs(10));
+        assert_eq!(
+            config.members(["direct", "proxy-kr", "block", "proxy-jp"]),
+            ["proxy-kr", "proxy-jp"]
+        );
+
+        // no health checks unless asked for
+        let config =
+            BalancerConfig::from_json(&json!({"tag": "b", "selector": ["x"]}), "B").unwrap();
+        assert_eq!((config.strategy, config.probe), (Strategy::Random, None));
+        let config = BalancerConfig::from_json(
+            &json!({"tag": "b", "selector": ["x"], "strategy": {"type": "roundRobin", "settings": {"probeUrl": "http://example.com/"}}}),
+            "B",
+        )
+        .unwrap();
+        assert!(config.probe.is_some());
+
+        assert_eq!(config.sticky, None);
+        let config = BalancerConfig::from_json(
+            &json!({"tag": "b", "selector": ["x"], "strategy": {"settings": {"sticky": true}}}),
+            "B",
+        )
+        .unwrap();
+        assert_eq!(config.sticky, Some(DEFAULT_STICKY_TTL));
+        let config = BalancerConfig::from_json(
+            &json!({"tag": "b", "selector": ["x"], "strategy": {"type": "leastPing", "settings": {"sticky": true, "stickyTtl": "10m"}}}),
+            "B",
+        )
+        .unwrap();
+        assert_eq!(config.sticky, Some(Duration::from_secs(600)));
+        let errors = BalancerConfig::from_json(
+            &json!({"tag": "b", "selector": ["x"], "strategy": {"settings": {"sticky": "yes", "stickyTtl": 0}}}),
+            "B",
+        )
+        .unwrap_err();
+        let paths: Vec<_> = errors.iter().map(|x| x.path.as_str()).collect();
+        assert_eq!(paths, ["B.strategy.settings.sticky", "B.strategy.settings.stickyTtl"]);
+
+        let errors = BalancerConfig::from_json(
+            &json!({"selector": [""], "strategy": {"type": "leastLoad", "settings": {"probeUrl": "https://example.com/", "probeInterval": "0s"}}}),
+            "B",
+        )
+        .err()
+        .unwrap();
+        let paths: Vec<_> = errors.iter().map(|x| x.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "B.selector[0]",
+                "B.strategy.settings.probeInterval",
+                "B.strategy.settings.probeUrl",
+                "B.strategy.type",
+            ]
+        );
+        let errors = BalancerConfig::from_json(&json!({"selector": ["x"]}), "B")
+            .err()
+            .unwrap();
+        assert_eq!(errors[0].path, "B.tag");
+    }
+}
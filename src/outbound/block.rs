@@ -0,0 +1,119 @@
+use super::{AsyncStream, Outbound, Target};
+use crate::config::ConfigError;
+
+use async_trait::async_trait;
+use serde_json::Value;
+use tokio::io::AsyncWriteExt;
+use worker::*;
+
+// what v2ray's blackhole sends for `"response": {"type": "http"}`
+pub const HTTP_RESPONSE: &[u8] = b"HTTP/1.1 403 Forbidden\r\n\
+    Connection: close\r\n\
+    Cache-Control: max-age=3600, public\r\n\
+    Content-Length: 0\r\n\r\n";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BlockResponse {
+    // the connection is closed without a word
+    #[default]
+    None,
+    // a 403 first, so browsers give up instead of waiting
+    Http,
+}
+
+// the optional `BLACKHOLE` binding, v2ray's blackhole outbound settings:
+// `{"response": {"type": "http"}}`
+impl BlockResponse {
+    pub fn from_json(value: &Value, path: &str) -> std::result::Result<Self, Vec<ConfigError>> {
+        let Some(object) = value.as_object() else {
+            return Err(vec![ConfigError::new(path, "expected an object")]);
+        };
+
+        let mut errors = Vec::new();
+        let mut response = BlockResponse::default();
+        for (key, value) in object {
+            let path = format!("{path}.{key}");
+            match key.as_str() {
+                "response" => match value.get("type").and_then(Value::as_str) {
+                    Some("none") => response = BlockResponse::None,
+                    Some("http") => response = BlockResponse::Http,
+                    _ => errors.push(ConfigError::new(
+                        &format!("{path}.type"),
+                        "expected none or http",
+                    )),
+                },
+                _ => errors.push(ConfigError::new(&path, "unknown field")),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(response)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+// refuses every connection without dialing or reading anything
+#[derive(Default)]
+pub struct BlockOutbound {
+    response: BlockResponse,
+}
+
+impl BlockOutbound {
+    pub fn with_response(mut self, response: BlockResponse) -> Self {
+        self.response = response;
+        self
+    }
+}
+
+#[async_trait(?Send)]
+impl Outbound for BlockOutbound {
+    async fn dispatch(&self, target: &Target, stream: &mut dyn AsyncStream) -> Result<()> {
+        if self.response == BlockResponse::Http {
+            // best effort, the connection is refused either way
+            let _ = stream.write_all(HTTP_RESPONSE).await;
+            let _ = stream.shutdown().await;
+        }
+        Err(Error::RustError(format!("blocked {target}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbound::Network;
+    use serde_json::json;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_block() {
+        let target = Target::new("example.com".to_string(), 443, Network::Tcp);
+        // a stream that would fail the test if it were read or written
+        let (_client, mut server) = tokio::io::duplex(1);
+        let e = BlockOutbound::default()
+            .dispatch(&target, &mut server)
+            .await
+            .unwrap_err();
+        assert_eq!(e.to_string(), "blocked tcp:example.com:443");
+    }
+
+    #[tokio::test]
+    async fn test_block_http_response() {
+        let target = Target::new("ads.example.com".to_string(), 80, Network::Tcp);
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let response =
+            BlockResponse::from_json(&json!({"response": {"type": "http"}}), "BLACKHOLE");
+        let block = BlockOutbound::default().with_response(response.unwrap());
+        assert!(block.dispatch(&target, &mut server).await.is_err());
+
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, HTTP_RESPONSE);
+        assert!(received.starts_with(b"HTTP/1.1 403 Forbidden\r\n"));
+
+        let errors = BlockResponse::from_json(&json!({"response": {"type": "tls"}}), "BLACKHOLE")
+            .unwrap_err();
+        assert_eq!(errors[0].path, "BLACKHOLE.response.type");
+    }
+}
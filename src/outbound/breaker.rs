@@ -0,0 +1,302 @@
+use super::{AsyncStream, Outbound, Target};
+use crate::app::dns::cache::Clock;
+use crate::common::error::Retryable;
+use crate::common::time;
+
+use async_trait::async_trait;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
+use worker::*;
+
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(60);
+pub const DEFAULT_FAILURE_RATE: f64 = 0.5;
+// results within the window before the failure rate counts for anything
+pub const DEFAULT_MIN_CALLS: usize = 5;
+pub const DEFAULT_COOL_DOWN: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakerState {
+    // connections go through
+    Closed,
+    // connections fail right away until the cool down is over
+    Open,
+    // the cool down is over, the next connection probes the outbound
+    HalfOpen,
+}
+
+#[derive(Debug)]
+enum Circuit {
+    // when each recent connection finished and whether it failed
+    Closed(VecDeque<(Duration, bool)>),
+    Open { until: Duration },
+    // a probe is out, the others still fail right away
+    Probing,
+}
+
+// wraps an outbound that fails too often. once `failure_rate` of the
+// connections within `window` failed, the circuit opens and connections
+// fail without reaching the outbound. after `cool_down` one connection is
+// let through: its success closes the circuit, its failure opens it again.
+pub struct BreakerOutbound {
+    inner: Box<dyn Outbound>,
+    window: Duration,
+    failure_rate: f64,
+    min_calls: usize,
+    cool_down: Duration,
+    clock: Clock,
+    circuit: RefCell<Circuit>,
+}
+
+impl BreakerOutbound {
+    pub fn new(inner: Box<dyn Outbound>) -> Self {
+        Self {
+            inner,
+            window: DEFAULT_WINDOW,
+            failure_rate: DEFAULT_FAILURE_RATE,
+            min_calls: DEFAULT_MIN_CALLS,
+            cool_down: DEFAULT_COOL_DOWN,
+            clock: Rc::new(time::now),
+            circuit: RefCell::new(Circuit::Closed(VecDeque::new())),
+        }
+    }
+
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    // between 0 and 1
+    pub fn with_failure_rate(mut self, failure_rate: f64) -> Self {
+        self.failure_rate = failure_rate;
+        self
+    }
+
+    pub fn with_min_calls(mut self, min_calls: usize) -> Self {
+        self.min_calls = min_calls;
+        self
+    }
+
+    pub fn with_cool_down(mut self, cool_down: Duration) -> Self {
+        self.cool_down = cool_down;
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn state(&self) -> BreakerState {
+        match *self.circuit.borrow() {
+            Circuit::Closed(_) => BreakerState::Closed,
+            Circuit::Open { until } if (self.clock)() < until => BreakerState::Open,
+            Circuit::Open { .. } | Circuit::Probing => BreakerState::HalfOpen,
+        }
+    }
+
+    // whether this connection may go through, and if it is the probe
+    fn admit(&self) -> Option<bool> {
+        let mut circuit = self.circuit.borrow_mut();
+        match *circuit {
+            Circuit::Closed(_) => Some(false),
+            Circuit::Open { until } if (self.clock)() >= until => {
+                *circuit = Circuit::Probing;
+                Some(true)
+            }
+            Circuit::Open { .. } | Circuit::Probing => None,
+        }
+    }
+
+    fn report(&self, probe: bool, failed: bool) {
+        let now = (self.clock)();
+        let mut circuit = self.circuit.borrow_mut();
+        let open = Circuit::Open {
+            until: now + self.cool_down,
+        };
+        let results = match &mut *circuit {
+            Circuit::Probing if probe => {
+                *circuit = match failed {
+                    true => open,
+                    false => Circuit::Closed(VecDeque::new()),
+                };
+                return;
+            }
+            Circuit::Closed(results) => results,
+            // admitted before the circuit opened, too late to count
+            _ => return,
+        };
+
+        results.push_back((now, failed));
+        while results.front().is_some_and(|x| x.0 + self.window < now) {
+            results.pop_front();
+        }
+        let failures = results.iter().filter(|x| x.1).count();
+        if results.len() >= self.min_calls
+            && failures as f64 >= self.failure_rate * results.len() as f64
+        {
+            crate::log!(
+                "[breaker]: {} of {} connections failed, open for {:?}",
+                failures,
+                results.len(),
+                self.cool_down
+            );
+            *circuit = open;
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Outbound for BreakerOutbound {
+    async fn dispatch(&self, target: &Target, stream: &mut dyn AsyncStream) -> Result<()> {
+        // refused like a dial, so a fallback moves on
+        let Some(probe) = self.admit() else {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("circuit open, not dialing {target}"),
+            )));
+        };
+        // an error that would come back however often it is tried, like
+        // bad credentials, says nothing about the outbound being down
+        let res = self.inner.dispatch(target, stream).await;
+        self.report(probe, res.as_ref().is_err_and(|e| e.is_retryable()));
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbound::Network;
+    use std::cell::Cell;
+
+    // fails while `down` is set, counting the connections that reached it
+    struct MockOutbound {
+        down: Rc<Cell<bool>>,
+        calls: Rc<Cell<u32>>,
+    }
+
+    #[async_trait(?Send)]
+    impl Outbound for MockOutbound {
+        async fn dispatch(&self, _: &Target, _: &mut dyn AsyncStream) -> Result<()> {
+            self.calls.set(self.calls.get() + 1);
+            match self.down.get() {
+                true => Err(Error::Io(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    "down",
+                ))),
+                false => Ok(()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_breaker_states() {
+        let down = Rc::new(Cell::new(false));
+        let calls = Rc::new(Cell::new(0));
+        let now = Rc::new(Cell::new(Duration::from_secs(1_000)));
+        let clock = now.clone();
+        let inner = MockOutbound {
+            down: down.clone(),
+            calls: calls.clone(),
+        };
+        let breaker = BreakerOutbound::new(Box::new(inner))
+            .with_min_calls(4)
+            .with_failure_rate(0.5)
+            .with_cool_down(Duration::from_secs(30))
+            .with_clock(Rc::new(move || clock.get()));
+        let target = Target::new("example.com".to_string(), 443, Network::Tcp);
+        let connect = || async {
+            let (_client, mut server) = tokio::io::duplex(64);
+            breaker.dispatch(&target, &mut server).await
+        };
+
+        // two failures in four connections open it
+        for ok in [true, true] {
+            assert_eq!(connect().await.is_ok(), ok);
+        }
+        down.set(true);
+        assert!(connect().await.is_err());
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(connect().await.is_err());
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        // short-circuited, the outbound is left alone
+        assert!(connect().await.is_err());
+        assert_eq!(calls.get(), 4);
+
+        // a failed probe opens it again
+        now.set(now.get() + Duration::from_secs(30));
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(connect().await.is_err());
+        assert_eq!(calls.get(), 5);
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        // and a good one closes it
+        down.set(false);
+        now.set(now.get() + Duration::from_secs(30));
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(connect().await.is_ok());
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(connect().await.is_ok());
+        assert_eq!(calls.get(), 7);
+    }
+
+    // turns every connection down for its credentials
+    struct Denied(Rc<Cell<u32>>);
+
+    #[async_trait(?Send)]
+    impl Outbound for Denied {
+        async fn dispatch(&self, _: &Target, _: &mut dyn AsyncStream) -> Result<()> {
+            self.0.set(self.0.get() + 1);
+            Err(Error::Io(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "authentication failed",
+            )))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_breaker_permanent_errors() {
+        let calls = Rc::new(Cell::new(0));
+        let breaker = BreakerOutbound::new(Box::new(Denied(calls.clone()))).with_min_calls(2);
+        let target = Target::new("example.com".to_string(), 443, Network::Tcp);
+        for _ in 0..5 {
+            let (_client, mut server) = tokio::io::duplex(64);
+            let e = breaker.dispatch(&target, &mut server).await.unwrap_err();
+            assert!(e.to_string().contains("authentication failed"));
+        }
+        // each one reached the outbound and was passed on as it was
+        assert_eq!(calls.get(), 5);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_breaker_window() {
+        let now = Rc::new(Cell::new(Duration::from_secs(1_000)));
+        let clock = now.clone();
+        let inner = MockOutbound {
+            down: Rc::new(Cell::new(true)),
+            calls: Rc::default(),
+        };
+        let breaker = BreakerOutbound::new(Box::new(inner))
+            .with_min_calls(2)
+            .with_window(Duration::from_secs(10))
+            .with_clock(Rc::new(move || clock.get()));
+        let target = Target::new("example.com".to_string(), 443, Network::Tcp);
+        let (_client, mut server) = tokio::io::duplex(64);
+
+        // failures further apart than the window never add up
+        for _ in 0..3 {
+            assert!(breaker.dispatch(&target, &mut server).await.is_err());
+            assert_eq!(breaker.state(), BreakerState::Closed);
+            now.set(now.get() + Duration::from_secs(11));
+        }
+        assert!(breaker.dispatch(&target, &mut server).await.is_err());
+        now.set(now.get() + Duration::from_secs(5));
+        assert!(breaker.dispatch(&target, &mut server).await.is_err());
+        assert_eq!(breaker.state(), BreakerState::Open);
+    }
+}
@@ -0,0 +1,831 @@
+use super::dialer::{BoxStream, Dialer, SocketDialer};
+use super::{AsyncStream, Network, Outbound, Target};
+use crate::app::metrics;
+use crate::app::policy::Policy;
+use crate::common::relay::relay_bidirectional;
+use crate::common::time::{self, parse_duration};
+use crate::common::write_u16_be;
+use crate::config::ConfigError;
+use crate::proxy::vmess::chunk::VmessStream;
+use crate::proxy::vmess::client::VmessConnector;
+
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use futures_util::future::{self, Either};
+use serde_json::Value;
+use std::cell::Cell;
+use std::io;
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use worker::*;
+
+// an http proxy's answer is read a byte at a time up to this, so nothing
+// past its headers is taken from the tunnel
+const MAX_HTTP_RESPONSE: usize = 8 * 1024;
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(8);
+
+// a proxy in front of the vmess server, asked to connect to the next hop
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Hop {
+    // no authentication, or username and password (rfc 1929)
+    Socks5 {
+        server: Target,
+        auth: Option<(String, String)>,
+    },
+    // `CONNECT`, with basic proxy authorization
+    Http {
+        server: Target,
+        auth: Option<(String, String)>,
+    },
+}
+
+impl Hop {
+    pub fn server(&self) -> &Target {
+        match self {
+            Self::Socks5 { server, .. } | Self::Http { server, .. } => server,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Socks5 { .. } => "socks5",
+            Self::Http { .. } => "http",
+        }
+    }
+
+    // asks the proxy at the other end of `stream` for a tunnel to `target`
+    async fn connect(&self, stream: &mut BoxStream, target: &Target) -> io::Result<()> {
+        match self {
+            Self::Socks5 { auth, .. } => socks5_connect(stream, target, auth.as_ref()).await,
+            Self::Http { auth, .. } => http_connect(stream, target, auth.as_ref()).await,
+        }
+    }
+}
+
+// keeps the kind, which tells whether another try could do better
+fn hop_error(hop: &Hop, e: io::Error) -> Error {
+    let message = format!("{} proxy {}: {e}", hop.kind(), hop.server());
+    Error::Io(io::Error::new(e.kind(), message))
+}
+
+fn fail(kind: io::ErrorKind, message: impl Into<String>) -> io::Error {
+    io::Error::new(kind, message.into())
+}
+
+async fn socks5_connect(
+    stream: &mut BoxStream,
+    target: &Target,
+    auth: Option<&(String, String)>,
+) -> io::Result<()> {
+    use io::ErrorKind::*;
+
+    match auth {
+        Some(_) => stream.write_all(&[0x05, 0x02, 0x00, 0x02]).await?,
+        None => stream.write_all(&[0x05, 0x01, 0x00]).await?,
+    }
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await?;
+    match (method, auth) {
+        ([0x05, 0x00], _) => {}
+        ([0x05, 0x02], Some((user, pass))) => {
+            if user.len() > 255 || pass.len() > 255 {
+                return Err(fail(
+                    InvalidInput,
+                    "username and password are at most 255 bytes",
+                ));
+            }
+            let mut request = vec![0x01, user.len() as u8];
+            request.extend_from_slice(user.as_bytes());
+            request.push(pass.len() as u8);
+            request.extend_from_slice(pass.as_bytes());
+            stream.write_all(&request).await?;
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status).await?;
+            if status[1] != 0x00 {
+                return Err(fail(PermissionDenied, "authentication failed"));
+            }
+        }
+        ([0x05, 0xff], _) => {
+            return Err(fail(
+                PermissionDenied,
+                "no acceptable authentication method",
+            ))
+        }
+        _ => {
+            return Err(fail(
+                InvalidData,
+                format!("unexpected method {method:02x?}"),
+            ))
+        }
+    }
+
+    let mut request = vec![0x05, 0x01, 0x00];
+    match target.addr.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(0x01);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(0x04);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            if target.addr.len() > 255 {
+                return Err(fail(
+                    InvalidInput,
+                    format!("domain of {target} is too long"),
+                ));
+            }
+            request.push(0x03);
+            request.push(target.addr.len() as u8);
+            request.extend_from_slice(target.addr.as_bytes());
+        }
+    }
+    write_u16_be(&mut request, target.port);
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 0x05 {
+        return Err(fail(
+            InvalidData,
+            format!("unexpected version {}", reply[0]),
+        ));
+    }
+    if reply[1] != 0x00 {
+        let (kind, reason) = match reply[1] {
+            0x01 => (ConnectionAborted, "general failure"),
+            0x02 => (PermissionDenied, "not allowed by ruleset"),
+            0x03 => (NetworkUnreachable, "network unreachable"),
+            0x04 => (HostUnreachable, "host unreachable"),
+            0x05 => (ConnectionRefused, "connection refused"),
+            0x06 => (TimedOut, "ttl expired"),
+            0x07 => (Unsupported, "command not supported"),
+            0x08 => (Unsupported, "address type not supported"),
+            _ => (InvalidData, "unknown error"),
+        };
+        return Err(fail(kind, format!("connecting to {target}: {reason}")));
+    }
+    // the bound address, not needed
+    let len = match reply[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => stream.read_u8().await? as usize,
+        x => return Err(fail(InvalidData, format!("unexpected address type {x}"))),
+    };
+    let mut bound = vec![0u8; len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+async fn http_connect(
+    stream: &mut BoxStream,
+    target: &Target,
+    auth: Option<&(String, String)>,
+) -> io::Result<()> {
+    use io::ErrorKind::*;
+
+    let authority = match target.addr.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{ip}]:{}", target.port),
+        _ => format!("{}:{}", target.addr, target.port),
+    };
+    let mut request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+    if let Some((user, pass)) = auth {
+        let credentials = STANDARD.encode(format!("{user}:{pass}"));
+        request.push_str(&format!("Proxy-Authorization: Basic {credentials}\r\n"));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() == MAX_HTTP_RESPONSE {
+            return Err(fail(InvalidData, "response headers too long"));
+        }
+        response.push(stream.read_u8().await?);
+    }
+    let status = response
+        .split(|x| *x == b' ')
+        .nth(1)
+        .and_then(|x| std::str::from_utf8(x).ok())
+        .and_then(|x| x.parse::<u16>().ok());
+    let kind = match status {
+        Some(200..=299) => return Ok(()),
+        Some(401 | 403 | 407) => PermissionDenied,
+        Some(502 | 503) => ConnectionRefused,
+        Some(504) => TimedOut,
+        Some(_) => Other,
+        None => return Err(fail(InvalidData, "malformed response")),
+    };
+    let status = status.unwrap_or_default();
+    Err(fail(
+        kind,
+        format!("connecting to {target}: status {status}"),
+    ))
+}
+
+// which part of the handshake a connection is in, for telling where a
+// stalled one got stuck
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    // reaching the first hop, or the server without any
+    Dial,
+    // asking the nth hop, counting from 0, for its tunnel
+    Hop(usize),
+    // the vmess header round trip
+    Vmess,
+}
+
+// proxy over proxy: the first hop is dialed, then each hop is asked for a
+// tunnel to the next one and the last to the target
+pub struct ChainDialer {
+    hops: Vec<Hop>,
+    dialer: Rc<dyn Dialer>,
+}
+
+impl ChainDialer {
+    pub fn new(hops: Vec<Hop>) -> Self {
+        Self {
+            hops,
+            dialer: Rc::new(SocketDialer),
+        }
+    }
+
+    // how the first hop is reached, a worker socket by default
+    pub fn with_dialer(mut self, dialer: Rc<dyn Dialer>) -> Self {
+        self.dialer = dialer;
+        self
+    }
+
+    // `dial`, moving `stage` along as each hop is started
+    async fn dial_tracked(&self, target: &Target, stage: &Cell<Stage>) -> Result<BoxStream> {
+        stage.set(Stage::Dial);
+        let Some(first) = self.hops.first() else {
+            return self.dialer.dial(target).await;
+        };
+        if target.network == Network::Udp {
+            return Err(Error::RustError(format!(
+                "can not dial {target}, proxy hops are tcp only"
+            )));
+        }
+
+        let mut stream = self.dialer.dial(first.server()).await?;
+        for (i, hop) in self.hops.iter().enumerate() {
+            stage.set(Stage::Hop(i));
+            let next = self.hops.get(i + 1).map_or(target, |x| x.server());
+            if let Err(e) = hop.connect(&mut stream, next).await {
+                let e = hop_error(hop, e);
+                metrics::shared().dial_error("chain", &e);
+                return Err(e);
+            }
+        }
+        Ok(stream)
+    }
+
+    fn describe(&self, stage: Stage, server: &Target) -> String {
+        match stage {
+            Stage::Dial => match self.hops.first() {
+                Some(hop) => format!("dialing {}", hop.server()),
+                None => format!("dialing {server}"),
+            },
+            Stage::Hop(i) => {
+                let hop = &self.hops[i];
+                let (kind, server) = (hop.kind(), hop.server());
+                format!("the {kind} handshake with hop {} ({server})", i + 1)
+            }
+            Stage::Vmess => format!("the vmess handshake with {server}"),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Dialer for ChainDialer {
+    async fn dial(&self, target: &Target) -> Result<BoxStream> {
+        self.dial_tracked(target, &Cell::new(Stage::Dial)).await
+    }
+}
+
+// the optional settings of a chain outbound:
+// `{"hops": [{"protocol": "socks", "address": "1.2.3.4", "port": 1080}],
+// "handshakeTimeout": "8s"}`. hops also take `user` and `pass`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChainSettings {
+    pub hops: Vec<Hop>,
+    // from the start of the dial to the vmess answer, 0 for no limit
+    pub handshake_timeout: Option<Duration>,
+}
+
+impl Default for ChainSettings {
+    fn default() -> Self {
+        Self {
+            hops: Vec::new(),
+            handshake_timeout: Some(DEFAULT_HANDSHAKE_TIMEOUT),
+        }
+    }
+}
+
+impl ChainSettings {
+    pub fn from_json(value: &Value, path: &str) -> std::result::Result<Self, Vec<ConfigError>> {
+        let Some(object) = value.as_object() else {
+            return Err(vec![ConfigError::new(path, "expected an object")]);
+        };
+
+        let mut errors = Vec::new();
+        let mut settings = Self::default();
+        for (key, value) in object {
+            let path = format!("{path}.{key}");
+            match key.as_str() {
+                "hops" => match value.as_array() {
+                    Some(hops) => {
+                        for (i, hop) in hops.iter().enumerate() {
+                            match hop_from_json(hop, &format!("{path}[{i}]")) {
+                                Ok(x) => settings.hops.push(x),
+                                Err(e) => errors.extend(e),
+                            }
+                        }
+                    }
+                    None => errors.push(ConfigError::new(&path, "expected an array")),
+                },
+                "handshakeTimeout" => match parse_duration(value) {
+                    Ok(x) => settings.handshake_timeout = Some(x).filter(|x| !x.is_zero()),
+                    Err(e) => errors.push(ConfigError::new(&path, &e)),
+                },
+                _ => errors.push(ConfigError::new(&path, "unknown field")),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(settings)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+fn hop_from_json(value: &Value, path: &str) -> std::result::Result<Hop, Vec<ConfigError>> {
+    let Some(object) = value.as_object() else {
+        return Err(vec![ConfigError::new(path, "expected an object")]);
+    };
+
+    let mut errors = Vec::new();
+    let (mut protocol, mut address, mut port) = (None, None, None);
+    let (mut user, mut pass) = (None, None);
+    for (key, value) in object {
+        let path = format!("{path}.{key}");
+        match key.as_str() {
+            "protocol" => match value.as_str() {
+                Some(x @ ("socks" | "http")) => protocol = Some(x),
+                _ => errors.push(ConfigError::new(&path, "expected socks or http")),
+            },
+            "address" => match value.as_str() {
+                Some(x) if !x.is_empty() => address = Some(x.to_string()),
+                _ => errors.push(ConfigError::new(&path, "expected a non-empty string")),
+            },
+            "port" => match value.as_u64().and_then(|x| u16::try_from(x).ok()) {
+                Some(x) if x > 0 => port = Some(x),
+                _ => errors.push(ConfigError::new(&path, "expected a port")),
+            },
+            "user" | "pass" => match value.as_str() {
+                Some(x) if x.len() <= 255 => match key.as_str() {
+                    "user" => user = Some(x.to_string()),
+                    _ => pass = Some(x.to_string()),
+                },
+                _ => errors.push(ConfigError::new(&path, "expected at most 255 bytes")),
+            },
+            _ => errors.push(ConfigError::new(&path, "unknown field")),
+        }
+    }
+    for key in ["address", "port", "protocol"] {
+        if !object.contains_key(key) {
+            errors.push(ConfigError::new(&format!("{path}.{key}"), "missing"));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let server = Target::new(
+        address.unwrap_or_default(),
+        port.unwrap_or_default(),
+        Network::Tcp,
+    );
+    let auth = user.map(|x| (x, pass.unwrap_or_default()));
+    Ok(match protocol {
+        Some("http") => Hop::Http { server, auth },
+        _ => Hop::Socks5 { server, auth },
+    })
+}
+
+// vmess to a server that is only reached through the chain's proxies
+pub struct ChainOutbound {
+    connector: VmessConnector,
+    chain: ChainDialer,
+    policy: Policy,
+    handshake_timeout: Option<Duration>,
+}
+
+impl ChainOutbound {
+    // the chain replaces the connector's own dialer
+    pub fn new(connector: VmessConnector, chain: ChainDialer) -> Self {
+        Self {
+            connector,
+            chain,
+            policy: Policy::default(),
+            handshake_timeout: Some(DEFAULT_HANDSHAKE_TIMEOUT),
+        }
+    }
+
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    // none lets a handshake take as long as the proxies do
+    pub fn with_handshake_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    // everything from the dial to the vmess answer. when the deadline hits
+    // first the partial connection is dropped with the future.
+    async fn connect(&self, target: &Target) -> Result<VmessStream<BoxStream>> {
+        let stage = Cell::new(Stage::Dial);
+        let server = self.connector.server();
+        let handshake = async {
+            let stream = self.chain.dial_tracked(server, &stage).await?;
+            stage.set(Stage::Vmess);
+            Ok(self.connector.handshake_tcp(stream, target).await?)
+        };
+        let Some(timeout) = self.handshake_timeout else {
+            return handshake.await;
+        };
+        let raced = future::select(Box::pin(handshake), Box::pin(time::sleep(timeout))).await;
+        match raced {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => {
+                let message = format!(
+                    "handshake timed out after {timeout:?} during {}",
+                    self.chain.describe(stage.get(), server)
+                );
+                let e = Error::Io(io::Error::new(io::ErrorKind::TimedOut, message));
+                metrics::shared().dial_error("chain", &e);
+                Err(e)
+            }
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Outbound for ChainOutbound {
+    async fn dispatch(&self, target: &Target, stream: &mut dyn AsyncStream) -> Result<()> {
+        if target.network == Network::Udp {
+            return Err(Error::RustError(format!(
+                "can not dispatch {target}, the chain is tcp only"
+            )));
+        }
+        let mut remote = self.connect(target).await?;
+        relay_bidirectional(stream, &mut remote, self.policy.timeouts(Network::Tcp)).await?;
+        Ok(())
+    }
+
+    fn supports_udp(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::error::Retryable;
+    use crate::proxy::vmess::chunk::Security;
+    use crate::proxy::vmess::users::UserTable;
+    use crate::proxy::vmess::{open_vmess_header, seal_response_header};
+    use md5::Digest;
+    use sha2::Sha256;
+    use std::cell::RefCell;
+    use tokio::io::DuplexStream;
+    use uuid::Uuid;
+
+    const UUID: &str = "f282b878-8711-45a1-8c69-5564172123c1";
+
+    // hands out the client half of a duplex and remembers where it went
+    struct Pipe(RefCell<Option<DuplexStream>>, RefCell<Vec<Target>>);
+
+    #[async_trait(?Send)]
+    impl Dialer for Pipe {
+        async fn dial(&self, target: &Target) -> Result<BoxStream> {
+            self.1.borrow_mut().push(target.clone());
+            let stream = self.0.borrow_mut().take();
+            stream
+                .map(|x| Box::new(x) as BoxStream)
+                .ok_or_else(|| Error::RustError("dialed twice".to_string()))
+        }
+    }
+
+    fn pipe() -> (Rc<Pipe>, DuplexStream) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let pipe = Pipe(RefCell::new(Some(client)), RefCell::new(Vec::new()));
+        (Rc::new(pipe), server)
+    }
+
+    // a socks5 proxy that wants a password and serves one connect,
+    // returning where it was asked to go
+    async fn socks5(stream: &mut DuplexStream) -> Vec<u8> {
+        let mut greeting = [0u8; 4];
+        stream.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(greeting, [0x05, 0x02, 0x00, 0x02]);
+        stream.write_all(&[0x05, 0x02]).await.unwrap();
+        let mut auth = [0u8; 11];
+        stream.read_exact(&mut auth).await.unwrap();
+        assert_eq!(&auth, b"\x01\x04user\x04pass");
+        stream.write_all(&[0x01, 0x00]).await.unwrap();
+
+        let mut request = [0u8; 5];
+        stream.read_exact(&mut request).await.unwrap();
+        assert_eq!(request[..4], [0x05, 0x01, 0x00, 0x03]);
+        let mut addr = vec![0u8; request[4] as usize + 2];
+        stream.read_exact(&mut addr).await.unwrap();
+        stream
+            .write_all(&[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0x04, 0x38])
+            .await
+            .unwrap();
+        addr
+    }
+
+    // the vmess server behind the proxy, echoing the body
+    async fn vmess_echo(mut stream: DuplexStream) {
+        let users = UserTable::new(Uuid::parse_str(UUID).unwrap());
+        let cmd = open_vmess_header(&mut stream, &users).await.unwrap();
+        let (iv, key) = (&cmd[1..17], &cmd[17..33]);
+        let response_key = &crate::sha256!(key)[..16];
+        let response_iv = &crate::sha256!(iv)[..16];
+        let header = seal_response_header(response_key, response_iv, cmd[33]).unwrap();
+        stream.write_all(&header).await.unwrap();
+
+        let security = Security::from_byte(cmd[35]).unwrap();
+        let mut stream = VmessStream::new(
+            stream,
+            security,
+            cmd[34],
+            key,
+            iv,
+            response_key,
+            response_iv,
+        )
+        .unwrap();
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            stream.write_all(&buf[..n]).await.unwrap();
+        }
+        let _ = stream.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_vmess_over_socks5() {
+        let (dialer, mut server) = pipe();
+        let hop = Hop::Socks5 {
+            server: Target::new("127.0.0.1".to_string(), 1080, Network::Tcp),
+            auth: Some(("user".to_string(), "pass".to_string())),
+        };
+        let chain = ChainDialer::new(vec![hop]).with_dialer(dialer.clone());
+        let vmess = Target::new("vmess.example.com".to_string(), 443, Network::Tcp);
+        let uuid = Uuid::parse_str(UUID).unwrap();
+        let connector = VmessConnector::new(vmess, uuid, Security::Aes128Gcm);
+        let outbound = ChainOutbound::new(connector, chain);
+
+        let serving = async {
+            let addr = socks5(&mut server).await;
+            assert_eq!(addr, b"vmess.example.com\x01\xbb");
+            vmess_echo(server).await;
+        };
+        let (mut client, mut inbound) = tokio::io::duplex(1024);
+        let target = Target::new("echo.local".to_string(), 7, Network::Tcp);
+        let dispatching = async {
+            outbound.dispatch(&target, &mut inbound).await.unwrap();
+        };
+        let talking = async {
+            client.write_all(b"hello through the chain").await.unwrap();
+            let mut echoed = [0u8; 23];
+            client.read_exact(&mut echoed).await.unwrap();
+            client.shutdown().await.unwrap();
+            echoed
+        };
+        let ((), (), echoed) = tokio::join!(serving, dispatching, talking);
+        assert_eq!(&echoed, b"hello through the chain");
+        assert_eq!(dialer.1.borrow()[0].addr, "127.0.0.1");
+        assert!(!outbound.supports_udp());
+    }
+
+    #[tokio::test]
+    async fn test_chain_hops() {
+        let (dialer, mut server) = pipe();
+        let hops = vec![
+            Hop::Http {
+                server: Target::new("proxy.example.com".to_string(), 8080, Network::Tcp),
+                auth: Some(("user".to_string(), "pass".to_string())),
+            },
+            Hop::Socks5 {
+                server: Target::new("::1".to_string(), 1080, Network::Tcp),
+                auth: None,
+            },
+        ];
+        let chain = ChainDialer::new(hops).with_dialer(dialer.clone());
+        let target = Target::new("1.2.3.4".to_string(), 80, Network::Tcp);
+
+        let serving = async {
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(server.read_u8().await.unwrap());
+            }
+            assert_eq!(
+                String::from_utf8(request).unwrap(),
+                "CONNECT [::1]:1080 HTTP/1.1\r\nHost: [::1]:1080\r\n\
+                 Proxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n"
+            );
+            server
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await
+                .unwrap();
+
+            // the socks5 hop now talks through the http tunnel
+            let mut greeting = [0u8; 3];
+            server.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [0x05, 0x01, 0x00]);
+            server.write_all(&[0x05, 0x00]).await.unwrap();
+            let mut request = [0u8; 10];
+            server.read_exact(&mut request).await.unwrap();
+            assert_eq!(request, [0x05, 0x01, 0x00, 0x01, 1, 2, 3, 4, 0x00, 0x50]);
+            server
+                .write_all(&[0x05, 0x05, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+        };
+        let (_, dialed) = tokio::join!(serving, chain.dial(&target));
+        let Some(Error::Io(e)) = dialed.err() else {
+            panic!("expected an io error");
+        };
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(e.is_retryable());
+        assert_eq!(
+            e.to_string(),
+            "socks5 proxy tcp:::1:1080: connecting to tcp:1.2.3.4:80: connection refused"
+        );
+        assert_eq!(dialer.1.borrow()[0].addr, "proxy.example.com");
+
+        let empty = ChainDialer::new(Vec::new()).with_dialer(pipe().0);
+        let udp = Target::new("1.1.1.1".to_string(), 53, Network::Udp);
+        assert!(empty.dial(&udp).await.is_ok());
+    }
+
+    // never connects
+    struct Stall;
+
+    #[async_trait(?Send)]
+    impl Dialer for Stall {
+        async fn dial(&self, _: &Target) -> Result<BoxStream> {
+            future::pending().await
+        }
+    }
+
+    fn stalling(dialer: Rc<dyn Dialer>) -> ChainOutbound {
+        let hop = Hop::Socks5 {
+            server: Target::new("127.0.0.1".to_string(), 1080, Network::Tcp),
+            auth: Some(("user".to_string(), "pass".to_string())),
+        };
+        let chain = ChainDialer::new(vec![hop]).with_dialer(dialer);
+        let vmess = Target::new("vmess.example.com".to_string(), 443, Network::Tcp);
+        let uuid = Uuid::parse_str(UUID).unwrap();
+        let connector = VmessConnector::new(vmess, uuid, Security::Aes128Gcm);
+        ChainOutbound::new(connector, chain).with_handshake_timeout(Some(Duration::from_secs(8)))
+    }
+
+    async fn timed_out(outbound: &ChainOutbound) -> String {
+        let start = tokio::time::Instant::now();
+        let target = Target::new("echo.local".to_string(), 7, Network::Tcp);
+        let (_client, mut inbound) = tokio::io::duplex(1024);
+        let e = outbound.dispatch(&target, &mut inbound).await.unwrap_err();
+        assert_eq!(start.elapsed(), Duration::from_secs(8));
+        let Error::Io(e) = e else {
+            panic!("expected an io error");
+        };
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        e.to_string()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_handshake_timeout() {
+        let outbound = stalling(Rc::new(Stall));
+        assert_eq!(
+            timed_out(&outbound).await,
+            "handshake timed out after 8s during dialing tcp:127.0.0.1:1080"
+        );
+
+        // the proxy takes the greeting and never answers
+        let (dialer, mut server) = pipe();
+        let outbound = stalling(dialer);
+        assert_eq!(
+            timed_out(&outbound).await,
+            "handshake timed out after 8s during the socks5 handshake with hop 1 \
+             (tcp:127.0.0.1:1080)"
+        );
+        // and the half made connection went with the handshake
+        let mut sent = Vec::new();
+        server.read_to_end(&mut sent).await.unwrap();
+        assert_eq!(sent, [0x05, 0x02, 0x00, 0x02]);
+
+        // the tunnel is up, the vmess server never answers
+        let (dialer, mut server) = pipe();
+        let outbound = stalling(dialer);
+        let serving = async {
+            socks5(&mut server).await;
+            let mut sent = Vec::new();
+            server.read_to_end(&mut sent).await.unwrap();
+            assert!(!sent.is_empty());
+        };
+        let (e, ()) = tokio::join!(timed_out(&outbound), serving);
+        assert_eq!(
+            e,
+            "handshake timed out after 8s during the vmess handshake with \
+             tcp:vmess.example.com:443"
+        );
+
+        // without a deadline it runs until the server gives up
+        let (dialer, mut server) = pipe();
+        let outbound = stalling(dialer).with_handshake_timeout(None);
+        let target = Target::new("echo.local".to_string(), 7, Network::Tcp);
+        let start = tokio::time::Instant::now();
+        let serving = async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            socks5(&mut server).await;
+            // takes the header and hangs up
+            server.read_exact(&mut [0u8; 16]).await.unwrap();
+            drop(server);
+        };
+        let (connected, ()) = tokio::join!(outbound.connect(&target), serving);
+        let e = connected.err().unwrap().to_string();
+        assert_eq!(e, "IO Error: the server rejected the request");
+        assert_eq!(start.elapsed(), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_chain_settings() {
+        let settings = ChainSettings::from_json(
+            &serde_json::json!({
+                "hops": [
+                    {"protocol": "http", "address": "proxy.example.com", "port": 8080},
+                    {"protocol": "socks", "address": "::1", "port": 1080, "user": "u", "pass": "p"}
+                ],
+                "handshakeTimeout": "1500ms"
+            }),
+            "CHAIN",
+        )
+        .unwrap();
+        assert_eq!(
+            settings.handshake_timeout,
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(
+            settings.hops[1],
+            Hop::Socks5 {
+                server: Target::new("::1".to_string(), 1080, Network::Tcp),
+                auth: Some(("u".to_string(), "p".to_string())),
+            }
+        );
+        assert_eq!(settings.hops[0].server().port, 8080);
+
+        let settings =
+            ChainSettings::from_json(&serde_json::json!({"handshakeTimeout": 0}), "CHAIN");
+        assert_eq!(settings.unwrap().handshake_timeout, None);
+        assert_eq!(
+            ChainSettings::default().handshake_timeout,
+            Some(Duration::from_secs(8))
+        );
+
+        let errors = ChainSettings::from_json(
+            &serde_json::json!({
+                "hops": [{"protocol": "vmess", "port": 0}],
+                "handshakeTimeout": "8h",
+                "retries": 1
+            }),
+            "CHAIN",
+        )
+        .unwrap_err();
+        let paths: Vec<_> = errors.iter().map(|x| x.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "CHAIN.handshakeTimeout",
+                "CHAIN.hops[0].port",
+                "CHAIN.hops[0].protocol",
+                "CHAIN.hops[0].address",
+                "CHAIN.retries"
+            ]
+        );
+    }
+}
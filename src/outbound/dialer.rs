@@ -0,0 +1,239 @@
+use super::{AsyncStream, Network, Outbound, Target};
+use crate::app::metrics;
+use crate::common::task;
+
+use async_trait::async_trait;
+use std::io;
+use std::rc::Rc;
+use worker::*;
+
+// buffered between an outer hop and the outbound it dials through
+const CHAIN_BUFFER_SIZE: usize = 64 * 1024;
+
+pub type BoxStream = Box<dyn AsyncStream>;
+
+// how an outbound reaches its next hop
+#[async_trait(?Send)]
+pub trait Dialer {
+    async fn dial(&self, target: &Target) -> Result<BoxStream>;
+}
+
+// a worker socket, the default
+pub struct SocketDialer;
+
+#[async_trait(?Send)]
+impl Dialer for SocketDialer {
+    async fn dial(&self, target: &Target) -> Result<BoxStream> {
+        let dial = async {
+            if target.network == Network::Udp {
+                return Err(Error::RustError(format!(
+                    "can not dial {target}, worker sockets are tcp only"
+                )));
+            }
+            // whatever the socket says, the address did not take the
+            // connection and might next time
+            let refused = |e: Error| {
+                Error::Io(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    e.to_string(),
+                ))
+            };
+            let socket = Socket::builder()
+                .connect(&target.addr, target.port)
+                .map_err(refused)?;
+            socket.opened().await.map_err(refused)?;
+            Ok(socket)
+        };
+        match dial.await {
+            Ok(socket) => Ok(Box::new(socket)),
+            Err(e) => {
+                metrics::shared().dial_error("socket", &e);
+                Err(e)
+            }
+        }
+    }
+}
+
+// v2ray's `dialerProxy`: the connection to the next hop is itself carried
+// by another outbound, which sees that hop as its target
+pub struct ProxyDialer {
+    tag: String,
+    outbound: Rc<dyn Outbound>,
+}
+
+impl ProxyDialer {
+    pub fn new(tag: &str, outbound: Rc<dyn Outbound>) -> Self {
+        Self {
+            tag: tag.to_string(),
+            outbound,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Dialer for ProxyDialer {
+    async fn dial(&self, target: &Target) -> Result<BoxStream> {
+        if target.network == Network::Udp && !self.outbound.supports_udp() {
+            let e = Error::RustError(format!(
+                "can not dial {target} through {}, it does not carry udp",
+                self.tag
+            ));
+            metrics::shared().dial_error(&self.tag, &e);
+            return Err(e);
+        }
+
+        // the inner outbound runs on its own until either side hangs up
+        let (client, mut server) = tokio::io::duplex(CHAIN_BUFFER_SIZE);
+        let (tag, outbound, target) = (self.tag.clone(), self.outbound.clone(), target.clone());
+        task::spawn(async move {
+            if let Err(e) = outbound.dispatch(&target, &mut server).await {
+                crate::log_error!("[dialer]: {} via {}: {}", target, tag, e);
+                metrics::shared().dial_error(&tag, &e);
+            }
+        });
+        Ok(Box::new(client))
+    }
+}
+
+// the first loop among `dialerProxy` settings, as the tags along it. tags
+// that are not registered end a chain, they are reported elsewhere.
+pub fn find_cycle(chains: &[(&str, Option<&str>)]) -> Option<Vec<String>> {
+    let next = |tag: &str| chains.iter().find(|(t, _)| *t == tag).and_then(|(_, x)| *x);
+    for (start, _) in chains {
+        let mut path = vec![start.to_string()];
+        let mut tag = *start;
+        while let Some(x) = next(tag) {
+            path.push(x.to_string());
+            if let Some(i) = path[..path.len() - 1].iter().position(|t| t == x) {
+                return Some(path.split_off(i));
+            }
+            tag = x;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    type Seen = Rc<RefCell<Vec<(String, Target, Vec<u8>)>>>;
+
+    // a hop with its own server address: everything the client sends goes
+    // to that server prefixed with the hop's name, and the answer comes
+    // back the same way. the last hop answers itself.
+    struct Hop {
+        name: &'static str,
+        server: Target,
+        dialer: Option<Rc<dyn Dialer>>,
+        udp: bool,
+        seen: Seen,
+    }
+
+    #[async_trait(?Send)]
+    impl Outbound for Hop {
+        async fn dispatch(&self, target: &Target, stream: &mut dyn AsyncStream) -> Result<()> {
+            let mut data = vec![0u8; 1024];
+            let n = stream.read(&mut data).await?;
+            data.truncate(n);
+            self.seen
+                .borrow_mut()
+                .push((self.name.to_string(), target.clone(), data.clone()));
+
+            let Some(dialer) = &self.dialer else {
+                stream.write_all(b"pong").await?;
+                return Ok(());
+            };
+            let mut remote = dialer.dial(&self.server).await?;
+            remote
+                .write_all(format!("{}|", self.name).as_bytes())
+                .await?;
+            remote.write_all(&data).await?;
+            let mut response = vec![0u8; 1024];
+            let n = remote.read(&mut response).await?;
+            stream.write_all(&response[..n]).await?;
+            Ok(())
+        }
+
+        fn supports_udp(&self) -> bool {
+            self.udp
+        }
+    }
+
+    fn hop(name: &'static str, dialer: Option<Rc<dyn Dialer>>, seen: &Seen) -> Rc<Hop> {
+        let server = Target::new(format!("{name}.example.net"), 443, Network::Tcp);
+        Rc::new(Hop {
+            name,
+            server,
+            dialer,
+            udp: true,
+            seen: seen.clone(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_chained_dial() {
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let seen = Seen::default();
+                let c = hop("c", None, &seen);
+                let b = hop("b", Some(Rc::new(ProxyDialer::new("c", c))), &seen);
+                let a = hop("a", Some(Rc::new(ProxyDialer::new("b", b))), &seen);
+
+                let target = Target::new("example.com".to_string(), 80, Network::Tcp);
+                let (mut client, mut server) = tokio::io::duplex(1024);
+                client.write_all(b"ping").await.unwrap();
+                a.dispatch(&target, &mut server).await.unwrap();
+                let mut response = vec![0u8; 4];
+                client.read_exact(&mut response).await.unwrap();
+                assert_eq!(response, b"pong");
+
+                // every hop got the one before it as its target and the
+                // bytes wrapped by each hop in order
+                let hop = |name: &str, addr: &str, data: &[u8]| {
+                    let port = if addr == "example.com" { 80 } else { 443 };
+                    let target = Target::new(addr.to_string(), port, Network::Tcp);
+                    (name.to_string(), target, data.to_vec())
+                };
+                assert_eq!(
+                    *seen.borrow(),
+                    [
+                        hop("a", "example.com", b"ping"),
+                        hop("b", "a.example.net", b"a|ping"),
+                        hop("c", "b.example.net", b"b|a|ping"),
+                    ]
+                );
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_udp_needs_support() {
+        let seen = Seen::default();
+        let tcp_only = Rc::new(Hop {
+            udp: false,
+            ..Rc::into_inner(hop("tcp", None, &seen)).unwrap()
+        });
+        let dialer = ProxyDialer::new("tcp", tcp_only);
+        let target = Target::new("1.1.1.1".to_string(), 53, Network::Udp);
+        let e = dialer.dial(&target).await.err().unwrap();
+        assert!(e.to_string().contains("does not carry udp"), "{e}");
+    }
+
+    #[test]
+    fn test_find_cycle() {
+        assert_eq!(find_cycle(&[("a", Some("b")), ("b", None)]), None);
+        assert_eq!(find_cycle(&[("a", Some("missing"))]), None);
+        assert_eq!(
+            find_cycle(&[("a", Some("b")), ("b", Some("c")), ("c", Some("b"))]).unwrap(),
+            ["b", "c", "b"]
+        );
+        assert_eq!(
+            find_cycle(&[("direct", Some("direct"))]).unwrap(),
+            ["direct", "direct"]
+        );
+    }
+}
@@ -0,0 +1,471 @@
+use super::fragment::{Fragment, FragmentDialer};
+use super::{AsyncStream, Dialer, Network, Outbound, SocketDialer, Target};
+use crate::app::dns::QueryStrategy;
+use crate::app::policy::Policy;
+use crate::app::router::Resolve;
+use crate::common::relay::relay_bidirectional;
+use crate::config::ConfigError;
+use crate::proxy::{relay_tcp_outbound, relay_udp_outbound};
+
+use async_trait::async_trait;
+use serde_json::Value;
+use std::net::IpAddr;
+use std::rc::Rc;
+use worker::*;
+
+// v2ray's freedom `domainStrategy`: whether domains are resolved by the
+// worker before dialing, and which family goes first
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DomainStrategy {
+    // the socket resolves the domain itself
+    #[default]
+    AsIs,
+    // whatever the dns binding's `queryStrategy` asks for
+    UseIP,
+    UseIPv4,
+    UseIPv6,
+    PreferIPv4,
+    PreferIPv6,
+}
+
+impl DomainStrategy {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "AsIs" => Some(Self::AsIs),
+            "UseIP" => Some(Self::UseIP),
+            "UseIPv4" => Some(Self::UseIPv4),
+            "UseIPv6" => Some(Self::UseIPv6),
+            "PreferIPv4" => Some(Self::PreferIPv4),
+            "PreferIPv6" => Some(Self::PreferIPv6),
+            _ => None,
+        }
+    }
+
+    async fn resolve(&self, resolver: &dyn Resolve, domain: &str) -> Result<Vec<IpAddr>> {
+        let strategy = match self {
+            Self::AsIs => return Ok(Vec::new()),
+            Self::UseIP => return resolver.resolve(domain).await,
+            Self::UseIPv4 => QueryStrategy::UseIPv4,
+            Self::UseIPv6 => QueryStrategy::UseIPv6,
+            Self::PreferIPv4 => QueryStrategy::PreferIPv4,
+            Self::PreferIPv6 => QueryStrategy::PreferIPv6,
+        };
+        resolver.resolve_with(domain, strategy).await
+    }
+}
+
+// v2ray's freedom `redirect`: every connection goes to `host:port`
+// instead of its target. `:port` keeps the host, port 0 the port.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Redirect {
+    pub addr: Option<String>,
+    pub port: Option<u16>,
+}
+
+impl Redirect {
+    pub fn parse(s: &str) -> Option<Self> {
+        let (host, port) = s.rsplit_once(':')?;
+        let port: u16 = port.parse().ok()?;
+        let host = host
+            .strip_prefix('[')
+            .and_then(|x| x.strip_suffix(']'))
+            .unwrap_or(host);
+        if host.is_empty() && port == 0 {
+            return None;
+        }
+        Some(Self {
+            addr: (!host.is_empty()).then(|| host.to_string()),
+            port: (port != 0).then_some(port),
+        })
+    }
+
+    pub fn apply(&self, target: &Target) -> Target {
+        Target::new(
+            self.addr.clone().unwrap_or_else(|| target.addr.clone()),
+            self.port.unwrap_or(target.port),
+            target.network,
+        )
+    }
+}
+
+// the optional `FREEDOM` binding, v2ray's freedom outbound settings
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Freedom {
+    pub domain_strategy: DomainStrategy,
+    // tag of the outbound connections are dialed through
+    pub dialer_proxy: Option<String>,
+    pub redirect: Option<Redirect>,
+    // xray's extension, tcp only
+    pub fragment: Option<Fragment>,
+}
+
+impl Freedom {
+    pub fn from_json(value: &Value, path: &str) -> std::result::Result<Self, Vec<ConfigError>> {
+        let Some(object) = value.as_object() else {
+            return Err(vec![ConfigError::new(path, "expected an object")]);
+        };
+
+        let mut errors = Vec::new();
+        let mut freedom = Freedom::default();
+        for (key, value) in object {
+            let path = format!("{path}.{key}");
+            match key.as_str() {
+                "domainStrategy" => match value.as_str().and_then(DomainStrategy::parse) {
+                    Some(x) => freedom.domain_strategy = x,
+                    None => errors.push(ConfigError::new(
+                        &path,
+                        "expected AsIs, UseIP, UseIPv4, UseIPv6, PreferIPv4 or PreferIPv6",
+                    )),
+                },
+                "dialerProxy" => match value.as_str() {
+                    Some(x) if !x.is_empty() => freedom.dialer_proxy = Some(x.to_string()),
+                    _ => errors.push(ConfigError::new(&path, "expected a non-empty string")),
+                },
+                "redirect" => match value.as_str().and_then(Redirect::parse) {
+                    Some(x) => freedom.redirect = Some(x),
+                    None => errors.push(ConfigError::new(&path, "expected host:port or :port")),
+                },
+                "fragment" => match Fragment::from_json(value, &path) {
+                    Ok(x) => freedom.fragment = Some(x),
+                    Err(e) => errors.extend(e),
+                },
+                _ => errors.push(ConfigError::new(&path, "unknown field")),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(freedom)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+// connects to the target from the worker itself. when a fallback is set
+// (the proxy ip) it is tried after the target, same as before outbounds
+// existed.
+pub struct DirectOutbound {
+    fallback: Option<(String, u16)>,
+    domain_strategy: DomainStrategy,
+    resolver: Option<Rc<dyn Resolve>>,
+    // worker sockets when unset
+    dialer: Option<Rc<dyn Dialer>>,
+    policy: Policy,
+    redirect: Option<Redirect>,
+    fragment: Option<Fragment>,
+}
+
+impl DirectOutbound {
+    pub fn new(fallback: Option<(String, u16)>) -> Self {
+        Self {
+            fallback,
+            domain_strategy: DomainStrategy::AsIs,
+            resolver: None,
+            dialer: None,
+            policy: Policy::default(),
+            redirect: None,
+            fragment: None,
+        }
+    }
+
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    // with a dialer udp goes through it too, instead of the doh relay
+    pub fn with_dialer(mut self, dialer: Rc<dyn Dialer>) -> Self {
+        self.dialer = Some(dialer);
+        self
+    }
+
+    pub fn with_redirect(mut self, redirect: Redirect) -> Self {
+        self.redirect = Some(redirect);
+        self
+    }
+
+    pub fn with_fragment(mut self, fragment: Fragment) -> Self {
+        self.fragment = Some(fragment);
+        self
+    }
+
+    pub fn with_domain_strategy(
+        mut self,
+        strategy: DomainStrategy,
+        resolver: Rc<dyn Resolve>,
+    ) -> Self {
+        self.domain_strategy = strategy;
+        self.resolver = Some(resolver);
+        self
+    }
+
+    // where tcp connections are attempted, in order. a domain that fails to
+    // resolve is left to the socket.
+    pub async fn addresses(&self, target: &Target) -> Vec<(String, u16)> {
+        let resolved = match &self.resolver {
+            Some(resolver) if target.addr.parse::<IpAddr>().is_err() => {
+                match self
+                    .domain_strategy
+                    .resolve(resolver.as_ref(), &target.addr)
+                    .await
+                {
+                    Ok(x) => x,
+                    Err(e) => {
+                        crate::log_error!("[direct]: resolving {}: {}", target.addr, e);
+                        Vec::new()
+                    }
+                }
+            }
+            _ => Vec::new(),
+        };
+
+        let targets: Vec<_> = match resolved.is_empty() {
+            true => vec![(target.addr.clone(), target.port)],
+            false => resolved
+                .iter()
+                .map(|x| (x.to_string(), target.port))
+                .collect(),
+        };
+        targets.into_iter().chain(self.fallback.clone()).collect()
+    }
+}
+
+#[async_trait(?Send)]
+impl Outbound for DirectOutbound {
+    async fn dispatch(&self, target: &Target, stream: &mut dyn AsyncStream) -> Result<()> {
+        let redirected = self.redirect.as_ref().map(|x| x.apply(target));
+        let target = redirected.as_ref().unwrap_or(target);
+        if target.network == Network::Udp {
+            if let Some(dialer) = &self.dialer {
+                // the domain strategy picks the address, udp is not raced
+                let (addr, port) = self.addresses(target).await.remove(0);
+                let mut remote = dialer.dial(&Target::new(addr, port, Network::Udp)).await?;
+                let timeouts = self.policy.timeouts(Network::Udp);
+                relay_bidirectional(stream, &mut remote, timeouts).await?;
+                return Ok(());
+            }
+            if let Err(e) = relay_udp_outbound(stream).await {
+                crate::log_error!("error handling udp: {}", e)
+            }
+            return Ok(());
+        }
+
+        let mut dialer = self.dialer.clone().unwrap_or_else(|| Rc::new(SocketDialer));
+        if let Some(fragment) = self.fragment {
+            dialer = Rc::new(FragmentDialer::new(dialer, fragment));
+        }
+        let mut addresses = self.addresses(target).await;
+        // the target's addresses race each other, the proxy ip is only tried
+        // after them
+        let fallback = match self.fallback {
+            Some(_) => addresses.pop(),
+            None => None,
+        };
+        let tcp = |(addr, port)| Target::new(addr, port, Network::Tcp);
+        let racing: Vec<_> = addresses.into_iter().map(tcp).collect();
+        let fallback: Vec<_> = fallback.into_iter().map(tcp).collect();
+        let timeouts = self.policy.timeouts(Network::Tcp);
+        for targets in [racing, fallback].into_iter().filter(|x| !x.is_empty()) {
+            if let Err(e) = relay_tcp_outbound(stream, dialer.as_ref(), targets, timeouts).await {
+                crate::log_error!("error handling tcp: {}", e)
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    // answers with both families, the way a dual stack resolver would
+    struct MockResolver;
+
+    #[async_trait(?Send)]
+    impl Resolve for MockResolver {
+        async fn resolve(&self, _: &str) -> Result<Vec<IpAddr>> {
+            Ok(vec![
+                Ipv4Addr::new(192, 0, 2, 1).into(),
+                Ipv4Addr::new(192, 0, 2, 2).into(),
+                Ipv4Addr::new(192, 0, 2, 3).into(),
+                Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into(),
+                Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2).into(),
+            ])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dial_order() {
+        let target = &Target::new("example.com".to_string(), 443, Network::Tcp);
+        let order = |strategy| async move {
+            let outbound = DirectOutbound::new(Some(("proxy.example.net".to_string(), 443)))
+                .with_domain_strategy(strategy, Rc::new(MockResolver));
+            let addresses = outbound.addresses(target).await;
+            addresses
+                .into_iter()
+                .map(|(addr, _)| addr)
+                .collect::<Vec<_>>()
+        };
+
+        let cases: &[(DomainStrategy, &[&str])] = &[
+            (DomainStrategy::AsIs, &["example.com"]),
+            (
+                DomainStrategy::UseIP,
+                &[
+                    "192.0.2.1",
+                    "192.0.2.2",
+                    "192.0.2.3",
+                    "2001:db8::1",
+                    "2001:db8::2",
+                ],
+            ),
+            (
+                DomainStrategy::UseIPv4,
+                &["192.0.2.1", "192.0.2.2", "192.0.2.3"],
+            ),
+            (DomainStrategy::UseIPv6, &["2001:db8::1", "2001:db8::2"]),
+            (
+                DomainStrategy::PreferIPv4,
+                &[
+                    "192.0.2.1",
+                    "2001:db8::1",
+                    "192.0.2.2",
+                    "2001:db8::2",
+                    "192.0.2.3",
+                ],
+            ),
+            (
+                DomainStrategy::PreferIPv6,
+                &[
+                    "2001:db8::1",
+                    "192.0.2.1",
+                    "2001:db8::2",
+                    "192.0.2.2",
+                    "192.0.2.3",
+                ],
+            ),
+        ];
+        for (strategy, expected) in cases {
+            let mut expected = expected.to_vec();
+            expected.push("proxy.example.net");
+            assert_eq!(order(*strategy).await, expected, "{strategy:?}");
+        }
+
+        // ip targets are dialed as they are
+        let outbound = DirectOutbound::new(None)
+            .with_domain_strategy(DomainStrategy::UseIPv6, Rc::new(MockResolver));
+        let ip = Target::new("198.51.100.7".to_string(), 80, Network::Tcp);
+        assert_eq!(
+            outbound.addresses(&ip).await,
+            [("198.51.100.7".to_string(), 80)]
+        );
+    }
+
+    // plain tcp on the host, since worker sockets only exist in the runtime
+    struct TcpDialer;
+
+    #[async_trait(?Send)]
+    impl Dialer for TcpDialer {
+        async fn dial(&self, target: &Target) -> Result<crate::outbound::dialer::BoxStream> {
+            let stream = tokio::net::TcpStream::connect((target.addr.as_str(), target.port))
+                .await
+                .map_err(|e| Error::RustError(e.to_string()))?;
+            Ok(Box::new(stream))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_echo() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let (mut reader, mut writer) = socket.split();
+            tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+        });
+
+        let outbound = DirectOutbound::new(None).with_dialer(Rc::new(TcpDialer));
+        let target = Target::new("127.0.0.1".to_string(), port, Network::Tcp);
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let client = async move {
+            client.write_all(b"hello").await.unwrap();
+            let mut echoed = [0u8; 5];
+            client.read_exact(&mut echoed).await.unwrap();
+            echoed
+        };
+        let (dispatched, echoed) = tokio::join!(outbound.dispatch(&target, &mut server), client);
+        dispatched.unwrap();
+        assert_eq!(&echoed, b"hello");
+    }
+
+    #[test]
+    fn test_redirect() {
+        let target = Target::new("example.com".to_string(), 80, Network::Tcp);
+        let cases = [
+            ("127.0.0.1:3366", "127.0.0.1", 3366),
+            (":443", "example.com", 443),
+            ("[2001:db8::1]:0", "2001:db8::1", 80),
+        ];
+        for (s, addr, port) in cases {
+            let redirected = Redirect::parse(s).unwrap().apply(&target);
+            assert_eq!(redirected, Target::new(addr.to_string(), port, Network::Tcp), "{s}");
+        }
+        for s in ["", ":0", "127.0.0.1", "127.0.0.1:http", ":65536"] {
+            assert_eq!(Redirect::parse(s), None, "{s:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_redirect_dial() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            socket.write_all(b"redirected").await.unwrap();
+        });
+
+        let redirect = Redirect::parse(&format!("127.0.0.1:{port}")).unwrap();
+        let outbound = DirectOutbound::new(None)
+            .with_dialer(Rc::new(TcpDialer))
+            .with_redirect(redirect);
+        let target = Target::new("unreachable.invalid".to_string(), 443, Network::Tcp);
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let client = async move {
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).await.unwrap();
+            received
+        };
+        let (dispatched, received) = tokio::join!(outbound.dispatch(&target, &mut server), client);
+        dispatched.unwrap();
+        assert_eq!(received, b"redirected");
+    }
+
+    #[test]
+    fn test_freedom_from_json() {
+        let freedom = Freedom::from_json(&json!({"domainStrategy": "PreferIPv6"}), "FREEDOM");
+        assert_eq!(freedom.unwrap().domain_strategy, DomainStrategy::PreferIPv6);
+
+        let errors = Freedom::from_json(
+            &json!({"dialerProxy": "", "domainStrategy": "UseIPv5", "redirect": ""}),
+            "FREEDOM",
+        )
+        .err()
+        .unwrap();
+        let paths: Vec<_> = errors.iter().map(|x| x.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "FREEDOM.dialerProxy",
+                "FREEDOM.domainStrategy",
+                "FREEDOM.redirect"
+            ]
+        );
+    }
+}
@@ -0,0 +1,226 @@
+use super::{AsyncStream, Network, Outbound, Target};
+use crate::app::dns::Resolver;
+use crate::app::router::normalize_domain;
+use crate::common::{read_u16_be, u16_be};
+
+use async_trait::async_trait;
+use hickory_proto::op::{Message, MessageType, ResponseCode};
+use hickory_proto::rr::rdata::{A, AAAA};
+use hickory_proto::rr::{DNSClass, RData, Record, RecordType};
+use std::net::IpAddr;
+use std::rc::Rc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use worker::*;
+
+// the resolver keeps the upstream ttl to itself, answers carry this one
+pub const ANSWER_TTL: u32 = 60;
+
+// answers the dns queries routed to it from the `DNS` resolver instead of
+// forwarding them. a udp read is one query, over tcp every query is behind
+// a two byte length. queries are answered until the client hangs up.
+pub struct DnsOutbound {
+    resolver: Rc<Resolver>,
+}
+
+impl DnsOutbound {
+    pub fn new(resolver: Rc<Resolver>) -> Self {
+        Self { resolver }
+    }
+
+    async fn answer(&self, query: &[u8]) -> Option<Vec<u8>> {
+        let query = Message::from_vec(query).ok()?;
+        let mut response = Message::new();
+        response
+            .set_id(query.id())
+            .set_message_type(MessageType::Response)
+            .set_op_code(query.op_code())
+            .set_recursion_desired(query.recursion_desired())
+            .set_recursion_available(true)
+            .add_queries(query.queries().to_vec());
+
+        let [question] = query.queries() else {
+            return refuse(response, ResponseCode::FormErr);
+        };
+        if query.message_type() != MessageType::Query || question.query_class() != DNSClass::IN {
+            return refuse(response, ResponseCode::FormErr);
+        }
+        let record_type = question.query_type();
+        if !matches!(record_type, RecordType::A | RecordType::AAAA) {
+            return refuse(response, ResponseCode::NotImp);
+        }
+
+        let domain = normalize_domain(&question.name().to_ascii());
+        match self.resolver.lookup(&domain, record_type).await {
+            Ok(ips) => {
+                crate::log!("[dns]: {} is {:?}", domain, ips);
+                for ip in ips {
+                    let rdata = match ip {
+                        IpAddr::V4(x) if record_type == RecordType::A => RData::A(A(x)),
+                        IpAddr::V6(x) if record_type == RecordType::AAAA => RData::AAAA(AAAA(x)),
+                        _ => continue,
+                    };
+                    response.add_answer(Record::from_rdata(
+                        question.name().clone(),
+                        ANSWER_TTL,
+                        rdata,
+                    ));
+                }
+            }
+            Err(e) => {
+                crate::log!("[dns]: {} failed: {}", domain, e);
+                response.set_response_code(ResponseCode::ServFail);
+            }
+        }
+        response.to_vec().ok()
+    }
+}
+
+fn refuse(mut response: Message, code: ResponseCode) -> Option<Vec<u8>> {
+    response.set_response_code(code);
+    response.to_vec().ok()
+}
+
+#[async_trait(?Send)]
+impl Outbound for DnsOutbound {
+    async fn dispatch(&self, target: &Target, stream: &mut dyn AsyncStream) -> Result<()> {
+        let mut buf = vec![0u8; u16::MAX.into()];
+        loop {
+            let query = match target.network {
+                Network::Udp => match stream.read(&mut buf).await? {
+                    0 => return Ok(()),
+                    n => &buf[..n],
+                },
+                Network::Tcp => {
+                    let mut len = [0u8; 2];
+                    match stream.read_exact(&mut len).await {
+                        Ok(_) => {}
+                        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+                        Err(e) => return Err(e.into()),
+                    }
+                    let len = read_u16_be(&len).into();
+                    stream.read_exact(&mut buf[..len]).await?;
+                    &buf[..len]
+                }
+            };
+            let Some(response) = self.answer(query).await else {
+                return Err(Error::RustError(format!("invalid dns query to {target}")));
+            };
+            if target.network == Network::Tcp {
+                stream
+                    .write_all(&u16_be(response.len() as u16))
+                    .await?;
+            }
+            stream.write_all(&response).await?;
+            stream.flush().await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::dns::{Server, Transport};
+    use hickory_proto::op::Query;
+    use hickory_proto::rr::Name;
+    use serde_json::json;
+    use std::cell::RefCell;
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+
+    // answers every query for an A record with 192.0.2.1, remembering
+    // which server was asked
+    struct MockServer(Rc<RefCell<Vec<String>>>);
+
+    #[async_trait(?Send)]
+    impl Transport for MockServer {
+        async fn exchange(&self, server: &Server, message: &[u8]) -> Result<Vec<u8>> {
+            self.0.borrow_mut().push(server.host.clone());
+            let query = Message::from_vec(message).unwrap();
+            let question = query.queries()[0].clone();
+            let mut answer = Message::new();
+            answer
+                .set_id(query.id())
+                .set_message_type(MessageType::Response)
+                .add_query(question.clone());
+            if question.query_type() == RecordType::A {
+                let data = RData::A(A(Ipv4Addr::new(192, 0, 2, 1)));
+                answer.add_answer(Record::from_rdata(question.name().clone(), 300, data));
+            }
+            Ok(answer.to_vec().unwrap())
+        }
+    }
+
+    fn query(name: &str, record_type: RecordType) -> Vec<u8> {
+        let mut query = Message::new();
+        query
+            .set_id(0x1234)
+            .set_recursion_desired(true)
+            .add_query(Query::query(Name::from_str(name).unwrap(), record_type));
+        query.to_vec().unwrap()
+    }
+
+    fn outbound() -> (DnsOutbound, Rc<RefCell<Vec<String>>>) {
+        let asked = Rc::default();
+        let resolver = Resolver::from_json(&json!({"servers": ["1.1.1.1"]}), "DNS")
+            .unwrap()
+            .with_cache(None)
+            .with_transport(Box::new(MockServer(Rc::clone(&asked))));
+        (DnsOutbound::new(Rc::new(resolver)), asked)
+    }
+
+    #[tokio::test]
+    async fn test_dns_outbound_udp() {
+        let (dns, asked) = outbound();
+        let target = Target::new("8.8.8.8".to_string(), 53, Network::Udp);
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        let (res, _) = tokio::join!(dns.dispatch(&target, &mut server), async {
+            client
+                .write_all(&query("example.com.", RecordType::A))
+                .await
+                .unwrap();
+            let mut buf = vec![0u8; 4096];
+            let n = client.read(&mut buf).await.unwrap();
+            let response = Message::from_vec(&buf[..n]).unwrap();
+            assert_eq!(response.id(), 0x1234);
+            assert_eq!(response.response_code(), ResponseCode::NoError);
+            let Some(RData::A(ip)) = response.answers()[0].data() else {
+                panic!("unexpected answer {response:?}");
+            };
+            assert_eq!(ip.0, Ipv4Addr::new(192, 0, 2, 1));
+
+            client
+                .write_all(&query("example.com.", RecordType::MX))
+                .await
+                .unwrap();
+            let n = client.read(&mut buf).await.unwrap();
+            let response = Message::from_vec(&buf[..n]).unwrap();
+            assert_eq!(response.response_code(), ResponseCode::NotImp);
+            drop(client);
+        });
+        res.unwrap();
+        // the configured upstream answered, not 8.8.8.8
+        assert_eq!(*asked.borrow(), ["1.1.1.1"]);
+    }
+
+    #[tokio::test]
+    async fn test_dns_outbound_tcp() {
+        let (dns, _) = outbound();
+        let target = Target::new("8.8.8.8".to_string(), 53, Network::Tcp);
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        let (res, _) = tokio::join!(dns.dispatch(&target, &mut server), async {
+            for _ in 0..2 {
+                let query = query("example.com.", RecordType::AAAA);
+                client.write_u16(query.len() as u16).await.unwrap();
+                client.write_all(&query).await.unwrap();
+                let len = client.read_u16().await.unwrap();
+                let mut buf = vec![0u8; len.into()];
+                client.read_exact(&mut buf).await.unwrap();
+                let response = Message::from_vec(&buf).unwrap();
+                assert_eq!(response.response_code(), ResponseCode::NoError);
+                assert!(response.answers().is_empty());
+            }
+            drop(client);
+        });
+        res.unwrap();
+    }
+}
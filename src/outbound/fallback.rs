@@ -0,0 +1,472 @@
+use super::{AsyncStream, Outbound, Target};
+use crate::app::dns::cache::Clock;
+use crate::common::error::Retryable;
+use crate::common::time;
+
+use async_trait::async_trait;
+use futures_util::future::{self, Either};
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use worker::*;
+
+// consecutive failures before an outbound's circuit opens
+const MAX_FAILURES: u32 = 3;
+
+pub const DEFAULT_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_COOL_DOWN: Duration = Duration::from_secs(30);
+
+// client bytes kept for replaying to the next outbound. a client that sends
+// more than this before hearing back can not be moved.
+const MAX_REPLAY: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FallbackStats {
+    // connections tried through the outbound
+    pub attempts: u64,
+    pub failures: u64,
+    // failures that handed the connection on to the next outbound
+    pub fallbacks: u64,
+}
+
+#[derive(Debug, Default)]
+struct Circuit {
+    failures: u32,
+    // set while the circuit is open. once it passes a single connection is
+    // let through to probe the outbound, and the circuit closes again if
+    // that one succeeds.
+    open_until: Option<Duration>,
+    stats: FallbackStats,
+}
+
+// the client side of one attempt. bytes read from the client are recorded
+// until the outbound writes back, replayed first to the next attempt.
+struct Attempt<'a> {
+    inner: &'a mut dyn AsyncStream,
+    recorded: &'a mut Vec<u8>,
+    pos: usize,
+    overflowed: &'a Cell<bool>,
+    eof: &'a Cell<bool>,
+    // the outbound answered, the connection is its from here on
+    committed: &'a Cell<bool>,
+}
+
+impl AsyncRead for Attempt<'_> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.pos < this.recorded.len() {
+            let n = buf.remaining().min(this.recorded.len() - this.pos);
+            buf.put_slice(&this.recorded[this.pos..this.pos + n]);
+            this.pos += n;
+            return Poll::Ready(Ok(()));
+        }
+
+        let before = buf.filled().len();
+        let res = Pin::new(&mut *this.inner).poll_read(cx, buf);
+        let read = &buf.filled()[before..];
+        if let Poll::Ready(Ok(())) = res {
+            if read.is_empty() && buf.remaining() > 0 {
+                this.eof.set(true);
+            }
+        }
+        if !this.committed.get() && !this.overflowed.get() {
+            if this.recorded.len() + read.len() > MAX_REPLAY {
+                this.overflowed.set(true);
+                *this.recorded = Vec::new();
+            } else {
+                this.recorded.extend_from_slice(read);
+            }
+            this.pos = this.recorded.len();
+        }
+        res
+    }
+}
+
+impl AsyncWrite for Attempt<'_> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut *self.inner).poll_write(cx, buf);
+        if matches!(res, Poll::Ready(Ok(n)) if n > 0) && !self.committed.replace(true) {
+            *self.recorded = Vec::new();
+            self.pos = 0;
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+// tries outbounds in order. an attempt fails when the outbound gives up or
+// times out before sending anything back to the client, and the connection
+// then moves on to the next outbound with the client's bytes replayed.
+// once a response byte went out nothing is retried, the outbound's result
+// is the connection's. neither is a failure that would happen again
+// anywhere, like a server turning down the credentials.
+pub struct FallbackOutbound {
+    outbounds: Vec<(String, Box<dyn Outbound>)>,
+    circuits: RefCell<Vec<Circuit>>,
+    attempt_timeout: Duration,
+    cool_down: Duration,
+    clock: Clock,
+}
+
+impl FallbackOutbound {
+    pub fn new(outbounds: Vec<(String, Box<dyn Outbound>)>) -> Self {
+        assert!(
+            !outbounds.is_empty(),
+            "fallback needs at least one outbound"
+        );
+        Self {
+            circuits: RefCell::new(outbounds.iter().map(|_| Circuit::default()).collect()),
+            outbounds,
+            attempt_timeout: DEFAULT_ATTEMPT_TIMEOUT,
+            cool_down: DEFAULT_COOL_DOWN,
+            clock: Rc::new(time::now),
+        }
+    }
+
+    // how long an outbound gets to send its first byte back
+    pub fn with_attempt_timeout(mut self, timeout: Duration) -> Self {
+        self.attempt_timeout = timeout;
+        self
+    }
+
+    // how long an outbound is skipped once it failed MAX_FAILURES times in
+    // a row
+    pub fn with_cool_down(mut self, cool_down: Duration) -> Self {
+        self.cool_down = cool_down;
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn stats(&self, tag: &str) -> Option<FallbackStats> {
+        let i = self.outbounds.iter().position(|(t, _)| t == tag)?;
+        Some(self.circuits.borrow()[i].stats)
+    }
+
+    // the outbounds this connection may use, in order. an open circuit
+    // whose cool down passed lets this one connection through and stays
+    // open for the others meanwhile. with every circuit open all of them
+    // are tried anyway.
+    fn candidates(&self) -> Vec<usize> {
+        let now = (self.clock)();
+        let mut circuits = self.circuits.borrow_mut();
+        let allowed: Vec<usize> = (0..circuits.len())
+            .filter(|&i| match circuits[i].open_until {
+                None => true,
+                Some(x) if now >= x => {
+                    circuits[i].open_until = Some(now + self.cool_down);
+                    true
+                }
+                Some(_) => false,
+            })
+            .collect();
+        match allowed.is_empty() {
+            true => (0..circuits.len()).collect(),
+            false => allowed,
+        }
+    }
+
+    fn report(&self, i: usize, ok: bool) {
+        let now = (self.clock)();
+        let circuit = &mut self.circuits.borrow_mut()[i];
+        circuit.stats.attempts += 1;
+        if ok {
+            if circuit.open_until.take().is_some() {
+                crate::log!("[fallback]: {} is back up", self.outbounds[i].0);
+            }
+            circuit.failures = 0;
+            return;
+        }
+        circuit.stats.failures += 1;
+        circuit.failures = circuit.failures.saturating_add(1);
+        if circuit.failures >= MAX_FAILURES {
+            if circuit.open_until.is_none() {
+                crate::log!(
+                    "[fallback]: skipping {} for {:?}",
+                    self.outbounds[i].0,
+                    self.cool_down
+                );
+            }
+            circuit.open_until = Some(now + self.cool_down);
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Outbound for FallbackOutbound {
+    async fn dispatch(&self, target: &Target, stream: &mut dyn AsyncStream) -> Result<()> {
+        let candidates = self.candidates();
+        let mut recorded = Vec::new();
+        let overflowed = Cell::new(false);
+        let mut last_error = None;
+
+        for (n, &i) in candidates.iter().enumerate() {
+            let (tag, outbound) = &self.outbounds[i];
+            let committed = Cell::new(false);
+            let eof = Cell::new(false);
+            let mut attempt = Attempt {
+                inner: &mut *stream,
+                recorded: &mut recorded,
+                pos: 0,
+                overflowed: &overflowed,
+                eof: &eof,
+                committed: &committed,
+            };
+            let timeout = async {
+                time::sleep(self.attempt_timeout).await;
+                if committed.get() {
+                    future::pending::<()>().await;
+                }
+            };
+            let res =
+                match future::select(outbound.dispatch(target, &mut attempt), Box::pin(timeout))
+                    .await
+                {
+                    Either::Left((x, _)) => x,
+                    Either::Right(_) => Err(Error::Io(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("no response in {:?}", self.attempt_timeout),
+                    ))),
+                };
+
+            // a client that left without a word is no fault of the outbound
+            let done = committed.get() || (res.is_ok() && eof.get());
+            self.report(i, done);
+            if done {
+                return res;
+            }
+            if res.as_ref().is_err_and(|e| !e.is_retryable()) {
+                return res;
+            }
+            if overflowed.get() {
+                return res.and(Err(Error::RustError(format!(
+                    "{tag} failed, too much data to replay"
+                ))));
+            }
+
+            let e = res.err().map_or_else(
+                || "closed without a response".to_string(),
+                |e| e.to_string(),
+            );
+            if let Some(&next) = candidates.get(n + 1) {
+                self.circuits.borrow_mut()[i].stats.fallbacks += 1;
+                crate::log!(
+                    "[fallback]: {} via {} failed ({}), trying {}",
+                    target,
+                    tag,
+                    e,
+                    self.outbounds[next].0
+                );
+            }
+            last_error = Some(e);
+        }
+
+        Err(Error::RustError(format!(
+            "every outbound failed, last: {}",
+            last_error.unwrap_or_default()
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbound::Network;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum Mode {
+        // reads the request and answers it
+        Up,
+        // gives up without reading, like a dial that failed
+        Down,
+        // never answers
+        Hang,
+        // answers, then fails
+        Reset,
+        // turns the credentials down, as it will every time
+        Denied,
+    }
+
+    type Received = Rc<RefCell<Vec<Vec<u8>>>>;
+
+    struct MockOutbound {
+        mode: Rc<Cell<Mode>>,
+        received: Received,
+    }
+
+    #[async_trait(?Send)]
+    impl Outbound for MockOutbound {
+        async fn dispatch(&self, _: &Target, stream: &mut dyn AsyncStream) -> Result<()> {
+            match self.mode.get() {
+                Mode::Down => return Ok(()),
+                Mode::Denied => {
+                    return Err(Error::Io(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "authentication failed",
+                    )))
+                }
+                Mode::Hang => future::pending().await,
+                Mode::Up | Mode::Reset => {}
+            }
+            let mut request = vec![0u8; 6];
+            stream.read_exact(&mut request).await?;
+            self.received.borrow_mut().push(request);
+            stream.write_all(b"pong").await?;
+            match self.mode.get() {
+                Mode::Reset => Err(Error::RustError("reset".to_string())),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    struct Harness {
+        fallback: FallbackOutbound,
+        modes: Vec<Rc<Cell<Mode>>>,
+        received: Vec<Received>,
+        now: Rc<Cell<Duration>>,
+    }
+
+    fn harness() -> Harness {
+        let (modes, received): (Vec<_>, Vec<_>) = (0..2)
+            .map(|_| (Rc::new(Cell::new(Mode::Up)), Received::default()))
+            .unzip();
+        let outbounds = ["primary", "secondary"]
+            .iter()
+            .zip(modes.iter().zip(&received))
+            .map(|(tag, (mode, received))| {
+                let outbound = MockOutbound {
+                    mode: mode.clone(),
+                    received: received.clone(),
+                };
+                (tag.to_string(), Box::new(outbound) as Box<dyn Outbound>)
+            })
+            .collect();
+        let now = Rc::new(Cell::new(Duration::from_secs(1_000)));
+        let clock = now.clone();
+        let fallback = FallbackOutbound::new(outbounds)
+            .with_attempt_timeout(Duration::from_secs(2))
+            .with_cool_down(Duration::from_secs(30))
+            .with_clock(Rc::new(move || clock.get()));
+        Harness {
+            fallback,
+            modes,
+            received,
+            now,
+        }
+    }
+
+    async fn connect(fallback: &FallbackOutbound) -> Result<Vec<u8>> {
+        let target = Target::new("example.com".to_string(), 443, Network::Tcp);
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(b"ping!\n").await.unwrap();
+        let res = fallback.dispatch(&target, &mut server).await;
+        drop(server);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        res.map(|_| response)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fallback_and_recovery() {
+        let h = harness();
+        let stats = |tag| h.fallback.stats(tag).unwrap();
+        assert_eq!(connect(&h.fallback).await.unwrap(), b"pong");
+        assert_eq!(h.received[1].borrow().len(), 0);
+
+        // the primary dies mid-run, new connections go out the secondary
+        // with their request intact
+        h.modes[0].set(Mode::Down);
+        for _ in 0..MAX_FAILURES {
+            assert_eq!(connect(&h.fallback).await.unwrap(), b"pong");
+        }
+        assert!(h.received[1].borrow().iter().all(|x| x == b"ping!\n"));
+        assert_eq!(
+            stats("primary"),
+            FallbackStats {
+                attempts: 4,
+                failures: 3,
+                fallbacks: 3
+            }
+        );
+
+        // the circuit is open, the primary is not even tried
+        h.now.set(h.now.get() + Duration::from_secs(29));
+        assert_eq!(connect(&h.fallback).await.unwrap(), b"pong");
+        assert_eq!(stats("primary").attempts, 4);
+        assert_eq!(stats("secondary").attempts, 4);
+
+        // after the cool down one connection probes it, still dead
+        h.now.set(h.now.get() + Duration::from_secs(1));
+        assert_eq!(connect(&h.fallback).await.unwrap(), b"pong");
+        assert_eq!(stats("primary").attempts, 5);
+        assert_eq!(connect(&h.fallback).await.unwrap(), b"pong");
+        assert_eq!(stats("primary").attempts, 5);
+
+        // the next probe finds it back up and it takes over again
+        h.modes[0].set(Mode::Up);
+        h.now.set(h.now.get() + Duration::from_secs(30));
+        for _ in 0..2 {
+            assert_eq!(connect(&h.fallback).await.unwrap(), b"pong");
+        }
+        assert_eq!(stats("primary").attempts, 7);
+        assert_eq!(stats("secondary").attempts, 6);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_attempt_timeout() {
+        let h = harness();
+        h.modes[0].set(Mode::Hang);
+        let start = tokio::time::Instant::now();
+        assert_eq!(connect(&h.fallback).await.unwrap(), b"pong");
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+        assert_eq!(h.fallback.stats("secondary").unwrap().attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn test_no_retry_after_response() {
+        let h = harness();
+        h.modes[0].set(Mode::Reset);
+        let e = connect(&h.fallback).await.unwrap_err();
+        assert!(e.to_string().contains("reset"), "{e}");
+        assert_eq!(h.fallback.stats("secondary").unwrap().attempts, 0);
+
+        // and with everything down the connection fails
+        h.modes[0].set(Mode::Down);
+        h.modes[1].set(Mode::Down);
+        let e = connect(&h.fallback).await.unwrap_err();
+        assert!(e.to_string().contains("every outbound failed"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn test_no_retry_on_permanent_errors() {
+        let h = harness();
+        h.modes[0].set(Mode::Denied);
+        let e = connect(&h.fallback).await.unwrap_err();
+        assert!(e.to_string().contains("authentication failed"), "{e}");
+        assert_eq!(h.fallback.stats("primary").unwrap().failures, 1);
+        assert_eq!(h.fallback.stats("secondary").unwrap().attempts, 0);
+    }
+}
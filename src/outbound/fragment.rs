@@ -0,0 +1,305 @@
+use super::dialer::{BoxStream, Dialer};
+use super::Target;
+use crate::common::time;
+use crate::config::ConfigError;
+
+use async_trait::async_trait;
+use serde_json::Value;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use worker::*;
+
+// which writes to the server are cut up
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Packets {
+    // the first write, when it starts with a tls handshake record
+    TlsHello,
+    // the nth to mth writes, counting from 1
+    Range(u64, u64),
+}
+
+// xray's freedom `fragment`: a write is sent in pieces of `length` bytes,
+// `interval` milliseconds apart, so middleboxes reading the sni from the
+// first segment see only part of it. both are `min-max` ranges.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fragment {
+    pub packets: Packets,
+    pub length: (u64, u64),
+    pub interval: (u64, u64),
+}
+
+impl Fragment {
+    // `{"packets": "tlshello", "length": "100-200", "interval": "10-20"}`
+    pub fn from_json(value: &Value, path: &str) -> std::result::Result<Self, Vec<ConfigError>> {
+        let Some(object) = value.as_object() else {
+            return Err(vec![ConfigError::new(path, "expected an object")]);
+        };
+
+        let mut errors = Vec::new();
+        let mut fragment = Fragment {
+            packets: Packets::TlsHello,
+            length: (0, 0),
+            interval: (0, 0),
+        };
+        for (key, value) in object {
+            let path = format!("{path}.{key}");
+            match key.as_str() {
+                "packets" => match value.as_str() {
+                    Some("tlshello") => fragment.packets = Packets::TlsHello,
+                    _ => match parse_range(value).filter(|x| x.0 > 0) {
+                        Some((min, max)) => fragment.packets = Packets::Range(min, max),
+                        None => errors.push(ConfigError::new(
+                            &path,
+                            "expected tlshello or a range of writes like 1-3",
+                        )),
+                    },
+                },
+                "length" => match parse_range(value).filter(|x| x.0 > 0) {
+                    Some(x) => fragment.length = x,
+                    None => errors.push(ConfigError::new(&path, "expected a range like 100-200")),
+                },
+                "interval" => match parse_range(value) {
+                    Some(x) => fragment.interval = x,
+                    None => errors.push(ConfigError::new(&path, "expected a range like 10-20")),
+                },
+                _ => errors.push(ConfigError::new(&path, "unknown field")),
+            }
+        }
+        if !object.contains_key("length") {
+            errors.push(ConfigError::new(&format!("{path}.length"), "missing"));
+        }
+
+        if errors.is_empty() {
+            Ok(fragment)
+        } else {
+            Err(errors)
+        }
+    }
+
+    // whether the `n`th write, counting from 1, is cut up
+    fn applies(&self, n: u64, buf: &[u8]) -> bool {
+        match self.packets {
+            // a handshake record of tls 1.0 or later
+            Packets::TlsHello => n == 1 && buf.len() > 5 && buf[0] == 0x16 && buf[1] == 0x03,
+            Packets::Range(min, max) => (min..=max).contains(&n),
+        }
+    }
+}
+
+// `n`, `"n"` or `"min-max"`
+fn parse_range(value: &Value) -> Option<(u64, u64)> {
+    if let Some(n) = value.as_u64() {
+        return Some((n, n));
+    }
+    let s = value.as_str()?;
+    let (min, max) = s.split_once('-').unwrap_or((s, s));
+    let range = (min.trim().parse().ok()?, max.trim().parse().ok()?);
+    (range.0 <= range.1).then_some(range)
+}
+
+fn pick((min, max): (u64, u64)) -> u64 {
+    if min == max {
+        return min;
+    }
+    let mut buf = [0u8; 8];
+    crate::common::random(&mut buf);
+    min + u64::from_le_bytes(buf) % (max - min + 1)
+}
+
+// cuts the writes `fragment` picks into pieces. a write is one call to
+// `poll_write` and the ones after it until its bytes are through, which is
+// how `write_all` and the relay copy use it.
+pub struct FragmentStream<S> {
+    inner: S,
+    fragment: Fragment,
+    writes: u64,
+    // bytes of the write being cut up that are not sent yet
+    left: usize,
+    delay: Option<Pin<Box<dyn Future<Output = ()>>>>,
+}
+
+impl<S> FragmentStream<S> {
+    pub fn new(inner: S, fragment: Fragment) -> Self {
+        Self {
+            inner,
+            fragment,
+            writes: 0,
+            left: 0,
+            delay: None,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for FragmentStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for FragmentStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if let Some(delay) = this.delay.as_mut() {
+            ready!(delay.as_mut().poll(cx));
+            this.delay = None;
+        }
+        if this.left == 0 {
+            this.writes = this.writes.saturating_add(1);
+            if !this.fragment.applies(this.writes, buf) {
+                return Pin::new(&mut this.inner).poll_write(cx, buf);
+            }
+            this.left = buf.len();
+        }
+
+        let piece = usize::try_from(pick(this.fragment.length)).unwrap_or(usize::MAX);
+        let piece = piece.min(this.left).min(buf.len());
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..piece]))?;
+        this.left = this.left.saturating_sub(n);
+        if this.left > 0 {
+            let interval = Duration::from_millis(pick(this.fragment.interval));
+            this.delay = Some(Box::pin(time::sleep(interval)));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// fragments the connections another dialer makes
+pub struct FragmentDialer {
+    inner: Rc<dyn Dialer>,
+    fragment: Fragment,
+}
+
+impl FragmentDialer {
+    pub fn new(inner: Rc<dyn Dialer>, fragment: Fragment) -> Self {
+        Self { inner, fragment }
+    }
+}
+
+#[async_trait(?Send)]
+impl Dialer for FragmentDialer {
+    async fn dial(&self, target: &Target) -> Result<BoxStream> {
+        let stream = self.inner.dial(target).await?;
+        Ok(Box::new(FragmentStream::new(stream, self.fragment)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::cell::RefCell;
+    use tokio::io::AsyncWriteExt;
+
+    type Writes = Rc<RefCell<Vec<(Vec<u8>, tokio::time::Instant)>>>;
+
+    // remembers every write and when it arrived
+    #[derive(Default)]
+    struct Recorder(Writes);
+
+    impl AsyncWrite for Recorder {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let now = tokio::time::Instant::now();
+            self.0.borrow_mut().push((buf.to_vec(), now));
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fragment_tls_hello() {
+        let fragment =
+            Fragment::from_json(&json!({"length": 4, "interval": "10"}), "FREEDOM.fragment");
+        let recorder = Recorder::default();
+        let writes = recorder.0.clone();
+        let mut stream = FragmentStream::new(recorder, fragment.unwrap());
+
+        let hello = [0x16, 0x03, 0x01, 0x00, 0x05, 1, 2, 3, 4, 5];
+        let start = tokio::time::Instant::now();
+        stream.write_all(&hello).await.unwrap();
+        stream.write_all(b"request").await.unwrap();
+
+        let writes = writes.borrow();
+        let pieces: Vec<_> = writes.iter().map(|x| x.0.as_slice()).collect();
+        assert_eq!(pieces, [&hello[..4], &hello[4..8], &hello[8..], b"request"]);
+        let offsets: Vec<_> = writes.iter().map(|x| x.1 - start).collect();
+        let ms = Duration::from_millis;
+        assert_eq!(offsets, [ms(0), ms(10), ms(20), ms(20)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fragment_packets() {
+        let fragment = Fragment::from_json(
+            &json!({"packets": "2-3", "length": "3-3", "interval": 0}),
+            "FREEDOM.fragment",
+        );
+        let recorder = Recorder::default();
+        let writes = recorder.0.clone();
+        let mut stream = FragmentStream::new(recorder, fragment.unwrap());
+        for data in [b"aaaaa", b"bbbbb", b"ccccc", b"ddddd"] {
+            stream.write_all(data).await.unwrap();
+        }
+        let sizes: Vec<_> = writes.borrow().iter().map(|x| x.0.len()).collect();
+        assert_eq!(sizes, [5, 3, 2, 3, 2, 5]);
+
+        // not a tls handshake, sent as it is
+        let recorder = Recorder::default();
+        let writes = recorder.0.clone();
+        let fragment = Fragment::from_json(&json!({"length": 1}), "FREEDOM.fragment");
+        let mut stream = FragmentStream::new(recorder, fragment.unwrap());
+        stream.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        assert_eq!(writes.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_fragment_from_json() {
+        let errors = Fragment::from_json(
+            &json!({"packets": "3-1", "length": "0-5", "interval": "x", "size": 1}),
+            "FREEDOM.fragment",
+        )
+        .unwrap_err();
+        let paths: Vec<_> = errors.iter().map(|x| x.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "FREEDOM.fragment.interval",
+                "FREEDOM.fragment.length",
+                "FREEDOM.fragment.packets",
+                "FREEDOM.fragment.size"
+            ]
+        );
+        let errors = Fragment::from_json(&json!({}), "FREEDOM.fragment").unwrap_err();
+        assert_eq!(errors[0].path, "FREEDOM.fragment.length");
+    }
+}
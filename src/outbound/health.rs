@@ -0,0 +1,188 @@
+use super::balancer::{Balancer, Probe};
+use super::{Network, Outbound, OutboundManager, Target};
+use crate::common::{task, time};
+
+use futures_util::future::{self, Either};
+use std::rc::{Rc, Weak};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use worker::*;
+
+// a HEAD request through the outbound, timed from the dial to the first
+// byte of the response
+pub async fn probe(outbound: &dyn Outbound, probe: &Probe) -> Result<Duration> {
+    let url = &probe.url;
+    let host = url
+        .host_str()
+        .ok_or_else(|| Error::RustError(format!("probe url without a host: {url}")))?;
+    let target = Target::new(
+        host.trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string(),
+        url.port_or_known_default().unwrap_or(80),
+        Network::Tcp,
+    );
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let request = format!("HEAD {path} HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\n\r\n");
+
+    let (mut client, mut server) = tokio::io::duplex(1024);
+    let start = time::now();
+    let exchange = async move {
+        client.write_all(request.as_bytes()).await?;
+        let mut buf = [0u8; 1];
+        match client.read(&mut buf).await? {
+            0 => Err(Error::RustError("closed without a response".to_string())),
+            _ => Ok(time::now().saturating_sub(start)),
+        }
+    };
+    let relay = async {
+        outbound.dispatch(&target, &mut server).await?;
+        Err(Error::RustError("closed without a response".to_string()))
+    };
+
+    let exchange = future::select(Box::pin(exchange), Box::pin(relay));
+    let result = match future::select(exchange, Box::pin(time::sleep(probe.timeout))).await {
+        Either::Left((Either::Left((x, _)) | Either::Right((x, _)), _)) => x,
+        Either::Right(_) => Err(Error::RustError("timed out".to_string())),
+    };
+    result
+}
+
+// probes every member of the balancer once per interval for as long as the
+// balancer is in use. it stops on its own once a config change replaced it.
+pub fn spawn_checker(balancer: Weak<Balancer>, outbounds: Rc<OutboundManager>, probe: Probe) {
+    task::spawn(async move {
+        loop {
+            let Some(balancer) = balancer.upgrade() else {
+                return;
+            };
+            for tag in balancer.tags() {
+                let Some(outbound) = outbounds.get(tag) else {
+                    continue;
+                };
+                let alive = balancer.is_alive(tag);
+                match self::probe(outbound, &probe).await {
+                    Ok(x) => balancer.report_latency(tag, x),
+                    Err(e) => {
+                        crate::log_error!("[balancer]: probing {}: {}", tag, e);
+                        balancer.report_failure(tag);
+                    }
+                }
+                match (alive, balancer.is_alive(tag)) {
+                    (true, false) => crate::log!("[balancer]: {} is down", tag),
+                    (false, true) => crate::log!("[balancer]: {} is back up", tag),
+                    _ => {}
+                }
+            }
+            drop(balancer);
+            time::sleep(probe.interval).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{AsyncStream, Strategy};
+    use super::*;
+    use async_trait::async_trait;
+    use std::cell::Cell;
+
+    // answers like a web server after `delay`, or drops the connection
+    struct MockOutbound {
+        delay: Duration,
+        up: Rc<Cell<bool>>,
+    }
+
+    #[async_trait(?Send)]
+    impl Outbound for MockOutbound {
+        async fn dispatch(&self, target: &Target, stream: &mut dyn AsyncStream) -> Result<()> {
+            assert_eq!((target.addr.as_str(), target.port), ("www.google.com", 80));
+            let mut request = vec![0u8; 1024];
+            let n = stream.read(&mut request).await?;
+            assert!(request[..n]
+                .starts_with(b"HEAD /generate_204 HTTP/1.1\r\nHost: www.google.com\r\n"));
+            if !self.up.get() {
+                return Ok(());
+            }
+            tokio::time::sleep(self.delay).await;
+            stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await?;
+            std::future::pending().await
+        }
+    }
+
+    fn manager(outbounds: &[(&str, u64, &Rc<Cell<bool>>)]) -> OutboundManager {
+        let mut manager = OutboundManager::default();
+        for (tag, delay, up) in outbounds {
+            let outbound = MockOutbound {
+                delay: Duration::from_millis(*delay),
+                up: (*up).clone(),
+            };
+            manager.add(tag, Box::new(outbound));
+        }
+        manager
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        let up = Rc::new(Cell::new(true));
+        let outbounds = manager(&[("fast", 10, &up), ("slow", 10_000, &up)]);
+        let probe_options = Probe::default();
+
+        let latency = probe(outbounds.get("fast").unwrap(), &probe_options)
+            .await
+            .unwrap();
+        assert!(latency < Duration::from_secs(1), "{latency:?}");
+
+        let options = Probe {
+            timeout: Duration::from_millis(50),
+            ..Probe::default()
+        };
+        let e = probe(outbounds.get("slow").unwrap(), &options)
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("timed out"), "{e}");
+
+        up.set(false);
+        let e = probe(outbounds.get("fast").unwrap(), &probe_options)
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("closed"), "{e}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_checker_excludes_and_recovers() {
+        let (up, flaky) = (Rc::new(Cell::new(true)), Rc::new(Cell::new(false)));
+        let outbounds = Rc::new(manager(&[("a", 300, &up), ("b", 100, &flaky)]));
+        let tags = vec!["a".to_string(), "b".to_string()];
+        let balancer = Rc::new(Balancer::new(tags, Strategy::RoundRobin));
+        let probe = Probe {
+            interval: Duration::from_secs(10),
+            ..Probe::default()
+        };
+
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                spawn_checker(Rc::downgrade(&balancer), outbounds, probe);
+                let target = Target::new("example.com".to_string(), 443, Network::Tcp);
+
+                // three rounds of failures take b out
+                tokio::time::sleep(Duration::from_secs(25)).await;
+                assert!(!balancer.is_alive("b"));
+                assert!((0..4).all(|_| balancer.select(&target) == "a"));
+
+                // and the first good probe brings it back
+                flaky.set(true);
+                tokio::time::sleep(Duration::from_secs(10)).await;
+                assert!(balancer.is_alive("b"));
+                let picked: Vec<_> = (0..4)
+                    .map(|_| balancer.select(&target).to_string())
+                    .collect();
+                assert!(picked.iter().any(|x| x == "b"), "{picked:?}");
+            })
+            .await;
+    }
+}
@@ -0,0 +1,105 @@
+pub mod balancer;
+pub mod block;
+pub mod breaker;
+pub mod chain;
+pub mod dialer;
+pub mod direct;
+pub mod dns;
+pub mod fallback;
+pub mod fragment;
+pub mod health;
+pub mod uot;
+
+use std::fmt;
+use std::rc::Rc;
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite};
+use worker::*;
+
+pub use balancer::{Balancer, BalancerConfig, Strategy};
+pub use block::{BlockOutbound, BlockResponse};
+pub use breaker::{BreakerOutbound, BreakerState};
+pub use chain::{ChainDialer, ChainOutbound, ChainSettings, Hop};
+pub use dialer::{Dialer, ProxyDialer, SocketDialer};
+pub use direct::DirectOutbound;
+pub use dns::DnsOutbound;
+pub use fallback::FallbackOutbound;
+pub use uot::{UotOutbound, UotVersion};
+
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Unpin + ?Sized> AsyncStream for T {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Network {
+    Tcp,
+    Udp,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Target {
+    pub addr: String,
+    pub port: u16,
+    pub network: Network,
+}
+
+impl Target {
+    pub fn new(addr: String, port: u16, network: Network) -> Self {
+        Self { addr, port, network }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let network = match self.network {
+            Network::Tcp => "tcp",
+            Network::Udp => "udp",
+        };
+        write!(f, "{}:{}:{}", network, self.addr, self.port)
+    }
+}
+
+// worker sockets are not Send, so neither are the outbound futures
+#[async_trait(?Send)]
+pub trait Outbound {
+    async fn dispatch(&self, target: &Target, stream: &mut dyn AsyncStream) -> Result<()>;
+
+    // whether udp targets can be dispatched, checked before chaining
+    // another outbound's dial through this one
+    fn supports_udp(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Default)]
+pub struct OutboundManager {
+    outbounds: Vec<(String, Rc<dyn Outbound>)>,
+}
+
+impl OutboundManager {
+    // registering a tag twice replaces the earlier outbound
+    pub fn add(&mut self, tag: &str, outbound: Box<dyn Outbound>) {
+        match self.outbounds.iter_mut().find(|(t, _)| t == tag) {
+            Some((_, x)) => *x = outbound.into(),
+            None => self.outbounds.push((tag.to_string(), outbound.into())),
+        }
+    }
+
+    pub fn get(&self, tag: &str) -> Option<&dyn Outbound> {
+        self.outbounds
+            .iter()
+            .find(|(t, _)| t == tag)
+            .map(|(_, x)| x.as_ref())
+    }
+
+    // for outbounds that dial through this one
+    pub fn get_shared(&self, tag: &str) -> Option<Rc<dyn Outbound>> {
+        self.outbounds
+            .iter()
+            .find(|(t, _)| t == tag)
+            .map(|(_, x)| x.clone())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &dyn Outbound)> {
+        self.outbounds.iter().map(|(t, x)| (t.as_str(), x.as_ref()))
+    }
+}
@@ -0,0 +1,566 @@
+use super::{AsyncStream, Network, Outbound, Target};
+use crate::app::policy::Policy;
+use crate::common::relay::relay_bidirectional;
+use crate::common::{read_u16_be, write_u16_be};
+use crate::config::ConfigError;
+
+use async_trait::async_trait;
+use bytes::{Buf, BytesMut};
+use futures_util::future::{self, Either};
+use serde_json::Value;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use worker::*;
+
+// sing-box's udp over tcp: an outbound that only carries tcp is asked for
+// a stream to one of these domains, and the datagrams go over it framed.
+// the far end knows the domain and sends them on as udp.
+pub const MAGIC_ADDRESS: &str = "sp.v2.udp-over-tcp.arpa";
+pub const LEGACY_MAGIC_ADDRESS: &str = "sp.udp-over-tcp.arpa";
+
+// buffered between the udp side and the outbound's tcp stream
+const BUFFER_SIZE: usize = 64 * 1024;
+// read from the tcp stream at a time, frames are put together from these
+const READ_SIZE: usize = 8 * 1024;
+
+// the address families of sing-box's serializer, not socks5's
+const FAMILY_IPV4: u8 = 0x00;
+const FAMILY_IPV6: u8 = 0x01;
+const FAMILY_FQDN: u8 = 0x02;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UotVersion {
+    // every datagram carries its address, there is no request
+    V1,
+    // a request names the destination first, the datagrams after it are
+    // only length prefixed
+    #[default]
+    V2,
+}
+
+impl UotVersion {
+    pub fn magic_address(self) -> &'static str {
+        match self {
+            Self::V1 => LEGACY_MAGIC_ADDRESS,
+            Self::V2 => MAGIC_ADDRESS,
+        }
+    }
+
+    // the version a tcp target asks for, if it is one of the magic domains
+    pub fn of(target: &Target) -> Option<Self> {
+        if target.network != Network::Tcp {
+            return None;
+        }
+        match target.addr.as_str() {
+            MAGIC_ADDRESS => Some(Self::V2),
+            LEGACY_MAGIC_ADDRESS => Some(Self::V1),
+            _ => None,
+        }
+    }
+
+    // an outbound's `udpOverTcp`: `true` for version 2, `false`, or
+    // `{"version": 1}` for servers that only know the first one
+    pub fn from_json(
+        value: &Value,
+        path: &str,
+    ) -> std::result::Result<Option<Self>, Vec<ConfigError>> {
+        if let Some(enabled) = value.as_bool() {
+            return Ok(enabled.then_some(Self::V2));
+        }
+        let Some(object) = value.as_object() else {
+            return Err(vec![ConfigError::new(path, "expected a bool or an object")]);
+        };
+
+        let mut errors = Vec::new();
+        let mut version = Self::V2;
+        for (key, value) in object {
+            let path = format!("{path}.{key}");
+            match key.as_str() {
+                "version" => match value.as_u64() {
+                    Some(1) => version = Self::V1,
+                    Some(2) => version = Self::V2,
+                    _ => errors.push(ConfigError::new(&path, "expected 1 or 2")),
+                },
+                _ => errors.push(ConfigError::new(&path, "unknown field")),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(Some(version))
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Framing {
+    // length and payload, for a stream bound to one destination
+    Connect,
+    // address, port, length and payload. datagrams go out addressed to
+    // `peer`. on the server side the ones coming in have to be for it,
+    // the client takes whatever address the replies come from.
+    Packet { peer: (String, u16), strict: bool },
+}
+
+// datagrams over a stream, one read or write of this side for each
+pub struct UotStream<S> {
+    inner: S,
+    framing: Framing,
+    // what has come in of the next frames
+    read: BytesMut,
+    // the request and frames not through to the stream yet
+    write: Vec<u8>,
+    written: usize,
+}
+
+impl<S> UotStream<S> {
+    // the client end of a stream an outbound opened to the magic address of
+    // `version`. the request goes out along with the first datagram, a
+    // target that cannot be written fails here already.
+    pub fn connect(inner: S, target: &Target, version: UotVersion) -> io::Result<Self> {
+        let peer = (target.addr.clone(), target.port);
+        let mut request = Vec::new();
+        write_addr(&mut request, &peer.0, peer.1)?;
+        let (framing, write) = match version {
+            UotVersion::V1 => (
+                Framing::Packet {
+                    peer,
+                    strict: false,
+                },
+                Vec::new(),
+            ),
+            UotVersion::V2 => {
+                // is connect, then the destination
+                request.insert(0, 0x01);
+                (Framing::Connect, request)
+            }
+        };
+        Ok(Self::new(inner, framing, BytesMut::new(), write))
+    }
+
+    fn new(inner: S, framing: Framing, read: BytesMut, write: Vec<u8>) -> Self {
+        Self {
+            inner,
+            framing,
+            read,
+            write,
+            written: 0,
+        }
+    }
+
+    // the next complete frame's payload, taken off the read buffer
+    fn next_frame(&mut self) -> io::Result<Option<BytesMut>> {
+        let head = match &self.framing {
+            Framing::Connect => 0,
+            Framing::Packet { peer, strict } => {
+                let Some((addr, port, n)) = read_addr(&self.read)? else {
+                    return Ok(None);
+                };
+                if *strict && (addr.as_str(), port) != (peer.0.as_str(), peer.1) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "datagram for {addr}:{port}, the stream is bound to {}:{}",
+                            peer.0, peer.1
+                        ),
+                    ));
+                }
+                n
+            }
+        };
+        if self.read.len() < head + 2 {
+            return Ok(None);
+        }
+        let len = read_u16_be(&self.read[head..]) as usize;
+        if self.read.len() < head + 2 + len {
+            return Ok(None);
+        }
+        self.read.advance(head + 2);
+        Ok(Some(self.read.split_to(len)))
+    }
+}
+
+impl<S: AsyncRead + Unpin> UotStream<S> {
+    // the server end of a stream opened to the magic address of `version`,
+    // and where its datagrams are for. with version 1 that is the address
+    // of the first one, later ones have to be for it as well.
+    pub async fn accept(mut inner: S, version: UotVersion) -> io::Result<(Target, Self)> {
+        let connect = match version {
+            UotVersion::V1 => false,
+            UotVersion::V2 => inner.read_u8().await? != 0,
+        };
+        let (addr, port) = read_addr_from(&mut inner).await?;
+        let mut read = BytesMut::new();
+        if version == UotVersion::V1 {
+            // it is the first datagram's, put back for the frame to parse
+            let mut first = Vec::new();
+            write_addr(&mut first, &addr, port)?;
+            read.extend_from_slice(&first);
+        }
+        let framing = match connect {
+            true => Framing::Connect,
+            false => Framing::Packet {
+                peer: (addr.clone(), port),
+                strict: true,
+            },
+        };
+        let target = Target::new(addr, port, Network::Udp);
+        Ok((target, Self::new(inner, framing, read, Vec::new())))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> UotStream<S> {
+    // until what is in the write buffer is through
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.write.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.write.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for UotStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(datagram) = this.next_frame()? {
+                if datagram.len() > buf.remaining() {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("datagram of {} bytes does not fit the read", datagram.len()),
+                    )));
+                }
+                buf.put_slice(&datagram);
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut chunk = [0u8; READ_SIZE];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                if !this.read.is_empty() {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "stream closed within a datagram",
+                    )));
+                }
+                return Poll::Ready(Ok(()));
+            }
+            this.read.extend_from_slice(chunk.filled());
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for UotStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        let Ok(len) = u16::try_from(buf.len()) else {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("datagram of {} bytes is too large", buf.len()),
+            )));
+        };
+        if let Framing::Packet { peer, .. } = &this.framing {
+            // checked when the stream was made
+            write_addr(&mut this.write, &peer.0, peer.1)?;
+        }
+        write_u16_be(&mut this.write, len);
+        this.write.extend_from_slice(buf);
+        // the datagram is taken, what does not go through now goes with the
+        // next write or flush
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+fn write_addr(out: &mut Vec<u8>, addr: &str, port: u16) -> io::Result<()> {
+    match addr.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            out.push(FAMILY_IPV4);
+            out.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            out.push(FAMILY_IPV6);
+            out.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            // cutting it would send the datagram somewhere else
+            let Ok(len) = u8::try_from(addr.len()) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("domain of {} bytes is too long", addr.len()),
+                ));
+            };
+            out.push(FAMILY_FQDN);
+            out.push(len);
+            out.extend_from_slice(addr.as_bytes());
+        }
+    }
+    write_u16_be(out, port);
+    Ok(())
+}
+
+// an address and port at the start of `buf` and the bytes they take, none
+// while they are not all there
+fn read_addr(buf: &[u8]) -> io::Result<Option<(String, u16, usize)>> {
+    let Some(&family) = buf.first() else {
+        return Ok(None);
+    };
+    let (start, len) = match family {
+        FAMILY_IPV4 => (1, 4),
+        FAMILY_IPV6 => (1, 16),
+        FAMILY_FQDN => match buf.get(1) {
+            Some(&len) => (2, len as usize),
+            None => return Ok(None),
+        },
+        x => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected address family {x}"),
+            ))
+        }
+    };
+    let end = start + len;
+    if buf.len() < end + 2 {
+        return Ok(None);
+    }
+    let raw = &buf[start..end];
+    let addr = match family {
+        FAMILY_IPV4 => Ipv4Addr::from(<[u8; 4]>::try_from(raw).unwrap()).to_string(),
+        FAMILY_IPV6 => Ipv6Addr::from(<[u8; 16]>::try_from(raw).unwrap()).to_string(),
+        _ => String::from_utf8(raw.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "domain is not utf-8"))?,
+    };
+    Ok(Some((addr, read_u16_be(&buf[end..]), end + 2)))
+}
+
+// `read_addr`, from a stream nothing past the address may be taken from
+async fn read_addr_from<R: AsyncRead + Unpin>(r: &mut R) -> io::Result<(String, u16)> {
+    let mut buf = vec![r.read_u8().await?];
+    if buf[0] == FAMILY_FQDN {
+        buf.push(r.read_u8().await?);
+    }
+    loop {
+        match read_addr(&buf)? {
+            Some((addr, port, _)) => return Ok((addr, port)),
+            None => buf.push(r.read_u8().await?),
+        }
+    }
+}
+
+// carries the udp an outbound can not over udp over tcp, everything else
+// goes to it as it is
+pub struct UotOutbound {
+    inner: Box<dyn Outbound>,
+    version: UotVersion,
+    policy: Policy,
+}
+
+impl UotOutbound {
+    pub fn new(inner: Box<dyn Outbound>, version: UotVersion) -> Self {
+        Self {
+            inner,
+            version,
+            policy: Policy::default(),
+        }
+    }
+
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+}
+
+#[async_trait(?Send)]
+impl Outbound for UotOutbound {
+    async fn dispatch(&self, target: &Target, stream: &mut dyn AsyncStream) -> Result<()> {
+        if target.network == Network::Tcp || self.inner.supports_udp() {
+            return self.inner.dispatch(target, stream).await;
+        }
+
+        let magic = Target::new(self.version.magic_address().to_string(), 0, Network::Tcp);
+        let (client, mut server) = tokio::io::duplex(BUFFER_SIZE);
+        let remote = UotStream::connect(client, target, self.version)?;
+        let tunnel = self.inner.dispatch(&magic, &mut server);
+        let relay = async {
+            relay_bidirectional(stream, remote, self.policy.timeouts(Network::Udp)).await
+        };
+        // the relay is done once both sides are, the tunnel then has nothing
+        // left to send. a tunnel that ends first takes the relay with it.
+        let raced = future::select(Box::pin(tunnel), Box::pin(relay)).await;
+        match raced {
+            Either::Left((result, _)) => result,
+            Either::Right((result, _)) => result.map(|_| ()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // tcp only, and answers every datagram sing-box's server side would get
+    // for it with the same datagram upper cased
+    struct TcpOnly(Rc<RefCell<Vec<Target>>>);
+
+    #[async_trait(?Send)]
+    impl Outbound for TcpOnly {
+        async fn dispatch(&self, target: &Target, stream: &mut dyn AsyncStream) -> Result<()> {
+            self.0.borrow_mut().push(target.clone());
+            let version = UotVersion::of(target).expect("a magic target");
+            let (to, mut stream) = UotStream::accept(stream, version).await?;
+            self.0.borrow_mut().push(to);
+            let mut buf = [0u8; 1500];
+            loop {
+                let n = stream.read(&mut buf).await?;
+                if n == 0 {
+                    return Ok(());
+                }
+                stream.write_all(&buf[..n].to_ascii_uppercase()).await?;
+                stream.flush().await?;
+            }
+        }
+
+        fn supports_udp(&self) -> bool {
+            false
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wire_format() {
+        let dns = Target::new("8.8.8.8".to_string(), 53, Network::Udp);
+        let mut stream = UotStream::connect(Vec::new(), &dns, UotVersion::V2).unwrap();
+        stream.write_all(b"query").await.unwrap();
+        assert_eq!(
+            stream.inner,
+            [&[1, 0, 8, 8, 8, 8, 0, 53][..], &[0, 5], b"query"].concat()
+        );
+
+        let quic = Target::new("example.com".to_string(), 443, Network::Udp);
+        let mut stream = UotStream::connect(Vec::new(), &quic, UotVersion::V1).unwrap();
+        stream.write_all(b"hi").await.unwrap();
+        let mut expected = vec![2, 11];
+        expected.extend_from_slice(b"example.com");
+        expected.extend_from_slice(&[1, 187, 0, 2]);
+        expected.extend_from_slice(b"hi");
+        assert_eq!(stream.inner, expected);
+
+        // a domain longer than its length byte can say is refused, not cut
+        let long = Target::new("a".repeat(256), 443, Network::Udp);
+        for version in [UotVersion::V1, UotVersion::V2] {
+            let e = UotStream::connect(Vec::<u8>::new(), &long, version).err().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+            assert_eq!(e.to_string(), "domain of 256 bytes is too long");
+        }
+        let fits = Target::new("a".repeat(255), 443, Network::Udp);
+        assert!(UotStream::connect(Vec::<u8>::new(), &fits, UotVersion::V2).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_outbound_over_tcp() {
+        for version in [UotVersion::V1, UotVersion::V2] {
+            let seen = Rc::new(RefCell::new(Vec::new()));
+            let outbound = UotOutbound::new(Box::new(TcpOnly(seen.clone())), version);
+            assert!(outbound.supports_udp());
+
+            let target = Target::new("2001:db8::1".to_string(), 443, Network::Udp);
+            let (mut client, mut server) = tokio::io::duplex(1024);
+            let dispatch = outbound.dispatch(&target, &mut server);
+            let exchange = async {
+                for datagram in [&b"first"[..], b"second"] {
+                    client.write_all(datagram).await.unwrap();
+                    let mut buf = [0u8; 64];
+                    let n = client.read(&mut buf).await.unwrap();
+                    assert_eq!(&buf[..n], datagram.to_ascii_uppercase());
+                }
+                client.shutdown().await.unwrap();
+            };
+            let (result, _) = tokio::join!(dispatch, exchange);
+            result.unwrap();
+
+            let magic = Target::new(version.magic_address().to_string(), 0, Network::Tcp);
+            assert_eq!(*seen.borrow(), [magic, target]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accept_errors() {
+        // a version 1 stream stays with the destination of its first datagram
+        let mut wire = Vec::new();
+        for (addr, datagram) in [("1.1.1.1", b"one"), ("1.0.0.1", b"two")] {
+            write_addr(&mut wire, addr, 53).unwrap();
+            write_u16_be(&mut wire, 3);
+            wire.extend_from_slice(datagram);
+        }
+        let (target, mut stream) = UotStream::accept(&wire[..], UotVersion::V1).await.unwrap();
+        assert_eq!(target, Target::new("1.1.1.1".to_string(), 53, Network::Udp));
+        let mut buf = [0u8; 16];
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 3);
+        let e = stream.read(&mut buf).await.unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "datagram for 1.0.0.1:53, the stream is bound to 1.1.1.1:53"
+        );
+
+        // cut short within the first datagram
+        let (_, mut stream) = UotStream::accept(&wire[..10], UotVersion::V1).await.unwrap();
+        let e = stream.read(&mut buf).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+        let e = UotStream::accept(&[1, 7][..], UotVersion::V2)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(e.to_string(), "unexpected address family 7");
+    }
+
+    #[test]
+    fn test_from_json() {
+        let parse = |x: Value| UotVersion::from_json(&x, "udpOverTcp");
+        assert_eq!(parse(Value::Bool(true)), Ok(Some(UotVersion::V2)));
+        assert_eq!(parse(Value::Bool(false)), Ok(None));
+        assert_eq!(
+            parse(serde_json::json!({"version": 1})),
+            Ok(Some(UotVersion::V1))
+        );
+        let errors = parse(serde_json::json!({"version": 3, "enabled": true})).unwrap_err();
+        let paths: Vec<_> = errors.iter().map(|x| x.path.as_str()).collect();
+        assert_eq!(paths, ["udpOverTcp.enabled", "udpOverTcp.version"]);
+    }
+}
@@ -1,7 +1,16 @@
+use crate::app::{metrics, Dispatcher, Metadata};
+use crate::common::dial::{happy_eyeballs_connect, CONNECTION_ATTEMPT_DELAY};
+use crate::common::{proxy_protocol, read_u16_be};
+use crate::common::relay::{relay_bidirectional, Timeouts};
+use crate::common::time;
 use crate::config::Config;
+use crate::outbound::{Dialer, Target};
+use crate::proxy::fallback;
 
 use std::pin::Pin;
+use std::rc::Rc;
 use std::task::{Context, Poll};
+use std::time::Duration;
 use bytes::{BufMut, BytesMut};
 use futures_util::Stream;
 use pin_project_lite::pin_project;
@@ -11,25 +20,44 @@ use worker::*;
 
 static MAX_WEBSOCKET_SIZE: usize = 64 * 1024; // 64kb
 static MAX_BUFFER_SIZE: usize = 512 * 1024; // 512kb
+// auth id, sealed length and nonce, which a rejected header stops after
+const VMESS_HEADER_LEN: usize = 42;
 
 pin_project! {
     pub struct ProxyStream<'a> {
         pub config: Config,
+        pub dispatcher: Rc<Dispatcher>,
+        pub source: Option<String>,
+        // cloudflare's `httpProtocol` for the websocket's request, which
+        // fallbacks may be picked by
+        pub alpn: Option<String>,
         pub ws: &'a WebSocket,
         pub buffer: BytesMut,
+        // when the websocket was accepted, handshakes are timed from here
+        pub started: Duration,
         #[pin]
         pub events: EventStream<'a>,
     }
 }
 
 impl<'a> ProxyStream<'a> {
-    pub fn new(config: Config, ws: &'a WebSocket, events: EventStream<'a>) -> Self {
+    pub fn new(
+        config: Config,
+        source: Option<String>,
+        ws: &'a WebSocket,
+        events: EventStream<'a>,
+    ) -> Self {
         let buffer = BytesMut::with_capacity(MAX_BUFFER_SIZE);
+        let dispatcher = Rc::new(Dispatcher::from_config(&config));
 
         Self {
             config,
+            dispatcher,
+            source,
+            alpn: None,
             ws,
             buffer,
+            started: time::now(),
             events,
         }
     }
@@ -48,7 +76,7 @@ impl<'a> ProxyStream<'a> {
                     break;
                 }
                 Some(Err(e)) => {
-                    return Err(std::io::Error::new(std::io::ErrorKind::Other, e.to_string()));
+                    return Err(std::io::Error::other(e.to_string()));
                 }
                 None => {
                     break;
@@ -63,24 +91,65 @@ impl<'a> ProxyStream<'a> {
         &self.buffer[..len]
     }
 
+    // the client as the relay in front of the worker saw it, in place of
+    // whoever opened the websocket
+    pub async fn consume_proxy_protocol(&mut self) -> Result<()> {
+        self.fill_buffer_until(proxy_protocol::HEADER_LEN).await?;
+        let len = proxy_protocol::header_len(&self.buffer)?;
+        self.fill_buffer_until(len).await?;
+        let (src, _, len) = proxy_protocol::parse_v2(&self.buffer)?;
+        let _ = self.buffer.split_to(len);
+        self.source = Some(src.ip().to_string());
+        Ok(())
+    }
+
     pub async fn process(&mut self) -> Result<()> {
+        if self.config.proxy_protocol {
+            self.consume_proxy_protocol().await?;
+        }
+
+        // a shadowsocks 2022 salt may start with anything, the others are
+        // only told apart once its header did not open
+        if let Some(ss) = self.config.shadowsocks.clone() {
+            self.fill_buffer_until(ss.header_len()).await?;
+            if let Some(request) = ss.identify(&self.buffer) {
+                console_log!("shadowsocks 2022 detected!");
+                return self.process_shadowsocks_2022(&ss, request).await;
+            }
+        }
+
+        // with fallbacks the first message decides, like xray reading the
+        // first packet. a website's client may be waiting for an answer.
+        let fallbacks = !self.config.fallbacks.is_empty();
         let peek_buffer_len = 62;
-        self.fill_buffer_until(peek_buffer_len).await?;
+        self.fill_buffer_until(if fallbacks { 1 } else { peek_buffer_len }).await?;
         let peeked_buffer = self.peek_buffer(peek_buffer_len);
 
-        if peeked_buffer.len() < (peek_buffer_len/2) {
+        if peeked_buffer.len() < (peek_buffer_len/2) && !fallbacks {
             return Err(Error::RustError("not enough buffer".to_string()));
         }
+        if peeked_buffer.is_empty() {
+            return Err(Error::RustError("closed before a request".to_string()));
+        }
 
+        let uuid = self.config.uuid;
         if self.is_vless(peeked_buffer) {
+            if fallbacks && !fallback::vless_authenticates(peeked_buffer, &uuid) {
+                return self.process_fallback().await;
+            }
             console_log!("vless detected!");
             self.process_vless().await
         } else if self.is_shadowsocks(peeked_buffer) {
             console_log!("shadowsocks detected!");
             self.process_shadowsocks().await
         } else if self.is_trojan(peeked_buffer) {
+            if fallbacks && !fallback::trojan_authenticates(peeked_buffer, &uuid) {
+                return self.process_fallback().await;
+            }
             console_log!("trojan detected!");
             self.process_trojan().await
+        } else if fallbacks && peeked_buffer.len() < VMESS_HEADER_LEN {
+            self.process_fallback().await
         } else if self.is_vmess(peeked_buffer) {
             console_log!("vmess detected!");
             self.process_vmess().await
@@ -99,7 +168,7 @@ impl<'a> ProxyStream<'a> {
                 if buffer.len() < 7 {
                     return false;
                 }
-                let remote_port = u16::from_be_bytes([buffer[5], buffer[6]]);
+                let remote_port = read_u16_be(&buffer[5..]);
                 remote_port != 0
             }
             3 => { // Domain name
@@ -110,17 +179,14 @@ impl<'a> ProxyStream<'a> {
                 if buffer.len() < 2 + domain_len + 2 {
                     return false;
                 }
-                let remote_port = u16::from_be_bytes([
-                    buffer[2 + domain_len],
-                    buffer[2 + domain_len + 1],
-                ]);
+                let remote_port = read_u16_be(&buffer[2 + domain_len..]);
                 remote_port != 0
             }
             4 => { // IPv6
                 if buffer.len() < 19 {
                     return false;
                 }
-                let remote_port = u16::from_be_bytes([buffer[17], buffer[18]]);
+                let remote_port = read_u16_be(&buffer[17..]);
                 remote_port != 0
             }
             _ => false,
@@ -132,39 +198,62 @@ impl<'a> ProxyStream<'a> {
     }
 
     fn is_vmess(&self, buffer: &[u8]) -> bool {
-        buffer.len() > 0 // fallback
+        !buffer.is_empty() // fallback
     }
 
-    pub async fn handle_tcp_outbound(&mut self, addr: String, port: u16) -> Result<()> {
-        let mut remote_socket = Socket::builder().connect(&addr, port).map_err(|e| {
-            Error::RustError(e.to_string())
-        })?;
-
-        remote_socket.opened().await.map_err(|e| {
-            Error::RustError(e.to_string())
-        })?;
+    // the header is parsed by the time there is a target, which ends the
+    // handshake
+    pub fn metadata(&self, inbound_tag: &str, target: Target) -> Metadata {
+        let elapsed = time::now().saturating_sub(self.started);
+        metrics::shared().handshake(inbound_tag).observe(elapsed);
+        Metadata {
+            inbound_tag: inbound_tag.to_string(),
+            source: self.source.clone(),
+            sniffed_host: None,
+            target,
+            handshake: elapsed,
+            user: None,
+        }
+    }
 
-        tokio::io::copy_bidirectional(self, &mut remote_socket)
-            .await
-            .map(|(a_to_b, b_to_a)| {
-                console_log!("copied data from {}:{}, up: {} and dl: {}", &addr, &port, convert(a_to_b as f64), convert(b_to_a as f64));
-            })
-            .map_err(|e| {
-                Error::RustError(e.to_string())
-            })?;
-        Ok(())
+    pub async fn dispatch(&mut self, inbound_tag: &str, target: Target) -> Result<()> {
+        let metadata = self.metadata(inbound_tag, target);
+        let dispatcher = self.dispatcher.clone();
+        dispatcher.dispatch(&metadata, self).await
     }
+}
 
-    pub async fn handle_udp_outbound(&mut self) -> Result<()> {
-        let mut buff = vec![0u8; 65535];
+// `targets` are addresses of the same host, raced against each other
+pub async fn relay_tcp_outbound<S>(stream: &mut S, dialer: &dyn Dialer, targets: Vec<Target>, timeouts: Timeouts) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let (addr, port) = match targets.first() {
+        Some(x) => (x.addr.clone(), x.port),
+        None => return Err(Error::RustError("no addresses to dial".to_string())),
+    };
+    let mut remote_socket = happy_eyeballs_connect(dialer, targets, CONNECTION_ATTEMPT_DELAY).await?;
 
-        let n = self.read(&mut buff).await?;
-        let data = &buff[..n];
-        if crate::dns::doh(data).await.is_ok() {
-            self.write(&data).await?;
-        };
-        Ok(())
-    }
+    relay_bidirectional(stream, &mut remote_socket, timeouts)
+        .await
+        .map(|(a_to_b, b_to_a)| {
+            crate::log!("copied data from {}:{}, up: {} and dl: {}", &addr, &port, convert(a_to_b as f64), convert(b_to_a as f64));
+        })?;
+    Ok(())
+}
+
+pub async fn relay_udp_outbound<S>(stream: &mut S) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let mut buff = vec![0u8; 65535];
+
+    let n = stream.read(&mut buff).await?;
+    let data = &buff[..n];
+    if crate::dns::doh(data).await.is_ok() {
+        stream.write_all(data).await?;
+    };
+    Ok(())
 }
 
 impl<'a> AsyncRead for ProxyStream<'a> {
@@ -186,7 +275,7 @@ impl<'a> AsyncRead for ProxyStream<'a> {
                 Poll::Ready(Some(Ok(WebsocketEvent::Message(msg)))) => {
                     if let Some(data) = msg.bytes() {
                         if data.len() > MAX_WEBSOCKET_SIZE {
-                            return Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::Other, "websocket buffer too long")))
+                            return Poll::Ready(Err(std::io::Error::other("websocket buffer too long")))
                         }
                         
                         if this.buffer.len() + data.len() > MAX_BUFFER_SIZE {
@@ -210,12 +299,12 @@ impl<'a> AsyncWrite for ProxyStream<'a> {
         _: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<tokio::io::Result<usize>> {
-        return Poll::Ready(
+        Poll::Ready(
             self.ws
                 .send_with_bytes(buf)
                 .map(|_| buf.len())
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string())),
-        );
+                .map_err(|e| std::io::Error::other(e.to_string())),
+        )
     }
 
     fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
@@ -225,10 +314,7 @@ impl<'a> AsyncWrite for ProxyStream<'a> {
     fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
         match self.ws.close(Some(1000), Some("shutdown".to_string())) {
             Ok(_) => Poll::Ready(Ok(())),
-            Err(e) => Poll::Ready(Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                e.to_string(),
-            ))),
+            Err(e) => Poll::Ready(Err(std::io::Error::other(e.to_string()))),
         }
     }
 }
@@ -0,0 +1,468 @@
+use super::ProxyStream;
+use crate::common::proxy_protocol;
+use crate::common::relay::{relay_bidirectional, Timeouts};
+use crate::config::ConfigError;
+use crate::outbound::dialer::SocketDialer;
+use crate::outbound::{Dialer, Network, Target};
+
+use serde_json::Value;
+use sha2::{Digest, Sha224};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use uuid::Uuid;
+use worker::*;
+
+// xray's inbound fallbacks: a connection whose first bytes do not open as
+// any of the protocols is handed, those bytes included, to a website of
+// its own instead. empty selectors match anything.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fallback {
+    // the host the client's tls was for, which cloudflare terminated
+    pub name: String,
+    // what cloudflare negotiated with the client, e.g. `h2`
+    pub alpn: String,
+    // the path on the request line of the first bytes
+    pub path: String,
+    pub dest: Target,
+    // 2 when the backend gets a proxy protocol v2 header first
+    pub xver: u8,
+}
+
+// the optional `FALLBACKS` binding, xray's `fallbacks` array
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Fallbacks(Vec<Fallback>);
+
+impl Fallbacks {
+    pub fn from_json(value: &Value, path: &str) -> std::result::Result<Self, Vec<ConfigError>> {
+        let Some(array) = value.as_array() else {
+            return Err(vec![ConfigError::new(path, "expected an array")]);
+        };
+
+        let mut errors = Vec::new();
+        let mut fallbacks = Vec::new();
+        for (i, value) in array.iter().enumerate() {
+            let path = format!("{path}[{i}]");
+            match Fallback::from_json(value, &path) {
+                Ok(x) => fallbacks.push(x),
+                Err(e) => errors.extend(e),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(Self(fallbacks))
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // the fallback whose selectors all match and the most of them are set,
+    // the first one of those on a tie
+    pub fn select(&self, name: &str, alpn: &str, first: &[u8]) -> Option<&Fallback> {
+        let path = http_path(first).unwrap_or_default();
+        let matches = |selector: &str, x: &str| selector.is_empty() || selector == x;
+        self.0
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| matches(&x.name, name) && matches(&x.alpn, alpn))
+            .filter(|(_, x)| matches(&x.path, path))
+            .max_by_key(|(i, x)| {
+                let set = [&x.name, &x.alpn, &x.path]
+                    .iter()
+                    .filter(|x| !x.is_empty())
+                    .count();
+                (set, usize::MAX - i)
+            })
+            .map(|(_, x)| x)
+    }
+}
+
+impl Fallback {
+    fn from_json(value: &Value, path: &str) -> std::result::Result<Self, Vec<ConfigError>> {
+        let Some(object) = value.as_object() else {
+            return Err(vec![ConfigError::new(path, "expected an object")]);
+        };
+
+        let mut errors = Vec::new();
+        let (mut name, mut alpn, mut selector, mut dest, mut xver) =
+            (String::new(), String::new(), String::new(), None, 0);
+        for (key, value) in object {
+            let path = format!("{path}.{key}");
+            let string = || value.as_str().map(str::to_string);
+            match key.as_str() {
+                "name" | "alpn" | "path" => match string() {
+                    Some(x) if key == "path" && !x.is_empty() && !x.starts_with('/') => {
+                        errors.push(ConfigError::new(&path, "expected a path starting with /"))
+                    }
+                    Some(x) if key == "name" => name = x,
+                    Some(x) if key == "alpn" => alpn = x,
+                    Some(x) => selector = x,
+                    None => errors.push(ConfigError::new(&path, "expected a string")),
+                },
+                // a bare port would be the worker's own localhost, which it
+                // can not connect to
+                "dest" => match value.as_str().and_then(parse_dest) {
+                    Some(x) => dest = Some(x),
+                    None => errors.push(ConfigError::new(&path, "expected \"host:port\"")),
+                },
+                "xver" => match value.as_u64() {
+                    Some(0) => xver = 0,
+                    Some(2) => xver = 2,
+                    Some(1) => errors.push(ConfigError::new(
+                        &path,
+                        "only proxy protocol version 2 is supported",
+                    )),
+                    _ => errors.push(ConfigError::new(&path, "expected 0 or 2")),
+                },
+                _ => errors.push(ConfigError::new(&path, "unknown field")),
+            }
+        }
+        if dest.is_none() && !object.contains_key("dest") {
+            errors.push(ConfigError::new(&format!("{path}.dest"), "missing"));
+        }
+
+        match dest {
+            Some(dest) if errors.is_empty() => Ok(Self {
+                name,
+                alpn,
+                path: selector,
+                dest,
+                xver,
+            }),
+            _ => Err(errors),
+        }
+    }
+}
+
+fn parse_dest(dest: &str) -> Option<Target> {
+    let (addr, port) = dest.rsplit_once(':')?;
+    let addr = addr
+        .strip_prefix('[')
+        .and_then(|x| x.strip_suffix(']'))
+        .unwrap_or(addr);
+    let port = port.parse().ok().filter(|x| *x != 0)?;
+    if addr.is_empty() {
+        return None;
+    }
+    Some(Target::new(addr.to_string(), port, Network::Tcp))
+}
+
+// what cloudflare's `httpProtocol` is called in a tls handshake
+pub fn alpn(http_protocol: &str) -> &str {
+    match http_protocol {
+        "HTTP/1.0" => "http/1.0",
+        "HTTP/1.1" => "http/1.1",
+        "HTTP/2" => "h2",
+        "HTTP/3" => "h3",
+        x => x,
+    }
+}
+
+// the path on an http/1 request line, without its query
+pub fn http_path(first: &[u8]) -> Option<&str> {
+    let line = first.split(|x| *x == b'\r').next()?;
+    let mut parts = line.splitn(3, |x| *x == b' ');
+    let (_method, target) = (parts.next()?, parts.next()?);
+    parts.next().filter(|x| x.starts_with(b"HTTP/"))?;
+    let target = std::str::from_utf8(target).ok()?;
+    let path = target.split('?').next()?;
+    path.starts_with('/').then_some(path)
+}
+
+// without returning early, how much of a guess was right does not show in
+// the timing
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// a vless request header carries the uuid right after its version
+pub fn vless_authenticates(first: &[u8], uuid: &Uuid) -> bool {
+    first.len() >= 17 && same(&first[1..17], uuid.as_bytes())
+}
+
+// a trojan request opens with the hex sha224 of the password, the uuid as
+// the subscription hands it out
+pub fn trojan_authenticates(first: &[u8], uuid: &Uuid) -> bool {
+    let hash = Sha224::digest(uuid.to_string().as_bytes());
+    let hex: String = hash.iter().map(|x| format!("{x:02x}")).collect();
+    first.len() >= 56 && same(&first[..56], hex.as_bytes())
+}
+
+// the worker has no address of its own to give as the destination
+fn proxy_header(source: Option<IpAddr>) -> Vec<u8> {
+    let src = SocketAddr::new(source.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)), 0);
+    let dst = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 443);
+    proxy_protocol::encode_v2(src, dst)
+}
+
+// relays `stream` to the fallback. whatever was already read from it is
+// still in there, so the backend sees the connection from its first byte.
+pub async fn forward<S>(
+    stream: &mut S,
+    fallback: &Fallback,
+    dialer: &dyn Dialer,
+    source: Option<IpAddr>,
+    timeouts: Timeouts,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let mut remote = dialer.dial(&fallback.dest).await?;
+    if fallback.xver == 2 {
+        remote.write_all(&proxy_header(source)).await?;
+    }
+    relay_bidirectional(stream, &mut remote, timeouts).await?;
+    Ok(())
+}
+
+impl<'a> ProxyStream<'a> {
+    // the buffer holds every byte read so far. nothing more is read before
+    // the backend is dialed, the same as a handshake that went through.
+    pub async fn process_fallback(&mut self) -> Result<()> {
+        let alpn = self.alpn.as_deref().map(alpn).unwrap_or_default();
+        let Some(fallback) = self
+            .config
+            .fallbacks
+            .select(&self.config.host, alpn, &self.buffer)
+        else {
+            return Err(Error::RustError("protocol not implemented".to_string()));
+        };
+        let fallback = fallback.clone();
+        crate::log!("[fallback]: to {}", fallback.dest);
+        let source = self.source.as_deref().and_then(|x| x.parse().ok());
+        let timeouts = self.config.policy.timeouts(Network::Tcp);
+        forward(self, &fallback, &SocketDialer, source, timeouts).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbound::dialer::BoxStream;
+    use async_trait::async_trait;
+    use serde_json::json;
+    use std::cell::RefCell;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, DuplexStream};
+
+    const UUID: &str = "f282b878-8711-45a1-8c69-5564172123c1";
+
+    // hands out the server halves it was given, by the address dialed
+    #[derive(Default)]
+    struct Backends(RefCell<Vec<(String, DuplexStream)>>);
+
+    impl Backends {
+        fn add(&self, addr: &str) -> DuplexStream {
+            let (client, server) = tokio::io::duplex(4096);
+            self.0.borrow_mut().push((addr.to_string(), client));
+            server
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl Dialer for Backends {
+        async fn dial(&self, target: &Target) -> Result<BoxStream> {
+            let mut backends = self.0.borrow_mut();
+            let i = backends
+                .iter()
+                .position(|(x, _)| *x == target.addr)
+                .unwrap();
+            Ok(Box::new(backends.remove(i).1))
+        }
+    }
+
+    // answers one request with `body`, returning everything it read
+    async fn nginx(mut stream: DuplexStream, body: &str) -> Vec<u8> {
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            let mut buf = [0u8; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend(&buf[..n]);
+        }
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+        request
+    }
+
+    fn fallbacks() -> Fallbacks {
+        Fallbacks::from_json(
+            &json!([
+                {"dest": "nginx:80"},
+                {"alpn": "h2", "dest": "h2c:8080"},
+                {"path": "/admin", "dest": "admin:80", "xver": 2},
+            ]),
+            "FALLBACKS",
+        )
+        .unwrap()
+    }
+
+    async fn request(alpn: &str, request: &[u8], backend: &str, body: &str) -> Vec<u8> {
+        let fallbacks = fallbacks();
+        let fallback = fallbacks
+            .select("example.com", alpn, request)
+            .unwrap()
+            .clone();
+        assert_eq!(fallback.dest.addr, backend);
+
+        let backends = Backends::default();
+        let server = tokio::spawn({
+            let (server, body) = (backends.add(backend), body.to_string());
+            async move { nginx(server, &body).await }
+        });
+        let (mut client, mut stream) = tokio::io::duplex(4096);
+        client.write_all(request).await.unwrap();
+        let source = Some("192.0.2.1".parse().unwrap());
+        let timeouts = Timeouts::idle(Duration::from_secs(10));
+        let relay = forward(&mut stream, &fallback, &backends, source, timeouts);
+        let reading = async {
+            let mut response = vec![0u8; 38 + body.len()];
+            client.read_exact(&mut response).await.unwrap();
+            drop(client);
+            response
+        };
+        let (relayed, response) = futures_util::join!(relay, reading);
+        relayed.unwrap();
+        let expected = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        assert_eq!(response, expected.as_bytes());
+        server.await.unwrap()
+    }
+
+    #[tokio::test(flavor = "current_thread", start_paused = true)]
+    async fn test_forward() {
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                // the request the proxy port got reaches the site byte for byte
+                let get = b"GET /index.html?x=1 HTTP/1.1\r\nHost: example.com\r\n\r\n";
+                assert_eq!(request("http/1.1", get, "nginx", "site").await, get);
+
+                let preface = b"PRI * HTTP/2.0\r\n\r\n";
+                assert_eq!(request("h2", preface, "h2c", "h2").await, preface);
+
+                // the client's address goes first where it is asked for
+                let admin = b"GET /admin HTTP/1.1\r\n\r\n";
+                let seen = request("http/1.1", admin, "admin", "admin").await;
+                let src = "192.0.2.1:0".parse().unwrap();
+                let header = proxy_protocol::encode_v2(src, "0.0.0.0:443".parse().unwrap());
+                assert_eq!(seen[..header.len()], header);
+                assert_eq!(seen[header.len()..], admin[..]);
+            })
+            .await;
+    }
+
+    #[test]
+    fn test_select() {
+        let fallbacks = Fallbacks::from_json(
+            &json!([
+                {"dest": "any:80"},
+                {"name": "a.example.com", "dest": "a:80"},
+                {"name": "a.example.com", "alpn": "h2", "dest": "a-h2:80"},
+                {"path": "/ws", "dest": "ws:80"},
+                {"path": "/ws", "dest": "ws-2:80"},
+            ]),
+            "FALLBACKS",
+        )
+        .unwrap();
+        let dest = |name, alpn, first: &[u8]| {
+            fallbacks
+                .select(name, alpn, first)
+                .unwrap()
+                .dest
+                .addr
+                .clone()
+        };
+        let get = b"GET / HTTP/1.1\r\n";
+        assert_eq!(dest("b.example.com", "http/1.1", get), "any");
+        assert_eq!(dest("a.example.com", "http/1.1", get), "a");
+        assert_eq!(dest("a.example.com", "h2", get), "a-h2");
+        assert_eq!(
+            dest("b.example.com", "h2", b"GET /ws?ed=2048 HTTP/1.1\r\n"),
+            "ws"
+        );
+        assert_eq!(dest("b.example.com", "h2", b"\x16\x03\x01\x02\x00"), "any");
+
+        let fallbacks =
+            Fallbacks::from_json(&json!([{"alpn": "h2", "dest": "a:80"}]), "F").unwrap();
+        assert!(fallbacks.select("example.com", "http/1.1", get).is_none());
+        assert_eq!(alpn("HTTP/2"), "h2");
+    }
+
+    #[test]
+    fn test_http_path() {
+        assert_eq!(
+            http_path(b"GET /a/b?c=d HTTP/1.1\r\nHost: x\r\n"),
+            Some("/a/b")
+        );
+        assert_eq!(http_path(b"POST / HTTP/1.0\r\n"), Some("/"));
+        assert_eq!(http_path(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"), None);
+        assert_eq!(http_path(b"\x00\xf2\x82\xb8\x78"), None);
+    }
+
+    #[test]
+    fn test_authenticates() {
+        let uuid = Uuid::parse_str(UUID).unwrap();
+        // a vless tcp request for example.com:443
+        let mut vless = vec![0];
+        vless.extend(uuid.as_bytes());
+        vless.extend([0, 1, 0x01, 0xbb, 2, 11]);
+        vless.extend(b"example.com");
+        assert!(vless_authenticates(&vless, &uuid));
+        assert!(!vless_authenticates(&vless, &Uuid::max()));
+        assert!(!vless_authenticates(&vless[..10], &uuid));
+
+        // sha224 of the uuid as text
+        let mut trojan = b"530069915b5483a30279943e0e60605b7b3a7b54817e82b1f19c1bb8".to_vec();
+        trojan.extend(b"\r\n\x01\x03\x0bexample.com\x01\xbb\r\n");
+        assert!(trojan_authenticates(&trojan, &uuid));
+        assert!(!trojan_authenticates(&trojan, &Uuid::max()));
+        assert!(!trojan_authenticates(b"GET / HTTP/1.1\r\n", &uuid));
+    }
+
+    #[test]
+    fn test_fallbacks_config() {
+        let fallbacks = Fallbacks::from_json(
+            &json!([{"name": "example.com", "alpn": "h2", "path": "/x", "dest": "[::1]:8080", "xver": 2}]),
+            "FALLBACKS",
+        )
+        .unwrap();
+        assert_eq!(
+            fallbacks,
+            Fallbacks(vec![Fallback {
+                name: "example.com".to_string(),
+                alpn: "h2".to_string(),
+                path: "/x".to_string(),
+                dest: Target::new("::1".to_string(), 8080, Network::Tcp),
+                xver: 2,
+            }])
+        );
+
+        let errors = Fallbacks::from_json(
+            &json!([{"dest": 80}, {"dest": "a:80", "path": "x", "xver": 1, "sni": "a"}, {}]),
+            "FALLBACKS",
+        )
+        .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                ConfigError::new("FALLBACKS[0].dest", "expected \"host:port\""),
+                ConfigError::new("FALLBACKS[1].path", "expected a path starting with /"),
+                ConfigError::new("FALLBACKS[1].sni", "unknown field"),
+                ConfigError::new(
+                    "FALLBACKS[1].xver",
+                    "only proxy protocol version 2 is supported"
+                ),
+                ConfigError::new("FALLBACKS[2].dest", "missing"),
+            ]
+        );
+        assert!(Fallbacks::from_json(&json!({}), "FALLBACKS").is_err());
+    }
+}
@@ -3,5 +3,6 @@ pub mod vless;
 pub mod trojan;
 pub mod shadowsocks;
 pub mod dns;
+pub mod fallback;
 pub mod conn;
 pub use conn::*;
@@ -1,33 +0,0 @@
-use super::ProxyStream;
-use crate::common::{parse_addr, parse_port};
-use worker::*;
-
-impl <'a> ProxyStream<'a> {
-    pub async fn process_shadowsocks(&mut self) -> Result<()> {
-        // read port and address
-        let remote_addr = parse_addr(self).await?;
-        let remote_port = parse_port(self).await?;
-        
-        let is_tcp = true; // difficult to detect udp packet from shadowsocks
-        
-        if is_tcp {
-            let addr_pool = [
-                (remote_addr.clone(), remote_port),
-                (self.config.proxy_addr.clone(), self.config.proxy_port)
-            ];
-
-            // send header
-            for (target_addr, target_port) in addr_pool {
-                if let Err(e) = self.handle_tcp_outbound(target_addr, target_port).await {
-                    console_error!("error handling tcp: {}", e)
-                }
-            }
-        } else {
-            if let Err(e) = self.handle_udp_outbound().await {
-                console_error!("error handling udp: {}", e)
-            }
-        }
-
-        Ok(())
-    }
-}
\ No newline at end of file
@@ -0,0 +1,19 @@
+pub mod ss2022;
+
+use super::ProxyStream;
+use crate::outbound::{Network, Target};
+use crate::common::{parse_addr, parse_port};
+use worker::*;
+
+impl <'a> ProxyStream<'a> {
+    pub async fn process_shadowsocks(&mut self) -> Result<()> {
+        // read port and address
+        let remote_addr = parse_addr(self).await?;
+        let remote_port = parse_port(self).await?;
+        
+        let is_tcp = true; // difficult to detect udp packet from shadowsocks
+        
+        let network = if is_tcp { Network::Tcp } else { Network::Udp };
+        self.dispatch("ss", Target::new(remote_addr, remote_port, network)).await
+    }
+}
\ No newline at end of file
@@ -0,0 +1,1027 @@
+use crate::common::aead::{Aes128Gcm, Aes256Gcm};
+use crate::common::error::ProtocolError;
+use crate::common::time::{Clock, SystemClock};
+use crate::common::{parse_addr, parse_port, read_u16_be, u16_be};
+use crate::config::ConfigError;
+use crate::outbound::{Network, Target};
+use crate::proxy::vmess::chunk::{Aead, TAG_SIZE};
+use crate::proxy::vmess::users;
+use crate::proxy::ProxyStream;
+
+use aes::cipher::{BlockDecrypt, KeyInit};
+use aes::{Aes128, Aes256};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chacha20poly1305::ChaCha20Poly1305;
+use futures_util::future::{self, Either};
+use serde_json::Value;
+use siren_hash::blake3::Blake3;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Cursor};
+use std::pin::{pin, Pin};
+use std::rc::Rc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use worker::*;
+
+// how far a client clock may be off
+pub const TIMESTAMP_WINDOW: u64 = 30;
+// how long a salt is remembered. a request replayed later than this is
+// refused for its timestamp instead.
+pub const SALT_WINDOW: u64 = 2 * TIMESTAMP_WINDOW;
+// salts remembered at most. once full, handshakes fail until the oldest
+// age out: forgetting early would let a replay through. only requests that
+// authenticated take a place, so filling it takes a key.
+pub const SALT_CAPACITY: usize = 1 << 16;
+// the largest payload of a chunk
+pub const MAX_PAYLOAD: usize = 0xffff;
+
+const SESSION_SUBKEY: &str = "shadowsocks 2022 session subkey";
+const IDENTITY_SUBKEY: &str = "shadowsocks 2022 identity subkey";
+
+const REQUEST: u8 = 0;
+const RESPONSE: u8 = 1;
+// type, timestamp and the length of the variable header
+const FIXED_HEADER_LEN: usize = 1 + 8 + 2;
+const IDENTITY_HEADER_LEN: usize = 16;
+
+// https://github.com/Shadowsocks-NET/shadowsocks-specs/blob/main/2022-1-shadowsocks-2022-edition.md
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    Aes128Gcm,
+    Aes256Gcm,
+    // no identity headers, single user only
+    ChaCha20Poly1305,
+}
+
+impl Method {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "2022-blake3-aes-128-gcm" => Some(Self::Aes128Gcm),
+            "2022-blake3-aes-256-gcm" => Some(Self::Aes256Gcm),
+            "2022-blake3-chacha20-poly1305" => Some(Self::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+
+    // of the psks, salts and session keys alike
+    pub fn key_len(self) -> usize {
+        match self {
+            Self::Aes128Gcm => 16,
+            Self::Aes256Gcm | Self::ChaCha20Poly1305 => 32,
+        }
+    }
+
+    fn aead(self, key: &[u8]) -> Box<dyn Aead> {
+        match self {
+            Self::Aes128Gcm => Box::new(Aes128Gcm::new(key.into())),
+            Self::Aes256Gcm => Box::new(Aes256Gcm::new(key.into())),
+            Self::ChaCha20Poly1305 => Box::new(ChaCha20Poly1305::new(key.into())),
+        }
+    }
+}
+
+impl Aead for Aes256Gcm {
+    fn seal_in_place(&self, nonce: &[u8; 12], buf: &mut [u8]) -> Result<[u8; TAG_SIZE]> {
+        aes_gcm::aead::AeadInPlace::encrypt_in_place_detached(self, nonce.into(), b"", buf)
+            .map(Into::into)
+            .map_err(|e| Error::RustError(e.to_string()))
+    }
+
+    fn open_in_place(
+        &self,
+        nonce: &[u8; 12],
+        buf: &mut [u8],
+        tag: &[u8],
+    ) -> std::result::Result<(), ProtocolError> {
+        aes_gcm::aead::AeadInPlace::decrypt_in_place_detached(
+            self,
+            nonce.into(),
+            b"",
+            buf,
+            tag.into(),
+        )
+        .map_err(|_| ProtocolError::AuthFailed("chunk authentication failed"))
+    }
+}
+
+fn derive_key(context: &str, psk: &[u8], salt: &[u8]) -> [u8; 32] {
+    let mut hasher = Blake3::new_derive_key(context);
+    hasher.update(psk);
+    hasher.update(salt);
+    hasher.finalize()
+}
+
+// one direction of a session. the nonce is a little endian count of the
+// chunks sealed or opened so far.
+struct Session {
+    aead: Box<dyn Aead>,
+    nonce: u64,
+}
+
+impl Session {
+    fn new(method: Method, psk: &[u8], salt: &[u8]) -> Self {
+        let key = derive_key(SESSION_SUBKEY, psk, salt);
+        Self {
+            aead: method.aead(&key[..method.key_len()]),
+            nonce: 0,
+        }
+    }
+
+    fn nonce(&mut self) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[..8].copy_from_slice(&self.nonce.to_le_bytes());
+        self.nonce += 1;
+        nonce
+    }
+
+    fn seal(&mut self, data: &[u8], out: &mut Vec<u8>) -> Result<()> {
+        let start = out.len();
+        out.extend_from_slice(data);
+        let nonce = self.nonce();
+        let tag = self.aead.seal_in_place(&nonce, &mut out[start..])?;
+        out.extend_from_slice(&tag);
+        Ok(())
+    }
+
+    // opens `buf` in place, leaving the plaintext
+    fn open(&mut self, buf: &mut Vec<u8>) -> std::result::Result<(), ProtocolError> {
+        let Some(len) = buf.len().checked_sub(TAG_SIZE) else {
+            return Err(ProtocolError::Truncated("chunk shorter than its tag"));
+        };
+        let nonce = self.nonce();
+        let (data, tag) = buf.split_at_mut(len);
+        self.aead.open_in_place(&nonce, data, tag)?;
+        buf.truncate(len);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Client {
+    pub email: String,
+    psk: Vec<u8>,
+}
+
+// the `SHADOWSOCKS` binding, a shadowsocks 2022 inbound. with `clients`
+// its psk is the identity psk of the multi-user mode: a request names its
+// user in an identity header and the session is keyed with theirs.
+#[derive(Debug)]
+pub struct Shadowsocks2022 {
+    method: Method,
+    psk: Vec<u8>,
+    clients: Vec<Client>,
+    // what identity headers carry, the start of the blake3 of a client's
+    // psk, to the client
+    identities: HashMap<[u8; 16], usize>,
+}
+
+fn parse_psk(
+    value: &Value,
+    method: Method,
+    path: &str,
+) -> std::result::Result<Vec<u8>, ConfigError> {
+    let Some(psk) = value.as_str() else {
+        return Err(ConfigError::new(path, "expected a base64 string"));
+    };
+    match STANDARD.decode(psk.trim()) {
+        Ok(x) if x.len() == method.key_len() => Ok(x),
+        Ok(x) => Err(ConfigError::new(
+            path,
+            format!("expected a {} byte key, got {}", method.key_len(), x.len()),
+        )),
+        Err(e) => Err(ConfigError::new(path, format!("invalid base64: {e}"))),
+    }
+}
+
+impl Shadowsocks2022 {
+    pub fn new(method: Method, psk: Vec<u8>) -> Self {
+        Self {
+            method,
+            psk,
+            clients: Vec::new(),
+            identities: HashMap::new(),
+        }
+    }
+
+    // identity headers are aes blocks, there is no multi-user chacha20
+    pub fn with_client(mut self, email: &str, psk: Vec<u8>) -> Self {
+        let hash = siren_hash::blake3::hash(&psk);
+        let mut identity = [0u8; 16];
+        identity.copy_from_slice(&hash[..16]);
+        self.identities.insert(identity, self.clients.len());
+        self.clients.push(Client {
+            email: email.to_string(),
+            psk,
+        });
+        self
+    }
+
+    // xray's shadowsocks settings: `{"method": "2022-blake3-aes-128-gcm",
+    // "password": "<base64 psk>", "clients": [{"password", "email"}]}`
+    pub fn from_json(value: &Value, path: &str) -> std::result::Result<Self, Vec<ConfigError>> {
+        let Some(object) = value.as_object() else {
+            return Err(vec![ConfigError::new(path, "expected an object")]);
+        };
+
+        let mut errors = Vec::new();
+        let method_path = format!("{path}.method");
+        let method = match object
+            .get("method")
+            .map(|x| x.as_str().and_then(Method::from_name))
+        {
+            Some(Some(x)) => Some(x),
+            Some(None) => {
+                errors.push(ConfigError::new(
+                    &method_path,
+                    "expected one of 2022-blake3-aes-128-gcm, 2022-blake3-aes-256-gcm \
+                     and 2022-blake3-chacha20-poly1305",
+                ));
+                None
+            }
+            None => {
+                errors.push(ConfigError::new(&method_path, "missing"));
+                None
+            }
+        };
+        for key in object.keys() {
+            if !matches!(key.as_str(), "method" | "password" | "clients") {
+                errors.push(ConfigError::new(&format!("{path}.{key}"), "unknown field"));
+            }
+        }
+        if !object.contains_key("password") {
+            errors.push(ConfigError::new(&format!("{path}.password"), "missing"));
+        }
+        let Some(method) = method else {
+            return Err(errors);
+        };
+
+        let mut psk = Vec::new();
+        if let Some(value) = object.get("password") {
+            match parse_psk(value, method, &format!("{path}.password")) {
+                Ok(x) => psk = x,
+                Err(e) => errors.push(e),
+            }
+        }
+        let mut ss = Shadowsocks2022::new(method, psk);
+
+        let clients_path = format!("{path}.clients");
+        let empty = Vec::new();
+        let clients = match object.get("clients").map(Value::as_array) {
+            None => &empty,
+            Some(Some(x)) => x,
+            Some(None) => {
+                errors.push(ConfigError::new(&clients_path, "expected an array"));
+                &empty
+            }
+        };
+        if !clients.is_empty() && method == Method::ChaCha20Poly1305 {
+            errors.push(ConfigError::new(
+                &clients_path,
+                "2022-blake3-chacha20-poly1305 has no multi-user mode",
+            ));
+        }
+        for (i, client) in clients.iter().enumerate() {
+            let path = format!("{clients_path}[{i}]");
+            let Some(client) = client.as_object() else {
+                errors.push(ConfigError::new(&path, "expected an object"));
+                continue;
+            };
+            let (mut email, mut psk) = (None, None);
+            for (key, value) in client {
+                let path = format!("{path}.{key}");
+                match key.as_str() {
+                    "email" => match value.as_str() {
+                        Some(x) if ss.clients.iter().any(|c| c.email == x) => {
+                            errors.push(ConfigError::new(&path, format!("duplicate user {x:?}")))
+                        }
+                        Some(x) => email = Some(x.to_string()),
+                        None => errors.push(ConfigError::new(&path, "expected a string")),
+                    },
+                    "password" => match parse_psk(value, method, &path) {
+                        Ok(x) if ss.clients.iter().any(|c| c.psk == x) => {
+                            errors.push(ConfigError::new(&path, "the psk of another user"))
+                        }
+                        Ok(x) => psk = Some(x),
+                        Err(e) => errors.push(e),
+                    },
+                    _ => errors.push(ConfigError::new(&path, "unknown field")),
+                }
+            }
+            for key in ["email", "password"] {
+                if !client.contains_key(key) {
+                    errors.push(ConfigError::new(&format!("{path}.{key}"), "missing"));
+                }
+            }
+            if let (Some(email), Some(psk)) = (email, psk) {
+                ss = ss.with_client(&email, psk);
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(ss)
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn method(&self) -> Method {
+        self.method
+    }
+
+    pub fn clients(&self) -> &[Client] {
+        &self.clients
+    }
+
+    // the salt, an identity header in the multi-user mode, and the sealed
+    // fixed header: what `identify` needs to tell a request apart
+    pub fn header_len(&self) -> usize {
+        let identity = match self.clients.is_empty() {
+            true => 0,
+            false => IDENTITY_HEADER_LEN,
+        };
+        self.method.key_len() + identity + FIXED_HEADER_LEN + TAG_SIZE
+    }
+
+    // the request `buf` starts with, None when it is none of this inbound's:
+    // the identity header names no client or the fixed header does not open
+    pub fn identify(&self, buf: &[u8]) -> Option<Request> {
+        let buf = buf.get(..self.header_len())?;
+        let (salt, mut rest) = buf.split_at(self.method.key_len());
+
+        let mut client = None;
+        if !self.clients.is_empty() {
+            let mut identity = [0u8; IDENTITY_HEADER_LEN];
+            identity.copy_from_slice(&rest[..IDENTITY_HEADER_LEN]);
+            let key = derive_key(IDENTITY_SUBKEY, &self.psk, salt);
+            match self.method {
+                Method::Aes128Gcm => {
+                    Aes128::new(key[..16].into()).decrypt_block((&mut identity).into())
+                }
+                _ => Aes256::new((&key).into()).decrypt_block((&mut identity).into()),
+            }
+            client = Some(*self.identities.get(&identity)?);
+            rest = &rest[IDENTITY_HEADER_LEN..];
+        }
+        self.open_fixed_header(client, salt, rest)
+    }
+
+    fn open_fixed_header(
+        &self,
+        client: Option<usize>,
+        salt: &[u8],
+        rest: &[u8],
+    ) -> Option<Request> {
+        let psk = match client {
+            Some(i) => &self.clients[i].psk,
+            None => &self.psk,
+        };
+        let mut session = Session::new(self.method, psk, salt);
+        let mut fixed = rest.to_vec();
+        session.open(&mut fixed).ok()?;
+        if fixed[0] != REQUEST {
+            return None;
+        }
+        let mut timestamp = [0u8; 8];
+        timestamp.copy_from_slice(&fixed[1..9]);
+        Some(Request {
+            client,
+            method: self.method,
+            psk: psk.clone(),
+            salt: salt.to_vec(),
+            timestamp: u64::from_be_bytes(timestamp),
+            variable_len: read_u16_be(&fixed[9..]).into(),
+            header_len: self.header_len(),
+            session,
+        })
+    }
+}
+
+// a request whose fixed header opened, not checked against the clock or
+// replays yet
+pub struct Request {
+    // the index of the client in the multi-user mode
+    pub client: Option<usize>,
+    method: Method,
+    // the client's psk, or the inbound's in the single user mode
+    psk: Vec<u8>,
+    salt: Vec<u8>,
+    timestamp: u64,
+    variable_len: usize,
+    // the bytes `identify` read
+    header_len: usize,
+    session: Session,
+}
+
+// what a request asked for, and the session to go on with
+pub struct Opened {
+    pub target: Target,
+    // the data the variable header carried
+    pub payload: Vec<u8>,
+    method: Method,
+    psk: Vec<u8>,
+    salt: Vec<u8>,
+    session: Session,
+}
+
+// takes what `identify` read from `reader`, checks the request against
+// the clock and the salts seen, then reads the variable header
+pub async fn open_request<R>(
+    reader: &mut R,
+    request: Request,
+    filter: &SaltFilter,
+) -> Result<Opened>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let Request {
+        method,
+        psk,
+        salt,
+        timestamp,
+        variable_len,
+        header_len,
+        mut session,
+        ..
+    } = request;
+    let mut header = vec![0u8; header_len];
+    reader.read_exact(&mut header).await?;
+    if timestamp.abs_diff(filter.clock().now()) > TIMESTAMP_WINDOW {
+        return Err(ProtocolError::Timestamp(timestamp).into());
+    }
+    filter.check(&salt)?;
+
+    let mut variable = vec![0u8; variable_len + TAG_SIZE];
+    reader.read_exact(&mut variable).await?;
+    session.open(&mut variable)?;
+
+    // address type, address, port, padding length, padding and payload
+    let mut buf = Cursor::new(variable);
+    let addr = parse_addr(&mut buf).await?;
+    let port = parse_port(&mut buf).await?;
+    let padding = buf.read_u16().await?;
+    let start = buf.position() as usize + usize::from(padding);
+    let variable = buf.into_inner();
+    let Some(payload) = variable.get(start..) else {
+        return Err(ProtocolError::Truncated("padding beyond the header").into());
+    };
+    Ok(Opened {
+        target: Target::new(addr, port, Network::Tcp),
+        payload: payload.to_vec(),
+        method,
+        psk,
+        salt,
+        session,
+    })
+}
+
+// salts of the requests within the window, oldest first
+pub struct SaltFilter {
+    clock: Rc<dyn Clock>,
+    window: u64,
+    capacity: usize,
+    order: RefCell<VecDeque<(u64, Vec<u8>)>>,
+    seen: RefCell<HashSet<Vec<u8>>>,
+}
+
+impl SaltFilter {
+    pub fn new(window: u64, capacity: usize) -> Self {
+        Self {
+            clock: Rc::new(SystemClock),
+            window,
+            capacity,
+            order: RefCell::default(),
+            seen: RefCell::default(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Rc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // remembers `salt`, unless it was seen within the window or there is
+    // no room for it
+    pub fn check(&self, salt: &[u8]) -> std::result::Result<(), ProtocolError> {
+        let now = self.clock.now();
+        let (mut order, mut seen) = (self.order.borrow_mut(), self.seen.borrow_mut());
+        while order
+            .front()
+            .is_some_and(|x| now.saturating_sub(x.0) >= self.window)
+        {
+            if let Some((_, salt)) = order.pop_front() {
+                seen.remove(&salt);
+            }
+        }
+        if seen.contains(salt) {
+            return Err(ProtocolError::Replayed);
+        }
+        if order.len() >= self.capacity {
+            return Err(ProtocolError::LengthOverflow("too many salts to remember"));
+        }
+        seen.insert(salt.to_vec());
+        order.push_back((now, salt.to_vec()));
+        Ok(())
+    }
+}
+
+thread_local! {
+    static SHARED: Rc<SaltFilter> = Rc::new(SaltFilter::new(SALT_WINDOW, SALT_CAPACITY));
+}
+
+// like the vmess replay filter, one per isolate
+pub fn shared() -> Rc<SaltFilter> {
+    SHARED.with(Rc::clone)
+}
+
+// the chunks after the request header: a sealed two byte length, then the
+// sealed payload. the response starts with its own salt and a fixed header
+// in front of the first chunk.
+pub struct Ss2022Stream<S> {
+    inner: S,
+    opener: Session,
+    // the sealed length or payload being read, and how long it is
+    sealed: Vec<u8>,
+    need: usize,
+    reading_len: bool,
+    // opened and not read yet, the request payload first
+    plain: Vec<u8>,
+    pos: usize,
+    sealer: Session,
+    // the salt and what the response header holds besides the length,
+    // until the first write
+    header: Option<(Vec<u8>, Vec<u8>)>,
+    // sealed and not through to `inner` yet
+    pending: Vec<u8>,
+    sent: usize,
+}
+
+impl<S> Ss2022Stream<S> {
+    pub fn new(inner: S, opened: Opened, clock: &dyn Clock) -> Self {
+        let Opened {
+            payload,
+            method,
+            psk,
+            salt: request_salt,
+            session,
+            ..
+        } = opened;
+        let mut salt = vec![0u8; method.key_len()];
+        crate::common::random(&mut salt);
+        let mut fixed = vec![RESPONSE];
+        fixed.extend_from_slice(&clock.now().to_be_bytes());
+        fixed.extend_from_slice(&request_salt);
+        Self {
+            inner,
+            opener: session,
+            sealed: Vec::new(),
+            need: 2 + TAG_SIZE,
+            reading_len: true,
+            plain: payload,
+            pos: 0,
+            sealer: Session::new(method, &psk, &salt),
+            header: Some((salt, fixed)),
+            pending: Vec::new(),
+            sent: 0,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Ss2022Stream<S> {
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.sent < self.pending.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.sent..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.sent += n;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn seal(&mut self, data: &[u8]) -> Result<()> {
+        self.pending.clear();
+        self.sent = 0;
+        let len = u16_be(data.len() as u16);
+        match self.header.take() {
+            Some((salt, mut fixed)) => {
+                self.pending.extend_from_slice(&salt);
+                fixed.extend_from_slice(&len);
+                self.sealer.seal(&fixed, &mut self.pending)?;
+            }
+            None => self.sealer.seal(&len, &mut self.pending)?,
+        }
+        self.sealer.seal(data, &mut self.pending)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Ss2022Stream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.pos < this.plain.len() {
+                let n = buf.remaining().min(this.plain.len() - this.pos);
+                buf.put_slice(&this.plain[this.pos..this.pos + n]);
+                this.pos += n;
+                return Poll::Ready(Ok(()));
+            }
+
+            while this.sealed.len() < this.need {
+                let filled = this.sealed.len();
+                this.sealed.resize(this.need, 0);
+                let mut read = ReadBuf::new(&mut this.sealed[filled..]);
+                let res = Pin::new(&mut this.inner).poll_read(cx, &mut read);
+                let n = read.filled().len();
+                this.sealed.truncate(filled + n);
+                ready!(res)?;
+                if n == 0 {
+                    // the client may hang up between chunks only
+                    if filled == 0 && this.reading_len {
+                        return Poll::Ready(Ok(()));
+                    }
+                    return Poll::Ready(Err(ProtocolError::Truncated("truncated chunk").into()));
+                }
+            }
+
+            this.opener.open(&mut this.sealed)?;
+            if this.reading_len {
+                let len = read_u16_be(&this.sealed);
+                this.need = usize::from(len) + TAG_SIZE;
+                this.sealed.clear();
+            } else {
+                this.plain = std::mem::take(&mut this.sealed);
+                this.pos = 0;
+                this.need = 2 + TAG_SIZE;
+            }
+            this.reading_len = !this.reading_len;
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Ss2022Stream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let n = buf.len().min(MAX_PAYLOAD);
+        this.seal(&buf[..n])
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        // the bytes are taken once sealed, what `inner` does not take now
+        // goes out with the next write or flush
+        if let Poll::Ready(Err(e)) = this.poll_send(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+impl<'a> ProxyStream<'a> {
+    pub async fn process_shadowsocks_2022(
+        &mut self,
+        ss: &Shadowsocks2022,
+        request: Request,
+    ) -> Result<()> {
+        let email = request.client.map(|i| ss.clients[i].email.clone());
+        let filter = shared();
+        let opened = open_request(self, request, &filter).await?;
+
+        // users the vmess table knows by the same email share their limits
+        let users = users::shared(&self.config.uuid);
+        let kicked = match email.as_deref() {
+            Some(email) => users.session(email)?,
+            None => None,
+        };
+
+        let mut metadata = self.metadata("ss2022", opened.target.clone());
+        metadata.user = email;
+        let dispatcher = self.dispatcher.clone();
+        let mut stream = Ss2022Stream::new(&mut *self, opened, filter.clock());
+        let dispatch = dispatcher.dispatch(&metadata, &mut stream);
+        let Some(kicked) = kicked else {
+            return dispatch.await;
+        };
+        let result = match future::select(pin!(dispatch), kicked).await {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => Err(Error::RustError("user removed".to_string())),
+        };
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::time::MockClock;
+    use aes::cipher::BlockEncrypt;
+    use serde_json::json;
+    use tokio::io::AsyncWriteExt;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn psk(byte: u8, method: Method) -> Vec<u8> {
+        vec![byte; method.key_len()]
+    }
+
+    // the client side of a request to example.com:443, `psks` being the
+    // identity psk and the user's in the multi-user mode
+    fn seal_request(
+        method: Method,
+        psks: &[&[u8]],
+        salt: &[u8],
+        timestamp: u64,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let mut out = salt.to_vec();
+        if let [identity_psk, user_psk] = psks {
+            let key = derive_key(IDENTITY_SUBKEY, identity_psk, salt);
+            let mut block = [0u8; 16];
+            block.copy_from_slice(&siren_hash::blake3::hash(user_psk)[..16]);
+            match method {
+                Method::Aes128Gcm => {
+                    Aes128::new(key[..16].into()).encrypt_block((&mut block).into())
+                }
+                _ => Aes256::new((&key).into()).encrypt_block((&mut block).into()),
+            }
+            out.extend_from_slice(&block);
+        }
+        let mut session = Session::new(method, psks[psks.len() - 1], salt);
+
+        let mut variable = vec![3, 11];
+        variable.extend_from_slice(b"example.com");
+        variable.extend_from_slice(&443u16.to_be_bytes());
+        variable.extend_from_slice(&[0, 3, 0xaa, 0xbb, 0xcc]);
+        variable.extend_from_slice(payload);
+        let mut fixed = vec![REQUEST];
+        fixed.extend_from_slice(&timestamp.to_be_bytes());
+        fixed.extend_from_slice(&(variable.len() as u16).to_be_bytes());
+        session.seal(&fixed, &mut out).unwrap();
+        session.seal(&variable, &mut out).unwrap();
+        out
+    }
+
+    fn multi_user(method: Method) -> Shadowsocks2022 {
+        Shadowsocks2022::new(method, psk(1, method))
+            .with_client("alice@example.com", psk(2, method))
+            .with_client("bob@example.com", psk(3, method))
+    }
+
+    fn filter() -> SaltFilter {
+        SaltFilter::new(SALT_WINDOW, SALT_CAPACITY).with_clock(Rc::new(MockClock::new(NOW)))
+    }
+
+    async fn open(ss: &Shadowsocks2022, sealed: &[u8], filter: &SaltFilter) -> Result<Opened> {
+        let request = ss
+            .identify(sealed)
+            .ok_or(Error::RustError("unknown".into()))?;
+        open_request(&mut &sealed[..], request, filter).await
+    }
+
+    #[test]
+    fn test_derive_subkeys() {
+        // a 32 byte psk and salt, one full final block the way every
+        // aes-256 session derives. from the reference blake3 crate, which
+        // the hand-written one this replaced agreed with.
+        let psk: Vec<u8> = (0..32).collect();
+        let salt: Vec<u8> = (32..64).collect();
+        let hex = |key: [u8; 32]| key.iter().map(|x| format!("{x:02x}")).collect::<String>();
+        assert_eq!(
+            hex(derive_key(SESSION_SUBKEY, &psk, &salt)),
+            "374fca03e4dae7f998fd7e59c1edfcc8e3197f4db1c19ca1671be3b66a92ddda"
+        );
+        assert_eq!(
+            hex(derive_key(IDENTITY_SUBKEY, &psk, &salt)),
+            "28b2ce924944211fba7a70ee77a36e219a864b6bad4f5e18b7edf7cd8bd342fe"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_identify_users() {
+        for method in [Method::Aes128Gcm, Method::Aes256Gcm] {
+            let ss = multi_user(method);
+            let filter = filter();
+            let identity = psk(1, method);
+            for (i, byte) in [(0, 2), (1, 3)] {
+                let user = psk(byte, method);
+                let salt = vec![byte + 10; method.key_len()];
+                let sealed = seal_request(method, &[&identity, &user], &salt, NOW, b"hello");
+                assert_eq!(ss.identify(&sealed).unwrap().client, Some(i));
+                let opened = open(&ss, &sealed, &filter).await.unwrap();
+                assert_eq!(opened.target.to_string(), "tcp:example.com:443");
+                assert_eq!(opened.payload, b"hello");
+            }
+
+            // a psk that is nobody's, or a request sealed for another user
+            let salt = vec![20; method.key_len()];
+            let stranger = psk(4, method);
+            let sealed = seal_request(method, &[&identity, &stranger], &salt, NOW, b"");
+            assert!(ss.identify(&sealed).is_none());
+            let mut sealed = seal_request(method, &[&identity, &psk(2, method)], &salt, NOW, b"");
+            let key_len = method.key_len();
+            let bob = seal_request(method, &[&identity, &psk(3, method)], &salt, NOW, b"");
+            sealed[key_len..key_len + 16].copy_from_slice(&bob[key_len..key_len + 16]);
+            assert!(ss.identify(&sealed).is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_single_user() {
+        for method in [
+            Method::Aes128Gcm,
+            Method::Aes256Gcm,
+            Method::ChaCha20Poly1305,
+        ] {
+            let ss = Shadowsocks2022::new(method, psk(1, method));
+            let salt = vec![9; method.key_len()];
+            let sealed = seal_request(method, &[&psk(1, method)], &salt, NOW, b"hi");
+            let opened = open(&ss, &sealed, &filter()).await.unwrap();
+            assert_eq!(opened.payload, b"hi");
+            let sealed = seal_request(method, &[&psk(2, method)], &salt, NOW, b"hi");
+            assert!(ss.identify(&sealed).is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay_and_timestamp() {
+        let method = Method::Aes128Gcm;
+        let ss = multi_user(method);
+        let clock = Rc::new(MockClock::new(NOW));
+        let filter = SaltFilter::new(SALT_WINDOW, 2).with_clock(clock.clone());
+        let (identity, user) = (psk(1, method), psk(2, method));
+
+        let sealed = seal_request(method, &[&identity, &user], &[1; 16], NOW, b"");
+        open(&ss, &sealed, &filter).await.unwrap();
+        let e = open(&ss, &sealed, &filter).await.err().unwrap();
+        assert_eq!(e.to_string(), ProtocolError::Replayed.to_string());
+
+        let late = seal_request(method, &[&identity, &user], &[2; 16], NOW + 31, b"");
+        let e = open(&ss, &late, &filter).await.err().unwrap();
+        assert!(e.to_string().contains("outside the window"), "{e}");
+
+        // full until the first salt ages out
+        let sealed = seal_request(method, &[&identity, &user], &[3; 16], NOW, b"");
+        open(&ss, &sealed, &filter).await.unwrap();
+        let sealed = seal_request(method, &[&identity, &user], &[4; 16], NOW, b"");
+        assert!(open(&ss, &sealed, &filter).await.is_err());
+        clock.advance(SALT_WINDOW);
+        let sealed = seal_request(method, &[&identity, &user], &[4; 16], NOW + 30, b"");
+        open(&ss, &sealed, &filter).await.unwrap();
+        assert_eq!(filter.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stream_roundtrip() {
+        let method = Method::Aes256Gcm;
+        let ss = multi_user(method);
+        let (identity, user) = (psk(1, method), psk(3, method));
+        let salt = vec![7; 32];
+        let mut sealed = seal_request(method, &[&identity, &user], &salt, NOW, b"first");
+        // the client's session goes on with the nonces after its header
+        let mut client = Session::new(method, &user, &salt);
+        client.nonce = 2;
+        let body = vec![0x5a; MAX_PAYLOAD + 10];
+        for piece in [&body[..MAX_PAYLOAD], &body[MAX_PAYLOAD..]] {
+            client
+                .seal(&(piece.len() as u16).to_be_bytes(), &mut sealed)
+                .unwrap();
+            client.seal(piece, &mut sealed).unwrap();
+        }
+
+        let opened = open(&ss, &sealed, &filter()).await.unwrap();
+        let rest = &sealed[sealed.len() - (2 * (2 + 2 * TAG_SIZE) + body.len())..];
+        let (mut client_side, server_side) = tokio::io::duplex(1 << 20);
+        client_side.write_all(rest).await.unwrap();
+        client_side.shutdown().await.unwrap();
+        let mut stream = Ss2022Stream::new(server_side, opened, &MockClock::new(NOW));
+
+        let mut read = Vec::new();
+        stream.read_to_end(&mut read).await.unwrap();
+        assert_eq!(&read[..5], b"first");
+        assert_eq!(read[5..], body);
+
+        stream.write_all(b"response").await.unwrap();
+        stream.write_all(b"more").await.unwrap();
+        stream.flush().await.unwrap();
+        drop(stream);
+
+        // the response, keyed with the user's psk and the server's salt
+        let mut response = Vec::new();
+        client_side.read_to_end(&mut response).await.unwrap();
+        let mut server = Session::new(method, &user, &response[..32]);
+        let fixed_len = 1 + 8 + 32 + 2 + TAG_SIZE;
+        let mut fixed = response[32..32 + fixed_len].to_vec();
+        server.open(&mut fixed).unwrap();
+        assert_eq!(fixed[0], RESPONSE);
+        assert_eq!(u64::from_be_bytes(fixed[1..9].try_into().unwrap()), NOW);
+        assert_eq!(fixed[9..41], salt);
+        assert_eq!(fixed[41..], 8u16.to_be_bytes());
+        let rest = &response[32 + fixed_len..];
+        let mut payload = rest[..8 + TAG_SIZE].to_vec();
+        server.open(&mut payload).unwrap();
+        assert_eq!(payload, b"response");
+        let mut len = rest[8 + TAG_SIZE..8 + 2 * TAG_SIZE + 2].to_vec();
+        server.open(&mut len).unwrap();
+        assert_eq!(len, 4u16.to_be_bytes());
+    }
+
+    #[test]
+    fn test_from_json() {
+        let method = Method::Aes128Gcm;
+        let b64 = |byte| STANDARD.encode(psk(byte, method));
+        let ss = Shadowsocks2022::from_json(
+            &json!({
+                "method": "2022-blake3-aes-128-gcm",
+                "password": b64(1),
+                "clients": [
+                    {"password": b64(2), "email": "alice@example.com"},
+                    {"password": b64(3), "email": "bob@example.com"},
+                ],
+            }),
+            "SHADOWSOCKS",
+        )
+        .unwrap();
+        assert_eq!(ss.method(), method);
+        let emails: Vec<_> = ss.clients().iter().map(|x| x.email.as_str()).collect();
+        assert_eq!(emails, ["alice@example.com", "bob@example.com"]);
+        assert_eq!(ss.header_len(), 16 + 16 + FIXED_HEADER_LEN + TAG_SIZE);
+
+        let errors = Shadowsocks2022::from_json(
+            &json!({
+                "method": "2022-blake3-aes-128-gcm",
+                "password": STANDARD.encode([0u8; 32]),
+                "clients": [
+                    {"password": b64(2), "email": "alice@example.com"},
+                    {"password": b64(2), "email": "alice@example.com", "level": 0},
+                    {"email": "carol@example.com"},
+                ],
+                "network": "tcp",
+            }),
+            "SHADOWSOCKS",
+        )
+        .unwrap_err();
+        let paths: Vec<_> = errors.iter().map(|x| x.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "SHADOWSOCKS.network",
+                "SHADOWSOCKS.password",
+                "SHADOWSOCKS.clients[1].email",
+                "SHADOWSOCKS.clients[1].level",
+                "SHADOWSOCKS.clients[1].password",
+                "SHADOWSOCKS.clients[2].password",
+            ]
+        );
+        assert!(
+            errors[1].message.contains("16 byte key"),
+            "{}",
+            errors[1].message
+        );
+
+        let errors = Shadowsocks2022::from_json(
+            &json!({
+                "method": "2022-blake3-chacha20-poly1305",
+                "password": STANDARD.encode([1u8; 32]),
+                "clients": [{"password": STANDARD.encode([2u8; 32]), "email": "a"}],
+            }),
+            "SHADOWSOCKS",
+        )
+        .unwrap_err();
+        assert_eq!(errors[0].path, "SHADOWSOCKS.clients");
+        let errors = Shadowsocks2022::from_json(&json!({"method": "aes-128-gcm"}), "SHADOWSOCKS");
+        let paths: Vec<_> = errors.unwrap_err().into_iter().map(|x| x.path).collect();
+        assert_eq!(paths, ["SHADOWSOCKS.method", "SHADOWSOCKS.password"]);
+    }
+}
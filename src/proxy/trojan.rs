@@ -1,4 +1,5 @@
 use super::ProxyStream;
+use crate::outbound::{Network, Target};
 use tokio::io::AsyncReadExt;
 use crate::common::{parse_addr, parse_port};
 use worker::*;
@@ -23,24 +24,7 @@ impl <'a> ProxyStream<'a> {
         // remove crlf
         self.read_u16().await?;
 
-        if is_tcp {
-            let addr_pool = [
-                (remote_addr.clone(), remote_port),
-                (self.config.proxy_addr.clone(), self.config.proxy_port)
-            ];
-
-            // send header
-            for (target_addr, target_port) in addr_pool {
-                if let Err(e) = self.handle_tcp_outbound(target_addr, target_port).await {
-                    console_error!("error handling tcp: {}", e)
-                }
-            }
-        } else {
-            if let Err(e) = self.handle_udp_outbound().await {
-                console_error!("error handling udp: {}", e)
-            }
-        }
-
-        Ok(())
+        let network = if is_tcp { Network::Tcp } else { Network::Udp };
+        self.dispatch("trojan", Target::new(remote_addr, remote_port, network)).await
     }
 }
\ No newline at end of file
@@ -1,4 +1,5 @@
 use super::ProxyStream;
+use crate::outbound::{Network, Target};
 use crate::common::{parse_addr, parse_port};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use uuid::Uuid;
@@ -28,24 +29,11 @@ impl <'a> ProxyStream<'a> {
         let remote_addr = parse_addr(self).await?;
 
         if is_tcp {
-            let addr_pool = [
-                (remote_addr.clone(), remote_port),
-                (self.config.proxy_addr.clone(), self.config.proxy_port)
-            ];
-
             // send header
-            self.write(&[0u8; 2]).await?;
-            for (target_addr, target_port) in addr_pool {
-                if let Err(e) = self.handle_tcp_outbound(target_addr, target_port).await {
-                    console_error!("error handling tcp: {}", e)
-                }
-            }
-        } else {
-            if let Err(e) = self.handle_udp_outbound().await {
-                console_error!("error handling udp: {}", e)
-            }
+            self.write_all(&[0u8; 2]).await?;
         }
 
-        Ok(())
+        let network = if is_tcp { Network::Tcp } else { Network::Udp };
+        self.dispatch("vless", Target::new(remote_addr, remote_port, network)).await
     }
 }
\ No newline at end of file
@@ -0,0 +1,267 @@
+use crate::common::error::ProtocolError;
+use crate::common::hash;
+use crate::common::time::{Clock, SystemClock};
+
+use super::labels::{Labels, V2RAY};
+
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::Aes128;
+use std::borrow::Borrow;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+use uuid::Uuid;
+
+// how far a client clock may be off, and how long a seen auth id is
+// remembered. v2ray uses the same 120 seconds for both.
+pub const AUTH_ID_WINDOW: u64 = 120;
+
+// crc32 (ieee) of the first 12 bytes, all the auth id needs
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= u32::from(b);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn cipher(cmd_key: &[u8], labels: &Labels) -> Aes128 {
+    let key = &hash::kdf(cmd_key, &[labels.auth_id])[..16];
+    Aes128::new(key.into())
+}
+
+// a user's auth id ciphers, one per label version, with their key
+// schedules done up front. auth ids carry random bytes, so they can not be
+// looked up: every cipher gets one block decryption per handshake, found
+// or not, and this keeps that the only cost.
+#[derive(Clone)]
+pub struct AuthKey {
+    pub uuid: Uuid,
+    pub cmd_key: [u8; 16],
+    ciphers: Rc<[Aes128]>,
+}
+
+impl AuthKey {
+    pub fn new(uuid: Uuid, cmd_key: [u8; 16]) -> Self {
+        Self::with_labels(uuid, cmd_key, &[V2RAY])
+    }
+
+    pub fn with_labels(uuid: Uuid, cmd_key: [u8; 16], labels: &[Labels]) -> Self {
+        Self {
+            uuid,
+            cmd_key,
+            ciphers: labels.iter().map(|x| cipher(&cmd_key, x)).collect(),
+        }
+    }
+}
+
+// the client side: aes-128-ecb(timestamp | random | crc32)
+pub fn create_auth_id(cmd_key: &[u8], clock: &dyn Clock) -> [u8; 16] {
+    create_auth_id_with(cmd_key, &V2RAY, clock, &mut crate::common::random)
+}
+
+pub fn create_auth_id_with(
+    cmd_key: &[u8],
+    labels: &Labels,
+    clock: &dyn Clock,
+    random: &mut dyn FnMut(&mut [u8]),
+) -> [u8; 16] {
+    let mut id = [0u8; 16];
+    id[..8].copy_from_slice(&clock.now().to_be_bytes());
+    random(&mut id[8..12]);
+    let crc = crc32(&id[..12]);
+    id[12..].copy_from_slice(&crc.to_be_bytes());
+    cipher(cmd_key, labels).encrypt_block((&mut id).into());
+    id
+}
+
+// auth ids seen within the window. a client sending one again is replaying
+// a captured request.
+pub struct ReplayFilter {
+    clock: Rc<dyn Clock>,
+    window: u64,
+    seen: RefCell<HashMap<[u8; 16], u64>>,
+    // when the filter was last swept for expired ids
+    swept: Cell<u64>,
+}
+
+impl ReplayFilter {
+    pub fn new(window: u64) -> Self {
+        Self {
+            clock: Rc::new(SystemClock),
+            window,
+            seen: RefCell::default(),
+            swept: Cell::new(0),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Rc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // false when the id was already seen within the window
+    pub fn check(&self, id: &[u8; 16]) -> bool {
+        let now = self.clock.now();
+        let mut seen = self.seen.borrow_mut();
+        // at most one sweep per window keeps this amortized constant
+        if now.saturating_sub(self.swept.get()) >= self.window {
+            seen.retain(|_, &mut x| now.saturating_sub(x) < self.window);
+            self.swept.set(now);
+        }
+        match seen.get(id) {
+            Some(&x) if now.saturating_sub(x) < self.window => false,
+            _ => {
+                seen.insert(*id, now);
+                true
+            }
+        }
+    }
+
+    // decrypts and checks an auth id: its checksum, that its timestamp is
+    // within the window of the clock, and that it was not seen before
+    pub fn open(&self, cmd_key: &[u8], auth_id: &[u8; 16]) -> Result<(), ProtocolError> {
+        self.open_any(&[cmd_key], auth_id).map(|_| ())
+    }
+
+    // `open` with whichever of the keys the id was sealed with, its index
+    // is returned
+    pub fn open_any(
+        &self,
+        cmd_keys: &[&[u8]],
+        auth_id: &[u8; 16],
+    ) -> Result<usize, ProtocolError> {
+        let ciphers = cmd_keys.iter().map(|x| cipher(x, &V2RAY)).enumerate();
+        self.open_ciphers(ciphers, auth_id)
+    }
+
+    // `open` with the keys of users, the index of the one that opened the
+    // id and of the label version it was sealed under
+    pub fn open_keys(
+        &self,
+        keys: &[AuthKey],
+        auth_id: &[u8; 16],
+    ) -> Result<(usize, usize), ProtocolError> {
+        let ciphers = keys.iter().enumerate().flat_map(|(i, key)| {
+            key.ciphers.iter().enumerate().map(move |(version, x)| ((i, version), x))
+        });
+        self.open_ciphers(ciphers, auth_id)
+    }
+
+    fn open_ciphers<T, C: Borrow<Aes128>>(
+        &self,
+        ciphers: impl Iterator<Item = (T, C)>,
+        auth_id: &[u8; 16],
+    ) -> Result<T, ProtocolError> {
+        // every cipher is tried, so how long this takes does not tell where
+        // in the table the user is or whether there is one
+        let mut opened = None;
+        for (i, cipher) in ciphers {
+            let mut id = *auth_id;
+            cipher.borrow().decrypt_block((&mut id).into());
+            let matched = crc32(&id[..12]).to_be_bytes() == id[12..];
+            if matched && opened.is_none() {
+                opened = Some((i, id));
+            }
+        }
+        let Some((i, id)) = opened else {
+            return Err(ProtocolError::AuthFailed("invalid auth id"));
+        };
+
+        let mut timestamp = [0u8; 8];
+        timestamp.copy_from_slice(&id[..8]);
+        let timestamp = u64::from_be_bytes(timestamp);
+        if timestamp.abs_diff(self.clock.now()) > self.window {
+            return Err(ProtocolError::Timestamp(timestamp));
+        }
+        if !self.check(auth_id) {
+            return Err(ProtocolError::Replayed);
+        }
+        Ok(i)
+    }
+}
+
+thread_local! {
+    static SHARED: Rc<ReplayFilter> = Rc::new(ReplayFilter::new(AUTH_ID_WINDOW));
+}
+
+// connections come and go but a replay may hit any of them, so the filter
+// is one per isolate
+pub fn shared() -> Rc<ReplayFilter> {
+    SHARED.with(Rc::clone)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::time::MockClock;
+
+    const KEY: [u8; 16] = [7u8; 16];
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_auth_id() {
+        let clock = Rc::new(MockClock::new(1_700_000_000));
+        let filter = ReplayFilter::new(AUTH_ID_WINDOW).with_clock(clock.clone());
+
+        let id = create_auth_id(&KEY, clock.as_ref());
+        filter.open(&KEY, &id).unwrap();
+        let e = filter.open(&KEY, &id).unwrap_err();
+        assert_eq!(e, ProtocolError::Replayed);
+        assert!(filter.open(&[8u8; 16], &id).is_err());
+
+        // two minutes either way is fine, a second beyond that is not
+        let at = |t| create_auth_id(&KEY, &MockClock::new(t));
+        filter.open(&KEY, &at(1_700_000_000 - 120)).unwrap();
+        filter.open(&KEY, &at(1_700_000_000 + 120)).unwrap();
+        let e = filter.open(&KEY, &at(1_700_000_000 + 121)).unwrap_err();
+        assert_eq!(e, ProtocolError::Timestamp(1_700_000_121));
+        assert!(e.to_string().contains("outside the window"), "{e}");
+        let e = filter.open(&KEY, &at(1_700_000_000 - 121)).unwrap_err();
+        assert_eq!(e, ProtocolError::Timestamp(1_699_999_879));
+
+        let id = create_auth_id(&KEY, clock.as_ref());
+        assert_eq!(filter.open_any(&[&[8u8; 16], &KEY], &id).unwrap(), 1);
+        assert!(filter.open_any(&[&KEY], &id).is_err());
+    }
+
+    #[test]
+    fn test_replay_filter_eviction() {
+        let clock = Rc::new(MockClock::new(1_000));
+        let filter = ReplayFilter::new(AUTH_ID_WINDOW).with_clock(clock.clone());
+        assert!(filter.check(&[1u8; 16]));
+        clock.advance(60);
+        assert!(filter.check(&[2u8; 16]));
+        assert!(!filter.check(&[1u8; 16]));
+        assert_eq!(filter.len(), 2);
+
+        // the first id ages out, the second is still remembered
+        clock.advance(60);
+        assert!(!filter.check(&[2u8; 16]));
+        assert_eq!(filter.len(), 1);
+        assert!(filter.check(&[1u8; 16]));
+
+        clock.advance(AUTH_ID_WINDOW);
+        assert!(filter.check(&[3u8; 16]));
+        assert_eq!(filter.len(), 1);
+    }
+}
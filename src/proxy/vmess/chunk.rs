@@ -0,0 +1,1257 @@
+use std::cell::RefCell;
+use std::future::Future;
+use std::io;
+use std::ops::Range;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use aes::cipher::KeyInit;
+use aes_gcm::aead::AeadInPlace;
+use bytes::{Buf, BufMut, BytesMut};
+use chacha20poly1305::ChaCha20Poly1305;
+use md5::{Digest, Md5};
+use sha3::digest::{ExtendableOutput, XofReader};
+use sha3::{Shake128, Shake128Reader};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use worker::*;
+
+use crate::common::aead::Aes128Gcm;
+use crate::common::buf::PooledBuf;
+use crate::common::error::ProtocolError;
+use crate::common::{read_u16_be, time, u16_be};
+
+// https://xtls.github.io/en/development/protocols/vmess.html#data-section
+pub const OPTION_CHUNK_STREAM: u8 = 0x01;
+pub const OPTION_CHUNK_MASKING: u8 = 0x04;
+pub const OPTION_GLOBAL_PADDING: u8 = 0x08;
+pub const OPTION_AUTHENTICATED_LENGTH: u8 = 0x10;
+
+// the options byte of the request header, which both ends frame the body
+// by. the padding lengths are drawn from the masking stream, so global
+// padding without chunk masking is turned down like v2ray does.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Options(u8);
+
+impl Options {
+    pub const CHUNK_STREAM: Self = Self(OPTION_CHUNK_STREAM);
+    pub const CHUNK_MASKING: Self = Self(OPTION_CHUNK_MASKING);
+    pub const GLOBAL_PADDING: Self = Self(OPTION_GLOBAL_PADDING);
+    pub const AUTHENTICATED_LENGTH: Self = Self(OPTION_AUTHENTICATED_LENGTH);
+
+    // unknown bits are kept, they go back out as they came
+    pub const fn from_bits(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    // whether the body is in chunks at all, zero security never is
+    pub fn chunked(self, security: Security) -> bool {
+        security != Security::Zero && self.contains(Self::CHUNK_STREAM)
+    }
+
+    // chunk lengths xored with the shake128 stream of the iv
+    pub const fn masking(self) -> bool {
+        self.contains(Self::CHUNK_MASKING)
+    }
+
+    pub const fn padding(self) -> bool {
+        self.contains(Self::GLOBAL_PADDING)
+    }
+
+    pub(super) fn check(self) -> std::result::Result<(), ProtocolError> {
+        if self.contains(Self::AUTHENTICATED_LENGTH) {
+            return Err(ProtocolError::Unsupported(
+                "authenticated length is not supported".to_string(),
+            ));
+        }
+        if self.padding() && !self.masking() {
+            return Err(ProtocolError::Unsupported(
+                "global padding needs chunk masking".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl From<u8> for Options {
+    fn from(bits: u8) -> Self {
+        Self(bits)
+    }
+}
+
+impl From<Options> for u8 {
+    fn from(options: Options) -> Self {
+        options.0
+    }
+}
+
+impl std::ops::BitOr for Options {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+pub const TAG_SIZE: usize = 16;
+pub(crate) const MAX_CHUNK_PAYLOAD: usize = 8 * 1024;
+// largest chunk accepted from the peer, vmess' own limit. a length prefix
+// above it is refused before anything is buffered for it.
+pub const MAX_FRAME_SIZE: usize = 16 * 1024;
+const MAX_PADDING: u16 = 64;
+// room left in the read buffer before the next read from the peer
+const MIN_READ: usize = 4096;
+
+pub use crate::common::Random;
+
+fn os_random(buf: &mut [u8]) {
+    crate::common::random(buf);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Security {
+    Aes128Gcm,
+    ChaCha20Poly1305,
+    None,
+    Zero,
+    // an aead added with `register_aead`, by its security byte
+    Custom(u8),
+}
+
+impl Security {
+    // lower 4 bits of the security byte in the command section
+    pub fn from_byte(b: u8) -> std::result::Result<Self, ProtocolError> {
+        match b & 0x0f {
+            0x03 => Ok(Self::Aes128Gcm),
+            0x04 => Ok(Self::ChaCha20Poly1305),
+            0x05 => Ok(Self::None),
+            0x06 => Ok(Self::Zero),
+            x if registered(|aead| aead.byte == x).is_some() => Ok(Self::Custom(x)),
+            x => Err(ProtocolError::Unsupported(format!("unsupported security: {x}"))),
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            Self::Aes128Gcm => 0x03,
+            Self::ChaCha20Poly1305 => 0x04,
+            Self::None => 0x05,
+            Self::Zero => 0x06,
+            Self::Custom(x) => *x,
+        }
+    }
+
+    pub fn is_aead(&self) -> bool {
+        matches!(self, Self::Aes128Gcm | Self::ChaCha20Poly1305 | Self::Custom(_))
+    }
+
+    // how share links and configs spell it
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Aes128Gcm => "aes-128-gcm",
+            Self::ChaCha20Poly1305 => "chacha20-poly1305",
+            Self::None => "none",
+            Self::Zero => "zero",
+            Self::Custom(x) => registered(|aead| aead.byte == *x).map_or("unknown", |x| x.name),
+        }
+    }
+
+    pub fn from_name(s: &str) -> Option<Self> {
+        [Self::Aes128Gcm, Self::ChaCha20Poly1305, Self::None, Self::Zero]
+            .into_iter()
+            .find(|x| x.name() == s)
+            .or_else(|| registered(|aead| aead.name == s).map(|x| Self::Custom(x.byte)))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Security {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(self.name())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Security {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = <String as serde::Deserialize>::deserialize(deserializer)?;
+        Self::from_name(&s)
+            .ok_or_else(|| serde::de::Error::custom(format!("unsupported security {s:?}")))
+    }
+}
+
+// the cipher of a body stream: 12 byte nonces, 16 byte tags and no
+// associated data
+pub trait Aead {
+    fn seal_in_place(&self, nonce: &[u8; 12], buf: &mut [u8]) -> Result<[u8; TAG_SIZE]>;
+    fn open_in_place(
+        &self,
+        nonce: &[u8; 12],
+        buf: &mut [u8],
+        tag: &[u8],
+    ) -> std::result::Result<(), ProtocolError>;
+}
+
+// makes the cipher for a 16 byte body key
+pub type AeadFactory = fn(&[u8]) -> Box<dyn Aead>;
+
+impl Aead for Aes128Gcm {
+    fn seal_in_place(&self, nonce: &[u8; 12], buf: &mut [u8]) -> Result<[u8; TAG_SIZE]> {
+        self.encrypt_in_place_detached(nonce.into(), b"", buf)
+            .map(Into::into)
+            .map_err(|e| Error::RustError(e.to_string()))
+    }
+
+    fn open_in_place(
+        &self,
+        nonce: &[u8; 12],
+        buf: &mut [u8],
+        tag: &[u8],
+    ) -> std::result::Result<(), ProtocolError> {
+        self.decrypt_in_place_detached(nonce.into(), b"", buf, tag.into())
+            .map_err(|_| ProtocolError::AuthFailed("chunk authentication failed"))
+    }
+}
+
+impl Aead for ChaCha20Poly1305 {
+    fn seal_in_place(&self, nonce: &[u8; 12], buf: &mut [u8]) -> Result<[u8; TAG_SIZE]> {
+        self.encrypt_in_place_detached(nonce.into(), b"", buf)
+            .map(Into::into)
+            .map_err(|e| Error::RustError(e.to_string()))
+    }
+
+    fn open_in_place(
+        &self,
+        nonce: &[u8; 12],
+        buf: &mut [u8],
+        tag: &[u8],
+    ) -> std::result::Result<(), ProtocolError> {
+        self.decrypt_in_place_detached(nonce.into(), b"", buf, tag.into())
+            .map_err(|_| ProtocolError::AuthFailed("chunk authentication failed"))
+    }
+}
+
+fn aes_128_gcm(key: &[u8]) -> Box<dyn Aead> {
+    Box::new(Aes128Gcm::new(key.into()))
+}
+
+// https://github.com/v2fly/v2ray-core/blob/master/proxy/vmess/encoding/auth.go
+fn chacha20_poly1305(key: &[u8]) -> Box<dyn Aead> {
+    let mut chacha_key = [0u8; 32];
+    let first = crate::md5!(key);
+    let second = crate::md5!(&first);
+    chacha_key[..16].copy_from_slice(&first);
+    chacha_key[16..].copy_from_slice(&second);
+    Box::new(ChaCha20Poly1305::new((&chacha_key).into()))
+}
+
+#[derive(Clone, Copy)]
+struct Registered {
+    name: &'static str,
+    byte: u8,
+    factory: AeadFactory,
+}
+
+thread_local! {
+    static AEADS: RefCell<Vec<Registered>> = RefCell::new(vec![
+        Registered { name: "aes-128-gcm", byte: 0x03, factory: aes_128_gcm },
+        Registered { name: "chacha20-poly1305", byte: 0x04, factory: chacha20_poly1305 },
+    ]);
+}
+
+fn registered(f: impl Fn(&Registered) -> bool) -> Option<Registered> {
+    AEADS.with(|x| x.borrow().iter().copied().find(f))
+}
+
+// makes `factory` the cipher for security `byte`, which both ends have to
+// agree on, selected as `name` in configs and share links. a built-in
+// aead is replaced by registering its byte. none and zero, the legacy
+// bytes below 0x03 and anything past the low 4 bits are refused.
+pub fn register_aead(
+    name: &'static str,
+    byte: u8,
+    factory: AeadFactory,
+) -> std::result::Result<(), String> {
+    if !(0x03..=0x0f).contains(&byte) || byte == 0x05 || byte == 0x06 {
+        return Err(format!("security byte {byte:#04x} can not be an aead"));
+    }
+    if ["none", "zero"].contains(&name) {
+        return Err(format!("{name:?} can not be an aead"));
+    }
+    AEADS.with(|x| {
+        let mut aeads = x.borrow_mut();
+        if aeads.iter().any(|x| x.name == name && x.byte != byte) {
+            return Err(format!("{name:?} is already registered for another byte"));
+        }
+        aeads.retain(|x| x.byte != byte);
+        aeads.push(Registered { name, byte, factory });
+        Ok(())
+    })
+}
+
+fn new_cipher(security: Security, key: &[u8]) -> std::result::Result<Box<dyn Aead>, ProtocolError> {
+    let byte = security.to_byte();
+    match registered(|x| x.byte == byte) {
+        Some(aead) if security.is_aead() => Ok((aead.factory)(key)),
+        _ => Err(ProtocolError::Unsupported(format!("{security:?} is not an aead"))),
+    }
+}
+
+// where the reader is within a chunk. a chunk is opened the moment its
+// last byte is in, the stream hands it out before reading any further.
+#[derive(Clone, Copy)]
+enum ReadState {
+    WaitLen,
+    // the length prefix is consumed, the body has not fully arrived
+    WaitBody { size: usize, padding: usize },
+}
+
+// one direction of the body stream: request chunks are opened with the
+// header key/iv, response chunks are sealed with their sha256 derivations.
+// without a cipher (security none) chunks are only length framed.
+pub struct ChunkCodec {
+    cipher: Option<Box<dyn Aead>>,
+    nonce: [u8; 12],
+    count: u16,
+    mask: Option<Shake128Reader>,
+    padding: bool,
+    random: Random,
+    max_frame_size: usize,
+    state: ReadState,
+}
+
+impl ChunkCodec {
+    pub fn new(
+        security: Security,
+        key: &[u8],
+        iv: &[u8],
+        options: impl Into<Options>,
+    ) -> std::result::Result<Self, ProtocolError> {
+        let options = options.into();
+        if key.len() != 16 || iv.len() != 16 {
+            return Err(ProtocolError::Truncated("body keys and ivs are 16 bytes"));
+        }
+        options.check()?;
+        let mask = options.masking().then(|| {
+            let mut shake = Shake128::default();
+            sha3::digest::Update::update(&mut shake, iv);
+            shake.finalize_xof()
+        });
+
+        let cipher = match security {
+            Security::None => None,
+            _ => Some(new_cipher(security, key)?),
+        };
+        // v2ray frames none with a plain chunk writer, which never pads
+        let padding = options.padding() && cipher.is_some();
+        let mut nonce = [0u8; 12];
+        nonce.copy_from_slice(&iv[..12]);
+
+        Ok(Self {
+            cipher,
+            nonce,
+            count: 0,
+            padding,
+            mask,
+            random: Box::new(os_random),
+            max_frame_size: MAX_FRAME_SIZE,
+            state: ReadState::WaitLen,
+        })
+    }
+
+    pub fn with_random(mut self, random: Random) -> Self {
+        self.random = random;
+        self
+    }
+
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    // the sequence number lives in the first two bytes of the nonce, so a
+    // chunk replayed at another position fails authentication.
+    fn next_nonce(&mut self) -> [u8; 12] {
+        self.nonce[..2].copy_from_slice(&self.count.to_be_bytes());
+        self.count = self.count.wrapping_add(1);
+        self.nonce
+    }
+
+    fn next_mask(&mut self) -> u16 {
+        match self.mask.as_mut() {
+            Some(shake) => {
+                let mut mask = [0u8; 2];
+                shake.read(&mut mask);
+                read_u16_be(&mask)
+            }
+            None => 0,
+        }
+    }
+
+    fn tag_size(&self) -> usize {
+        if self.cipher.is_some() {
+            TAG_SIZE
+        } else {
+            0
+        }
+    }
+
+    // must be drawn before the length mask of the same chunk
+    fn next_padding(&mut self) -> usize {
+        if self.padding {
+            (self.next_mask() % MAX_PADDING) as usize
+        } else {
+            0
+        }
+    }
+
+    // +------------------+-----------------------------+-----------------+
+    // |  Length (masked) |  AEAD sealed payload + tag  |  Random padding |
+    // +------------------+-----------------------------+-----------------+
+    // |      2 Bytes     |  Length - Padding Bytes     |  0..64 Bytes    |
+    // +------------------+-----------------------------+-----------------+
+    pub fn encode_chunk_into(&mut self, pt: &[u8], dst: &mut BytesMut) -> Result<()> {
+        let tag_size = self.tag_size();
+        // the length has to fit its two bytes whatever padding is drawn.
+        // checked before anything is drawn, so the codec stays usable
+        let most = pt.len() + tag_size + if self.padding { MAX_PADDING as usize - 1 } else { 0 };
+        if most > u16::MAX as usize {
+            return Err(ProtocolError::LengthOverflow("chunk too large").into());
+        }
+        let padding = self.next_padding();
+        let size = ((pt.len() + tag_size + padding) as u16) ^ self.next_mask();
+
+        dst.reserve(2 + pt.len() + tag_size + padding);
+        dst.put_slice(&u16_be(size));
+        let start = dst.len();
+        dst.put_slice(pt);
+        // no nonce is used up when there is nothing to seal
+        let nonce = self.cipher.is_some().then(|| self.next_nonce());
+        if let (Some(cipher), Some(nonce)) = (&self.cipher, nonce) {
+            let tag = cipher.seal_in_place(&nonce, &mut dst[start..])?;
+            dst.put_slice(&tag);
+        }
+
+        let start = dst.len();
+        dst.resize(start + padding, 0);
+        (self.random)(&mut dst[start..]);
+        Ok(())
+    }
+
+    pub fn encode_chunk(&mut self, pt: &[u8]) -> Result<Vec<u8>> {
+        let mut chunk = BytesMut::new();
+        self.encode_chunk_into(pt, &mut chunk)?;
+        Ok(chunk.to_vec())
+    }
+
+    fn decode_size(&mut self, size: [u8; 2]) -> (usize, usize) {
+        let padding = self.next_padding();
+        let size = (read_u16_be(&size) ^ self.next_mask()) as usize;
+        (size, padding)
+    }
+
+    // opens the next complete chunk at the start of `src` where it lies.
+    // returns how many bytes of `src` are done with and where the plaintext
+    // is, None until enough bytes have arrived. a length prefix is taken
+    // as soon as it is there.
+    pub fn open_in_place(
+        &mut self,
+        src: &mut [u8],
+    ) -> std::result::Result<(usize, Option<Range<usize>>), ProtocolError> {
+        let mut start = 0;
+        let (size, padding) = match self.state {
+            ReadState::WaitBody { size, padding } => (size, padding),
+            ReadState::WaitLen if src.len() >= 2 => {
+                let (size, padding) = self.decode_size([src[0], src[1]]);
+                if size > self.max_frame_size {
+                    return Err(ProtocolError::LengthOverflow("frame too large"));
+                }
+                self.state = ReadState::WaitBody { size, padding };
+                start = 2;
+                (size, padding)
+            }
+            ReadState::WaitLen => return Ok((0, None)),
+        };
+
+        if src.len() - start < size {
+            return Ok((start, None));
+        }
+        self.state = ReadState::WaitLen;
+
+        let len = self.open_chunk(&mut src[start..start + size], padding)?;
+        Ok((start + size, Some(start..start + len)))
+    }
+
+    // splits the next complete chunk off `src` and opens it in place,
+    // returning None until enough bytes have arrived.
+    pub fn decode_from(
+        &mut self,
+        src: &mut BytesMut,
+    ) -> std::result::Result<Option<BytesMut>, ProtocolError> {
+        let (consumed, pt) = self.open_in_place(src)?;
+        let mut chunk = src.split_to(consumed);
+        Ok(pt.map(|pt| {
+            chunk.truncate(pt.end);
+            chunk.advance(pt.start);
+            chunk
+        }))
+    }
+
+    // takes a whole chunk including its length prefix. an empty plaintext
+    // marks the end of the stream
+    pub fn decode_chunk(&mut self, chunk: &[u8]) -> std::result::Result<Vec<u8>, ProtocolError> {
+        let mut src = BytesMut::from(chunk);
+        match self.decode_from(&mut src)? {
+            Some(pt) if src.is_empty() => Ok(pt.to_vec()),
+            Some(_) => Err(ProtocolError::LengthOverflow("chunk length mismatch")),
+            None => Err(ProtocolError::Truncated("chunk length mismatch")),
+        }
+    }
+
+    // the length of the plaintext left at the start of `chunk`
+    fn open_chunk(
+        &mut self,
+        chunk: &mut [u8],
+        padding: usize,
+    ) -> std::result::Result<usize, ProtocolError> {
+        let tag_size = self.tag_size();
+        if chunk.len() < tag_size + padding {
+            return Err(ProtocolError::Truncated("chunk too short"));
+        }
+        let len = chunk.len() - padding - tag_size;
+
+        let nonce = self.cipher.is_some().then(|| self.next_nonce());
+        if let (Some(cipher), Some(nonce)) = (&self.cipher, nonce) {
+            let (pt, rest) = chunk.split_at_mut(len);
+            cipher.open_in_place(&nonce, pt, &rest[..TAG_SIZE])?;
+        }
+        Ok(len)
+    }
+
+    pub fn is_pending(&self) -> bool {
+        matches!(self.state, ReadState::WaitBody { .. })
+    }
+}
+
+// body of a vmess session after the header has been exchanged. zero
+// security, like anything without chunk stream, is passed through
+// untouched. none keeps the length framing but skips the aead.
+pub struct VmessStream<S> {
+    inner: S,
+    reader: Option<ChunkCodec>,
+    writer: Option<ChunkCodec>,
+    // chunks are opened where they were read, the plaintext of the current
+    // one is `plaintext` in here and `consumed` bytes go once it is drained
+    read_buf: PooledBuf,
+    plaintext: Range<usize>,
+    consumed: usize,
+    read_eof: bool,
+    write_buf: PooledBuf,
+    end_written: bool,
+    // sealed chunks are held back until this many bytes wait, a flush, or
+    // `coalesce_delay` after the first of them. zero writes each chunk out
+    // right away. the deadline only wakes the writing task: the tail goes
+    // out with its next write or flush, so a writer that goes quiet has to
+    // flush when woken, as tokio's copy does once its reader is pending
+    coalesce_bytes: usize,
+    coalesce_delay: Duration,
+    deadline: Option<Pin<Box<dyn Future<Output = ()>>>>,
+}
+
+impl<S> VmessStream<S> {
+    pub fn new(
+        inner: S,
+        security: Security,
+        options: impl Into<Options>,
+        request_key: &[u8],
+        request_iv: &[u8],
+        response_key: &[u8],
+        response_iv: &[u8],
+    ) -> Result<Self> {
+        let options = options.into();
+        let (reader, writer) = if options.chunked(security) {
+            (
+                Some(ChunkCodec::new(security, request_key, request_iv, options)?),
+                Some(ChunkCodec::new(security, response_key, response_iv, options)?),
+            )
+        } else {
+            (None, None)
+        };
+
+        Ok(Self {
+            inner,
+            reader,
+            writer,
+            read_buf: PooledBuf::take(),
+            plaintext: 0..0,
+            consumed: 0,
+            read_eof: false,
+            write_buf: PooledBuf::take(),
+            end_written: false,
+            coalesce_bytes: 0,
+            coalesce_delay: Duration::ZERO,
+            deadline: None,
+        })
+    }
+
+    // caps the chunks read from the peer, MAX_FRAME_SIZE by default
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.reader = self.reader.map(|x| x.with_max_frame_size(max_frame_size));
+        self
+    }
+
+    // where the padding of written chunks comes from
+    pub fn with_random(mut self, random: Random) -> Self {
+        self.writer = self.writer.map(|x| x.with_random(random));
+        self
+    }
+
+    pub fn with_coalesce_bytes(mut self, coalesce_bytes: usize) -> Self {
+        self.coalesce_bytes = coalesce_bytes;
+        self
+    }
+
+    pub fn with_coalesce_delay(mut self, coalesce_delay: Duration) -> Self {
+        self.coalesce_delay = coalesce_delay;
+        self
+    }
+}
+
+impl<S: AsyncWrite + Unpin> VmessStream<S> {
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.advance(n);
+        }
+        self.deadline = None;
+        Poll::Ready(Ok(()))
+    }
+
+    // whether what is buffered should go out now. the deadline is armed by
+    // the first chunk held back and wakes `cx` once it is up
+    fn poll_due(&mut self, cx: &mut Context<'_>) -> bool {
+        if self.write_buf.len() >= self.coalesce_bytes {
+            return true;
+        }
+        let delay = self.coalesce_delay;
+        let deadline = self.deadline.get_or_insert_with(|| Box::pin(time::sleep(delay)));
+        deadline.as_mut().poll(cx).is_ready()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for VmessStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let Some(reader) = this.reader.as_mut() else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+
+        loop {
+            if !this.plaintext.is_empty() {
+                let n = this.plaintext.len().min(buf.remaining());
+                let start = this.plaintext.start;
+                buf.put_slice(&this.read_buf[start..start + n]);
+                this.plaintext.start += n;
+                return Poll::Ready(Ok(()));
+            }
+            this.read_buf.advance(std::mem::take(&mut this.consumed));
+
+            if this.read_eof {
+                return Poll::Ready(Ok(()));
+            }
+
+            let (consumed, pt) = reader
+                .open_in_place(&mut this.read_buf)
+                .map_err(io::Error::other)?;
+            if let Some(pt) = pt {
+                this.read_eof = pt.is_empty();
+                this.plaintext = pt;
+                this.consumed = consumed;
+                continue;
+            }
+            this.read_buf.advance(consumed);
+
+            // read straight into the spare room of the buffer
+            let len = this.read_buf.len();
+            if this.read_buf.capacity() - len < MIN_READ {
+                this.read_buf.reserve(MIN_READ);
+            }
+            let mut data = ReadBuf::uninit(this.read_buf.spare_capacity_mut());
+            let res = Pin::new(&mut this.inner).poll_read(cx, &mut data);
+            let n = data.filled().len();
+            // SAFETY: the reader initialized the first `n` spare bytes
+            unsafe { this.read_buf.set_len(len + n) };
+            ready!(res)?;
+            if n == 0 {
+                if this.read_buf.is_empty() && !reader.is_pending() {
+                    this.read_eof = true;
+                    continue;
+                }
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "truncated chunk",
+                )));
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for VmessStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.writer.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        if this.write_buf.len() >= this.coalesce_bytes {
+            ready!(this.poll_drain(cx))?;
+        }
+        // an empty chunk would tell the peer that the stream has ended
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let n = buf.len().min(MAX_CHUNK_PAYLOAD);
+        if let Some(writer) = this.writer.as_mut() {
+            writer
+                .encode_chunk_into(&buf[..n], &mut this.write_buf)
+                .map_err(io::Error::other)?;
+        }
+
+        // the chunk is buffered, so a pending drain is picked up by the next call
+        if this.poll_due(cx) {
+            if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+                return Poll::Ready(Err(e));
+            }
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        // the end goes out with whatever is still held back
+        if !this.end_written {
+            if let Some(writer) = this.writer.as_mut() {
+                writer
+                    .encode_chunk_into(&[], &mut this.write_buf)
+                    .map_err(io::Error::other)?;
+            }
+            this.end_written = true;
+        }
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const KEY: [u8; 16] = [7u8; 16];
+    const IV: [u8; 16] = [9u8; 16];
+
+    // xorshift so the padding bytes are reproducible
+    fn seeded(mut seed: u64) -> Random {
+        Box::new(move |buf: &mut [u8]| {
+            for b in buf.iter_mut() {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                *b = seed as u8;
+            }
+        })
+    }
+
+    #[test]
+    fn test_chunk_roundtrip() {
+        for security in [Security::Aes128Gcm, Security::ChaCha20Poly1305] {
+            let options = OPTION_CHUNK_STREAM | OPTION_CHUNK_MASKING;
+            let mut sealer = ChunkCodec::new(security, &KEY, &IV, options).unwrap();
+            let mut opener = ChunkCodec::new(security, &KEY, &IV, options).unwrap();
+
+            for msg in [&b"hello"[..], &b"world"[..], &[]] {
+                let chunk = sealer.encode_chunk(msg).unwrap();
+                assert_eq!(opener.decode_chunk(&chunk).unwrap(), msg);
+            }
+        }
+    }
+
+    #[test]
+    fn test_swapped_chunks_fail() {
+        let mut sealer = ChunkCodec::new(Security::Aes128Gcm, &KEY, &IV, OPTION_CHUNK_STREAM).unwrap();
+        let mut opener = ChunkCodec::new(Security::Aes128Gcm, &KEY, &IV, OPTION_CHUNK_STREAM).unwrap();
+
+        let first = sealer.encode_chunk(b"first").unwrap();
+        let second = sealer.encode_chunk(b"second").unwrap();
+
+        let e = opener.decode_chunk(&second).unwrap_err();
+        assert_eq!(e, ProtocolError::AuthFailed("chunk authentication failed"));
+        assert!(opener.decode_chunk(&first).is_err());
+    }
+
+    #[test]
+    fn test_padding() {
+        let msg = b"padded payload";
+        for padding in [0, OPTION_GLOBAL_PADDING] {
+            let options = OPTION_CHUNK_STREAM | OPTION_CHUNK_MASKING | padding;
+            let mut sealer = ChunkCodec::new(Security::Aes128Gcm, &KEY, &IV, options)
+                .unwrap()
+                .with_random(seeded(0x5eed));
+            let mut opener = ChunkCodec::new(Security::Aes128Gcm, &KEY, &IV, options).unwrap();
+
+            let mut padded = false;
+            for _ in 0..32 {
+                let chunk = sealer.encode_chunk(msg).unwrap();
+                let extra = chunk.len() - 2 - msg.len() - TAG_SIZE;
+                assert!(extra < MAX_PADDING as usize);
+                padded |= extra > 0;
+                assert_eq!(opener.decode_chunk(&chunk).unwrap(), msg);
+            }
+            assert_eq!(padded, padding != 0);
+        }
+    }
+
+    #[test]
+    fn test_bytes_path_matches_vec_path() {
+        let options = OPTION_CHUNK_STREAM | OPTION_CHUNK_MASKING;
+        let mut vec_codec = ChunkCodec::new(Security::ChaCha20Poly1305, &KEY, &IV, options).unwrap();
+        let mut bytes_codec = ChunkCodec::new(Security::ChaCha20Poly1305, &KEY, &IV, options).unwrap();
+
+        let mut wire = BytesMut::new();
+        for msg in [&b"zero"[..], &[1u8; 1500], &[]] {
+            let expected = vec_codec.encode_chunk(msg).unwrap();
+            let start = wire.len();
+            bytes_codec.encode_chunk_into(msg, &mut wire).unwrap();
+            assert_eq!(&wire[start..], &expected[..]);
+        }
+
+        // frames only come out once they are complete
+        let mut opener = ChunkCodec::new(Security::ChaCha20Poly1305, &KEY, &IV, options).unwrap();
+        let mut src = wire.split_to(3);
+        assert!(opener.decode_from(&mut src).unwrap().is_none());
+        src.unsplit(wire);
+        assert_eq!(&opener.decode_from(&mut src).unwrap().unwrap()[..], b"zero");
+        assert_eq!(&opener.decode_from(&mut src).unwrap().unwrap()[..], &[1u8; 1500]);
+        assert!(opener.decode_from(&mut src).unwrap().unwrap().is_empty());
+        assert!(src.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stream_none_and_zero() {
+        let payload = b"plaintext over a trusted link. ".repeat(400);
+        let padded = OPTION_CHUNK_STREAM | OPTION_CHUNK_MASKING | OPTION_GLOBAL_PADDING;
+        for (security, options, framed) in [
+            (Security::None, OPTION_CHUNK_STREAM, true),
+            // and none is never padded
+            (Security::None, padded, true),
+            (Security::None, 0, false),
+            // zero never frames, whatever the options say
+            (Security::Zero, padded, false),
+        ] {
+            let (mut client, server) = tokio::io::duplex(64 * 1024);
+            let mut server =
+                VmessStream::new(server, security, options, &KEY, &IV, &KEY, &IV).unwrap();
+            server.write_all(&payload).await.unwrap();
+            server.shutdown().await.unwrap();
+            drop(server);
+
+            let mut wire = Vec::new();
+            client.read_to_end(&mut wire).await.unwrap();
+            // the body is never encrypted, only framed
+            assert_eq!(wire == payload, !framed, "{security:?} {options}");
+            assert!(wire.windows(64).any(|x| x == &payload[..64]));
+            if framed {
+                let chunks = payload.len().div_ceil(MAX_CHUNK_PAYLOAD);
+                assert_eq!(wire.len() - payload.len(), 2 * (chunks + 1));
+            }
+
+            let mut client =
+                VmessStream::new(&wire[..], security, options, &KEY, &IV, &KEY, &IV).unwrap();
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, payload);
+        }
+    }
+
+    // none with masking and padding as v2ray writes it: masked lengths, one
+    // mask word per chunk, and no padding
+    #[test]
+    fn test_none_padding_v2ray_layout() {
+        let options = OPTION_CHUNK_STREAM | OPTION_CHUNK_MASKING | OPTION_GLOBAL_PADDING;
+        let mut shake = Shake128::default();
+        sha3::digest::Update::update(&mut shake, &IV);
+        let mut mask = shake.finalize_xof();
+        let mut wire = Vec::new();
+        let chunks: [&[u8]; 4] = [b"first", b"second chunk", &[0x5a; 300], b""];
+        for chunk in chunks {
+            let mut word = [0u8; 2];
+            mask.read(&mut word);
+            wire.extend(u16_be(chunk.len() as u16 ^ read_u16_be(&word)));
+            wire.extend(chunk);
+        }
+
+        let mut codec = ChunkCodec::new(Security::None, &KEY, &IV, options).unwrap();
+        let mut src = BytesMut::from(&wire[..]);
+        for chunk in chunks {
+            assert_eq!(&codec.decode_from(&mut src).unwrap().unwrap()[..], chunk);
+        }
+        assert!(src.is_empty());
+
+        // and what it writes is the same bytes
+        let mut codec = ChunkCodec::new(Security::None, &KEY, &IV, options).unwrap();
+        let encoded: Vec<u8> = chunks.iter().flat_map(|x| codec.encode_chunk(x).unwrap()).collect();
+        assert_eq!(encoded, wire);
+    }
+
+    #[tokio::test]
+    async fn test_masking_and_padding() {
+        let payload = b"masked and padded. ".repeat(100);
+        let framed = Options::CHUNK_STREAM;
+        for (masking, padding) in [(false, false), (true, false), (false, true), (true, true)] {
+            let mut options = framed;
+            if masking {
+                options = options | Options::CHUNK_MASKING;
+            }
+            if padding {
+                options = options | Options::GLOBAL_PADDING;
+            }
+            assert_eq!((options.masking(), options.padding()), (masking, padding));
+            let (mut client, server) = tokio::io::duplex(64 * 1024);
+            let security = Security::Aes128Gcm;
+            let server = VmessStream::new(server, security, options, &KEY, &IV, &KEY, &IV);
+            if padding && !masking {
+                let e = server.err().unwrap();
+                assert!(e.to_string().contains("global padding needs chunk masking"), "{e}");
+                continue;
+            }
+            let mut server = server.unwrap();
+            server.write_all(&payload).await.unwrap();
+            server.shutdown().await.unwrap();
+            drop(server);
+
+            let mut wire = Vec::new();
+            client.read_to_end(&mut wire).await.unwrap();
+            let length = u16::from_be_bytes([wire[0], wire[1]]) as usize;
+            // plain lengths count the sealed payload and nothing else
+            assert_eq!(length == payload.len() + TAG_SIZE, !masking, "{options:?}");
+            // one data chunk and the empty one closing the stream
+            let overhead = wire.len() - payload.len() - 2 * TAG_SIZE;
+            assert_eq!(overhead > 4, padding, "{options:?}");
+
+            let mut client =
+                VmessStream::new(&wire[..], security, options, &KEY, &IV, &KEY, &IV).unwrap();
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, payload);
+        }
+        assert_eq!(u8::from(Options::from(0x0d)), 0x0d);
+        assert!(Options::from_bits(0x0d).contains(Options::GLOBAL_PADDING | Options::CHUNK_STREAM));
+    }
+
+    #[tokio::test]
+    async fn test_stream_roundtrip() {
+        let options = OPTION_CHUNK_STREAM | OPTION_CHUNK_MASKING;
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut server =
+            VmessStream::new(server, Security::Aes128Gcm, options, &KEY, &IV, &KEY, &IV).unwrap();
+
+        // the response direction of the server is read back with the same keys
+        let mut client =
+            VmessStream::new(client, Security::Aes128Gcm, options, &KEY, &IV, &KEY, &IV).unwrap();
+
+        let payload = vec![42u8; 3 * MAX_CHUNK_PAYLOAD + 5];
+        server.write_all(&payload).await.unwrap();
+        server.shutdown().await.unwrap();
+
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, payload);
+    }
+
+    // xors with the first key byte, the tag is that byte repeated
+    struct Xor(u8);
+
+    impl Aead for Xor {
+        fn seal_in_place(&self, _: &[u8; 12], buf: &mut [u8]) -> Result<[u8; TAG_SIZE]> {
+            buf.iter_mut().for_each(|b| *b ^= self.0);
+            Ok([self.0; TAG_SIZE])
+        }
+
+        fn open_in_place(
+            &self,
+            _: &[u8; 12],
+            buf: &mut [u8],
+            tag: &[u8],
+        ) -> std::result::Result<(), ProtocolError> {
+            if tag != [self.0; TAG_SIZE] {
+                return Err(ProtocolError::AuthFailed("bad xor tag"));
+            }
+            buf.iter_mut().for_each(|b| *b ^= self.0);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_register_aead() {
+        assert!(register_aead("xor", 0x05, |key| Box::new(Xor(key[0]))).is_err());
+        register_aead("xor", 0x0f, |key| Box::new(Xor(key[0]))).unwrap();
+        let security = Security::from_name("xor").unwrap();
+        assert_eq!(security, Security::Custom(0x0f));
+        assert_eq!(Security::from_byte(0x0f).unwrap(), security);
+        assert_eq!(security.name(), "xor");
+        assert!(register_aead("xor", 0x0e, |key| Box::new(Xor(key[0]))).is_err());
+
+        let mut codec = ChunkCodec::new(security, &KEY, &IV, OPTION_CHUNK_STREAM).unwrap();
+        let chunk = codec.encode_chunk(b"ping").unwrap();
+        assert_eq!(&chunk[2..6], b"ping".map(|b| b ^ KEY[0]));
+
+        let options = OPTION_CHUNK_STREAM | OPTION_CHUNK_MASKING;
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut server = VmessStream::new(server, security, options, &KEY, &IV, &KEY, &IV).unwrap();
+        let mut client = VmessStream::new(client, security, options, &KEY, &IV, &KEY, &IV).unwrap();
+        let payload = vec![42u8; 2 * MAX_CHUNK_PAYLOAD + 5];
+        server.write_all(&payload).await.unwrap();
+        server.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, payload);
+    }
+
+    // counts the writes that reach it
+    #[derive(Default)]
+    struct Counting {
+        data: Vec<u8>,
+        writes: usize,
+    }
+
+    impl AsyncWrite for Counting {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            this.writes += 1;
+            this.data.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_coalesce_writes() {
+        let stream = |coalesce_bytes| {
+            VmessStream::new(
+                Counting::default(),
+                Security::Aes128Gcm,
+                OPTION_CHUNK_STREAM,
+                &KEY,
+                &IV,
+                &KEY,
+                &IV,
+            )
+            .unwrap()
+            .with_coalesce_bytes(coalesce_bytes)
+            .with_coalesce_delay(Duration::from_millis(5))
+        };
+
+        let mut plain = stream(0);
+        let mut coalesced = stream(1024);
+        for _ in 0..100 {
+            plain.write_all(b"0123456789").await.unwrap();
+            coalesced.write_all(b"0123456789").await.unwrap();
+        }
+        assert_eq!(plain.inner.writes, 100);
+        // 28 bytes a chunk, 1024 are reached with the 37th
+        assert_eq!(coalesced.inner.writes, 2);
+        coalesced.flush().await.unwrap();
+        assert_eq!(coalesced.inner.writes, 3);
+        assert_eq!(coalesced.inner.data, plain.inner.data);
+
+        // held back until the delay is up
+        coalesced.write_all(b"first").await.unwrap();
+        assert_eq!(coalesced.inner.writes, 3);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        coalesced.write_all(b"second").await.unwrap();
+        assert_eq!(coalesced.inner.writes, 4);
+
+        coalesced.write_all(b"last").await.unwrap();
+        coalesced.shutdown().await.unwrap();
+        assert_eq!(coalesced.inner.writes, 5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_coalesce_quiet_writer() {
+        use futures_util::task::{waker, ArcWake};
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        #[derive(Default)]
+        struct Woken(AtomicBool);
+
+        impl ArcWake for Woken {
+            fn wake_by_ref(arc_self: &Arc<Self>) {
+                arc_self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let mut stream = VmessStream::new(
+            Counting::default(),
+            Security::Aes128Gcm,
+            OPTION_CHUNK_STREAM,
+            &KEY,
+            &IV,
+            &KEY,
+            &IV,
+        )
+        .unwrap()
+        .with_coalesce_bytes(1024)
+        .with_coalesce_delay(Duration::from_millis(5));
+
+        // one short write, then nothing more to send
+        let woken = Arc::new(Woken::default());
+        let waker = waker(woken.clone());
+        let mut cx = Context::from_waker(&waker);
+        let res = Pin::new(&mut stream).poll_write(&mut cx, b"short");
+        assert!(matches!(res, Poll::Ready(Ok(5))));
+        assert_eq!(stream.inner.writes, 0);
+        assert!(!woken.0.load(Ordering::SeqCst));
+
+        // the deadline wakes the writer, whose flush sends the tail
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(woken.0.load(Ordering::SeqCst));
+        assert!(Pin::new(&mut stream).poll_flush(&mut cx).is_ready());
+        assert_eq!(stream.inner.writes, 1);
+    }
+
+    #[tokio::test]
+    async fn test_chunk_surfaces_on_arrival() {
+        use futures_util::FutureExt;
+
+        let options = OPTION_CHUNK_STREAM | OPTION_CHUNK_MASKING;
+        let mut codec = ChunkCodec::new(Security::Aes128Gcm, &KEY, &IV, options).unwrap();
+        let chunk = codec.encode_chunk(b"pong").unwrap();
+        let (mut peer, stream) = tokio::io::duplex(1024);
+        let mut reader =
+            VmessStream::new(stream, Security::Aes128Gcm, options, &KEY, &IV, &KEY, &IV).unwrap();
+        let mut buf = [0u8; 64];
+
+        // the length prefix and part of the body
+        peer.write_all(&chunk[..8]).await.unwrap();
+        assert!(reader.read(&mut buf).now_or_never().is_none());
+
+        // the rest of it, and the peer goes quiet without closing
+        peer.write_all(&chunk[8..]).await.unwrap();
+        let n = reader.read(&mut buf).now_or_never().unwrap().unwrap();
+        assert_eq!(&buf[..n], b"pong");
+        assert!(reader.read(&mut buf).now_or_never().is_none());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_security_serde() {
+        let all = [
+            Security::Aes128Gcm,
+            Security::ChaCha20Poly1305,
+            Security::None,
+            Security::Zero,
+        ];
+        for security in all {
+            let json = serde_json::to_string(&security).unwrap();
+            assert_eq!(json, format!("{:?}", security.name()));
+            assert_eq!(serde_json::from_str::<Security>(&json).unwrap(), security);
+        }
+        let e = serde_json::from_str::<Security>(r#""aes128gcm""#).unwrap_err();
+        assert!(e.to_string().contains("unsupported security"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn test_frame_too_large() {
+        let mut codec = ChunkCodec::new(Security::None, &KEY, &IV, OPTION_CHUNK_STREAM)
+            .unwrap()
+            .with_max_frame_size(1024);
+        let mut src = BytesMut::from(&[0xff, 0xff][..]);
+        let e = codec.decode_from(&mut src).err().unwrap();
+        assert_eq!(e.to_string(), "frame too large");
+
+        // the stream fails on the prefix alone instead of waiting for, and
+        // buffering, the 64k it announces
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut server = VmessStream::new(
+            server,
+            Security::Aes128Gcm,
+            OPTION_CHUNK_STREAM,
+            &KEY,
+            &IV,
+            &KEY,
+            &IV,
+        )
+        .unwrap()
+        .with_max_frame_size(1024);
+        client.write_all(&[0xff, 0xff]).await.unwrap();
+        let mut buf = [0u8; 16];
+        let read = tokio::time::timeout(std::time::Duration::from_secs(1), server.read(&mut buf));
+        let e = read.await.unwrap().err().unwrap();
+        assert_eq!(e.to_string(), "frame too large");
+        assert!(server.read_buf.capacity() < 0xffff);
+
+        // a chunk past what two length bytes hold is refused, not cut
+        let mut writer = ChunkCodec::new(Security::Aes128Gcm, &KEY, &IV, OPTION_CHUNK_STREAM)
+            .unwrap();
+        let e = writer.encode_chunk(&vec![1u8; 0xffff]).err().unwrap();
+        assert_eq!(e.to_string(), "chunk too large");
+        let fits = writer.encode_chunk(&vec![1u8; 0xffff - TAG_SIZE]).unwrap();
+        let mut reader = ChunkCodec::new(Security::Aes128Gcm, &KEY, &IV, OPTION_CHUNK_STREAM)
+            .unwrap()
+            .with_max_frame_size(0xffff);
+        assert_eq!(reader.decode_chunk(&fits).unwrap().len(), 0xffff - TAG_SIZE);
+
+        // the largest chunk this side writes still fits the default
+        let mut writer =
+            ChunkCodec::new(Security::Aes128Gcm, &KEY, &IV, OPTION_CHUNK_STREAM).unwrap();
+        let mut reader =
+            ChunkCodec::new(Security::Aes128Gcm, &KEY, &IV, OPTION_CHUNK_STREAM).unwrap();
+        let chunk = writer.encode_chunk(&[1u8; MAX_CHUNK_PAYLOAD]).unwrap();
+        let pt = reader.decode_chunk(&chunk).unwrap();
+        assert_eq!(pt.len(), MAX_CHUNK_PAYLOAD);
+    }
+}
@@ -0,0 +1,511 @@
+use super::chunk::{Security, VmessStream, MAX_CHUNK_PAYLOAD};
+use super::labels::{Labels, V2RAY};
+use super::{keylog, open_aead, seal_vmess_header_with, users, VmessRequestBuilder};
+use super::{COMMAND_TCP, COMMAND_UDP};
+use crate::app::policy::Policy;
+use crate::common::time::{self, SystemClock};
+use crate::common::{hash, read_u16_be, Random};
+use crate::outbound::dialer::{BoxStream, Dialer, SocketDialer};
+use crate::outbound::Target;
+
+use md5::Digest;
+use sha2::Sha256;
+use std::cell::RefCell;
+use std::fmt;
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use uuid::Uuid;
+
+// why a handshake with the server failed. the io errors of
+// `VmessConnector` carry it, `HandshakeError::of` gets it back.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HandshakeError {
+    // the server could not be reached
+    Dial(String),
+    // hung up without answering, how servers turn away an unknown uuid or
+    // a clock too far off
+    Rejected,
+    // the answer does not open with the request's keys
+    UndecryptableResponse,
+    // the answer is for another request
+    AuthMismatch,
+    // no answer within the handshake timeout
+    Timeout(Duration),
+}
+
+impl HandshakeError {
+    pub fn of(e: &io::Error) -> Option<&Self> {
+        e.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Dial(e) => write!(f, "dialing the server: {e}"),
+            Self::Rejected => write!(f, "the server rejected the request"),
+            Self::UndecryptableResponse => write!(f, "undecryptable response header"),
+            Self::AuthMismatch => write!(f, "response header for another request"),
+            Self::Timeout(x) => write!(f, "handshake timed out after {x:?}"),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+impl From<HandshakeError> for io::Error {
+    fn from(e: HandshakeError) -> Self {
+        let kind = match e {
+            HandshakeError::Dial(_) => io::ErrorKind::ConnectionRefused,
+            // the uuid or the clock is wrong, and will be next time too
+            HandshakeError::Rejected => io::ErrorKind::PermissionDenied,
+            HandshakeError::Timeout(_) => io::ErrorKind::TimedOut,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+// a vmess client for use outside the proxy. clones share the dialer.
+#[derive(Clone)]
+pub struct VmessConnector {
+    server: Target,
+    cmd_key: [u8; 16],
+    security: Security,
+    labels: Labels,
+    dialer: Rc<dyn Dialer>,
+    random: Rc<RefCell<Random>>,
+    handshake_timeout: Duration,
+}
+
+impl VmessConnector {
+    pub fn new(server: Target, uuid: Uuid, security: Security) -> Self {
+        Self {
+            server,
+            cmd_key: users::cmd_key(&uuid),
+            security,
+            labels: V2RAY,
+            dialer: Rc::new(SocketDialer),
+            random: Rc::new(RefCell::new(Box::new(crate::common::random))),
+            handshake_timeout: Policy::default().handshake,
+        }
+    }
+
+    // how the server is reached, a worker socket by default
+    pub fn with_dialer(mut self, dialer: Rc<dyn Dialer>) -> Self {
+        self.dialer = dialer;
+        self
+    }
+
+    // where the body keys, the auth id, the header nonce and the padding
+    // come from, the platform's randomness by default
+    pub fn with_random(mut self, random: Random) -> Self {
+        self.random = Rc::new(RefCell::new(random));
+        self
+    }
+
+    // v2ray's by default. the server has to take them as one of its
+    // versions, it answers under the same ones.
+    pub fn with_labels(mut self, labels: Labels) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    // how long the header may take to go out and the answer to come back,
+    // the policy's default by default. dialing is not counted.
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    pub async fn connect_tcp(&self, target: &Target) -> io::Result<VmessStream<BoxStream>> {
+        self.connect(target, COMMAND_TCP).await
+    }
+
+    pub async fn connect_udp(&self, target: &Target) -> io::Result<VmessDatagram> {
+        let stream = self.connect(target, COMMAND_UDP).await?;
+        Ok(VmessDatagram { stream })
+    }
+
+    pub fn server(&self) -> &Target {
+        &self.server
+    }
+
+    // the tcp handshake over a connection to the server made elsewhere
+    pub async fn handshake_tcp(
+        &self,
+        stream: BoxStream,
+        target: &Target,
+    ) -> io::Result<VmessStream<BoxStream>> {
+        self.handshake(stream, target, COMMAND_TCP).await
+    }
+
+    async fn connect(&self, target: &Target, command: u8) -> io::Result<VmessStream<BoxStream>> {
+        let stream = self
+            .dialer
+            .dial(&self.server)
+            .await
+            .map_err(|e| HandshakeError::Dial(e.to_string()))?;
+        self.handshake(stream, target, command).await
+    }
+
+    // the answer is awaited before anything else is sent, so a failed
+    // handshake is seen here and not on the first read. a server that
+    // stalls is hung up on once the timeout passes.
+    async fn handshake(
+        &self,
+        stream: BoxStream,
+        target: &Target,
+        command: u8,
+    ) -> io::Result<VmessStream<BoxStream>> {
+        let exchange = self.exchange(stream, target, command);
+        time::timeout(self.handshake_timeout, exchange)
+            .await
+            .unwrap_or(Err(HandshakeError::Timeout(self.handshake_timeout).into()))
+    }
+
+    async fn exchange(
+        &self,
+        mut stream: BoxStream,
+        target: &Target,
+        command: u8,
+    ) -> io::Result<VmessStream<BoxStream>> {
+        let mut secrets = [0u8; 33];
+        (self.random.borrow_mut())(&mut secrets);
+        let (mut iv, mut key) = ([0u8; 16], [0u8; 16]);
+        iv.copy_from_slice(&secrets[..16]);
+        key.copy_from_slice(&secrets[16..32]);
+        let request = VmessRequestBuilder::new(command, &target.addr, target.port)
+            .with_keys(iv, key, secrets[32])
+            .with_security(self.security)
+            .build()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        let cmd = request.encode();
+
+        let header = {
+            let random = &mut *self.random.borrow_mut();
+            seal_vmess_header_with(&self.cmd_key, &self.labels, &cmd, &SystemClock, random)
+        };
+        let header = header.map_err(io::Error::other)?;
+        keylog::log_session(&header[..16], &key, &iv);
+        stream.write_all(&header).await?;
+        stream.flush().await?;
+
+        let response_key = &crate::sha256!(key)[..16];
+        let response_iv = &crate::sha256!(iv)[..16];
+        let answer = open_response_header(&mut stream, &self.labels, response_key, response_iv);
+        if answer.await? != request.auth {
+            return Err(HandshakeError::AuthMismatch.into());
+        }
+
+        VmessStream::new(
+            stream,
+            self.security,
+            request.options,
+            response_key,
+            response_iv,
+            &key,
+            &iv,
+        )
+        .map(|x| {
+            let random = self.random.clone();
+            x.with_random(Box::new(move |buf: &mut [u8]| (random.borrow_mut())(buf)))
+        })
+        .map_err(io::Error::other)
+    }
+}
+
+// udp through vmess, one datagram per chunk
+pub struct VmessDatagram {
+    stream: VmessStream<BoxStream>,
+}
+
+impl VmessDatagram {
+    pub async fn send(&mut self, datagram: &[u8]) -> io::Result<()> {
+        if datagram.is_empty() || datagram.len() > MAX_CHUNK_PAYLOAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("datagrams take 1 to {MAX_CHUNK_PAYLOAD} bytes"),
+            ));
+        }
+        self.stream.write_all(datagram).await?;
+        self.stream.flush().await
+    }
+
+    // the next datagram into `buf`, 0 once the server closed the session.
+    // whatever of it does not fit is dropped.
+    pub async fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut datagram = [0u8; MAX_CHUNK_PAYLOAD];
+        let n = self.stream.read(&mut datagram).await?;
+        let n = n.min(buf.len());
+        buf[..n].copy_from_slice(&datagram[..n]);
+        Ok(n)
+    }
+}
+
+// reads the server's answer and returns its authentication value. commands
+// in it are skipped.
+async fn open_response_header<R>(
+    reader: &mut R,
+    labels: &Labels,
+    key: &[u8],
+    iv: &[u8],
+) -> io::Result<u8>
+where
+    R: AsyncRead + Unpin,
+{
+    let rejected = |e: io::Error| match e.kind() {
+        io::ErrorKind::UnexpectedEof => HandshakeError::Rejected.into(),
+        _ => e,
+    };
+    let length_key = &hash::kdf(key, &[labels.response_length_key])[..16];
+    let length_iv = &hash::kdf(iv, &[labels.response_length_iv])[..12];
+    let mut length = [0u8; 18];
+    reader.read_exact(&mut length).await.map_err(rejected)?;
+    let length = open_aead(length_key, length_iv, &length, b"")
+        .ok_or(HandshakeError::UndecryptableResponse)?;
+    let length = read_u16_be(&length) as usize;
+
+    let payload_key = &hash::kdf(key, &[labels.response_key])[..16];
+    let payload_iv = &hash::kdf(iv, &[labels.response_iv])[..12];
+    let mut payload = vec![0u8; length + 16];
+    reader.read_exact(&mut payload).await.map_err(rejected)?;
+    match open_aead(payload_key, payload_iv, &payload, b"") {
+        Some(x) if x.len() >= 4 => Ok(x[0]),
+        _ => Err(HandshakeError::UndecryptableResponse.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbound::Network;
+    use crate::proxy::vmess::users::UserTable;
+    use crate::proxy::vmess::{fnv1a, open_vmess_header, seal_response_header};
+    use crate::testutil::{self, EchoOutbound};
+    use async_trait::async_trait;
+    use std::cell::RefCell;
+    use tokio::io::DuplexStream;
+
+    const UUID: &str = "f282b878-8711-45a1-8c69-5564172123c1";
+
+    // hands out the client half of a duplex
+    struct Pipe(RefCell<Option<DuplexStream>>);
+
+    #[async_trait(?Send)]
+    impl Dialer for Pipe {
+        async fn dial(&self, _: &Target) -> worker::Result<BoxStream> {
+            let stream = self.0.borrow_mut().take();
+            stream
+                .map(|x| Box::new(x) as BoxStream)
+                .ok_or_else(|| worker::Error::RustError("dialed twice".to_string()))
+        }
+    }
+
+    fn dialing(uuid: &str) -> (VmessConnector, DuplexStream) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let server_addr = Target::new("vmess.example.com".to_string(), 443, Network::Tcp);
+        let connector = VmessConnector::new(
+            server_addr,
+            Uuid::parse_str(uuid).unwrap(),
+            Security::Aes128Gcm,
+        )
+        .with_dialer(Rc::new(Pipe(RefCell::new(Some(client)))));
+        (connector, server)
+    }
+
+    // the proxy's side of a session: checks the command and echoes the body
+    async fn serve(mut stream: DuplexStream, wrong_auth: bool) -> Vec<u8> {
+        let users = UserTable::new(Uuid::parse_str(UUID).unwrap());
+        let Ok(cmd) = open_vmess_header(&mut stream, &users).await else {
+            return Vec::new();
+        };
+        let (body, sum) = cmd.split_at(cmd.len() - 4);
+        assert_eq!(fnv1a(body).to_be_bytes(), sum);
+
+        let (iv, key) = (&cmd[1..17], &cmd[17..33]);
+        let response_key = &crate::sha256!(key)[..16];
+        let response_iv = &crate::sha256!(iv)[..16];
+        let header = seal_response_header(response_key, response_iv, cmd[33] ^ wrong_auth as u8);
+        stream.write_all(&header.unwrap()).await.unwrap();
+
+        let security = Security::from_byte(cmd[35]).unwrap();
+        let mut stream = VmessStream::new(
+            stream,
+            security,
+            cmd[34],
+            key,
+            iv,
+            response_key,
+            response_iv,
+        )
+        .unwrap();
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            stream.write_all(&buf[..n]).await.unwrap();
+        }
+        // the client may be gone already
+        let _ = stream.shutdown().await;
+        cmd
+    }
+
+    #[tokio::test]
+    async fn test_connect_tcp() {
+        let uuid = Uuid::parse_str(UUID).unwrap();
+        let echo = EchoOutbound::new();
+        let server = testutil::vmess_server("client-tcp", uuid, echo.clone()).unwrap();
+        let connector = testutil::vmess_connector("client-tcp", uuid);
+        let target = Target::new("example.com".to_string(), 443, Network::Tcp);
+        let echoed = testutil::run(vec![server], async {
+            let mut stream = connector.connect_tcp(&target).await.unwrap();
+            stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+            stream.shutdown().await.unwrap();
+            let mut echoed = Vec::new();
+            stream.read_to_end(&mut echoed).await.unwrap();
+            echoed
+        })
+        .await;
+        assert_eq!(echoed, b"GET / HTTP/1.1\r\n\r\n");
+        // the server read tcp to example.com:443 from the command
+        assert_eq!(echo.targets(), [target]);
+    }
+
+    #[tokio::test]
+    async fn test_connect_udp() {
+        let uuid = Uuid::parse_str(UUID).unwrap();
+        let echo = EchoOutbound::new();
+        let server = testutil::vmess_server("client-udp", uuid, echo.clone()).unwrap();
+        let connector = testutil::vmess_connector("client-udp", uuid);
+        let target = Target::new("1.1.1.1".to_string(), 53, Network::Udp);
+        testutil::run(vec![server], async {
+            let mut datagrams = connector.connect_udp(&target).await.unwrap();
+            let mut buf = [0u8; 16];
+            datagrams.send(b"query").await.unwrap();
+            let n = datagrams.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"query");
+            datagrams.send(b"another").await.unwrap();
+            let n = datagrams.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"another");
+            assert!(datagrams.send(&[]).await.is_err());
+        })
+        .await;
+        assert_eq!(echo.targets(), [target]);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_errors() {
+        let target = Target::new("example.com".to_string(), 443, Network::Tcp);
+
+        let (connector, server) = dialing("00000000-0000-0000-0000-000000000001");
+        let serving = async {
+            serve(server, false).await;
+        };
+        let (_, e) = tokio::join!(serving, connector.connect_tcp(&target));
+        assert_eq!(
+            HandshakeError::of(&e.err().unwrap()),
+            Some(&HandshakeError::Rejected)
+        );
+
+        let (connector, server) = dialing(UUID);
+        let serving = async {
+            serve(server, true).await;
+        };
+        let (_, e) = tokio::join!(serving, connector.connect_tcp(&target));
+        assert_eq!(
+            HandshakeError::of(&e.err().unwrap()),
+            Some(&HandshakeError::AuthMismatch)
+        );
+
+        let e = connector.connect_tcp(&target).await.err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(matches!(
+            HandshakeError::of(&e),
+            Some(HandshakeError::Dial(_))
+        ));
+    }
+
+    // a server that takes the header and never answers
+    #[tokio::test(start_paused = true)]
+    async fn test_handshake_timeout() {
+        let target = Target::new("example.com".to_string(), 443, Network::Tcp);
+        let (connector, mut server) = dialing(UUID);
+        let connector = connector.with_handshake_timeout(Duration::from_secs(3));
+        let started = tokio::time::Instant::now();
+        let e = connector.connect_tcp(&target).await.err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(
+            HandshakeError::of(&e),
+            Some(&HandshakeError::Timeout(Duration::from_secs(3)))
+        );
+        assert_eq!(started.elapsed(), Duration::from_secs(3));
+        // and the connection went with the handshake
+        let mut header = Vec::new();
+        server.read_to_end(&mut header).await.unwrap();
+        assert!(!header.is_empty());
+    }
+
+    // labels to move to, every one of them other than v2ray's
+    const NEXT: Labels = Labels {
+        auth_id: b"siren auth id v2",
+        header_length_key: b"siren header length key v2",
+        header_length_iv: b"siren header length iv v2",
+        header_key: b"siren header key v2",
+        header_iv: b"siren header iv v2",
+        response_length_key: b"siren response length key v2",
+        response_length_iv: b"siren response length iv v2",
+        response_key: b"siren response key v2",
+        response_iv: b"siren response iv v2",
+    };
+
+    // a server in the middle of a migration takes clients on either
+    // version and answers each under its own
+    #[tokio::test]
+    async fn test_label_versions() {
+        use crate::proxy::vmess::auth::{ReplayFilter, AUTH_ID_WINDOW};
+        use crate::server::memory::MemoryListener;
+        use crate::server::vmess::VmessInbound;
+        use crate::server::Server;
+
+        let (uuid, alice) = (Uuid::parse_str(UUID).unwrap(), Uuid::from_u128(2));
+        let users = UserTable::new(uuid).with_labels(vec![V2RAY, NEXT]);
+        users.add_user(alice, "alice@example.com", 0).unwrap();
+        let listener = MemoryListener::bind("client-labels").unwrap();
+        let inbound = VmessInbound::new(listener, Rc::new(users))
+            .with_filter(Rc::new(ReplayFilter::new(AUTH_ID_WINDOW)));
+        let echo = EchoOutbound::new();
+        let server = Server::builder()
+            .add_inbound("vmess", inbound)
+            .add_outbound("direct", echo.clone())
+            .build()
+            .unwrap();
+
+        let target = Target::new("example.com".to_string(), 443, Network::Tcp);
+        testutil::run(vec![server], async {
+            for (uuid, labels) in [(uuid, V2RAY), (uuid, NEXT), (alice, NEXT), (alice, V2RAY)] {
+                let connector = testutil::vmess_connector("client-labels", uuid);
+                let mut stream = connector.with_labels(labels).connect_tcp(&target).await.unwrap();
+                stream.write_all(b"ping").await.unwrap();
+                let mut buf = [0u8; 4];
+                stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"ping");
+            }
+
+            // labels the server does not take are an unknown user
+            let other = Labels {
+                auth_id: b"siren auth id v3",
+                ..NEXT
+            };
+            let connector = testutil::vmess_connector("client-labels", uuid).with_labels(other);
+            let e = connector.connect_tcp(&target).await.err().unwrap();
+            assert_eq!(HandshakeError::of(&e), Some(&HandshakeError::Rejected));
+        })
+        .await;
+        assert_eq!(echo.targets().len(), 4);
+    }
+}
@@ -0,0 +1,97 @@
+// the body keys of every session, for a dissector to decrypt captures with
+// the way wireshark reads nss's keylog file for tls. built only with the
+// `keylog` feature and written only when `SIREN_KEYLOG` names a file, one
+// `LABEL auth-id hex` line per secret. anyone with the file reads the
+// traffic, it is for debugging interop and nothing else.
+
+// the file the lines are appended to
+pub const KEYLOG_VAR: &str = "SIREN_KEYLOG";
+
+// the response key and iv are derived from the request's, as on the wire
+#[cfg(feature = "keylog")]
+pub fn log_session(auth_id: &[u8], request_key: &[u8], request_iv: &[u8]) {
+    use sha2::{Digest, Sha256};
+    use std::io::Write;
+
+    let Ok(path) = std::env::var(KEYLOG_VAR) else {
+        return;
+    };
+    let response_key = &crate::sha256!(request_key)[..16];
+    let response_iv = &crate::sha256!(request_iv)[..16];
+    let auth_id = hex(auth_id);
+    let mut lines = String::new();
+    for (label, secret) in [
+        ("REQUEST_KEY", request_key),
+        ("REQUEST_IV", request_iv),
+        ("RESPONSE_KEY", response_key),
+        ("RESPONSE_IV", response_iv),
+    ] {
+        lines.push_str(&format!("{label} {auth_id} {}\n", hex(secret)));
+    }
+    // one write, so the lines of sessions at the same time do not mix
+    let written = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut x| x.write_all(lines.as_bytes()));
+    if let Err(e) = written {
+        crate::log_error!("[keylog]: {}: {}", path, e);
+    }
+}
+
+#[cfg(not(feature = "keylog"))]
+#[inline]
+pub fn log_session(_auth_id: &[u8], _request_key: &[u8], _request_iv: &[u8]) {}
+
+#[cfg(feature = "keylog")]
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{x:02x}")).collect()
+}
+
+#[cfg(all(test, feature = "keylog"))]
+mod tests {
+    use super::*;
+    use crate::common::time::SystemClock;
+    use crate::proxy::vmess::users::{self, UserTable};
+    use crate::proxy::vmess::{open_vmess_header, seal_vmess_header};
+    use sha2::{Digest, Sha256};
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_session_keylog() {
+        let path = std::env::temp_dir().join(format!("siren-keylog-{}", std::process::id()));
+        std::env::set_var(KEYLOG_VAR, &path);
+
+        let uuid = Uuid::from_u128(7);
+        let mut cmd = vec![1u8];
+        cmd.extend([3u8; 16]); // iv
+        cmd.extend([4u8; 16]); // key
+        cmd.extend([0x2a, 0x01, 0x03, 0x00, 0x01]);
+        cmd.extend(443u16.to_be_bytes());
+        cmd.extend([0x01, 192, 0, 2, 1]);
+        let sealed = seal_vmess_header(&users::cmd_key(&uuid), &cmd, &SystemClock).unwrap();
+        let users = UserTable::new(uuid);
+        open_vmess_header(&mut &sealed[..], &users).await.unwrap();
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let auth_id = hex(&sealed[..16]);
+        let lines: Vec<Vec<&str>> = log
+            .lines()
+            .map(|x| x.split(' ').collect())
+            .filter(|x: &Vec<&str>| x.get(1) == Some(&auth_id.as_str()))
+            .collect();
+        let labels: Vec<_> = lines.iter().map(|x| x[0]).collect();
+        assert_eq!(
+            labels,
+            ["REQUEST_KEY", "REQUEST_IV", "RESPONSE_KEY", "RESPONSE_IV"]
+        );
+        assert_eq!(lines[0][2], "04".repeat(16));
+        assert_eq!(lines[1][2], "03".repeat(16));
+        assert_eq!(lines[2][2], hex(&crate::sha256!(&[4u8; 16])[..16]));
+        for line in lines {
+            assert_eq!(line.len(), 3);
+            assert!(line[2].len() == 32 && line[2].bytes().all(|x| x.is_ascii_hexdigit()));
+        }
+    }
+}
@@ -0,0 +1,37 @@
+// the kdf labels that separate vmess's keys from one another. a server may
+// take several versions at once, so clients move to new labels one by one
+// without any of them being turned away in between.
+
+use crate::common::{
+    KDFSALT_CONST_AEAD_RESP_HEADER_IV, KDFSALT_CONST_AEAD_RESP_HEADER_KEY,
+    KDFSALT_CONST_AEAD_RESP_HEADER_LEN_IV, KDFSALT_CONST_AEAD_RESP_HEADER_LEN_KEY,
+    KDFSALT_CONST_AUTH_ID_ENCRYPTION_KEY, KDFSALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_IV,
+    KDFSALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_KEY, KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_IV,
+    KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_KEY,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Labels {
+    pub auth_id: &'static [u8],
+    pub header_length_key: &'static [u8],
+    pub header_length_iv: &'static [u8],
+    pub header_key: &'static [u8],
+    pub header_iv: &'static [u8],
+    pub response_length_key: &'static [u8],
+    pub response_length_iv: &'static [u8],
+    pub response_key: &'static [u8],
+    pub response_iv: &'static [u8],
+}
+
+// what v2ray and every other client use
+pub const V2RAY: Labels = Labels {
+    auth_id: KDFSALT_CONST_AUTH_ID_ENCRYPTION_KEY,
+    header_length_key: KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_KEY,
+    header_length_iv: KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_IV,
+    header_key: KDFSALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_KEY,
+    header_iv: KDFSALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_IV,
+    response_length_key: KDFSALT_CONST_AEAD_RESP_HEADER_LEN_KEY,
+    response_length_iv: KDFSALT_CONST_AEAD_RESP_HEADER_LEN_IV,
+    response_key: KDFSALT_CONST_AEAD_RESP_HEADER_KEY,
+    response_iv: KDFSALT_CONST_AEAD_RESP_HEADER_IV,
+};
@@ -0,0 +1,721 @@
+use super::chunk::Security;
+
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine as _};
+use futures_util::{stream, Stream, StreamExt};
+use serde_json::{Map, Value};
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::IpAddr;
+use std::pin::pin;
+use std::str::FromStr;
+use uuid::Uuid;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Address {
+    Ip(IpAddr),
+    Domain(String),
+}
+
+impl From<&str> for Address {
+    fn from(s: &str) -> Self {
+        // ipv6 hosts sometimes come bracketed
+        let ip = s.trim_start_matches('[').trim_end_matches(']');
+        match ip.parse() {
+            Ok(x) => Self::Ip(x),
+            Err(_) => Self::Domain(s.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ip(x) => write!(f, "{x}"),
+            Self::Domain(x) => write!(f, "{x}"),
+        }
+    }
+}
+
+// the host alone, the way it is written, as the port is kept next to it
+#[cfg(feature = "serde")]
+impl serde::Serialize for Address {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Address {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = <String as serde::Deserialize>::deserialize(deserializer)?;
+        if s.is_empty() {
+            return Err(serde::de::Error::custom("empty address"));
+        }
+        Ok(Self::from(s.as_str()))
+    }
+}
+
+// a `vmess://` share link. the v2rayN json body (version 2, and version 1
+// with `host;path` packed into `host`) and the older shadowrocket form
+// `base64(scy:id@add:port)?remarks=..&obfs=websocket` are understood.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VmessShareLink {
+    // `ps`
+    pub remarks: String,
+    pub address: Address,
+    pub port: u16,
+    pub id: Uuid,
+    // `aid`, anything but 0 is legacy md5 auth which this worker refuses
+    pub alter_id: u16,
+    // `scy`
+    pub security: Security,
+    // `net`: tcp, ws, h2, grpc...
+    pub network: String,
+    // `type`, the header obfuscation for tcp/kcp/quic
+    pub header_type: String,
+    pub host: String,
+    pub path: String,
+    pub tls: bool,
+    pub sni: String,
+}
+
+// `scy`. for auto v2ray picks aes-128-gcm wherever it has hardware aes,
+// which is every client platform that matters
+pub(crate) fn parse_security(s: &str) -> Result<Security, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "" | "auto" => Ok(Security::Aes128Gcm),
+        x => Security::from_name(x).ok_or_else(|| format!("unsupported security {x:?}")),
+    }
+}
+
+// the base64 body of either link form, padded or not, standard or url safe
+fn decode(s: &str) -> Result<String, String> {
+    let normalized: String = s
+        .trim()
+        .trim_end_matches('=')
+        .chars()
+        .map(|c| match c {
+            '-' => '+',
+            '_' => '/',
+            c => c,
+        })
+        .collect();
+    let body = STANDARD_NO_PAD
+        .decode(normalized)
+        .map_err(|e| format!("invalid base64: {e}"))?;
+    String::from_utf8(body).map_err(|_| "link body is not utf-8".to_string())
+}
+
+// clients disagree on whether numbers are strings
+fn number(object: &Map<String, Value>, key: &str) -> Result<Option<u64>, String> {
+    match object.get(key) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(x)) if x.is_empty() => Ok(None),
+        Some(Value::String(x)) => x
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("`{key}` is not a number: {x:?}")),
+        Some(Value::Number(x)) => x
+            .as_u64()
+            .map(Some)
+            .ok_or_else(|| format!("`{key}` is not a positive integer: {x}")),
+        Some(x) => Err(format!("`{key}` is not a number: {x}")),
+    }
+}
+
+fn string<'a>(object: &'a Map<String, Value>, key: &str) -> &'a str {
+    object.get(key).and_then(Value::as_str).unwrap_or_default()
+}
+
+fn port(port: Option<u64>) -> Result<u16, String> {
+    match port.map(u16::try_from) {
+        Some(Ok(x)) if x != 0 => Ok(x),
+        Some(_) => Err("`port` must be between 1 and 65535".to_string()),
+        None => Err("missing field `port`".to_string()),
+    }
+}
+
+fn uuid(id: &str) -> Result<Uuid, String> {
+    if id.is_empty() {
+        return Err("missing field `id`".to_string());
+    }
+    Uuid::parse_str(id).map_err(|e| format!("invalid id {id:?}: {e}"))
+}
+
+impl VmessShareLink {
+    fn from_json(body: &str) -> Result<Self, String> {
+        let value: Value = serde_json::from_str(body).map_err(|e| format!("invalid json: {e}"))?;
+        let object = value.as_object().ok_or("link body is not a json object")?;
+
+        let address = match string(object, "add") {
+            "" => return Err("missing field `add`".to_string()),
+            x => Address::from(x),
+        };
+        let alter_id = number(object, "aid")?
+            .map(u16::try_from)
+            .transpose()
+            .map_err(|_| "`aid` is out of range".to_string())?;
+
+        // version 1 links carry the path after the host
+        let (mut host, mut path) = (string(object, "host"), string(object, "path"));
+        if number(object, "v")? == Some(1) {
+            if let Some((h, p)) = host.split_once(';') {
+                (host, path) = (h, p);
+            }
+        }
+
+        Ok(Self {
+            remarks: string(object, "ps").to_string(),
+            address,
+            port: port(number(object, "port")?)?,
+            id: uuid(string(object, "id"))?,
+            alter_id: alter_id.unwrap_or(0),
+            security: parse_security(string(object, "scy"))?,
+            network: match string(object, "net") {
+                "" => "tcp".to_string(),
+                x => x.to_string(),
+            },
+            header_type: match string(object, "type") {
+                "" => "none".to_string(),
+                x => x.to_string(),
+            },
+            host: host.to_string(),
+            path: path.to_string(),
+            tls: string(object, "tls") == "tls",
+            sni: string(object, "sni").to_string(),
+        })
+    }
+
+    // `scy:id@add:port`, the rest comes from the query
+    fn from_legacy(body: &str, query: &str) -> Result<Self, String> {
+        let (security, rest) = body.split_once(':').ok_or("expected scy:id@add:port")?;
+        let (id, server) = rest.split_once('@').ok_or("expected scy:id@add:port")?;
+        let (address, port_str) = server.rsplit_once(':').ok_or("missing field `port`")?;
+        if address.is_empty() {
+            return Err("missing field `add`".to_string());
+        }
+        let port_num = port_str
+            .parse()
+            .map_err(|_| format!("`port` is not a number: {port_str:?}"))?;
+        let port = port(Some(port_num))?;
+
+        let mut link = Self {
+            remarks: String::new(),
+            address: Address::from(address),
+            port,
+            id: uuid(id)?,
+            alter_id: 0,
+            security: parse_security(security)?,
+            network: "tcp".to_string(),
+            header_type: "none".to_string(),
+            host: String::new(),
+            path: String::new(),
+            tls: false,
+            sni: String::new(),
+        };
+        let query = format!("http://link/?{query}");
+        let query = worker::Url::parse(&query).map_err(|e| format!("invalid query: {e}"))?;
+        for (key, value) in query.query_pairs() {
+            match key.as_ref() {
+                "remarks" | "remark" => link.remarks = value.to_string(),
+                "obfs" if value == "websocket" => link.network = "ws".to_string(),
+                "obfs" if value != "none" => link.network = value.to_string(),
+                "obfsParam" | "peer" => link.host = value.to_string(),
+                "path" => link.path = value.to_string(),
+                "tls" => link.tls = value == "1" || value == "true",
+                "alterId" | "aid" => {
+                    link.alter_id = value
+                        .parse()
+                        .map_err(|_| format!("invalid alterId {value:?}"))?
+                }
+                _ => {}
+            }
+        }
+        Ok(link)
+    }
+}
+
+impl FromStr for VmessShareLink {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let rest = s
+            .strip_prefix("vmess://")
+            .ok_or("expected a vmess:// link")?;
+        // the legacy form has a plain query after the base64
+        let (encoded, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let body = decode(encoded)?;
+        if body.trim_start().starts_with('{') {
+            Self::from_json(&body)
+        } else {
+            Self::from_legacy(&body, query)
+        }
+    }
+}
+
+// the lines of a subscription body that parse, and a warning for every one
+// that does not. bodies are the usual base64 of newline separated links,
+// though plain text is taken as well.
+pub fn parse_subscription(body: &str) -> (Vec<VmessShareLink>, Vec<String>) {
+    let decoded = match body.contains("://") {
+        true => None,
+        false => decode(&body.split_whitespace().collect::<String>()).ok(),
+    };
+    let mut links = Vec::new();
+    let mut warnings = Vec::new();
+    for (i, line) in decoded.as_deref().unwrap_or(body).lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        match line.parse() {
+            Ok(x) => links.push(x),
+            Err(e) => warnings.push(format!("line {}: {e}", i + 1)),
+        }
+    }
+    (links, warnings)
+}
+
+// a line this long without its end is given up on, which with the base64
+// carry bounds what a decoder holds
+const MAX_LINE: usize = 64 * 1024;
+
+// non-whitespace bytes looked at to tell base64 from plain text, every
+// link scheme has its `:` well before
+const SNIFF_LEN: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Encoding {
+    Base64,
+    Plain,
+}
+
+// `parse_subscription` a piece of the body at a time, holding only the line
+// being read. the base64 does not need to break where the lines do.
+#[derive(Debug, Default)]
+pub struct SubscriptionDecoder {
+    encoding: Option<Encoding>,
+    // the body as sent while its encoding is not known yet
+    start: Vec<u8>,
+    // base64 short of a whole quantum
+    carry: Vec<u8>,
+    line: Vec<u8>,
+    lines: usize,
+    overlong: bool,
+    // the base64 broke, nothing after it is read
+    failed: bool,
+}
+
+impl SubscriptionDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // the links of the lines `chunk` completes, a warning for each that
+    // does not parse
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<Result<VmessShareLink, String>> {
+        let mut links = Vec::new();
+        match self.encoding {
+            _ if self.failed => {}
+            Some(Encoding::Plain) => self.lines_of(chunk, &mut links),
+            Some(Encoding::Base64) => self.base64(chunk, &mut links),
+            None => {
+                self.start.extend_from_slice(chunk);
+                let text = self.start.iter().filter(|x| !x.is_ascii_whitespace());
+                if self.start.contains(&b':') {
+                    self.begin(Encoding::Plain, &mut links);
+                } else if text.count() >= SNIFF_LEN {
+                    self.begin(Encoding::Base64, &mut links);
+                }
+            }
+        }
+        links
+    }
+
+    // the last line, which has no line break after it
+    pub fn finish(&mut self) -> Vec<Result<VmessShareLink, String>> {
+        let mut links = Vec::new();
+        if self.encoding.is_none() {
+            let plain = self.start.contains(&b':') || self.start.trim_ascii().is_empty();
+            let encoding = if plain { Encoding::Plain } else { Encoding::Base64 };
+            self.begin(encoding, &mut links);
+        }
+        if self.encoding == Some(Encoding::Base64) && !self.failed {
+            match STANDARD_NO_PAD.decode(std::mem::take(&mut self.carry)) {
+                Ok(x) => self.lines_of(&x, &mut links),
+                Err(e) => {
+                    self.failed = true;
+                    links.push(Err(format!("invalid base64: {e}")));
+                }
+            }
+        }
+        if !self.failed && (!self.line.is_empty() || self.overlong) {
+            self.lines_of(b"\n", &mut links);
+        }
+        links
+    }
+
+    fn begin(&mut self, encoding: Encoding, links: &mut Vec<Result<VmessShareLink, String>>) {
+        self.encoding = Some(encoding);
+        let start = std::mem::take(&mut self.start);
+        match encoding {
+            Encoding::Plain => self.lines_of(&start, links),
+            Encoding::Base64 => self.base64(&start, links),
+        }
+    }
+
+    // whole quanta are decoded, the rest waits for the next chunk
+    fn base64(&mut self, chunk: &[u8], links: &mut Vec<Result<VmessShareLink, String>>) {
+        let normalized = chunk.iter().filter_map(|&x| match x {
+            b'-' => Some(b'+'),
+            b'_' => Some(b'/'),
+            b'=' => None,
+            x if x.is_ascii_whitespace() => None,
+            x => Some(x),
+        });
+        self.carry.extend(normalized);
+        let whole = self.carry.len() / 4 * 4;
+        match STANDARD_NO_PAD.decode(&self.carry[..whole]) {
+            Ok(x) => {
+                self.carry.drain(..whole);
+                self.lines_of(&x, links);
+            }
+            Err(e) => {
+                self.failed = true;
+                self.carry = Vec::new();
+                links.push(Err(format!("invalid base64: {e}")));
+            }
+        }
+    }
+
+    fn lines_of(&mut self, text: &[u8], links: &mut Vec<Result<VmessShareLink, String>>) {
+        for piece in text.split_inclusive(|x| *x == b'\n') {
+            let (piece, complete) = match piece.strip_suffix(b"\n") {
+                Some(x) => (x, true),
+                None => (piece, false),
+            };
+            if !self.overlong {
+                self.line.extend_from_slice(piece);
+                if self.line.len() > MAX_LINE {
+                    self.overlong = true;
+                    self.line = Vec::new();
+                }
+            }
+            if !complete {
+                continue;
+            }
+            self.lines += 1;
+            let line = std::mem::take(&mut self.line);
+            if std::mem::take(&mut self.overlong) {
+                links.push(Err(format!("line {}: longer than {MAX_LINE} bytes", self.lines)));
+                continue;
+            }
+            let line = match std::str::from_utf8(&line) {
+                Ok(x) => x.trim(),
+                Err(_) => {
+                    links.push(Err(format!("line {}: not utf-8", self.lines)));
+                    continue;
+                }
+            };
+            if !line.is_empty() {
+                links.push(line.parse().map_err(|e| format!("line {}: {e}", self.lines)));
+            }
+        }
+    }
+}
+
+// the links of a subscription body as it comes in. the outer error is the
+// body failing to arrive, the inner one a line that does not parse.
+pub fn decode_subscription<S, B, E>(
+    body: S,
+) -> impl Stream<Item = worker::Result<Result<VmessShareLink, String>>>
+where
+    S: Stream<Item = Result<B, E>>,
+    B: AsRef<[u8]>,
+    E: fmt::Display,
+{
+    let state = (Box::pin(body), SubscriptionDecoder::new(), VecDeque::new(), false);
+    stream::unfold(state, |(mut body, mut decoder, mut queue, mut done)| async move {
+        loop {
+            if let Some(x) = queue.pop_front() {
+                return Some((Ok(x), (body, decoder, queue, done)));
+            }
+            if done {
+                return None;
+            }
+            match body.next().await {
+                Some(Ok(chunk)) => queue.extend(decoder.push(chunk.as_ref())),
+                Some(Err(e)) => {
+                    let e = worker::Error::RustError(format!("reading the subscription: {e}"));
+                    return Some((Err(e), (body, decoder, queue, true)));
+                }
+                None => {
+                    queue.extend(decoder.finish());
+                    done = true;
+                }
+            }
+        }
+    })
+}
+
+// fetches a subscription url. lines that fail to parse are logged and left
+// out, only a failed request fails the fetch.
+pub async fn fetch_subscription(url: &str) -> worker::Result<Vec<VmessShareLink>> {
+    let response = reqwest::get(url)
+        .await
+        .and_then(|x| x.error_for_status())
+        .map_err(|e| worker::Error::RustError(e.to_string()))?;
+
+    let mut entries = pin!(decode_subscription(response.bytes_stream()));
+    let mut links = Vec::new();
+    let mut warnings = Vec::new();
+    while let Some(entry) = entries.next().await {
+        match entry? {
+            Ok(x) => links.push(x),
+            Err(e) => warnings.push(e),
+        }
+    }
+    if !warnings.is_empty() {
+        crate::log!(
+            "[subscription]: skipped {} in {}: {}",
+            warnings.len(),
+            url,
+            warnings.join(", ")
+        );
+    }
+    Ok(links)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::{STANDARD, URL_SAFE};
+    use serde_json::json;
+
+    const UUID: &str = "f282b878-8711-45a1-8c69-5564172123c1";
+
+    fn link(body: &Value) -> String {
+        format!("vmess://{}", URL_SAFE.encode(body.to_string()))
+    }
+
+    #[test]
+    fn test_v2_link() {
+        // what `/sub` hands out
+        let body = json!({
+            "ps": "siren vmess", "v": "2", "add": "example.com", "port": "80", "id": UUID,
+            "aid": "0", "scy": "zero", "net": "ws", "type": "none", "host": "example.com",
+            "path": "/KR", "tls": "", "sni": "", "alpn": "",
+        });
+        let parsed: VmessShareLink = link(&body).parse().unwrap();
+        assert_eq!(
+            parsed,
+            VmessShareLink {
+                remarks: "siren vmess".to_string(),
+                address: Address::Domain("example.com".to_string()),
+                port: 80,
+                id: Uuid::parse_str(UUID).unwrap(),
+                alter_id: 0,
+                security: Security::Zero,
+                network: "ws".to_string(),
+                header_type: "none".to_string(),
+                host: "example.com".to_string(),
+                path: "/KR".to_string(),
+                tls: false,
+                sni: String::new(),
+            }
+        );
+
+        // numbers as numbers, standard base64 without padding, an ip host
+        let body =
+            json!({"v": 2, "add": "2001:db8::1", "port": 443, "id": UUID, "aid": 0, "tls": "tls"});
+        let encoded = STANDARD.encode(body.to_string());
+        let parsed: VmessShareLink = format!("vmess://{}", encoded.trim_end_matches('='))
+            .parse()
+            .unwrap();
+        assert_eq!(parsed.address, Address::Ip("2001:db8::1".parse().unwrap()));
+        assert_eq!((parsed.port, parsed.tls), (443, true));
+        assert_eq!(parsed.security, Security::Aes128Gcm);
+        assert_eq!(parsed.network, "tcp");
+    }
+
+    #[test]
+    fn test_legacy_links() {
+        let body = json!({"v": "1", "add": "example.com", "port": "443", "id": UUID, "net": "ws", "host": "cdn.example.com;/ws"});
+        let parsed: VmessShareLink = link(&body).parse().unwrap();
+        assert_eq!(
+            (parsed.host.as_str(), parsed.path.as_str()),
+            ("cdn.example.com", "/ws")
+        );
+
+        let encoded = STANDARD.encode(format!("chacha20-poly1305:{UUID}@198.51.100.1:8443"));
+        let parsed: VmessShareLink =
+            format!("vmess://{encoded}?remarks=home%20server&obfs=websocket&path=/v2&tls=1")
+                .parse()
+                .unwrap();
+        assert_eq!(parsed.address, Address::Ip("198.51.100.1".parse().unwrap()));
+        assert_eq!(parsed.port, 8443);
+        assert_eq!(parsed.security, Security::ChaCha20Poly1305);
+        assert_eq!(parsed.remarks, "home server");
+        assert_eq!(
+            (parsed.network.as_str(), parsed.path.as_str()),
+            ("ws", "/v2")
+        );
+        assert!(parsed.tls);
+    }
+
+    #[test]
+    fn test_malformed_links() {
+        let full = link(&json!({"add": "example.com", "port": "443", "id": UUID}));
+        let truncated = &full[..full.len() - 12];
+        let e = truncated.parse::<VmessShareLink>().unwrap_err();
+        assert!(
+            e.starts_with("invalid json") || e.starts_with("invalid base64"),
+            "{e}"
+        );
+
+        let cases = [
+            ("vless://abc", "expected a vmess:// link"),
+            ("vmess://not*base64", "invalid base64"),
+            (
+                &link(&json!({"port": "443", "id": UUID})),
+                "missing field `add`",
+            ),
+            (
+                &link(&json!({"add": "example.com", "id": UUID})),
+                "missing field `port`",
+            ),
+            (
+                &link(&json!({"add": "example.com", "port": "443"})),
+                "missing field `id`",
+            ),
+            (
+                &link(&json!({"add": "example.com", "port": "x", "id": UUID})),
+                "`port` is not a number",
+            ),
+            (
+                &link(&json!({"add": "example.com", "port": 443, "id": UUID, "scy": "rc4"})),
+                "unsupported security",
+            ),
+        ];
+        for (link, message) in cases {
+            let e = link.parse::<VmessShareLink>().unwrap_err();
+            assert!(e.starts_with(message), "{link}: {e}");
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_address_serde() {
+        for (json, address) in [
+            (r#""198.51.100.1""#, Address::Ip("198.51.100.1".parse().unwrap())),
+            (r#""2001:db8::1""#, Address::Ip("2001:db8::1".parse().unwrap())),
+            (r#""edge.example.com""#, Address::Domain("edge.example.com".to_string())),
+        ] {
+            assert_eq!(serde_json::to_string(&address).unwrap(), json);
+            assert_eq!(serde_json::from_str::<Address>(json).unwrap(), address);
+        }
+        let bracketed: Address = serde_json::from_str(r#""[2001:db8::1]""#).unwrap();
+        assert_eq!(bracketed, Address::Ip("2001:db8::1".parse().unwrap()));
+        assert!(serde_json::from_str::<Address>(r#""""#).is_err());
+        assert!(serde_json::from_str::<Address>("443").is_err());
+    }
+
+    #[test]
+    fn test_parse_subscription() {
+        let good = link(&json!({"add": "example.com", "port": "443", "id": UUID}));
+        let plain = format!("{good}\r\nvmess://not*base64\r\n\r\ntrojan://x@example.com:443\r\n");
+        for body in [plain.clone(), STANDARD.encode(&plain)] {
+            let (links, warnings) = parse_subscription(&body);
+            assert_eq!(links.len(), 1);
+            assert_eq!(warnings.len(), 2, "{warnings:?}");
+            assert!(
+                warnings[0].starts_with("line 2: invalid base64"),
+                "{}",
+                warnings[0]
+            );
+            assert!(
+                warnings[1].starts_with("line 4: expected a vmess:// link"),
+                "{}",
+                warnings[1]
+            );
+        }
+    }
+
+    // base64 wrapped at 76 columns like mime, read back seven bytes at a
+    // time so neither the chunks nor the wrapping line up with anything
+    #[tokio::test]
+    async fn test_decode_subscription_stream() {
+        let mut plain = String::new();
+        for i in 0..200 {
+            let body = json!({"ps": format!("node {i}"), "add": "a.test", "port": 443, "id": UUID});
+            plain.push_str(&link(&body));
+            plain.push_str(if i % 2 == 0 { "\n" } else { "\r\n" });
+        }
+        plain.push_str("vmess://{broken\n");
+        let last = json!({"ps": "last", "add": "example.com", "port": 8443, "id": UUID});
+        plain.push_str(&link(&last));
+        let encoded = STANDARD.encode(&plain);
+        let wrapped: Vec<_> = encoded.as_bytes().chunks(76).collect();
+        let body = wrapped.join(b"\r\n".as_slice());
+        assert!(body.len() > 16 * 1024);
+
+        for body in [&body, plain.as_bytes()] {
+            let chunks = body.chunks(7).map(Ok::<_, String>);
+            let entries: Vec<_> = decode_subscription(stream::iter(chunks)).collect().await;
+            let (links, warnings): (Vec<_>, Vec<_>) =
+                entries.into_iter().map(Result::unwrap).partition(Result::is_ok);
+            assert_eq!(links.len(), 201);
+            let last = links.last().unwrap().as_ref().unwrap();
+            assert_eq!((last.remarks.as_str(), last.port), ("last", 8443));
+            assert_eq!(warnings.len(), 1);
+            let warning = warnings[0].as_ref().unwrap_err();
+            assert!(warning.starts_with("line 201: "), "{warning}");
+        }
+
+        let chunks = [Ok(b"dm1lc3M6Ly9".as_slice()), Err("connection reset")];
+        let mut entries = pin!(decode_subscription(stream::iter(chunks)));
+        let e = entries.next().await.unwrap().unwrap_err();
+        assert_eq!(e.to_string(), "reading the subscription: connection reset");
+        assert!(entries.next().await.is_none());
+
+        let mut decoder = SubscriptionDecoder::new();
+        assert!(decoder.push(b"dm1lc3M6Ly9*").is_empty());
+        let e = decoder.push(b"AAAA").remove(0).unwrap_err();
+        assert!(e.starts_with("invalid base64"), "{e}");
+        assert!(decoder.push(b"AAAA").is_empty() && decoder.finish().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_subscription() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let one = link(&json!({"ps": "one", "add": "example.com", "port": "443", "id": UUID}));
+        let body = STANDARD.encode(format!("{one}\nvmess://{{broken\n"));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0u8; 1024];
+            let n = stream.read(&mut request).await.unwrap();
+            assert!(request[..n].starts_with(b"GET /sub HTTP/1.1\r\n"));
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+
+        let links = fetch_subscription(&format!("http://{addr}/sub"))
+            .await
+            .unwrap();
+        server.await.unwrap();
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0].remarks, "one");
+
+        assert!(fetch_subscription(&format!("http://{addr}/sub"))
+            .await
+            .is_err());
+    }
+}
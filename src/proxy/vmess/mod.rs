@@ -0,0 +1,945 @@
+pub mod auth;
+pub mod chunk;
+pub mod client;
+pub mod keylog;
+pub mod labels;
+pub mod link;
+pub mod users;
+
+use super::ProxyStream;
+use auth::{AuthKey, ReplayFilter};
+use users::UserTable;
+use crate::app::stats::CountingStream;
+use crate::outbound::{Network, Target};
+use chunk::{Options, Security, VmessStream};
+use labels::{Labels, V2RAY};
+use crate::common::{
+    hash, read_port, read_addr, u16_be,
+};
+use futures_util::future::{self, Either};
+use std::io::{Cursor, Read};
+use std::net::{IpAddr, Ipv6Addr};
+use crate::common::error::ProtocolError;
+use crate::common::time::{self, Clock};
+use std::pin::pin;
+use std::rc::Rc;
+use uuid::Uuid;
+use aes::cipher::KeyInit;
+use aes_gcm::aead::{Aead, Payload};
+use crate::common::aead::Aes128Gcm;
+use md5::Digest;
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use worker::*;
+
+
+// the client's claimed header length is only known once the length block
+// opens, rejected requests open a typical command section instead
+const DECOY_HEADER_LEN: usize = 48;
+
+thread_local! {
+    static DECOY_KEY: [u8; 16] = {
+        let mut key = [0u8; 16];
+        crate::common::random(&mut key);
+        key
+    };
+}
+
+fn decoy_key() -> [u8; 16] {
+    DECOY_KEY.with(|x| *x)
+}
+
+// aead operations done while opening headers on this thread
+#[cfg(test)]
+thread_local! {
+    static AEAD_OPS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+fn open_aead(key: &[u8], nonce: &[u8], msg: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+    #[cfg(test)]
+    AEAD_OPS.with(|x| x.set(x.get() + 1));
+    Aes128Gcm::new(key.into())
+        .decrypt(nonce.into(), Payload { msg, aad })
+        .ok()
+}
+
+// the aead request header, which stays encrypted whatever body security
+// the client picked. auth ids are checked against the system clock and the
+// isolate's replay filter.
+pub async fn open_vmess_header<R>(reader: &mut R, users: &UserTable) -> Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    open_vmess_header_with(reader, users, &auth::shared()).await
+}
+
+pub async fn open_vmess_header_with<R>(
+    reader: &mut R,
+    users: &UserTable,
+    filter: &ReplayFilter,
+) -> Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    open_vmess_session(reader, users, filter).await.map(|x| x.1)
+}
+
+// the header, the uuid that opened it and the labels it was sealed under,
+// which the response is sealed under too. the users are read once up
+// front, changes to them apply from the next handshake on. limits are
+// checked once the auth id is in.
+pub async fn open_vmess_session<R>(
+    reader: &mut R,
+    users: &UserTable,
+    filter: &ReplayFilter,
+) -> Result<(Uuid, Vec<u8>, Labels)>
+where
+    R: AsyncRead + Unpin,
+{
+    let keys = users.auth_keys();
+    let mut prefix = [0u8; HEADER_PREFIX_LEN];
+    reader.read_exact(&mut prefix).await?;
+    let prefix = open_header_prefix(&prefix, users, &keys, filter);
+    let payload = match prefix.length {
+        Some(length) => {
+            let mut cmd = vec![0u8; length];
+            reader.read_exact(&mut cmd).await?;
+            Some(cmd)
+        }
+        None => None,
+    };
+    open_header_payload(prefix, payload.as_deref())
+}
+
+// `open_vmess_session` on bytes that are all there already, for callers
+// without a runtime. the last value is how many of them the header took.
+pub fn open_vmess_session_sync(
+    bytes: &[u8],
+    users: &UserTable,
+    filter: &ReplayFilter,
+) -> Result<(Uuid, Vec<u8>, Labels, usize)> {
+    let short = || Error::Io(std::io::ErrorKind::UnexpectedEof.into());
+    let prefix = bytes.get(..HEADER_PREFIX_LEN).ok_or_else(short)?;
+    let keys = users.auth_keys();
+    let prefix = open_header_prefix(prefix.try_into().unwrap(), users, &keys, filter);
+    let end = HEADER_PREFIX_LEN + prefix.length.unwrap_or(0);
+    let payload = match prefix.length {
+        Some(_) => Some(bytes.get(HEADER_PREFIX_LEN..end).ok_or_else(short)?),
+        None => None,
+    };
+    let (uuid, header, labels) = open_header_payload(prefix, payload)?;
+    Ok((uuid, header, labels, end))
+}
+
+// +-------------------+-------------------+-------------------+
+// |     Auth ID       |   Header Length   |       Nonce       |
+// +-------------------+-------------------+-------------------+
+// |     16 Bytes      |     18 Bytes      |      8 Bytes      |
+// +-------------------+-------------------+-------------------+
+const HEADER_PREFIX_LEN: usize = 42;
+
+// what the fixed part of a header tells: who sealed it, and how long the
+// sealed command section after it is
+struct HeaderPrefix {
+    auth: std::result::Result<Uuid, ProtocolError>,
+    auth_id: [u8; 16],
+    labels: Labels,
+    payload_key: [u8; 16],
+    payload_nonce: [u8; 12],
+    // none once it is rejected, nothing more is read then
+    length: Option<usize>,
+}
+
+fn open_header_prefix(
+    prefix: &[u8; HEADER_PREFIX_LEN],
+    users: &UserTable,
+    keys: &Rc<[AuthKey]>,
+    filter: &ReplayFilter,
+) -> HeaderPrefix {
+    let mut auth_id = [0u8; 16];
+    auth_id.copy_from_slice(&prefix[..16]);
+    let (len, nonce) = (&prefix[16..34], &prefix[34..]);
+
+    // an unknown, stale or replayed auth id is not turned away here: the
+    // rest of the header is opened all the same with a key no client has,
+    // so every rejection does the same work and reads the same error
+    let auth = filter.open_keys(keys, &auth_id).and_then(|(i, version)| {
+        match users.accepts(keys, i) {
+            true => Ok((keys[i].uuid, keys[i].cmd_key, version)),
+            false => Err(ProtocolError::AuthFailed("user past its limits")),
+        }
+    });
+    // a decoy takes the first version's labels
+    let (key, version) = match auth {
+        Ok((_, key, version)) => (key, version),
+        Err(_) => (decoy_key(), 0),
+    };
+    let labels = users.labels()[version];
+
+    // https://github.com/v2fly/v2ray-core/blob/master/proxy/vmess/aead/kdf.go
+    let header_length_key = &hash::kdf(&key, &[labels.header_length_key, &auth_id, nonce])[..16];
+    let header_length_nonce = &hash::kdf(&key, &[labels.header_length_iv, &auth_id, nonce])[..12];
+    let mut payload_key = [0u8; 16];
+    payload_key.copy_from_slice(&hash::kdf(&key, &[labels.header_key, &auth_id, nonce])[..16]);
+    let mut payload_nonce = [0u8; 12];
+    payload_nonce.copy_from_slice(&hash::kdf(&key, &[labels.header_iv, &auth_id, nonce])[..12]);
+
+    // 16 bytes padding
+    let length = open_aead(header_length_key, header_length_nonce, len, &auth_id)
+        .filter(|_| auth.is_ok())
+        .map(|x| (((x[0] as usize) << 8) | (x[1] as usize)) + 16);
+    HeaderPrefix {
+        auth: auth.map(|x| x.0),
+        auth_id,
+        labels,
+        payload_key,
+        payload_nonce,
+        length,
+    }
+}
+
+// the command section, `payload` being the `length` bytes after the prefix
+fn open_header_payload(
+    prefix: HeaderPrefix,
+    payload: Option<&[u8]>,
+) -> Result<(Uuid, Vec<u8>, Labels)> {
+    let HeaderPrefix {
+        auth,
+        auth_id,
+        labels,
+        payload_key,
+        payload_nonce,
+        ..
+    } = prefix;
+    let header_payload = match payload {
+        Some(cmd) => open_aead(&payload_key, &payload_nonce, cmd, &auth_id),
+        None => {
+            open_aead(&payload_key, &payload_nonce, &[0u8; DECOY_HEADER_LEN + 16], &auth_id);
+            None
+        }
+    };
+
+    match (auth, header_payload) {
+        (Ok(uuid), Some(x)) => {
+            // the header is checked further on, it may be short
+            if let (Some(iv), Some(key)) = (x.get(1..17), x.get(17..33)) {
+                keylog::log_session(&auth_id, key, iv);
+            }
+            Ok((uuid, x, labels))
+        }
+        (auth, _) => {
+            let reason = auth.err().map_or("undecryptable header".to_string(), |e| e.to_string());
+            crate::log!("[vmess]: rejected request: {}", reason);
+            Err(ProtocolError::AuthFailed("invalid vmess header").into())
+        }
+    }
+}
+
+// the client half of `open_vmess_header`: auth id, sealed length, nonce and
+// the sealed command section
+pub fn seal_vmess_header(cmd_key: &[u8; 16], cmd: &[u8], clock: &dyn Clock) -> Result<Vec<u8>> {
+    seal_vmess_header_with(cmd_key, &V2RAY, cmd, clock, &mut crate::common::random)
+}
+
+// `random` fills the auth id's random bytes and the nonce
+pub fn seal_vmess_header_with(
+    cmd_key: &[u8; 16],
+    labels: &Labels,
+    cmd: &[u8],
+    clock: &dyn Clock,
+    random: &mut dyn FnMut(&mut [u8]),
+) -> Result<Vec<u8>> {
+    let len = u16::try_from(cmd.len())
+        .map_err(|_| Error::RustError(format!("{} bytes are too long for a header", cmd.len())))?;
+    let auth_id = auth::create_auth_id_with(cmd_key, labels, clock, random);
+    let mut nonce = [0u8; 8];
+    random(&mut nonce);
+    let seal = |key_salt, iv_salt, msg: &[u8]| {
+        let key = &hash::kdf(cmd_key, &[key_salt, &auth_id, &nonce])[..16];
+        let iv = &hash::kdf(cmd_key, &[iv_salt, &auth_id, &nonce])[..12];
+        Aes128Gcm::new(key.into())
+            .encrypt(iv.into(), Payload { msg, aad: &auth_id })
+            .map_err(|e| Error::RustError(e.to_string()))
+    };
+
+    let mut header = auth_id.to_vec();
+    header.extend(seal(labels.header_length_key, labels.header_length_iv, &u16_be(len))?);
+    header.extend(nonce);
+    header.extend(seal(labels.header_key, labels.header_iv, cmd)?);
+    Ok(header)
+}
+
+// the response header echoing the request's authentication value `auth`,
+// sealed with the response body's key and iv
+pub fn seal_response_header(key: &[u8], iv: &[u8], auth: u8) -> Result<Vec<u8>> {
+    seal_response_header_with(&V2RAY, key, iv, auth)
+}
+
+// under the labels the request was sealed under
+pub fn seal_response_header_with(
+    labels: &Labels,
+    key: &[u8],
+    iv: &[u8],
+    auth: u8,
+) -> Result<Vec<u8>> {
+    // https://github.com/v2ray/v2ray-core/blob/master/proxy/vmess/encoding/client.go#L196
+    let length_key = &hash::kdf(key, &[labels.response_length_key])[..16];
+    let length_iv = &hash::kdf(iv, &[labels.response_length_iv])[..12];
+    let mut header = Aes128Gcm::new(length_key.into())
+        // 4 bytes header: https://github.com/v2ray/v2ray-core/blob/master/proxy/vmess/encoding/client.go#L238
+        .encrypt(length_iv.into(), &u16_be(4)[..])
+        .map_err(|e| Error::RustError(e.to_string()))?;
+
+    let payload_key = &hash::kdf(key, &[labels.response_key])[..16];
+    let payload_iv = &hash::kdf(iv, &[labels.response_iv])[..12];
+    // https://github.com/v2ray/v2ray-core/blob/master/proxy/vmess/encoding/client.go#L242
+    let payload = [auth, 0x00, 0x00, 0x00];
+    let payload = Aes128Gcm::new(payload_key.into())
+        .encrypt(payload_iv.into(), &payload[..])
+        .map_err(|e| Error::RustError(e.to_string()))?;
+    header.extend(payload);
+    Ok(header)
+}
+
+// the command section of an opened header, what the session is for
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VmessRequest {
+    pub iv: [u8; 16],
+    pub key: [u8; 16],
+    // echoed in the response header
+    pub auth: u8,
+    pub options: u8,
+    pub security: Security,
+    pub target: Target,
+}
+
+impl VmessRequest {
+    pub fn parse(header: &[u8]) -> Result<Self> {
+        let mut buf = Cursor::new(header);
+
+        // https://xtls.github.io/en/development/protocols/vmess.html#command-section
+        //
+        // +---------+--------------------+---------------------+-------------------------------+---------+----------+-------------------+----------+---------+---------+--------------+---------+--------------+----------+
+        // | 1 Byte  |      16 Bytes      |      16 Bytes       |            1 Byte             | 1 Byte  |  4 bits  |      4 bits       |  1 Byte  | 1 Byte  | 2 Bytes |    1 Byte    | N Bytes |   P Bytes    | 4 Bytes  |
+        // +---------+--------------------+---------------------+-------------------------------+---------+----------+-------------------+----------+---------+---------+--------------+---------+--------------+----------+
+        // | Version | Data Encryption IV | Data Encryption Key | Response Authentication Value | Options | Reserved | Encryption Method | Reserved | Command | Port    | Address Type | Address | Random Value | Checksum |
+        // +---------+--------------------+---------------------+-------------------------------+---------+----------+-------------------+----------+---------+---------+--------------+---------+--------------+----------+
+
+        let mut version = [0u8];
+        Read::read_exact(&mut buf, &mut version)?;
+        let version = version[0];
+        if version != 1 {
+            return Err(Error::RustError("invalid version".to_string()));
+        }
+
+        let mut iv = [0u8; 16];
+        Read::read_exact(&mut buf, &mut iv)?;
+        let mut key = [0u8; 16];
+        Read::read_exact(&mut buf, &mut key)?;
+
+        // response authentication value, options, padding length + security and reserved
+        // and the command
+        let mut options = [0u8; 5];
+        Read::read_exact(&mut buf, &mut options)?;
+        let security = Security::from_byte(options[2])?;
+        let network = if options[4] == 0x1 { Network::Tcp } else { Network::Udp };
+
+        let port = read_port(&mut buf)?;
+        let addr = read_vmess_addr(&mut buf)?;
+        Ok(Self {
+            iv,
+            key,
+            auth: options[0],
+            options: options[1],
+            security,
+            target: Target::new(addr, port, network),
+        })
+    }
+
+    // the response body's key and iv
+    pub fn response_keys(&self) -> ([u8; 16], [u8; 16]) {
+        let mut key = [0u8; 16];
+        key.copy_from_slice(&crate::sha256!(&self.key)[..16]);
+        let mut iv = [0u8; 16];
+        iv.copy_from_slice(&crate::sha256!(&self.iv)[..16]);
+        (key, iv)
+    }
+}
+
+// vmess numbers its ipv6 addresses 0x03, which trojan and socks take for a
+// domain, so the shared reader only sees the other types
+fn read_vmess_addr(buf: &mut Cursor<&[u8]>) -> Result<String> {
+    if buf.get_ref().get(buf.position() as usize) != Some(&0x03) {
+        return read_addr(buf);
+    }
+    let mut addr = [0u8; 17];
+    Read::read_exact(buf, &mut addr)?;
+    let mut ip = [0u8; 16];
+    ip.copy_from_slice(&addr[1..]);
+    Ok(Ipv6Addr::from(ip).to_string())
+}
+
+pub const COMMAND_TCP: u8 = 0x01;
+pub const COMMAND_UDP: u8 = 0x02;
+
+impl VmessRequest {
+    // the command section for this request, checksum included and with no
+    // padding. the builder's checks are what keep the fields encodable.
+    pub fn encode(&self) -> Vec<u8> {
+        let command = match self.target.network {
+            Network::Tcp => COMMAND_TCP,
+            Network::Udp => COMMAND_UDP,
+        };
+        let mut cmd = vec![1u8];
+        cmd.extend(self.iv);
+        cmd.extend(self.key);
+        cmd.extend([self.auth, self.options, self.security.to_byte(), 0x00, command]);
+        cmd.extend(u16_be(self.target.port));
+        match self.target.addr.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                cmd.push(0x01);
+                cmd.extend(ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                cmd.push(0x03);
+                cmd.extend(ip.octets());
+            }
+            Err(_) => {
+                cmd.extend([0x02, self.target.addr.len() as u8]);
+                cmd.extend(self.target.addr.as_bytes());
+            }
+        }
+        cmd.extend(fnv1a(&cmd).to_be_bytes());
+        cmd
+    }
+}
+
+// the checksum closing the command section
+pub(crate) fn fnv1a(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c9dc5, |hash, &b| {
+        (hash ^ b as u32).wrapping_mul(0x01000193)
+    })
+}
+
+// a request to send, checked for what servers would turn away before any
+// of it is sealed. `VmessRequest` itself takes whatever a header decodes to.
+#[derive(Clone, Debug)]
+pub struct VmessRequestBuilder {
+    command: u8,
+    addr: String,
+    port: u16,
+    iv: [u8; 16],
+    key: [u8; 16],
+    auth: u8,
+    options: Options,
+    security: Security,
+}
+
+impl VmessRequestBuilder {
+    pub fn new(command: u8, addr: &str, port: u16) -> Self {
+        Self {
+            command,
+            addr: addr.to_string(),
+            port,
+            iv: [0u8; 16],
+            key: [0u8; 16],
+            auth: 0,
+            options: Options::CHUNK_STREAM | Options::CHUNK_MASKING,
+            security: Security::Aes128Gcm,
+        }
+    }
+
+    // the body's iv and key and the response's authentication value, all
+    // zero until set
+    pub fn with_keys(mut self, iv: [u8; 16], key: [u8; 16], auth: u8) -> Self {
+        (self.iv, self.key, self.auth) = (iv, key, auth);
+        self
+    }
+
+    // chunk stream and masking by default
+    pub fn with_options(mut self, options: impl Into<Options>) -> Self {
+        self.options = options.into();
+        self
+    }
+
+    // aes-128-gcm by default
+    pub fn with_security(mut self, security: Security) -> Self {
+        self.security = security;
+        self
+    }
+
+    pub fn build(self) -> Result<VmessRequest> {
+        let network = match self.command {
+            COMMAND_TCP => Network::Tcp,
+            COMMAND_UDP => Network::Udp,
+            x => return Err(Error::RustError(format!("unknown command {x:#04x}"))),
+        };
+        if self.port == 0 {
+            return Err(Error::RustError("port 0 is not a destination".to_string()));
+        }
+        if self.addr.parse::<IpAddr>().is_err() && !is_hostname(&self.addr) {
+            return Err(Error::RustError(format!("invalid host {:?}", self.addr)));
+        }
+        self.options.check()?;
+        Ok(VmessRequest {
+            iv: self.iv,
+            key: self.key,
+            auth: self.auth,
+            options: self.options.bits(),
+            security: self.security,
+            target: Target::new(self.addr, self.port, network),
+        })
+    }
+}
+
+// 1 to 255 bytes of labels of letters, digits, `-` and `_`, with at most a
+// trailing dot. labels do not start or end with `-`.
+fn is_hostname(host: &str) -> bool {
+    let labels = host.strip_suffix('.').unwrap_or(host);
+    (1..=255).contains(&host.len())
+        && labels.split('.').all(|label| {
+            (1..=63).contains(&label.len())
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.bytes().all(|x| x.is_ascii_alphanumeric() || x == b'-' || x == b'_')
+        })
+}
+
+// the server side of a session over any stream: the header is opened and
+// answered, the body is what is left. the uuid is the one that opened it.
+pub async fn accept_vmess<S>(
+    mut stream: S,
+    users: &UserTable,
+    filter: &ReplayFilter,
+) -> Result<(Uuid, VmessRequest, VmessStream<S>)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (uuid, header, labels) = open_vmess_session(&mut stream, users, filter).await?;
+    let request = VmessRequest::parse(&header)?;
+    let (key, iv) = request.response_keys();
+    let response = seal_response_header_with(&labels, &key, &iv, request.auth)?;
+    stream.write_all(&response).await?;
+    let stream = VmessStream::new(
+        stream,
+        request.security,
+        request.options,
+        &request.key,
+        &request.iv,
+        &key,
+        &iv,
+    )?;
+    Ok((uuid, request, stream))
+}
+
+impl <'a> ProxyStream<'a> {
+    pub async fn process_vmess(&mut self) -> Result<()> {
+        let users = users::shared(&self.config.uuid);
+        // a rejected header read no further than the first message, which
+        // goes to a fallback in full
+        let replay = (!self.config.fallbacks.is_empty()).then(|| self.buffer.clone());
+        let (handshake, filter) = (self.config.policy.handshake, auth::shared());
+        // the response header is part of the handshake, a client that does
+        // not read it stalls it as much as one that does not send its own
+        let mut opened = false;
+        let exchange = async {
+            let (uuid, header, labels) = open_vmess_session(self, &users, &filter).await?;
+            opened = true;
+            let request = VmessRequest::parse(&header)?;
+            let (key, iv) = request.response_keys();
+            let header = seal_response_header_with(&labels, &key, &iv, request.auth)?;
+            self.write_all(&header).await?;
+            Ok((uuid, request, key, iv))
+        };
+        let exchanged = time::timeout(handshake, exchange).await;
+        let (uuid, request, key, iv) = match (exchanged, replay) {
+            (Some(Ok(x)), _) => x,
+            // the client stalled, the connection goes with it
+            (None, _) => {
+                let message = format!("vmess handshake timed out after {handshake:?}");
+                return Err(Error::Io(std::io::Error::new(std::io::ErrorKind::TimedOut, message)));
+            }
+            (Some(Err(_)), Some(replay)) if !opened && !self.buffer.is_empty() => {
+                self.buffer = replay;
+                return self.process_fallback().await;
+            }
+            (Some(Err(e)), _) => return Err(e),
+        };
+        let (user, kicked) = (users.user(&uuid), users.kicked(&uuid));
+
+        let mut metadata = self.metadata("vmess", request.target.clone());
+        // added users are counted by email, their quota is read from that
+        metadata.user = user.map(|x| x.email);
+        let quota = metadata.user.as_deref().and_then(|x| users.quota(x));
+        let dispatcher = self.dispatcher.clone();
+
+        let stream = VmessStream::new(
+            &mut *self,
+            request.security,
+            request.options,
+            &request.key,
+            &request.iv,
+            &key,
+            &iv,
+        )?;
+        // the dispatcher counts the user's traffic, this one only stops it
+        // once the quota is used up, without waiting for the next check
+        let mut stream = CountingStream::new(stream, Vec::new(), Vec::new());
+        if let Some(quota) = quota {
+            stream = stream.with_quota(quota);
+        }
+        let dispatch = dispatcher.dispatch(&metadata, &mut stream);
+        let Some(kicked) = kicked else {
+            return dispatch.await;
+        };
+        let result = match future::select(pin!(dispatch), kicked).await {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => Err(Error::RustError("user removed".to_string())),
+        };
+        result
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chunk::ChunkCodec;
+    use crate::common::time::{MockClock, SystemClock};
+
+    const UUID: &str = "f282b878-8711-45a1-8c69-5564172123c1";
+
+    fn seal_header(uuid: &Uuid, cmd: &[u8]) -> Vec<u8> {
+        seal_vmess_header(&users::cmd_key(uuid), cmd, &SystemClock).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_header_encrypted_for_none_security() {
+        let uuid = Uuid::parse_str(UUID).unwrap();
+        let target = b"plaintext.example.com";
+        let mut cmd = vec![1u8];
+        cmd.extend([3u8; 32]); // iv and key
+        cmd.extend([0x2a, chunk::OPTION_CHUNK_STREAM, 0x05, 0x00, 0x01]);
+        cmd.extend(443u16.to_be_bytes());
+        cmd.extend([0x02, target.len() as u8]);
+        cmd.extend(target);
+
+        let sealed = seal_header(&uuid, &cmd);
+        assert!(!sealed.windows(target.len()).any(|x| x == target));
+
+        let users = UserTable::new(uuid);
+        let header = open_vmess_header(&mut &sealed[..], &users).await.unwrap();
+        assert_eq!(header, cmd);
+        assert_eq!(Security::from_byte(header[35]).unwrap(), Security::None);
+
+        let other = Uuid::parse_str("00000000-0000-0000-0000-000000000001").unwrap();
+        let others = UserTable::new(other);
+        assert!(open_vmess_header(&mut &seal_header(&uuid, &cmd)[..], &others).await.is_err());
+
+        // the same request a second time is a replay
+        let e = open_vmess_header(&mut &sealed[..], &users).await.unwrap_err();
+        assert_eq!(e.to_string(), "invalid vmess header");
+    }
+
+    #[tokio::test]
+    async fn test_open_never_panics() {
+        let uuid = Uuid::parse_str(UUID).unwrap();
+        // the longest length block there is
+        let cmd = vec![1u8; u16::MAX as usize];
+        let sealed = seal_header(&uuid, &cmd);
+        let header = open_vmess_header(&mut &sealed[..], &UserTable::new(uuid)).await;
+        assert_eq!(header.unwrap(), cmd);
+        let sealing = seal_vmess_header(&[0u8; 16], &[1u8; 1 << 16], &SystemClock);
+        assert!(sealing.is_err());
+
+        let sealed = seal_header(&uuid, &[1u8; 41]);
+        for len in 0..sealed.len() {
+            let res = open_vmess_header(&mut &sealed[..len], &UserTable::new(uuid)).await;
+            assert!(res.is_err(), "{len} bytes");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rejections_do_the_same_work() {
+        let uuid = Uuid::parse_str(UUID).unwrap();
+        let other = Uuid::parse_str("00000000-0000-0000-0000-000000000001").unwrap();
+        let cmd = [1u8; 41];
+        let ops = |sealed: Vec<u8>, uuid: Uuid| async move {
+            let before = AEAD_OPS.with(|x| x.get());
+            let res = open_vmess_header(&mut &sealed[..], &UserTable::new(uuid)).await;
+            (AEAD_OPS.with(|x| x.get()) - before, res.map_err(|e| e.to_string()))
+        };
+
+        let sealed = seal_header(&uuid, &cmd);
+        assert_eq!(ops(sealed.clone(), uuid).await, (2, Ok(cmd.to_vec())));
+
+        let rejected = "invalid vmess header".to_string();
+        let mut tampered = seal_header(&uuid, &cmd);
+        tampered[20] ^= 1;
+        let cases = [
+            ("unknown user", seal_header(&uuid, &cmd), other),
+            ("replay", sealed, uuid),
+            ("bad length", tampered, uuid),
+        ];
+        for (name, sealed, uuid) in cases {
+            assert_eq!(ops(sealed, uuid).await, (2, Err(rejected.clone())), "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rotate_uuid_keeps_sessions() {
+        let old = Uuid::parse_str(UUID).unwrap();
+        let new = Uuid::parse_str("00000000-0000-0000-0000-000000000001").unwrap();
+        let users = UserTable::new(old).with_grace(0);
+        let mut cmd = vec![1u8];
+        cmd.extend([3u8; 16]); // iv
+        cmd.extend([4u8; 16]); // key
+        cmd.extend([0x2a, chunk::OPTION_CHUNK_STREAM, 0x03, 0x00, 0x01]);
+        cmd.extend(443u16.to_be_bytes());
+        cmd.extend([0x01, 192, 0, 2, 1]);
+
+        // a session opened under the old uuid
+        let header = open_vmess_header(&mut &seal_header(&old, &cmd)[..], &users)
+            .await
+            .unwrap();
+        let (iv, key) = (&header[1..17], &header[17..33]);
+        let options = chunk::OPTION_CHUNK_STREAM;
+        let (client, server) = tokio::io::duplex(1024);
+        let mut server =
+            VmessStream::new(server, Security::Aes128Gcm, options, key, iv, key, iv).unwrap();
+        let mut client =
+            VmessStream::new(client, Security::Aes128Gcm, options, key, iv, key, iv).unwrap();
+        let mut buf = [0u8; 6];
+        client.write_all(b"before").await.unwrap();
+        server.read_exact(&mut buf).await.unwrap();
+
+        users.rotate_uuid(old, new).unwrap();
+
+        // it keeps flowing, only new handshakes need the new uuid
+        client.write_all(b"after!").await.unwrap();
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"after!");
+        server.write_all(b"answer").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"answer");
+
+        assert!(open_vmess_header(&mut &seal_header(&old, &cmd)[..], &users)
+            .await
+            .is_err());
+        let header = open_vmess_header(&mut &seal_header(&new, &cmd)[..], &users)
+            .await
+            .unwrap();
+        assert_eq!(header, cmd);
+    }
+
+    #[tokio::test]
+    async fn test_managed_users() {
+        let binding = Uuid::parse_str(UUID).unwrap();
+        let alice = Uuid::from_u128(2);
+        let users = UserTable::new(binding);
+        let cmd = [1u8; 41];
+        let filter = ReplayFilter::new(120);
+        let open = |uuid| {
+            let sealed = seal_header(&uuid, &cmd);
+            let (users, filter) = (&users, &filter);
+            async move { open_vmess_session(&mut &sealed[..], users, filter).await }
+        };
+
+        assert!(open(alice).await.is_err());
+        users.add_user(alice, "alice@example.com", 0).unwrap();
+        assert_eq!(open(alice).await.unwrap(), (alice, cmd.to_vec(), V2RAY));
+        assert_eq!(open(binding).await.unwrap().0, binding);
+
+        users.remove_user("alice@example.com", false).unwrap();
+        assert!(open(alice).await.is_err());
+        assert!(open(binding).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_users_change_during_handshakes() {
+        let users = UserTable::new(Uuid::parse_str(UUID).unwrap());
+        let filter = ReplayFilter::new(120);
+        let cmd = [1u8; 41];
+        let uuid = |i: usize| Uuid::from_u128(i as u128 + 2);
+        for i in 0..16 {
+            users.add_user(uuid(i), &format!("{i}@example.com"), 0).unwrap();
+        }
+
+        // the users change while every header is only partly in
+        let (mut clients, mut servers, mut sealed) = (Vec::new(), Vec::new(), Vec::new());
+        for i in 0..16 {
+            let (mut client, server) = tokio::io::duplex(1024);
+            let header = seal_header(&uuid(i), &cmd);
+            client.write_all(&header[..20]).await.unwrap();
+            clients.push(client);
+            servers.push(server);
+            sealed.push(header);
+        }
+        let handshakes = servers.iter_mut().map(|x| open_vmess_session(x, &users, &filter));
+        let churn = async {
+            for (i, client) in clients.iter_mut().enumerate() {
+                if i % 2 == 0 {
+                    users.remove_user(&format!("{i}@example.com"), false).unwrap();
+                } else {
+                    users.add_user(uuid(i + 100), &format!("new{i}@example.com"), 0).unwrap();
+                }
+                client.write_all(&sealed[i][20..]).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        };
+        let (opened, _) = future::join(future::join_all(handshakes), churn).await;
+        for (i, opened) in opened.into_iter().enumerate() {
+            assert_eq!(opened.unwrap(), (uuid(i), cmd.to_vec(), V2RAY), "{i}");
+        }
+
+        // and apply to the handshakes after
+        let open = |i| {
+            let header = seal_header(&uuid(i), &cmd);
+            let (users, filter) = (&users, &filter);
+            async move { open_vmess_session(&mut &header[..], users, filter).await }
+        };
+        assert!(open(0).await.is_err());
+        assert!(open(1).await.is_ok());
+        assert!(open(101).await.is_ok());
+    }
+
+    // seal, open and a chunk each way on plain bytes, with no runtime around
+    #[test]
+    fn test_sync_session() {
+        let uuid = Uuid::parse_str(UUID).unwrap();
+        let mut cmd = vec![1u8];
+        cmd.extend([3u8; 16]); // iv
+        cmd.extend([4u8; 16]); // key
+        cmd.extend([0x2a, 0x05, 0x03, 0x00, 0x01]);
+        cmd.extend(443u16.to_be_bytes());
+        cmd.extend([0x02, 11]);
+        cmd.extend(b"example.com");
+        let mut bytes = seal_header(&uuid, &cmd);
+        let header_len = bytes.len();
+
+        let security = Security::Aes128Gcm;
+        let mut sealer = ChunkCodec::new(security, &[4u8; 16], &[3u8; 16], 0x05).unwrap();
+        bytes.extend(sealer.encode_chunk(b"GET /").unwrap());
+        let (users, filter) = (UserTable::new(uuid), ReplayFilter::new(120));
+        // short of the command section, which the auth id was seen for
+        let seen = ReplayFilter::new(120);
+        let short = open_vmess_session_sync(&bytes[..header_len - 1], &users, &seen);
+        assert!(matches!(short, Err(Error::Io(_))));
+        let (opened, header, _, n) = open_vmess_session_sync(&bytes, &users, &filter).unwrap();
+        assert_eq!((opened, n), (uuid, header_len));
+
+        let request = VmessRequest::parse(&header).unwrap();
+        assert_eq!(
+            request.target,
+            Target::new("example.com".to_string(), 443, Network::Tcp)
+        );
+        let (key, iv) = request.response_keys();
+        let mut opener =
+            ChunkCodec::new(request.security, &request.key, &request.iv, request.options).unwrap();
+        assert_eq!(opener.decode_chunk(&bytes[n..]).unwrap(), b"GET /");
+        assert!(seal_response_header(&key, &iv, request.auth).is_ok());
+        let mut sealer = ChunkCodec::new(request.security, &key, &iv, request.options).unwrap();
+        let mut opener = ChunkCodec::new(request.security, &key, &iv, request.options).unwrap();
+        let chunk = sealer.encode_chunk(b"200 OK").unwrap();
+        assert_eq!(opener.decode_chunk(&chunk).unwrap(), b"200 OK");
+    }
+
+    #[test]
+    fn test_request_builder() {
+        let request = VmessRequestBuilder::new(COMMAND_UDP, "dns.example.com", 53)
+            .with_keys([3u8; 16], [4u8; 16], 0x2a)
+            .with_security(Security::ChaCha20Poly1305)
+            .build()
+            .unwrap();
+        assert_eq!(
+            request.target,
+            Target::new("dns.example.com".to_string(), 53, Network::Udp)
+        );
+        assert_eq!(request.options, 0x05);
+        let cmd = request.encode();
+        let (body, sum) = cmd.split_at(cmd.len() - 4);
+        assert_eq!(fnv1a(body).to_be_bytes(), sum);
+        assert_eq!(VmessRequest::parse(&cmd).unwrap(), request);
+
+        // ipv6 goes as its 16 octets under vmess' own type
+        let request = VmessRequestBuilder::new(COMMAND_TCP, "2001:db8::1", 443)
+            .with_keys([3u8; 16], [4u8; 16], 0x2a)
+            .build()
+            .unwrap();
+        let cmd = request.encode();
+        assert_eq!(cmd[40], 0x03);
+        assert_eq!(cmd[41..57], "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        assert_eq!(VmessRequest::parse(&cmd).unwrap(), request);
+
+        let build = |command, addr: &str, port| {
+            let e = VmessRequestBuilder::new(command, addr, port).build().err().unwrap();
+            e.to_string()
+        };
+        assert_eq!(build(0x03, "example.com", 443), "unknown command 0x03");
+        assert_eq!(build(0x00, "example.com", 443), "unknown command 0x00");
+        assert_eq!(build(COMMAND_TCP, "example.com", 0), "port 0 is not a destination");
+        assert_eq!(build(COMMAND_TCP, "", 443), "invalid host \"\"");
+        let long = format!("{}com", "a.".repeat(127));
+        assert_eq!(build(COMMAND_TCP, &long, 443), format!("invalid host {long:?}"));
+        for host in ["exa mple.com", "-example.com", "example..com", "ex@mple.com", "é.com"] {
+            assert_eq!(build(COMMAND_TCP, host, 443), format!("invalid host {host:?}"));
+        }
+        let padded = VmessRequestBuilder::new(COMMAND_TCP, "example.com", 443)
+            .with_options(Options::CHUNK_STREAM | Options::GLOBAL_PADDING)
+            .build();
+        assert!(padded.is_err());
+
+        // ips, a fully qualified name and a 255 byte one are all fine
+        let longest = format!("{}a", "a.".repeat(127));
+        for host in ["192.0.2.1", "2001:db8::1", "example.com.", "_dmarc.example", &longest] {
+            assert!(VmessRequestBuilder::new(COMMAND_TCP, host, 443).build().is_ok(), "{host}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_expired_user_is_unknown() {
+        let now = SystemClock.now();
+        let (alice, bob) = (Uuid::from_u128(2), Uuid::from_u128(3));
+        let users = UserTable::new(Uuid::parse_str(UUID).unwrap());
+        users.add_user(alice, "alice@example.com", 0).unwrap();
+        users.add_user(bob, "bob@example.com", 0).unwrap();
+        users.set_limits("alice@example.com", Some(now - 1), None).unwrap();
+        users.set_limits("bob@example.com", Some(now + 3600), None).unwrap();
+
+        let filter = ReplayFilter::new(120);
+        let cmd = [1u8; 41];
+        let open = |uuid| {
+            let sealed = seal_header(&uuid, &cmd);
+            let (users, filter) = (&users, &filter);
+            async move { open_vmess_session(&mut &sealed[..], users, filter).await }
+        };
+        let e = open(alice).await.unwrap_err();
+        let unknown = open(Uuid::from_u128(4)).await.unwrap_err();
+        assert_eq!(e.to_string(), unknown.to_string());
+        assert_eq!(open(bob).await.unwrap().0, bob);
+    }
+
+    #[tokio::test]
+    async fn test_seeded_seal() {
+        use rand_chacha::rand_core::{RngCore, SeedableRng};
+        use rand_chacha::ChaCha20Rng;
+
+        let key = users::cmd_key(&Uuid::parse_str(UUID).unwrap());
+        let clock = MockClock::new(1_700_000_000);
+        let cmd = [1u8; 41];
+        let seal = |seed| {
+            let mut rng = ChaCha20Rng::seed_from_u64(seed);
+            let random = &mut |x: &mut [u8]| rng.fill_bytes(x);
+            seal_vmess_header_with(&key, &V2RAY, &cmd, &clock, random).unwrap()
+        };
+
+        // the same seed seals the same auth id and nonce
+        let (a, b) = (seal(0x5eed), seal(0x5eed));
+        assert_eq!(a[..16], b[..16]);
+        assert_eq!(a, b);
+        assert_ne!(a[..16], seal(0x5eee)[..16]);
+
+        let users = UserTable::new(Uuid::parse_str(UUID).unwrap());
+        let filter = ReplayFilter::new(120).with_clock(Rc::new(clock));
+        let header = open_vmess_header_with(&mut &a[..], &users, &filter).await.unwrap();
+        assert_eq!(header, cmd);
+    }
+}
@@ -0,0 +1,994 @@
+use super::auth::AuthKey;
+use super::labels::{Labels, V2RAY};
+use crate::app::stats::{self, Direction, QuotaGuard, Stats};
+use crate::common::task;
+use crate::common::time::{self, Clock, SystemClock};
+use crate::server::ShutdownSignal;
+
+use async_trait::async_trait;
+use md5::{Digest, Md5};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+use std::time::Duration;
+use uuid::Uuid;
+use worker::*;
+
+// how long a rotated away uuid still opens new sessions, time for clients
+// to pick up the new one
+pub const ROTATION_GRACE: u64 = 3600;
+
+// how often quotas are checked and usage is saved. kv takes one write per
+// key and second, and keeps what it is given eventually consistent anyway.
+pub const ACCOUNTING_INTERVAL: Duration = Duration::from_secs(60);
+
+// the kv entries with the bytes users have used, a json object by email
+// each. every isolate saves its own under this and a random suffix, the
+// bare key holds what isolates that are gone had saved.
+const USAGE_KEY: &str = "vmess_usage";
+
+// an isolate's entry outlives its last save by this much, so that one that
+// is gone does not leave it behind for good
+const USAGE_TTL: u64 = 5 * ACCOUNTING_INTERVAL.as_secs();
+
+// an entry not saved for this long is of an isolate that is gone, and is
+// merged into the bare key before it expires
+const USAGE_STALE: u64 = 3 * ACCOUNTING_INTERVAL.as_secs();
+
+// the key auth ids are sealed with
+pub fn cmd_key(uuid: &Uuid) -> [u8; 16] {
+    crate::md5!(&uuid.as_bytes(), b"c48619fe-8f02-49e0-b9e9-edf763e17e21").into()
+}
+
+// a user added at runtime, next to the one of the `UUID` binding
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct User {
+    pub uuid: Uuid,
+    pub email: String,
+    pub level: u32,
+    // unix time from which on handshakes are refused
+    pub expires_at: Option<u64>,
+    // bytes in both directions, across isolates
+    pub quota_bytes: Option<u64>,
+}
+
+struct Entry {
+    user: User,
+    auth: AuthKey,
+    // triggered when the user is removed with `kick` or runs out
+    kicked: ShutdownSignal,
+    // used in other isolates, as they last saved it
+    remote: u64,
+    // used in this isolate before the counters were last read
+    carried: u64,
+    // what the counters were at when last read
+    seen: u64,
+}
+
+// where usage is kept between isolates. an isolate saves only what it
+// counted itself, so isolates running at the same time add up instead of
+// overwriting each other.
+#[async_trait(?Send)]
+pub trait UsageStore {
+    // what every other isolate saved, summed by email
+    async fn load(&self) -> Result<HashMap<String, u64>>;
+    // this isolate's own usage, in place of what it saved before
+    async fn save(&self, usage: &HashMap<String, u64>) -> Result<()>;
+}
+
+// adds `usage` into `total`, by email
+pub fn add_usage(total: &mut HashMap<String, u64>, usage: HashMap<String, u64>) {
+    for (email, bytes) in usage {
+        *total.entry(email).or_default() += bytes;
+    }
+}
+
+// the parts of kv that usage is kept with
+#[async_trait(?Send)]
+pub trait UsageKv {
+    // the keys under `prefix` and the unix time each expires at
+    async fn list(&self, prefix: &str) -> Result<Vec<(String, Option<u64>)>>;
+    async fn get(&self, key: &str) -> Result<Option<HashMap<String, u64>>>;
+    // `ttl` in seconds, none keeps it for good
+    async fn put(&self, key: &str, usage: &HashMap<String, u64>, ttl: Option<u64>) -> Result<()>;
+    async fn delete(&self, key: &str) -> Result<()>;
+}
+
+#[async_trait(?Send)]
+impl UsageKv for kv::KvStore {
+    async fn list(&self, prefix: &str) -> Result<Vec<(String, Option<u64>)>> {
+        let mut keys = Vec::new();
+        let mut cursor = None;
+        loop {
+            let mut list = kv::KvStore::list(self).prefix(prefix.to_string());
+            if let Some(cursor) = cursor.take() {
+                list = list.cursor(cursor);
+            }
+            let page = list.execute().await?;
+            keys.extend(page.keys.into_iter().map(|x| (x.name, x.expiration)));
+            match page.cursor {
+                Some(x) if !page.list_complete => cursor = Some(x),
+                _ => return Ok(keys),
+            }
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<HashMap<String, u64>>> {
+        Ok(kv::KvStore::get(self, key).json().await?)
+    }
+
+    async fn put(&self, key: &str, usage: &HashMap<String, u64>, ttl: Option<u64>) -> Result<()> {
+        let mut put = kv::KvStore::put(self, key, usage)?;
+        if let Some(ttl) = ttl {
+            put = put.expiration_ttl(ttl);
+        }
+        put.execute().await?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        Ok(kv::KvStore::delete(self, key).await?)
+    }
+}
+
+// one kv entry per live isolate, expiring unless it is saved again. loading
+// reads every entry but its own and merges those of isolates that are gone
+// into the bare key, so the entries stay about as many as there are
+// isolates. kv has no transactions: two isolates merging at once can count
+// a gone one twice, which errs towards cutting a user off early, and one
+// that is gone while no isolate runs for `USAGE_TTL` is lost.
+pub struct KvUsageStore<K = kv::KvStore> {
+    kv: K,
+    key: String,
+    clock: Rc<dyn Clock>,
+}
+
+impl<K: UsageKv> KvUsageStore<K> {
+    pub fn new(kv: K) -> Self {
+        let mut id = [0u8; 8];
+        crate::common::random(&mut id);
+        Self {
+            kv,
+            key: format!("{USAGE_KEY}:{:016x}", u64::from_be_bytes(id)),
+            clock: Rc::new(SystemClock),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Rc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // adds the entries the isolates that are gone saved to the bare key,
+    // then drops them
+    async fn merge(
+        &self,
+        mut merged: HashMap<String, u64>,
+        gone: Vec<(String, HashMap<String, u64>)>,
+    ) {
+        let names: Vec<_> = gone.iter().map(|x| x.0.clone()).collect();
+        for (_, usage) in gone {
+            add_usage(&mut merged, usage);
+        }
+        if let Err(e) = self.kv.put(USAGE_KEY, &merged, None).await {
+            crate::log_error!("[vmess]: merging usage: {}", e);
+            return;
+        }
+        for name in names {
+            if let Err(e) = self.kv.delete(&name).await {
+                crate::log_error!("[vmess]: dropping {}: {}", name, e);
+            }
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<K: UsageKv> UsageStore for KvUsageStore<K> {
+    // an entry that cannot be read is left out rather than failing the rest
+    async fn load(&self) -> Result<HashMap<String, u64>> {
+        let stale_before = self.clock.now() + USAGE_TTL - USAGE_STALE;
+        let mut usage = HashMap::new();
+        // none while the bare key could not be read, it is not written then
+        let mut merged = Some(HashMap::new());
+        let mut gone = Vec::new();
+        for (name, expiration) in self.kv.list(USAGE_KEY).await? {
+            if name == self.key {
+                continue;
+            }
+            let saved = match self.kv.get(&name).await {
+                Ok(saved) => saved.unwrap_or_default(),
+                Err(e) => {
+                    crate::log_error!("[vmess]: reading {}: {}", name, e);
+                    if name == USAGE_KEY {
+                        merged = None;
+                    }
+                    continue;
+                }
+            };
+            add_usage(&mut usage, saved.clone());
+            if name == USAGE_KEY {
+                merged = Some(saved);
+            } else if expiration.is_some_and(|x| x < stale_before) {
+                gone.push((name, saved));
+            }
+        }
+        match merged {
+            Some(merged) if !gone.is_empty() => self.merge(merged, gone).await,
+            _ => {}
+        }
+        Ok(usage)
+    }
+
+    async fn save(&self, usage: &HashMap<String, u64>) -> Result<()> {
+        self.kv.put(&self.key, usage, Some(USAGE_TTL)).await
+    }
+}
+
+// the uuids new handshakes are accepted with. sessions keep the keys they
+// were opened with, so rotating only ever affects handshakes.
+pub struct UserTable {
+    clock: Rc<dyn Clock>,
+    grace: u64,
+    // the user traffic counters, by email
+    stats: Rc<Stats>,
+    current: Cell<(Uuid, [u8; 16])>,
+    // the uuid rotated away from and when it stops being accepted
+    previous: Cell<Option<(Uuid, [u8; 16], u64)>>,
+    users: RefCell<Vec<Entry>>,
+    // the label versions handshakes are accepted under, in the order they
+    // are tried
+    labels: Rc<[Labels]>,
+    // what `auth_keys` returns until the uuids change
+    auth_keys: RefCell<Option<Rc<[AuthKey]>>>,
+    // other isolates' usage of users that were not added yet
+    restored: RefCell<HashMap<String, u64>>,
+}
+
+impl UserTable {
+    pub fn new(uuid: Uuid) -> Self {
+        Self {
+            clock: Rc::new(SystemClock),
+            grace: ROTATION_GRACE,
+            stats: stats::shared(),
+            current: Cell::new((uuid, cmd_key(&uuid))),
+            previous: Cell::new(None),
+            users: RefCell::default(),
+            labels: Rc::new([V2RAY]),
+            auth_keys: RefCell::default(),
+            restored: RefCell::default(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Rc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_grace(mut self, grace: u64) -> Self {
+        self.grace = grace;
+        self
+    }
+
+    pub fn with_stats(mut self, stats: Rc<Stats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    // v2ray's only by default. none leaves them as they were.
+    pub fn with_labels(mut self, labels: Vec<Labels>) -> Self {
+        if labels.is_empty() {
+            return self;
+        }
+        self.labels = labels.into();
+        for entry in self.users.get_mut() {
+            let uuid = entry.user.uuid;
+            entry.auth = AuthKey::with_labels(uuid, cmd_key(&uuid), &self.labels);
+        }
+        self.auth_keys.take();
+        self
+    }
+
+    pub fn labels(&self) -> Rc<[Labels]> {
+        self.labels.clone()
+    }
+
+    pub fn uuid(&self) -> Uuid {
+        self.current.get().0
+    }
+
+    // new handshakes use `new` from now on, `old` keeps working for the
+    // grace period. a rotation before an earlier one's grace is up ends it.
+    pub fn rotate_uuid(&self, old: Uuid, new: Uuid) -> Result<()> {
+        let (current, key) = self.current.get();
+        if current != old {
+            return Err(Error::RustError(format!("{old} is not the current uuid")));
+        }
+        if new == old {
+            return Ok(());
+        }
+        let until = self.clock.now() + self.grace;
+        self.previous.set(Some((old, key, until)));
+        self.current.set((new, cmd_key(&new)));
+        self.auth_keys.take();
+        Ok(())
+    }
+
+    // handshakes already reading their header keep the keys they started
+    // with, only the next ones see the user
+    pub fn add_user(&self, uuid: Uuid, email: &str, level: u32) -> Result<()> {
+        let mut users = self.users.borrow_mut();
+        if users.iter().any(|x| x.user.email == email) {
+            return Err(Error::RustError(format!("user {email:?} already exists")));
+        }
+        let taken = users.iter().map(|x| x.user.uuid).chain([self.current.get().0]);
+        if taken.chain(self.previous.get().map(|x| x.0)).any(|x| x == uuid) {
+            return Err(Error::RustError(format!("{uuid} is already a user")));
+        }
+        let user = User {
+            uuid,
+            email: email.to_string(),
+            level,
+            expires_at: None,
+            quota_bytes: None,
+        };
+        users.push(Entry {
+            user,
+            auth: AuthKey::with_labels(uuid, cmd_key(&uuid), &self.labels),
+            kicked: ShutdownSignal::new(),
+            remote: self.restored.borrow_mut().remove(email).unwrap_or_default(),
+            carried: 0,
+            seen: self.counted(email),
+        });
+        self.auth_keys.take();
+        Ok(())
+    }
+
+    // None lifts a limit. a user let back in gets a new kick signal, the
+    // sessions cut off are gone for good.
+    pub fn set_limits(
+        &self,
+        email: &str,
+        expires_at: Option<u64>,
+        quota_bytes: Option<u64>,
+    ) -> Result<()> {
+        let mut users = self.users.borrow_mut();
+        let Some(entry) = users.iter_mut().find(|x| x.user.email == email) else {
+            return Err(Error::RustError(format!("unknown user {email:?}")));
+        };
+        entry.user.expires_at = expires_at;
+        entry.user.quota_bytes = quota_bytes;
+        if entry.kicked.is_triggered() && self.is_active(entry) {
+            entry.kicked = ShutdownSignal::new();
+        }
+        Ok(())
+    }
+
+    // the user's next handshake fails. sessions already open are closed
+    // too with `kick`, and otherwise left running.
+    pub fn remove_user(&self, email: &str, kick: bool) -> Result<User> {
+        let mut users = self.users.borrow_mut();
+        let Some(i) = users.iter().position(|x| x.user.email == email) else {
+            return Err(Error::RustError(format!("unknown user {email:?}")));
+        };
+        let entry = users.remove(i);
+        self.auth_keys.take();
+        if kick {
+            entry.kicked.trigger();
+        }
+        Ok(entry.user)
+    }
+
+    pub fn list_users(&self) -> Vec<User> {
+        self.users.borrow().iter().map(|x| x.user.clone()).collect()
+    }
+
+    // the added user `uuid`, None for the uuid of the binding
+    pub fn user(&self, uuid: &Uuid) -> Option<User> {
+        let users = self.users.borrow();
+        users.iter().find(|x| x.user.uuid == *uuid).map(|x| x.user.clone())
+    }
+
+    // fires once the added user `uuid` is kicked, None for the uuid of the
+    // binding and anyone not added
+    pub fn kicked(&self, uuid: &Uuid) -> Option<ShutdownSignal> {
+        let users = self.users.borrow();
+        let entry = users.iter().find(|x| x.user.uuid == *uuid)?;
+        Some(entry.kicked.clone())
+    }
+
+    // for inbounds that know users by email: an error for an added user
+    // past their limits, else their kick signal. None for anyone not added.
+    pub fn session(&self, email: &str) -> Result<Option<ShutdownSignal>> {
+        let users = self.users.borrow();
+        let Some(entry) = users.iter().find(|x| x.user.email == email) else {
+            return Ok(None);
+        };
+        if !self.is_active(entry) {
+            return Err(Error::RustError(format!("{email} is past their limits")));
+        }
+        Ok(Some(entry.kicked.clone()))
+    }
+
+    // bytes the user has relayed, in this isolate and the ones before
+    pub fn usage(&self, email: &str) -> Option<u64> {
+        let users = self.users.borrow();
+        users.iter().find(|x| x.user.email == email).map(|x| self.used(x))
+    }
+
+    // what is left of the user's quota, as a cap the session's stream can
+    // enforce between accounting checks. None for a user without one.
+    pub fn quota(&self, email: &str) -> Option<QuotaGuard> {
+        let users = self.users.borrow();
+        let entry = users.iter().find(|x| x.user.email == email)?;
+        let quota = entry.user.quota_bytes?;
+        let counters = [Direction::Uplink, Direction::Downlink]
+            .map(|x| self.stats.counter(&stats::user_traffic(email, x)));
+        Some(QuotaGuard::new(counters.to_vec(), quota.saturating_sub(self.used(entry))))
+    }
+
+    fn counted(&self, email: &str) -> u64 {
+        [Direction::Uplink, Direction::Downlink]
+            .into_iter()
+            .filter_map(|x| self.stats.get(&stats::user_traffic(email, x)))
+            .sum()
+    }
+
+    // billing may take the counters with a reset, a count below the one
+    // last seen starts over from zero
+    fn since_seen(&self, entry: &Entry) -> u64 {
+        let counted = self.counted(&entry.user.email);
+        counted.checked_sub(entry.seen).unwrap_or(counted)
+    }
+
+    // in this isolate only
+    fn used_here(&self, entry: &Entry) -> u64 {
+        entry.carried + self.since_seen(entry)
+    }
+
+    fn used(&self, entry: &Entry) -> u64 {
+        entry.remote + self.used_here(entry)
+    }
+
+    // neither expired nor over quota
+    fn is_active(&self, entry: &Entry) -> bool {
+        let expired = entry.user.expires_at.is_some_and(|x| self.clock.now() >= x);
+        let exhausted = entry.user.quota_bytes.is_some_and(|x| self.used(entry) >= x);
+        !expired && !exhausted
+    }
+
+    // the current uuid first, then the previous one while it is accepted,
+    // then the added users that are active. users past their limits are
+    // left out, their handshakes fail like those of unknown users.
+    pub fn cmd_keys(&self) -> Vec<(Uuid, [u8; 16])> {
+        let mut keys = vec![self.current.get()];
+        match self.previous.get() {
+            Some((uuid, key, until)) if self.clock.now() < until => keys.push((uuid, key)),
+            Some(_) => self.previous.set(None),
+            None => {}
+        }
+        let users = self.users.borrow();
+        let active = users.iter().filter(|x| self.is_active(x));
+        keys.extend(active.map(|x| (x.user.uuid, x.auth.cmd_key)));
+        keys
+    }
+
+    // every uuid `cmd_keys` may return in the same order, limits or not.
+    // it is only rebuilt after uuids were added, removed or rotated, so a
+    // handshake takes it in constant time however many users there are.
+    pub fn auth_keys(&self) -> Rc<[AuthKey]> {
+        if let Some((_, _, until)) = self.previous.get() {
+            if self.clock.now() >= until {
+                self.previous.set(None);
+                self.auth_keys.take();
+            }
+        }
+        let mut cached = self.auth_keys.borrow_mut();
+        let keys = cached.get_or_insert_with(|| {
+            let (uuid, key) = self.current.get();
+            let key_of = |(uuid, key)| AuthKey::with_labels(uuid, key, &self.labels);
+            let previous = self.previous.get().map(|(uuid, key, _)| key_of((uuid, key)));
+            let users = self.users.borrow();
+            let added = users.iter().map(|x| x.auth.clone());
+            [key_of((uuid, key))].into_iter().chain(previous).chain(added).collect()
+        });
+        keys.clone()
+    }
+
+    // whether the `i`th of `keys`, taken from `auth_keys`, opens a session:
+    // false for an added user past their limits. a uuid removed since
+    // `keys` were taken is still accepted, like `cmd_keys` would have.
+    pub fn accepts(&self, keys: &Rc<[AuthKey]>, i: usize) -> bool {
+        let users = self.users.borrow();
+        let current = self.auth_keys.borrow();
+        let entry = match current.as_ref() {
+            // the users did not change, their order is that of `keys`
+            Some(x) if Rc::ptr_eq(x, keys) => {
+                let head = keys.len() - users.len();
+                i.checked_sub(head).and_then(|i| users.get(i))
+            }
+            _ => users.iter().find(|x| x.user.uuid == keys[i].uuid),
+        };
+        entry.is_none_or(|x| self.is_active(x))
+    }
+
+    // adds up what the counters saw since the last call, then kicks the
+    // sessions of users that ran past a limit and returns them
+    pub fn enforce_limits(&self) -> Vec<String> {
+        let mut users = self.users.borrow_mut();
+        for entry in users.iter_mut() {
+            entry.carried += self.since_seen(entry);
+            entry.seen = self.counted(&entry.user.email);
+        }
+        let exceeded = users.iter().filter(|x| !x.kicked.is_triggered() && !self.is_active(x));
+        exceeded
+            .map(|x| {
+                x.kicked.trigger();
+                x.user.email.clone()
+            })
+            .collect()
+    }
+
+    // what `UsageStore::save` keeps, the usage of every user in this
+    // isolate by email
+    pub fn snapshot_usage(&self) -> HashMap<String, u64> {
+        let users = self.users.borrow();
+        users.iter().map(|x| (x.user.email.clone(), self.used_here(x))).collect()
+    }
+
+    // what `UsageStore::load` returns, for users added before and after.
+    // kv reads may lag, a user's usage never goes back down with them.
+    pub fn restore_usage(&self, mut usage: HashMap<String, u64>) {
+        for entry in self.users.borrow_mut().iter_mut() {
+            if let Some(x) = usage.remove(&entry.user.email) {
+                entry.remote = entry.remote.max(x);
+            }
+        }
+        let mut restored = self.restored.borrow_mut();
+        for (email, bytes) in usage {
+            let x = restored.entry(email).or_default();
+            *x = (*x).max(bytes);
+        }
+    }
+}
+
+// loads the other isolates' usage from `store`, then once per interval
+// loads it again, checks the limits and saves this isolate's usage, for as
+// long as the table is in use. what other isolates relayed since they last
+// saved is not seen until they save again.
+pub fn spawn_accounting(users: Weak<UserTable>, store: Rc<dyn UsageStore>, interval: Duration) {
+    task::spawn(async move {
+        if !load_usage(&users, store.as_ref()).await {
+            return;
+        }
+        loop {
+            time::sleep(interval).await;
+            if !load_usage(&users, store.as_ref()).await {
+                return;
+            }
+            let Some(users) = users.upgrade() else {
+                return;
+            };
+            for email in users.enforce_limits() {
+                crate::log!("[vmess]: {} is past its limits", email);
+            }
+            let usage = users.snapshot_usage();
+            drop(users);
+            if let Err(e) = store.save(&usage).await {
+                crate::log_error!("[vmess]: saving usage: {}", e);
+            }
+        }
+    });
+}
+
+// false once the table is gone
+async fn load_usage(users: &Weak<UserTable>, store: &dyn UsageStore) -> bool {
+    let usage = store.load().await;
+    let Some(users) = users.upgrade() else {
+        return false;
+    };
+    match usage {
+        Ok(usage) => users.restore_usage(usage),
+        Err(e) => crate::log_error!("[vmess]: loading usage: {}", e),
+    }
+    true
+}
+
+thread_local! {
+    // the table and the `UUID` binding it last followed
+    static SHARED: RefCell<Option<(Uuid, Rc<UserTable>)>> = const { RefCell::new(None) };
+}
+
+// one per isolate so a rotation holds for every request it serves. the
+// `UUID` binding seeds it on first use, and a request that sees the binding
+// changed rotates to it, so the old uuid keeps its grace period. users added
+// at runtime live only in this table, `store` keeps their usage.
+pub fn shared_with_store<F>(uuid: &Uuid, store: F) -> Rc<UserTable>
+where
+    F: FnOnce() -> Option<Rc<dyn UsageStore>>,
+{
+    SHARED.with(|x| {
+        let mut shared = x.borrow_mut();
+        let (binding, users) = shared.get_or_insert_with(|| {
+            let users = Rc::new(UserTable::new(*uuid));
+            if let Some(store) = store() {
+                spawn_accounting(Rc::downgrade(&users), store, ACCOUNTING_INTERVAL);
+            }
+            (*uuid, users)
+        });
+        if binding != uuid {
+            // rotating from the table's own uuid never fails
+            let _ = users.rotate_uuid(users.uuid(), *uuid);
+            *binding = *uuid;
+        }
+        users.clone()
+    })
+}
+
+pub fn shared(uuid: &Uuid) -> Rc<UserTable> {
+    shared_with_store(uuid, || None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::time::MockClock;
+    use crate::proxy::vmess::auth::{create_auth_id, ReplayFilter};
+
+    #[test]
+    fn test_rotate_uuid() {
+        let old = Uuid::from_u128(1);
+        let new = Uuid::from_u128(2);
+        let clock = Rc::new(MockClock::new(1_000));
+        let users = UserTable::new(old).with_clock(clock.clone()).with_grace(60);
+
+        let e = users.rotate_uuid(new, old).unwrap_err();
+        assert_eq!(e.to_string(), format!("{new} is not the current uuid"));
+
+        users.rotate_uuid(old, new).unwrap();
+        assert_eq!(users.uuid(), new);
+        let uuids = |users: &UserTable| users.cmd_keys().iter().map(|x| x.0).collect::<Vec<_>>();
+        assert_eq!(uuids(&users), [new, old]);
+        assert_eq!(users.cmd_keys()[1].1, cmd_key(&old));
+
+        clock.advance(60);
+        assert_eq!(uuids(&users), [new]);
+    }
+
+    #[test]
+    fn test_manage_users() {
+        let binding = Uuid::from_u128(1);
+        let alice = Uuid::from_u128(2);
+        let users = UserTable::new(binding);
+
+        users.add_user(alice, "alice@example.com", 1).unwrap();
+        let e = users.add_user(Uuid::from_u128(3), "alice@example.com", 0).unwrap_err();
+        assert_eq!(e.to_string(), r#"user "alice@example.com" already exists"#);
+        let e = users.add_user(binding, "bob@example.com", 0).unwrap_err();
+        assert_eq!(e.to_string(), format!("{binding} is already a user"));
+        users.add_user(Uuid::from_u128(4), "bob@example.com", 0).unwrap();
+
+        let emails: Vec<_> = users.list_users().into_iter().map(|x| x.email).collect();
+        assert_eq!(emails, ["alice@example.com", "bob@example.com"]);
+        assert_eq!(users.cmd_keys()[1], (alice, cmd_key(&alice)));
+        assert!(users.kicked(&binding).is_none());
+
+        // only a kick closes what is already open
+        let kicked = users.kicked(&alice).unwrap();
+        let removed = users.remove_user("alice@example.com", false).unwrap();
+        assert_eq!(removed.level, 1);
+        assert!(!kicked.is_triggered());
+        assert!(users.cmd_keys().iter().all(|x| x.0 != alice));
+        let kicked = users.kicked(&Uuid::from_u128(4)).unwrap();
+        users.remove_user("bob@example.com", true).unwrap();
+        assert!(kicked.is_triggered());
+
+        let e = users.remove_user("bob@example.com", false).unwrap_err();
+        assert_eq!(e.to_string(), r#"unknown user "bob@example.com""#);
+        assert_eq!(users.cmd_keys().len(), 1);
+    }
+
+    #[test]
+    fn test_shared_follows_binding() {
+        let (old, new) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let users = shared(&old);
+        assert!(Rc::ptr_eq(&users, &shared(&old)));
+
+        // the next request after the binding changed rotates to it
+        assert!(Rc::ptr_eq(&users, &shared(&new)));
+        assert_eq!(users.uuid(), new);
+        let keys: Vec<_> = users.cmd_keys().iter().map(|x| x.0).collect();
+        assert_eq!(keys, [new, old]);
+
+        // a rotation through the api holds until the binding changes again
+        let api = Uuid::from_u128(3);
+        users.rotate_uuid(new, api).unwrap();
+        assert_eq!(shared(&new).uuid(), api);
+    }
+
+    // what the isolates of a test saved, by isolate
+    #[derive(Default)]
+    struct MemoryKv(RefCell<HashMap<&'static str, HashMap<String, u64>>>);
+
+    // the kv as `KvUsageStore` uses it, the isolate being `name`
+    struct MemoryStore {
+        kv: Rc<MemoryKv>,
+        name: &'static str,
+    }
+
+    impl MemoryStore {
+        fn new(kv: &Rc<MemoryKv>, name: &'static str) -> Rc<Self> {
+            let kv = kv.clone();
+            Rc::new(Self { kv, name })
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl UsageStore for MemoryStore {
+        async fn load(&self) -> Result<HashMap<String, u64>> {
+            let mut usage = HashMap::new();
+            for (name, saved) in self.kv.0.borrow().iter() {
+                if *name != self.name {
+                    add_usage(&mut usage, saved.clone());
+                }
+            }
+            Ok(usage)
+        }
+
+        async fn save(&self, usage: &HashMap<String, u64>) -> Result<()> {
+            self.kv.0.borrow_mut().insert(self.name, usage.clone());
+            Ok(())
+        }
+    }
+
+    // kv as `KvUsageStore` sees it, the json and expiry of each key
+    struct RawKv {
+        clock: Rc<MockClock>,
+        entries: RefCell<HashMap<String, (String, Option<u64>)>>,
+    }
+
+    #[async_trait(?Send)]
+    impl UsageKv for RawKv {
+        async fn list(&self, prefix: &str) -> Result<Vec<(String, Option<u64>)>> {
+            let entries = self.entries.borrow();
+            let keys = entries.iter().filter(|x| x.0.starts_with(prefix));
+            Ok(keys.map(|(name, x)| (name.clone(), x.1)).collect())
+        }
+
+        async fn get(&self, key: &str) -> Result<Option<HashMap<String, u64>>> {
+            let Some((json, _)) = self.entries.borrow().get(key).cloned() else {
+                return Ok(None);
+            };
+            serde_json::from_str(&json).map_err(|e| Error::RustError(e.to_string()))
+        }
+
+        async fn put(
+            &self,
+            key: &str,
+            usage: &HashMap<String, u64>,
+            ttl: Option<u64>,
+        ) -> Result<()> {
+            let entry = (serde_json::to_string(usage)?, ttl.map(|x| self.clock.now() + x));
+            self.entries.borrow_mut().insert(key.to_string(), entry);
+            Ok(())
+        }
+
+        async fn delete(&self, key: &str) -> Result<()> {
+            self.entries.borrow_mut().remove(key);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_kv_usage_store() {
+        let clock = Rc::new(MockClock::new(1_000));
+        let live = Some(1_000 + USAGE_TTL);
+        let entries = [
+            (USAGE_KEY, r#"{"alice@example.com": 100}"#, None),
+            ("vmess_usage:live", r#"{"alice@example.com": 10}"#, live),
+            ("vmess_usage:broken", "{", live),
+            (
+                "vmess_usage:gone",
+                r#"{"alice@example.com": 1, "bob@example.com": 5}"#,
+                Some(1_000 + USAGE_TTL - USAGE_STALE - 1),
+            ),
+        ];
+        let entries = entries.map(|(key, json, x)| (key.to_string(), (json.to_string(), x)));
+        let kv = RawKv {
+            clock: clock.clone(),
+            entries: RefCell::new(entries.into()),
+        };
+        let store = KvUsageStore::new(kv).with_clock(clock);
+
+        // the unreadable entry is left out, the rest still count
+        let expected = HashMap::from([
+            ("alice@example.com".to_string(), 111),
+            ("bob@example.com".to_string(), 5),
+        ]);
+        assert_eq!(store.load().await.unwrap(), expected);
+
+        // the gone isolate's usage moved into the bare key
+        let gone = store.kv.get(USAGE_KEY).await.unwrap().unwrap();
+        assert_eq!(gone["alice@example.com"], 101);
+        assert_eq!(gone["bob@example.com"], 5);
+        let mut keys: Vec<_> = store.kv.entries.borrow().keys().cloned().collect();
+        keys.sort();
+        assert_eq!(keys, [USAGE_KEY, "vmess_usage:broken", "vmess_usage:live"]);
+        assert_eq!(store.load().await.unwrap(), expected);
+
+        // its own entry expires unless saved again, and is not loaded
+        let usage = HashMap::from([("alice@example.com".to_string(), 7)]);
+        store.save(&usage).await.unwrap();
+        assert_eq!(store.kv.entries.borrow()[&store.key].1, live);
+        assert_eq!(store.load().await.unwrap(), expected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_quota_cut_off() {
+        let stats = Rc::new(Stats::default());
+        let users = Rc::new(UserTable::new(Uuid::from_u128(1)).with_stats(stats.clone()));
+        let (alice, bob) = (Uuid::from_u128(2), Uuid::from_u128(3));
+        users.add_user(alice, "alice@example.com", 0).unwrap();
+        users.add_user(bob, "bob@example.com", 0).unwrap();
+        for email in ["alice@example.com", "bob@example.com"] {
+            users.set_limits(email, None, Some(1_000_000)).unwrap();
+        }
+        let kv = Rc::new(MemoryKv::default());
+        let earlier = HashMap::from([("bob@example.com".to_string(), 999_000)]);
+        kv.0.borrow_mut().insert("earlier", earlier);
+        let store = MemoryStore::new(&kv, "here");
+
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let interval = Duration::from_secs(10);
+                spawn_accounting(Rc::downgrade(&users), store, interval);
+                tokio::task::yield_now().await;
+                let kicked = users.kicked(&alice).unwrap();
+                let up = stats::user_traffic("alice@example.com", Direction::Uplink);
+                let up = stats.counter(&up);
+                up.add(600_000);
+                assert!(users.cmd_keys().iter().any(|x| x.0 == alice));
+                let down = stats::user_traffic("alice@example.com", Direction::Downlink);
+                stats.counter(&down).add(400_001);
+
+                // no more handshakes right away, the open sessions go with
+                // the next check
+                assert!(users.cmd_keys().iter().all(|x| x.0 != alice));
+                assert!(!kicked.is_triggered());
+                time::sleep(interval).await;
+                tokio::task::yield_now().await;
+                assert!(kicked.is_triggered());
+
+                // bob's usage came from the store, and only what was used
+                // here is saved for here
+                assert_eq!(users.usage("bob@example.com"), Some(999_000));
+                assert!(users.cmd_keys().iter().any(|x| x.0 == bob));
+                assert_eq!(kv.0.borrow()["here"]["alice@example.com"], 1_000_001);
+                assert_eq!(kv.0.borrow()["here"]["bob@example.com"], 0);
+
+                // billing taking the counters leaves the usage as it was
+                stats.query("user>>>alice@example.com>>>", true);
+                up.add(10);
+                assert_eq!(users.usage("alice@example.com"), Some(1_000_011));
+
+                users.set_limits("alice@example.com", None, Some(2_000_000)).unwrap();
+                assert!(!users.kicked(&alice).unwrap().is_triggered());
+                assert!(users.cmd_keys().iter().any(|x| x.0 == alice));
+            })
+            .await;
+    }
+
+    // two isolates at once, each counting part of alice's traffic
+    #[tokio::test(start_paused = true)]
+    async fn test_isolates_add_up() {
+        let kv = Rc::new(MemoryKv::default());
+        let isolates = ["a", "b"].map(|name| {
+            let stats = Rc::new(Stats::default());
+            let users = Rc::new(UserTable::new(Uuid::from_u128(1)).with_stats(stats.clone()));
+            users.add_user(Uuid::from_u128(2), "alice@example.com", 0).unwrap();
+            users.set_limits("alice@example.com", None, Some(1_000)).unwrap();
+            (name, stats, users)
+        });
+
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let interval = Duration::from_secs(10);
+                for (name, stats, users) in &isolates {
+                    let store = MemoryStore::new(&kv, name);
+                    spawn_accounting(Rc::downgrade(users), store, interval);
+                    let up = stats::user_traffic("alice@example.com", Direction::Uplink);
+                    stats.counter(&up).add(600);
+                }
+                tokio::task::yield_now().await;
+                let kicked = isolates.each_ref().map(|x| x.2.kicked(&Uuid::from_u128(2)).unwrap());
+
+                // each saves its own 600 and neither overwrites the other.
+                // one sees the other's once it is saved, by the next check
+                // at the latest
+                time::sleep(interval).await;
+                tokio::task::yield_now().await;
+                assert_eq!(kv.0.borrow()["a"]["alice@example.com"], 600);
+                assert_eq!(kv.0.borrow()["b"]["alice@example.com"], 600);
+                time::sleep(interval).await;
+                tokio::task::yield_now().await;
+                for (_, _, users) in &isolates {
+                    assert_eq!(users.usage("alice@example.com"), Some(1_200));
+                }
+                assert!(kicked.iter().all(|x| x.is_triggered()));
+            })
+            .await;
+    }
+
+    #[test]
+    fn test_quota_guard() {
+        let stats = Rc::new(Stats::default());
+        let users = UserTable::new(Uuid::from_u128(1)).with_stats(stats.clone());
+        users.add_user(Uuid::from_u128(2), "alice@example.com", 0).unwrap();
+        assert!(users.quota("alice@example.com").is_none());
+        assert!(users.quota("bob@example.com").is_none());
+
+        // usage from before counts against what is left
+        users.restore_usage(HashMap::from([("alice@example.com".to_string(), 900)]));
+        users.set_limits("alice@example.com", None, Some(1_000)).unwrap();
+        let guard = users.quota("alice@example.com").unwrap();
+        let down = stats.counter(&stats::user_traffic("alice@example.com", Direction::Downlink));
+        down.add(99);
+        assert!(!guard.exceeded());
+        down.add(1);
+        assert!(guard.exceeded());
+        assert!(users.quota("alice@example.com").unwrap().exceeded());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let clock = Rc::new(MockClock::new(1_000));
+        let users = UserTable::new(Uuid::from_u128(1)).with_clock(clock.clone());
+        users.add_user(Uuid::from_u128(2), "alice@example.com", 0).unwrap();
+        users.add_user(Uuid::from_u128(3), "bob@example.com", 0).unwrap();
+        users.set_limits("alice@example.com", Some(1_060), None).unwrap();
+        assert_eq!(users.cmd_keys().len(), 3);
+
+        clock.advance(60);
+        let uuids: Vec<_> = users.cmd_keys().iter().map(|x| x.0).collect();
+        assert_eq!(uuids, [Uuid::from_u128(1), Uuid::from_u128(3)]);
+        assert_eq!(users.enforce_limits(), ["alice@example.com"]);
+        assert!(users.enforce_limits().is_empty());
+
+        // by email, the way shadowsocks users are looked up
+        assert!(users.session("alice@example.com").is_err());
+        assert!(users.session("bob@example.com").unwrap().is_some());
+        assert!(users.session("carol@example.com").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_auth_keys() {
+        let clock = Rc::new(MockClock::new(1_000));
+        let users = UserTable::new(Uuid::from_u128(1)).with_clock(clock.clone());
+        let uuid = |i: u128| Uuid::from_u128(i + 2);
+        for i in 0..1_000 {
+            users.add_user(uuid(i), &format!("{i}@example.com"), 0).unwrap();
+        }
+        let filter = ReplayFilter::new(120).with_clock(clock.clone());
+        let id = |i| create_auth_id(&cmd_key(&uuid(i)), clock.as_ref());
+
+        // taken again without rebuilding while nothing changes
+        let keys = users.auth_keys();
+        assert!(Rc::ptr_eq(&keys, &users.auth_keys()));
+        assert_eq!(keys.len(), 1_001);
+        let (i, _) = filter.open_keys(&keys, &id(737)).unwrap();
+        assert_eq!((keys[i].uuid, keys[i].cmd_key), (uuid(737), cmd_key(&uuid(737))));
+        assert!(users.accepts(&keys, i));
+
+        users.set_limits("737@example.com", Some(1_000), None).unwrap();
+        assert!(!users.accepts(&keys, i));
+
+        // a handshake that took the keys before a removal keeps them
+        users.remove_user("10@example.com", false).unwrap();
+        assert!(!Rc::ptr_eq(&keys, &users.auth_keys()));
+        let (j, _) = filter.open_keys(&keys, &id(10)).unwrap();
+        assert!(users.accepts(&keys, j));
+        assert!(!users.accepts(&keys, i));
+        assert!(filter.open_keys(&users.auth_keys(), &id(10)).is_err());
+    }
+}
@@ -0,0 +1,322 @@
+pub mod dokodemo;
+pub mod memory;
+pub mod vmess;
+
+use crate::app::router::Router;
+use crate::app::{Dispatcher, Metadata};
+use crate::common::task;
+use crate::config::ConfigError;
+use crate::outbound::{AsyncStream, Balancer, Outbound, OutboundManager};
+
+use async_trait::async_trait;
+use futures_util::future::{self, Either};
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::{pin, Pin};
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+use worker::*;
+
+// a client connection whose target the inbound's handshake has read
+pub struct Accepted {
+    pub metadata: Metadata,
+    pub stream: Box<dyn AsyncStream>,
+}
+
+#[async_trait(?Send)]
+pub trait Inbound {
+    // the next connection, None once no more will come
+    async fn accept(&self) -> Option<Result<Accepted>>;
+}
+
+// where an inbound's connections come from before its handshake, as a
+// tcp listener would hand them out
+#[async_trait(?Send)]
+pub trait Listener {
+    // the next connection, None once no more will come
+    async fn accept(&self) -> Option<std::io::Result<Box<dyn AsyncStream>>>;
+}
+
+#[derive(Default)]
+struct Shutdown {
+    triggered: bool,
+    wakers: Vec<Waker>,
+}
+
+// stops `Server::run` once triggered. clones share the signal.
+#[derive(Clone, Default)]
+pub struct ShutdownSignal(Rc<RefCell<Shutdown>>);
+
+impl ShutdownSignal {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn trigger(&self) {
+        let mut shutdown = self.0.borrow_mut();
+        shutdown.triggered = true;
+        for waker in shutdown.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.0.borrow().triggered
+    }
+}
+
+impl Future for ShutdownSignal {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut shutdown = self.0.borrow_mut();
+        if shutdown.triggered {
+            return Poll::Ready(());
+        }
+        // polled again with every accept, the waker usually is the same
+        if !shutdown.wakers.iter().any(|x| x.will_wake(cx.waker())) {
+            shutdown.wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+// inbounds, outbounds and routing put together without any bindings, for
+// embedding the proxy elsewhere. the first outbound added is the default.
+#[derive(Default)]
+pub struct ServerBuilder {
+    inbounds: Vec<(String, Box<dyn Inbound>)>,
+    outbounds: OutboundManager,
+    default_tag: Option<String>,
+    router: Router,
+}
+
+impl ServerBuilder {
+    pub fn add_inbound(mut self, tag: &str, inbound: impl Inbound + 'static) -> Self {
+        self.inbounds.push((tag.to_string(), Box::new(inbound)));
+        self
+    }
+
+    pub fn add_outbound(mut self, tag: &str, outbound: impl Outbound + 'static) -> Self {
+        self.outbounds.add(tag, Box::new(outbound));
+        self.default_tag.get_or_insert_with(|| tag.to_string());
+        self
+    }
+
+    pub fn router(mut self, router: Router) -> Self {
+        self.router = router;
+        self
+    }
+
+    // every tag the routing rules and balancers name has to be there
+    pub fn build(self) -> std::result::Result<Server, Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        let Some(default_tag) = self.default_tag else {
+            return Err(vec![ConfigError::new(
+                "outbounds",
+                "at least one is needed",
+            )]);
+        };
+        let tags: Vec<_> = self.outbounds.iter().map(|(tag, _)| tag).collect();
+
+        let mut balancers = Vec::new();
+        for (i, balancer) in self.router.balancers().iter().enumerate() {
+            if tags.contains(&balancer.tag.as_str()) {
+                errors.push(ConfigError::new(
+                    &format!("balancers[{i}].tag"),
+                    format!("{:?} is already an outbound", balancer.tag),
+                ));
+            }
+            let members = balancer.members(tags.iter().copied());
+            if members.is_empty() {
+                errors.push(ConfigError::new(
+                    &format!("balancers[{i}].selector"),
+                    "matches no outbound",
+                ));
+            }
+            balancers.push((
+                balancer.tag.clone(),
+                Balancer::new(members, balancer.strategy),
+            ));
+        }
+
+        for (i, rule) in self.router.rules().iter().enumerate() {
+            let tag = rule.outbound_tag.as_str();
+            if rule.balancer && self.router.balancer(tag).is_none() {
+                errors.push(ConfigError::new(
+                    &format!("rules[{i}].balancerTag"),
+                    format!("unknown balancer {tag:?}"),
+                ));
+            } else if !rule.balancer && !tags.contains(&tag) {
+                errors.push(ConfigError::new(
+                    &format!("rules[{i}].outboundTag"),
+                    format!("unknown outbound {tag:?}"),
+                ));
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let mut dispatcher =
+            Dispatcher::new(self.outbounds, &default_tag).with_router(Rc::new(self.router));
+        for (tag, balancer) in balancers {
+            dispatcher = dispatcher.with_balancer(&tag, Rc::new(balancer));
+        }
+        Ok(Server {
+            inbounds: self.inbounds,
+            dispatcher: Rc::new(dispatcher),
+        })
+    }
+}
+
+pub struct Server {
+    inbounds: Vec<(String, Box<dyn Inbound>)>,
+    dispatcher: Rc<Dispatcher>,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    // accepts on every inbound until they are all done or `shutdown` is
+    // triggered. each connection is dispatched in a task of its own, those
+    // still open at shutdown keep running.
+    pub async fn run(self, shutdown: ShutdownSignal) {
+        let accepting = future::join_all(self.inbounds.iter().map(|(tag, inbound)| {
+            let dispatcher = self.dispatcher.clone();
+            async move {
+                while let Some(accepted) = inbound.accept().await {
+                    let Accepted {
+                        mut metadata,
+                        mut stream,
+                    } = match accepted {
+                        Ok(x) => x,
+                        Err(e) => {
+                            crate::log_error!("[{}]: {}", tag, e);
+                            continue;
+                        }
+                    };
+                    metadata.inbound_tag = tag.clone();
+                    let dispatcher = dispatcher.clone();
+                    task::spawn(async move {
+                        // the dispatcher logs failures on its own
+                        let _ = dispatcher.dispatch(&metadata, &mut stream).await;
+                    });
+                }
+            }
+        }));
+        if let Either::Left(_) = future::select(pin!(accepting), shutdown).await {
+            crate::log!("[server]: every inbound is closed");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbound::{Network, Target};
+    use serde_json::json;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    struct Echo;
+
+    #[async_trait(?Send)]
+    impl Outbound for Echo {
+        async fn dispatch(&self, _: &Target, stream: &mut dyn AsyncStream) -> Result<()> {
+            let mut data = Vec::new();
+            stream.read_to_end(&mut data).await?;
+            stream.write_all(&data).await?;
+            Ok(())
+        }
+    }
+
+    // hands out queued connections, then waits for more forever
+    #[derive(Default)]
+    struct Queue(RefCell<Vec<DuplexStream>>);
+
+    #[async_trait(?Send)]
+    impl Inbound for Queue {
+        async fn accept(&self) -> Option<Result<Accepted>> {
+            let Some(stream) = self.0.borrow_mut().pop() else {
+                return future::pending().await;
+            };
+            let target = Target::new("example.com".to_string(), 443, Network::Tcp);
+            let metadata = Metadata {
+                inbound_tag: String::new(),
+                source: None,
+                sniffed_host: None,
+                target,
+                handshake: Duration::ZERO,
+                user: None,
+            };
+            Some(Ok(Accepted {
+                metadata,
+                stream: Box::new(stream),
+            }))
+        }
+    }
+
+    fn router(value: serde_json::Value) -> Router {
+        Router::from_json(&value, "routing").unwrap()
+    }
+
+    #[test]
+    fn test_build_dangling_tag() {
+        let rules = json!({"rules": [
+            {"port": "443", "outboundTag": "direct"},
+            {"port": "80", "outboundTag": "proxy"},
+            {"port": "53", "balancerTag": "pool"},
+        ]});
+        let errors = Server::builder()
+            .add_outbound("direct", Echo)
+            .router(router(rules))
+            .build()
+            .err()
+            .unwrap();
+        let errors: Vec<_> = errors.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            errors,
+            [
+                r#"rules[1].outboundTag: unknown outbound "proxy""#,
+                r#"rules[2].balancerTag: unknown balancer "pool""#,
+            ]
+        );
+
+        let errors = Server::builder().build().err().unwrap();
+        assert_eq!(errors[0].to_string(), "outbounds: at least one is needed");
+    }
+
+    #[tokio::test]
+    async fn test_run_passthrough() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let inbound = Queue::default();
+        inbound.0.borrow_mut().push(server);
+        let rules = json!({"rules": [{"port": "443", "outboundTag": "direct"}]});
+        let server = Server::builder()
+            .add_inbound("socks", inbound)
+            .add_outbound("direct", Echo)
+            .router(router(rules))
+            .build()
+            .unwrap();
+
+        let shutdown = ShutdownSignal::new();
+        let local = tokio::task::LocalSet::new();
+        let running = local.spawn_local(server.run(shutdown.clone()));
+        local
+            .run_until(async {
+                client.write_all(b"ping").await.unwrap();
+                client.shutdown().await.unwrap();
+                let mut echoed = Vec::new();
+                client.read_to_end(&mut echoed).await.unwrap();
+                assert_eq!(echoed, b"ping");
+
+                assert!(!shutdown.is_triggered());
+                shutdown.trigger();
+                running.await.unwrap();
+            })
+            .await;
+    }
+}
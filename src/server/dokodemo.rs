@@ -0,0 +1,531 @@
+use super::{Accepted, Inbound};
+use crate::app::router::parse_networks;
+use crate::app::Metadata;
+use crate::common::time::{self, Instant};
+use crate::config::ConfigError;
+use crate::outbound::{Network, Target};
+
+use async_trait::async_trait;
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::future::{poll_fn, Future};
+use std::io;
+use std::pin::Pin;
+use std::rc::{Rc, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use worker::*;
+
+// v2ray's default for dokodemo-door udp sessions
+pub const DEFAULT_UDP_IDLE: Duration = Duration::from_secs(60);
+
+// v2ray's dokodemo-door `settings` without followRedirect:
+// `{"address": .., "port": .., "network": "tcp,udp"}`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DokodemoSettings {
+    pub address: String,
+    pub port: u16,
+    pub networks: Vec<Network>,
+}
+
+impl DokodemoSettings {
+    pub fn new(address: &str, port: u16) -> Self {
+        Self {
+            address: address.to_string(),
+            port,
+            networks: vec![Network::Tcp],
+        }
+    }
+
+    pub fn with_networks(mut self, networks: Vec<Network>) -> Self {
+        self.networks = networks;
+        self
+    }
+
+    pub fn from_json(value: &Value, path: &str) -> std::result::Result<Self, Vec<ConfigError>> {
+        let Some(object) = value.as_object() else {
+            return Err(vec![ConfigError::new(path, "expected an object")]);
+        };
+
+        let mut errors = Vec::new();
+        let mut settings = Self::new("", 0);
+        for (key, value) in object {
+            let path = format!("{path}.{key}");
+            match key.as_str() {
+                "address" => match value.as_str() {
+                    Some(x) if !x.is_empty() => settings.address = x.to_string(),
+                    _ => errors.push(ConfigError::new(&path, "expected a non-empty string")),
+                },
+                "port" => match value.as_u64().and_then(|x| u16::try_from(x).ok()) {
+                    Some(x) if x != 0 => settings.port = x,
+                    _ => errors.push(ConfigError::new(&path, "must be between 1 and 65535")),
+                },
+                "network" => match parse_networks(value) {
+                    Ok(x) if !x.is_empty() => settings.networks = x,
+                    Ok(_) => errors.push(ConfigError::new(&path, "expected tcp, udp or both")),
+                    Err(e) => errors.push(ConfigError::new(&path, e)),
+                },
+                _ => errors.push(ConfigError::new(&path, "unknown field")),
+            }
+        }
+        for (key, missing) in [
+            ("address", settings.address.is_empty()),
+            ("port", settings.port == 0),
+        ] {
+            if missing && !object.contains_key(key) {
+                errors.push(ConfigError::new(&format!("{path}.{key}"), "is not set"));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(settings)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+// forwards whatever `inner` accepts to the one address of the settings,
+// whichever target its connections were headed for. routing, sniffing and
+// the outbounds still see them as any other connection.
+pub struct DokodemoInbound<I> {
+    inner: I,
+    settings: DokodemoSettings,
+}
+
+impl<I> DokodemoInbound<I> {
+    pub fn new(inner: I, settings: DokodemoSettings) -> Self {
+        Self { inner, settings }
+    }
+}
+
+#[async_trait(?Send)]
+impl<I: Inbound> Inbound for DokodemoInbound<I> {
+    async fn accept(&self) -> Option<Result<Accepted>> {
+        let mut accepted = match self.inner.accept().await? {
+            Ok(x) => x,
+            Err(e) => return Some(Err(e)),
+        };
+        let network = accepted.metadata.target.network;
+        if !self.settings.networks.contains(&network) {
+            let network = match network {
+                Network::Tcp => "tcp",
+                Network::Udp => "udp",
+            };
+            return Some(Err(Error::RustError(format!("{network} is not forwarded"))));
+        }
+        let (address, port) = (self.settings.address.clone(), self.settings.port);
+        accepted.metadata.target = Target::new(address, port, network);
+        Some(Ok(accepted))
+    }
+}
+
+#[derive(Default)]
+struct Session {
+    datagrams: VecDeque<Vec<u8>>,
+    waker: Option<Waker>,
+}
+
+#[derive(Default)]
+struct Shared {
+    sessions: HashMap<String, Rc<RefCell<Session>>>,
+    opened: VecDeque<Accepted>,
+    accept_waker: Option<Waker>,
+    replies: VecDeque<(String, Vec<u8>)>,
+    reply_waker: Option<Waker>,
+    closed: bool,
+}
+
+// the datagrams of many clients on one socket, told apart by source. each
+// source is a connection of its own, accepted with its first datagram and
+// done once it has been idle for a while, so the replies of the outbound
+// it was routed to only go back to that source. clones share the sessions.
+#[derive(Clone)]
+pub struct UdpSessions {
+    shared: Rc<RefCell<Shared>>,
+    // the address the datagrams were sent to
+    local: Target,
+    idle: Duration,
+}
+
+impl UdpSessions {
+    pub fn new(local: Target) -> Self {
+        Self {
+            shared: Rc::default(),
+            local: Target::new(local.addr, local.port, Network::Udp),
+            idle: DEFAULT_UDP_IDLE,
+        }
+    }
+
+    pub fn with_idle(mut self, idle: Duration) -> Self {
+        self.idle = idle;
+        self
+    }
+
+    // a datagram from `source`, the first one after a while opens a session
+    pub fn receive(&self, source: &str, datagram: &[u8]) {
+        let mut shared = self.shared.borrow_mut();
+        if shared.closed {
+            return;
+        }
+        let session = match shared.sessions.get(source) {
+            Some(x) => x.clone(),
+            None => {
+                let session = Rc::<RefCell<Session>>::default();
+                shared.sessions.insert(source.to_string(), session.clone());
+                let stream = SessionStream {
+                    shared: Rc::downgrade(&self.shared),
+                    session: session.clone(),
+                    source: source.to_string(),
+                    idle: self.idle,
+                    last_active: Instant::now(),
+                    timer: None,
+                };
+                let metadata = Metadata {
+                    inbound_tag: String::new(),
+                    source: Some(source.to_string()),
+                    sniffed_host: None,
+                    target: self.local.clone(),
+                    handshake: Duration::ZERO,
+                    user: None,
+                };
+                shared.opened.push_back(Accepted {
+                    metadata,
+                    stream: Box::new(stream),
+                });
+                if let Some(x) = shared.accept_waker.take() {
+                    x.wake();
+                }
+                session
+            }
+        };
+        let mut session = session.borrow_mut();
+        session.datagrams.push_back(datagram.to_vec());
+        if let Some(x) = session.waker.take() {
+            x.wake();
+        }
+    }
+
+    // the next datagram sent back and the source it is for, None once
+    // closed and every reply is out
+    pub async fn reply(&self) -> Option<(String, Vec<u8>)> {
+        poll_fn(|cx| {
+            let mut shared = self.shared.borrow_mut();
+            if let Some(x) = shared.replies.pop_front() {
+                return Poll::Ready(Some(x));
+            }
+            if shared.closed && shared.sessions.is_empty() {
+                return Poll::Ready(None);
+            }
+            shared.reply_waker = Some(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+
+    // no sessions are opened from here on, the open ones run until idle
+    pub fn close(&self) {
+        let mut shared = self.shared.borrow_mut();
+        shared.closed = true;
+        for x in [shared.accept_waker.take(), shared.reply_waker.take()]
+            .into_iter()
+            .flatten()
+        {
+            x.wake();
+        }
+    }
+
+    // sessions open right now
+    pub fn sessions(&self) -> usize {
+        self.shared.borrow().sessions.len()
+    }
+}
+
+#[async_trait(?Send)]
+impl Inbound for UdpSessions {
+    async fn accept(&self) -> Option<Result<Accepted>> {
+        poll_fn(|cx| {
+            let mut shared = self.shared.borrow_mut();
+            if let Some(x) = shared.opened.pop_front() {
+                return Poll::Ready(Some(Ok(x)));
+            }
+            if shared.closed {
+                return Poll::Ready(None);
+            }
+            shared.accept_waker = Some(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+type Timer = Pin<Box<dyn Future<Output = ()>>>;
+
+// one source's datagrams, a read or a write each. reads end once nothing
+// went either way for `idle`, and dropping the stream ends the session.
+struct SessionStream {
+    shared: Weak<RefCell<Shared>>,
+    session: Rc<RefCell<Session>>,
+    source: String,
+    idle: Duration,
+    last_active: Instant,
+    // the sleep until `last_active + idle`, for the deadline it was set for
+    timer: Option<(Instant, Timer)>,
+}
+
+impl AsyncRead for SessionStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if let Some(datagram) = this.session.borrow_mut().datagrams.pop_front() {
+                // like a udp socket, what does not fit is cut off
+                let n = datagram.len().min(buf.remaining());
+                buf.put_slice(&datagram[..n]);
+                this.last_active = Instant::now();
+                return Poll::Ready(Ok(()));
+            }
+            this.session.borrow_mut().waker = Some(cx.waker().clone());
+
+            let deadline = this.last_active + this.idle;
+            let now = Instant::now();
+            if now >= deadline {
+                return Poll::Ready(Ok(()));
+            }
+            if this.timer.as_ref().is_none_or(|(x, _)| *x != deadline) {
+                let sleep = time::sleep(deadline.saturating_duration_since(now));
+                this.timer = Some((deadline, Box::pin(sleep)));
+            }
+            let (_, timer) = this.timer.as_mut().expect("timer was just set");
+            if timer.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+impl AsyncWrite for SessionStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let Some(shared) = self.shared.upgrade() else {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        };
+        let mut shared = shared.borrow_mut();
+        shared
+            .replies
+            .push_back((self.source.clone(), buf.to_vec()));
+        if let Some(x) = shared.reply_waker.take() {
+            x.wake();
+        }
+        self.last_active = Instant::now();
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for SessionStream {
+    fn drop(&mut self) {
+        let Some(shared) = self.shared.upgrade() else {
+            return;
+        };
+        let mut shared = shared.borrow_mut();
+        // a datagram that came in since is the start of a new session
+        if shared
+            .sessions
+            .get(&self.source)
+            .is_some_and(|x| Rc::ptr_eq(x, &self.session))
+        {
+            shared.sessions.remove(&self.source);
+        }
+        if let Some(x) = shared.reply_waker.take() {
+            x.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::router::Router;
+    use crate::outbound::AsyncStream;
+    use crate::outbound::Outbound;
+    use crate::server::{Server, ShutdownSignal};
+    use serde_json::json;
+    use std::cell::RefCell;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    type Targets = Rc<RefCell<Vec<Target>>>;
+
+    // answers every read with the target and the bytes read
+    struct Echo(Targets);
+
+    #[async_trait(?Send)]
+    impl Outbound for Echo {
+        async fn dispatch(&self, target: &Target, stream: &mut dyn AsyncStream) -> Result<()> {
+            self.0.borrow_mut().push(target.clone());
+            let mut buf = [0u8; 1024];
+            loop {
+                let n = stream.read(&mut buf).await?;
+                if n == 0 {
+                    return Ok(());
+                }
+                let reply = [format!("{target} ").as_bytes(), &buf[..n]].concat();
+                stream.write_all(&reply).await?;
+            }
+        }
+    }
+
+    // hands out the one tcp connection, then nothing more
+    struct Once(RefCell<Option<DuplexStream>>);
+
+    #[async_trait(?Send)]
+    impl Inbound for Once {
+        async fn accept(&self) -> Option<Result<Accepted>> {
+            let stream = self.0.borrow_mut().take()?;
+            let metadata = Metadata {
+                inbound_tag: String::new(),
+                source: Some("127.0.0.1".to_string()),
+                sniffed_host: None,
+                target: Target::new("127.0.0.1".to_string(), 8080, Network::Tcp),
+                handshake: Duration::ZERO,
+                user: None,
+            };
+            Some(Ok(Accepted {
+                metadata,
+                stream: Box::new(stream),
+            }))
+        }
+    }
+
+    fn server(inbound: impl Inbound + 'static, targets: &Targets) -> Server {
+        let rules = json!({"rules": [{"port": "53", "outboundTag": "proxy"}]});
+        Server::builder()
+            .add_inbound("dokodemo", inbound)
+            .add_outbound("direct", Echo(Rc::default()))
+            .add_outbound("proxy", Echo(targets.clone()))
+            .router(Router::from_json(&rules, "routing").unwrap())
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_settings_from_json() {
+        let settings = DokodemoSettings::from_json(
+            &json!({"address": "8.8.8.8", "port": 53, "network": "tcp,udp"}),
+            "settings",
+        )
+        .unwrap();
+        assert_eq!(
+            settings,
+            DokodemoSettings::new("8.8.8.8", 53).with_networks(vec![Network::Tcp, Network::Udp])
+        );
+
+        let errors = DokodemoSettings::from_json(
+            &json!({"port": 0, "network": "sctp", "followRedirect": true}),
+            "settings",
+        )
+        .err()
+        .unwrap();
+        let errors: Vec<_> = errors.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            errors,
+            [
+                "settings.followRedirect: unknown field",
+                "settings.network: unknown network \"sctp\"",
+                "settings.port: must be between 1 and 65535",
+                "settings.address: is not set",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forward_tcp() {
+        let (mut client, stream) = tokio::io::duplex(1024);
+        let targets = Targets::default();
+        let inbound = DokodemoInbound::new(
+            Once(RefCell::new(Some(stream))),
+            DokodemoSettings::new("8.8.8.8", 53),
+        );
+        let server = server(inbound, &targets);
+
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let running = tokio::task::spawn_local(server.run(ShutdownSignal::new()));
+                client.write_all(b"ping").await.unwrap();
+                let mut reply = [0u8; 19];
+                client.read_exact(&mut reply).await.unwrap();
+                assert_eq!(&reply, b"tcp:8.8.8.8:53 ping");
+                drop(client);
+                running.await.unwrap();
+            })
+            .await;
+        let expected = Target::new("8.8.8.8".to_string(), 53, Network::Tcp);
+        assert_eq!(*targets.borrow(), [expected]);
+    }
+
+    #[tokio::test]
+    async fn test_forward_refuses_network() {
+        let (_client, stream) = tokio::io::duplex(1024);
+        let settings = DokodemoSettings::new("8.8.8.8", 53).with_networks(vec![Network::Udp]);
+        let inbound = DokodemoInbound::new(Once(RefCell::new(Some(stream))), settings);
+        let e = inbound.accept().await.unwrap().err().unwrap();
+        assert_eq!(e.to_string(), "tcp is not forwarded");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_forward_udp_sessions() {
+        let targets = Targets::default();
+        let local = Target::new("127.0.0.1".to_string(), 5353, Network::Udp);
+        let sessions = UdpSessions::new(local).with_idle(Duration::from_secs(30));
+        let settings = DokodemoSettings::new("8.8.8.8", 53).with_networks(vec![Network::Udp]);
+        let server = server(DokodemoInbound::new(sessions.clone(), settings), &targets);
+
+        let tasks = tokio::task::LocalSet::new();
+        tasks
+            .run_until(async {
+                let running = tokio::task::spawn_local(server.run(ShutdownSignal::new()));
+                let (a, b) = ("192.0.2.1:40000", "192.0.2.2:40000");
+                sessions.receive(a, b"a1");
+                sessions.receive(b, b"b1");
+                sessions.receive(a, b"a2");
+                let mut replies = Vec::new();
+                for _ in 0..3 {
+                    let (source, reply) = sessions.reply().await.unwrap();
+                    replies.push((source, String::from_utf8(reply).unwrap()));
+                }
+                replies.sort();
+                let reply = |source: &str, x| (source.to_string(), format!("udp:8.8.8.8:53 {x}"));
+                assert_eq!(replies, [reply(a, "a1"), reply(a, "a2"), reply(b, "b1")]);
+                assert_eq!(sessions.sessions(), 2);
+
+                // idle sessions end, the next datagram opens a new one
+                tokio::time::sleep(Duration::from_secs(31)).await;
+                assert_eq!(sessions.sessions(), 0);
+                sessions.receive(a, b"a3");
+                assert_eq!(sessions.reply().await.unwrap().0, a);
+                assert_eq!(targets.borrow().len(), 3);
+
+                sessions.close();
+                tokio::time::sleep(Duration::from_secs(31)).await;
+                assert_eq!(sessions.reply().await, None);
+                running.await.unwrap();
+            })
+            .await;
+    }
+}
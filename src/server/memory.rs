@@ -0,0 +1,215 @@
+use super::Listener;
+use crate::config::ConfigError;
+use crate::outbound::dialer::{BoxStream, Dialer};
+use crate::outbound::{AsyncStream, Target};
+
+use async_trait::async_trait;
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::future::poll_fn;
+use std::io;
+use std::rc::{Rc, Weak};
+use std::task::{Poll, Waker};
+use tokio::io::DuplexStream;
+use worker::*;
+
+// buffered each way of an in-memory connection
+pub const BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Default)]
+struct Backlog {
+    pending: VecDeque<DuplexStream>,
+    waker: Option<Waker>,
+}
+
+thread_local! {
+    // the listeners by name. everything is on one thread, in a worker the
+    // isolate's, so that is as far as the network reaches.
+    static LISTENERS: RefCell<HashMap<String, Weak<RefCell<Backlog>>>> =
+        RefCell::new(HashMap::new());
+}
+
+// a listener on the in-memory network, which dialers reach by its name
+// without any socket. the name is free again once it is dropped.
+pub struct MemoryListener {
+    name: String,
+    backlog: Rc<RefCell<Backlog>>,
+}
+
+impl MemoryListener {
+    pub fn bind(name: &str) -> Result<Self> {
+        LISTENERS.with(|x| {
+            let mut listeners = x.borrow_mut();
+            if listeners.get(name).is_some_and(|x| x.strong_count() > 0) {
+                return Err(Error::RustError(format!("memory:{name} is already bound")));
+            }
+            let backlog = Rc::new(RefCell::new(Backlog::default()));
+            listeners.insert(name.to_string(), Rc::downgrade(&backlog));
+            Ok(Self {
+                name: name.to_string(),
+                backlog,
+            })
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        // the thread's storage may be gone already when it exits
+        let _ = LISTENERS.try_with(|x| {
+            let mut listeners = x.borrow_mut();
+            let ours = Rc::downgrade(&self.backlog);
+            if listeners.get(&self.name).is_some_and(|x| x.ptr_eq(&ours)) {
+                listeners.remove(&self.name);
+            }
+        });
+    }
+}
+
+#[async_trait(?Send)]
+impl Listener for MemoryListener {
+    // waits for the next connection as long as the listener is there
+    async fn accept(&self) -> Option<io::Result<Box<dyn AsyncStream>>> {
+        poll_fn(|cx| {
+            let mut backlog = self.backlog.borrow_mut();
+            match backlog.pending.pop_front() {
+                Some(x) => Poll::Ready(Some(Ok(Box::new(x) as Box<dyn AsyncStream>))),
+                None => {
+                    backlog.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+}
+
+// a connection to the listener `name`, refused when there is none
+pub fn connect(name: &str) -> io::Result<DuplexStream> {
+    let backlog = LISTENERS.with(|x| x.borrow().get(name).and_then(Weak::upgrade));
+    let Some(backlog) = backlog else {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("nothing listens on memory:{name}"),
+        ));
+    };
+    let (client, server) = tokio::io::duplex(BUFFER_SIZE);
+    let mut backlog = backlog.borrow_mut();
+    backlog.pending.push_back(server);
+    if let Some(waker) = backlog.waker.take() {
+        waker.wake();
+    }
+    Ok(client)
+}
+
+// reaches every target at the listener `name`, which stands in for the
+// server's address the way v2ray's transports do
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryDialer {
+    name: String,
+}
+
+impl MemoryDialer {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // an outbound's `streamSettings` of `{"network": "memory", "name": ..}`,
+    // none for any other network
+    pub fn from_json(
+        value: &Value,
+        path: &str,
+    ) -> std::result::Result<Option<Self>, Vec<ConfigError>> {
+        let Some(object) = value.as_object() else {
+            return Err(vec![ConfigError::new(path, "expected an object")]);
+        };
+        if object.get("network").and_then(Value::as_str) != Some("memory") {
+            return Ok(None);
+        }
+
+        let mut errors = Vec::new();
+        let mut name = None;
+        for (key, value) in object {
+            let path = format!("{path}.{key}");
+            match key.as_str() {
+                "network" => {}
+                "name" => match value.as_str() {
+                    Some(x) if !x.is_empty() => name = Some(x),
+                    _ => errors.push(ConfigError::new(&path, "expected a non-empty string")),
+                },
+                _ => errors.push(ConfigError::new(&path, "unknown field")),
+            }
+        }
+        if !object.contains_key("name") {
+            errors.push(ConfigError::new(&format!("{path}.name"), "missing"));
+        }
+
+        match name {
+            Some(name) if errors.is_empty() => Ok(Some(Self::new(name))),
+            _ => Err(errors),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Dialer for MemoryDialer {
+    async fn dial(&self, _: &Target) -> Result<BoxStream> {
+        Ok(Box::new(connect(&self.name)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbound::Network;
+    use serde_json::json;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_listen_and_dial() {
+        let listener = MemoryListener::bind("test-listen").unwrap();
+        let e = MemoryListener::bind("test-listen").err().unwrap();
+        assert_eq!(e.to_string(), "memory:test-listen is already bound");
+
+        let target = Target::new("vmess.example.com".to_string(), 443, Network::Tcp);
+        let dialer = MemoryDialer::new("test-listen");
+        let (accepted, dialed) = tokio::join!(listener.accept(), dialer.dial(&target));
+        let (mut server, mut client) = (accepted.unwrap().unwrap(), dialed.unwrap());
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // the name goes with the listener
+        drop(listener);
+        let e = dialer.dial(&target).await.err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            "IO Error: nothing listens on memory:test-listen"
+        );
+        assert!(MemoryListener::bind("test-listen").is_ok());
+    }
+
+    #[test]
+    fn test_from_json() {
+        let parse = |x| MemoryDialer::from_json(&x, "streamSettings");
+        let dialer = parse(json!({"network": "memory", "name": "pipeline"}));
+        assert_eq!(dialer, Ok(Some(MemoryDialer::new("pipeline"))));
+        assert_eq!(parse(json!({"network": "ws"})), Ok(None));
+
+        let errors = parse(json!({"network": "memory", "path": "/"})).unwrap_err();
+        let paths: Vec<_> = errors.iter().map(|x| x.path.as_str()).collect();
+        assert_eq!(paths, ["streamSettings.path", "streamSettings.name"]);
+    }
+}
@@ -0,0 +1,173 @@
+use super::{Accepted, Inbound, Listener};
+use crate::app::policy::Policy;
+use crate::app::stats::CountingStream;
+use crate::app::Metadata;
+use crate::common::time;
+use crate::proxy::vmess::accept_vmess;
+use crate::proxy::vmess::auth::{self, ReplayFilter};
+use crate::proxy::vmess::users::UserTable;
+
+use async_trait::async_trait;
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
+use worker::*;
+
+// vmess on the connections of any listener, the way v2ray's vmess inbound
+// takes them from its transport. a handshake is done before the next
+// connection is taken, one that fails or stalls is the inbound's error.
+pub struct VmessInbound {
+    listener: Box<dyn Listener>,
+    users: Rc<UserTable>,
+    filter: Rc<ReplayFilter>,
+    handshake_timeout: Duration,
+}
+
+impl VmessInbound {
+    pub fn new(listener: impl Listener + 'static, users: Rc<UserTable>) -> Self {
+        Self {
+            listener: Box::new(listener),
+            users,
+            filter: auth::shared(),
+            handshake_timeout: Policy::default().handshake,
+        }
+    }
+
+    // the isolate's replay filter by default
+    pub fn with_filter(mut self, filter: Rc<ReplayFilter>) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    // how long a client has for its header, the policy's default by default
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+}
+
+#[async_trait(?Send)]
+impl Inbound for VmessInbound {
+    async fn accept(&self) -> Option<Result<Accepted>> {
+        let stream = match self.listener.accept().await? {
+            Ok(x) => x,
+            Err(e) => return Some(Err(e.into())),
+        };
+        let started = time::now();
+        let accepting = accept_vmess(stream, &self.users, &self.filter);
+        // the connection is dropped with the handshake that stalled
+        let Some(accepted) = time::timeout(self.handshake_timeout, accepting).await else {
+            let message = format!("vmess handshake timed out after {:?}", self.handshake_timeout);
+            return Some(Err(io::Error::new(io::ErrorKind::TimedOut, message).into()));
+        };
+        Some(accepted.map(|(uuid, request, stream)| {
+            // added users are counted by email, as on the websocket
+            let user = self.users.user(&uuid).map(|x| x.email);
+            let mut stream = CountingStream::new(stream, Vec::new(), Vec::new());
+            if let Some(quota) = user.as_deref().and_then(|x| self.users.quota(x)) {
+                stream = stream.with_quota(quota);
+            }
+            Accepted {
+                metadata: Metadata {
+                    inbound_tag: "vmess".to_string(),
+                    source: None,
+                    sniffed_host: None,
+                    target: request.target,
+                    handshake: time::now().saturating_sub(started),
+                    user,
+                },
+                stream: Box::new(stream),
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::time::SystemClock;
+    use crate::outbound::AsyncStream;
+    use crate::proxy::vmess::users::cmd_key;
+    use crate::proxy::vmess::{seal_vmess_header, VmessRequestBuilder, COMMAND_TCP};
+    use crate::server::memory::{self, MemoryListener};
+    use std::cell::RefCell;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+    use uuid::Uuid;
+
+    // a client that connects and never sends its header
+    #[tokio::test(start_paused = true)]
+    async fn test_handshake_timeout() {
+        let listener = MemoryListener::bind("test-handshake-timeout").unwrap();
+        let users = Rc::new(UserTable::new(Uuid::from_u128(1)));
+        let inbound = VmessInbound::new(listener, users)
+            .with_filter(Rc::new(ReplayFilter::new(auth::AUTH_ID_WINDOW)))
+            .with_handshake_timeout(Duration::from_secs(3));
+        let mut client = memory::connect("test-handshake-timeout").unwrap();
+
+        let started = tokio::time::Instant::now();
+        let e = inbound.accept().await.unwrap().err().unwrap();
+        assert_eq!(e.to_string(), "IO Error: vmess handshake timed out after 3s");
+        assert_eq!(started.elapsed(), Duration::from_secs(3));
+        // hung up on
+        assert_eq!(client.read(&mut [0u8; 1]).await.unwrap(), 0);
+
+        // a client that sends its header and never reads the response
+        let uuid = Uuid::from_u128(1);
+        let request = VmessRequestBuilder::new(COMMAND_TCP, "example.com", 443)
+            .build()
+            .unwrap();
+        let header = seal_vmess_header(&cmd_key(&uuid), &request.encode(), &SystemClock);
+        let stream: Box<dyn AsyncStream> = Box::new(Stalled(io::Cursor::new(header.unwrap())));
+        let listener = OneListener(RefCell::new(Some(stream)));
+        let inbound = VmessInbound::new(listener, Rc::new(UserTable::new(uuid)))
+            .with_filter(Rc::new(ReplayFilter::new(auth::AUTH_ID_WINDOW)))
+            .with_handshake_timeout(Duration::from_secs(3));
+        let started = tokio::time::Instant::now();
+        let e = inbound.accept().await.unwrap().err().unwrap();
+        assert_eq!(e.to_string(), "IO Error: vmess handshake timed out after 3s");
+        assert_eq!(started.elapsed(), Duration::from_secs(3));
+    }
+
+    // reads what it was given, and no write ever goes through
+    struct Stalled(io::Cursor<Vec<u8>>);
+
+    impl AsyncRead for Stalled {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().0).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for Stalled {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Pending
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Pending
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    // hands out a single connection
+    struct OneListener(RefCell<Option<Box<dyn AsyncStream>>>);
+
+    #[async_trait(?Send)]
+    impl Listener for OneListener {
+        async fn accept(&self) -> Option<io::Result<Box<dyn AsyncStream>>> {
+            self.0.borrow_mut().take().map(Ok)
+        }
+    }
+}
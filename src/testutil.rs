@@ -0,0 +1,195 @@
+// pieces for testing inbounds, routing and outbounds together in one
+// process. the vmess hops go over the in-memory network, so a pipeline
+// needs no sockets and no ports.
+
+use crate::app::Metadata;
+use crate::outbound::chain::{ChainDialer, ChainOutbound};
+use crate::outbound::{AsyncStream, Outbound, Target};
+use crate::proxy::vmess::auth::{ReplayFilter, AUTH_ID_WINDOW};
+use crate::proxy::vmess::chunk::Security;
+use crate::proxy::vmess::client::VmessConnector;
+use crate::proxy::vmess::users::UserTable;
+use crate::server::memory::{MemoryDialer, MemoryListener, BUFFER_SIZE};
+use crate::server::vmess::VmessInbound;
+use crate::server::{Accepted, Inbound, Server, ShutdownSignal};
+
+use async_trait::async_trait;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::future::{poll_fn, Future};
+use std::rc::Rc;
+use std::task::{Poll, Waker};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use uuid::Uuid;
+use worker::*;
+
+#[derive(Default)]
+struct Opened {
+    pending: VecDeque<(Target, DuplexStream)>,
+    waker: Option<Waker>,
+}
+
+// connections the test opens, to targets it picks, handed to the server
+// as if an inbound had read their handshakes. clones share them.
+#[derive(Clone, Default)]
+pub struct StreamInbound(Rc<RefCell<Opened>>);
+
+impl StreamInbound {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // the client's end of a connection to `target`
+    pub fn connect(&self, target: Target) -> DuplexStream {
+        let (client, server) = tokio::io::duplex(BUFFER_SIZE);
+        let mut opened = self.0.borrow_mut();
+        opened.pending.push_back((target, server));
+        if let Some(waker) = opened.waker.take() {
+            waker.wake();
+        }
+        client
+    }
+}
+
+#[async_trait(?Send)]
+impl Inbound for StreamInbound {
+    async fn accept(&self) -> Option<Result<Accepted>> {
+        let (target, stream) = poll_fn(|cx| {
+            let mut opened = self.0.borrow_mut();
+            match opened.pending.pop_front() {
+                Some(x) => Poll::Ready(x),
+                None => {
+                    opened.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await;
+        let metadata = Metadata {
+            inbound_tag: String::new(),
+            source: None,
+            sniffed_host: None,
+            target,
+            handshake: Duration::ZERO,
+            user: None,
+        };
+        Some(Ok(Accepted {
+            metadata,
+            stream: Box::new(stream),
+        }))
+    }
+}
+
+// sends back whatever one read brings, which echoes tcp and udp alike, and
+// remembers the targets it was asked for. clones share them.
+#[derive(Clone, Default)]
+pub struct EchoOutbound(Rc<RefCell<Vec<Target>>>);
+
+impl EchoOutbound {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn targets(&self) -> Vec<Target> {
+        self.0.borrow().clone()
+    }
+}
+
+#[async_trait(?Send)]
+impl Outbound for EchoOutbound {
+    async fn dispatch(&self, target: &Target, stream: &mut dyn AsyncStream) -> Result<()> {
+        self.0.borrow_mut().push(target.clone());
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        loop {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(());
+            }
+            stream.write_all(&buf[..n]).await?;
+        }
+    }
+}
+
+// a vmess server for `uuid` on the memory listener `name`, which sends
+// everything to `outbound`. it has a replay filter of its own.
+pub fn vmess_server(name: &str, uuid: Uuid, outbound: impl Outbound + 'static) -> Result<Server> {
+    let filter = Rc::new(ReplayFilter::new(AUTH_ID_WINDOW));
+    let inbound = VmessInbound::new(MemoryListener::bind(name)?, Rc::new(UserTable::new(uuid)))
+        .with_filter(filter);
+    Server::builder()
+        .add_inbound("vmess", inbound)
+        .add_outbound("direct", outbound)
+        .build()
+        .map_err(|e| {
+            let errors: Vec<_> = e.iter().map(ToString::to_string).collect();
+            Error::RustError(errors.join(", "))
+        })
+}
+
+// a vmess client of the server on the memory listener `name`
+pub fn vmess_connector(name: &str, uuid: Uuid) -> VmessConnector {
+    let server = Target::new(name.to_string(), 0, crate::outbound::Network::Tcp);
+    VmessConnector::new(server, uuid, Security::Aes128Gcm)
+        .with_dialer(Rc::new(MemoryDialer::new(name)))
+}
+
+// `vmess_connector` as an outbound
+pub fn vmess_outbound(name: &str, uuid: Uuid) -> ChainOutbound {
+    let chain = ChainDialer::new(Vec::new()).with_dialer(Rc::new(MemoryDialer::new(name)));
+    ChainOutbound::new(vmess_connector(name, uuid), chain)
+}
+
+// runs the servers on this thread until `test` is done, then shuts them
+// down. connections are dispatched in tasks, which need the local set.
+pub async fn run<T>(servers: Vec<Server>, test: impl Future<Output = T>) -> T {
+    let shutdown = ShutdownSignal::new();
+    let local = tokio::task::LocalSet::new();
+    for server in servers {
+        local.spawn_local(server.run(shutdown.clone()));
+    }
+    let output = local.run_until(test).await;
+    shutdown.trigger();
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::router::Router;
+    use crate::outbound::Network;
+    use serde_json::json;
+
+    const UUID: &str = "f282b878-8711-45a1-8c69-5564172123c1";
+
+    // client inbound -> routing -> vmess outbound -> the memory network ->
+    // vmess inbound -> echo, without a socket anywhere
+    #[tokio::test]
+    async fn test_pipeline() {
+        let uuid = Uuid::parse_str(UUID).unwrap();
+        let echo = EchoOutbound::new();
+        let server = vmess_server("test-pipeline", uuid, echo.clone()).unwrap();
+        let (inbound, blocked) = (StreamInbound::new(), EchoOutbound::new());
+        let rules = json!({"rules": [{"port": "443", "outboundTag": "proxy"}]});
+        let client = Server::builder()
+            .add_inbound("client", inbound.clone())
+            .add_outbound("blocked", blocked.clone())
+            .add_outbound("proxy", vmess_outbound("test-pipeline", uuid))
+            .router(Router::from_json(&rules, "routing").unwrap())
+            .build()
+            .unwrap();
+
+        run(vec![server, client], async {
+            let target = Target::new("example.com".to_string(), 443, Network::Tcp);
+            let mut stream = inbound.connect(target.clone());
+            stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+            stream.shutdown().await.unwrap();
+            let mut echoed = Vec::new();
+            stream.read_to_end(&mut echoed).await.unwrap();
+            assert_eq!(echoed, b"GET / HTTP/1.1\r\n\r\n");
+            assert_eq!(echo.targets(), [target]);
+            assert!(blocked.targets().is_empty());
+        })
+        .await;
+    }
+}
@@ -0,0 +1,56 @@
+use crate::common::hash;
+use crate::common::time::Clock;
+use crate::proxy::vmess::{seal_vmess_header, users};
+
+use js_sys::{Array, Uint8Array};
+use uuid::Uuid;
+use wasm_bindgen::prelude::*;
+
+// the caller's time, javascript has no clock this crate could read
+struct At(u64);
+
+impl Clock for At {
+    fn now(&self) -> u64 {
+        self.0
+    }
+}
+
+// the first 16 bytes of the vmess kdf, `path` is an array of Uint8Array
+#[wasm_bindgen(js_name = kdf16)]
+pub fn kdf16(key: &[u8], path: &Array) -> Vec<u8> {
+    let path: Vec<Vec<u8>> = path.iter().map(|x| Uint8Array::new(&x).to_vec()).collect();
+    let path: Vec<&[u8]> = path.iter().map(Vec::as_slice).collect();
+    hash::kdf(key, &path)[..16].to_vec()
+}
+
+// the aead request header for the command section `cmd`, sealed for
+// `uuid` at `now` in unix seconds
+#[wasm_bindgen(js_name = encodeRequestHeader)]
+pub fn encode_request_header(uuid: &str, cmd: &[u8], now: f64) -> Result<Vec<u8>, JsError> {
+    let uuid = Uuid::parse_str(uuid).map_err(|e| JsError::new(&e.to_string()))?;
+    seal_vmess_header(&users::cmd_key(&uuid), cmd, &At(now as u64))
+        .map_err(|e| JsError::new(&e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::vmess::auth::{ReplayFilter, AUTH_ID_WINDOW};
+    use crate::proxy::vmess::open_vmess_header_with;
+    use crate::proxy::vmess::users::UserTable;
+    use std::rc::Rc;
+
+    const UUID: &str = "f282b878-8711-45a1-8c69-5564172123c1";
+
+    #[tokio::test]
+    async fn test_encode_request_header() {
+        let cmd = [1u8; 41];
+        let sealed = encode_request_header(UUID, &cmd, 1_700_000_000.0).unwrap();
+
+        let users = UserTable::new(Uuid::parse_str(UUID).unwrap());
+        let clock = Rc::new(crate::common::time::MockClock::new(1_700_000_000));
+        let filter = ReplayFilter::new(AUTH_ID_WINDOW).with_clock(clock);
+        let header = open_vmess_header_with(&mut &sealed[..], &users, &filter).await;
+        assert_eq!(header.unwrap(), cmd);
+    }
+}
@@ -0,0 +1,92 @@
+#!/usr/bin/env python3
+# Regenerates the VMess AEAD request in tests/interop_vmess.rs.
+#
+# This is a line-by-line port of the v2fly/v2ray-core v5 client path and
+# deliberately shares no code with the worker:
+#   proxy/vmess/aead/kdf.go             nested hmac-sha256 kdf
+#   proxy/vmess/aead/authid.go          CreateAuthID
+#   proxy/vmess/aead/encrypt.go         SealVMessAEADHeader
+#   proxy/vmess/encoding/client.go      EncodeRequestHeader
+#   common/crypto/chunk.go, auth.go     chunk stream with shake128 masking
+#
+# Every random input is pinned so the output is reproducible:
+#   python3 testdata/vmess_request.py
+import hashlib
+import hmac
+import struct
+import uuid
+import zlib
+
+from cryptography.hazmat.primitives.ciphers import Cipher, algorithms, modes
+from cryptography.hazmat.primitives.ciphers.aead import AESGCM
+
+UUID = uuid.UUID("f282b878-8711-45a1-8c69-5564172123c1")
+TIMESTAMP = 1700000000
+AUTH_RANDOM = bytes.fromhex("a1b2c3d4")
+CONNECTION_NONCE = bytes.fromhex("0102030405060708")
+BODY_IV = bytes(range(0x10, 0x20))
+BODY_KEY = bytes(range(0x20, 0x30))
+RESPONSE_AUTH = 0x5A
+PADDING = bytes.fromhex("deadbeef")
+TARGET = ("example.com", 443)
+PAYLOAD = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n"
+
+
+def kdf(key, *path):
+    def sha256(data=b""):
+        return hashlib.sha256(data)
+
+    digest = sha256
+    for salt in (b"VMess AEAD KDF",) + path:
+        def nested(data=b"", salt=salt, parent=digest):
+            return hmac.new(salt, data, parent)
+
+        digest = nested
+    return digest(key).digest()
+
+
+def fnv1a32(data):
+    h = 0x811C9DC5
+    for b in data:
+        h = ((h ^ b) * 0x01000193) & 0xFFFFFFFF
+    return h
+
+
+cmd_key = hashlib.md5(UUID.bytes + b"c48619fe-8f02-49e0-b9e9-edf763e17e21").digest()
+
+# auth id: aes-128-ecb(timestamp | random | crc32)
+auth_id = struct.pack(">Q", TIMESTAMP) + AUTH_RANDOM
+auth_id += struct.pack(">I", zlib.crc32(auth_id))
+ecb = Cipher(algorithms.AES(kdf(cmd_key, b"AES Auth ID Encryption")[:16]), modes.ECB())
+auth_id = ecb.encryptor().update(auth_id)
+
+# command section: chunk stream + masking, aes-128-gcm, tcp
+host, port = TARGET
+header = bytes([1]) + BODY_IV + BODY_KEY
+header += bytes([RESPONSE_AUTH, 0x01 | 0x04, (len(PADDING) << 4) | 0x03, 0x00, 0x01])
+header += struct.pack(">H", port) + bytes([0x02, len(host)]) + host.encode()
+header += PADDING
+header += struct.pack(">I", fnv1a32(header))
+
+def seal(key_salt, iv_salt, msg):
+    key = kdf(cmd_key, key_salt, auth_id, CONNECTION_NONCE)[:16]
+    iv = kdf(cmd_key, iv_salt, auth_id, CONNECTION_NONCE)[:12]
+    return AESGCM(key).encrypt(iv, msg, auth_id)
+
+request = auth_id
+request += seal(
+    b"VMess Header AEAD Key_Length",
+    b"VMess Header AEAD Nonce_Length",
+    struct.pack(">H", len(header)),
+)
+request += CONNECTION_NONCE
+request += seal(b"VMess Header AEAD Key", b"VMess Header AEAD Nonce", header)
+
+# first body chunk, sealed with the raw request key/iv
+mask = hashlib.shake_128(BODY_IV).digest(2)
+nonce = struct.pack(">H", 0) + BODY_IV[2:12]
+chunk = AESGCM(BODY_KEY).encrypt(nonce, PAYLOAD, b"")
+request += struct.pack(">H", len(chunk) ^ struct.unpack(">H", mask)[0]) + chunk
+
+for i in range(0, len(request), 16):
+    print("    " + " ".join(f"0x{b:02x}," for b in request[i : i + 16]))
@@ -0,0 +1,37 @@
+// The c abi, from a c program compiled against include/siren.h and the
+// cdylib of this build: `cargo test --features capi --test capi`. Needs a
+// c compiler as `cc`.
+
+#![cfg(feature = "capi")]
+
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn test_c_program() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    // target/<profile>/deps, next to this test is the cdylib of its build
+    let exe = std::env::current_exe().unwrap();
+    let lib_dir = exe.parent().unwrap();
+    let program = Path::new(env!("CARGO_TARGET_TMPDIR")).join("capi_kdf");
+
+    let status = Command::new("cc")
+        .arg(root.join("tests/capi/kdf.c"))
+        .arg("-I")
+        .arg(root.join("include"))
+        .arg("-L")
+        .arg(lib_dir)
+        .arg("-lsiren")
+        .arg("-o")
+        .arg(&program)
+        .status()
+        .expect("no c compiler");
+    assert!(status.success(), "compiling kdf.c");
+
+    let status = Command::new(&program)
+        .env("LD_LIBRARY_PATH", lib_dir)
+        .env("DYLD_LIBRARY_PATH", lib_dir)
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
@@ -0,0 +1,45 @@
+/* siren-hash's test_kdf vector, reproduced through the c abi */
+
+#include <stdio.h>
+#include <string.h>
+
+#include "siren.h"
+
+#define CHECK(x)                                                   \
+    if (!(x)) {                                                    \
+        fprintf(stderr, "%s:%d: %s failed\n", __FILE__, __LINE__, #x); \
+        return 1;                                                  \
+    }
+
+int main(void) {
+    /* 96850032-1b92-46e9-a4f2-b99631456894 */
+    const uint8_t uuid[16] = {0x96, 0x85, 0x00, 0x32, 0x1b, 0x92, 0x46, 0xe9,
+                              0xa4, 0xf2, 0xb9, 0x96, 0x31, 0x45, 0x68, 0x94};
+    const uint8_t expected[16] = {117, 82, 144, 159, 147, 65, 74, 253,
+                                  91, 74, 70, 84, 114, 118, 203, 30};
+
+    uint8_t key[16];
+    CHECK(sw2_cmd_key(uuid, key) == SW2_OK);
+
+    const char *label = "AES Auth ID Encryption";
+    const uint8_t *labels[] = {(const uint8_t *)label};
+    const size_t lens[] = {strlen(label)};
+    uint8_t derived[32];
+    CHECK(sw2_kdf(key, sizeof(key), labels, lens, 1, derived) == SW2_OK);
+    CHECK(memcmp(derived, expected, sizeof(expected)) == 0);
+
+    uint8_t long_label[65] = {0};
+    const uint8_t *long_labels[] = {long_label};
+    const size_t long_lens[] = {sizeof(long_label)};
+    CHECK(sw2_kdf(key, sizeof(key), long_labels, long_lens, 1, derived) == SW2_OK);
+    CHECK(sw2_kdf(NULL, 16, labels, lens, 1, derived) == SW2_NULL_POINTER);
+
+    /* auth id, sealed length, nonce and the sealed command */
+    uint8_t cmd[41] = {1};
+    Sw2Buffer header;
+    CHECK(sw2_encode_request_header(key, cmd, sizeof(cmd), 1700000000, &header) == SW2_OK);
+    CHECK(header.len == 16 + 18 + 8 + sizeof(cmd) + 16);
+    sw2_free(header);
+
+    return 0;
+}
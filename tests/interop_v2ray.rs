@@ -0,0 +1,214 @@
+// VMess against a real v2ray, with this crate on either end of the
+// connection. Needs `--features interop-tests` and V2RAY_BIN pointing at a
+// v2ray v5 binary; without V2RAY_BIN the tests pass without doing anything:
+//
+//   V2RAY_BIN=/usr/local/bin/v2ray cargo test --features interop-tests --test interop_v2ray
+#![cfg(feature = "interop-tests")]
+
+use async_trait::async_trait;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use siren::outbound::dialer::{BoxStream, Dialer};
+use siren::outbound::{Network, Target};
+use siren::proxy::vmess::auth::{ReplayFilter, AUTH_ID_WINDOW};
+use siren::proxy::vmess::chunk::{Security, VmessStream};
+use siren::proxy::vmess::client::VmessConnector;
+use siren::proxy::vmess::users::UserTable;
+use siren::proxy::vmess::{open_vmess_header_with, seal_response_header};
+use std::process::{Child, Command};
+use std::rc::Rc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use uuid::Uuid;
+
+const UUID: &str = "f282b878-8711-45a1-8c69-5564172123c1";
+
+// killed with the test, pass or fail
+struct V2ray(Child);
+
+impl V2ray {
+    fn start(name: &str, config: serde_json::Value) -> Option<Self> {
+        let Ok(bin) = std::env::var("V2RAY_BIN") else {
+            eprintln!("V2RAY_BIN is not set, skipping");
+            return None;
+        };
+        let path = std::env::temp_dir().join(format!("siren-{name}-{}.json", std::process::id()));
+        std::fs::write(&path, config.to_string()).unwrap();
+        let child = Command::new(bin)
+            .arg("run")
+            .arg("-c")
+            .arg(&path)
+            .spawn()
+            .unwrap();
+        Some(Self(child))
+    }
+}
+
+impl Drop for V2ray {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+struct TcpDialer;
+
+#[async_trait(?Send)]
+impl Dialer for TcpDialer {
+    async fn dial(&self, target: &Target) -> worker::Result<BoxStream> {
+        let stream = TcpStream::connect((target.addr.as_str(), target.port))
+            .await
+            .map_err(|e| worker::Error::RustError(e.to_string()))?;
+        Ok(Box::new(stream))
+    }
+}
+
+async fn free_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+// v2ray takes a moment to bind its inbound
+async fn connect_retrying(port: u16) -> TcpStream {
+    for _ in 0..50 {
+        if let Ok(stream) = TcpStream::connect(("127.0.0.1", port)).await {
+            return stream;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("nothing is listening on {port}");
+}
+
+async fn echo(listener: TcpListener) {
+    loop {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        tokio::task::spawn_local(async move {
+            let (mut r, mut w) = stream.split();
+            let _ = tokio::io::copy(&mut r, &mut w).await;
+        });
+    }
+}
+
+// the payload spans a few chunks to get the masking and padding going
+fn payload() -> Vec<u8> {
+    (0..40_000u32).map(|x| x as u8).collect()
+}
+
+async fn roundtrip<S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin>(stream: &mut S) {
+    let payload = payload();
+    stream.write_all(&payload).await.unwrap();
+    stream.flush().await.unwrap();
+    let mut echoed = vec![0u8; payload.len()];
+    stream.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(echoed, payload);
+}
+
+#[tokio::test]
+async fn test_v2ray_server() {
+    let echo_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let echo_port = echo_listener.local_addr().unwrap().port();
+    let vmess_port = free_port().await;
+    let config = json!({
+        "inbounds": [{
+            "listen": "127.0.0.1",
+            "port": vmess_port,
+            "protocol": "vmess",
+            "settings": {"clients": [{"id": UUID, "alterId": 0}]},
+        }],
+        "outbounds": [{"protocol": "freedom"}],
+    });
+    let Some(_v2ray) = V2ray::start("server", config) else {
+        return;
+    };
+    drop(connect_retrying(vmess_port).await);
+
+    let local = tokio::task::LocalSet::new();
+    local
+        .run_until(async {
+            tokio::task::spawn_local(echo(echo_listener));
+            for security in [
+                Security::Aes128Gcm,
+                Security::ChaCha20Poly1305,
+                Security::None,
+            ] {
+                let server = Target::new("127.0.0.1".to_string(), vmess_port, Network::Tcp);
+                let connector =
+                    VmessConnector::new(server, Uuid::parse_str(UUID).unwrap(), security)
+                        .with_dialer(Rc::new(TcpDialer));
+                let target = Target::new("127.0.0.1".to_string(), echo_port, Network::Tcp);
+                let mut stream = connector.connect_tcp(&target).await.unwrap();
+                roundtrip(&mut stream).await;
+            }
+        })
+        .await;
+}
+
+// the server half of process_vmess, echoing the body back
+async fn serve_vmess(listener: TcpListener) {
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let users = UserTable::new(Uuid::parse_str(UUID).unwrap());
+    let filter = ReplayFilter::new(AUTH_ID_WINDOW);
+    let header = open_vmess_header_with(&mut stream, &users, &filter)
+        .await
+        .unwrap();
+    let (iv, key) = (&header[1..17], &header[17..33]);
+    let (auth, options) = (header[33], header[34]);
+    let security = Security::from_byte(header[35]).unwrap();
+
+    let response_key = &Sha256::digest(key)[..16];
+    let response_iv = &Sha256::digest(iv)[..16];
+    let response = seal_response_header(response_key, response_iv, auth).unwrap();
+    stream.write_all(&response).await.unwrap();
+
+    let mut stream = VmessStream::new(
+        stream,
+        security,
+        options,
+        key,
+        iv,
+        response_key,
+        response_iv,
+    )
+    .unwrap();
+    let mut buf = vec![0u8; payload().len()];
+    stream.read_exact(&mut buf).await.unwrap();
+    stream.write_all(&buf).await.unwrap();
+    stream.flush().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_v2ray_client() {
+    let vmess_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let vmess_port = vmess_listener.local_addr().unwrap().port();
+    let door_port = free_port().await;
+    let config = json!({
+        "inbounds": [{
+            "listen": "127.0.0.1",
+            "port": door_port,
+            "protocol": "dokodemo-door",
+            "settings": {"address": "example.com", "port": 443, "network": "tcp"},
+        }],
+        "outbounds": [{
+            "protocol": "vmess",
+            "settings": {"vnext": [{
+                "address": "127.0.0.1",
+                "port": vmess_port,
+                "users": [{"id": UUID, "alterId": 0, "security": "aes-128-gcm"}],
+            }]},
+        }],
+    });
+    let Some(_v2ray) = V2ray::start("client", config) else {
+        return;
+    };
+
+    let local = tokio::task::LocalSet::new();
+    local
+        .run_until(async {
+            let server = tokio::task::spawn_local(serve_vmess(vmess_listener));
+            let mut stream = connect_retrying(door_port).await;
+            roundtrip(&mut stream).await;
+            server.await.unwrap();
+        })
+        .await;
+}
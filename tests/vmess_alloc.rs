@@ -0,0 +1,171 @@
+// Allocations and throughput of the vmess body stream. The allocator below
+// counts per thread, so only what the test itself does is seen.
+//
+// The throughput numbers come from the ignored benchmark:
+// `cargo test --release --test vmess_alloc -- --ignored --nocapture`
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use siren::proxy::vmess::chunk::{
+    ChunkCodec, Security, VmessStream, OPTION_CHUNK_MASKING, OPTION_CHUNK_STREAM,
+};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+struct Counting;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|x| x.set(x.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.with(|x| x.set(x.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(|x| x.get())
+}
+
+const KEY: [u8; 16] = [7u8; 16];
+const IV: [u8; 16] = [9u8; 16];
+const OPTIONS: u8 = OPTION_CHUNK_STREAM | OPTION_CHUNK_MASKING;
+const CHUNK: usize = 8 * 1024;
+
+// takes everything, the way a socket with room to spare would
+#[derive(Default)]
+struct Sink(usize);
+
+impl AsyncWrite for Sink {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.0 += buf.len();
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn sealed_chunks(n: usize) -> Vec<u8> {
+    let mut codec = ChunkCodec::new(Security::Aes128Gcm, &KEY, &IV, OPTIONS).unwrap();
+    let mut wire = Vec::new();
+    for _ in 0..n {
+        wire.extend(codec.encode_chunk(&[42u8; CHUNK]).unwrap());
+    }
+    wire
+}
+
+#[tokio::test]
+async fn test_steady_state_chunks_do_not_allocate() {
+    let payload = vec![42u8; CHUNK];
+    let mut writer = VmessStream::new(
+        Sink::default(),
+        Security::Aes128Gcm,
+        OPTIONS,
+        &KEY,
+        &IV,
+        &KEY,
+        &IV,
+    )
+    .unwrap();
+    for _ in 0..4 {
+        writer.write_all(&payload).await.unwrap();
+    }
+    let before = allocations();
+    for _ in 0..64 {
+        writer.write_all(&payload).await.unwrap();
+    }
+    assert_eq!(allocations() - before, 0, "sealing");
+
+    let wire = sealed_chunks(68);
+    let mut reader = VmessStream::new(
+        &wire[..],
+        Security::Aes128Gcm,
+        OPTIONS,
+        &KEY,
+        &IV,
+        &KEY,
+        &IV,
+    )
+    .unwrap();
+    let mut buf = vec![0u8; CHUNK];
+    for _ in 0..4 {
+        reader.read_exact(&mut buf).await.unwrap();
+    }
+    let before = allocations();
+    for _ in 0..64 {
+        reader.read_exact(&mut buf).await.unwrap();
+    }
+    assert_eq!(allocations() - before, 0, "opening");
+    assert_eq!(buf, payload);
+}
+
+#[tokio::test]
+#[ignore]
+async fn bench_throughput() {
+    const TOTAL: usize = 256 * 1024 * 1024;
+    let payload = vec![42u8; CHUNK];
+
+    let mut writer = VmessStream::new(
+        Sink::default(),
+        Security::Aes128Gcm,
+        OPTIONS,
+        &KEY,
+        &IV,
+        &KEY,
+        &IV,
+    )
+    .unwrap();
+    let started = std::time::Instant::now();
+    for _ in 0..TOTAL / CHUNK {
+        writer.write_all(&payload).await.unwrap();
+    }
+    let seal = TOTAL as f64 / started.elapsed().as_secs_f64() / (1 << 20) as f64;
+
+    let wire = sealed_chunks(TOTAL / CHUNK);
+    let mut reader = VmessStream::new(
+        &wire[..],
+        Security::Aes128Gcm,
+        OPTIONS,
+        &KEY,
+        &IV,
+        &KEY,
+        &IV,
+    )
+    .unwrap();
+    let mut buf = vec![0u8; 16 * 1024];
+    let started = std::time::Instant::now();
+    let mut n = 0;
+    while n < TOTAL {
+        n += reader.read(&mut buf).await.unwrap();
+    }
+    let open = TOTAL as f64 / started.elapsed().as_secs_f64() / (1 << 20) as f64;
+
+    println!("seal {seal:.0} MiB/s, open {open:.0} MiB/s");
+}